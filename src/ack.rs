@@ -0,0 +1,159 @@
+// アプリケーションレベルの到達確認 (at-least-once)
+//
+// 送信側は `Outbox` でメッセージにIDを付けて送り、相手からAckが届くまで保持する。
+// 切断された場合は、再接続後に `Outbox::resend` で未確認のメッセージを送り直す。
+// 受信側は `decode` でメッセージを取り出し、`ack(id)` を送り返す。
+// 再送により同じIDのメッセージを2回以上受け取ることがある
+//
+// どちらもBinaryメッセージとして送る:
+// 種類 (1: Text / 2: Binary / 3: Ack) | ID (u64 BE) | payload (Ackならなし)
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{error::Result, message::Message};
+
+const TEXT: u8 = 1;
+const BINARY: u8 = 2;
+const ACK: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    /// IDの付いたメッセージ。受け取ったら `ack(id)` を送り返す
+    Data { id: u64, message: Message },
+    /// 相手がIDのメッセージを受け取った
+    Ack(u64),
+}
+
+/// 受信したメッセージを取り出す。この形式でなければ None
+pub fn decode(message: &Message) -> Option<Packet> {
+    let Message::Binary(bytes) = message else {
+        return None;
+    };
+    if bytes.len() < 9 {
+        return None;
+    }
+    let id = u64::from_be_bytes(bytes[1..9].try_into().unwrap());
+    let payload = bytes[9..].to_vec();
+
+    match bytes[0] {
+        TEXT => Some(Packet::Data {
+            id,
+            message: Message::Text(String::from_utf8(payload).ok()?),
+        }),
+        BINARY => Some(Packet::Data {
+            id,
+            message: Message::Binary(payload),
+        }),
+        ACK => Some(Packet::Ack(id)),
+        _ => None,
+    }
+}
+
+/// IDのメッセージを受け取ったことを伝えるメッセージ
+pub fn ack(id: u64) -> Message {
+    Message::Binary(header(ACK, id))
+}
+
+fn header(kind: u8, id: u64) -> Vec<u8> {
+    let mut bytes = vec![kind];
+    bytes.extend_from_slice(&id.to_be_bytes());
+    bytes
+}
+
+fn encode(id: u64, message: &Message) -> Message {
+    let kind = match message {
+        Message::Text(_) => TEXT,
+        Message::Binary(_) => BINARY,
+    };
+    let mut bytes = header(kind, id);
+    bytes.extend_from_slice(message.as_bytes());
+    Message::Binary(bytes)
+}
+
+type Callback = Box<dyn Fn(u64, &Message) + Send + Sync>;
+
+/// Ackが届くまで送信したメッセージを保持する
+pub struct Outbox {
+    next_id: AtomicU64,
+    /// 未確認のメッセージ (ID順)
+    pending: Mutex<BTreeMap<u64, Message>>,
+    on_delivered: Option<Callback>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(BTreeMap::new()),
+            on_delivered: None,
+        }
+    }
+
+    /// Ackが届いたときに呼ぶコールバックを設定する
+    pub fn with_callback<F>(mut self, on_delivered: F) -> Self
+    where
+        F: Fn(u64, &Message) + Send + Sync + 'static,
+    {
+        self.on_delivered = Some(Box::new(on_delivered));
+        self
+    }
+
+    /// IDを付けてsendで送る。戻り値は付けたID。
+    /// 送信に失敗してもメッセージは保持し、`resend` で送り直せる
+    pub fn send<F>(&self, message: Message, send: F) -> Result<u64>
+    where
+        F: FnOnce(Message) -> Result<()>,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let packet = encode(id, &message);
+        self.pending.lock().unwrap().insert(id, message);
+        send(packet)?;
+        Ok(id)
+    }
+
+    /// 受信したメッセージがAckなら未確認のメッセージから取り除いてtrueを返す
+    pub fn receive(&self, message: &Message) -> bool {
+        let Some(Packet::Ack(id)) = decode(message) else {
+            return false;
+        };
+        let delivered = self.pending.lock().unwrap().remove(&id);
+        if let (Some(message), Some(on_delivered)) = (delivered, &self.on_delivered) {
+            on_delivered(id, &message);
+        }
+        true
+    }
+
+    /// 未確認のメッセージ (ID順)
+    pub fn pending(&self) -> Vec<(u64, Message)> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, message)| (*id, message.clone()))
+            .collect()
+    }
+
+    /// 未確認のメッセージを同じIDのまま送り直す (再接続後など)。戻り値は送った数
+    pub fn resend<F>(&self, mut send: F) -> Result<usize>
+    where
+        F: FnMut(Message) -> Result<()>,
+    {
+        let pending = self.pending();
+        for (id, message) in &pending {
+            send(encode(*id, message))?;
+        }
+        Ok(pending.len())
+    }
+}
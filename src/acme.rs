@@ -0,0 +1,997 @@
+// ACME (RFC 8555) による証明書の自動取得
+//
+// `Acme` で取得するドメインとCAを指定し、`TlsConfig::from_acme` に渡す:
+//
+//   let acme = Acme::new(Acme::LETS_ENCRYPT, &["chat.example.org"], "/var/lib/websocket-rs/acme")
+//       .contact("admin@example.org");
+//   Server::bind("0.0.0.0:443", handler)?.with_tls(TlsConfig::from_acme(acme)?)?.run()?;
+//
+// ドメインの確認は TLS-ALPN-01 (RFC 8737) で行う。CAはALPNで `acme-tls/1` を提示してドメインの443番ポートに接続し、
+// サーバーは検証用の自己署名の証明書 (acmeIdentifier拡張にkey authorizationのSHA-256を入れる) で応じる。
+// そのため、サーバーは443番ポートで直接 (TLSを終端するプロキシを挟まずに) 待ち受けている必要がある。
+// TLS-ALPN-01 はワイルドカードの証明書には使えない。
+//
+// アカウントの鍵 (account.pem)、取得した証明書チェーン (cert.pem) と秘密鍵 (key.pem) は `cache_dir` に保存し、
+// 再起動したら読み込む。証明書がまだなければ、取得するまで自己署名の証明書で待ち受ける。
+// 有効期限の `renew_before` (デフォルトは30日) 前になったら取得し直し、次のTLSのhandshakeから使う。
+// 失敗したら `retry` (デフォルトは1時間) 後にもう一度取得する
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, Weak},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    sign::CertifiedKey,
+};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::{
+    http::{self, ClientResponse},
+    json::{self, Value},
+    log::{info, warning},
+    tls::{self, sha256, Certificates, ClientTlsConfig},
+};
+
+/// TLS-ALPN-01 の検証でCAが提示するALPNのプロトコル
+pub(crate) const ALPN: &[u8] = b"acme-tls/1";
+
+/// ACMEのサーバーへのリクエストのタイムアウト
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// 受信するレスポンスの上限
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+/// authorization・orderの状態を確かめる間隔と回数
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_ATTEMPTS: u32 = 60;
+/// 証明書の有効期限を確かめる間隔の上限
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// ACMEで証明書を取得する設定。`TlsConfig::from_acme` に渡す
+#[derive(Clone, Debug)]
+pub struct Acme {
+    directory: String,
+    domains: Vec<String>,
+    contact: Vec<String>,
+    cache_dir: PathBuf,
+    renew_before: Duration,
+    retry: Duration,
+    /// `https://` のdirectoryに接続するときの設定
+    tls: ClientTlsConfig,
+}
+
+impl Acme {
+    /// Let's Encryptの本番環境
+    pub const LETS_ENCRYPT: &'static str = "https://acme-v02.api.letsencrypt.org/directory";
+    /// Let's Encryptのステージング環境 (信頼されない証明書を発行する代わりに、レート制限が緩い)
+    pub const LETS_ENCRYPT_STAGING: &'static str =
+        "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+    /// `directory` のURLのCAから `domains` の証明書を取得する。鍵と証明書は `cache_dir` に保存する
+    pub fn new<P: AsRef<Path>>(directory: &str, domains: &[&str], cache_dir: P) -> Self {
+        Self {
+            directory: directory.to_string(),
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            contact: vec![],
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+            retry: Duration::from_secs(60 * 60),
+            tls: ClientTlsConfig::new(),
+        }
+    }
+
+    /// アカウントの連絡先のメールアドレス。有効期限の通知などが届く
+    pub fn contact(mut self, email: &str) -> Self {
+        self.contact.push(format!("mailto:{}", email));
+        self
+    }
+
+    /// 有効期限のどれだけ前に取得し直すか (デフォルトは30日)
+    pub fn renew_before(mut self, renew_before: Duration) -> Self {
+        self.renew_before = renew_before;
+        self
+    }
+
+    /// 取得に失敗したときに、もう一度取得するまでの間隔 (デフォルトは1時間)
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// directoryのサーバーの証明書を検証する設定 (デフォルトは webpki-roots のCA)
+    pub fn directory_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub(crate) fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    /// 保存した証明書。なければ、取得するまで使う自己署名の証明書
+    pub(crate) fn initial_certificate(&self) -> io::Result<Arc<CertifiedKey>> {
+        if let Ok(certified) = self.cached() {
+            return Ok(certified);
+        }
+        let key = KeyPair::generate().map_err(io::Error::other)?;
+        let cert = CertificateParams::new(self.domains.clone())
+            .and_then(|params| params.self_signed(&key))
+            .map_err(io::Error::other)?;
+        tls::certified_key(cert.pem().as_bytes(), key.serialize_pem().as_bytes())
+    }
+
+    fn cached(&self) -> io::Result<Arc<CertifiedKey>> {
+        tls::certified_key(
+            &fs::read(self.cache_dir.join("cert.pem"))?,
+            &fs::read(self.cache_dir.join("key.pem"))?,
+        )
+    }
+
+    /// 保存した証明書を取得し直すまでの時間。証明書がなければ 0
+    fn until_renewal(&self) -> Duration {
+        let not_after = fs::read(self.cache_dir.join("cert.pem"))
+            .ok()
+            .and_then(|pem| tls::certificates(&pem).ok())
+            .and_then(|certs| {
+                let (_, cert) = X509Certificate::from_der(&certs[0]).ok()?;
+                u64::try_from(cert.validity().not_after.timestamp()).ok()
+            });
+        let Some(not_after) = not_after else {
+            return Duration::ZERO;
+        };
+        (UNIX_EPOCH + Duration::from_secs(not_after))
+            .checked_sub(self.renew_before)
+            .and_then(|renewal| renewal.duration_since(SystemTime::now()).ok())
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// 保存したアカウントの鍵。なければ作って保存する
+    fn account_key(&self) -> io::Result<KeyPair> {
+        let path = self.cache_dir.join("account.pem");
+        match fs::read_to_string(&path) {
+            Ok(pem) => KeyPair::from_pem(&pem).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = KeyPair::generate().map_err(io::Error::other)?;
+                fs::create_dir_all(&self.cache_dir)?;
+                write_private(&path, key.serialize_pem().as_bytes())?;
+                Ok(key)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 証明書を取得して `cache_dir` に保存する。検証の間は `certificates` に検証用の証明書を置く
+    fn obtain(&self, certificates: &RwLock<Certificates>) -> io::Result<Arc<CertifiedKey>> {
+        let mut session = Session::new(self, &self.account_key()?)?;
+        session.register()?;
+        let (order_url, order) = session.new_order()?;
+        for authorization in array(&order, "authorizations")? {
+            let Value::String(url) = authorization else {
+                return Err(invalid("authorization is not a string"));
+            };
+            session.authorize(url, certificates)?;
+        }
+        let order = session.poll(&order_url, &["pending"])?;
+        expect_status(&order, "ready")?;
+
+        let key = KeyPair::generate().map_err(io::Error::other)?;
+        let csr = CertificateParams::new(self.domains.clone())
+            .and_then(|params| params.serialize_request(&key))
+            .map_err(io::Error::other)?;
+        let payload = format!(
+            "{{\"csr\":\"{}\"}}",
+            URL_SAFE_NO_PAD.encode(csr.der().as_ref())
+        );
+        session.post(string(&order, "finalize")?, Some(&payload))?;
+        let order = session.poll(&order_url, &["ready", "processing"])?;
+        expect_status(&order, "valid")?;
+        let chain = session.post(string(&order, "certificate")?, None)?.body;
+
+        let key = key.serialize_pem();
+        let certified = tls::certified_key(&chain, key.as_bytes())?;
+        write_private(&self.cache_dir.join("key.pem"), key.as_bytes())?;
+        fs::write(self.cache_dir.join("cert.pem"), &chain)?;
+        Ok(certified)
+    }
+}
+
+/// `certificates` を共有するサーバーがなくなるまで、証明書の有効期限を確かめて取得し直す
+pub(crate) fn spawn(acme: Acme, certificates: Weak<RwLock<Certificates>>) {
+    thread::Builder::new()
+        .name("acme".to_string())
+        .spawn(move || renew(acme, certificates))
+        .ok();
+}
+
+fn renew(acme: Acme, certificates: Weak<RwLock<Certificates>>) {
+    while let Some(certificates) = certificates.upgrade() {
+        let mut wait = acme.until_renewal();
+        if wait.is_zero() {
+            let domains = acme.domains.join(",");
+            match acme.obtain(&certificates) {
+                Ok(certified) => {
+                    certificates
+                        .write()
+                        .unwrap()
+                        .set_acme(&acme.domains, certified);
+                    info!(
+                        "acme_certificate_issued",
+                        { domains: domains.as_str() },
+                        "obtained a certificate for {}",
+                        domains
+                    );
+                    // renew_beforeが証明書の有効期間より長くても、取得を繰り返さない
+                    wait = acme.until_renewal().max(acme.retry);
+                }
+                Err(e) => {
+                    warning!(
+                        "acme_failed",
+                        { domains: domains.as_str(), error: e.to_string() },
+                        "failed to obtain a certificate for {}: {}",
+                        domains,
+                        e
+                    );
+                    wait = acme.retry;
+                }
+            }
+        }
+        drop(certificates);
+        thread::sleep(wait.min(CHECK_INTERVAL));
+    }
+}
+
+/// アカウントの鍵で署名したリクエストを送る、1回の取得の間の状態
+struct Session<'a> {
+    acme: &'a Acme,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// 公開鍵のJWK (RFC 7638 のthumbprintの形: メンバーを辞書順に並べ、空白を入れない)
+    jwk: String,
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    /// 前のレスポンスの Replay-Nonce
+    nonce: Option<String>,
+    /// アカウントのURL。登録した後はJWKの代わりにkidとして送る
+    account: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    fn new(acme: &'a Acme, account_key: &KeyPair) -> io::Result<Self> {
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            account_key.serialized_der(),
+            &rng,
+        )
+        .map_err(|_| invalid("account key is not an ECDSA P-256 key"))?;
+        let jwk = jwk(ring::signature::KeyPair::public_key(&key).as_ref());
+        let response = request(acme, "GET", &acme.directory, None)?;
+        let directory = parse(&response)?;
+        Ok(Self {
+            acme,
+            key,
+            rng,
+            jwk,
+            new_nonce: string(&directory, "newNonce")?.to_string(),
+            new_account: string(&directory, "newAccount")?.to_string(),
+            new_order: string(&directory, "newOrder")?.to_string(),
+            nonce: None,
+            account: None,
+        })
+    }
+
+    /// アカウントを登録する。同じ鍵のアカウントがあればそれを使う
+    fn register(&mut self) -> io::Result<()> {
+        let contact = self
+            .acme
+            .contact
+            .iter()
+            .map(|contact| json::string(contact))
+            .collect::<Vec<_>>()
+            .join(",");
+        let payload = format!(
+            "{{\"termsOfServiceAgreed\":true,\"contact\":[{}]}}",
+            contact
+        );
+        let response = self.post(&self.new_account.clone(), Some(&payload))?;
+        let account = response
+            .header("location")
+            .ok_or_else(|| invalid("newAccount without Location"))?;
+        self.account = Some(account.to_string());
+        Ok(())
+    }
+
+    /// (orderのURL, order)
+    fn new_order(&mut self) -> io::Result<(String, Value)> {
+        let identifiers = self
+            .acme
+            .domains
+            .iter()
+            .map(|domain| format!("{{\"type\":\"dns\",\"value\":{}}}", json::string(domain)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let payload = format!("{{\"identifiers\":[{}]}}", identifiers);
+        let response = self.post(&self.new_order.clone(), Some(&payload))?;
+        let url = response
+            .header("location")
+            .ok_or_else(|| invalid("newOrder without Location"))?
+            .to_string();
+        Ok((url, parse(&response)?))
+    }
+
+    /// authorizationのTLS-ALPN-01のchallengeに応じ、検証が終わるまで待つ
+    fn authorize(&mut self, url: &str, certificates: &RwLock<Certificates>) -> io::Result<()> {
+        let authorization = parse(&self.post(url, None)?)?;
+        if status(&authorization) == Some("valid") {
+            return Ok(());
+        }
+        let domain = get(&authorization, "identifier")
+            .map(|identifier| string(identifier, "value"))
+            .ok_or_else(|| invalid("authorization without identifier"))??
+            .to_string();
+        let challenge = array(&authorization, "challenges")?
+            .iter()
+            .find(|challenge| {
+                get(challenge, "type") == Some(&Value::String("tls-alpn-01".to_string()))
+            })
+            .ok_or_else(|| invalid(&format!("no tls-alpn-01 challenge for {}", domain)))?;
+        let challenge_url = string(challenge, "url")?.to_string();
+        let key_authorization = format!(
+            "{}.{}",
+            string(challenge, "token")?,
+            URL_SAFE_NO_PAD.encode(sha256(self.jwk.as_bytes()))
+        );
+        let certified = challenge_certificate(&domain, &key_authorization)?;
+        certificates
+            .write()
+            .unwrap()
+            .set_challenge(&domain, Some(certified));
+        let result = self
+            .post(&challenge_url, Some("{}"))
+            .and_then(|_| self.poll(url, &["pending"]));
+        certificates.write().unwrap().set_challenge(&domain, None);
+        let authorization = result?;
+        if status(&authorization) != Some("valid") {
+            let error = array(&authorization, "challenges")
+                .ok()
+                .and_then(|challenges| challenges.iter().find_map(|c| get(c, "error")))
+                .map(problem)
+                .unwrap_or_default();
+            return Err(invalid(&format!(
+                "authorization for {} failed: {}",
+                domain, error
+            )));
+        }
+        Ok(())
+    }
+
+    /// `url` (authorization・order) の状態が `pending` のどれかでなくなるまで待つ
+    fn poll(&mut self, url: &str, pending: &[&str]) -> io::Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let value = parse(&self.post(url, None)?)?;
+            if !status(&value).is_some_and(|status| pending.contains(&status)) {
+                return Ok(value);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} is still pending", url),
+        ))
+    }
+
+    /// JWSで署名したPOSTを送る。`payload` が None ならPOST-as-GET。
+    /// nonceが古いと言われたら (badNonce) 1回だけ送り直す
+    fn post(&mut self, url: &str, payload: Option<&str>) -> io::Result<ClientResponse> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => {
+                    let response = request(self.acme, "HEAD", &self.new_nonce, None)?;
+                    response
+                        .header("replay-nonce")
+                        .ok_or_else(|| invalid("newNonce without Replay-Nonce"))?
+                        .to_string()
+                }
+            };
+            let key = match &self.account {
+                Some(account) => format!("\"kid\":{}", json::string(account)),
+                None => format!("\"jwk\":{}", self.jwk),
+            };
+            let protected = URL_SAFE_NO_PAD.encode(format!(
+                "{{\"alg\":\"ES256\",{},\"nonce\":{},\"url\":{}}}",
+                key,
+                json::string(&nonce),
+                json::string(url)
+            ));
+            let payload = payload.map_or(String::new(), |payload| URL_SAFE_NO_PAD.encode(payload));
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| io::Error::other("failed to sign a request"))?;
+            let body = format!(
+                "{{\"protected\":\"{}\",\"payload\":\"{}\",\"signature\":\"{}\"}}",
+                protected,
+                payload,
+                URL_SAFE_NO_PAD.encode(signature.as_ref())
+            );
+            let response = request(self.acme, "POST", url, Some(&body))?;
+            self.nonce = response.header("replay-nonce").map(str::to_string);
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let error = parse(&response).map(|value| problem(&value));
+            let bad_nonce = error
+                .as_ref()
+                .is_ok_and(|error| error.starts_with("urn:ietf:params:acme:error:badNonce"));
+            if bad_nonce && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(io::Error::other(format!(
+                "{} returned {}: {}",
+                url,
+                response.status,
+                error.unwrap_or_default()
+            )));
+        }
+    }
+}
+
+/// `http://`・`https://` のURLにリクエストを送る。`body` はJWSのJSON
+fn request(acme: &Acme, method: &str, url: &str, body: Option<&str>) -> io::Result<ClientResponse> {
+    let (secure, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (_, Some(rest)) => (false, rest),
+        _ => return Err(invalid(&format!("unsupported url: {}", url))),
+    };
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| invalid(&format!("invalid port: {}", url)))?,
+        ),
+        None => (authority, if secure { 443 } else { 80 }),
+    };
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        User-Agent: websocket-rs\r\n\
+        Connection: close\r\n",
+        method, path, authority
+    );
+    if let Some(body) = body {
+        request.push_str(&format!(
+            "Content-Type: application/jose+json\r\nContent-Length: {}\r\n",
+            body.len()
+        ));
+    }
+    request.push_str("\r\n");
+    request.push_str(body.unwrap_or_default());
+    let response = http::send(
+        host,
+        port,
+        secure.then_some(&acme.tls),
+        request.as_bytes(),
+        TIMEOUT,
+        MAX_RESPONSE_BYTES,
+    )?;
+    http::parse_response(&response)
+}
+
+/// TLS-ALPN-01 の検証に使う、acmeIdentifier拡張 (critical) を入れた自己署名の証明書
+fn challenge_certificate(domain: &str, key_authorization: &str) -> io::Result<Arc<CertifiedKey>> {
+    let key = KeyPair::generate().map_err(io::Error::other)?;
+    let mut params = CertificateParams::new(vec![domain.to_string()]).map_err(io::Error::other)?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(&sha256(
+        key_authorization.as_bytes(),
+    ))];
+    let cert = params.self_signed(&key).map_err(io::Error::other)?;
+    // rustlsは未知のcriticalな拡張を含む証明書を検証できないので、秘密鍵と対になっているかは確かめない
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+    let signing_key = any_supported_type(&key).map_err(io::Error::other)?;
+    Ok(Arc::new(CertifiedKey::new(
+        vec![cert.der().clone()],
+        signing_key,
+    )))
+}
+
+/// P-256の公開鍵 (0x04 || x || y) のJWK
+fn jwk(public_key: &[u8]) -> String {
+    format!(
+        "{{\"crv\":\"P-256\",\"kty\":\"EC\",\"x\":\"{}\",\"y\":\"{}\"}}",
+        URL_SAFE_NO_PAD.encode(&public_key[1..33]),
+        URL_SAFE_NO_PAD.encode(&public_key[33..65])
+    )
+}
+
+/// 秘密鍵のファイルは所有者だけが読めるようにする
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+fn parse(response: &ClientResponse) -> io::Result<Value> {
+    std::str::from_utf8(&response.body)
+        .ok()
+        .and_then(json::parse)
+        .ok_or_else(|| invalid("invalid JSON in response"))
+}
+
+fn get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> io::Result<&'a str> {
+    match get(value, key) {
+        Some(Value::String(s)) => Ok(s),
+        _ => Err(invalid(&format!("missing {}", key))),
+    }
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> io::Result<&'a [Value]> {
+    match get(value, key) {
+        Some(Value::Array(values)) => Ok(values),
+        _ => Err(invalid(&format!("missing {}", key))),
+    }
+}
+
+fn status(value: &Value) -> Option<&str> {
+    string(value, "status").ok()
+}
+
+fn expect_status(value: &Value, expected: &str) -> io::Result<()> {
+    match status(value) {
+        Some(status) if status == expected => Ok(()),
+        status => Err(invalid(&format!(
+            "unexpected order status: {}",
+            status.unwrap_or("none")
+        ))),
+    }
+}
+
+/// problem document (RFC 7807) の `type: detail`
+fn problem(value: &Value) -> String {
+    format!(
+        "{}: {}",
+        string(value, "type").unwrap_or("unknown"),
+        string(value, "detail").unwrap_or("")
+    )
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        env,
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        process,
+        sync::Mutex,
+        time::Instant,
+    };
+
+    use rcgen::{BasicConstraints, CertificateSigningRequestParams, CertifiedIssuer, DnType, IsCa};
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, CertificateSigningRequestDer, ServerName, UnixTime},
+        ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme,
+    };
+
+    use super::*;
+    use crate::{
+        client::Client, connection::Connection, handler::Handler, handshake::Request,
+        message::Message, server::Server, testing, tls::TlsConfig,
+    };
+
+    /// 検証用の自己署名の証明書を受け取る。CAと同じく、acmeIdentifier拡張だけを確かめる
+    #[derive(Debug)]
+    struct AcceptAny;
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    /// テスト用のACMEのCA。`localhost` の証明書を1つのorderで発行する。
+    /// TLS-ALPN-01 の検証では、443番ポートの代わりに `target` に接続する
+    struct MockCa {
+        base: String,
+        issuer: CertifiedIssuer<'static, KeyPair>,
+        target: Mutex<Option<SocketAddr>>,
+        state: Mutex<MockState>,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        nonce: u32,
+        /// 発行して、まだ使われていないnonce
+        nonces: HashSet<String>,
+        /// 最初のJWSはbadNonceで拒否する
+        rejected_nonce: bool,
+        /// アカウントの公開鍵 (JWK)
+        jwk: Option<Value>,
+        /// TLS-ALPN-01 の検証の結果
+        validated: Option<bool>,
+        certificate: Option<String>,
+        issued: u32,
+    }
+
+    impl MockCa {
+        fn spawn() -> Arc<Self> {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "mock acme ca");
+            let ca = Arc::new(Self {
+                base: format!("http://{}", listener.local_addr().unwrap()),
+                issuer: CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap(),
+                target: Mutex::default(),
+                state: Mutex::default(),
+            });
+            let server = ca.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let _ = server.handle(stream.unwrap());
+                }
+            });
+            ca
+        }
+
+        fn directory(&self) -> String {
+            format!("{}/directory", self.base)
+        }
+
+        fn issued(&self) -> u32 {
+            self.state.lock().unwrap().issued
+        }
+
+        /// `acme.rs` の中で待ち受けるサーバーには443番ポートで接続できないので、接続先を指定する
+        fn set_target(&self, target: SocketAddr) {
+            *self.target.lock().unwrap() = Some(target);
+        }
+
+        /// 次のorderでは検証からやり直す
+        fn reset_order(&self) {
+            let mut state = self.state.lock().unwrap();
+            state.validated = None;
+            state.certificate = None;
+        }
+
+        fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+            let (request, mut body) =
+                Request::read_head(&mut stream).map_err(|e| io::Error::other(e.to_string()))?;
+            let length = request
+                .header("content-length")
+                .map_or(0, |length| length.parse().unwrap());
+            while body.len() < length {
+                let mut chunk = [0; 4096];
+                let n = stream.read(&mut chunk)?;
+                if n == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..n]);
+            }
+            let (status, headers, body) = self.route(&request, &String::from_utf8_lossy(&body));
+            let nonce = {
+                let mut state = self.state.lock().unwrap();
+                state.nonce += 1;
+                let nonce = format!("nonce-{}", state.nonce);
+                state.nonces.insert(nonce.clone());
+                nonce
+            };
+            let mut response = format!("HTTP/1.1 {}\r\nReplay-Nonce: {}\r\n", status, nonce);
+            for (key, value) in headers {
+                response.push_str(&format!("{}: {}\r\n", key, value));
+            }
+            response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+            stream.write_all(response.as_bytes())
+        }
+
+        fn route(&self, request: &Request, body: &str) -> (&str, Vec<(String, String)>, String) {
+            let path = request.path.as_str();
+            match request.method.as_str() {
+                "GET" if path == "/directory" => {
+                    let body = format!(
+                        "{{\"newNonce\":\"{0}/nonce\",\"newAccount\":\"{0}/account\",\"newOrder\":\"{0}/order\"}}",
+                        self.base
+                    );
+                    return ("200 OK", vec![], body);
+                }
+                "HEAD" if path == "/nonce" => return ("200 OK", vec![], String::new()),
+                "POST" => {}
+                _ => return ("404 Not Found", vec![], String::new()),
+            }
+            let payload = match self.verify(path, body) {
+                Ok(payload) => payload,
+                Err(error) => {
+                    let body = format!(
+                        "{{\"type\":\"urn:ietf:params:acme:error:{}\",\"detail\":\"rejected\"}}",
+                        error
+                    );
+                    return ("400 Bad Request", vec![], body);
+                }
+            };
+            let location =
+                |path: &str| vec![("Location".to_string(), format!("{}{}", self.base, path))];
+            match path {
+                "/account" => (
+                    "201 Created",
+                    location("/account/1"),
+                    "{\"status\":\"valid\"}".to_string(),
+                ),
+                "/order" => {
+                    assert!(payload.contains("\"value\":\"localhost\""), "{}", payload);
+                    ("201 Created", location("/order/1"), self.order())
+                }
+                "/order/1" => ("200 OK", vec![], self.order()),
+                "/authz/1" => ("200 OK", vec![], self.authorization()),
+                "/challenge/1" => {
+                    let validated = self.validate();
+                    self.state.lock().unwrap().validated = Some(validated);
+                    ("200 OK", vec![], "{\"type\":\"tls-alpn-01\"}".to_string())
+                }
+                "/finalize/1" => {
+                    let payload = json::parse(&payload).unwrap();
+                    let der = URL_SAFE_NO_PAD
+                        .decode(string(&payload, "csr").unwrap())
+                        .unwrap();
+                    let csr = CertificateSigningRequestParams::from_der(
+                        &CertificateSigningRequestDer::from(der),
+                    )
+                    .unwrap();
+                    assert_eq!(self.state.lock().unwrap().validated, Some(true));
+                    let cert = csr.signed_by(&self.issuer).unwrap();
+                    let mut state = self.state.lock().unwrap();
+                    state.certificate = Some(cert.pem() + self.issuer.pem().as_str());
+                    state.issued += 1;
+                    drop(state);
+                    ("200 OK", vec![], self.order())
+                }
+                "/certificate/1" => {
+                    let certificate = self.state.lock().unwrap().certificate.clone();
+                    ("200 OK", vec![], certificate.unwrap())
+                }
+                _ => ("404 Not Found", vec![], String::new()),
+            }
+        }
+
+        /// JWSの署名・nonce・urlを確かめ、payloadを返す。失敗したらACMEのエラーの種類
+        fn verify(&self, path: &str, body: &str) -> Result<String, &'static str> {
+            let decode = |value: &str| URL_SAFE_NO_PAD.decode(value).map_err(|_| "malformed");
+            let jws = json::parse(body).ok_or("malformed")?;
+            let field = |key| string(&jws, key).map_err(|_| "malformed");
+            let (protected, payload) = (field("protected")?, field("payload")?);
+            let header = String::from_utf8(decode(protected)?).map_err(|_| "malformed")?;
+            let header = json::parse(&header).ok_or("malformed")?;
+            let mut state = self.state.lock().unwrap();
+            let nonce = string(&header, "nonce").map_err(|_| "badNonce")?;
+            if !state.nonces.remove(nonce) || !std::mem::replace(&mut state.rejected_nonce, true) {
+                return Err("badNonce");
+            }
+            if string(&header, "url").ok() != Some(format!("{}{}", self.base, path).as_str()) {
+                return Err("unauthorized");
+            }
+            let jwk = match (get(&header, "jwk"), string(&header, "kid")) {
+                (Some(jwk), _) if path == "/account" => {
+                    state.jwk = Some(jwk.clone());
+                    jwk.clone()
+                }
+                (None, Ok(kid)) if kid == format!("{}/account/1", self.base) => {
+                    state.jwk.clone().ok_or("accountDoesNotExist")?
+                }
+                _ => return Err("malformed"),
+            };
+            let mut public_key = vec![4];
+            public_key.extend(decode(string(&jwk, "x").unwrap())?);
+            public_key.extend(decode(string(&jwk, "y").unwrap())?);
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+                .verify(
+                    format!("{}.{}", protected, payload).as_bytes(),
+                    &decode(field("signature")?)?,
+                )
+                .map_err(|_| "malformed")?;
+            String::from_utf8(decode(payload)?).map_err(|_| "malformed")
+        }
+
+        fn order(&self) -> String {
+            let state = self.state.lock().unwrap();
+            let status = match (&state.certificate, state.validated) {
+                (Some(_), _) => "valid",
+                (None, Some(true)) => "ready",
+                (None, Some(false)) => "invalid",
+                (None, None) => "pending",
+            };
+            format!(
+                "{{\"status\":\"{1}\",\"authorizations\":[\"{0}/authz/1\"],\"finalize\":\"{0}/finalize/1\",\"certificate\":\"{0}/certificate/1\"}}",
+                self.base, status
+            )
+        }
+
+        fn authorization(&self) -> String {
+            let status = match self.state.lock().unwrap().validated {
+                Some(true) => "valid",
+                Some(false) => "invalid",
+                None => "pending",
+            };
+            format!(
+                "{{\"status\":\"{1}\",\"identifier\":{{\"type\":\"dns\",\"value\":\"localhost\"}},\"challenges\":[\
+                 {{\"type\":\"http-01\",\"url\":\"{0}/challenge/0\",\"token\":\"unused\"}},\
+                 {{\"type\":\"tls-alpn-01\",\"url\":\"{0}/challenge/1\",\"token\":\"token-1\"}}]}}",
+                self.base, status
+            )
+        }
+
+        /// `acme-tls/1` で接続し、acmeIdentifier拡張がkey authorizationのSHA-256か確かめる
+        fn validate(&self) -> bool {
+            let jwk = self.state.lock().unwrap().jwk.clone().unwrap();
+            let thumbprint = format!(
+                "{{\"crv\":\"{}\",\"kty\":\"{}\",\"x\":\"{}\",\"y\":\"{}\"}}",
+                string(&jwk, "crv").unwrap(),
+                string(&jwk, "kty").unwrap(),
+                string(&jwk, "x").unwrap(),
+                string(&jwk, "y").unwrap()
+            );
+            let key_authorization = format!(
+                "token-1.{}",
+                URL_SAFE_NO_PAD.encode(sha256(thumbprint.as_bytes()))
+            );
+            let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAny))
+                .with_no_client_auth();
+            config.alpn_protocols = vec![ALPN.to_vec()];
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let mut conn = ClientConnection::new(Arc::new(config), server_name).unwrap();
+            let target = self.target.lock().unwrap().unwrap();
+            let mut socket = TcpStream::connect(target).unwrap();
+            while conn.is_handshaking() {
+                if conn.complete_io(&mut socket).is_err() {
+                    return false;
+                }
+            }
+            let der = conn.peer_certificates().unwrap()[0].to_vec();
+            let (_, cert) = X509Certificate::from_der(&der).unwrap();
+            let mut expected = vec![0x04, 0x20];
+            expected.extend(sha256(key_authorization.as_bytes()));
+            cert.extensions().iter().any(|extension| {
+                extension.oid.to_id_string() == "1.3.6.1.5.5.7.1.31"
+                    && extension.critical
+                    && extension.value == expected
+            })
+        }
+    }
+
+    struct Hello;
+
+    impl Handler for Hello {
+        fn on_open(&self, conn: &mut Connection) {
+            let _ = conn.send(Message::Text("hello".to_string()));
+        }
+    }
+
+    /// `acme` で取得した証明書で待ち受けるサーバーと、その `wss://` のURL
+    fn spawn_server(ca: &MockCa, acme: Acme) -> (testing::TestServer, String) {
+        let server = Server::bind("127.0.0.1:0", Hello).unwrap();
+        ca.set_target(server.local_addr().unwrap());
+        let server = server
+            .with_tls(TlsConfig::from_acme(acme).unwrap())
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let url = format!("wss://localhost:{}/", server.addr().port());
+        (server, url)
+    }
+
+    /// CAが発行した証明書で接続できるまで待つ
+    fn connect(url: &str, tls: &ClientTlsConfig) -> Client {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            match Client::connect_with_tls(url, &[], tls) {
+                Ok(client) => return client,
+                Err(e) => assert!(Instant::now() < deadline, "{}", e),
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn obtains_and_renews_certificates_with_tls_alpn_01() {
+        let dir = env::temp_dir().join(format!("websocket-rs-acme-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let ca = MockCa::spawn();
+        let trusted = ClientTlsConfig::with_root_certificates(ca.issuer.pem().as_bytes()).unwrap();
+        let acme = Acme::new(&ca.directory(), &["localhost"], &dir).contact("admin@example.org");
+
+        // 取得するまでは自己署名の証明書で待ち受ける
+        let (server, url) = spawn_server(&ca, acme.clone());
+        let mut client = connect(&url, &trusted);
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("hello".to_string()))
+        );
+        assert_eq!(ca.issued(), 1);
+        for file in ["account.pem", "cert.pem", "key.pem"] {
+            assert!(dir.join(file).exists(), "{}", file);
+        }
+        drop(server);
+
+        // 再起動したら保存した証明書を使い、有効期限まで間があれば取得し直さない
+        let (_server, url) = spawn_server(&ca, acme.clone());
+        assert!(Client::connect_with_tls(&url, &[], &trusted).is_ok());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(ca.issued(), 1);
+
+        // 有効期限が近ければ取得し直す
+        ca.reset_order();
+        let cert = fs::read(dir.join("cert.pem")).unwrap();
+        let (_server, _) = spawn_server(&ca, acme.renew_before(Duration::MAX));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while fs::read(dir.join("cert.pem")).unwrap() == cert {
+            assert!(Instant::now() < deadline, "certificate was not renewed");
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(ca.issued(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+// actix-webのアダプター
+//
+// actix-webのrouteで受け付けたhandshakeを、`Upgrader` を通してこのクレートのHandlerで処理する:
+//
+//   async fn ws(upgrade: Upgrade, upgrader: web::Data<Upgrader<Chat>>) -> WebSocketResponse {
+//       upgrade.upgrade(&upgrader).await
+//   }
+//   App::new()
+//       .app_data(web::Data::new(server.upgrader()))
+//       .route("/ws", web::get().to(ws))
+//
+// `Upgrade` (extractor) はリクエストのヘッダーとbodyのstreamを取り出す。
+// actix-webはupgradeしたリクエストの後に届いたバイト列をbody (`Payload`) として渡し、
+// 101の応答のbodyとして返したバイト列をそのまま送るので、その2つを `Bridge` のパイプでつなぐ。
+// `Upgrader` は接続ごとのスレッドでパイプの反対側を読み書きするので、middleware・room・actorなどは
+// `Server::run` で受け付けた接続と同じように使える
+
+use std::{
+    convert::Infallible,
+    future::{poll_fn, ready, Ready},
+    io::Write,
+    net::{Shutdown, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::Payload,
+    error::ErrorBadRequest,
+    http::StatusCode,
+    web::Bytes,
+    FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures_core::Stream as _;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{
+    bridge::Bridge, handler::Handler, handshake::Request, log::warning, server::Upgrader,
+    stream::Stream, testing::Pipe,
+};
+
+/// WebSocketのhandshakeのリクエスト。`Upgrade: websocket` がなければ 400 で拒否する
+pub struct Upgrade {
+    request: Request,
+    peer_addr: SocketAddr,
+    payload: Payload,
+}
+
+/// `Upgrade::upgrade` の応答。受け付けたら101、middlewareなどが拒否したらその応答を返す
+pub struct WebSocketResponse {
+    response: HttpResponse,
+}
+
+/// 接続のスレッドがパイプに書き込んだバイト列を、応答のbodyとして送る
+struct Outgoing {
+    /// 応答のヘッダーと一緒に読み込んだ、bodyの先頭
+    head: Option<Bytes>,
+    chunks: UnboundedReceiver<Vec<u8>>,
+}
+
+impl FromRequest for Upgrade {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let upgrade = req
+            .headers()
+            .get("upgrade")
+            .and_then(|value| value.to_str().ok());
+        if !upgrade.is_some_and(|value| value.eq_ignore_ascii_case("websocket")) {
+            return ready(Err(ErrorBadRequest("expected a websocket upgrade")));
+        }
+
+        let request = Request {
+            method: req.method().to_string(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.path().to_string(), |path| path.to_string()),
+            version: format!("{:?}", req.version()),
+            headers: req
+                .headers()
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.as_str().to_ascii_lowercase(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    )
+                })
+                .collect(),
+        };
+        ready(Ok(Self {
+            request,
+            peer_addr: req
+                .peer_addr()
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 0))),
+            payload: payload.take(),
+        }))
+    }
+}
+
+impl Upgrade {
+    /// handshakeのリクエスト (ヘッダー名は小文字)
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    /// `upgrader` のサーバーに接続を渡し、その応答を待つ
+    pub async fn upgrade<H: Handler>(self, upgrader: &Upgrader<H>) -> WebSocketResponse {
+        let mut bridge = match Bridge::upgrade(upgrader, self.request, self.peer_addr) {
+            Ok(bridge) => bridge,
+            Err(e) => return WebSocketResponse::error(&e.to_string()),
+        };
+        let Some((head, mut body)) = bridge.response().await else {
+            return WebSocketResponse::error("connection closed before the response");
+        };
+        let Ok(status) = StatusCode::from_u16(head.status) else {
+            return WebSocketResponse::error("malformed response");
+        };
+        let mut builder = HttpResponse::build(status);
+        if status == StatusCode::SWITCHING_PROTOCOLS {
+            builder.upgrade("websocket");
+        }
+        for (key, value) in head.end_to_end_headers() {
+            builder.append_header((key.as_str(), value.as_str()));
+        }
+
+        if status != StatusCode::SWITCHING_PROTOCOLS {
+            bridge.read_to_end(&mut body).await;
+            return WebSocketResponse {
+                response: builder.body(body),
+            };
+        }
+        let Bridge { pipe, chunks } = bridge;
+        actix_web::rt::spawn(forward_payload(self.payload, pipe));
+        WebSocketResponse {
+            response: builder
+                .message_body(Outgoing {
+                    head: Some(Bytes::from(body)).filter(|body| !body.is_empty()),
+                    chunks,
+                })
+                .unwrap()
+                .map_into_boxed_body(),
+        }
+    }
+}
+
+/// リクエストのbody (upgradeした後に届いたバイト列) を接続のスレッドに渡す
+async fn forward_payload(mut payload: Payload, mut pipe: Pipe) {
+    while let Some(chunk) = poll_fn(|cx| Pin::new(&mut payload).poll_next(cx)).await {
+        let written = match chunk {
+            Ok(chunk) => pipe.write_all(&chunk),
+            Err(e) => {
+                warning!(
+                    "actix_payload_failed",
+                    { error: e.to_string() },
+                    "failed to read the websocket payload: {}",
+                    e
+                );
+                break;
+            }
+        };
+        if written.is_err() {
+            return;
+        }
+    }
+    // クライアントが切断したら、接続のスレッドの読み込みをEOFにする
+    let _ = Stream::shutdown(&pipe, Shutdown::Write);
+}
+
+impl WebSocketResponse {
+    fn error(reason: &str) -> Self {
+        Self {
+            response: HttpResponse::InternalServerError().body(reason.to_string()),
+        }
+    }
+}
+
+impl Responder for WebSocketResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        self.response
+    }
+}
+
+impl MessageBody for Outgoing {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        if let Some(head) = self.head.take() {
+            return Poll::Ready(Some(Ok(head)));
+        }
+        self.chunks
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| Ok(Bytes::from(chunk))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpStream, sync::mpsc as std_mpsc, thread};
+
+    use actix_web::{web, App, HttpServer};
+
+    use super::*;
+    use crate::{
+        client::Client, connection::Connection, message::Message, middleware::BasicAuth,
+        server::Server,
+    };
+
+    /// 受信したメッセージをそのまま返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    async fn ws(upgrade: Upgrade, upgrader: web::Data<Upgrader<Echo>>) -> WebSocketResponse {
+        upgrade.upgrade(&upgrader).await
+    }
+
+    /// `/ws` で `server` に渡すactix-webのサーバーを別のスレッドで起動し、そのアドレスを返す
+    fn spawn_actix(server: Server<Echo>) -> SocketAddr {
+        let upgrader = web::Data::new(server.upgrader());
+        let (sender, receiver) = std_mpsc::channel();
+        thread::spawn(move || {
+            actix_web::rt::System::new().block_on(async move {
+                let server = HttpServer::new(move || {
+                    App::new()
+                        .app_data(upgrader.clone())
+                        .route("/ws", web::get().to(ws))
+                })
+                .workers(1)
+                .bind("127.0.0.1:0")
+                .unwrap();
+                sender.send(server.addrs()[0]).unwrap();
+                server.run().await
+            })
+        });
+        receiver.recv().unwrap()
+    }
+
+    #[test]
+    fn passes_connections_to_the_handler() {
+        let addr = spawn_actix(Server::bind("127.0.0.1:0", Echo).unwrap());
+        let mut client = Client::connect(&format!("ws://{}/ws?room=lobby", addr)).unwrap();
+        for text in ["hello", "world"] {
+            client.send(Message::Text(text.to_string())).unwrap();
+            assert_eq!(
+                client.recv().unwrap(),
+                Some(Message::Text(text.to_string()))
+            );
+        }
+        assert_eq!(client.close(1000, "bye").unwrap(), (1005, String::new()));
+    }
+
+    #[test]
+    fn returns_rejections_from_middleware() {
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_layer(BasicAuth::new("chat", "user", "secret"));
+        let addr = spawn_actix(server);
+        match Client::connect(&format!("ws://{}/ws", addr)) {
+            Err(crate::Error::Handshake(status)) => assert!(status.contains("401"), "{}", status),
+            _ => panic!("expected the handshake to be rejected"),
+        }
+
+        // WebSocketのhandshakeでなければextractorが拒否する
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    }
+}
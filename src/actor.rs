@@ -0,0 +1,309 @@
+// アクター形式の接続
+//
+// 接続ごとにメールボックスを持つスレッドを起動する。アプリケーションは `Mailbox` に `Command` を送って
+// 接続を操作し、全ての接続のイベント (`Event`) を1つのチャネルから受け取る。
+// Handlerのcallbackを実装せずに、1つのスレッドのループで多数の接続を扱える。
+// 既存のチャネル (crossbeamなど) にイベントを流す場合は `system_with` に送る関数を渡す
+//
+//   let (actors, handler) = actor::system();
+//   let server = Server::bind("127.0.0.1:8080", handler)?;
+//   thread::spawn(move || server.run());
+//   for event in actors.events() {
+//       if let Event::Message { id, message } = event {
+//           actors.send(id, Command::Send(message));
+//       }
+//   }
+
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    connection::{Connection, ConnectionHandle, ConnectionId},
+    error::Error,
+    handler::Handler,
+    hub::Hub,
+    log::debug,
+    message::Message,
+};
+
+/// 接続への指示
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Send(Message),
+    SendText(String),
+    /// roomに参加する
+    Subscribe(String),
+    Unsubscribe(String),
+    /// roomに参加している全ての接続に送る
+    Publish(String, Message),
+    Close(u16, String),
+}
+
+/// 接続で起きたこと
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Open {
+        id: ConnectionId,
+        path: String,
+    },
+    Message {
+        id: ConnectionId,
+        message: Message,
+    },
+    /// peerからCloseを受信した
+    PeerClose {
+        id: ConnectionId,
+        code: u16,
+        reason: String,
+    },
+    Error {
+        id: ConnectionId,
+        error: String,
+    },
+    /// 接続が閉じた。この後はメールボックスに送れない
+    Closed {
+        id: ConnectionId,
+    },
+}
+
+/// 接続のメールボックスに送る端。
+/// 接続が閉じた後も持っていると、捨てるまでメールボックスのスレッドが残る
+#[derive(Clone, Debug)]
+pub struct Mailbox {
+    id: ConnectionId,
+    sender: Sender<Command>,
+}
+
+impl Mailbox {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// 接続が閉じていれば false
+    pub fn send(&self, command: Command) -> bool {
+        self.sender.send(command).is_ok()
+    }
+}
+
+/// 開いている接続のメールボックス
+#[derive(Clone, Default)]
+pub struct Mailboxes {
+    mailboxes: Arc<Mutex<HashMap<ConnectionId, Mailbox>>>,
+}
+
+/// イベントを受け取り、接続に指示を送る側
+pub struct Actors {
+    events: Receiver<Event>,
+    mailboxes: Mailboxes,
+}
+
+/// サーバーに渡すHandler。接続ごとにメールボックスのスレッドを起動する
+pub struct ActorHandler {
+    emit: Box<dyn Fn(Event) + Send + Sync>,
+    mailboxes: Mailboxes,
+}
+
+/// `Actors` と、そのイベントを送る `ActorHandler` を作る
+pub fn system() -> (Actors, ActorHandler) {
+    let (sender, events) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    // Actorsを捨てた後のイベントは捨てる
+    let (mailboxes, handler) = system_with(move |event| {
+        let _ = sender.lock().unwrap().send(event);
+    });
+    (Actors { events, mailboxes }, handler)
+}
+
+/// イベントを `emit` に渡す `ActorHandler` を作る。
+/// crossbeamなど、std以外のチャネルにイベントを流す場合に使う
+pub fn system_with<F>(emit: F) -> (Mailboxes, ActorHandler)
+where
+    F: Fn(Event) + Send + Sync + 'static,
+{
+    let mailboxes = Mailboxes::default();
+    (
+        mailboxes.clone(),
+        ActorHandler {
+            emit: Box::new(emit),
+            mailboxes,
+        },
+    )
+}
+
+impl Mailboxes {
+    pub fn get(&self, id: ConnectionId) -> Option<Mailbox> {
+        self.mailboxes.lock().unwrap().get(&id).cloned()
+    }
+
+    /// 接続に指示を送る。接続が閉じていれば false
+    pub fn send(&self, id: ConnectionId, command: Command) -> bool {
+        self.get(id).is_some_and(|mailbox| mailbox.send(command))
+    }
+
+    /// 開いている全ての接続に送る
+    pub fn broadcast(&self, command: Command) -> usize {
+        let mailboxes = self
+            .mailboxes
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        mailboxes
+            .iter()
+            .filter(|mailbox| mailbox.send(command.clone()))
+            .count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.mailboxes.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn insert(&self, mailbox: Mailbox) {
+        self.mailboxes.lock().unwrap().insert(mailbox.id, mailbox);
+    }
+
+    fn remove(&self, id: ConnectionId) {
+        self.mailboxes.lock().unwrap().remove(&id);
+    }
+}
+
+impl Actors {
+    /// 次のイベントを待つ。Handlerを捨てた (サーバーが終了した) 後は None
+    pub fn recv(&self) -> Option<Event> {
+        self.events.recv().ok()
+    }
+
+    /// `timeout` の間にイベントがなければ None
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    pub fn events(&self) -> mpsc::Iter<'_, Event> {
+        self.events.iter()
+    }
+
+    /// 開いている接続のメールボックス
+    pub fn mailbox(&self, id: ConnectionId) -> Option<Mailbox> {
+        self.mailboxes.get(id)
+    }
+
+    /// 接続に指示を送る。接続が閉じていれば false
+    pub fn send(&self, id: ConnectionId, command: Command) -> bool {
+        self.mailboxes.send(id, command)
+    }
+
+    /// 開いている全ての接続に送る
+    pub fn broadcast(&self, command: Command) -> usize {
+        self.mailboxes.broadcast(command)
+    }
+
+    /// 他のスレッドから指示を送るためのメールボックスの一覧
+    pub fn mailboxes(&self) -> Mailboxes {
+        self.mailboxes.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.mailboxes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mailboxes.is_empty()
+    }
+}
+
+impl ActorHandler {
+    fn emit(&self, event: Event) {
+        (self.emit)(event)
+    }
+}
+
+/// メールボックスの指示を順に実行する。全ての送る端が捨てられたら終わる
+fn run(handle: ConnectionHandle, hub: Arc<Hub>, commands: Receiver<Command>) {
+    let id = handle.id();
+    for command in commands {
+        let result = match command {
+            Command::Send(message) => handle.send(message),
+            Command::SendText(text) => handle.send(Message::Text(text)),
+            Command::Subscribe(room) => {
+                hub.join(&room, handle.clone());
+                Ok(())
+            }
+            Command::Unsubscribe(room) => {
+                hub.leave(&room, id);
+                Ok(())
+            }
+            Command::Publish(room, message) => {
+                hub.publish(&room, message);
+                Ok(())
+            }
+            Command::Close(code, reason) => handle.close(code, &reason),
+        };
+        if let Err(e) = result {
+            debug!(
+                "actor_command_failed",
+                { conn: id, error: e.to_string() },
+                "[{}] command failed: {}",
+                id,
+                e
+            );
+        }
+    }
+}
+
+impl Handler for ActorHandler {
+    fn on_open(&self, conn: &mut Connection) {
+        let id = conn.id();
+        let (sender, commands) = mpsc::channel();
+        self.mailboxes.insert(Mailbox { id, sender });
+        let handle = conn.handle();
+        let hub = conn.hub().clone();
+        thread::Builder::new()
+            .name(format!("actor-{}", id))
+            .spawn(move || run(handle, hub, commands))
+            .ok();
+        self.emit(Event::Open {
+            id,
+            path: conn.path().to_string(),
+        });
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        self.emit(Event::Message {
+            id: conn.id(),
+            message,
+        });
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        self.emit(Event::PeerClose {
+            id: conn.id(),
+            code,
+            reason: reason.to_string(),
+        });
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        // 送る端を捨てると、残っている指示を実行した後にスレッドが終わる
+        self.mailboxes.remove(id);
+        self.emit(Event::Closed { id });
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, _terminated: bool) {
+        self.emit(Event::Error {
+            id,
+            error: error.to_string(),
+        });
+    }
+}
@@ -0,0 +1,484 @@
+// 実行中のサーバーの接続を一覧・切断するための管理API
+//
+// HTTPで公開する場合のエンドポイント:
+// GET  /connections                        -> 接続の一覧 (JSON)。path・接続してからの時間・参加しているroom・
+//                                             書き込み待ちのフレーム数・送受信の累計など
+// GET  /connections/<id>                   -> 1つの接続 (JSON)
+// POST /connections/<id>/close?code=<code> -> 指定した接続をclose codeを付けて切断
+// POST /drain?grace=<secs>&message=<text>  -> 新しい接続の受け付けを止め、messageを全ての接続に送り、
+//                                             grace秒後に残った接続を 1001 で閉じる (どちらも省略可)
+// GET  /drain                              -> {"draining":true,"connections":3,"drained":false}
+// GET  /metrics/routes                     -> routeごとの接続数と送受信の累計 (JSON)
+// GET  /metrics/rooms                      -> roomごとの参加者数とpublish・配送の累計 (JSON)
+//
+// 接続を切断できるので、`serve` はloopback (127.0.0.0/8・::1) からのリクエストだけを受け付け、他は 403 を返す。
+// 他のホストから使う場合は `serve_with_token` で `Authorization: Bearer <token>` を必須にする
+
+use std::{
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    connection::ConnectionId,
+    error::{Error, Result},
+    handshake::Request,
+    http,
+    hub::Hub,
+    json,
+    message::Message,
+    middleware::constant_time_eq,
+    registry::Registry,
+    stats::{Breakdown, ConnectionStats, LabeledStats, Snapshot, Stats},
+};
+
+#[derive(Clone)]
+pub struct Admin {
+    registry: Arc<Registry>,
+    stats: Arc<Stats>,
+    routes: Arc<Breakdown>,
+    hub: Arc<Hub>,
+    draining: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub peer_addr: SocketAddr,
+    pub path: String,
+    pub connected_at: SystemTime,
+    /// 送受信の累計・書き込み待ちのフレーム数など
+    pub stats: ConnectionStats,
+    /// 参加しているroom
+    pub rooms: Vec<String>,
+}
+
+impl Admin {
+    pub(crate) fn new(
+        registry: Arc<Registry>,
+        stats: Arc<Stats>,
+        routes: Arc<Breakdown>,
+        hub: Arc<Hub>,
+        draining: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            registry,
+            stats,
+            routes,
+            hub,
+            draining,
+        }
+    }
+
+    /// サーバー起動時からの送受信の累計
+    pub fn stats(&self) -> Snapshot {
+        self.stats.snapshot()
+    }
+
+    /// route (クエリを除いたpath) ごとの開いている接続の数と、送受信の累計
+    pub fn route_stats(&self) -> Vec<LabeledStats> {
+        let connections = self.connections();
+        self.routes
+            .snapshots()
+            .into_iter()
+            .map(|(route, totals)| LabeledStats {
+                connections: connections
+                    .iter()
+                    .filter(|info| http::split_query(&info.path).0 == route)
+                    .count(),
+                label: route,
+                totals,
+            })
+            .collect()
+    }
+
+    /// roomごとの参加者の数と、publish・配送の累計
+    pub fn room_stats(&self) -> Vec<LabeledStats> {
+        self.hub.room_stats()
+    }
+
+    /// 新しい接続の受け付けを止める。既存の接続はそのまま
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// 新しい接続の受け付けを止め、`notice` を全ての接続に送る。
+    /// `grace` を指定すると、その時間が過ぎた後も残っている接続を 1001 で閉じる
+    pub fn drain_with(&self, notice: Option<Message>, grace: Option<Duration>) {
+        self.drain();
+        if let Some(notice) = notice {
+            for entry in self.registry.entries() {
+                let _ = entry.handle.send(notice.clone());
+            }
+        }
+        let Some(grace) = grace else {
+            return;
+        };
+        let admin = self.clone();
+        thread::spawn(move || {
+            thread::sleep(grace);
+            for entry in admin.registry.entries() {
+                let _ = entry.handle.close(1001, "server restarting");
+            }
+        });
+    }
+
+    /// 受け付けを止めていて、接続が残っていない
+    pub fn is_drained(&self) -> bool {
+        self.is_draining() && self.registry.entries().is_empty()
+    }
+
+    /// 全ての接続が閉じるまで最大 `timeout` 待つ。閉じたら true
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.is_drained() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        true
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut memberships = self.hub.memberships();
+        self.registry
+            .entries()
+            .into_iter()
+            .map(|entry| ConnectionInfo {
+                id: entry.handle.id(),
+                peer_addr: entry.handle.peer_addr(),
+                path: entry.path,
+                connected_at: entry.connected_at,
+                stats: entry.handle.stats(),
+                rooms: memberships.remove(&entry.handle.id()).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.connections().into_iter().find(|info| info.id == id)
+    }
+
+    /// 接続にメッセージを送る。該当する接続がなければ false
+    pub fn send(&self, id: ConnectionId, message: Message) -> Result<bool> {
+        match self.registry.get(id) {
+            Some(entry) => {
+                entry.handle.send(message)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 接続を強制的に切断する。該当する接続がなければ false
+    pub fn kick(&self, id: ConnectionId, code: u16, reason: &str) -> Result<bool> {
+        if !(1000..=4999).contains(&code) {
+            return Err(Error::Protocol(format!("invalid close code: {}", code)));
+        }
+
+        match self.registry.get(id) {
+            Some(entry) => {
+                entry.handle.terminate(code, reason)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 管理用のHTTPエンドポイントを別スレッドで立ち上げる。loopbackからのリクエストだけを受け付ける
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> std::io::Result<JoinHandle<()>> {
+        self.serve_with(addr, None)
+    }
+
+    /// `serve` と同じだが、`Authorization: Bearer <token>` が一致するリクエストだけを受け付ける。
+    /// loopback以外からも使える
+    pub fn serve_with_token<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        token: &str,
+    ) -> std::io::Result<JoinHandle<()>> {
+        self.serve_with(addr, Some(token.to_string()))
+    }
+
+    fn serve_with<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        token: Option<String>,
+    ) -> std::io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        let admin = self.clone();
+
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+                let response = match Request::read_from(&mut stream) {
+                    Ok(request) => match authorize(&request, peer, token.as_deref()) {
+                        Ok(()) => admin.route(&request),
+                        Err(response) => response,
+                    },
+                    Err(_) => http::response("400 Bad Request", "text/plain", ""),
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        }))
+    }
+
+    fn route(&self, request: &Request) -> String {
+        let (path, query) = http::split_query(&request.path);
+        let segments = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>();
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["connections"]) => {
+                http::response("200 OK", "application/json", &self.connections_json())
+            }
+            ("GET", ["connections", id]) => {
+                let Ok(id) = id.parse::<ConnectionId>() else {
+                    return http::response("400 Bad Request", "text/plain", "invalid id");
+                };
+                match self.connection(id) {
+                    Some(info) => http::response(
+                        "200 OK",
+                        "application/json",
+                        &connection_json(&info, SystemTime::now()),
+                    ),
+                    None => http::response("404 Not Found", "text/plain", ""),
+                }
+            }
+            ("POST", ["connections", id, "close"]) => {
+                let Ok(id) = id.parse::<ConnectionId>() else {
+                    return http::response("400 Bad Request", "text/plain", "invalid id");
+                };
+                let code = query
+                    .iter()
+                    .find(|(k, _)| *k == "code")
+                    .and_then(|(_, v)| v.parse::<u16>().ok())
+                    .unwrap_or(1000);
+
+                match self.kick(id, code, "") {
+                    Ok(true) => http::response("204 No Content", "text/plain", ""),
+                    Ok(false) => http::response("404 Not Found", "text/plain", ""),
+                    Err(e) => http::response("400 Bad Request", "text/plain", &e.to_string()),
+                }
+            }
+            ("POST", ["drain"]) => {
+                let param = |key| query.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+                let grace = match param("grace").map(|v| v.parse::<u64>()) {
+                    None => None,
+                    Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                    Some(Err(_)) => {
+                        return http::response("400 Bad Request", "text/plain", "invalid grace")
+                    }
+                };
+                let notice = param("message").map(|text| Message::Text(http::percent_decode(text)));
+                self.drain_with(notice, grace);
+                http::response("202 Accepted", "application/json", &self.drain_json())
+            }
+            ("GET", ["metrics", "routes"]) => http::response(
+                "200 OK",
+                "application/json",
+                &labeled_json("route", &self.route_stats()),
+            ),
+            ("GET", ["metrics", "rooms"]) => http::response(
+                "200 OK",
+                "application/json",
+                &labeled_json("room", &self.room_stats()),
+            ),
+            ("GET", ["drain"]) => http::response("200 OK", "application/json", &self.drain_json()),
+            _ => http::response("404 Not Found", "text/plain", ""),
+        }
+    }
+
+    fn drain_json(&self) -> String {
+        format!(
+            "{{\"draining\":{},\"connections\":{},\"drained\":{}}}",
+            self.is_draining(),
+            self.connections().len(),
+            self.is_drained()
+        )
+    }
+
+    pub(crate) fn connections_json(&self) -> String {
+        let now = SystemTime::now();
+        let items = self
+            .connections()
+            .iter()
+            .map(|info| connection_json(info, now))
+            .collect::<Vec<_>>();
+        format!("[{}]", items.join(","))
+    }
+}
+
+/// tokenがなければloopbackからのリクエストだけ、あればBearerのtokenが一致するリクエストだけを通す。
+/// 通さない場合は返す応答
+fn authorize(
+    request: &Request,
+    peer: Option<IpAddr>,
+    token: Option<&str>,
+) -> std::result::Result<(), String> {
+    let Some(token) = token else {
+        let loopback = peer.is_some_and(|ip| match ip {
+            IpAddr::V6(v6) => v6
+                .to_ipv4_mapped()
+                .map_or(v6.is_loopback(), |v4| v4.is_loopback()),
+            ip => ip.is_loopback(),
+        });
+        return match loopback {
+            true => Ok(()),
+            false => Err(http::response("403 Forbidden", "text/plain", "")),
+        };
+    };
+    let given = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+        return Ok(());
+    }
+    Err("HTTP/1.1 401 Unauthorized\r\n\
+        WWW-Authenticate: Bearer\r\n\
+        Content-Length: 0\r\n\
+        Connection: close\r\n\
+        \r\n"
+        .to_string())
+}
+
+fn connection_json(info: &ConnectionInfo, now: SystemTime) -> String {
+    let rooms = info
+        .rooms
+        .iter()
+        .map(|room| json::string(room))
+        .collect::<Vec<_>>();
+    let ping_rtt = info
+        .stats
+        .ping_rtt
+        .map_or("null".to_string(), |rtt| rtt.as_millis().to_string());
+    format!(
+        "{{\"id\":{},\"peer\":{},\"path\":{},\"connected_at\":{},\"uptime_secs\":{},\"rooms\":[{}],\"queue_depth\":{},\"messages_in\":{},\"messages_out\":{},\"bytes_in\":{},\"bytes_out\":{},\"idle_secs\":{},\"ping_rtt_ms\":{}}}",
+        info.id,
+        json::string(&info.peer_addr.to_string()),
+        json::string(&info.path),
+        info.connected_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        now.duration_since(info.connected_at)
+            .map_or(0, |d| d.as_secs()),
+        rooms.join(","),
+        info.stats.queue_depth,
+        info.stats.messages_in,
+        info.stats.messages_out,
+        info.stats.bytes_in,
+        info.stats.bytes_out,
+        now.duration_since(info.stats.last_activity)
+            .map_or(0, |d| d.as_secs()),
+        ping_rtt,
+    )
+}
+
+/// `[{"route":"/chat","connections":3,"messages_in":10,...}]`
+fn labeled_json(key: &str, stats: &[LabeledStats]) -> String {
+    let items = stats
+        .iter()
+        .map(|stats| {
+            format!(
+                "{{\"{}\":{},\"connections\":{},\"messages_in\":{},\"messages_out\":{},\"bytes_in\":{},\"bytes_out\":{}}}",
+                key,
+                json::string(&stats.label),
+                stats.connections,
+                stats.totals.messages_in,
+                stats.totals.messages_out,
+                stats.totals.bytes_in,
+                stats.totals.bytes_out,
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", items.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: "/drain".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: authorization
+                .map(|value| ("authorization".to_string(), value.to_string()))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn status(result: std::result::Result<(), String>) -> Option<String> {
+        result
+            .err()
+            .map(|response| response.lines().next().unwrap().to_string())
+    }
+
+    #[test]
+    fn accepts_only_loopback_without_a_token() {
+        for peer in ["127.0.0.1", "127.1.2.3", "::1", "::ffff:127.0.0.1"] {
+            assert_eq!(
+                status(authorize(&request(None), peer.parse().ok(), None)),
+                None,
+                "{}",
+                peer
+            );
+        }
+        for peer in ["192.0.2.1", "::ffff:192.0.2.1", "2001:db8::1"] {
+            assert_eq!(
+                status(authorize(&request(None), peer.parse().ok(), None)),
+                Some("HTTP/1.1 403 Forbidden".to_string()),
+                "{}",
+                peer
+            );
+        }
+        assert!(authorize(&request(None), None, None).is_err());
+    }
+
+    #[test]
+    fn requires_the_bearer_token() {
+        let peer = "192.0.2.1".parse().ok();
+        assert_eq!(
+            status(authorize(
+                &request(Some("Bearer secret")),
+                peer,
+                Some("secret")
+            )),
+            None
+        );
+        for authorization in [
+            None,
+            Some("Bearer wrong"),
+            Some("Basic secret"),
+            Some("secret"),
+        ] {
+            assert_eq!(
+                status(authorize(&request(authorization), peer, Some("secret"))),
+                Some("HTTP/1.1 401 Unauthorized".to_string()),
+                "{:?}",
+                authorization
+            );
+        }
+        // tokenを設定したらloopbackからでも必要
+        assert!(authorize(&request(None), "127.0.0.1".parse().ok(), Some("secret")).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+// async/awaitで使うWebSocketクライアント
+//
+// `Client` と同じhandshake・フレームの処理を、`Runtime` (tokio・async-std・smol) のTCPの上で行う:
+//
+//   let mut client = AsyncClient::<Tokio>::connect("ws://127.0.0.1:7778/").await?;
+//   client.send(Message::Text("hello".to_string())).await?;
+//   let reply = client.recv().await?;
+//
+// 受信と送信を別のtaskで行うときは `writer` で `AsyncClientWriter` を取り出す。
+// `wss://` には対応していない (TLSが必要なら同期の `Client` を使う)
+
+use std::{
+    io,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use crate::{
+    client::{self, Url},
+    error::{Error, Result},
+    frame::{Frame, Opcode, DEFAULT_MAX_PAYLOAD_LEN},
+    handshake,
+    message::Message,
+    runtime::{self, AsyncSocket, Runtime},
+};
+
+/// `close` でサーバーのCloseを待つ時間
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// handshakeの応答のヘッダーの上限 (bytes)
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+pub struct AsyncClient<R: Runtime> {
+    socket: Arc<R::TcpStream>,
+    /// 受信したが、まだフレームとして取り出していないデータ
+    buffer: Vec<u8>,
+    writer: AsyncClientWriter<R>,
+    /// handshakeの応答のヘッダー
+    headers: Vec<(String, String)>,
+    /// サーバーから受信したCloseのstatus codeとreason
+    peer_close: Option<(u16, String)>,
+    /// サーバーからCloseを受信した (status codeがない場合も含む)
+    closed: bool,
+}
+
+/// 受信とは別のtaskから送信するためのハンドル
+pub struct AsyncClientWriter<R: Runtime> {
+    socket: Arc<R::TcpStream>,
+    /// 1つのフレームを書き終えるまで、他の書き込みを待たせる
+    lock: Arc<async_lock::Mutex<()>>,
+}
+
+impl<R: Runtime> Clone for AsyncClientWriter<R> {
+    fn clone(&self) -> Self {
+        Self {
+            socket: self.socket.clone(),
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+impl<R: Runtime> AsyncClient<R> {
+    /// サーバーに接続してhandshakeを行う
+    pub async fn connect(url: &str) -> Result<Self> {
+        Self::connect_with_protocols(url, &[]).await
+    }
+
+    /// サブプロトコルを提示して接続する。サーバーが選んだものは `protocol` で取得できる
+    pub async fn connect_with_protocols(url: &str, protocols: &[&str]) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if url.secure {
+            return Err(Error::Handshake(
+                "AsyncClient does not support wss://".to_string(),
+            ));
+        }
+        let socket = Arc::new(R::connect(&url.host, url.port).await?);
+        let mut client = Self {
+            socket: socket.clone(),
+            buffer: vec![],
+            writer: AsyncClientWriter {
+                socket,
+                lock: Arc::new(async_lock::Mutex::new(())),
+            },
+            headers: vec![],
+            peer_close: None,
+            closed: false,
+        };
+        client.handshake(&url, protocols).await?;
+        Ok(client)
+    }
+
+    async fn handshake(&mut self, url: &Url, protocols: &[&str]) -> Result<()> {
+        let (key, request) = client::handshake_request(url, protocols);
+        self.writer.write(request.as_bytes()).await?;
+
+        // 空行の後ろはサーバーが送ってきたフレームなので、bufferに残す
+        let end = loop {
+            if let Some(i) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            if self.buffer.len() > MAX_RESPONSE_HEAD {
+                return Err(Error::Handshake("response header too large".to_string()));
+            }
+            if !self.fill().await? {
+                return Err(Error::Handshake("connection closed".to_string()));
+            }
+        };
+        let head = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+        self.buffer.drain(..end);
+
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        if status_line.split(' ').nth(1) != Some("101") {
+            return Err(Error::Handshake(format!(
+                "unexpected status: {}",
+                status_line.trim()
+            )));
+        }
+        for line in lines {
+            if let Some((k, v)) = line.split_once(':') {
+                self.headers
+                    .push((k.trim().to_ascii_lowercase(), v.trim().to_string()));
+            }
+        }
+
+        if self.header("sec-websocket-accept") != Some(handshake::accept_key(&key).as_str()) {
+            return Err(Error::Handshake("invalid Sec-WebSocket-Accept".to_string()));
+        }
+        Ok(())
+    }
+
+    /// handshakeの応答のヘッダーの値 (大文字小文字は区別しない)
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// サーバーが選んだサブプロトコル
+    pub fn protocol(&self) -> Option<&str> {
+        self.header("sec-websocket-protocol")
+    }
+
+    /// サーバーからCloseを受信した
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// サーバーから受信したCloseのstatus codeとreason (`recv` が None を返した後に使う)
+    pub fn peer_close(&self) -> Option<&(u16, String)> {
+        self.peer_close.as_ref()
+    }
+
+    pub fn writer(&self) -> AsyncClientWriter<R> {
+        self.writer.clone()
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        self.writer.send(message).await
+    }
+
+    /// メッセージを1つ受信する。Pingには自動でPongを返す。
+    /// サーバーから閉じられた場合は None
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        let mut fragments: Option<(Opcode, Vec<u8>)> = None;
+
+        loop {
+            let frame = self.read_frame().await?;
+            let (opcode, payload) = match frame.opcode {
+                Opcode::Close => {
+                    self.peer_close = frame.close_code_and_reason();
+                    self.closed = true;
+                    let _ = self
+                        .writer
+                        .send_frame(Frame::new(Opcode::Close, None))
+                        .await;
+                    return Ok(None);
+                }
+                Opcode::Ping => {
+                    self.writer
+                        .send_frame(Frame::new(Opcode::Pong, Some(frame.payload)))
+                        .await?;
+                    continue;
+                }
+                Opcode::Pong => continue,
+                Opcode::Text | Opcode::Binary if !frame.fin => {
+                    fragments = Some((frame.opcode, frame.payload));
+                    continue;
+                }
+                Opcode::Text | Opcode::Binary => (frame.opcode, frame.payload),
+                Opcode::Continuation => {
+                    let (opcode, mut payload) = fragments.take().ok_or_else(|| {
+                        Error::Protocol("continuation frame without a message".to_string())
+                    })?;
+                    payload.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        fragments = Some((opcode, payload));
+                        continue;
+                    }
+                    (opcode, payload)
+                }
+            };
+
+            return Ok(Some(if opcode == Opcode::Text {
+                Message::Text(String::from_utf8(payload).map_err(|_| Error::InvalidUtf8)?)
+            } else {
+                Message::Binary(payload)
+            }));
+        }
+    }
+
+    /// Closeを送り、サーバーのCloseを受信するまで待って (最大5秒)、そのstatus codeとreasonを返す。
+    /// 待っている間に届いたメッセージは捨てる。status codeがなければ 1005
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(u16, String)> {
+        if !self.closed {
+            self.writer.send_frame(Frame::close(code, reason)).await?;
+            runtime::timeout::<R, _>(CLOSE_TIMEOUT, self.wait_close())
+                .await
+                .ok_or_else(|| Error::Io(io::ErrorKind::TimedOut.into()))??;
+        }
+        Ok(self
+            .peer_close
+            .clone()
+            .unwrap_or_else(|| (1005, String::new())))
+    }
+
+    async fn wait_close(&mut self) -> Result<()> {
+        loop {
+            let frame = self.read_frame().await?;
+            match frame.opcode {
+                Opcode::Close => {
+                    self.peer_close = frame.close_code_and_reason();
+                    self.closed = true;
+                    return Ok(());
+                }
+                Opcode::Ping => {
+                    self.writer
+                        .send_frame(Frame::new(Opcode::Pong, Some(frame.payload)))
+                        .await?
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame> {
+        loop {
+            if let Some((frame, len)) =
+                Frame::parse_with_limit(&self.buffer, DEFAULT_MAX_PAYLOAD_LEN)?
+            {
+                self.buffer.drain(..len);
+                return Ok(frame);
+            }
+            if !self.fill().await? {
+                return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    /// 受信したデータをbufferに追加する。EOFなら false
+    async fn fill(&mut self) -> Result<bool> {
+        let mut chunk = [0; 4096];
+        let n = self.socket.read(&mut chunk).await?;
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+}
+
+impl<R: Runtime> AsyncClientWriter<R> {
+    pub async fn send(&self, message: Message) -> Result<()> {
+        self.send_frame(Frame::from(message)).await
+    }
+
+    /// masking_keyは毎回ランダムに生成する
+    pub async fn send_frame(&self, frame: Frame) -> Result<()> {
+        let bytes = frame.masked(rand::random()).to_bytes();
+        self.write(&bytes).await
+    }
+
+    /// `interval` ごとにPingを送るtaskを起動する。
+    /// `AsyncClient` と全ての `AsyncClientWriter` を捨てるか、送信に失敗すると止まる
+    pub fn keepalive(&self, interval: Duration) {
+        let socket = Arc::downgrade(&self.socket);
+        let lock = Arc::downgrade(&self.lock);
+        R::spawn(async move {
+            loop {
+                R::sleep(interval).await;
+                let (Some(socket), Some(lock)) = (Weak::upgrade(&socket), Weak::upgrade(&lock))
+                else {
+                    return;
+                };
+                let writer = AsyncClientWriter::<R> { socket, lock };
+                if writer
+                    .send_frame(Frame::new(Opcode::Ping, None))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+    }
+
+    async fn write(&self, bytes: &[u8]) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        self.socket.write_all(bytes).await?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use std::{io::Write, net::TcpListener, thread};
+
+    use super::*;
+    use crate::{
+        connection::Connection, handler::Handler, handshake::Request, server::Server, testing,
+    };
+
+    /// 受信したメッセージをそのまま返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    fn spawn_echo() -> testing::TestServer {
+        testing::spawn_server(Server::bind("127.0.0.1:0", Echo).unwrap()).unwrap()
+    }
+
+    async fn echoes<R: Runtime>(url: String) {
+        let mut client = AsyncClient::<R>::connect(&url).await.unwrap();
+        client
+            .send(Message::Text("hello".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Some(Message::Text("hello".to_string()))
+        );
+
+        // 別のtaskから送っても、受信側で受け取れる
+        let writer = client.writer();
+        R::spawn(async move {
+            writer.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        });
+        assert_eq!(
+            client.recv().await.unwrap(),
+            Some(Message::Binary(vec![1, 2, 3]))
+        );
+
+        // サーバーはstatus codeのないCloseを返す
+        assert_eq!(
+            client.close(1000, "bye").await.unwrap(),
+            (1005, String::new())
+        );
+        assert!(client.is_closed());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn echoes_on_tokio() {
+        let server = spawn_echo();
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(echoes::<crate::runtime::Tokio>(server.url("/")));
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn echoes_on_async_std() {
+        let server = spawn_echo();
+        async_std::task::block_on(echoes::<crate::runtime::AsyncStd>(server.url("/")));
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn echoes_on_smol() {
+        let server = spawn_echo();
+        smol::block_on(echoes::<crate::runtime::Smol>(server.url("/")));
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn rejects_wss_urls() {
+        let result = smol::block_on(AsyncClient::<crate::runtime::Smol>::connect(
+            "wss://127.0.0.1:1/",
+        ));
+        assert!(matches!(result, Err(Error::Handshake(_))));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn keepalive_sends_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = Request::read_from(&mut stream).unwrap();
+            let key = request.header("sec-websocket-key").unwrap().to_string();
+            stream
+                .write_all(handshake::response(&key).as_bytes())
+                .unwrap();
+            Frame::read_from(&mut stream).unwrap()
+        });
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let client = AsyncClient::<crate::runtime::Tokio>::connect(&url)
+                    .await
+                    .unwrap();
+                client.writer().keepalive(Duration::from_millis(10));
+                crate::runtime::Tokio::sleep(Duration::from_millis(100)).await;
+            });
+        assert_eq!(server.join().unwrap().opcode, Opcode::Ping);
+    }
+}
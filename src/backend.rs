@@ -0,0 +1,106 @@
+// Hubのpublishを他のサーバーと共有するためのブローカー
+//
+// ブローカーには以下の形式でメッセージを流す:
+// 送信元のnode id (u64 BE) | 種類 (1: Text / 2: Binary) | payload
+// 自分がpublishしたメッセージはローカルへ配送済みなので、受信したときに読み飛ばす
+//
+// ブローカーとの接続が切れたら、待ち時間を倍にしながら (最大30秒) 再接続してsubscribeし直す。
+// 切断中にpublishされたメッセージは他のサーバーには届かない
+
+mod nats;
+mod redis;
+
+pub use nats::NatsBackend;
+pub use redis::RedisBackend;
+
+use std::{
+    io::BufRead,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+/// ブローカーから受信するpayloadの最大のbyte数。長さを読んだ時点で確かめ、超えたら切断する
+pub(crate) const MAX_PAYLOAD_LEN: usize = 64 * 1024 * 1024;
+
+/// 他のサーバーから届いたメッセージをローカルの接続へ配送する (room, message)
+pub type Deliver = Box<dyn Fn(&str, Message) + Send + 'static>;
+
+pub trait Backend: Send + Sync + 'static {
+    /// 他のサーバーからのメッセージの受信を始める。Hubの作成時に1度だけ呼ばれる
+    fn subscribe(&self, deliver: Deliver) -> Result<()>;
+
+    /// roomへのpublishを他のサーバーへ流す。
+    /// 失敗してもローカルへの配送は続けるので、エラーは返さない
+    fn publish(&self, room: &str, message: &Message);
+}
+
+pub(crate) fn encode(node_id: u64, message: &Message) -> Vec<u8> {
+    let kind = match message {
+        Message::Text(_) => 1,
+        Message::Binary(_) => 2,
+    };
+    let mut bytes = node_id.to_be_bytes().to_vec();
+    bytes.push(kind);
+    bytes.extend_from_slice(message.as_bytes());
+    bytes
+}
+
+pub(crate) fn decode(bytes: Vec<u8>) -> Option<(u64, Message)> {
+    if bytes.len() < 9 {
+        return None;
+    }
+    let node_id = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+    let payload = bytes[9..].to_vec();
+    let message = match bytes[8] {
+        1 => Message::Text(String::from_utf8(payload).ok()?),
+        2 => Message::Binary(payload),
+        _ => return None,
+    };
+    Some((node_id, message))
+}
+
+/// 1行読んで末尾の CRLF を取り除く
+pub(crate) fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(line.trim_end_matches("\r\n").to_string())
+}
+
+/// 再接続の間隔。失敗するたびに倍にする
+pub(crate) struct Backoff {
+    delay: Duration,
+    next: Instant,
+}
+
+impl Backoff {
+    const MIN: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(30);
+
+    pub(crate) fn new() -> Self {
+        Self {
+            delay: Self::MIN,
+            next: Instant::now(),
+        }
+    }
+
+    /// 失敗したので、次に試すのを今の間隔だけ遅らせる
+    pub(crate) fn failed(&mut self) {
+        self.next = Instant::now() + self.delay;
+        self.delay = (self.delay * 2).min(Self::MAX);
+    }
+
+    pub(crate) fn succeeded(&mut self) {
+        self.delay = Self::MIN;
+    }
+
+    /// 次に試せるまでの時間
+    pub(crate) fn remaining(&self) -> Duration {
+        self.next.saturating_duration_since(Instant::now())
+    }
+}
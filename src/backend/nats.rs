@@ -0,0 +1,278 @@
+// NATSを使うBackend。roomごとにsubject `<prefix>.<room>` を使う
+//
+// https://docs.nats.io/reference/reference-protocols/nats-protocol
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use super::{decode, encode, read_line, Backend, Backoff, Deliver, MAX_PAYLOAD_LEN};
+use crate::{
+    error::{Error, Result},
+    log::{info, warning},
+    message::Message,
+};
+
+pub struct NatsBackend {
+    addr: String,
+    /// 切断している間は None。受信のスレッドが再接続して入れ直す
+    writer: Arc<Mutex<Option<TcpStream>>>,
+    reader: Mutex<Option<BufReader<TcpStream>>>,
+    prefix: String,
+    node_id: u64,
+}
+
+impl NatsBackend {
+    /// addrは `host:port`。prefixはsubjectの先頭のトークン (例: `websocket-rs`)
+    pub fn connect(addr: &str, prefix: &str) -> Result<Self> {
+        let (writer, reader) = handshake(addr)?;
+        Ok(Self {
+            addr: addr.to_string(),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            reader: Mutex::new(Some(reader)),
+            prefix: prefix.to_string(),
+            node_id: rand::random(),
+        })
+    }
+
+    fn subject(&self, room: &str) -> String {
+        format!("{}.{}", self.prefix, room)
+    }
+}
+
+impl Backend for NatsBackend {
+    /// publishと同じ接続でsubscribeし、バックグラウンドのスレッドで受信する。
+    /// 切断されたら再接続してSUBし直す
+    fn subscribe(&self, deliver: Deliver) -> Result<()> {
+        let mut reader = self
+            .reader
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| Error::Protocol("already subscribed".to_string()))?;
+        let sub = format!("SUB {}.> 1\r\n", self.prefix);
+        if let Some(writer) = &mut *self.writer.lock().unwrap() {
+            writer.write_all(sub.as_bytes())?;
+        }
+
+        let addr = self.addr.clone();
+        let writer = self.writer.clone();
+        let node_id = self.node_id;
+        let prefix_len = self.prefix.len() + 1;
+        thread::spawn(move || {
+            let mut backoff = Backoff::new();
+            loop {
+                let e = receive(
+                    &mut reader,
+                    &writer,
+                    node_id,
+                    prefix_len,
+                    &deliver,
+                    &mut backoff,
+                );
+                warning!("nats_disconnected", { error: e.to_string() }, "nats subscriber disconnected: {}", e);
+                *writer.lock().unwrap() = None;
+                reader = loop {
+                    backoff.failed();
+                    thread::sleep(backoff.remaining());
+                    let connected = handshake(&addr).and_then(|(mut stream, reader)| {
+                        stream.write_all(sub.as_bytes())?;
+                        Ok((stream, reader))
+                    });
+                    match connected {
+                        Ok((stream, reader)) => {
+                            *writer.lock().unwrap() = Some(stream);
+                            break reader;
+                        }
+                        Err(e) => {
+                            warning!("nats_reconnect_failed", { error: e.to_string() }, "nats: failed to reconnect: {}", e)
+                        }
+                    }
+                };
+                info!("nats_reconnected", { addr: addr.as_str() }, "nats subscriber reconnected to {}", addr);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn publish(&self, room: &str, message: &Message) {
+        let subject = self.subject(room);
+        // subjectに空白は使えない
+        if subject.contains(char::is_whitespace) {
+            warning!("nats_error", { room: room }, "nats: invalid room name: {:?}", room);
+            return;
+        }
+        let payload = encode(self.node_id, message);
+
+        let mut writer = self.writer.lock().unwrap();
+        // 再接続するまでは捨てる
+        let Some(stream) = &mut *writer else {
+            return;
+        };
+        let result = write!(stream, "PUB {} {}\r\n", subject, payload.len())
+            .and_then(|_| stream.write_all(&payload))
+            .and_then(|_| stream.write_all(b"\r\n"));
+        if let Err(e) = result {
+            // 受信のスレッドに切断を気付かせて再接続させる
+            let _ = stream.shutdown(Shutdown::Both);
+            *writer = None;
+            warning!("nats_error", { error: e.to_string() }, "nats: {}", e);
+        }
+    }
+}
+
+/// 接続してINFOを読み、CONNECTを送る
+fn handshake(addr: &str) -> Result<(TcpStream, BufReader<TcpStream>)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let info = read_line(&mut reader)?;
+    if !info.starts_with("INFO ") {
+        return Err(Error::Protocol(format!(
+            "unexpected nats greeting: {}",
+            info
+        )));
+    }
+
+    stream.write_all(
+        b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"websocket-rs\"}\r\n",
+    )?;
+    Ok((stream, reader))
+}
+
+/// 切断されるまでMSGを受信して配送し、切断された理由を返す
+fn receive<R: BufRead>(
+    reader: &mut R,
+    writer: &Mutex<Option<TcpStream>>,
+    node_id: u64,
+    prefix_len: usize,
+    deliver: &Deliver,
+    backoff: &mut Backoff,
+) -> Error {
+    loop {
+        let (subject, payload) = match read_message(reader, writer) {
+            Ok(message) => message,
+            Err(e) => return e,
+        };
+        backoff.succeeded();
+        let Some((from, message)) = decode(payload) else {
+            continue;
+        };
+        if from == node_id {
+            continue;
+        }
+        deliver(&subject[prefix_len.min(subject.len())..], message);
+    }
+}
+
+/// 次のMSGを読む。途中のPINGには応答する。戻り値はsubjectとpayload
+fn read_message<R: BufRead>(
+    reader: &mut R,
+    writer: &Mutex<Option<TcpStream>>,
+) -> Result<(String, Vec<u8>)> {
+    loop {
+        let line = read_line(reader)?;
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            Some("MSG") => {
+                let parts = parts.collect::<Vec<_>>();
+                let (Some(subject), Some(len)) = (parts.first(), parts.last()) else {
+                    return Err(Error::Protocol(format!("invalid nats message: {}", line)));
+                };
+                let len: usize = len
+                    .parse()
+                    .map_err(|_| Error::Protocol(format!("invalid nats message: {}", line)))?;
+                if len > MAX_PAYLOAD_LEN {
+                    return Err(Error::Protocol(format!(
+                        "nats message too large: {} bytes",
+                        len
+                    )));
+                }
+                let mut payload = vec![0; len + 2];
+                reader.read_exact(&mut payload)?;
+                payload.truncate(len);
+                return Ok((subject.to_string(), payload));
+            }
+            Some("PING") => {
+                if let Some(writer) = &mut *writer.lock().unwrap() {
+                    writer.write_all(b"PONG\r\n")?;
+                }
+            }
+            Some("-ERR") => warning!("nats_error", { error: line.as_str() }, "nats: {}", line),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, sync::mpsc, time::Duration};
+
+    use super::*;
+
+    /// INFOを送り、CONNECTとSUBを読む
+    fn accept(listener: &TcpListener) -> (TcpStream, BufReader<TcpStream>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"INFO {}\r\n").unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        assert!(read_line(&mut reader).unwrap().starts_with("CONNECT "));
+        (stream, reader)
+    }
+
+    fn msg(subject: &str, text: &str) -> Vec<u8> {
+        let payload = encode(1, &Message::Text(text.to_string()));
+        let mut bytes = format!("MSG {} 1 {}\r\n", subject, payload.len()).into_bytes();
+        bytes.extend(payload);
+        bytes.extend(b"\r\n");
+        bytes
+    }
+
+    #[test]
+    fn resubscribes_and_publishes_after_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let nats = thread::spawn(move || {
+            let (mut stream, mut reader) = accept(&listener);
+            assert_eq!(read_line(&mut reader).unwrap(), "SUB ws.> 1");
+            stream.write_all(&msg("ws.lobby", "first")).unwrap();
+            drop((stream, reader));
+            // 大きすぎるpayloadの長さを送ると、確保せずに切断して再接続する
+            let (mut stream, mut reader) = accept(&listener);
+            assert_eq!(read_line(&mut reader).unwrap(), "SUB ws.> 1");
+            stream.write_all(b"MSG ws.lobby 1 99999999999\r\n").unwrap();
+            let (mut stream, mut reader) = accept(&listener);
+            assert_eq!(read_line(&mut reader).unwrap(), "SUB ws.> 1");
+            stream.write_all(&msg("ws.lobby", "second")).unwrap();
+            // 再接続した接続でpublishする
+            let line = read_line(&mut reader).unwrap();
+            let len: usize = line.strip_prefix("PUB ws.lobby ").unwrap().parse().unwrap();
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload).unwrap();
+            payload
+        });
+
+        let backend = NatsBackend::connect(&addr, "ws").unwrap();
+        let (tx, rx) = mpsc::channel();
+        backend
+            .subscribe(Box::new(move |room, message| {
+                let _ = tx.send((room.to_string(), message));
+            }))
+            .unwrap();
+        for text in ["first", "second"] {
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+                ("lobby".to_string(), Message::Text(text.to_string()))
+            );
+        }
+        backend.publish("lobby", &Message::Text("hello".to_string()));
+        assert_eq!(
+            decode(nats.join().unwrap()).unwrap().1,
+            Message::Text("hello".to_string())
+        );
+    }
+}
@@ -0,0 +1,354 @@
+// Redis pub/subを使うBackend。roomごとにchannel `<prefix><room>` を使う
+//
+// RESP: https://redis.io/docs/latest/develop/reference/protocol-spec/
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::Mutex,
+    thread,
+};
+
+use super::{decode, encode, read_line, Backend, Backoff, Deliver, MAX_PAYLOAD_LEN};
+use crate::{
+    error::{Error, Result},
+    log::{info, warning},
+    message::Message,
+};
+
+/// 1つの応答の配列の最大の要素数。pmessageは4つ
+const MAX_ARRAY_LEN: i64 = 1024;
+
+pub struct RedisBackend {
+    addr: String,
+    publisher: Mutex<Publisher>,
+    prefix: String,
+    node_id: u64,
+}
+
+/// publish用の接続。切断したら、次のpublishで間隔を空けて再接続する
+struct Publisher {
+    stream: Option<BufReader<TcpStream>>,
+    backoff: Backoff,
+}
+
+impl RedisBackend {
+    /// addrは `host:port`
+    pub fn connect(addr: &str, prefix: &str) -> Result<Self> {
+        Ok(Self {
+            addr: addr.to_string(),
+            publisher: Mutex::new(Publisher {
+                stream: Some(BufReader::new(TcpStream::connect(addr)?)),
+                backoff: Backoff::new(),
+            }),
+            prefix: prefix.to_string(),
+            node_id: rand::random(),
+        })
+    }
+}
+
+impl Backend for RedisBackend {
+    /// subscribe用にもう1本接続を張り、バックグラウンドのスレッドで受信する。
+    /// 切断されたら再接続してPSUBSCRIBEし直す
+    fn subscribe(&self, deliver: Deliver) -> Result<()> {
+        let pattern = format!("{}*", self.prefix);
+        let mut subscriber = psubscribe(&self.addr, &pattern)?;
+
+        let addr = self.addr.clone();
+        let node_id = self.node_id;
+        let prefix_len = self.prefix.len();
+        thread::spawn(move || {
+            let mut backoff = Backoff::new();
+            loop {
+                let e = receive(&mut subscriber, node_id, prefix_len, &deliver, &mut backoff);
+                warning!("redis_disconnected", { error: e.to_string() }, "redis subscriber disconnected: {}", e);
+                subscriber = loop {
+                    backoff.failed();
+                    thread::sleep(backoff.remaining());
+                    match psubscribe(&addr, &pattern) {
+                        Ok(subscriber) => break subscriber,
+                        Err(e) => {
+                            warning!("redis_reconnect_failed", { error: e.to_string() }, "redis: failed to reconnect: {}", e)
+                        }
+                    }
+                };
+                info!("redis_reconnected", { addr: addr.as_str() }, "redis subscriber reconnected to {}", addr);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn publish(&self, room: &str, message: &Message) {
+        let channel = format!("{}{}", self.prefix, room);
+        let payload = encode(self.node_id, message);
+
+        let mut publisher = self.publisher.lock().unwrap();
+        let result = match publisher.connect(&self.addr) {
+            Ok(Some(stream)) => command(
+                stream.get_mut(),
+                &[b"PUBLISH", channel.as_bytes(), &payload],
+            )
+            .and_then(|_| read_reply(stream)),
+            // 再接続の間隔が空くまでは捨てる
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(Reply::Error(e)) => warning!("redis_error", { error: e.as_str() }, "redis: {}", e),
+            Err(e) => {
+                publisher.disconnected();
+                warning!("redis_error", { error: e.to_string() }, "redis: {}", e)
+            }
+            Ok(_) => publisher.backoff.succeeded(),
+        }
+    }
+}
+
+impl Publisher {
+    /// 切断していれば再接続する。前に失敗してから間隔が空いていなければ None
+    fn connect(&mut self, addr: &str) -> Result<Option<&mut BufReader<TcpStream>>> {
+        if self.stream.is_none() {
+            if !self.backoff.remaining().is_zero() {
+                return Ok(None);
+            }
+            self.stream = Some(BufReader::new(TcpStream::connect(addr)?));
+        }
+        Ok(self.stream.as_mut())
+    }
+
+    fn disconnected(&mut self) {
+        self.stream = None;
+        self.backoff.failed();
+    }
+}
+
+/// subscribe用の接続を張り、`pattern` をPSUBSCRIBEする
+fn psubscribe(addr: &str, pattern: &str) -> Result<BufReader<TcpStream>> {
+    let mut subscriber = BufReader::new(TcpStream::connect(addr)?);
+    command(subscriber.get_mut(), &[b"PSUBSCRIBE", pattern.as_bytes()])?;
+    // PSUBSCRIBEの応答
+    read_reply(&mut subscriber)?;
+    Ok(subscriber)
+}
+
+/// 切断されるまでpmessageを受信して配送し、切断された理由を返す
+fn receive<R: BufRead>(
+    subscriber: &mut R,
+    node_id: u64,
+    prefix_len: usize,
+    deliver: &Deliver,
+    backoff: &mut Backoff,
+) -> Error {
+    loop {
+        let reply = match read_reply(subscriber) {
+            Ok(reply) => reply,
+            Err(e) => return e,
+        };
+        backoff.succeeded();
+        // ["pmessage", pattern, channel, payload]
+        let Reply::Array(mut items) = reply else {
+            continue;
+        };
+        if items.len() != 4 {
+            continue;
+        }
+        let (Reply::Bulk(Some(payload)), Reply::Bulk(Some(channel))) =
+            (items.pop().unwrap(), items.pop().unwrap())
+        else {
+            continue;
+        };
+        let Some((from, message)) = decode(payload) else {
+            continue;
+        };
+        if from == node_id {
+            continue;
+        }
+        let room = String::from_utf8_lossy(&channel[prefix_len.min(channel.len())..]);
+        deliver(&room, message);
+    }
+}
+
+enum Reply {
+    Status,
+    Error(String),
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+fn command(stream: &mut TcpStream, args: &[&[u8]]) -> Result<()> {
+    let mut bytes = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        bytes.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        bytes.extend_from_slice(arg);
+        bytes.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&bytes)?;
+    Ok(())
+}
+
+fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply> {
+    let line = read_line(reader)?;
+    let invalid = || Error::Protocol(format!("invalid redis reply: {:?}", line));
+    let (kind, rest) = line.split_at_checked(1).ok_or_else(invalid)?;
+
+    match kind {
+        "+" => Ok(Reply::Status),
+        "-" => Ok(Reply::Error(rest.to_string())),
+        ":" => rest
+            .parse::<i64>()
+            .map(|_| Reply::Integer)
+            .map_err(|_| invalid()),
+        "$" => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            if len > MAX_PAYLOAD_LEN as i64 {
+                return Err(Error::Protocol(format!(
+                    "redis reply too large: {} bytes",
+                    len
+                )));
+            }
+            let mut bytes = vec![0; len as usize + 2];
+            reader.read_exact(&mut bytes)?;
+            bytes.truncate(len as usize);
+            Ok(Reply::Bulk(Some(bytes)))
+        }
+        "*" => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            if len > MAX_ARRAY_LEN {
+                return Err(Error::Protocol(format!(
+                    "redis reply too large: {} items",
+                    len
+                )));
+            }
+            (0..len.max(0))
+                .map(|_| read_reply(reader))
+                .collect::<Result<Vec<_>>>()
+                .map(Reply::Array)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::mpsc, time::Duration};
+
+    use super::*;
+
+    fn pmessage(channel: &str, message: Message) -> Vec<u8> {
+        let payload = encode(1, &message);
+        let mut bytes = format!(
+            "*4\r\n$8\r\npmessage\r\n$4\r\nws:*\r\n${}\r\n{}\r\n${}\r\n",
+            channel.len(),
+            channel,
+            payload.len()
+        )
+        .into_bytes();
+        bytes.extend(payload);
+        bytes.extend(b"\r\n");
+        bytes
+    }
+
+    /// 受け付けた接続からコマンドを1つ読む
+    fn read_command(stream: &TcpStream) -> Vec<Vec<u8>> {
+        let Reply::Array(args) = read_reply(&mut BufReader::new(stream)).unwrap() else {
+            panic!("expected a command");
+        };
+        args.into_iter()
+            .map(|arg| match arg {
+                Reply::Bulk(Some(arg)) => arg,
+                _ => panic!("expected a bulk string"),
+            })
+            .collect()
+    }
+
+    fn accept_psubscribe(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(read_command(&stream), [&b"PSUBSCRIBE"[..], b"ws:*"]);
+        stream
+            .write_all(b"*3\r\n$10\r\npsubscribe\r\n$4\r\nws:*\r\n:1\r\n")
+            .unwrap();
+        stream
+    }
+
+    #[test]
+    fn resubscribes_after_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let backend = RedisBackend::connect(&addr, "ws:").unwrap();
+        let _publisher = listener.accept().unwrap();
+        let redis = thread::spawn(move || {
+            let mut stream = accept_psubscribe(&listener);
+            stream
+                .write_all(&pmessage("ws:lobby", Message::Text("first".to_string())))
+                .unwrap();
+            drop(stream);
+            // 大きすぎるbulk stringの長さを送ると、確保せずに切断して再接続する
+            let mut stream = accept_psubscribe(&listener);
+            stream
+                .write_all(
+                    b"*4\r\n$8\r\npmessage\r\n$4\r\nws:*\r\n$8\r\nws:lobby\r\n$99999999999\r\n",
+                )
+                .unwrap();
+            let mut stream = accept_psubscribe(&listener);
+            stream
+                .write_all(&pmessage("ws:lobby", Message::Text("second".to_string())))
+                .unwrap();
+            stream
+        });
+
+        let (tx, rx) = mpsc::channel();
+        backend
+            .subscribe(Box::new(move |room, message| {
+                let _ = tx.send((room.to_string(), message));
+            }))
+            .unwrap();
+        for text in ["first", "second"] {
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+                ("lobby".to_string(), Message::Text(text.to_string()))
+            );
+        }
+        let _stream = redis.join().unwrap();
+    }
+
+    #[test]
+    fn reconnects_the_publisher() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend =
+            RedisBackend::connect(&listener.local_addr().unwrap().to_string(), "ws:").unwrap();
+        drop(listener.accept().unwrap());
+        // 切断されていたので捨てられ、次は間隔を空けてから再接続する
+        backend.publish("lobby", &Message::Text("lost".to_string()));
+        backend.publish("lobby", &Message::Text("too early".to_string()));
+        thread::sleep(Duration::from_millis(200));
+
+        let redis = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let command = read_command(&stream);
+            stream.write_all(b":1\r\n").unwrap();
+            command
+        });
+        backend.publish("lobby", &Message::Text("hello".to_string()));
+        let command = redis.join().unwrap();
+        assert_eq!(command[..2], [&b"PUBLISH"[..], b"ws:lobby"]);
+        assert_eq!(
+            decode(command[2].clone()).unwrap().1,
+            Message::Text("hello".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_replies_too_large_to_allocate() {
+        for reply in [&b"$99999999999\r\n"[..], b"*99999999999\r\n"] {
+            assert!(matches!(
+                read_reply(&mut &reply[..]),
+                Err(Error::Protocol(_))
+            ));
+        }
+    }
+}
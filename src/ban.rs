@@ -0,0 +1,231 @@
+// 迷惑な接続元の一時的な拒否
+//
+// プロトコル違反 (不正なhandshake・フレーム)、認証の失敗 (401・403)、レート制限の超過 (429) を
+// 送信元のIPアドレスごとに数え、`window` の間に `max_strikes` 回に達したアドレスからの接続を
+// `cooldown` の間、handshakeの前に切断する
+//
+// 期限の切れた記録は、`window` と `cooldown` の短い方ごとに1度だけまとめて捨てる
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::log::info;
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    /// 数え始めた時刻と、それからの違反の回数
+    window_start: Instant,
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Entry {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            strikes: 0,
+            banned_until: None,
+        }
+    }
+
+    /// 拒否の期限か、数える期間が過ぎた
+    fn is_expired(&self, now: Instant, window: Duration) -> bool {
+        match self.banned_until {
+            Some(until) => until <= now,
+            None => now.duration_since(self.window_start) >= window,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<IpAddr, Entry>,
+    /// 次に期限の切れたものを捨てる時刻
+    next_prune: Option<Instant>,
+}
+
+pub struct BanList {
+    max_strikes: u32,
+    window: Duration,
+    cooldown: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl BanList {
+    pub fn new(max_strikes: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            max_strikes,
+            window,
+            cooldown,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .map
+            .get(&ip)
+            .and_then(|entry| entry.banned_until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// 違反を1回記録する。回数に達したら拒否を始め、true を返す
+    pub fn strike(&self, ip: IpAddr, reason: &str) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        self.prune(&mut entries, now);
+
+        let entry = entries.map.entry(ip).or_insert(Entry::new(now));
+        if entry.is_expired(now, self.window) {
+            *entry = Entry::new(now);
+        }
+        if entry.banned_until.is_some() {
+            return false;
+        }
+        entry.strikes += 1;
+        if entry.strikes < self.max_strikes {
+            return false;
+        }
+        entry.banned_until = Some(now + self.cooldown);
+        info!(
+            "peer_banned",
+            {
+                peer: ip.to_string(),
+                strikes: entry.strikes as u64,
+                reason: reason,
+                cooldown_secs: self.cooldown.as_secs(),
+            },
+            "banned {} for {:?} after {} violations (last: {})",
+            ip,
+            self.cooldown,
+            entry.strikes,
+            reason
+        );
+        true
+    }
+
+    /// 前に捨ててから `window` と `cooldown` の短い方が過ぎていれば、期限の切れたものを捨てる。
+    /// 違反のたびに全体を見ないので、記録の数が多くてもロックを長く持たない
+    fn prune(&self, entries: &mut Entries, now: Instant) {
+        if entries.next_prune.is_some_and(|next| now < next) {
+            return;
+        }
+        entries
+            .map
+            .retain(|_, entry| !entry.is_expired(now, self.window));
+        entries.next_prune = Some(now + self.window.min(self.cooldown));
+    }
+
+    /// `duration` の間、拒否する
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let now = Instant::now();
+        self.entries.lock().unwrap().map.insert(
+            ip,
+            Entry {
+                window_start: now,
+                strikes: 0,
+                banned_until: Some(now + duration),
+            },
+        );
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        self.entries.lock().unwrap().map.remove(&ip);
+    }
+
+    /// 拒否しているアドレスと、拒否が終わるまでの時間
+    pub fn banned(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .map
+            .iter()
+            .filter_map(|(ip, entry)| {
+                let until = entry.banned_until?;
+                (now < until).then(|| (*ip, until - now))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bans_after_max_strikes() {
+        let bans = BanList::new(3, Duration::from_secs(60), Duration::from_secs(60));
+        let peer = ip("192.0.2.1");
+        assert!(!bans.strike(peer, "invalid frame"));
+        assert!(!bans.strike(peer, "invalid frame"));
+        assert!(!bans.is_banned(peer));
+        assert!(bans.strike(peer, "invalid frame"));
+        assert!(bans.is_banned(peer));
+        // 拒否している間の違反では、もう一度 true を返さない
+        assert!(!bans.strike(peer, "invalid frame"));
+        // 他のアドレスは別に数える
+        assert!(!bans.is_banned(ip("192.0.2.2")));
+        assert!(!bans.strike(ip("192.0.2.2"), "401"));
+
+        let banned = bans.banned();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].0, peer);
+        assert!(banned[0].1 <= Duration::from_secs(60));
+        bans.unban(peer);
+        assert!(!bans.is_banned(peer));
+        assert!(!bans.strike(peer, "invalid frame"));
+    }
+
+    #[test]
+    fn forgets_strikes_after_the_window() {
+        let bans = BanList::new(2, Duration::from_millis(50), Duration::from_secs(60));
+        let peer = ip("2001:db8::1");
+        assert!(!bans.strike(peer, "429"));
+        thread::sleep(Duration::from_millis(80));
+        // 数える期間が過ぎたので、1回目から数え直す
+        assert!(!bans.strike(peer, "429"));
+        assert!(bans.strike(peer, "429"));
+    }
+
+    #[test]
+    fn lifts_bans_after_the_cooldown() {
+        let bans = BanList::new(1, Duration::from_secs(60), Duration::from_millis(50));
+        let peer = ip("192.0.2.1");
+        assert!(bans.strike(peer, "403"));
+        assert!(bans.is_banned(peer));
+        thread::sleep(Duration::from_millis(80));
+        assert!(!bans.is_banned(peer));
+        assert!(bans.banned().is_empty());
+        assert!(bans.strike(peer, "403"));
+
+        bans.ban(ip("192.0.2.9"), Duration::from_millis(50));
+        assert!(bans.is_banned(ip("192.0.2.9")));
+        thread::sleep(Duration::from_millis(80));
+        assert!(!bans.is_banned(ip("192.0.2.9")));
+    }
+
+    #[test]
+    fn prunes_expired_entries_once_per_interval() {
+        let bans = BanList::new(5, Duration::from_millis(50), Duration::from_secs(60));
+        for i in 0..100u8 {
+            bans.strike(IpAddr::from([192, 0, 2, i]), "invalid frame");
+        }
+        assert_eq!(bans.entries.lock().unwrap().map.len(), 100);
+        thread::sleep(Duration::from_millis(80));
+        // 期間が過ぎてから最初の違反で、期限の切れたものをまとめて捨てる
+        bans.strike(ip("198.51.100.1"), "invalid frame");
+        assert_eq!(bans.entries.lock().unwrap().map.len(), 1);
+    }
+}
@@ -0,0 +1,221 @@
+// サーバーの負荷試験用クライアント
+//
+// 使い方:
+// ws-bench [url] [--connections N] [--rate R] [--size BYTES] [--duration SECS]
+//
+// N本の接続それぞれから、1秒あたりR回、BYTESバイトのTextメッセージを送信し、
+// スループットと応答までのレイテンシ (パーセンタイル) を表示する。
+// 送信するメッセージの先頭には連番が入っており、
+// 同じ連番で始まる応答が返ってきた時点でレイテンシを計測する
+
+use std::{
+    collections::HashMap,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use websocket_rs::{Client, Frame, Message};
+
+struct Options {
+    url: String,
+    connections: usize,
+    rate: u64,
+    size: usize,
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct Report {
+    sent: u64,
+    received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    latencies: Vec<Duration>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        url: "ws://127.0.0.1:7778/".to_string(),
+        connections: 10,
+        rate: 10,
+        size: 64,
+        duration: Duration::from_secs(10),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("{} requires a value", name))
+        };
+        match arg.as_str() {
+            "--connections" => {
+                options.connections = value(&arg)?.parse().map_err(|e| format!("{}", e))?
+            }
+            "--rate" => options.rate = value(&arg)?.parse().map_err(|e| format!("{}", e))?,
+            "--size" => options.size = value(&arg)?.parse().map_err(|e| format!("{}", e))?,
+            "--duration" => {
+                options.duration =
+                    Duration::from_secs(value(&arg)?.parse().map_err(|e| format!("{}", e))?)
+            }
+            url if !url.starts_with("--") => options.url = url.to_string(),
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+
+    if options.rate == 0 {
+        return Err("--rate must be greater than 0".to_string());
+    }
+    Ok(options)
+}
+
+/// 1本の接続で送受信を行い、結果を返す
+fn run(options: &Options) -> websocket_rs::Result<Report> {
+    let mut client = Client::connect(&options.url)?;
+    let writer = client.writer();
+
+    // 連番 -> 送信時刻
+    let in_flight = Arc::new(Mutex::new(HashMap::<u64, Instant>::new()));
+    let report = Arc::new(Mutex::new(Report::default()));
+
+    let receiver = {
+        let in_flight = in_flight.clone();
+        let report = report.clone();
+        thread::spawn(move || {
+            while let Ok(Some(message)) = client.recv() {
+                let sent_at = match &message {
+                    Message::Text(text) => text
+                        .split(' ')
+                        .next()
+                        .and_then(|seq| seq.parse::<u64>().ok())
+                        .and_then(|seq| in_flight.lock().unwrap().remove(&seq)),
+                    Message::Binary(_) => None,
+                };
+
+                let mut report = report.lock().unwrap();
+                report.received += 1;
+                report.bytes_received += message.len() as u64;
+                if let Some(sent_at) = sent_at {
+                    report.latencies.push(sent_at.elapsed());
+                }
+            }
+        })
+    };
+
+    let interval = Duration::from_nanos(1_000_000_000 / options.rate);
+    let started_at = Instant::now();
+    let mut seq = 0;
+    while started_at.elapsed() < options.duration {
+        let mut text = format!("{} ", seq);
+        text.push_str(&"x".repeat(options.size.saturating_sub(text.len())));
+
+        in_flight.lock().unwrap().insert(seq, Instant::now());
+        writer.send(Message::Text(text.clone()))?;
+        {
+            let mut report = report.lock().unwrap();
+            report.sent += 1;
+            report.bytes_sent += text.len() as u64;
+        }
+
+        seq += 1;
+        let next = started_at + interval * seq as u32;
+        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+
+    // 遅れて届く応答を少しだけ待ってから閉じる
+    thread::sleep(Duration::from_secs(1));
+    let _ = writer.send_frame(Frame::close(1000, ""));
+    let _ = receiver.join();
+
+    let report = std::mem::take(&mut *report.lock().unwrap());
+    Ok(report)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "usage: ws-bench [url] [--connections N] [--rate R] [--size BYTES] [--duration SECS]"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!(
+        "{}: {} connections, {} msg/s each, {} bytes, {}s",
+        options.url,
+        options.connections,
+        options.rate,
+        options.size,
+        options.duration.as_secs()
+    );
+
+    let started_at = Instant::now();
+    let workers = (0..options.connections)
+        .map(|_| {
+            let options = options.clone();
+            thread::spawn(move || run(&options))
+        })
+        .collect::<Vec<_>>();
+
+    let mut total = Report::default();
+    let mut failed = 0;
+    for worker in workers {
+        match worker.join().unwrap() {
+            Ok(report) => {
+                total.sent += report.sent;
+                total.received += report.received;
+                total.bytes_sent += report.bytes_sent;
+                total.bytes_received += report.bytes_received;
+                total.latencies.extend(report.latencies);
+            }
+            Err(e) => {
+                eprintln!("connection failed: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = started_at.elapsed().as_secs_f64();
+
+    total.latencies.sort();
+    println!("connections failed: {}", failed);
+    println!(
+        "sent:     {} messages ({:.1} msg/s, {:.1} bytes/s)",
+        total.sent,
+        total.sent as f64 / elapsed,
+        total.bytes_sent as f64 / elapsed
+    );
+    println!(
+        "received: {} messages ({:.1} msg/s, {:.1} bytes/s)",
+        total.received,
+        total.received as f64 / elapsed,
+        total.bytes_received as f64 / elapsed
+    );
+    println!(
+        "latency:  p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(&total.latencies, 0.50),
+        percentile(&total.latencies, 0.90),
+        percentile(&total.latencies, 0.99),
+        total.latencies.last().copied().unwrap_or_default()
+    );
+
+    if failed == options.connections {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,89 @@
+// 標準入力と標準出力をWebSocketにつなぐクライアント (websocat のようなもの)
+//
+// 使い方:
+// ws-cat <url> [--binary] [--protocol <protocol>]
+//
+// 標準入力の1行を1つのTextメッセージとして送り (--binary ならBinaryメッセージ)、
+// 受信したメッセージを標準出力に書く (Textは1行ずつ、Binaryはそのまま)。
+// 標準入力が終わったらCloseを送り、サーバーから閉じられたら終了する
+//
+// 例: echo hello | ws-cat ws://127.0.0.1:7778/
+
+use std::{
+    io::{self, BufRead, Write},
+    process::ExitCode,
+    thread,
+};
+
+use websocket_rs::{frame::Frame, Client, Message};
+
+fn main() -> ExitCode {
+    let mut url = None;
+    let mut binary = false;
+    let mut protocols = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--binary" => binary = true,
+            "--protocol" => match args.next() {
+                Some(protocol) => protocols.push(protocol),
+                None => return usage(),
+            },
+            _ if url.is_none() => url = Some(arg),
+            _ => return usage(),
+        }
+    }
+    let Some(url) = url else {
+        return usage();
+    };
+
+    let protocols = protocols.iter().map(String::as_str).collect::<Vec<_>>();
+    let mut client = match Client::connect_with_protocols(&url, &protocols) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", url, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // 標準入力 -> WebSocket
+    let writer = client.writer();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let message = if binary {
+                Message::Binary(line.into_bytes())
+            } else {
+                Message::Text(line)
+            };
+            if writer.send(message).is_err() {
+                return;
+            }
+        }
+        let _ = writer.send_frame(Frame::close(1000, ""));
+    });
+
+    // WebSocket -> 標準出力
+    let mut stdout = io::stdout().lock();
+    loop {
+        let written = match client.recv() {
+            Ok(Some(Message::Text(text))) => writeln!(stdout, "{}", text),
+            Ok(Some(Message::Binary(bytes))) => stdout.write_all(&bytes),
+            Ok(None) => return ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        if written.and_then(|_| stdout.flush()).is_err() {
+            return ExitCode::FAILURE;
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: ws-cat <url> [--binary] [--protocol <protocol>]");
+    ExitCode::FAILURE
+}
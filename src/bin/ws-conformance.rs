@@ -0,0 +1,389 @@
+// フレームのcodecの差分テスト
+//
+// 使い方:
+// ws-conformance [--count N] [--seed S]
+//
+// 境界値 (payloadの長さ 0, 125, 126, 65535, 65536 など) と、seedから生成したランダムなフレームのコーパスを作り、
+// このクレートのcodec (`Frame::to_bytes`・`Frame::read_from`・`Frame::parse`) と tungstenite のcodecの両方に通して、
+// 食い違いを表示する。フレームは片方でエンコードしたものをもう片方でデコードし、元のフレームに戻ることも確かめる。
+// ランダムなバイト列 (壊れたヘッダーを含む) も両方でデコードし、受け付けるか・拒否するかを比べる。
+//
+// tungstenite のcodecは以下の点が異なるので、比べる前にそろえる:
+// - Controlフレームの規則 (分割しない・payloadは125bytes以下) はcodecではなく `WebSocket` が確かめる
+// - 予約済みのopcodeは、ヘッダーを (masking keyまで) 読み終えてから拒否する
+//
+// `conformance` featureが必要 (cargo run --features conformance --bin ws-conformance)
+
+use std::{io::Cursor, process::ExitCode};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tungstenite::protocol::frame::{coding::OpCode, Frame as TungsteniteFrame, FrameHeader};
+use websocket_rs::{Frame, Opcode};
+
+struct Options {
+    count: usize,
+    seed: u64,
+}
+
+/// 生成するフレーム
+#[derive(Clone, Debug)]
+struct Case {
+    fin: bool,
+    /// RSV1..3 (0b100 = RSV1)
+    rsv: u8,
+    opcode: u8,
+    masking_key: Option<[u8; 4]>,
+    payload: Vec<u8>,
+}
+
+/// デコードした結果。どちらのcodecの結果もこの形にして比べる
+#[derive(Debug, PartialEq)]
+enum Decoded {
+    Frame {
+        fin: bool,
+        rsv: u8,
+        opcode: u8,
+        masking_key: Option<[u8; 4]>,
+        payload: Vec<u8>,
+        len: usize,
+    },
+    /// フレームの途中まで
+    Incomplete,
+    Invalid,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        count: 10000,
+        seed: 0,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("{} requires a value", name))
+        };
+        match arg.as_str() {
+            "--count" => options.count = value(&arg)?.parse().map_err(|e| format!("{}", e))?,
+            "--seed" => options.seed = value(&arg)?.parse().map_err(|e| format!("{}", e))?,
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// tungstenite でエンコードする
+fn tungstenite_encode(case: &Case) -> Vec<u8> {
+    let header = FrameHeader {
+        is_final: case.fin,
+        rsv1: case.rsv & 0b100 != 0,
+        rsv2: case.rsv & 0b010 != 0,
+        rsv3: case.rsv & 0b001 != 0,
+        opcode: OpCode::from(case.opcode),
+        mask: case.masking_key,
+    };
+    let mut bytes = vec![];
+    TungsteniteFrame::from_payload(header, case.payload.clone().into())
+        .format(&mut bytes)
+        .expect("writing to a Vec does not fail");
+    bytes
+}
+
+/// tungstenite でデコードする
+fn tungstenite_decode(bytes: &[u8]) -> Decoded {
+    let mut cursor = Cursor::new(bytes);
+    let (header, len) = match FrameHeader::parse(&mut cursor) {
+        Ok(Some(header)) => header,
+        Ok(None) => return incomplete_header(bytes),
+        Err(_) => return Decoded::Invalid,
+    };
+    if violates_control_rules(&header, len) {
+        return Decoded::Invalid;
+    }
+    let offset = cursor.position() as usize;
+    if ((bytes.len() - offset) as u64) < len {
+        return Decoded::Incomplete;
+    }
+    let end = offset + len as usize;
+    let mut payload = bytes[offset..end].to_vec();
+    if let Some(key) = header.mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= key[i % 4];
+        }
+    }
+    Decoded::Frame {
+        fin: header.is_final,
+        rsv: (header.rsv1 as u8) << 2 | (header.rsv2 as u8) << 1 | header.rsv3 as u8,
+        opcode: u8::from(header.opcode),
+        masking_key: header.mask,
+        payload,
+        len: end,
+    }
+}
+
+/// 途中までのヘッダーを、残りを0で埋めて読み終えたら拒否されるかどうか
+fn incomplete_header(bytes: &[u8]) -> Decoded {
+    let mut padded = bytes.to_vec();
+    padded.resize(14, 0);
+    match FrameHeader::parse(&mut Cursor::new(&padded)) {
+        Err(_) => Decoded::Invalid,
+        Ok(Some((header, len))) if violates_control_rules(&header, len) => Decoded::Invalid,
+        Ok(_) => Decoded::Incomplete,
+    }
+}
+
+/// tungstenite の `WebSocket` がフレームを受け取ってから確かめるControlフレームの規則 (RFC 6455 5.5)
+fn violates_control_rules(header: &FrameHeader, len: u64) -> bool {
+    matches!(header.opcode, OpCode::Control(_)) && (!header.is_final || len > 125)
+}
+
+/// このクレートの `Frame` を比べる形にする
+fn from_frame(frame: &Frame, len: usize) -> Decoded {
+    Decoded::Frame {
+        fin: frame.fin,
+        rsv: (frame.rsv1 as u8) << 2 | (frame.rsv2 as u8) << 1 | frame.rsv3 as u8,
+        opcode: u8::from(frame.opcode),
+        masking_key: frame.masking_key,
+        payload: frame.payload.clone(),
+        len,
+    }
+}
+
+fn opcode(byte: u8) -> Opcode {
+    Opcode::try_from(byte).unwrap()
+}
+
+/// 境界値のフレーム
+fn edge_cases() -> Vec<Case> {
+    let mut cases = vec![];
+    for opcode in [0x0, 0x1, 0x2, 0x8, 0x9, 0xA] {
+        let lengths: &[usize] = if opcode >= 0x8 {
+            &[0, 1, 2, 124, 125]
+        } else {
+            &[0, 1, 125, 126, 127, 65535, 65536, 70000]
+        };
+        for &len in lengths {
+            for masking_key in [None, Some([0x12, 0x34, 0x56, 0x78]), Some([0; 4])] {
+                for rsv in [0, 0b100, 0b111] {
+                    cases.push(Case {
+                        fin: true,
+                        rsv,
+                        opcode,
+                        masking_key,
+                        payload: (0..len).map(|i| i as u8).collect(),
+                    });
+                }
+            }
+        }
+    }
+    cases
+}
+
+fn random_case(rng: &mut StdRng) -> Case {
+    let opcode = [0x0, 0x1, 0x2, 0x8, 0x9, 0xA][rng.gen_range(0..6)];
+    let len = if opcode >= 0x8 {
+        rng.gen_range(0..=125)
+    } else {
+        // 長さの区切りの前後を多めに選ぶ
+        match rng.gen_range(0..4) {
+            0 => rng.gen_range(120..=130),
+            1 => rng.gen_range(65530..=65540),
+            _ => rng.gen_range(0..2048),
+        }
+    };
+    Case {
+        fin: opcode >= 0x8 || rng.gen(),
+        rsv: rng.gen_range(0..8),
+        opcode,
+        masking_key: rng.gen::<bool>().then(|| rng.gen()),
+        payload: (0..len).map(|_| rng.gen()).collect(),
+    }
+}
+
+/// ランダムなヘッダーと短いpayload。予約済みのopcodeや不正なControlフレーム、途中で切れたものを含む
+fn random_bytes(rng: &mut StdRng) -> Vec<u8> {
+    let mut bytes = vec![rng.gen(), rng.gen()];
+    // 長さは小さめにして、大きな長さを宣言したフレームは途中までにする
+    match bytes[1] & 0x7F {
+        126 => bytes.extend([0, rng.gen()]),
+        127 => bytes.extend([0, 0, 0, 0, 0, 0, rng.gen_range(0..2), rng.gen()]),
+        _ => {}
+    }
+    let extra = rng.gen_range(0..300);
+    bytes.extend((0..extra).map(|_| rng.gen::<u8>()));
+    bytes
+}
+
+/// 1つのフレームを両方でエンコード・デコードし、食い違いを返す
+fn check_case(case: &Case) -> Vec<String> {
+    let mut divergences = vec![];
+    let mut builder = Frame::builder()
+        .opcode(opcode(case.opcode))
+        .fin(case.fin)
+        .rsv1(case.rsv & 0b100 != 0)
+        .rsv2(case.rsv & 0b010 != 0)
+        .rsv3(case.rsv & 0b001 != 0)
+        .payload(case.payload.clone());
+    if let Some(key) = case.masking_key {
+        builder = builder.mask(key);
+    }
+    let encoded = builder.build().to_bytes();
+    let expected = tungstenite_encode(case);
+    if encoded != expected {
+        divergences.push(format!(
+            "encode: header {:02x?} != tungstenite {:02x?}",
+            &encoded[..encoded.len().min(14)],
+            &expected[..expected.len().min(14)]
+        ));
+    }
+
+    // このクレートでエンコードしたものを tungstenite で、tungstenite でエンコードしたものをこのクレートでデコードし、
+    // どちらも元のフレームに戻ること
+    let original = Decoded::Frame {
+        fin: case.fin,
+        rsv: case.rsv,
+        opcode: case.opcode,
+        masking_key: case.masking_key,
+        payload: case.payload.clone(),
+        len: expected.len(),
+    };
+    let decoded = tungstenite_decode(&encoded);
+    if decoded != original {
+        divergences.push(format!(
+            "tungstenite decoding our frame: {} != {}",
+            summary(&decoded),
+            summary(&original)
+        ));
+    }
+    let decoded = match Frame::read_from(&mut expected.as_slice()) {
+        Ok(frame) => from_frame(&frame, expected.len()),
+        Err(_) => Decoded::Invalid,
+    };
+    if decoded != original {
+        divergences.push(format!(
+            "read_from of a tungstenite frame: {} != {}",
+            summary(&decoded),
+            summary(&original)
+        ));
+    }
+    let parsed = parse(&expected);
+    if parsed != original {
+        divergences.push(format!(
+            "parse of a tungstenite frame: {} != {}",
+            summary(&parsed),
+            summary(&original)
+        ));
+    }
+
+    // 1 byte足りなければ、どちらも途中までとみなす
+    let truncated = &expected[..expected.len() - 1];
+    let (parsed, theirs) = (parse(truncated), tungstenite_decode(truncated));
+    if parsed != Decoded::Incomplete || theirs != Decoded::Incomplete {
+        divergences.push(format!(
+            "truncated frame: {} (tungstenite: {})",
+            summary(&parsed),
+            summary(&theirs)
+        ));
+    }
+    divergences
+}
+
+fn check_bytes(bytes: &[u8]) -> Vec<String> {
+    let parsed = parse(bytes);
+    let theirs = tungstenite_decode(bytes);
+    if parsed != theirs {
+        return vec![format!(
+            "parse {:02x?}: {} != tungstenite {}",
+            &bytes[..bytes.len().min(10)],
+            summary(&parsed),
+            summary(&theirs)
+        )];
+    }
+    vec![]
+}
+
+fn parse(bytes: &[u8]) -> Decoded {
+    match Frame::parse(bytes) {
+        Ok(Some((frame, len))) => from_frame(&frame, len),
+        Ok(None) => Decoded::Incomplete,
+        Err(_) => Decoded::Invalid,
+    }
+}
+
+fn summary(decoded: &Decoded) -> String {
+    match decoded {
+        Decoded::Frame {
+            fin,
+            rsv,
+            opcode,
+            masking_key,
+            payload,
+            len,
+        } => format!(
+            "frame(fin: {}, rsv: {:#05b}, opcode: {:#x}, masked: {}, payload: {} bytes, consumed: {})",
+            fin,
+            rsv,
+            opcode,
+            masking_key.is_some(),
+            payload.len(),
+            len
+        ),
+        Decoded::Incomplete => "incomplete".to_string(),
+        Decoded::Invalid => "invalid".to_string(),
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: ws-conformance [--count N] [--seed S]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut cases = edge_cases();
+    cases.extend((0..options.count).map(|_| random_case(&mut rng)));
+
+    let mut failures = 0;
+    for case in &cases {
+        let divergences = check_case(case);
+        if !divergences.is_empty() {
+            failures += 1;
+            println!(
+                "opcode {:#x}, fin {}, rsv {:#05b}, masked {}, {} bytes:",
+                case.opcode,
+                case.fin,
+                case.rsv,
+                case.masking_key.is_some(),
+                case.payload.len()
+            );
+            for divergence in divergences {
+                println!("  {}", divergence);
+            }
+        }
+    }
+    for _ in 0..options.count {
+        let bytes = random_bytes(&mut rng);
+        for divergence in check_bytes(&bytes) {
+            failures += 1;
+            println!("{}", divergence);
+        }
+    }
+
+    println!(
+        "{} frames and {} byte strings checked (seed {}), {} divergences",
+        cases.len(),
+        options.count,
+        options.seed,
+        failures
+    );
+    if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,46 @@
+// Journal (--journal で記録したディレクトリ) の中身を表示するツール
+//
+// 使い方:
+// ws-journal <dir>             -> 記録されたメッセージを全て表示する
+// ws-journal <dir> --room <room> -> 指定したroomのメッセージだけ表示する
+
+use std::{process::ExitCode, time::UNIX_EPOCH};
+
+use websocket_rs::{journal, Message};
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let (dir, room) = match args.as_slice() {
+        [dir] => (dir, None),
+        [dir, flag, room] if flag == "--room" => (dir, Some(room)),
+        _ => {
+            eprintln!("usage: ws-journal <dir> [--room <room>]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let entries = match journal::read(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", dir, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for entry in entries
+        .iter()
+        .filter(|entry| room.is_none_or(|room| *room == entry.room))
+    {
+        let time = entry
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        match &entry.message {
+            Message::Text(text) => println!("{:.6} {} text {:?}", time, entry.room, text),
+            Message::Binary(bytes) => println!("{:.6} {} binary {:02x?}", time, entry.room, bytes),
+        }
+    }
+
+    ExitCode::SUCCESS
+}
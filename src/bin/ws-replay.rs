@@ -0,0 +1,115 @@
+// 記録したセッション (.wsrec) を再生するツール
+//
+// 使い方:
+// ws-replay <file>              -> 記録されたフレームをデコードして表示する
+// ws-replay <file> --send <url> -> 受信側で記録されたフレームを、記録時と同じ間隔でサーバーに送り直す
+
+use std::{
+    process::ExitCode,
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use websocket_rs::{
+    record::{self, Direction, Record},
+    Client,
+};
+
+fn print(record: &Record) {
+    let direction = match record.direction {
+        Direction::Inbound => "<",
+        Direction::Outbound => ">",
+    };
+
+    match record.frame() {
+        Ok(frame) => {
+            let preview = &frame.payload[..frame.payload.len().min(64)];
+            println!(
+                "+{:>10.3}ms {} {:?} fin={} rsv={}{}{} len={} {:?}",
+                record.elapsed.as_secs_f64() * 1000.0,
+                direction,
+                frame.opcode,
+                frame.fin,
+                frame.rsv1 as u8,
+                frame.rsv2 as u8,
+                frame.rsv3 as u8,
+                frame.payload_len,
+                String::from_utf8_lossy(preview),
+            );
+        }
+        Err(e) => println!(
+            "+{:>10.3}ms {} <undecodable: {}> {:02x?}",
+            record.elapsed.as_secs_f64() * 1000.0,
+            direction,
+            e,
+            record.bytes
+        ),
+    }
+}
+
+fn send(url: &str, records: &[Record]) -> websocket_rs::Result<()> {
+    let mut client = Client::connect(url)?;
+    let writer = client.writer();
+
+    // サーバーからの応答は表示するだけ
+    thread::spawn(move || {
+        while let Ok(Some(message)) = client.recv() {
+            println!("received: {:?}", message);
+        }
+    });
+
+    let started_at = Instant::now();
+    for record in records.iter().filter(|r| r.direction == Direction::Inbound) {
+        if let Some(wait) = record.elapsed.checked_sub(started_at.elapsed()) {
+            thread::sleep(wait);
+        }
+        print(record);
+        writer.send_frame(record.frame()?)?;
+    }
+
+    // 最後の応答を待つ
+    thread::sleep(Duration::from_secs(1));
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let (path, url) = match args.as_slice() {
+        [path] => (path, None),
+        [path, flag, url] if flag == "--send" => (path, Some(url)),
+        _ => {
+            eprintln!("usage: ws-replay <file> [--send <url>]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (started_at, records) = match record::read(path) {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match url {
+        None => {
+            println!(
+                "recorded at {}s since epoch, {} frames",
+                started_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                records.len()
+            );
+            records.iter().for_each(print);
+        }
+        Some(url) => {
+            if let Err(e) = send(url, &records) {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
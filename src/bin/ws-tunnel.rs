@@ -0,0 +1,33 @@
+// ローカルのTCPポートで受け付けた接続を、WebSocketでサーバーにトンネルするツール
+//
+// 使い方:
+// ws-tunnel <listen-addr> <url>
+//
+// 例: サーバーを `--tunnel /ssh=127.0.0.1:22` で起動しておき、
+// ws-tunnel 127.0.0.1:2222 ws://example.com:7778/ssh
+// ssh -p 2222 127.0.0.1
+
+use std::{net::TcpListener, process::ExitCode};
+
+use websocket_rs::tunnel;
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let [listen, url] = args.as_slice() else {
+        eprintln!("usage: ws-tunnel <listen-addr> <url>");
+        return ExitCode::FAILURE;
+    };
+
+    let listener = match TcpListener::bind(listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("failed to listen on {}: {}", listen, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = tunnel::forward(listener, url) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
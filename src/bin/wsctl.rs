@@ -0,0 +1,59 @@
+// 実行中のサーバーを制御ソケット経由で操作するCLI
+//
+// 使い方:
+// wsctl [--socket <path>] stats
+// wsctl [--socket <path>] connections [--json]
+// wsctl [--socket <path>] kick <id> [code]
+// wsctl [--socket <path>] log-level [level]
+// wsctl [--socket <path>] drain [grace_secs] [message]
+// wsctl [--socket <path>] drain-status
+
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    process::ExitCode,
+};
+
+const DEFAULT_SOCKET: &str = "/tmp/websocket-rs.sock";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    let mut socket = DEFAULT_SOCKET.to_string();
+    if args.first().map(String::as_str) == Some("--socket") {
+        if args.len() < 2 {
+            eprintln!("--socket requires a path");
+            return ExitCode::FAILURE;
+        }
+        socket = args.remove(1);
+        args.remove(0);
+    }
+
+    if args.is_empty() {
+        eprintln!("usage: wsctl [--socket <path>] <stats|connections|kick|log-level|drain|drain-status> [args]");
+        return ExitCode::FAILURE;
+    }
+
+    let mut stream = match UnixStream::connect(&socket) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", socket, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut response = String::new();
+    let result =
+        writeln!(stream, "{}", args.join(" ")).and_then(|_| stream.read_to_string(&mut response));
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    print!("{}", response);
+    if response.starts_with("error:") {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,117 @@
+// 非同期のHTTPサーバーから `Upgrader` への橋渡し
+//
+// actix-web・HTTP/2 (h2)・WebTransport で受け付けたWebSocketは、TCP接続をそのまま引き渡せないので、
+// メモリ上のパイプ (`testing::Pipe`) の片方を `Upgrader::upgrade` に渡す。もう片方は:
+//
+//   - HTTPサーバーが受信したバイト列 (フレーム) を `pipe` に書き込む。書き込みは待たない
+//   - Upgraderが書き込んだ応答 (101のヘッダーとフレーム) は、スレッドで読み込んで `chunks` に送る
+//
+// 101の応答のヘッダーは `response` で取り出し、それぞれのHTTPの応答に置き換える
+
+use std::{
+    io::{self, Read},
+    net::{Shutdown, SocketAddr},
+    thread,
+};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::{
+    handler::Handler,
+    handshake::Request,
+    server::Upgrader,
+    stream::Stream,
+    testing::{self, Pipe},
+};
+
+pub(crate) struct Bridge {
+    /// 書き込んだバイト列は接続のスレッドが読み込む
+    pub(crate) pipe: Pipe,
+    /// 接続のスレッドが書き込んだバイト列
+    pub(crate) chunks: UnboundedReceiver<Vec<u8>>,
+}
+
+/// Upgraderが書き込んだ応答のstatus codeとヘッダー
+pub(crate) struct ResponseHead {
+    pub(crate) status: u16,
+    /// webtransportは応答をstreamのリセットにするので、ヘッダーとbodyは使わない
+    #[cfg_attr(not(any(feature = "actix", feature = "http2")), allow(dead_code))]
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl Bridge {
+    /// `request` を `upgrader` に渡す。middlewareなどで拒否された場合も、その応答を `response` で受け取る
+    pub(crate) fn upgrade<H: Handler>(
+        upgrader: &Upgrader<H>,
+        request: Request,
+        peer_addr: SocketAddr,
+    ) -> io::Result<Self> {
+        let (pipe, conn) = testing::pipe();
+        let mut reader = pipe.try_clone()?;
+        let (sender, chunks) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("bridge".to_string())
+            .spawn(move || {
+                let mut buf = [0; 16 * 1024];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => {
+                            // 応答を送れなくなったら (クライアントが切断したら) 接続も閉じる
+                            if sender.send(buf[..n].to_vec()).is_err() {
+                                let _ = reader.shutdown(Shutdown::Both);
+                                return;
+                            }
+                        }
+                    }
+                }
+            })?;
+        // 拒否した場合も応答を書き込んでいるので、ここではエラーを見ない
+        let _ = upgrader.upgrade(conn.with_peer_addr(peer_addr), request);
+        Ok(Self { pipe, chunks })
+    }
+
+    /// 応答のヘッダーと、その後ろに続いて読み込んだバイト列。ヘッダーの前に閉じられたら None
+    pub(crate) async fn response(&mut self) -> Option<(ResponseHead, Vec<u8>)> {
+        let mut received = Vec::new();
+        let end = loop {
+            if let Some(i) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            received.extend_from_slice(&self.chunks.recv().await?);
+        };
+        let head = String::from_utf8_lossy(&received[..end]).into_owned();
+        let mut lines = head.split("\r\n");
+        let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Some((ResponseHead { status, headers }, received.split_off(end)))
+    }
+
+    /// 拒否の応答は書き込んだ後に閉じるので、bodyの残りを最後まで読み込む
+    #[cfg_attr(not(any(feature = "actix", feature = "http2")), allow(dead_code))]
+    pub(crate) async fn read_to_end(&mut self, body: &mut Vec<u8>) {
+        while let Some(chunk) = self.chunks.recv().await {
+            body.extend_from_slice(&chunk);
+        }
+    }
+}
+
+impl ResponseHead {
+    /// 接続の扱いとbodyの長さのヘッダーを除いたもの。それらはHTTPサーバーが決める
+    #[cfg_attr(not(any(feature = "actix", feature = "http2")), allow(dead_code))]
+    pub(crate) fn end_to_end_headers(&self) -> impl Iterator<Item = &(String, String)> {
+        self.headers.iter().filter(|(key, _)| {
+            ![
+                "connection",
+                "upgrade",
+                "content-length",
+                "transfer-encoding",
+            ]
+            .iter()
+            .any(|skip| key.eq_ignore_ascii_case(skip))
+        })
+    }
+}
@@ -0,0 +1,36 @@
+// クライアントの再接続処理などを試すための障害注入
+//
+// 有効にすると、サーバーから送信するフレームに対して
+// 遅延・重複・Pongの欠落・突然の切断を意図的に発生させる
+
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default)]
+pub struct Chaos {
+    /// Text/Binaryフレームを送信する前に挟む遅延
+    pub latency: Duration,
+    /// Text/Binaryフレームを2回送信する確率 (0.0 ~ 1.0)
+    pub duplicate: f64,
+    /// Pingに対してPongを返さない確率 (0.0 ~ 1.0)
+    pub drop_pong: f64,
+    /// Text/Binaryフレームを送信する代わりに、Closeを送らずにTCP接続を切断する確率 (0.0 ~ 1.0)
+    pub reset: f64,
+}
+
+impl Chaos {
+    pub(crate) fn should_duplicate(&self) -> bool {
+        roll(self.duplicate)
+    }
+
+    pub(crate) fn should_drop_pong(&self) -> bool {
+        roll(self.drop_pong)
+    }
+
+    pub(crate) fn should_reset(&self) -> bool {
+        roll(self.reset)
+    }
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::random::<f64>() < probability
+}
@@ -0,0 +1,487 @@
+// WebSocketクライアントの実装
+//
+// 以下のようなリクエストを送る:
+// GET /chat HTTP/1.1
+// Host: 127.0.0.1:7778
+// Upgrade: websocket
+// Connection: Upgrade
+// Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+// Sec-WebSocket-Version: 13
+//
+// `wss://` は `tls` featureを有効にすると接続できる。TLSの設定 (CA・ピン留めなど) は `connect_with_tls` で指定する
+
+use base64::{engine::general_purpose, Engine as _};
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(all(unix, feature = "reactor"))]
+use std::os::fd::{AsRawFd, RawFd};
+
+#[cfg(all(unix, feature = "reactor"))]
+use crate::reactor;
+#[cfg(feature = "tls")]
+use crate::tls::{self, ClientTlsConfig, TlsInfo};
+use crate::{
+    error::{Error, Result},
+    frame::{Frame, Opcode, DEFAULT_MAX_PAYLOAD_LEN},
+    handshake, happy_eyeballs,
+    message::Message,
+    stream::Stream,
+};
+
+pub struct Client {
+    /// `ws://` ならTCP、`wss://` ならTLSの接続
+    reader: BufReader<Box<dyn Stream>>,
+    /// `AsRawFd`・`is_readable` に使うTCPのソケット
+    #[cfg(all(unix, feature = "reactor"))]
+    fd: RawFd,
+    writer: ClientWriter,
+    /// handshakeの応答のヘッダー
+    headers: Vec<(String, String)>,
+    /// サーバーから受信したCloseのstatus codeとreason
+    peer_close: Option<(u16, String)>,
+    /// サーバーからCloseを受信した (status codeがない場合も含む)
+    closed: bool,
+}
+
+/// `Client::try_recv` の結果
+#[derive(Clone, Debug, PartialEq)]
+pub enum TryRecv {
+    Message(Message),
+    /// 受信済みのメッセージがない
+    Empty,
+    /// サーバーから閉じられた
+    Closed,
+}
+
+/// `close` でサーバーのCloseを待つ時間
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 受信とは別のスレッドから送信するためのハンドル
+#[derive(Clone)]
+pub struct ClientWriter {
+    stream: Arc<Mutex<Box<dyn Stream>>>,
+}
+
+/// `ws://host:port/path`・`wss://host:port/path` を分解したもの
+#[derive(Clone, Debug, PartialEq)]
+pub struct Url {
+    /// `wss://`
+    pub secure: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self> {
+        let (secure, rest) = match (url.strip_prefix("ws://"), url.strip_prefix("wss://")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => return Err(Error::Handshake(format!("unsupported url: {}", url))),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| Error::Handshake(format!("invalid port: {}", port)))?,
+            ),
+            None if secure => (authority, 443),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(Error::Handshake(format!("missing host: {}", url)));
+        }
+
+        Ok(Self {
+            secure,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Client {
+    /// サーバーに接続してhandshakeを行う
+    pub fn connect(url: &str) -> Result<Self> {
+        Self::connect_with_protocols(url, &[])
+    }
+
+    /// サブプロトコルを提示して接続する。サーバーが選んだものは `protocol` で取得できる。
+    /// `wss://` なら `ClientTlsConfig::new()` (webpki-roots のCA) で検証する
+    pub fn connect_with_protocols(url: &str, protocols: &[&str]) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if url.secure {
+            #[cfg(feature = "tls")]
+            return Self::connect_url_with_tls(&url, protocols, &ClientTlsConfig::new());
+            #[cfg(not(feature = "tls"))]
+            return Err(Error::Handshake(
+                "wss:// requires the tls feature".to_string(),
+            ));
+        }
+        let stream = happy_eyeballs::connect(&url.host, url.port)?;
+        Self::handshake_on(stream, &url, protocols, |stream| Ok(Box::new(stream)))
+    }
+
+    /// `wss://` のURLに、`tls` の設定 (CA・ピン留め・クライアント証明書など) で接続する
+    #[cfg(feature = "tls")]
+    pub fn connect_with_tls(url: &str, protocols: &[&str], tls: &ClientTlsConfig) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if !url.secure {
+            return Err(Error::Handshake(format!(
+                "tls requires a wss:// url: {}:{}",
+                url.host, url.port
+            )));
+        }
+        Self::connect_url_with_tls(&url, protocols, tls)
+    }
+
+    #[cfg(feature = "tls")]
+    fn connect_url_with_tls(url: &Url, protocols: &[&str], tls: &ClientTlsConfig) -> Result<Self> {
+        let stream = happy_eyeballs::connect(&url.host, url.port)?;
+        Self::handshake_on(stream, url, protocols, |stream| {
+            Ok(Box::new(tls::connect(stream, &url.host, tls)?))
+        })
+    }
+
+    /// アドレスを指定して接続する (IPv6のアドレスなど、URLにしにくい場合)
+    #[cfg(feature = "server")]
+    pub(crate) fn connect_addr(addr: SocketAddr, path: &str) -> Result<Self> {
+        let url = Url {
+            secure: false,
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            path: path.to_string(),
+        };
+        Self::handshake_on(TcpStream::connect(addr)?, &url, &[], |stream| {
+            Ok(Box::new(stream))
+        })
+    }
+
+    /// `wrap` で接続したソケットをTLSなどのstreamにしてからhandshakeを行う
+    fn handshake_on(
+        socket: TcpStream,
+        url: &Url,
+        protocols: &[&str],
+        wrap: impl FnOnce(TcpStream) -> std::io::Result<Box<dyn Stream>>,
+    ) -> Result<Self> {
+        #[cfg(all(unix, feature = "reactor"))]
+        let fd = socket.as_raw_fd();
+        let stream = wrap(socket)?;
+        let mut client = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            #[cfg(all(unix, feature = "reactor"))]
+            fd,
+            writer: ClientWriter {
+                stream: Arc::new(Mutex::new(stream)),
+            },
+            headers: vec![],
+            peer_close: None,
+            closed: false,
+        };
+        client.handshake(url, protocols)?;
+        Ok(client)
+    }
+
+    fn handshake(&mut self, url: &Url, protocols: &[&str]) -> Result<()> {
+        let (key, request) = handshake_request(url, protocols);
+        self.writer.write(request.as_bytes())?;
+
+        // handshake直後にサーバーがフレームを送ってくることがあるので、
+        // 空行までを1行ずつ読む
+        let mut status_line = String::new();
+        self.reader.read_line(&mut status_line)?;
+        if status_line.split(' ').nth(1) != Some("101") {
+            return Err(Error::Handshake(format!(
+                "unexpected status: {}",
+                status_line.trim()
+            )));
+        }
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Error::Handshake("connection closed".to_string()));
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                self.headers
+                    .push((k.trim().to_ascii_lowercase(), v.trim().to_string()));
+            }
+        }
+
+        if self.header("sec-websocket-accept") != Some(handshake::accept_key(&key).as_str()) {
+            return Err(Error::Handshake("invalid Sec-WebSocket-Accept".to_string()));
+        }
+        Ok(())
+    }
+
+    /// handshakeの応答のヘッダーの値 (大文字小文字は区別しない)
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// サーバーが再開可能なセッションを発行していれば、そのトークン。
+    /// 再接続するときは `?session=<token>` を付ける
+    pub fn session_token(&self) -> Option<&str> {
+        self.header("session-token")
+    }
+
+    /// サーバーが選んだサブプロトコル
+    pub fn protocol(&self) -> Option<&str> {
+        self.header("sec-websocket-protocol")
+    }
+
+    /// `wss://` の接続なら、ALPNで合意したプロトコルとサーバーの証明書
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<TlsInfo> {
+        self.reader.get_ref().tls_info()
+    }
+
+    /// サーバーからCloseを受信した
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// サーバーから受信したCloseのstatus codeとreason (`recv` が None を返した後に使う)
+    pub fn peer_close(&self) -> Option<&(u16, String)> {
+        self.peer_close.as_ref()
+    }
+
+    pub fn writer(&self) -> ClientWriter {
+        self.writer.clone()
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        self.writer.send(message)
+    }
+
+    /// メッセージを1つ受信する。Pingには自動でPongを返す。
+    /// サーバーから閉じられた場合は None
+    pub fn recv(&mut self) -> Result<Option<Message>> {
+        match self.read_message(true)? {
+            TryRecv::Message(message) => Ok(Some(message)),
+            TryRecv::Closed | TryRecv::Empty => Ok(None),
+        }
+    }
+
+    /// 受信済みのデータがあればメッセージを1つ受信し、なければ待たずに `TryRecv::Empty` を返す。
+    /// フレームやfragmentの途中までしか届いていない場合は、メッセージの終わりまで待つ
+    #[cfg(all(unix, feature = "reactor"))]
+    pub fn try_recv(&mut self) -> Result<TryRecv> {
+        self.read_message(false)
+    }
+
+    /// 読み込めるデータ (EOFを含む) があるか。待たずに返す。
+    /// ソケットのブロッキングのモードは変えないので、`ClientWriter` で書き込んでいるスレッドに影響しない
+    #[cfg(all(unix, feature = "reactor"))]
+    pub fn is_readable(&self) -> Result<bool> {
+        if self.has_buffered() {
+            return Ok(true);
+        }
+        Ok(reactor::is_readable(self.fd)?)
+    }
+
+    /// 受信したが、まだメッセージとして取り出していないデータがあるか
+    #[cfg(all(unix, feature = "reactor"))]
+    pub(crate) fn has_buffered(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.reader.get_ref().has_buffered() {
+            return true;
+        }
+        !self.reader.buffer().is_empty()
+    }
+
+    /// `wait` が false なら、メッセージの区切りで読み込めるデータがなければ Empty を返す
+    fn read_message(&mut self, wait: bool) -> Result<TryRecv> {
+        #[cfg(not(all(unix, feature = "reactor")))]
+        let _ = wait;
+        let mut fragments: Option<(Opcode, Vec<u8>)> = None;
+
+        loop {
+            #[cfg(all(unix, feature = "reactor"))]
+            if !wait && fragments.is_none() && !self.is_readable()? {
+                return Ok(TryRecv::Empty);
+            }
+            let frame = Frame::read_from_with_limit(&mut self.reader, DEFAULT_MAX_PAYLOAD_LEN)?;
+            let (opcode, payload) = match frame.opcode {
+                Opcode::Close => {
+                    self.peer_close = frame.close_code_and_reason();
+                    self.closed = true;
+                    let _ = self.writer.send_frame(Frame::new(Opcode::Close, None));
+                    return Ok(TryRecv::Closed);
+                }
+                Opcode::Ping => {
+                    self.writer
+                        .send_frame(Frame::new(Opcode::Pong, Some(frame.payload)))?;
+                    continue;
+                }
+                Opcode::Pong => continue,
+                Opcode::Text | Opcode::Binary if !frame.fin => {
+                    fragments = Some((frame.opcode, frame.payload));
+                    continue;
+                }
+                Opcode::Text | Opcode::Binary => (frame.opcode, frame.payload),
+                Opcode::Continuation => {
+                    let (opcode, mut payload) = fragments.take().ok_or_else(|| {
+                        Error::Protocol("continuation frame without a message".to_string())
+                    })?;
+                    payload.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        fragments = Some((opcode, payload));
+                        continue;
+                    }
+                    (opcode, payload)
+                }
+            };
+
+            return Ok(TryRecv::Message(if opcode == Opcode::Text {
+                Message::Text(String::from_utf8(payload).map_err(|_| Error::InvalidUtf8)?)
+            } else {
+                Message::Binary(payload)
+            }));
+        }
+    }
+
+    /// Closeを送り、サーバーのCloseを受信するまで待って (最大5秒)、そのstatus codeとreasonを返す。
+    /// 待っている間に届いたメッセージは捨てる。status codeがなければ 1005
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(u16, String)> {
+        if !self.closed {
+            self.writer.send_frame(Frame::close(code, reason))?;
+            self.reader
+                .get_ref()
+                .set_read_timeout(Some(CLOSE_TIMEOUT))?;
+            let result = self.wait_close();
+            self.reader.get_ref().set_read_timeout(None)?;
+            result?;
+        }
+        Ok(self
+            .peer_close
+            .clone()
+            .unwrap_or_else(|| (1005, String::new())))
+    }
+
+    /// Pingを送り、対応するPongが `timeout` の間に届くか確かめる。待っている間に届いたメッセージは捨てる。
+    /// 時間切れの場合はフレームの途中まで読んでいることがあるので、この接続は使わないこと
+    pub(crate) fn probe(&mut self, timeout: Duration) -> Result<bool> {
+        let payload = rand::random::<[u8; 8]>().to_vec();
+        self.writer
+            .send_frame(Frame::new(Opcode::Ping, Some(payload.clone())))?;
+        let result = self.wait_pong(&payload, Instant::now() + timeout);
+        self.reader.get_ref().set_read_timeout(None)?;
+        match result {
+            Err(Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                Ok(false)
+            }
+            result => result,
+        }
+    }
+
+    fn wait_pong(&mut self, payload: &[u8], deadline: Instant) -> Result<bool> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            self.reader.get_ref().set_read_timeout(Some(remaining))?;
+            let frame = Frame::read_from_with_limit(&mut self.reader, DEFAULT_MAX_PAYLOAD_LEN)?;
+            match frame.opcode {
+                Opcode::Pong if frame.payload == payload => return Ok(true),
+                Opcode::Close => {
+                    self.peer_close = frame.close_code_and_reason();
+                    self.closed = true;
+                    return Ok(false);
+                }
+                Opcode::Ping => self
+                    .writer
+                    .send_frame(Frame::new(Opcode::Pong, Some(frame.payload)))?,
+                _ => {}
+            }
+        }
+    }
+
+    fn wait_close(&mut self) -> Result<()> {
+        loop {
+            let frame = Frame::read_from_with_limit(&mut self.reader, DEFAULT_MAX_PAYLOAD_LEN)?;
+            match frame.opcode {
+                Opcode::Close => {
+                    self.peer_close = frame.close_code_and_reason();
+                    self.closed = true;
+                    return Ok(());
+                }
+                Opcode::Ping => self
+                    .writer
+                    .send_frame(Frame::new(Opcode::Pong, Some(frame.payload)))?,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// handshakeのリクエストと、それに使った `Sec-WebSocket-Key`
+pub(crate) fn handshake_request(url: &Url, protocols: &[&str]) -> (String, String) {
+    let key = general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+    let protocols = if protocols.is_empty() {
+        String::new()
+    } else {
+        format!("Sec-WebSocket-Protocol: {}\r\n", protocols.join(", "))
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+        Host: {}:{}\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: {}\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        {}\
+        \r\n",
+        url.path, url.host, url.port, key, protocols
+    );
+    (key, request)
+}
+
+/// `reactor` や `Selector` に登録して、受信を待つのに使う。
+/// 受信済みのデータが `Client` のbufferに残っている場合があるので、`is_readable` も確かめること
+#[cfg(all(unix, feature = "reactor"))]
+impl AsRawFd for Client {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl ClientWriter {
+    pub fn send(&self, message: Message) -> Result<()> {
+        self.send_frame(Frame::from(message))
+    }
+
+    /// masking_keyは毎回ランダムに生成する
+    pub fn send_frame(&self, frame: Frame) -> Result<()> {
+        self.write(&frame.masked(rand::random()).to_bytes())
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(bytes)?;
+        stream.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,649 @@
+// 複数のサーバーをクラスタとして動かす
+//
+// 各ノードはクラスタ用のアドレスで待ち受け、他のノード (固定のリスト、またはDNSの名前を解決したもの) へ
+// 定期的に自分の状態 (接続数・roomごとの参加者数) を送る。
+// 一定時間状態が届かなくなったノードはクラスタから外れたものとみなす
+//
+// ノードは接続ごとに共有鍵 (`ClusterConfig::secret`) で互いを認証する。
+// 受け付けた側がnonceを送り、接続した側は自分のnonceとHMAC-SHA256を返し、受け付けた側もHMAC-SHA256を返す:
+//   受け付けた側 -> 接続した側: nonce_a (16byte)
+//   接続した側 -> 受け付けた側: nonce_b (16byte) | HMAC(secret, "client" | nonce_a | nonce_b)
+//   受け付けた側 -> 接続した側: HMAC(secret, "server" | nonce_a | nonce_b)
+// 認証した後の通信は暗号化しないので、信頼できるネットワークの中で使う
+//
+// ノード間のメッセージの形式 (本文は16MiBまで):
+// 種類 (u8) | 長さ (u32 BE) | 本文
+// 1 STATE: "<ノード名>\n<待ち受けアドレス>\n<接続数>\n" に続けて、roomごとに "<参加者数> <room>\n"
+// 2 SEND:  接続ID (u64 BE) | 種類 (1: Text / 2: Binary) | payload
+// 3 ROOM:  room名の長さ (u16 BE) | room名 | 種類 (1: Text / 2: Binary) | payload
+
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+use crate::{
+    admin::Admin,
+    connection::ConnectionId,
+    error::{Error, Result},
+    hub::Hub,
+    log::{info, warning},
+    message::Message,
+    topic,
+};
+
+const STATE: u8 = 1;
+const SEND: u8 = 2;
+const ROOM: u8 = 3;
+
+/// メッセージの本文の長さの上限。これより長いと申告した接続は切る
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+/// 認証のhandshakeのnonceの長さ
+const NONCE_LEN: usize = 16;
+
+/// 認証のhandshakeを終えるまでの時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 他のノードの見つけ方
+#[derive(Clone, Debug)]
+pub enum Discovery {
+    /// `host:port` の固定のリスト
+    Static(Vec<String>),
+    /// `name:port` を定期的に解決し、得られたアドレス全てをノードとみなす
+    Dns(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// クラスタ内で一意なノード名
+    pub name: String,
+    /// ノード間の通信を待ち受けるアドレス。他のノードからもこのアドレスで接続できること
+    pub listen: SocketAddr,
+    pub discovery: Discovery,
+    /// 状態を送る間隔。この3倍の間状態が届かなければノードを外す
+    pub heartbeat: Duration,
+    /// ノード間の認証に使う共有鍵。全てのノードで同じにする。空にはできない
+    pub secret: Vec<u8>,
+}
+
+/// クラスタ内のノードの状態
+#[derive(Clone, Debug)]
+pub struct NodeInfo {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub connections: usize,
+    /// roomごとの参加者数
+    pub rooms: Vec<(String, usize)>,
+    pub last_seen: SystemTime,
+}
+
+pub struct Cluster {
+    config: ClusterConfig,
+    admin: Admin,
+    hub: Arc<Hub>,
+    /// 自分以外のノード (ノード名 -> 状態)
+    nodes: Mutex<HashMap<String, NodeInfo>>,
+    /// 他のノードへの接続 (アドレス -> 接続)。接続ごとにロックし、遅いノードへの書き込みが他のノードへの送信を止めないようにする
+    peers: Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>,
+}
+
+impl Cluster {
+    /// ノード間の通信の待ち受けと、状態の定期的な送信を始める
+    pub fn start(mut config: ClusterConfig, admin: Admin, hub: Arc<Hub>) -> io::Result<Arc<Self>> {
+        if config.secret.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cluster secret must not be empty",
+            ));
+        }
+        let listener = TcpListener::bind(config.listen)?;
+        // ポート0で待ち受けた場合も、他のノードには実際のアドレスを伝える
+        config.listen = listener.local_addr()?;
+        let cluster = Arc::new(Self {
+            config,
+            admin,
+            hub,
+            nodes: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+        });
+
+        let receiver = cluster.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let cluster = receiver.clone();
+                thread::spawn(move || cluster.receive(stream));
+            }
+        });
+
+        let heartbeat = cluster.clone();
+        thread::spawn(move || loop {
+            heartbeat.heartbeat();
+            thread::sleep(heartbeat.config.heartbeat);
+        });
+
+        Ok(cluster)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// 自分を含むクラスタ内のノードの一覧 (ノード名順)
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .nodes
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        nodes.push(self.local());
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        nodes
+    }
+
+    /// クラスタ全体の接続数
+    pub fn connections(&self) -> usize {
+        self.nodes().iter().map(|node| node.connections).sum()
+    }
+
+    /// roomに参加者がいるノードと、そのノードでの参加者数。
+    /// `orders.*` のようなパターンのroomの参加者も、`room` に一致すれば数える (`topic` を参照)
+    pub fn presence(&self, room: &str) -> Vec<(String, usize)> {
+        self.nodes()
+            .into_iter()
+            .filter_map(|node| {
+                let members = node
+                    .rooms
+                    .iter()
+                    .filter(|(name, _)| topic::matches(name, room))
+                    .map(|(_, members)| members)
+                    .sum::<usize>();
+                (members > 0).then_some((node.name, members))
+            })
+            .collect()
+    }
+
+    /// 指定したノードの接続にメッセージを送る。自分のノードならそのまま送る
+    pub fn send_to(&self, node: &str, id: ConnectionId, message: Message) -> Result<()> {
+        if node == self.config.name {
+            return match self.admin.send(id, message)? {
+                true => Ok(()),
+                false => Err(Error::Protocol(format!("no such connection: {}", id))),
+            };
+        }
+
+        let mut body = id.to_be_bytes().to_vec();
+        body.extend(encode(&message));
+        self.send_to_node(node, SEND, &body)
+    }
+
+    /// roomの参加者全員にメッセージを送る。
+    /// 他のノードへは参加者がいるノードにだけ転送する。戻り値は自分のノードで配送できた接続数。
+    /// room名が64KiB以上なら (ノード間のメッセージに入らないので) どこにも配送しない
+    pub fn publish(&self, room: &str, message: Message) -> usize {
+        if room.len() > u16::MAX as usize {
+            warning!("cluster_error", { len: room.len() as u64 }, "cluster: room name too long: {} bytes", room.len());
+            return 0;
+        }
+        let mut body = (room.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(room.as_bytes());
+        body.extend(encode(&message));
+
+        for (node, _) in self.presence(room) {
+            if node != self.config.name {
+                if let Err(e) = self.send_to_node(&node, ROOM, &body) {
+                    warning!("cluster_error", { node: node.as_str(), error: e.to_string() }, "cluster: failed to forward to {}: {}", node, e);
+                }
+            }
+        }
+        self.hub.publish(room, message)
+    }
+
+    fn local(&self) -> NodeInfo {
+        NodeInfo {
+            name: self.config.name.clone(),
+            addr: self.config.listen,
+            connections: self.admin.connections().len(),
+            rooms: self.hub.rooms(),
+            last_seen: SystemTime::now(),
+        }
+    }
+
+    fn send_to_node(&self, node: &str, kind: u8, body: &[u8]) -> Result<()> {
+        let addr = self
+            .nodes
+            .lock()
+            .unwrap()
+            .get(node)
+            .map(|node| node.addr)
+            .ok_or_else(|| Error::Protocol(format!("unknown node: {}", node)))?;
+        Ok(self.send_to_addr(addr, kind, body)?)
+    }
+
+    /// 接続がなければ張り、失敗したら次回に張り直す。接続している間は `peers` をロックしない
+    fn send_to_addr(&self, addr: SocketAddr, kind: u8, body: &[u8]) -> io::Result<()> {
+        if body.len() > MAX_BODY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cluster message too large",
+            ));
+        }
+        let mut bytes = vec![kind];
+        bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(body);
+
+        let peer = self.peers.lock().unwrap().get(&addr).cloned();
+        let peer = match peer {
+            Some(peer) => peer,
+            None => {
+                let mut stream = TcpStream::connect_timeout(&addr, self.config.heartbeat)?;
+                authenticate(&mut stream, &self.config.secret)?;
+                // 同時に張った接続があれば、先に登録された方を使う
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .entry(addr)
+                    .or_insert_with(|| Arc::new(Mutex::new(stream)))
+                    .clone()
+            }
+        };
+        let result = peer.lock().unwrap().write_all(&bytes);
+        if result.is_err() {
+            let mut peers = self.peers.lock().unwrap();
+            // 他のスレッドが張り直した接続は残す
+            if peers
+                .get(&addr)
+                .is_some_and(|stream| Arc::ptr_eq(stream, &peer))
+            {
+                peers.remove(&addr);
+            }
+        }
+        result
+    }
+
+    fn peer_addrs(&self) -> Vec<SocketAddr> {
+        let addrs = match &self.config.discovery {
+            Discovery::Static(peers) => peers
+                .iter()
+                .filter_map(|peer| peer.to_socket_addrs().ok())
+                .flatten()
+                .collect::<Vec<_>>(),
+            Discovery::Dns(name) => name
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect())
+                .unwrap_or_default(),
+        };
+        addrs
+            .into_iter()
+            .filter(|addr| *addr != self.config.listen)
+            .collect()
+    }
+
+    fn heartbeat(&self) {
+        let local = self.local();
+        let mut body = format!("{}\n{}\n{}\n", local.name, local.addr, local.connections);
+        for (room, members) in &local.rooms {
+            body.push_str(&format!("{} {}\n", members, room));
+        }
+
+        for addr in self.peer_addrs() {
+            // 起動していないノードもあるので、失敗は無視する
+            let _ = self.send_to_addr(addr, STATE, body.as_bytes());
+        }
+
+        let expire = self.config.heartbeat * 3;
+        self.nodes.lock().unwrap().retain(|name, node| {
+            let alive = node.last_seen.elapsed().unwrap_or_default() < expire;
+            if !alive {
+                info!("cluster_node_left", { node: name.as_str() }, "cluster: node {} left", name);
+            }
+            alive
+        });
+    }
+
+    fn receive(&self, mut stream: TcpStream) {
+        if let Err(e) = accept(&mut stream, &self.config.secret) {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+            warning!("cluster_auth_failed", { peer: peer.clone(), error: e.to_string() }, "cluster: failed to authenticate {}: {}", peer, e);
+            return;
+        }
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut header = [0; 5];
+            if reader.read_exact(&mut header).is_err() {
+                return;
+            }
+            let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+            // 申告された長さのバッファを確保する前に確かめる
+            if len > MAX_BODY_LEN {
+                warning!("cluster_error", { len: len as u64 }, "cluster: message too large: {} bytes", len);
+                return;
+            }
+            let mut body = vec![0; len];
+            if reader.read_exact(&mut body).is_err() {
+                return;
+            }
+
+            match header[0] {
+                STATE => self.update(&body),
+                SEND if body.len() >= 8 => {
+                    let id = u64::from_be_bytes(body[..8].try_into().unwrap());
+                    if let Some(message) = decode(&body[8..]) {
+                        let _ = self.admin.send(id, message);
+                    }
+                }
+                ROOM if body.len() >= 2 => {
+                    let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let Some(room) = body.get(2..2 + len) else {
+                        continue;
+                    };
+                    let room = String::from_utf8_lossy(room).into_owned();
+                    if let Some(message) = decode(&body[2 + len..]) {
+                        self.hub.deliver(&room, &message);
+                    }
+                }
+                kind => {
+                    warning!("cluster_error", { kind: kind as u64 }, "cluster: unknown message kind: {}", kind)
+                }
+            }
+        }
+    }
+
+    fn update(&self, body: &[u8]) {
+        let body = String::from_utf8_lossy(body);
+        let mut lines = body.lines();
+        let (Some(name), Some(addr), Some(connections)) =
+            (lines.next(), lines.next(), lines.next())
+        else {
+            return;
+        };
+        let (Ok(addr), Ok(connections)) = (addr.parse(), connections.parse()) else {
+            return;
+        };
+        if name == self.config.name {
+            return;
+        }
+        let rooms = lines
+            .filter_map(|line| {
+                let (members, room) = line.split_once(' ')?;
+                Some((room.to_string(), members.parse().ok()?))
+            })
+            .collect();
+
+        let node = NodeInfo {
+            name: name.to_string(),
+            addr,
+            connections,
+            rooms,
+            last_seen: SystemTime::now(),
+        };
+        if self
+            .nodes
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), node)
+            .is_none()
+        {
+            info!("cluster_node_joined", { node: name, addr: addr.to_string() }, "cluster: node {} joined ({})", name, addr);
+        }
+    }
+}
+
+/// handshakeのHMAC。`label` でどちらが送ったものかを区別する
+fn handshake_mac(secret: &[u8], label: &[u8], nonce_a: &[u8], nonce_b: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    mac.update(nonce_a);
+    mac.update(nonce_b);
+    mac
+}
+
+/// 受け付けた接続の相手が同じ共有鍵を持つことを確かめ、自分も持つことを示す
+fn accept(stream: &mut TcpStream, secret: &[u8]) -> io::Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let nonce_a = rand::random::<[u8; NONCE_LEN]>();
+    stream.write_all(&nonce_a)?;
+    let mut reply = [0; NONCE_LEN + 32];
+    stream.read_exact(&mut reply)?;
+    let (nonce_b, tag) = reply.split_at(NONCE_LEN);
+    handshake_mac(secret, b"client", &nonce_a, nonce_b)
+        .verify_slice(tag)
+        .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "invalid cluster secret"))?;
+    stream.write_all(
+        &handshake_mac(secret, b"server", &nonce_a, nonce_b)
+            .finalize()
+            .into_bytes(),
+    )?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)
+}
+
+/// 接続した相手と互いに共有鍵を持つことを確かめる (`accept` の相手側)
+fn authenticate(stream: &mut TcpStream, secret: &[u8]) -> io::Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut nonce_a = [0; NONCE_LEN];
+    stream.read_exact(&mut nonce_a)?;
+    let nonce_b = rand::random::<[u8; NONCE_LEN]>();
+    let mut reply = nonce_b.to_vec();
+    reply.extend_from_slice(
+        &handshake_mac(secret, b"client", &nonce_a, &nonce_b)
+            .finalize()
+            .into_bytes(),
+    );
+    stream.write_all(&reply)?;
+    let mut tag = [0; 32];
+    stream.read_exact(&mut tag)?;
+    handshake_mac(secret, b"server", &nonce_a, &nonce_b)
+        .verify_slice(&tag)
+        .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "invalid cluster secret"))?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)
+}
+
+fn encode(message: &Message) -> Vec<u8> {
+    let kind = match message {
+        Message::Text(_) => 1,
+        Message::Binary(_) => 2,
+    };
+    let mut bytes = vec![kind];
+    bytes.extend_from_slice(message.as_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<Message> {
+    let (kind, payload) = bytes.split_first()?;
+    match kind {
+        1 => Some(Message::Text(String::from_utf8(payload.to_vec()).ok()?)),
+        2 => Some(Message::Binary(payload.to_vec())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{connection::Connection, handler::Handler, server::Server, testing};
+
+    const HEARTBEAT: Duration = Duration::from_millis(20);
+
+    fn start(name: &str, secret: &[u8], peers: Vec<String>) -> Arc<Cluster> {
+        let server = Server::bind("127.0.0.1:0", ()).unwrap();
+        let config = ClusterConfig {
+            name: name.to_string(),
+            listen: "127.0.0.1:0".parse().unwrap(),
+            discovery: Discovery::Static(peers),
+            heartbeat: HEARTBEAT,
+            secret: secret.to_vec(),
+        };
+        Cluster::start(config, server.admin(), server.hub()).unwrap()
+    }
+
+    /// `cluster` が `node` の状態を受け取るまで (最大2秒) 待つ
+    fn wait_for(cluster: &Cluster, node: &str) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(2) {
+            if cluster.nodes().iter().any(|info| info.name == node) {
+                return true;
+            }
+            thread::sleep(HEARTBEAT);
+        }
+        false
+    }
+
+    #[test]
+    fn joins_only_nodes_with_the_same_secret() {
+        let a = start("a", b"secret", vec![]);
+        let addr = a.config.listen.to_string();
+        let _intruder = start("intruder", b"other", vec![addr.clone()]);
+        let _b = start("b", b"secret", vec![addr]);
+        assert!(wait_for(&a, "b"));
+        thread::sleep(HEARTBEAT * 5);
+        assert!(a.nodes().iter().all(|node| node.name != "intruder"));
+
+        let config = ClusterConfig {
+            secret: vec![],
+            ..a.config.clone()
+        };
+        let server = Server::bind("127.0.0.1:0", ()).unwrap();
+        match Cluster::start(config, server.admin(), server.hub()) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("expected an empty secret to be rejected"),
+        }
+    }
+
+    #[test]
+    fn closes_unauthenticated_and_oversized_streams() {
+        let a = start("a", b"secret", vec![]);
+        let closed = |stream: &mut TcpStream| matches!(stream.read(&mut [0; 64]), Ok(0) | Err(_));
+
+        // 鍵を知らなければ認証できない
+        let mut stream = TcpStream::connect(a.config.listen).unwrap();
+        assert!(authenticate(&mut stream, b"other").is_err());
+
+        // 認証せずに送ったメッセージは読まない
+        let mut stream = TcpStream::connect(a.config.listen).unwrap();
+        stream.read_exact(&mut [0; NONCE_LEN]).unwrap();
+        stream.write_all(&[0; NONCE_LEN + 32]).unwrap();
+        assert!(closed(&mut stream));
+
+        // 認証した後でも、上限を超える長さを申告したらバッファを確保せずに切る
+        let mut stream = TcpStream::connect(a.config.listen).unwrap();
+        authenticate(&mut stream, b"secret").unwrap();
+        stream.write_all(&[STATE, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert!(closed(&mut stream));
+        assert_eq!(a.nodes().len(), 1);
+    }
+
+    #[test]
+    fn connects_to_peers_without_blocking_other_sends() {
+        let a = start("a", b"secret", vec![]);
+        let b = start("b", b"secret", vec![]);
+        // 接続は受け付けるが (backlog)、handshakeに応答しないノード
+        let stalled = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        {
+            let a = a.clone();
+            thread::spawn(move || a.send_to_addr(stalled_addr, STATE, b""));
+        }
+        thread::sleep(HEARTBEAT);
+
+        let started = Instant::now();
+        a.send_to_addr(b.config.listen, STATE, b"").unwrap();
+        assert!(started.elapsed() < HANDSHAKE_TIMEOUT / 2);
+    }
+
+    #[test]
+    fn forwards_to_nodes_with_matching_patterns() {
+        /// `orders.*` に参加する
+        struct JoinOrders;
+
+        impl Handler for JoinOrders {
+            fn on_open(&self, conn: &mut Connection) {
+                let _ = conn.join("orders.*");
+            }
+        }
+
+        let a = start("a", b"secret", vec![]);
+        let (server, mut client) = testing::spawn_test_server(JoinOrders).unwrap();
+        let config = ClusterConfig {
+            name: "b".to_string(),
+            listen: "127.0.0.1:0".parse().unwrap(),
+            discovery: Discovery::Static(vec![a.config.listen.to_string()]),
+            heartbeat: HEARTBEAT,
+            secret: b"secret".to_vec(),
+        };
+        let _b = Cluster::start(config, server.admin().clone(), server.hub()).unwrap();
+
+        // bの参加者がaに届くまで待つ
+        let started = Instant::now();
+        while a.presence("orders.new").is_empty() && started.elapsed() < Duration::from_secs(2) {
+            thread::sleep(HEARTBEAT);
+        }
+        assert_eq!(a.presence("orders.new"), vec![("b".to_string(), 1)]);
+        assert!(a.presence("orders").is_empty());
+        assert!(a.presence("orders.new.jp").is_empty());
+
+        assert_eq!(a.publish("orders.new", Message::Text("hi".to_string())), 0);
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("hi".to_string()))
+        );
+    }
+
+    #[test]
+    fn refuses_room_names_that_do_not_fit_the_frame() {
+        /// 長さが64KiBのroomに参加する
+        struct JoinLongRoom;
+
+        impl Handler for JoinLongRoom {
+            fn on_open(&self, conn: &mut Connection) {
+                let _ = conn.join(&long_room());
+            }
+        }
+
+        fn long_room() -> String {
+            "r".repeat(u16::MAX as usize + 1)
+        }
+
+        let (server, _client) = testing::spawn_test_server(JoinLongRoom).unwrap();
+        while server.hub().rooms().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let config = ClusterConfig {
+            name: "a".to_string(),
+            listen: "127.0.0.1:0".parse().unwrap(),
+            discovery: Discovery::Static(vec![]),
+            heartbeat: HEARTBEAT,
+            secret: b"secret".to_vec(),
+        };
+        let a = Cluster::start(config, server.admin().clone(), server.hub()).unwrap();
+        assert_eq!(
+            server
+                .hub()
+                .publish(&long_room(), Message::Text("hi".to_string())),
+            1
+        );
+        // 他のノードに正しく転送できないので、自分のノードでも配送しない
+        assert_eq!(a.publish(&long_room(), Message::Text("hi".to_string())), 0);
+    }
+}
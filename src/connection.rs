@@ -0,0 +1,1199 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    net::{Shutdown, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "jwt")]
+use crate::jwt::Claims;
+#[cfg(feature = "tls")]
+use crate::tls::TlsInfo;
+use crate::{
+    chaos::Chaos,
+    error::{Error, Result},
+    extension::{self, Codec, Codecs, Offer},
+    frame::{Frame, Opcode, Role, Violation, DEFAULT_MAX_PAYLOAD_LEN},
+    handshake::Request,
+    hub::Hub,
+    interceptor::{self, Pipeline},
+    log::debug,
+    message::Message,
+    outbox::{Outbox, Push},
+    record::{Direction, Recorder},
+    schema::{Invalid, OnInvalid, Validator},
+    server::{ControlFrameLimit, SendQueue},
+    session::Session,
+    stats::{ConnectionCounters, ConnectionStats, Stats},
+    stream::Stream,
+    trace,
+};
+
+/// 接続ごとにアプリケーションが持つ値を、型ごとに1つずつ保持する。
+/// 接続IDをキーにしたHashMapを別に持たなくても、認証したユーザーなどを接続に紐づけられる
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Value>>,
+}
+
+/// `Extensions` に入れられる値
+trait Value: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn Value>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Clone + Send + Sync + 'static> Value for T {
+    fn clone_box(&self) -> Box<dyn Value> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Clone for Box<dyn Value> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 値を追加する。同じ型の値があれば置き換え、前の値を返す
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| {
+                previous
+                    .into_any()
+                    .downcast()
+                    .ok()
+                    .map(|previous| *previous)
+            })
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any().downcast_ref())
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.values
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any_mut().downcast_mut())
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.into_any().downcast().ok().map(|value| *value))
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// `other` の値を追加する (同じ型の値は `other` のもので置き換える)
+    pub fn extend(&mut self, other: Extensions) {
+        self.values.extend(other.values);
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+/// サーバー内で接続を一意に識別するID (accept順に採番)
+pub type ConnectionId = u64;
+
+/// handshake済みのWebSocket接続
+pub struct Connection {
+    handle: ConnectionHandle,
+    /// 読み込み用。書き込みは `handle` 経由で行う
+    stream: Box<dyn Stream>,
+    path: String,
+    /// peerから受信したCloseのstatus codeとreason
+    peer_close: Option<(u16, String)>,
+    hub: Arc<Hub>,
+    /// 再開可能なセッションのトークン
+    session: Option<String>,
+    resumed: bool,
+    /// handshakeで合意したサブプロトコル (Sec-WebSocket-Protocol)
+    protocol: Option<String>,
+    /// handshakeで検証したJWTのpayload
+    #[cfg(feature = "jwt")]
+    claims: Option<Claims>,
+    /// handshakeでcookieから読み込んだセッション
+    user_session: Option<Session>,
+    /// TLSで受け付けた接続の情報
+    #[cfg(feature = "tls")]
+    tls: Option<TlsInfo>,
+    control_frame_limit: Option<ControlFrameLimit>,
+    /// 受信するメッセージの最大のbyte数
+    max_message_size: Option<usize>,
+    /// 現在のwindowの開始時刻と、その間に受信したControlフレームの数
+    control_frames: (Instant, u32),
+    /// アプリケーションが接続に紐づける値
+    extensions: Extensions,
+    /// handshakeのリクエストのHTTPのバージョン
+    http_version: String,
+    /// handshakeのリクエストのヘッダー (名前は小文字)
+    request_headers: Vec<(String, String)>,
+    /// handshakeで合意した拡張とパラメーター (101の応答に載せたもの)
+    negotiated_extensions: Vec<Offer>,
+    /// 合意した拡張が使うRSVビット。それ以外のビットが立ったフレームはプロトコル違反
+    rsv_bits: u8,
+    /// 受信したメッセージを検証するValidatorと、失敗したときの扱い
+    validators: Vec<(Arc<dyn Validator>, OnInvalid)>,
+}
+
+/// 一度だけエンコードしたメッセージ。broadcastで多数の接続に同じバイト列を書き込む
+#[derive(Clone)]
+pub struct PreparedMessage {
+    message: Message,
+    /// マスクしないフレームのバイト列
+    bytes: Arc<[u8]>,
+    /// 圧縮するか (`send_with_compression`)。None なら拡張の設定に従う
+    compress: Option<bool>,
+}
+
+impl PreparedMessage {
+    pub fn new(message: Message) -> Self {
+        let bytes = Frame::from(message.clone()).to_bytes().into();
+        Self {
+            message,
+            bytes,
+            compress: None,
+        }
+    }
+
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+}
+
+/// 送信キューでの優先度。キューに溜まっている場合は優先度の高いメッセージから書き込み、
+/// 一杯になった場合は優先度の低いメッセージから捨てる
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// 大きなデータなど、遅れてもよいもの
+    Low,
+    #[default]
+    Normal,
+    /// presenceの通知や制御用のメッセージなど、急ぐもの
+    High,
+}
+
+impl Priority {
+    pub(crate) const ALL: [Self; 3] = [Self::Low, Self::Normal, Self::High];
+}
+
+/// 書き込み用の接続。corkしている間は書き込むバイト列をbufferに溜める
+struct Writer {
+    stream: Box<dyn Stream>,
+    /// corkの入れ子の深さ
+    corks: usize,
+    buffer: Vec<u8>,
+    /// `feed` で溜めている (corkを1つ使っている)
+    fed: bool,
+    /// `flush` した回数。自動のflushが既にflushされたものを書き込まないように使う
+    flushes: u64,
+    /// `feed` してからこの時間が過ぎたら自動でflushする。None なら `flush` を呼ぶまで溜める
+    flush_deadline: Option<Duration>,
+}
+
+/// `feed` で溜めたバイト数がこれを超えたら、期限を待たずに書き込む
+const MAX_FEED_BUFFER: usize = 64 * 1024;
+
+impl Writer {
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.corks > 0 {
+            self.buffer.extend_from_slice(bytes);
+            return Ok(());
+        }
+        self.stream.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.corks > 0 {
+            return Ok(());
+        }
+        self.stream.flush()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+}
+
+/// 他のスレッドから接続にメッセージを送ったり、接続を閉じたりするためのハンドル
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    id: ConnectionId,
+    peer_addr: SocketAddr,
+    writer: Arc<Mutex<Writer>>,
+    /// こちらからCloseフレームを送信済み
+    closing: Arc<AtomicBool>,
+    /// サーバー全体の統計
+    stats: Arc<Stats>,
+    /// 接続したroute (path) ごとの統計
+    route_stats: Option<Arc<Stats>>,
+    counters: Arc<ConnectionCounters>,
+    chaos: Option<Arc<Chaos>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    /// 送受信するメッセージに適用するInterceptor
+    interceptors: Option<Pipeline>,
+    /// handshakeで合意した拡張
+    codecs: Option<Codecs>,
+    /// 送信するメッセージを分けるフレームの大きさ。0 なら分けない
+    fragment_size: Arc<AtomicUsize>,
+    /// メッセージのフレームを送信している間は他のメッセージを送らない
+    sending: Arc<Mutex<()>>,
+    /// 送信キュー。設定されていればメッセージは送信スレッドが書き込む
+    outbox: Option<Arc<Outbox>>,
+    /// 書き込み中のスレッドを待たずに切断するため、writerのロックの外に置く
+    socket: Arc<dyn Stream>,
+    /// peerから届いたClose。`close_and_wait` が待つ
+    peer_close: Arc<PeerClose>,
+    /// この接続のフレームを読み込み、Handlerを呼ぶスレッド
+    reader: ThreadId,
+}
+
+/// peerから届いたCloseのstatus codeとreason
+#[derive(Default)]
+struct PeerClose {
+    received: Mutex<Option<(u16, String)>>,
+    changed: Condvar,
+}
+
+impl PeerClose {
+    fn set(&self, code: u16, reason: String) {
+        let mut received = self.received.lock().unwrap();
+        if received.is_none() {
+            *received = Some((code, reason));
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// `ConnectionHandle::close_and_wait` がpeerのCloseを待つ時間
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Connection {
+    pub(crate) fn new(
+        id: ConnectionId,
+        stream: Box<dyn Stream>,
+        stats: Arc<Stats>,
+        hub: Arc<Hub>,
+    ) -> io::Result<Self> {
+        let counters = Arc::new(ConnectionCounters::default());
+        counters.received();
+        let socket: Arc<dyn Stream> = stream.try_clone()?.into();
+        #[cfg(feature = "tls")]
+        let tls = stream.tls_info();
+
+        Ok(Self {
+            handle: ConnectionHandle {
+                id,
+                peer_addr: stream.peer_addr()?,
+                writer: Arc::new(Mutex::new(Writer {
+                    stream: stream.try_clone()?,
+                    corks: 0,
+                    buffer: vec![],
+                    fed: false,
+                    flushes: 0,
+                    flush_deadline: None,
+                })),
+                closing: Arc::new(AtomicBool::new(false)),
+                stats,
+                route_stats: None,
+                counters,
+                chaos: None,
+                recorder: None,
+                interceptors: None,
+                codecs: None,
+                fragment_size: Arc::new(AtomicUsize::new(0)),
+                sending: Arc::default(),
+                outbox: None,
+                socket,
+                peer_close: Arc::default(),
+                reader: thread::current().id(),
+            },
+            stream,
+            path: String::new(),
+            peer_close: None,
+            hub,
+            session: None,
+            resumed: false,
+            protocol: None,
+            #[cfg(feature = "jwt")]
+            claims: None,
+            user_session: None,
+            #[cfg(feature = "tls")]
+            tls,
+            control_frame_limit: None,
+            max_message_size: Some(DEFAULT_MAX_PAYLOAD_LEN),
+            control_frames: (Instant::now(), 0),
+            extensions: Extensions::new(),
+            http_version: String::new(),
+            request_headers: vec![],
+            negotiated_extensions: vec![],
+            rsv_bits: 0,
+            validators: vec![],
+        })
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.handle.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.handle.peer_addr
+    }
+
+    /// handshakeのRequest-Lineで指定されたpath
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// 再開可能なセッションが有効なら、このセッションのトークン
+    pub fn session_token(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// handshakeで合意したサブプロトコル
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// handshakeのリクエストのHTTPのバージョン (`HTTP/1.1` など)
+    pub fn http_version(&self) -> &str {
+        &self.http_version
+    }
+
+    /// handshakeのリクエストのヘッダー (名前は小文字に正規化済み)
+    pub fn request_headers(&self) -> &[(String, String)] {
+        &self.request_headers
+    }
+
+    /// handshakeのリクエストのヘッダーの値 (大文字小文字は区別しない)
+    pub fn request_header(&self, key: &str) -> Option<&str> {
+        self.request_headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// handshakeで合意した拡張とパラメーター。合意しなければ空
+    pub fn negotiated_extensions(&self) -> &[Offer] {
+        &self.negotiated_extensions
+    }
+
+    /// handshakeのmiddlewareの `Jwt` が検証したトークンのpayload
+    #[cfg(feature = "jwt")]
+    pub fn claims(&self) -> Option<&Claims> {
+        self.claims.as_ref()
+    }
+
+    /// handshakeのmiddlewareの `SessionLayer` がcookieから読み込んだセッション
+    pub fn session(&self) -> Option<&Session> {
+        self.user_session.as_ref()
+    }
+
+    /// TLSで受け付けた接続なら、SNIのホスト名と検証したクライアント証明書
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+
+    /// 切断されたセッションを再開した接続か
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    pub fn handle(&self) -> ConnectionHandle {
+        self.handle.clone()
+    }
+
+    /// アプリケーションが接続に紐づけた値。handshakeのmiddlewareが `Handshake::extensions` に入れた値も含む
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// 値を紐づけるには `conn.extensions_mut().insert(state)` とする
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        self.handle.send(message)
+    }
+
+    pub fn send_with_priority(&mut self, message: Message, priority: Priority) -> Result<()> {
+        self.handle.send_with_priority(message, priority)
+    }
+
+    /// 圧縮するかを指定して送信する。拡張の最小の大きさなどの設定より優先する
+    pub fn send_with_compression(&mut self, message: Message, compress: bool) -> Result<()> {
+        self.handle.send_with_compression(message, compress)
+    }
+
+    /// 書き込まずに溜める。`flush` するか、`Config::flush_deadline` が過ぎたら書き込む
+    pub fn feed(&mut self, message: Message) -> Result<()> {
+        self.handle.feed(message)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.handle.flush()
+    }
+
+    /// サーバーのHub。roomへの参加・publishに使う
+    pub fn hub(&self) -> &Arc<Hub> {
+        &self.hub
+    }
+
+    /// roomに参加する。接続が閉じると自動的に抜ける。
+    /// HubのAuthorizerが許可しなければ参加せずに `Error::PolicyViolation` を返す
+    pub fn join(&self, room: &str) -> Result<()> {
+        self.hub.authorize_join(self, room)?;
+        self.hub.join(room, self.handle());
+        Ok(())
+    }
+
+    /// ユーザー名などを付けてroomに参加する
+    pub fn join_as(&self, room: &str, user: &str) -> Result<()> {
+        self.hub.authorize_join(self, room)?;
+        self.hub.join_as(room, self.handle(), user);
+        Ok(())
+    }
+
+    /// この接続からroomにpublishする。HubのAuthorizerが許可しなければ `Error::PolicyViolation`。
+    /// 戻り値はローカルで配送できた接続数
+    pub fn publish(&self, room: &str, message: Message) -> Result<usize> {
+        self.hub.authorize_publish(self, room, &message)?;
+        Ok(self.hub.publish(room, message))
+    }
+
+    pub fn leave(&self, room: &str) {
+        self.hub.leave(room, self.id());
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        self.handle.ping()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.handle.stats()
+    }
+
+    pub fn send_frame(&mut self, frame: Frame) -> Result<()> {
+        self.handle.send_frame(frame)
+    }
+
+    /// `size` bytesより大きいメッセージを複数のフレームに分けて送信する。None なら分けない
+    pub fn set_fragment_size(&self, size: Option<usize>) {
+        self.handle.set_fragment_size(size);
+    }
+
+    /// Closeフレームを送信して接続を閉じ始める。
+    /// 以降はpeerからのCloseを待つだけになる
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<()> {
+        self.handle.close(code, reason)
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.handle.is_closing()
+    }
+
+    pub(crate) fn set_chaos(&mut self, chaos: Option<Arc<Chaos>>) {
+        self.handle.chaos = chaos;
+    }
+
+    /// 送信キューと、キューのメッセージを書き込むスレッドを用意する
+    pub(crate) fn set_send_queue(&mut self, config: SendQueue) {
+        let outbox = Arc::new(Outbox::new(config));
+        let writer = self.handle.clone();
+        let queue = outbox.clone();
+        thread::spawn(move || {
+            while let Some(messages) = queue.pop_all() {
+                if writer.send_all(&messages).is_err() {
+                    break;
+                }
+            }
+            queue.close();
+        });
+        self.handle.outbox = Some(outbox);
+    }
+
+    pub(crate) fn set_flush_deadline(&mut self, deadline: Option<Duration>) {
+        self.handle.set_flush_deadline(deadline);
+    }
+
+    pub(crate) fn set_control_frame_limit(&mut self, limit: ControlFrameLimit) {
+        self.control_frame_limit = Some(limit);
+    }
+
+    pub(crate) fn set_max_message_size(&mut self, max: Option<usize>) {
+        self.max_message_size = max;
+    }
+
+    pub(crate) fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(usize::MAX)
+    }
+
+    /// Controlフレームを受信したことを記録し、制限を超えていればエラーを返す
+    pub(crate) fn count_control_frame(&mut self) -> Result<()> {
+        let Some(limit) = self.control_frame_limit else {
+            return Ok(());
+        };
+        let (started, count) = &mut self.control_frames;
+        if started.elapsed() >= limit.window {
+            *started = Instant::now();
+            *count = 0;
+        }
+        *count += 1;
+        if *count > limit.max {
+            return Err(Error::PolicyViolation(format!(
+                "more than {} control frames in {:?}",
+                limit.max, limit.window
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_codecs(&mut self, codecs: Vec<Box<dyn Codec>>) {
+        self.rsv_bits = codecs.iter().fold(0, |bits, codec| bits | codec.rsv_bits());
+        self.handle.codecs = (!codecs.is_empty()).then(|| Arc::new(Mutex::new(codecs)));
+    }
+
+    pub(crate) fn set_recorder(&mut self, recorder: Recorder) {
+        self.handle.recorder = Some(Arc::new(Mutex::new(recorder)));
+    }
+
+    pub(crate) fn set_route_stats(&mut self, stats: Arc<Stats>) {
+        self.handle.route_stats = Some(stats);
+    }
+
+    /// サーバー全体の統計や障害注入の対象から外す (dashboardなど内部の接続用)
+    pub(crate) fn make_internal(&mut self) {
+        self.handle.stats = Arc::new(Stats::default());
+        self.handle.route_stats = None;
+        self.handle.chaos = None;
+    }
+
+    pub(crate) fn peer_close(&self) -> Option<&(u16, String)> {
+        self.peer_close.as_ref()
+    }
+
+    pub(crate) fn set_peer_close(&mut self, peer_close: Option<(u16, String)>) {
+        let (code, reason) = peer_close.clone().unwrap_or((1005, String::new()));
+        self.handle.peer_close.set(code, reason);
+        self.peer_close = peer_close;
+    }
+
+    pub(crate) fn set_session_token(&mut self, token: String) {
+        self.session = Some(token);
+    }
+
+    pub(crate) fn set_resumed(&mut self, resumed: bool) {
+        self.resumed = resumed;
+    }
+
+    pub(crate) fn set_protocol(&mut self, protocol: String) {
+        self.protocol = Some(protocol);
+    }
+
+    #[cfg(feature = "jwt")]
+    pub(crate) fn set_claims(&mut self, claims: Option<Claims>) {
+        self.claims = claims;
+    }
+
+    pub(crate) fn set_user_session(&mut self, session: Option<Session>) {
+        self.user_session = session;
+    }
+
+    pub(crate) fn set_interceptors(&mut self, interceptors: Pipeline) {
+        self.handle.interceptors = Some(interceptors);
+    }
+
+    /// 受信したメッセージにInterceptorを適用する。None なら捨てる
+    pub(crate) fn intercept_inbound(&self, message: Message) -> Option<Message> {
+        match &self.handle.interceptors {
+            Some(pipeline) => interceptor::apply(pipeline, self.id(), Direction::Inbound, message),
+            None => Some(message),
+        }
+    }
+
+    pub(crate) fn set_validators(&mut self, validators: Vec<(Arc<dyn Validator>, OnInvalid)>) {
+        self.validators = validators;
+    }
+
+    /// 受信したメッセージをValidatorで検証する。失敗したら理由と扱いを返す
+    pub(crate) fn validate(&self, message: &Message) -> Option<(Invalid, OnInvalid)> {
+        self.validators.iter().find_map(|(validator, on_invalid)| {
+            validator
+                .validate(message)
+                .err()
+                .map(|invalid| (invalid, *on_invalid))
+        })
+    }
+
+    pub(crate) fn set_request(&mut self, request: Request) {
+        self.path = request.path;
+        self.http_version = request.version;
+        self.request_headers = request.headers;
+    }
+
+    pub(crate) fn set_negotiated_extensions(&mut self, extensions: Vec<Offer>) {
+        self.negotiated_extensions = extensions;
+    }
+
+    pub(crate) fn stream(&mut self) -> &mut dyn Stream {
+        &mut *self.stream
+    }
+
+    pub(crate) fn read_frame(&mut self) -> Result<Frame> {
+        let max_payload_len = self.max_message_size();
+        let frame = Frame::read_from_with_limit(&mut self.stream, max_payload_len)?;
+        self.receive_frame(frame)
+    }
+
+    /// 読み込んだフレームを記録し、検証して合意した拡張を適用する
+    pub(crate) fn receive_frame(&mut self, mut frame: Frame) -> Result<Frame> {
+        self.handle.record_in(frame.payload_len, false);
+        self.handle.counters.received();
+        trace::frame(self.handle.id, Direction::Inbound, &frame);
+        if let Some(recorder) = &self.handle.recorder {
+            recorder
+                .lock()
+                .unwrap()
+                .write(Direction::Inbound, &frame.clone().to_bytes());
+        }
+        // 合意した拡張が使わないRSVビットは、拡張に渡す前にエラーにする (1002)
+        if let Err(violations) = frame.validate(Role::Client, self.rsv_bits) {
+            return Err(match &violations[0] {
+                Violation::Unmasked => Error::Unmasked,
+                violation => Error::Protocol(violation.to_string()),
+            });
+        }
+        if let Some(codecs) = &self.handle.codecs {
+            extension::decode(codecs, &mut frame)?;
+            // 展開などでpayloadが大きくなったフレーム
+            if frame.payload.len() > self.max_message_size() {
+                return Err(Error::MessageTooBig(frame.payload.len()));
+            }
+        }
+        Ok(frame)
+    }
+
+    pub(crate) fn record_message_in(&self) {
+        self.handle.record_in(0, true);
+    }
+
+    /// peerからのCloseに応答する
+    pub(crate) fn reply_close(&mut self) -> Result<()> {
+        self.handle.send_close(Frame::new(Opcode::Close, None))
+    }
+
+    pub(crate) fn pong_received(&self, payload: &[u8]) -> Option<Duration> {
+        self.handle.counters.pong_received(payload)
+    }
+
+    pub(crate) fn pong(&mut self, payload: Vec<u8>) -> Result<()> {
+        if let Some(chaos) = &self.handle.chaos {
+            if chaos.should_drop_pong() {
+                return Ok(());
+            }
+        }
+        self.send_frame(Frame::new(Opcode::Pong, Some(payload)))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(outbox) = &self.handle.outbox {
+            outbox.close();
+        }
+        // Closeを受信せずに接続が終わった (RFC 6455 7.1.5)
+        self.handle.peer_close.set(1006, String::new());
+    }
+}
+
+impl ConnectionHandle {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn send(&self, message: Message) -> Result<()> {
+        self.send_with_priority(message, Priority::Normal)
+    }
+
+    /// 優先度を付けて送信する。送信キュー (`Config::send_queue`) がなければ `send` と同じ
+    pub fn send_with_priority(&self, message: Message, priority: Priority) -> Result<()> {
+        self.send_message(message, priority, None)
+    }
+
+    /// 圧縮するかを指定して送信する。permessage-deflateなどの拡張は、最小の大きさなどの設定より優先する。
+    /// payloadを圧縮しない拡張には影響しない
+    pub fn send_with_compression(&self, message: Message, compress: bool) -> Result<()> {
+        self.send_message(message, Priority::Normal, Some(compress))
+    }
+
+    fn send_message(
+        &self,
+        message: Message,
+        priority: Priority,
+        compress: Option<bool>,
+    ) -> Result<()> {
+        if let Some(outbox) = &self.outbox {
+            let message = PreparedMessage {
+                compress,
+                ..PreparedMessage::new(message)
+            };
+            return self.enqueue(outbox, message, priority);
+        }
+        let message = match &self.interceptors {
+            Some(pipeline) => {
+                match interceptor::apply(pipeline, self.id, Direction::Outbound, message) {
+                    Some(message) => message,
+                    // Interceptorが捨てた
+                    None => return Ok(()),
+                }
+            }
+            None => message,
+        };
+        let frame = Frame::from(message);
+        let size = self.fragment_size.load(Ordering::Relaxed);
+        // フレームの間に他のメッセージが割り込まないようにする (Controlフレームは割り込める)
+        let _sending = self.sending.lock().unwrap();
+        if size == 0 || frame.payload.len() <= size {
+            return self.write_frame(frame, compress);
+        }
+        let count = frame.payload.len().div_ceil(size);
+        for (i, chunk) in frame.payload.chunks(size).enumerate() {
+            let opcode = if i == 0 {
+                frame.opcode
+            } else {
+                Opcode::Continuation
+            };
+            let fragment = Frame::builder()
+                .opcode(opcode)
+                .fin(i == count - 1)
+                .payload(chunk)
+                .build();
+            self.write_frame(fragment, compress)?;
+        }
+        Ok(())
+    }
+
+    /// `messages` から受け取ったメッセージを順に送信するスレッドを起動する。
+    /// std・crossbeamのReceiverなど、`IntoIterator` なら送信元にできる。
+    /// `messages` が終わるか、送信に失敗したら (接続が閉じたら) スレッドは終わる
+    pub fn forward<I>(&self, messages: I) -> thread::JoinHandle<()>
+    where
+        I: IntoIterator<Item = Message> + Send + 'static,
+    {
+        let handle = self.clone();
+        thread::spawn(move || {
+            for message in messages {
+                if let Err(e) = handle.send(message) {
+                    debug!(
+                        "forward_stopped",
+                        { conn: handle.id, error: e.to_string() },
+                        "[{}] stopped forwarding: {}",
+                        handle.id,
+                        e
+                    );
+                    return;
+                }
+            }
+        })
+    }
+
+    /// 複数の `PreparedMessage` をcorkした上で送信し、まとめて書き込む
+    pub fn send_all(&self, messages: &[PreparedMessage]) -> Result<()> {
+        // 送信キューがあれば、送信スレッドが溜まったメッセージをまとめて書き込む
+        if messages.len() == 1 || self.outbox.is_some() {
+            return messages
+                .iter()
+                .try_for_each(|message| self.send_prepared(message));
+        }
+        self.cork();
+        let result = messages
+            .iter()
+            .try_for_each(|message| self.send_prepared(message));
+        let flushed = self.uncork();
+        result.and(flushed)
+    }
+
+    /// `PreparedMessage` を送信する。Interceptor・拡張・分割・障害注入が設定されていれば `send` と同じ
+    pub fn send_prepared(&self, prepared: &PreparedMessage) -> Result<()> {
+        if let Some(outbox) = &self.outbox {
+            return self.enqueue(outbox, prepared.clone(), Priority::Normal);
+        }
+        let size = self.fragment_size.load(Ordering::Relaxed);
+        if self.interceptors.is_some()
+            || self.codecs.is_some()
+            || self.chaos.is_some()
+            || (size != 0 && prepared.message.len() > size)
+        {
+            return self.send_message(
+                prepared.message.clone(),
+                Priority::Normal,
+                prepared.compress,
+            );
+        }
+        if trace::enabled() {
+            let frame = Frame::from(prepared.message.clone());
+            trace::frame(self.id, Direction::Outbound, &frame);
+        }
+        let _sending = self.sending.lock().unwrap();
+        self.counters.begin_write();
+        let result = self.write(&prepared.bytes, 1, prepared.message.len(), true);
+        self.counters.end_write();
+        result
+    }
+
+    fn enqueue(&self, outbox: &Outbox, message: PreparedMessage, priority: Priority) -> Result<()> {
+        // Closeを送り始めた後のメッセージは受け付けない
+        if self.is_closing() {
+            return Err(
+                io::Error::new(io::ErrorKind::NotConnected, "connection is closing").into(),
+            );
+        }
+        match outbox.push(message, priority) {
+            Push::Queued => Ok(()),
+            Push::Dropped => {
+                debug!(
+                    "message_dropped",
+                    { connection_id: self.id },
+                    "connection {}: send queue is full, dropped a message",
+                    self.id
+                );
+                Ok(())
+            }
+            Push::Overflowed(code) => {
+                // 書き込みが詰まっている場合はCloseを送れないので、そのまま切断する
+                if self.writer.try_lock().is_ok() {
+                    let _ = self.terminate(code, "slow consumer");
+                } else {
+                    let _ = self.socket.shutdown(Shutdown::Both);
+                }
+                Err(Error::PolicyViolation("send queue is full".to_string()))
+            }
+            Push::Closed => {
+                Err(io::Error::new(io::ErrorKind::NotConnected, "send queue is closed").into())
+            }
+        }
+    }
+
+    /// `size` bytesより大きいメッセージを複数のフレームに分けて送信する。None なら分けない
+    pub fn set_fragment_size(&self, size: Option<usize>) {
+        self.fragment_size
+            .store(size.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn send_frame(&self, frame: Frame) -> Result<()> {
+        self.write_frame(frame, None)
+    }
+
+    fn write_frame(&self, mut frame: Frame, compress: Option<bool>) -> Result<()> {
+        if let Some(codecs) = &self.codecs {
+            extension::encode(codecs, &mut frame, compress)?;
+        }
+        let is_message = frame.fin && !frame.opcode.is_control();
+        let payload_len = frame.payload_len;
+
+        let mut copies = 1;
+        if let Some(chaos) = self.chaos.as_deref().filter(|_| !frame.opcode.is_control()) {
+            thread::sleep(chaos.latency);
+            if chaos.should_reset() {
+                self.writer.lock().unwrap().shutdown(Shutdown::Both)?;
+                return Err(
+                    io::Error::new(io::ErrorKind::ConnectionReset, "reset by chaos").into(),
+                );
+            }
+            if chaos.should_duplicate() {
+                copies = 2;
+            }
+        }
+
+        trace::frame(self.id, Direction::Outbound, &frame);
+        let bytes = frame.to_bytes();
+        self.counters.begin_write();
+        let result = self.write(&bytes, copies, payload_len, is_message);
+        self.counters.end_write();
+        result
+    }
+
+    /// サーバー全体・route・接続の統計に記録する
+    fn record_in(&self, bytes: usize, message: bool) {
+        self.stats.record_in(bytes, message);
+        self.counters.stats.record_in(bytes, message);
+        if let Some(stats) = &self.route_stats {
+            stats.record_in(bytes, message);
+        }
+    }
+
+    fn record_out(&self, bytes: usize, message: bool) {
+        self.stats.record_out(bytes, message);
+        self.counters.stats.record_out(bytes, message);
+        if let Some(stats) = &self.route_stats {
+            stats.record_out(bytes, message);
+        }
+    }
+
+    fn write(
+        &self,
+        bytes: &[u8],
+        copies: usize,
+        payload_len: usize,
+        is_message: bool,
+    ) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for _ in 0..copies {
+            writer.write_all(bytes)?;
+            self.record_out(payload_len, is_message);
+            self.counters.touch();
+            if let Some(recorder) = &self.recorder {
+                recorder.lock().unwrap().write(Direction::Outbound, bytes);
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// `uncork` するまで送信するフレームを溜め、まとめて1回で書き込む。入れ子にできる
+    pub fn cork(&self) {
+        self.writer.lock().unwrap().corks += 1;
+    }
+
+    /// 溜めたフレームを書き込む。入れ子の場合は一番外側の `uncork` で書き込む
+    pub fn uncork(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.corks = writer.corks.saturating_sub(1);
+        if writer.corks > 0 || writer.buffer.is_empty() {
+            return Ok(());
+        }
+        let buffer = std::mem::take(&mut writer.buffer);
+        writer.write_all(&buffer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// メッセージを書き込まずに溜める。`flush` を呼ぶか、最初に溜めてから
+    /// `Config::flush_deadline` が過ぎると、溜めたメッセージをまとめて1回で書き込む
+    pub fn feed(&self, message: Message) -> Result<()> {
+        let deadline = {
+            let mut writer = self.writer.lock().unwrap();
+            if writer.fed {
+                None
+            } else {
+                writer.fed = true;
+                writer.corks += 1;
+                writer
+                    .flush_deadline
+                    .map(|deadline| (deadline, writer.flushes))
+            }
+        };
+        if let Some((deadline, flushes)) = deadline {
+            let handle = self.clone();
+            thread::spawn(move || {
+                thread::sleep(deadline);
+                let _ = handle.flush_if(|writer| writer.flushes == flushes);
+            });
+        }
+        self.send(message)?;
+        if self.writer.lock().unwrap().buffer.len() >= MAX_FEED_BUFFER {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// `feed` で溜めたメッセージを書き込む
+    pub fn flush(&self) -> Result<()> {
+        self.flush_if(|_| true)
+    }
+
+    fn flush_if(&self, condition: impl FnOnce(&Writer) -> bool) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            if !writer.fed || !condition(&writer) {
+                return Ok(());
+            }
+            writer.fed = false;
+            writer.flushes += 1;
+        }
+        self.uncork()
+    }
+
+    pub(crate) fn set_flush_deadline(&self, deadline: Option<Duration>) {
+        self.writer.lock().unwrap().flush_deadline = deadline;
+    }
+
+    /// Pingを送信する。対応するPongが届くとRTTが `stats().ping_rtt` に記録される
+    pub fn ping(&self) -> Result<()> {
+        let payload = rand::random::<[u8; 8]>().to_vec();
+        self.counters.ping_sent(payload.clone());
+        self.send_frame(Frame::new(Opcode::Ping, Some(payload)))
+    }
+
+    /// この接続で送受信したメッセージ数・バイト数など
+    pub fn stats(&self) -> ConnectionStats {
+        self.counters.snapshot()
+    }
+
+    pub fn close(&self, code: u16, reason: &str) -> Result<()> {
+        self.send_close(Frame::close(code, reason))
+    }
+
+    /// Closeフレームは1度しか送らない。送信キューがあれば、溜まっているメッセージを書き終えてから送る
+    fn send_close(&self, frame: Frame) -> Result<()> {
+        if self.closing.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(outbox) = &self.outbox {
+            outbox.finish();
+        }
+        self.send_frame(frame)?;
+        // feedで溜めていてもCloseはすぐに届ける
+        self.flush()
+    }
+
+    /// Closeを送り、peerのCloseを受信するまで待って (最大5秒)、そのstatus codeとreasonを返す。
+    /// status codeがなければ 1005、Closeを受信せずに接続が終われば 1006。
+    /// Closeは接続のスレッドが受信するので、Handlerの中からは呼べない (`Connection::close` と `on_peer_close` を使う)
+    pub fn close_and_wait(&self, code: u16, reason: &str) -> Result<(u16, String)> {
+        if thread::current().id() == self.reader {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "close_and_wait cannot be called from the connection's own thread",
+            )
+            .into());
+        }
+        // 既に切れていて送れなくても、接続のスレッドが 1006 を記録するまで待つ
+        let sent = self.close(code, reason);
+        let received = self.peer_close.received.lock().unwrap();
+        let (received, _) = self
+            .peer_close
+            .changed
+            .wait_timeout_while(received, CLOSE_TIMEOUT, |received| received.is_none())
+            .unwrap();
+        match received.clone() {
+            Some(peer_close) => Ok(peer_close),
+            None => {
+                sent?;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "peer did not send Close").into())
+            }
+        }
+    }
+
+    /// Closeフレームを送信した上で、peerの応答を待たずにTCP接続を切断する
+    pub fn terminate(&self, code: u16, reason: &str) -> Result<()> {
+        let _ = self.close(code, reason);
+        self.writer.lock().unwrap().shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Closeを送らずにTCP接続を切断する。書き込みで止まっているスレッドも戻る
+    pub(crate) fn abort(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    /// 最後にフレームを受信してからの時間
+    pub(crate) fn idle(&self) -> Duration {
+        self.counters.since_received()
+    }
+
+    /// 最後の受信の後にPingを送って応答を待っているか、書き込み中
+    pub(crate) fn is_probing(&self) -> bool {
+        self.counters.ping_pending() || self.counters.snapshot().queue_depth > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    use super::*;
+    use crate::{handler::Handler, server::Server, testing};
+
+    /// 接続したら別のスレッドから `close_and_wait` し、結果を送る
+    struct CloseOnOpen(Mutex<Sender<Result<(u16, String)>>>);
+
+    impl Handler for CloseOnOpen {
+        fn on_open(&self, conn: &mut Connection) {
+            // 接続のスレッドからはpeerのCloseを受信できないので呼べない
+            assert!(conn.handle().close_and_wait(1000, "bye").is_err());
+            let handle = conn.handle();
+            let results = self.0.lock().unwrap().clone();
+            thread::spawn(move || results.send(handle.close_and_wait(1000, "bye")));
+        }
+    }
+
+    fn spawn() -> (testing::TestServer, Receiver<Result<(u16, String)>>) {
+        let (results, received) = mpsc::channel();
+        let server = Server::bind("127.0.0.1:0", CloseOnOpen(Mutex::new(results))).unwrap();
+        (testing::spawn_server(server).unwrap(), received)
+    }
+
+    #[test]
+    fn close_and_wait_returns_the_peer_close() {
+        let (server, results) = spawn();
+        let mut client = server.connect("/").unwrap();
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close(), Some(&(1000, "bye".to_string())));
+        // クライアントはstatus codeのないCloseを返す
+        assert_eq!(results.recv().unwrap().unwrap(), (1005, String::new()));
+    }
+
+    #[test]
+    fn close_and_wait_reports_abnormal_closure() {
+        let (server, results) = spawn();
+        drop(server.connect("/").unwrap());
+        assert_eq!(results.recv().unwrap().unwrap(), (1006, String::new()));
+    }
+}
@@ -0,0 +1,112 @@
+// wsctl から操作するためのUnixドメインソケット
+//
+// 1接続につき1行のコマンドを受け取り、結果を返して切断する:
+// stats                -> 接続数と送受信の累計
+// connections [--json] -> 接続の一覧。--json なら参加しているroomや送受信の累計も含めたJSON
+// kick <id> [code]     -> 指定した接続を切断
+// log-level [level]    -> ログレベルの取得・変更
+// drain [grace] [text] -> 新しい接続の受け付けを止める。textを全ての接続に送り、grace秒後に残った接続を 1001 で閉じる
+// drain-status         -> 受け付けを止めているか・残っている接続の数・全て閉じたか
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixListener,
+    path::Path,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{admin::Admin, connection::ConnectionId, log, message::Message};
+
+impl Admin {
+    /// 制御用のUnixドメインソケットを別スレッドで立ち上げる
+    pub fn serve_control<P: AsRef<Path>>(&self, path: P) -> io::Result<JoinHandle<()>> {
+        // 前回のプロセスが残したソケットファイルは消す
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(path)?;
+        let admin = self.clone();
+
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let mut line = String::new();
+                if BufReader::new(&stream).read_line(&mut line).is_err() {
+                    continue;
+                }
+                let response = admin.execute(line.trim());
+                let _ = (&stream).write_all(response.as_bytes());
+            }
+        }))
+    }
+
+    fn execute(&self, command: &str) -> String {
+        let args = command.split_whitespace().collect::<Vec<&str>>();
+
+        match args.as_slice() {
+            ["stats"] => {
+                let stats = self.stats();
+                format!(
+                    "connections {}\nmessages_in {}\nmessages_out {}\nbytes_in {}\nbytes_out {}\ndraining {}\n",
+                    self.connections().len(),
+                    stats.messages_in,
+                    stats.messages_out,
+                    stats.bytes_in,
+                    stats.bytes_out,
+                    self.is_draining(),
+                )
+            }
+            ["connections"] => self
+                .connections()
+                .iter()
+                .map(|info| format!("{}\t{}\t{}\n", info.id, info.peer_addr, info.path))
+                .collect(),
+            ["connections", "--json"] => format!("{}\n", self.connections_json()),
+            ["kick", id, rest @ ..] => {
+                let Ok(id) = id.parse::<ConnectionId>() else {
+                    return format!("error: invalid id: {}\n", id);
+                };
+                let code = match rest.first().map(|code| code.parse::<u16>()) {
+                    None => 1000,
+                    Some(Ok(code)) => code,
+                    Some(Err(_)) => return "error: invalid close code\n".to_string(),
+                };
+
+                match self.kick(id, code, "") {
+                    Ok(true) => "ok\n".to_string(),
+                    Ok(false) => format!("error: no such connection: {}\n", id),
+                    Err(e) => format!("error: {}\n", e),
+                }
+            }
+            ["log-level"] => format!("{}\n", log::level()),
+            ["log-level", level] => match level.parse() {
+                Ok(level) => {
+                    log::set_level(level);
+                    "ok\n".to_string()
+                }
+                Err(e) => format!("error: {}\n", e),
+            },
+            ["drain"] => {
+                self.drain();
+                "ok\n".to_string()
+            }
+            ["drain", grace, text @ ..] => {
+                let Ok(grace) = grace.parse::<u64>() else {
+                    return format!("error: invalid grace period: {}\n", grace);
+                };
+                let notice = (!text.is_empty()).then(|| Message::Text(text.join(" ")));
+                self.drain_with(notice, Some(Duration::from_secs(grace)));
+                "ok\n".to_string()
+            }
+            ["drain-status"] => format!(
+                "draining {}\nconnections {}\ndrained {}\n",
+                self.is_draining(),
+                self.connections().len(),
+                self.is_drained()
+            ),
+            _ => format!("error: unknown command: {}\n", command),
+        }
+    }
+}
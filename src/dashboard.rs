@@ -0,0 +1,136 @@
+// `/dashboard` で配信する統計情報のページ
+//
+// ページ自身が `/dashboard/ws` にWebSocketで接続し、
+// サーバーは1秒ごとに統計情報をJSONのTextメッセージとして送り続ける
+
+use std::{net::Shutdown, thread, time::Duration};
+
+use crate::{
+    connection::Connection,
+    handler::Handler,
+    handshake::Request,
+    http,
+    hub::Hub,
+    json,
+    message::Message,
+    server::{self, Shared},
+    stats::Snapshot,
+};
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>websocket-rs dashboard</title>
+<style>
+body { font-family: monospace; margin: 2em; }
+td { padding: 0.2em 1em; }
+</style>
+</head>
+<body>
+<h1>websocket-rs</h1>
+<table>
+<tr><td>connections</td><td id="connections">-</td></tr>
+<tr><td>messages in/s</td><td id="messages_in">-</td></tr>
+<tr><td>messages out/s</td><td id="messages_out">-</td></tr>
+<tr><td>bytes in/s</td><td id="bytes_in">-</td></tr>
+<tr><td>bytes out/s</td><td id="bytes_out">-</td></tr>
+</table>
+<h2>top rooms</h2>
+<table id="rooms"></table>
+<p id="status">connecting...</p>
+<script>
+const ws = new WebSocket(`ws://${location.host}/dashboard/ws`);
+ws.onopen = () => { document.getElementById("status").textContent = "live"; };
+ws.onclose = () => { document.getElementById("status").textContent = "disconnected"; };
+ws.onmessage = (event) => {
+  const stats = JSON.parse(event.data);
+  const rooms = document.getElementById("rooms");
+  rooms.replaceChildren(...stats.rooms.map(([name, members]) => {
+    const row = document.createElement("tr");
+    for (const value of [name, members]) {
+      const cell = document.createElement("td");
+      cell.textContent = value;
+      row.appendChild(cell);
+    }
+    return row;
+  }));
+  for (const key of Object.keys(stats)) {
+    const cell = document.getElementById(key);
+    if (cell) cell.textContent = stats[key];
+  }
+};
+</script>
+</body>
+</html>
+"#;
+
+/// dashboardが処理すべきリクエストか
+pub(crate) fn handles(request: &Request) -> bool {
+    request.path == "/dashboard" || request.path == "/dashboard/ws"
+}
+
+pub(crate) fn serve<H: Handler>(mut conn: Connection, request: &Request, shared: &Shared<H>) {
+    if request.path == "/dashboard" {
+        let response = http::response("200 OK", "text/html; charset=utf-8", PAGE);
+        let _ = conn.stream().write_all(response.as_bytes());
+        return;
+    }
+
+    conn.make_internal();
+    if server::accept(&mut conn, request.clone(), &[]).is_err() {
+        let _ = conn
+            .stream()
+            .write_all(http::response("400 Bad Request", "text/plain", "").as_bytes());
+        return;
+    }
+
+    let handle = conn.handle();
+    let registry = shared.registry.clone();
+    let stats = shared.stats.clone();
+    let hub = shared.hub.clone();
+    thread::spawn(move || {
+        let mut previous = stats.snapshot();
+        while !handle.is_closing() {
+            thread::sleep(Duration::from_secs(1));
+
+            let current = stats.snapshot();
+            let json = to_json(registry.entries().len(), &hub, &previous, &current);
+            previous = current;
+
+            if handle.send(Message::Text(json)).is_err() {
+                break;
+            }
+        }
+    });
+
+    // peerからのCloseを待つ。受信したメッセージは捨てる
+    let _ = server::serve(&mut conn, &Ignore, None);
+    let _ = conn.stream().shutdown(Shutdown::Both);
+}
+
+struct Ignore;
+
+impl Handler for Ignore {}
+
+/// 参加者の多い順に表示するroomの数
+const TOP_ROOMS: usize = 10;
+
+/// 1秒あたりの値にして返す
+fn to_json(connections: usize, hub: &Hub, previous: &Snapshot, current: &Snapshot) -> String {
+    let rooms = hub
+        .rooms()
+        .iter()
+        .take(TOP_ROOMS)
+        .map(|(room, members)| format!("[{},{}]", json::string(room), members))
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"connections\":{},\"rooms\":[{}],\"messages_in\":{},\"messages_out\":{},\"bytes_in\":{},\"bytes_out\":{}}}",
+        connections,
+        rooms.join(","),
+        current.messages_in - previous.messages_in,
+        current.messages_out - previous.messages_out,
+        current.bytes_in - previous.bytes_in,
+        current.bytes_out - previous.bytes_out,
+    )
+}
@@ -0,0 +1,768 @@
+// permessage-deflate (RFC 7692)
+//
+// `Server::with_extension(Deflate::new())` で登録すると、クライアントの提案と照らし合わせて
+// パラメーターを決め、101の応答に載せる:
+//
+//   Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits; server_max_window_bits=10
+//   -> Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=10
+//
+// 合意したパラメーター:
+// server_no_context_takeover -> クライアントが提案するか、`server_no_context_takeover()` で指定すると、
+//                               送信するメッセージごとに圧縮の辞書を捨てる
+// client_no_context_takeover -> `client_no_context_takeover()` で指定すると応答に載せ、
+//                               受信するメッセージごとに展開の辞書を捨てる
+// server_max_window_bits     -> クライアントの値と `server_max_window_bits()` の小さい方で圧縮する。
+//                               15 未満なら提案になくても応答に載せる。zlibは8bitのwindowで圧縮できないので、
+//                               8 を提案されたらその提案は合意しない
+// client_max_window_bits     -> クライアントが提案し、`client_max_window_bits()` を指定した場合だけ応答に載せる。
+//                               展開は常に15bitのwindowで行うので、クライアントがどの大きさで圧縮しても読める
+//
+// 圧縮したメッセージは最初のフレームにRSV1を立てる。fragmentされたメッセージは各フレームを続けて圧縮・展開する。
+// `min_size()` より小さいメッセージは圧縮しない (小さなメッセージは圧縮しても縮まず、CPUを使うだけなので)。
+// `ConnectionHandle::send_with_compression` でメッセージごとに圧縮するかを指定すると、`min_size()` より優先する。
+//
+// 小さな圧縮データが展開すると巨大になる (decompression bomb) ため、受信するメッセージは
+// 展開後の大きさ (`max_inflated_size()`) と圧縮率 (`max_ratio()`) を確かめながら少しずつ展開し、
+// 超えたら展開をやめて 1009 で閉じる。展開に使うメモリは `max_inflated_size()` の分までで済む
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::{
+    error::{Error, Result},
+    extension::{Codec, Extension, Offer},
+    frame::{Frame, Opcode},
+};
+
+const NAME: &str = "permessage-deflate";
+
+/// 圧縮したメッセージの最後から取り除く、空のstored block (RFC 7692 7.2.1)
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// 展開後の大きさがこれより小さいメッセージは圧縮率を確かめない。
+/// 同じ文字の繰り返しのような短いメッセージは、正当でも圧縮率が高くなるため
+const MIN_RATIO_CHECK: usize = 64 * 1024;
+
+/// サーバーの方針。`Server::with_extension` で登録する
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deflate {
+    level: u32,
+    server_max_window_bits: u8,
+    client_max_window_bits: Option<u8>,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    min_size: usize,
+    max_inflated_size: usize,
+    max_ratio: Option<usize>,
+}
+
+impl Default for Deflate {
+    fn default() -> Self {
+        Self {
+            level: Compression::default().level(),
+            server_max_window_bits: 15,
+            client_max_window_bits: None,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            min_size: 0,
+            max_inflated_size: 64 * 1024 * 1024,
+            max_ratio: None,
+        }
+    }
+}
+
+impl Deflate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 圧縮のレベル (0-9)。デフォルトは 6
+    pub fn level(mut self, level: u32) -> Self {
+        assert!(level <= 9, "compression level must be 0-9");
+        self.level = level;
+        self
+    }
+
+    /// 送信するメッセージを圧縮するwindowの大きさ (9-15)。小さいほど接続ごとのメモリが減る
+    pub fn server_max_window_bits(mut self, bits: u8) -> Self {
+        assert!(
+            (9..=15).contains(&bits),
+            "server_max_window_bits must be 9-15"
+        );
+        self.server_max_window_bits = bits;
+        self
+    }
+
+    /// クライアントが `client_max_window_bits` を提案した場合に、クライアントに使わせるwindowの大きさ (8-15)
+    pub fn client_max_window_bits(mut self, bits: u8) -> Self {
+        assert!(
+            (8..=15).contains(&bits),
+            "client_max_window_bits must be 8-15"
+        );
+        self.client_max_window_bits = Some(bits);
+        self
+    }
+
+    /// 送信するメッセージごとに圧縮の辞書を捨てる。圧縮率は下がるが、接続ごとの辞書を持ち続けなくてよい
+    pub fn server_no_context_takeover(mut self) -> Self {
+        self.server_no_context_takeover = true;
+        self
+    }
+
+    /// クライアントにもメッセージごとに辞書を捨てさせる
+    pub fn client_no_context_takeover(mut self) -> Self {
+        self.client_no_context_takeover = true;
+        self
+    }
+
+    /// この大きさ (bytes) より小さいメッセージは圧縮せずに送る。デフォルトは 0 (全て圧縮する)
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// 受信したメッセージを展開した後の最大の大きさ (bytes)。超えた接続は 1009 で閉じる。デフォルトは 64MiB
+    pub fn max_inflated_size(mut self, bytes: usize) -> Self {
+        self.max_inflated_size = bytes;
+        self
+    }
+
+    /// 受信したメッセージの、展開した後の大きさと圧縮された大きさの比の上限。超えた接続は 1009 で閉じる。
+    /// 展開後が64KiB未満のメッセージは確かめない。デフォルトは制限しない
+    pub fn max_ratio(mut self, ratio: usize) -> Self {
+        self.max_ratio = Some(ratio);
+        self
+    }
+
+    /// 提案を1つ検証し、合意できれば応答に載せる内容と接続ごとのパラメーターを返す
+    fn accept(&self, offer: &Offer) -> Option<(Offer, Params)> {
+        let mut server_no_context_takeover = false;
+        let mut server_max_window_bits = None;
+        let mut client_max_window_bits = None;
+        for (i, (key, value)) in offer.params.iter().enumerate() {
+            // 同じパラメーターが2回ある提案は不正
+            if offer.params[..i]
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case(key))
+            {
+                return None;
+            }
+            match (key.to_ascii_lowercase().as_str(), value.as_deref()) {
+                ("server_no_context_takeover", None) => server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => {}
+                ("server_max_window_bits", Some(value)) => {
+                    server_max_window_bits = Some(window_bits(value)?);
+                }
+                ("client_max_window_bits", None) => client_max_window_bits = Some(15),
+                ("client_max_window_bits", Some(value)) => {
+                    client_max_window_bits = Some(window_bits(value)?);
+                }
+                _ => return None,
+            }
+        }
+
+        let mut response = Offer::new(NAME);
+        let server_no_context_takeover =
+            server_no_context_takeover || self.server_no_context_takeover;
+        if server_no_context_takeover {
+            response = response.param("server_no_context_takeover", None);
+        }
+        if self.client_no_context_takeover {
+            response = response.param("client_no_context_takeover", None);
+        }
+        let window_bits = match server_max_window_bits {
+            Some(8) => return None,
+            Some(bits) => bits.min(self.server_max_window_bits),
+            None => self.server_max_window_bits,
+        };
+        if server_max_window_bits.is_some() || window_bits < 15 {
+            response = response.param("server_max_window_bits", Some(&window_bits.to_string()));
+        }
+        if let (Some(offered), Some(bits)) = (client_max_window_bits, self.client_max_window_bits) {
+            response = response.param(
+                "client_max_window_bits",
+                Some(&offered.min(bits).to_string()),
+            );
+        }
+
+        Some((
+            response,
+            Params {
+                level: self.level,
+                window_bits,
+                server_no_context_takeover,
+                client_no_context_takeover: self.client_no_context_takeover,
+                min_size: self.min_size,
+                max_inflated_size: self.max_inflated_size,
+                max_ratio: self.max_ratio,
+            },
+        ))
+    }
+}
+
+/// window_bitsの値 (8-15)。先頭の0や符号は認めない
+fn window_bits(value: &str) -> Option<u8> {
+    if value.starts_with('0') || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+impl Extension for Deflate {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn negotiate(&self, offers: &[Offer]) -> Option<(Offer, Box<dyn Codec>)> {
+        offers.iter().find_map(|offer| {
+            let (response, params) = self.accept(offer)?;
+            Some((
+                response,
+                Box::new(DeflateCodec::new(params)) as Box<dyn Codec>,
+            ))
+        })
+    }
+}
+
+/// 合意したパラメーター
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Params {
+    level: u32,
+    window_bits: u8,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    min_size: usize,
+    max_inflated_size: usize,
+    max_ratio: Option<usize>,
+}
+
+/// 接続ごとの圧縮・展開の状態
+struct DeflateCodec {
+    params: Params,
+    compress: Compress,
+    decompress: Decompress,
+    /// 送信中のメッセージを圧縮している
+    deflating: bool,
+    /// 受信中のメッセージが圧縮されている
+    inflating: bool,
+    /// 受信中のメッセージの、前のフレームまでの圧縮された大きさと展開した大きさ
+    received: (usize, usize),
+}
+
+impl DeflateCodec {
+    fn new(params: Params) -> Self {
+        Self {
+            params,
+            compress: Compress::new_with_window_bits(
+                Compression::new(params.level),
+                false,
+                params.window_bits,
+            ),
+            decompress: Decompress::new_with_window_bits(false, 15),
+            deflating: false,
+            inflating: false,
+            received: (0, 0),
+        }
+    }
+
+    /// メッセージの最初のフレームで圧縮するかを決める。`compress` がなければ `min_size` で決める
+    fn encode_message(&mut self, frame: &mut Frame, compress: Option<bool>) -> Result<()> {
+        match frame.opcode {
+            Opcode::Text | Opcode::Binary => {
+                self.deflating =
+                    compress.unwrap_or(!frame.fin || frame.payload.len() >= self.params.min_size);
+            }
+            Opcode::Continuation => {}
+            _ => return Ok(()),
+        }
+        if !self.deflating {
+            return Ok(());
+        }
+        frame.rsv1 = frame.opcode != Opcode::Continuation;
+        let mut payload = self.deflate(&frame.payload)?;
+        if frame.fin {
+            if payload.ends_with(&TAIL) {
+                payload.truncate(payload.len() - TAIL.len());
+            }
+            if self.params.server_no_context_takeover {
+                self.compress.reset();
+            }
+            self.deflating = false;
+        }
+        frame.payload = payload;
+        Ok(())
+    }
+
+    fn deflate(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(input.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&input[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(|e| Error::Protocol(format!("failed to compress: {}", e)))?;
+            // 入力を全て渡し、出力に余りがあればflushし終えている
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == input.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+            output.reserve(output.capacity().max(64));
+        }
+    }
+
+    fn inflate(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
+        let start = self.decompress.total_in();
+        let (compressed, inflated) = self.received;
+        loop {
+            // 上限を1byte超えるところまでしか確保しない
+            if output.len() == output.capacity() {
+                let room =
+                    (self.params.max_inflated_size + 1).saturating_sub(inflated + output.len());
+                output.reserve_exact((input.len() * 2).max(256).min(room.max(1)));
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], output, FlushDecompress::Sync)
+                .map_err(|e| Error::Protocol(format!("invalid compressed data: {}", e)))?;
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let size = inflated + output.len();
+            if size > self.params.max_inflated_size {
+                return Err(Error::MessageTooBig(size));
+            }
+            if let Some(ratio) = self.params.max_ratio {
+                if size >= MIN_RATIO_CHECK && size / (compressed + consumed).max(1) > ratio {
+                    return Err(Error::MessageTooBig(size));
+                }
+            }
+            // BFINALのblockで終わった後は、次のメッセージを新しい辞書で展開する
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                return Ok(());
+            }
+            if consumed == input.len() && output.len() < output.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Codec for DeflateCodec {
+    fn rsv_bits(&self) -> u8 {
+        0b100
+    }
+
+    fn encode(&mut self, frame: &mut Frame) -> Result<()> {
+        self.encode_message(frame, None)
+    }
+
+    fn encode_with(&mut self, frame: &mut Frame, compress: bool) -> Result<()> {
+        self.encode_message(frame, Some(compress))
+    }
+
+    fn decode(&mut self, frame: &mut Frame) -> Result<()> {
+        match frame.opcode {
+            Opcode::Text | Opcode::Binary => self.inflating = frame.rsv1,
+            _ if frame.rsv1 => {
+                return Err(Error::Protocol(format!(
+                    "RSV1 set on a {:?} frame",
+                    frame.opcode
+                )))
+            }
+            Opcode::Continuation => {}
+            _ => return Ok(()),
+        }
+        if !self.inflating {
+            return Ok(());
+        }
+        frame.rsv1 = false;
+        let mut payload = Vec::new();
+        self.inflate(&frame.payload, &mut payload)?;
+        self.received.0 += frame.payload.len();
+        if frame.fin {
+            self.inflate(&TAIL, &mut payload)?;
+            if self.params.client_no_context_takeover {
+                self.decompress.reset(false);
+            }
+            self.inflating = false;
+            self.received = (0, 0);
+        } else {
+            self.received.1 += payload.len();
+        }
+        frame.payload = payload;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{
+        connection::Connection, extension, handler::Handler, message::Message, server::Server,
+        testing,
+    };
+
+    fn negotiate(deflate: &Deflate, header: &str) -> Option<String> {
+        let offers = extension::parse(header);
+        deflate
+            .negotiate(&offers)
+            .map(|(response, _)| response.to_string())
+    }
+
+    #[test]
+    fn negotiates_parameters_from_offer_and_policy() {
+        let deflate = Deflate::new();
+        assert_eq!(
+            negotiate(&deflate, "permessage-deflate").as_deref(),
+            Some("permessage-deflate")
+        );
+        assert_eq!(
+            negotiate(
+                &deflate,
+                "permessage-deflate; client_max_window_bits; server_max_window_bits=10"
+            )
+            .as_deref(),
+            Some("permessage-deflate; server_max_window_bits=10")
+        );
+        assert_eq!(
+            negotiate(&deflate, "permessage-deflate; server_no_context_takeover").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+
+        let deflate = Deflate::new()
+            .server_max_window_bits(12)
+            .client_max_window_bits(10)
+            .client_no_context_takeover();
+        assert_eq!(
+            negotiate(&deflate, "permessage-deflate; client_max_window_bits").as_deref(),
+            Some(
+                "permessage-deflate; client_no_context_takeover; server_max_window_bits=12; \
+                 client_max_window_bits=10"
+            )
+        );
+        // 提案のない client_max_window_bits は応答に載せない
+        assert_eq!(
+            negotiate(&deflate, "permessage-deflate; server_max_window_bits=14").as_deref(),
+            Some("permessage-deflate; client_no_context_takeover; server_max_window_bits=12")
+        );
+    }
+
+    #[test]
+    fn skips_invalid_offers() {
+        let deflate = Deflate::new();
+        for header in [
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits=010",
+            "permessage-deflate; server_max_window_bits=8",
+            "permessage-deflate; client_no_context_takeover=1",
+            "permessage-deflate; unknown",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+        ] {
+            assert_eq!(negotiate(&deflate, header), None, "{}", header);
+        }
+        // 合意できない提案の次の提案を使う
+        assert_eq!(
+            negotiate(
+                &deflate,
+                "permessage-deflate; server_max_window_bits=8, permessage-deflate"
+            )
+            .as_deref(),
+            Some("permessage-deflate")
+        );
+    }
+
+    /// サーバーのCodecで圧縮したフレームを、クライアント役のCodecで展開する
+    fn round_trip(params: Params, frames: Vec<Frame>) -> Vec<Frame> {
+        let mut server = DeflateCodec::new(params);
+        let mut client = DeflateCodec::new(params);
+        frames
+            .into_iter()
+            .map(|mut frame| {
+                server.encode(&mut frame).unwrap();
+                client.decode(&mut frame).unwrap();
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compresses_messages_and_fragments() {
+        let params = Deflate::new().accept(&Offer::new(NAME)).unwrap().1;
+        let text = "hello ".repeat(100);
+        let mut server = DeflateCodec::new(params);
+        let mut frame = Frame::from(Message::Text(text.clone()));
+        server.encode(&mut frame).unwrap();
+        assert!(frame.rsv1);
+        assert!(frame.payload.len() < text.len() / 10);
+
+        let fragments = vec![
+            Frame::builder()
+                .opcode(Opcode::Text)
+                .fin(false)
+                .payload(b"hello ")
+                .build(),
+            Frame::new(Opcode::Ping, Some(b"ping".to_vec())),
+            Frame::builder()
+                .opcode(Opcode::Continuation)
+                .fin(true)
+                .payload(b"world")
+                .build(),
+            Frame::from(Message::Text("hello world".to_string())),
+        ];
+        let frames = round_trip(params, fragments);
+        let payloads = frames
+            .iter()
+            .map(|frame| (frame.rsv1, frame.payload.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            payloads,
+            [
+                (false, &b"hello "[..]),
+                (false, b"ping"),
+                (false, b"world"),
+                (false, b"hello world"),
+            ]
+        );
+    }
+
+    #[test]
+    fn discards_context_without_takeover() {
+        let accept = |deflate: Deflate| deflate.accept(&Offer::new(NAME)).unwrap().1;
+        let message = || Frame::from(Message::Text("hello hello hello".to_string()));
+        // 辞書を引き継ぐと、同じメッセージの2回目はより小さくなる
+        let mut codec = DeflateCodec::new(accept(Deflate::new()));
+        let sizes = [message(), message()].map(|mut frame| {
+            codec.encode(&mut frame).unwrap();
+            frame.payload.len()
+        });
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+
+        let mut codec = DeflateCodec::new(accept(Deflate::new().server_no_context_takeover()));
+        let sizes = [message(), message()].map(|mut frame| {
+            codec.encode(&mut frame).unwrap();
+            frame.payload.len()
+        });
+        assert_eq!(sizes[0], sizes[1]);
+
+        // クライアント役も辞書を引き継がずに圧縮するので、受信側が辞書を捨てても展開できる
+        let params = accept(
+            Deflate::new()
+                .server_no_context_takeover()
+                .client_no_context_takeover(),
+        );
+        let frames = round_trip(params, vec![message(), message()]);
+        assert!(frames
+            .iter()
+            .all(|frame| frame.payload == b"hello hello hello"));
+    }
+
+    #[test]
+    fn skips_small_messages_unless_asked() {
+        let params = Deflate::new()
+            .min_size(16)
+            .accept(&Offer::new(NAME))
+            .unwrap()
+            .1;
+        let mut codec = DeflateCodec::new(params);
+        let encode = |codec: &mut DeflateCodec, text: &str, compress: Option<bool>| {
+            let mut frame = Frame::from(Message::Text(text.to_string()));
+            match compress {
+                Some(compress) => codec.encode_with(&mut frame, compress).unwrap(),
+                None => codec.encode(&mut frame).unwrap(),
+            }
+            frame.rsv1
+        };
+        assert!(!encode(&mut codec, "small", None));
+        assert!(encode(&mut codec, "large enough to compress", None));
+        assert!(encode(&mut codec, "small", Some(true)));
+        assert!(!encode(&mut codec, "large enough to compress", Some(false)));
+    }
+
+    /// `len` bytesの0を圧縮したフレームを `fragments` 個に分けて作る
+    fn zeros(len: usize, fragments: usize) -> Vec<Frame> {
+        let params = Deflate::new().accept(&Offer::new(NAME)).unwrap().1;
+        let mut codec = DeflateCodec::new(params);
+        let size = len / fragments;
+        (0..fragments)
+            .map(|i| {
+                let mut frame = Frame::builder()
+                    .opcode(if i == 0 {
+                        Opcode::Binary
+                    } else {
+                        Opcode::Continuation
+                    })
+                    .fin(i == fragments - 1)
+                    .payload(vec![0; size])
+                    .build();
+                codec.encode(&mut frame).unwrap();
+                frame
+            })
+            .collect()
+    }
+
+    fn decode_all(deflate: Deflate, frames: Vec<Frame>) -> Result<usize> {
+        let params = deflate.accept(&Offer::new(NAME)).unwrap().1;
+        let mut codec = DeflateCodec::new(params);
+        let mut size = 0;
+        for mut frame in frames {
+            codec.decode(&mut frame)?;
+            size += frame.payload.len();
+        }
+        Ok(size)
+    }
+
+    #[test]
+    fn limits_inflated_size() {
+        let deflate = || Deflate::new().max_inflated_size(1024 * 1024);
+        assert_eq!(
+            decode_all(deflate(), zeros(1024 * 1024, 1)).unwrap(),
+            1024 * 1024
+        );
+        // 4MiBの0は4KiBほどに縮むが、1MiBを超えたところで展開をやめる
+        let frames = zeros(4 * 1024 * 1024, 1);
+        assert!(frames[0].payload.len() < 16 * 1024);
+        assert!(matches!(
+            decode_all(deflate(), frames),
+            Err(Error::MessageTooBig(size)) if size <= 1024 * 1024 + 256 * 1024
+        ));
+        // fragmentを合わせた大きさで制限する
+        assert!(matches!(
+            decode_all(deflate(), zeros(2 * 1024 * 1024, 4)),
+            Err(Error::MessageTooBig(_))
+        ));
+    }
+
+    #[test]
+    fn limits_compression_ratio() {
+        assert!(matches!(
+            decode_all(Deflate::new().max_ratio(100), zeros(1024 * 1024, 1)),
+            Err(Error::MessageTooBig(_))
+        ));
+        // 64KiB未満のメッセージは確かめない
+        assert_eq!(
+            decode_all(Deflate::new().max_ratio(100), zeros(32 * 1024, 1)).unwrap(),
+            32 * 1024
+        );
+        let text = (0..100_000u32)
+            .map(|i| i.wrapping_mul(2_654_435_761).to_string())
+            .collect::<String>();
+        let params = Deflate::new().accept(&Offer::new(NAME)).unwrap().1;
+        let mut frame = Frame::from(Message::Text(text.clone()));
+        DeflateCodec::new(params).encode(&mut frame).unwrap();
+        assert_eq!(
+            decode_all(Deflate::new().max_ratio(100), vec![frame]).unwrap(),
+            text.len()
+        );
+    }
+
+    #[test]
+    fn rejects_rsv1_on_continuation_and_control_frames() {
+        let params = Deflate::new().accept(&Offer::new(NAME)).unwrap().1;
+        let mut codec = DeflateCodec::new(params);
+        let mut ping = Frame::builder()
+            .opcode(Opcode::Ping)
+            .rsv1(true)
+            .payload(b"ping")
+            .build();
+        assert!(matches!(codec.decode(&mut ping), Err(Error::Protocol(_))));
+    }
+
+    /// 受信したメッセージを返す。"raw:" で始まるメッセージは圧縮せずに返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            match &message {
+                Message::Text(text) if text.starts_with("raw:") => {
+                    let _ = conn.send_with_compression(message, false);
+                }
+                _ => {
+                    let _ = conn.send(message);
+                }
+            }
+        }
+    }
+
+    /// permessage-deflateを提案してhandshakeし、101の応答を返す
+    fn connect(server: &testing::TestServer) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = vec![];
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        (stream, String::from_utf8(response).unwrap())
+    }
+
+    #[test]
+    fn server_negotiates_and_echoes_compressed_messages() {
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_extension(Deflate::new());
+        let server = testing::spawn_server(server).unwrap();
+        let (mut stream, response) = connect(&server);
+        assert!(
+            response.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"),
+            "{}",
+            response
+        );
+
+        let params = Deflate::new().accept(&Offer::new(NAME)).unwrap().1;
+        let mut client = DeflateCodec::new(params);
+        let text = "compressed ".repeat(50);
+        let mut frame = Frame::from(Message::Text(text.clone()));
+        client.encode(&mut frame).unwrap();
+        frame.payload_len = frame.payload.len();
+        stream
+            .write_all(&frame.masked([1, 2, 3, 4]).to_bytes())
+            .unwrap();
+
+        let mut echoed = Frame::read_from(&mut stream).unwrap();
+        assert!(echoed.rsv1);
+        assert!(echoed.payload.len() < text.len());
+        client.decode(&mut echoed).unwrap();
+        assert_eq!(echoed.payload, text.as_bytes());
+
+        let text = format!("raw:{}", text);
+        let mut frame = Frame::from(Message::Text(text.clone()));
+        client.encode(&mut frame).unwrap();
+        frame.payload_len = frame.payload.len();
+        stream
+            .write_all(&frame.masked([1, 2, 3, 4]).to_bytes())
+            .unwrap();
+        let echoed = Frame::read_from(&mut stream).unwrap();
+        assert!(!echoed.rsv1);
+        assert_eq!(echoed.payload, text.as_bytes());
+    }
+
+    #[test]
+    fn closes_with_1009_when_inflated_message_is_too_big() {
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_extension(Deflate::new().max_inflated_size(64 * 1024));
+        let server = testing::spawn_server(server).unwrap();
+        let (mut stream, _) = connect(&server);
+        let mut frame = zeros(1024 * 1024, 1).remove(0);
+        frame.payload_len = frame.payload.len();
+        stream
+            .write_all(&frame.masked([1, 2, 3, 4]).to_bytes())
+            .unwrap();
+        let close = Frame::read_from(&mut stream).unwrap();
+        assert_eq!(close.opcode, Opcode::Close);
+        assert_eq!(close.close_code_and_reason().unwrap().0, 1009);
+    }
+}
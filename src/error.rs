@@ -0,0 +1,139 @@
+use alloc::{format, string::String};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// TCPの読み書きに失敗した
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// handshakeのリクエストを受け付けなかった
+    Handshake(String),
+    /// RFC 6455に違反したフレームを受信した
+    Protocol(String),
+    /// Textフレームのpayloadが不正なUTF-8だった
+    InvalidUtf8,
+    /// クライアントからのフレームがマスクされていなかった
+    Unmasked,
+    /// 予約されたopcodeのフレームを受信した
+    ReservedOpcode(u8),
+    /// 受信したメッセージが大きすぎた (bytes)
+    MessageTooBig(usize),
+    /// サーバーの方針に違反した (Controlフレームを送りすぎたなど)
+    PolicyViolation(String),
+}
+
+/// 接続を閉じる原因になった違反の種類。サーバーの `ClosePolicy` で種類ごとにCloseフレームを決める
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ViolationKind {
+    InvalidUtf8,
+    MessageTooBig,
+    Unmasked,
+    ReservedOpcode,
+    /// その他のRFC 6455の違反
+    Protocol,
+    /// サーバーの方針への違反
+    Policy,
+}
+
+impl ViolationKind {
+    pub const ALL: [Self; 6] = [
+        Self::InvalidUtf8,
+        Self::MessageTooBig,
+        Self::Unmasked,
+        Self::ReservedOpcode,
+        Self::Protocol,
+        Self::Policy,
+    ];
+
+    /// RFC 6455 7.4.1 のstatus code
+    pub fn default_close_code(self) -> u16 {
+        match self {
+            Self::Protocol | Self::Unmasked | Self::ReservedOpcode => 1002,
+            Self::InvalidUtf8 => 1007,
+            Self::Policy => 1008,
+            Self::MessageTooBig => 1009,
+        }
+    }
+
+    /// "invalid-utf8" のような名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::InvalidUtf8 => "invalid-utf8",
+            Self::MessageTooBig => "too-big",
+            Self::Unmasked => "unmasked",
+            Self::ReservedOpcode => "reserved-opcode",
+            Self::Protocol => "protocol",
+            Self::Policy => "policy",
+        }
+    }
+}
+
+impl core::str::FromStr for ViolationKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| format!("unknown violation: {}", s))
+    }
+}
+
+impl Error {
+    /// 接続を閉じる原因になる違反なら、その種類
+    pub fn violation_kind(&self) -> Option<ViolationKind> {
+        match self {
+            #[cfg(feature = "std")]
+            Self::Io(_) => None,
+            Self::Handshake(_) => None,
+            Self::Protocol(_) => Some(ViolationKind::Protocol),
+            Self::InvalidUtf8 => Some(ViolationKind::InvalidUtf8),
+            Self::Unmasked => Some(ViolationKind::Unmasked),
+            Self::ReservedOpcode(_) => Some(ViolationKind::ReservedOpcode),
+            Self::PolicyViolation(_) => Some(ViolationKind::Policy),
+            Self::MessageTooBig(_) => Some(ViolationKind::MessageTooBig),
+        }
+    }
+
+    /// 接続を閉じる際にCloseフレームに載せるstatus code (RFC 6455 7.4.1)
+    pub fn close_code(&self) -> Option<u16> {
+        self.violation_kind().map(ViolationKind::default_close_code)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::Handshake(reason) => write!(f, "handshake rejected: {}", reason),
+            Self::Protocol(reason) => write!(f, "protocol violation: {}", reason),
+            Self::InvalidUtf8 => write!(f, "invalid UTF-8 in text message"),
+            Self::Unmasked => write!(f, "unmasked frame from client"),
+            Self::ReservedOpcode(opcode) => write!(f, "reserved opcode: {:#x}", opcode),
+            Self::PolicyViolation(reason) => write!(f, "policy violation: {}", reason),
+            Self::MessageTooBig(size) => write!(f, "message too big: {} bytes", size),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
@@ -0,0 +1,633 @@
+// 接続ごとにスレッドを使わないサーバーのevent loop
+//
+// `Server::run_event_loop(threads)` は `threads` 個のスレッドで全ての接続を扱う。
+// 各スレッドは `reactor` (epoll・kqueue) で待ち受けソケットと受け持つ接続のreadinessを待ち、
+// 読み込めるようになった接続から届いている分だけを読み込む。handshakeのヘッダーやフレームが
+// 途中までしか届いていなければ接続ごとのbufferに溜め、揃ったものから処理する。
+// 読み込みを待って止まることはないので、ゆっくり送ってくる接続が他の接続を止めることはない
+//
+// 届いたbyte列を処理する `State` はI/Oに依存しないので、io_uringのevent loop (`uring`) も同じものを使う
+//
+// ソケットはブロッキングのまま使う。readinessを確かめてから1回だけ `read` するので待たずに戻り、
+// 他のスレッドから `ConnectionHandle::send` で書き込む場合もnon-blockingのモードに影響されない。
+// 接続を閉じたり他のスレッドに渡したりする前には、必ずreactorから外す。
+// `ConnectionHandle` などが同じソケットを複製して持っていると、閉じても登録が残るため
+//
+// Handlerはevent loopのスレッドで呼ぶ。Handlerの中で待つと、同じスレッドの他の接続も待たされる。
+// 書き込みもブロッキングなので、読み込みの遅いpeerには `Config::send_queue` を使う
+// (送信キューは接続ごとに送信スレッドを使う)。
+// dashboard・SSE・long-pollingは応答を送り続けるため、これまでどおり接続ごとのスレッドで処理する
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::TcpListener,
+    os::fd::{AsRawFd, RawFd},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    connection::Connection,
+    error::{Error, Result},
+    frame::{Frame, Opcode},
+    handler::Handler,
+    handshake::{Head, Request},
+    log::warning,
+    reactor::{self, Interest, Reactor},
+    server::{self, Shared},
+    telemetry::Span,
+};
+
+/// 待ち受けソケットのtoken。接続には1から割り当てる
+const LISTENER: usize = 0;
+
+/// 1回の `read` で読み込む大きさ
+const READ_CHUNK: usize = 16 * 1024;
+
+/// `threads` 個のスレッドでevent loopを動かす。エラーで止まったスレッドがあれば、そのエラーを返す
+pub(crate) fn run<H: Handler>(
+    listener: TcpListener,
+    shared: Arc<Shared<H>>,
+    threads: usize,
+) -> io::Result<()> {
+    // 同じ接続を複数のスレッドがacceptしようとするので、acceptは待たずに戻るようにする
+    listener.set_nonblocking(true)?;
+    run_threads(listener, shared, threads, |listener, shared| {
+        EventLoop::new(listener, shared)?.run()
+    })
+}
+
+/// `threads` 個のスレッドで `event_loop` を動かす。1つは呼び出したスレッドで動かす
+pub(crate) fn run_threads<H: Handler>(
+    listener: TcpListener,
+    shared: Arc<Shared<H>>,
+    threads: usize,
+    event_loop: fn(TcpListener, Arc<Shared<H>>) -> io::Result<()>,
+) -> io::Result<()> {
+    let workers = (1..threads.max(1))
+        .map(|_| {
+            let listener = listener.try_clone()?;
+            let shared = shared.clone();
+            Ok(thread::spawn(move || event_loop(listener, shared)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let result = event_loop(listener, shared);
+    for worker in workers {
+        if let Ok(Err(e)) = worker.join() {
+            warning!(
+                "event_loop_error",
+                { error: e.to_string() },
+                "event loop stopped: {}",
+                e
+            );
+        }
+    }
+    result
+}
+
+/// 接続の状態
+pub(crate) enum State {
+    /// handshakeのリクエストのヘッダーを読み込んでいる
+    Handshake(Handshake),
+    /// WebSocketのフレームを読み込んでいる
+    Open(Open),
+}
+
+pub(crate) struct Handshake {
+    conn: Connection,
+    span: Option<Span>,
+    handshake_span: Option<Span>,
+    head: Head,
+    /// `handshake_limits.timeout` による期限
+    expires: Instant,
+    /// `expires` と、最後に届いてから `handshake_limits.read_timeout` が過ぎる時刻の早い方
+    deadline: Instant,
+}
+
+pub(crate) struct Open {
+    conn: Connection,
+    span: Option<Span>,
+    /// fragmentされたメッセージの先頭フレームのopcodeと、結合中のpayload
+    fragments: Option<(Opcode, Vec<u8>)>,
+    /// 届いたがフレームとしてまだ揃っていないbyte列
+    inbox: Vec<u8>,
+}
+
+/// 届いたbyte列を処理した後の接続の扱い
+pub(crate) enum Step {
+    /// 続きが届くのを待つ
+    Wait(State),
+    /// 接続を終える。reactorなどから外してから `Ending::end` を呼ぶ
+    End(Ending),
+    /// dashboard・SSE・long-pollingのリクエスト。専用のスレッドで `server::serve_http` を呼ぶ
+    Http(Connection, Request, Vec<u8>),
+}
+
+/// 終える接続
+pub(crate) enum Ending {
+    /// handshakeを終えられなかった
+    Reject(Handshake, Error),
+    /// WebSocketの接続を閉じる。`Err` ならエラーを記録してから閉じる
+    Finish(Open, Result<()>),
+    /// handshakeの応答で拒否した。後始末は済んでいる
+    Closed,
+}
+
+impl State {
+    /// `server::open` で作った接続のhandshakeを待つ
+    pub(crate) fn accepted<H: Handler>(
+        conn: Connection,
+        span: Option<Span>,
+        handshake_span: Option<Span>,
+        shared: &Shared<H>,
+    ) -> Self {
+        let limits = &shared.config.handshake_limits;
+        let now = Instant::now();
+        let expires = now + limits.timeout;
+        State::Handshake(Handshake {
+            conn,
+            span,
+            handshake_span,
+            head: Head::default(),
+            expires,
+            deadline: expires.min(now + limits.read_timeout),
+        })
+    }
+
+    /// handshakeを読み込んでいれば、その期限
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        match self {
+            State::Handshake(handshake) => Some(handshake.deadline),
+            State::Open(_) => None,
+        }
+    }
+
+    /// `e` で接続を終える
+    pub(crate) fn abort(self, e: Error) -> Ending {
+        match self {
+            State::Handshake(handshake) => Ending::Reject(handshake, e),
+            State::Open(open) => Ending::Finish(open, Err(e)),
+        }
+    }
+
+    /// 期限までにhandshakeのヘッダーが揃わなかった接続を終える
+    pub(crate) fn expire(self) -> Ending {
+        self.abort(Error::Io(io::Error::new(
+            ErrorKind::TimedOut,
+            "handshake timed out",
+        )))
+    }
+
+    pub(crate) fn conn_mut(&mut self) -> &mut Connection {
+        match self {
+            State::Handshake(handshake) => &mut handshake.conn,
+            State::Open(open) => &mut open.conn,
+        }
+    }
+
+    /// 届いたbyte列を処理する。`received` は空ならEOF
+    pub(crate) fn received<H: Handler>(
+        self,
+        received: io::Result<&[u8]>,
+        shared: &Shared<H>,
+    ) -> Step {
+        match self {
+            State::Handshake(handshake) => handshake.received(received, shared),
+            State::Open(mut open) => {
+                let read = match received {
+                    Ok([]) => Err(Error::Io(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "connection closed",
+                    ))),
+                    Ok(bytes) => {
+                        open.inbox.extend_from_slice(bytes);
+                        Ok(())
+                    }
+                    Err(e) => Err(Error::Io(e)),
+                };
+                open.process(read, shared)
+            }
+        }
+    }
+}
+
+impl Handshake {
+    fn received<H: Handler>(mut self, received: io::Result<&[u8]>, shared: &Shared<H>) -> Step {
+        let limits = &shared.config.handshake_limits;
+        let pushed = match received {
+            Ok([]) => Err(Error::Handshake("connection closed".to_string())),
+            Ok(bytes) => {
+                self.deadline = self.expires.min(Instant::now() + limits.read_timeout);
+                self.head.push(bytes, limits)
+            }
+            Err(e) => Err(Error::Io(e)),
+        };
+        let (request, body) = match pushed {
+            Ok(Some(request)) => request,
+            Ok(None) => return Step::Wait(State::Handshake(self)),
+            Err(e) => return Step::End(Ending::Reject(self, e)),
+        };
+        let Handshake {
+            conn,
+            span,
+            handshake_span,
+            ..
+        } = self;
+
+        if server::handles_http(&request, shared) {
+            return Step::Http(conn, request, body);
+        }
+        // 拒否した場合は、接続を複製したものが残っていないので閉じるだけでよい
+        let Some((conn, span)) = server::establish(conn, request, span, handshake_span, shared)
+        else {
+            return Step::End(Ending::Closed);
+        };
+        // handshakeのリクエストに続けて送られてきたフレーム
+        let open = Open {
+            conn,
+            span,
+            fragments: None,
+            inbox: body,
+        };
+        open.process(Ok(()), shared)
+    }
+}
+
+impl Open {
+    /// `inbox` に揃っているフレームを処理する。Closeのやりとりを終えたか、エラーなら接続を終える
+    fn process<H: Handler>(mut self, read: Result<()>, shared: &Shared<H>) -> Step {
+        let Open {
+            conn,
+            span,
+            fragments,
+            inbox,
+        } = &mut self;
+        let trace = span.as_ref().zip(shared.exporter.as_deref());
+        let result: Result<bool> = read.and_then(|()| {
+            let mut consumed = 0;
+            let done = loop {
+                let max_payload_len = conn.max_message_size();
+                let Some((frame, len)) =
+                    Frame::parse_with_limit(&inbox[consumed..], max_payload_len)?
+                else {
+                    break false;
+                };
+                consumed += len;
+                let frame = conn.receive_frame(frame)?;
+                if server::dispatch(conn, &shared.handler, trace, fragments, frame)? {
+                    break true;
+                }
+            };
+            inbox.drain(..consumed);
+            Ok(done)
+        });
+        let result = match result {
+            Ok(false) => return Step::Wait(State::Open(self)),
+            Ok(true) => Ok(()),
+            // こちらから閉じた (kickなど) 後の切断はエラーとしない
+            Err(Error::Io(_)) if conn.is_closing() => Ok(()),
+            Err(e) => Err(e),
+        };
+        Step::End(Ending::Finish(self, result))
+    }
+}
+
+impl Ending {
+    pub(crate) fn end<H: Handler>(self, shared: &Shared<H>) {
+        match self {
+            Ending::Reject(handshake, e) => {
+                let Handshake {
+                    mut conn,
+                    span,
+                    handshake_span,
+                    ..
+                } = handshake;
+                shared.strike(&conn, &e);
+                server::end_with_error(handshake_span, span, shared.exporter.as_deref(), &e);
+                server::reject(&mut conn, &shared.handler, e);
+            }
+            Ending::Finish(open, result) => server::finish(open.conn, open.span, result, shared),
+            Ending::Closed => {}
+        }
+    }
+}
+
+struct Entry {
+    fd: RawFd,
+    state: State,
+}
+
+/// 1つのスレッドのevent loop
+struct EventLoop<H> {
+    listener: TcpListener,
+    shared: Arc<Shared<H>>,
+    reactor: Box<dyn Reactor>,
+    entries: HashMap<usize, Entry>,
+    next_token: usize,
+    /// `read` に使うbuffer
+    chunk: Vec<u8>,
+}
+
+impl<H: Handler> EventLoop<H> {
+    fn new(listener: TcpListener, shared: Arc<Shared<H>>) -> io::Result<Self> {
+        let reactor = reactor::new()?;
+        reactor.register(listener.as_raw_fd(), LISTENER, Interest::Readable)?;
+        Ok(Self {
+            listener,
+            shared,
+            reactor,
+            entries: HashMap::new(),
+            next_token: LISTENER + 1,
+            chunk: vec![0; READ_CHUNK],
+        })
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        let mut events = vec![];
+        loop {
+            // handshakeの期限が一番近いものまで待つ
+            let now = Instant::now();
+            let timeout = self
+                .entries
+                .values()
+                .filter_map(|entry| entry.state.deadline())
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now));
+            events.clear();
+            self.reactor.poll(&mut events, timeout)?;
+
+            for event in &events {
+                if event.token == LISTENER {
+                    self.accept()?;
+                } else if event.readable {
+                    self.receive(event.token);
+                }
+            }
+            self.expire_handshakes();
+        }
+    }
+
+    /// 届いている接続を全て受け付ける
+    fn accept(&mut self) -> io::Result<()> {
+        // 新しいプロセスに引き継いだ後は待ち受けソケットを見ない
+        if self.shared.handed_off.load(Ordering::SeqCst) {
+            let _ = self.reactor.deregister(self.listener.as_raw_fd());
+            return Ok(());
+        }
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // 接続ごとのエラー (ECONNABORTEDなど) や、fdが足りない場合は次のイベントで受け付け直す
+                Err(_) => return Ok(()),
+            };
+            // macOS・BSDでは待ち受けソケットのnon-blockingを引き継ぐので戻す
+            stream.set_nonblocking(false)?;
+            if !self.shared.admits(&mut stream) {
+                continue;
+            }
+
+            let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+            let fd = stream.as_raw_fd();
+            let Some((conn, span, handshake_span)) =
+                server::open(id, Box::new(stream), &self.shared)
+            else {
+                continue;
+            };
+            let state = State::accepted(conn, span, handshake_span, &self.shared);
+            let token = self.next_token;
+            self.next_token += 1;
+            if let Err(e) = self.reactor.register(fd, token, Interest::Readable) {
+                state.abort(Error::Io(e)).end(&self.shared);
+                continue;
+            }
+            self.entries.insert(token, Entry { fd, state });
+        }
+    }
+
+    /// 読み込めるようになった接続から、届いている分を読み込んで処理する
+    fn receive(&mut self, token: usize) {
+        let Some(Entry { fd, mut state }) = self.entries.remove(&token) else {
+            return;
+        };
+        let step = match read_available(state.conn_mut(), &mut self.chunk) {
+            Ok(Some(n)) => state.received(Ok(&self.chunk[..n]), &self.shared),
+            Ok(None) => Step::Wait(state),
+            Err(e) => state.received(Err(e), &self.shared),
+        };
+        match step {
+            Step::Wait(state) => {
+                self.entries.insert(token, Entry { fd, state });
+            }
+            Step::End(ending) => {
+                let _ = self.reactor.deregister(fd);
+                ending.end(&self.shared);
+            }
+            Step::Http(conn, request, body) => {
+                let _ = self.reactor.deregister(fd);
+                // 応答を送り続けるので、専用のスレッドに渡す
+                let shared = self.shared.clone();
+                thread::spawn(move || server::serve_http(conn, &request, body, &shared));
+            }
+        }
+    }
+
+    /// 期限までにhandshakeのヘッダーが揃わなかった接続を閉じる
+    fn expire_handshakes(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .state
+                    .deadline()
+                    .is_some_and(|deadline| deadline <= now)
+            })
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for token in expired {
+            let Some(Entry { fd, state }) = self.entries.remove(&token) else {
+                continue;
+            };
+            let _ = self.reactor.deregister(fd);
+            state.expire().end(&self.shared);
+        }
+    }
+}
+
+/// 届いている分を `chunk` に読み込み、読み込んだbyte数を返す。EOFなら 0、読み込めるものがなければ None
+fn read_available(conn: &mut Connection, chunk: &mut [u8]) -> io::Result<Option<usize>> {
+    match conn.stream().read(chunk) {
+        Ok(n) => Ok(Some(n)),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        message::Message,
+        server::{Config, Server},
+        testing,
+    };
+
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    const REQUEST: &str = "GET / HTTP/1.1\r\n\
+        Host: 127.0.0.1\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        \r\n";
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut response = vec![];
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    fn text(payload: &str) -> Vec<u8> {
+        Frame::new(Opcode::Text, Some(payload.as_bytes().to_vec()))
+            .masked([1, 2, 3, 4])
+            .to_bytes()
+    }
+
+    #[test]
+    fn echoes_messages_of_many_clients_on_one_thread() {
+        let server = Server::bind("127.0.0.1:0", Echo).unwrap();
+        let server = testing::spawn_event_loop_server(server, 1).unwrap();
+        let mut clients = (0..3)
+            .map(|_| server.connect("/").unwrap())
+            .collect::<Vec<_>>();
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.send(Message::Text(format!("hello {}", i))).unwrap();
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            assert_eq!(
+                client.recv().unwrap(),
+                Some(Message::Text(format!("hello {}", i)))
+            );
+        }
+        for mut client in clients {
+            client.close(1000, "bye").unwrap();
+        }
+    }
+
+    #[test]
+    fn slow_senders_do_not_block_other_connections() {
+        let server = Server::bind("127.0.0.1:0", Echo).unwrap();
+        let server = testing::spawn_event_loop_server(server, 1).unwrap();
+
+        // handshakeのリクエストとフレームを途中まで送ったまま止まる接続
+        let mut slow = TcpStream::connect(server.addr()).unwrap();
+        slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (first, rest) = REQUEST.split_at(20);
+        slow.write_all(first.as_bytes()).unwrap();
+
+        let mut client = server.connect("/").unwrap();
+        client.send(Message::Text("fast".to_string())).unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("fast".to_string()))
+        );
+
+        slow.write_all(rest.as_bytes()).unwrap();
+        assert!(read_response(&mut slow).starts_with("HTTP/1.1 101"));
+        let frame = text("slow");
+        slow.write_all(&frame[..3]).unwrap();
+
+        client
+            .send(Message::Text("still fast".to_string()))
+            .unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("still fast".to_string()))
+        );
+
+        slow.write_all(&frame[3..]).unwrap();
+        let echoed = Frame::read_from(&mut slow).unwrap();
+        assert_eq!(echoed.payload, b"slow");
+    }
+
+    #[test]
+    fn reads_frames_sent_with_the_handshake() {
+        let server = Server::bind("127.0.0.1:0", Echo).unwrap();
+        let server = testing::spawn_event_loop_server(server, 2).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut bytes = REQUEST.as_bytes().to_vec();
+        bytes.extend(text("one"));
+        bytes.extend(text("two"));
+        stream.write_all(&bytes).unwrap();
+
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 101"));
+        assert_eq!(Frame::read_from(&mut stream).unwrap().payload, b"one");
+        assert_eq!(Frame::read_from(&mut stream).unwrap().payload, b"two");
+    }
+
+    #[test]
+    fn closes_handshakes_that_do_not_finish_in_time() {
+        let mut config = Config::default();
+        config.handshake_limits.timeout = Duration::from_millis(200);
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_config(config);
+        let server = testing::spawn_event_loop_server(server, 1).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(&REQUEST.as_bytes()[..20]).unwrap();
+
+        let started = Instant::now();
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn finishes_connections_closed_by_the_peer() {
+        let server = Server::bind("127.0.0.1:0", Echo).unwrap();
+        let server = testing::spawn_event_loop_server(server, 1).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(REQUEST.as_bytes()).unwrap();
+        read_response(&mut stream);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.admin().connections().is_empty() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        drop(stream);
+        while !server.admin().connections().is_empty() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// Sec-WebSocket-Extensionsで合意する拡張
+//
+// クライアントは以下のように提案する (RFC 6455 9.1):
+//   Sec-WebSocket-Extensions: foo; x=10, foo, bar
+// `Server::with_extension` で登録したExtensionに名前の一致する提案を渡し、合意したものを
+// 101の応答の Sec-WebSocket-Extensions に載せる。
+// 合意した拡張のCodecは、送信するフレームには登録した順に、受信したフレームには逆の順に適用する
+
+use std::fmt;
+#[cfg(feature = "server")]
+use std::sync::{Arc, Mutex};
+
+use crate::{error::Result, frame::Frame};
+
+/// 拡張の名前とパラメーター
+#[derive(Clone, Debug, PartialEq)]
+pub struct Offer {
+    pub name: String,
+    /// 値のないパラメーターは None
+    pub params: Vec<(String, Option<String>)>,
+}
+
+impl Offer {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            params: vec![],
+        }
+    }
+
+    pub fn param(mut self, key: &str, value: Option<&str>) -> Self {
+        self.params
+            .push((key.to_string(), value.map(str::to_string)));
+        self
+    }
+
+    /// パラメーターの値。パラメーターがなければ None、値がなければ Some(None)
+    pub fn get(&self, key: &str) -> Option<Option<&str>> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_deref())
+    }
+}
+
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (key, value) in &self.params {
+            match value {
+                Some(value) => write!(f, "; {}={}", key, value)?,
+                None => write!(f, "; {}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sec-WebSocket-Extensionsの値をパースする
+pub fn parse(header: &str) -> Vec<Offer> {
+    header
+        .split(',')
+        .filter_map(|offer| {
+            let mut parts = offer.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let params = parts
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.split_once('=') {
+                    Some((key, value)) => {
+                        let value = value.trim();
+                        // 値はquoted-stringでもよい
+                        let value = value
+                            .strip_prefix('"')
+                            .and_then(|v| v.strip_suffix('"'))
+                            .unwrap_or(value);
+                        (key.trim().to_string(), Some(value.to_string()))
+                    }
+                    None => (param.to_string(), None),
+                })
+                .collect();
+            Some(Offer {
+                name: name.to_string(),
+                params,
+            })
+        })
+        .collect()
+}
+
+/// 合意した拡張をSec-WebSocket-Extensionsの値にする
+pub fn to_header(offers: &[Offer]) -> String {
+    offers
+        .iter()
+        .map(Offer::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// サーバーが対応する拡張
+pub trait Extension: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    /// 名前の一致する提案 (クライアントの優先順) から1つを選び、応答に載せる内容と接続ごとのCodecを返す。
+    /// None なら合意しない
+    fn negotiate(&self, offers: &[Offer]) -> Option<(Offer, Box<dyn Codec>)>;
+}
+
+/// 合意した拡張の接続ごとの処理
+pub trait Codec: Send + 'static {
+    /// 使うRSVビット (RSV1 = 0b100, RSV2 = 0b010, RSV3 = 0b001)。他の拡張と重なる場合は合意しない
+    fn rsv_bits(&self) -> u8;
+
+    /// 送信するフレームを書き換える (RSVビットを立てる・payloadを変換するなど)
+    fn encode(&mut self, frame: &mut Frame) -> Result<()>;
+
+    /// 圧縮するかを指定したメッセージ (`ConnectionHandle::send_with_compression`) のフレームを書き換える。
+    /// 圧縮しない拡張は区別しなくてよいので、デフォルトは `encode` と同じ
+    fn encode_with(&mut self, frame: &mut Frame, _compress: bool) -> Result<()> {
+        self.encode(frame)
+    }
+
+    /// 受信したフレーム (マスクは解除済み) を書き換える
+    fn decode(&mut self, frame: &mut Frame) -> Result<()>;
+}
+
+/// 接続で合意したCodec (合意した順)
+#[cfg(feature = "server")]
+pub(crate) type Codecs = Arc<Mutex<Vec<Box<dyn Codec>>>>;
+
+/// 登録した順に提案と照らし合わせ、応答に載せる内容とCodecを返す
+#[cfg(feature = "server")]
+pub(crate) fn negotiate(
+    extensions: &[Box<dyn Extension>],
+    header: &str,
+) -> (Vec<Offer>, Vec<Box<dyn Codec>>) {
+    let offers = parse(header);
+    let mut accepted = vec![];
+    let mut codecs: Vec<Box<dyn Codec>> = vec![];
+    let mut used_bits = 0;
+    for extension in extensions {
+        let offers = offers
+            .iter()
+            .filter(|offer| offer.name.eq_ignore_ascii_case(extension.name()))
+            .cloned()
+            .collect::<Vec<_>>();
+        if offers.is_empty() {
+            continue;
+        }
+        if let Some((response, codec)) = extension.negotiate(&offers) {
+            if codec.rsv_bits() & used_bits != 0 {
+                continue;
+            }
+            used_bits |= codec.rsv_bits();
+            accepted.push(response);
+            codecs.push(codec);
+        }
+    }
+    (accepted, codecs)
+}
+
+#[cfg(feature = "server")]
+pub(crate) fn encode(codecs: &Codecs, frame: &mut Frame, compress: Option<bool>) -> Result<()> {
+    for codec in codecs.lock().unwrap().iter_mut() {
+        match compress {
+            Some(compress) => codec.encode_with(frame, compress)?,
+            None => codec.encode(frame)?,
+        }
+    }
+    frame.payload_len = frame.payload.len();
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+pub(crate) fn decode(codecs: &Codecs, frame: &mut Frame) -> Result<()> {
+    for codec in codecs.lock().unwrap().iter_mut().rev() {
+        codec.decode(frame)?;
+    }
+    frame.payload_len = frame.payload.len();
+    Ok(())
+}
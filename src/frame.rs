@@ -0,0 +1,773 @@
+use alloc::{format, string::String, vec, vec::Vec};
+use core::fmt;
+
+use crate::error::{Error, Result};
+
+/// フレームを読み込む元。stdが使えれば `std::io::Read` を実装した型は全て使える。
+/// no_stdでは `&[u8]` から読み込む
+pub trait ReadExact {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()>;
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read + ?Sized> ReadExact for R {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        std::io::Read::read_exact(self, buf).map_err(Error::Io)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ReadExact for &[u8] {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        if self.len() < buf.len() {
+            return Err(Error::Protocol(String::from("unexpected end of frame")));
+        }
+        let (head, rest) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = rest;
+        Ok(())
+    }
+}
+
+/// 受信するフレームのpayloadのデフォルトの上限 (64MiB)。`Config::max_message_size` のデフォルトで、
+/// `Client` が受信するフレームにも使う
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Continuation, // = 0x0,
+    Text,         // = 0x1,
+    Binary,       // = 0x2,
+    Close,        // = 0x8,
+    Ping,         // = 0x9,
+    Pong,         // = 0xA,
+}
+
+impl Opcode {
+    /// Close, Ping, Pong
+    pub fn is_control(&self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub fin: bool,
+    pub rsv1: bool,
+    pub rsv2: bool,
+    pub rsv3: bool,
+    pub opcode: Opcode,
+    pub mask: bool,
+    /// included extendted payload length
+    pub payload_len: usize,
+    pub masking_key: Option<[u8; 4]>,
+    /// decoded with masking_key
+    pub payload: Vec<u8>,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = Error;
+
+    fn try_from(byte: u8) -> Result<Self> {
+        match byte & 0x0F {
+            0x0 => Ok(Self::Continuation),
+            0x1 => Ok(Self::Text),
+            0x2 => Ok(Self::Binary),
+            0x8 => Ok(Self::Close),
+            0x9 => Ok(Self::Ping),
+            0xA => Ok(Self::Pong),
+            n => Err(Error::ReservedOpcode(n)),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: Option<Vec<u8>>) -> Self {
+        let (payload_len, payload) = match payload {
+            Some(payload) => (payload.len(), payload),
+            None => (0, vec![]),
+        };
+
+        Self {
+            fin: true, // Fragmentation is not supported, so always true
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode,
+            mask: false,
+            payload_len,
+            masking_key: None,
+            payload,
+        }
+    }
+
+    /// Close frame with a status code and a reason (RFC 6455 5.5.1)
+    pub fn close(code: u16, reason: &str) -> Self {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        Self::new(Opcode::Close, Some(payload))
+    }
+
+    /// Closeフレームのpayloadからstatus codeとreasonを取り出す
+    pub fn close_code_and_reason(&self) -> Option<(u16, String)> {
+        if self.opcode != Opcode::Close || self.payload.len() < 2 {
+            return None;
+        }
+        let code = u16::from_be_bytes([self.payload[0], self.payload[1]]);
+        let reason = String::from_utf8_lossy(&self.payload[2..]).into_owned();
+        Some((code, reason))
+    }
+
+    /// 任意のフレームを組み立てる (テストや拡張の実装用)
+    pub fn builder() -> FrameBuilder {
+        FrameBuilder {
+            frame: Self::new(Opcode::Binary, None),
+            payload_len: None,
+        }
+    }
+
+    /// masking_keyを付与する (クライアントから送信するフレームは必ずマスクする)
+    pub fn masked(mut self, masking_key: [u8; 4]) -> Self {
+        self.mask = true;
+        self.masking_key = Some(masking_key);
+        self
+    }
+
+    /// `sender` が送ったフレームとしてRFC 6455に従っているか調べ、違反を全て返す。
+    /// `rsv_bits` は合意した拡張が使うRSVビット (RSV1 = 0b100, RSV2 = 0b010, RSV3 = 0b001)
+    pub fn validate(&self, sender: Role, rsv_bits: u8) -> core::result::Result<(), Vec<Violation>> {
+        let mut violations = vec![];
+
+        // クライアントからのフレームは必ずマスクし、サーバーからのフレームはマスクしない (5.1)
+        match (sender, self.mask) {
+            (Role::Client, false) => violations.push(Violation::Unmasked),
+            (Role::Server, true) => violations.push(Violation::Masked),
+            _ => {}
+        }
+        if self.mask && self.masking_key.is_none() {
+            violations.push(Violation::MissingMaskingKey);
+        }
+
+        let rsv = (self.rsv1 as u8) << 2 | (self.rsv2 as u8) << 1 | self.rsv3 as u8;
+        if rsv & !rsv_bits != 0 {
+            violations.push(Violation::ReservedBits(rsv & !rsv_bits));
+        }
+
+        if self.payload_len != self.payload.len() {
+            violations.push(Violation::PayloadLengthMismatch {
+                header: self.payload_len,
+                actual: self.payload.len(),
+            });
+        }
+
+        // Controlフレームはfragmentせず、payloadは125bytes以下 (5.5)
+        if self.opcode.is_control() {
+            if !self.fin {
+                violations.push(Violation::FragmentedControl);
+            }
+            if self.payload.len() > 125 {
+                violations.push(Violation::ControlPayloadTooLong(self.payload.len()));
+            }
+        }
+
+        // Closeのpayloadは空か、status codeとUTF-8のreason (5.5.1)
+        if self.opcode == Opcode::Close {
+            match self.payload.len() {
+                0 => {}
+                1 => violations.push(Violation::TruncatedCloseCode),
+                _ => {
+                    let code = u16::from_be_bytes([self.payload[0], self.payload[1]]);
+                    if !is_valid_close_code(code) {
+                        violations.push(Violation::InvalidCloseCode(code));
+                    }
+                    if core::str::from_utf8(&self.payload[2..]).is_err() {
+                        violations.push(Violation::InvalidCloseReason);
+                    }
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        buffer.push(
+            (self.fin as u8) << 7
+                | (self.rsv1 as u8) << 6
+                | (self.rsv2 as u8) << 5
+                | (self.rsv3 as u8) << 4
+                | u8::from(self.opcode),
+        );
+
+        // MASKビットはmasking_keyがあるときだけ立てる (keyのない `mask` は `validate` で検出する)
+        let mask_bit = (self.masking_key.is_some() as u8) << 7;
+        if self.payload_len < 126 {
+            buffer.push(mask_bit | self.payload_len as u8);
+        } else if self.payload_len < 65536 {
+            buffer.push(mask_bit | 126);
+            buffer.extend_from_slice(&(self.payload_len as u16).to_be_bytes());
+        } else {
+            buffer.push(mask_bit | 127);
+            buffer.extend_from_slice(&(self.payload_len as u64).to_be_bytes());
+        }
+
+        match self.masking_key {
+            Some(masking_key) => {
+                buffer.extend(masking_key);
+                buffer.extend(
+                    self.payload
+                        .iter()
+                        .enumerate()
+                        .map(|(i, b)| b ^ masking_key[i % 4]),
+                );
+            }
+            None => buffer.extend_from_slice(&self.payload),
+        }
+
+        buffer
+    }
+
+    /// bufferの先頭からフレームを1つ読み込み、フレームと読み込んだbyte数を返す。
+    /// フレームの途中までしかなければ None を返すので、続きを受信してから同じ先頭で呼び直す
+    pub fn parse(buffer: &[u8]) -> Result<Option<(Self, usize)>> {
+        Self::parse_with_limit(buffer, usize::MAX)
+    }
+
+    /// `parse` と同じだが、payloadが `max_payload_len` を超えるフレームはpayloadが揃うのを待たずにエラーにする
+    pub(crate) fn parse_with_limit(
+        buffer: &[u8],
+        max_payload_len: usize,
+    ) -> Result<Option<(Self, usize)>> {
+        let Some(&[first, second]) = buffer.get(..2) else {
+            return Ok(None);
+        };
+        // 不正なヘッダーはpayloadが揃うのを待たずにエラーにする
+        let opcode = Opcode::try_from(first)?;
+        let (len_size, payload_len) = match second & 0b0111_1111 {
+            126 => match buffer.get(2..4) {
+                Some(len) => (2, u16::from_be_bytes([len[0], len[1]]) as u64),
+                None => return Ok(None),
+            },
+            127 => match buffer.get(2..10) {
+                Some(len) => (8, u64::from_be_bytes(len.try_into().unwrap())),
+                None => return Ok(None),
+            },
+            n => (0, n as u64),
+        };
+        if opcode.is_control() && (first & 0b1000_0000 == 0 || payload_len > 125) {
+            return Err(Error::Protocol(format!(
+                "invalid control frame: {:?}",
+                opcode
+            )));
+        }
+        if payload_len > max_payload_len as u64 {
+            return Err(Error::MessageTooBig(
+                usize::try_from(payload_len).unwrap_or(usize::MAX),
+            ));
+        }
+        let mask_size = if second & 0b1000_0000 != 0 { 4 } else { 0 };
+        let header_len = 2 + len_size + mask_size;
+        let total = (header_len as u64).saturating_add(payload_len);
+        if (buffer.len() as u64) < total {
+            return Ok(None);
+        }
+        let total = total as usize;
+        let frame = Self::read_from(&mut &buffer[..total])?;
+        Ok(Some((frame, total)))
+    }
+
+    /// streamからフレームを1つ読み込む
+    pub fn read_from<R: ReadExact>(reader: &mut R) -> Result<Self> {
+        Self::read_from_with_limit(reader, usize::MAX)
+    }
+
+    /// `read_from` と同じだが、payloadが `max_payload_len` を超えるフレームはpayloadを読み込まずにエラーにする
+    pub(crate) fn read_from_with_limit<R: ReadExact>(
+        reader: &mut R,
+        max_payload_len: usize,
+    ) -> Result<Self> {
+        let mut header = [0; 2];
+        reader.read_exact(&mut header)?;
+
+        let fin = header[0] & 0b1000_0000 != 0; // 0x80
+        let rsv1 = header[0] & 0b0100_0000 != 0; // 0x40
+        let rsv2 = header[0] & 0b0010_0000 != 0; // 0x20
+        let rsv3 = header[0] & 0b0001_0000 != 0; // 0x10
+        let opcode = Opcode::try_from(header[0])?;
+
+        let mask = header[1] & 0b1000_0000 != 0;
+
+        let payload_len = match header[1] & 0b0111_1111 {
+            126 => {
+                let mut payload_len = [0; 2];
+                reader.read_exact(&mut payload_len)?;
+                u16::from_be_bytes(payload_len) as u64
+            }
+            127 => {
+                let mut payload_len = [0; 8];
+                reader.read_exact(&mut payload_len)?;
+                u64::from_be_bytes(payload_len)
+            }
+            n => n as u64,
+        };
+
+        if opcode.is_control() && (!fin || payload_len > 125) {
+            return Err(Error::Protocol(format!(
+                "invalid control frame: {:?}",
+                opcode
+            )));
+        }
+
+        // 32bitのターゲットでも切り捨てずに、payloadを確保する前に比べる
+        if payload_len > max_payload_len as u64 {
+            return Err(Error::MessageTooBig(
+                usize::try_from(payload_len).unwrap_or(usize::MAX),
+            ));
+        }
+        let payload_len = payload_len as usize;
+
+        let masking_key = if mask {
+            let mut masking_key = [0; 4];
+            reader.read_exact(&mut masking_key)?;
+            Some(masking_key)
+        } else {
+            None
+        };
+
+        let mut payload = vec![0; payload_len];
+        reader.read_exact(&mut payload)?;
+        if let Some(masking_key) = masking_key {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= masking_key[i % 4];
+            }
+        }
+
+        Ok(Self {
+            fin,
+            rsv1,
+            rsv2,
+            rsv3,
+            opcode,
+            mask,
+            payload_len,
+            masking_key,
+            payload,
+        })
+    }
+}
+
+/// フレームを送る側
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// `Frame::validate` で見つかったRFC 6455の違反
+#[derive(Clone, Debug, PartialEq)]
+pub enum Violation {
+    /// クライアントからのフレームがマスクされていない
+    Unmasked,
+    /// サーバーからのフレームがマスクされている
+    Masked,
+    /// MASKビットが立っているがmasking_keyがない
+    MissingMaskingKey,
+    /// 合意した拡張が使わないRSVビットが立っている
+    ReservedBits(u8),
+    /// ヘッダーのpayloadの長さと実際のpayloadの長さが異なる
+    PayloadLengthMismatch {
+        header: usize,
+        actual: usize,
+    },
+    FragmentedControl,
+    ControlPayloadTooLong(usize),
+    /// Closeのpayloadが1byteしかない
+    TruncatedCloseCode,
+    /// 送ってはいけないstatus code (1005, 1006など)
+    InvalidCloseCode(u16),
+    /// Closeのreasonが不正なUTF-8
+    InvalidCloseReason,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unmasked => write!(f, "unmasked frame from client"),
+            Self::Masked => write!(f, "masked frame from server"),
+            Self::MissingMaskingKey => write!(f, "mask bit set without a masking key"),
+            Self::ReservedBits(bits) => write!(f, "reserved bits set: {:#05b}", bits),
+            Self::PayloadLengthMismatch { header, actual } => write!(
+                f,
+                "payload length {} does not match the payload ({} bytes)",
+                header, actual
+            ),
+            Self::FragmentedControl => write!(f, "fragmented control frame"),
+            Self::ControlPayloadTooLong(len) => {
+                write!(f, "control frame payload too long: {} bytes", len)
+            }
+            Self::TruncatedCloseCode => write!(f, "close frame with a 1-byte payload"),
+            Self::InvalidCloseCode(code) => write!(f, "invalid close code: {}", code),
+            Self::InvalidCloseReason => write!(f, "invalid UTF-8 in close reason"),
+        }
+    }
+}
+
+/// Closeフレームで送ってよいstatus code (RFC 6455 7.4)
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// `Frame::builder()` で作る。指定しなければ FIN が立ったpayloadの空のBinaryフレームになる:
+///   Frame::builder().opcode(Opcode::Text).fin(false).rsv1(true).payload("hel").build()
+#[derive(Clone, Debug)]
+pub struct FrameBuilder {
+    frame: Frame,
+    /// payloadと異なる長さを書き込む場合
+    payload_len: Option<usize>,
+}
+
+impl FrameBuilder {
+    pub fn opcode(mut self, opcode: Opcode) -> Self {
+        self.frame.opcode = opcode;
+        self
+    }
+
+    pub fn fin(mut self, fin: bool) -> Self {
+        self.frame.fin = fin;
+        self
+    }
+
+    pub fn rsv1(mut self, rsv1: bool) -> Self {
+        self.frame.rsv1 = rsv1;
+        self
+    }
+
+    pub fn rsv2(mut self, rsv2: bool) -> Self {
+        self.frame.rsv2 = rsv2;
+        self
+    }
+
+    pub fn rsv3(mut self, rsv3: bool) -> Self {
+        self.frame.rsv3 = rsv3;
+        self
+    }
+
+    pub fn payload<P: Into<Vec<u8>>>(mut self, payload: P) -> Self {
+        self.frame.payload = payload.into();
+        self
+    }
+
+    /// ヘッダーに書き込むpayloadの長さ。実際のpayloadと異なる不正なフレームを作る場合に使う
+    pub fn payload_len(mut self, payload_len: usize) -> Self {
+        self.payload_len = Some(payload_len);
+        self
+    }
+
+    pub fn mask(mut self, masking_key: [u8; 4]) -> Self {
+        self.frame.mask = true;
+        self.frame.masking_key = Some(masking_key);
+        self
+    }
+
+    pub fn build(self) -> Frame {
+        let mut frame = self.frame;
+        frame.payload_len = self.payload_len.unwrap_or(frame.payload.len());
+        frame
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+    use tungstenite::protocol::frame::{coding::OpCode, Frame as TungsteniteFrame, FrameHeader};
+
+    use super::*;
+
+    /// RFC 6455に従うフレーム。payloadの長さは長さの区切り (125・126・65535・65536) の前後を多めに選ぶ
+    fn frames() -> impl Strategy<Value = Frame> {
+        let opcode = prop::sample::select(vec![
+            Opcode::Continuation,
+            Opcode::Text,
+            Opcode::Binary,
+            Opcode::Close,
+            Opcode::Ping,
+            Opcode::Pong,
+        ]);
+        let payload = prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..300),
+            prop::collection::vec(any::<u8>(), 65530..65540),
+        ];
+        (
+            opcode,
+            any::<(bool, bool, bool, bool)>(),
+            any::<Option<[u8; 4]>>(),
+            payload,
+        )
+            .prop_map(
+                |(opcode, (fin, rsv1, rsv2, rsv3), masking_key, mut payload)| {
+                    // Controlフレームは分割せず、payloadは125bytes以下
+                    if opcode.is_control() {
+                        payload.truncate(125);
+                    }
+                    let mut builder = Frame::builder()
+                        .opcode(opcode)
+                        .fin(fin || opcode.is_control())
+                        .rsv1(rsv1)
+                        .rsv2(rsv2)
+                        .rsv3(rsv3)
+                        .payload(payload);
+                    if let Some(key) = masking_key {
+                        builder = builder.mask(key);
+                    }
+                    builder.build()
+                },
+            )
+    }
+
+    fn tungstenite_header(frame: &Frame) -> FrameHeader {
+        FrameHeader {
+            is_final: frame.fin,
+            rsv1: frame.rsv1,
+            rsv2: frame.rsv2,
+            rsv3: frame.rsv3,
+            opcode: OpCode::from(u8::from(frame.opcode)),
+            mask: frame.masking_key,
+        }
+    }
+
+    #[test]
+    fn rejects_payload_lengths_over_the_limit_before_reading() {
+        for len in [DEFAULT_MAX_PAYLOAD_LEN as u64 + 1, u64::MAX] {
+            let mut bytes = vec![0x82, 127];
+            bytes.extend(len.to_be_bytes());
+            match Frame::read_from_with_limit(&mut bytes.as_slice(), DEFAULT_MAX_PAYLOAD_LEN) {
+                Err(Error::MessageTooBig(_)) => {}
+                other => panic!("expected MessageTooBig: {:?}", other),
+            }
+            assert!(matches!(
+                Frame::parse_with_limit(&bytes, DEFAULT_MAX_PAYLOAD_LEN),
+                Err(Error::MessageTooBig(_))
+            ));
+        }
+        let mut bytes = vec![0x82, 126];
+        bytes.extend(300u16.to_be_bytes());
+        bytes.extend([0; 300]);
+        assert_eq!(
+            Frame::read_from_with_limit(&mut bytes.as_slice(), 300)
+                .unwrap()
+                .payload
+                .len(),
+            300
+        );
+    }
+
+    #[test]
+    fn builds_frames_with_every_field() {
+        let frame = Frame::builder()
+            .opcode(Opcode::Text)
+            .fin(false)
+            .rsv1(true)
+            .rsv3(true)
+            .payload("hel")
+            .mask([1, 2, 3, 4])
+            .build();
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(
+            (frame.fin, frame.rsv1, frame.rsv2, frame.rsv3),
+            (false, true, false, true)
+        );
+        assert!(frame.mask);
+        assert_eq!(frame.masking_key, Some([1, 2, 3, 4]));
+        assert_eq!(
+            (frame.payload_len, frame.payload.as_slice()),
+            (3, &b"hel"[..])
+        );
+
+        // payload_lenはpayloadと異なる長さを書き込める
+        let frame = Frame::builder().payload("hello").payload_len(2).build();
+        assert_eq!((frame.payload_len, frame.payload.len()), (2, 5));
+        assert_eq!(frame.to_bytes(), b"\x82\x02hello");
+    }
+
+    #[test]
+    fn encodes_the_mask_bit_only_with_a_masking_key() {
+        let bytes = Frame::builder()
+            .payload("ab")
+            .mask([1, 2, 3, 4])
+            .build()
+            .to_bytes();
+        assert_eq!(bytes, [0x82, 0x82, 1, 2, 3, 4, b'a' ^ 1, b'b' ^ 2]);
+
+        // keyのないmaskはpanicせず、マスクしないフレームになる
+        let mut frame = Frame::builder().payload("ab").build();
+        frame.mask = true;
+        assert_eq!(
+            frame.clone().validate(Role::Client, 0),
+            Err(vec![Violation::MissingMaskingKey])
+        );
+        assert_eq!(frame.to_bytes(), [0x82, 0x02, b'a', b'b']);
+    }
+
+    #[test]
+    fn validates_masking_by_sender() {
+        let unmasked = Frame::new(Opcode::Text, Some(b"hi".to_vec()));
+        let masked = unmasked.clone().masked([1, 2, 3, 4]);
+        assert_eq!(unmasked.validate(Role::Server, 0), Ok(()));
+        assert_eq!(masked.validate(Role::Client, 0), Ok(()));
+        assert_eq!(
+            unmasked.validate(Role::Client, 0),
+            Err(vec![Violation::Unmasked])
+        );
+        assert_eq!(
+            masked.validate(Role::Server, 0),
+            Err(vec![Violation::Masked])
+        );
+    }
+
+    #[test]
+    fn validates_rsv_bits_and_payload_length() {
+        let frame = Frame::builder().rsv1(true).rsv2(true).build();
+        assert_eq!(
+            frame.validate(Role::Server, 0),
+            Err(vec![Violation::ReservedBits(0b110)])
+        );
+        // 合意した拡張のビットは違反にしない
+        assert_eq!(
+            frame.validate(Role::Server, 0b100),
+            Err(vec![Violation::ReservedBits(0b010)])
+        );
+        assert_eq!(frame.validate(Role::Server, 0b110), Ok(()));
+
+        let frame = Frame::builder().payload("hello").payload_len(4).build();
+        assert_eq!(
+            frame.validate(Role::Server, 0),
+            Err(vec![Violation::PayloadLengthMismatch {
+                header: 4,
+                actual: 5
+            }])
+        );
+    }
+
+    #[test]
+    fn validates_control_frames() {
+        let frame = Frame::builder()
+            .opcode(Opcode::Ping)
+            .fin(false)
+            .payload(vec![0; 126])
+            .build();
+        assert_eq!(
+            frame.validate(Role::Server, 0),
+            Err(vec![
+                Violation::FragmentedControl,
+                Violation::ControlPayloadTooLong(126)
+            ])
+        );
+
+        let close = |payload: &[u8]| {
+            Frame::builder()
+                .opcode(Opcode::Close)
+                .payload(payload)
+                .build()
+                .validate(Role::Server, 0)
+        };
+        assert_eq!(close(b""), Ok(()));
+        assert_eq!(close(b"\x03\xe8bye"), Ok(()));
+        assert_eq!(close(b"\x03"), Err(vec![Violation::TruncatedCloseCode]));
+        assert_eq!(
+            close(b"\x03\xed"),
+            Err(vec![Violation::InvalidCloseCode(1005)])
+        );
+        assert_eq!(
+            close(b"\x03\xe8\xff"),
+            Err(vec![Violation::InvalidCloseReason])
+        );
+    }
+
+    #[test]
+    fn parses_frames_from_a_buffer() {
+        let mut buffer = Frame::new(Opcode::Text, Some(b"hello".to_vec())).to_bytes();
+        buffer.extend(Frame::close(1000, "").masked([1, 2, 3, 4]).to_bytes());
+
+        // 途中までしかなければ None
+        for len in 0..7 {
+            assert!(Frame::parse(&buffer[..len]).unwrap().is_none());
+        }
+        let (frame, len) = Frame::parse(&buffer).unwrap().unwrap();
+        assert_eq!((frame.opcode, len), (Opcode::Text, 7));
+        assert_eq!(frame.payload, b"hello");
+
+        // 続きのフレームは読み込んだbyte数の後から
+        let (frame, len) = Frame::parse(&buffer[7..]).unwrap().unwrap();
+        assert_eq!((frame.opcode, len), (Opcode::Close, 2 + 4 + 2));
+        assert_eq!(frame.masking_key, Some([1, 2, 3, 4]));
+        assert_eq!(frame.close_code_and_reason(), Some((1000, String::new())));
+    }
+
+    #[test]
+    fn rejects_invalid_control_frames_from_the_header() {
+        // payloadが届く前に、ヘッダーだけでエラーにする
+        for header in [&[0x09, 0x00][..], &[0x89, 126, 0, 126]] {
+            assert!(matches!(Frame::parse(header), Err(Error::Protocol(_))));
+        }
+        assert!(matches!(
+            Frame::parse(&[0x83, 0x00]),
+            Err(Error::ReservedOpcode(3))
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_through_tungstenite(frame in frames()) {
+            // このクレートでエンコードしたものを tungstenite でデコードする
+            let bytes = frame.clone().to_bytes();
+            let mut cursor = Cursor::new(bytes.as_slice());
+            let (header, len) = FrameHeader::parse(&mut cursor).unwrap().unwrap();
+            prop_assert_eq!(&header, &tungstenite_header(&frame));
+            let offset = cursor.position() as usize;
+            prop_assert_eq!(offset as u64 + len, bytes.len() as u64);
+            let mut payload = bytes[offset..].to_vec();
+            if let Some(key) = header.mask {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= key[i % 4];
+                }
+            }
+            prop_assert_eq!(&payload, &frame.payload);
+
+            // tungstenite でエンコードしたものは同じバイト列で、このクレートでデコードすると元に戻る
+            let mut theirs = vec![];
+            TungsteniteFrame::from_payload(tungstenite_header(&frame), frame.payload.clone().into())
+                .format(&mut theirs)
+                .unwrap();
+            prop_assert_eq!(&theirs, &bytes);
+            let (decoded, consumed) = Frame::parse(&theirs).unwrap().unwrap();
+            prop_assert_eq!(consumed, theirs.len());
+            prop_assert_eq!(
+                (decoded.fin, decoded.rsv1, decoded.rsv2, decoded.rsv3, decoded.opcode),
+                (frame.fin, frame.rsv1, frame.rsv2, frame.rsv3, frame.opcode)
+            );
+            prop_assert_eq!(decoded.masking_key, frame.masking_key);
+            prop_assert_eq!(decoded.payload, frame.payload);
+        }
+    }
+}
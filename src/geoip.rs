@@ -0,0 +1,481 @@
+// 接続元のIPアドレスから国とASNを調べる (MaxMind GeoLite2のCSV版とmmdb版)
+//
+// 使うファイル (https://dev.maxmind.com/geoip/geolite2-free-geolocation-data からダウンロードする):
+// GeoLite2-Country-Blocks-IPv4.csv, GeoLite2-Country-Blocks-IPv6.csv  -> network,geoname_id,...
+// GeoLite2-Country-Locations-en.csv                                    -> geoname_id,...,country_iso_code,...
+// GeoLite2-ASN-Blocks-IPv4.csv, GeoLite2-ASN-Blocks-IPv6.csv          -> network,autonomous_system_number,...
+//
+// 列は1行目のヘッダーの名前で探す。
+//
+// mmdb形式 (GeoLite2-Country.mmdb・GeoLite2-City.mmdb・GeoLite2-ASN.mmdb) は `mmdb` featureの
+// `GeoIp::load_mmdb` で読み込む (読むのは maxminddb に任せる)。CSVと両方読み込んだ場合はCSVを優先する
+//
+// `GeoIp::layer` でhandshakeのmiddlewareにすると、調べた `Location` を `Handshake::extensions` に入れる。
+// 後ろのmiddlewareとHandlerは `extensions().get::<Location>()` で国・ASNを見て、拒否したり接続に印を付けたりできる
+
+use std::{collections::HashMap, fs, io, net::IpAddr, path::Path, sync::Arc};
+
+#[cfg(feature = "mmdb")]
+use maxminddb::{geoip2, Reader};
+
+use crate::{
+    ipfilter::{self, Cidr},
+    log::info,
+    middleware::{Handshake, Layer, Next, Outcome, Rejection},
+};
+
+/// 接続元の国とASN。データベースにない項目は None
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 (例: "JP")
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    /// ASNの組織名
+    pub organization: Option<String>,
+}
+
+/// アドレスの範囲 (開始・終了) と値。開始の順に並べ、二分探索する
+struct Ranges<T> {
+    entries: Vec<(u128, u128, T)>,
+}
+
+impl<T> Default for Ranges<T> {
+    fn default() -> Self {
+        Self { entries: vec![] }
+    }
+}
+
+impl<T> Ranges<T> {
+    fn extend(&mut self, entries: Vec<(u128, u128, T)>) {
+        self.entries.extend(entries);
+        self.entries.sort_by_key(|(start, _, _)| *start);
+    }
+
+    fn get(&self, ip: IpAddr) -> Option<&T> {
+        let ip = ipfilter::to_u128(ip);
+        let index = self
+            .entries
+            .partition_point(|(start, _, _)| *start <= ip)
+            .checked_sub(1)?;
+        let (_, end, value) = &self.entries[index];
+        (ip <= *end).then_some(value)
+    }
+}
+
+#[derive(Default)]
+pub struct GeoIp {
+    countries: Ranges<Arc<str>>,
+    asns: Ranges<(u32, Arc<str>)>,
+    /// `load_mmdb` で読み込んだデータベース
+    #[cfg(feature = "mmdb")]
+    databases: Vec<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Country-Blocks (IPv4・IPv6のそれぞれ) とCountry-Locationsを読み込む
+    pub fn load_countries<P: AsRef<Path>>(
+        mut self,
+        blocks: &[P],
+        locations: P,
+    ) -> io::Result<Self> {
+        let mut countries = HashMap::new();
+        let locations = fs::read_to_string(locations)?;
+        let mut rows = csv(&locations);
+        let header = rows.next().unwrap_or_default();
+        let id = column(&header, "geoname_id")?;
+        let code = column(&header, "country_iso_code")?;
+        for row in rows {
+            if let (Some(id), Some(code)) = (row.get(id), row.get(code)) {
+                if !code.is_empty() {
+                    countries.insert(id.clone(), Arc::<str>::from(code.as_str()));
+                }
+            }
+        }
+
+        for path in blocks {
+            let blocks = fs::read_to_string(path)?;
+            let mut rows = csv(&blocks);
+            let header = rows.next().unwrap_or_default();
+            let network = column(&header, "network")?;
+            let id = column(&header, "geoname_id")?;
+            // 国が分からないブロックは登録された国を使う
+            let registered = column(&header, "registered_country_geoname_id").ok();
+            let entries = rows
+                .filter_map(|row| {
+                    let (start, end) = row.get(network)?.parse::<Cidr>().ok()?.range();
+                    let country = [Some(id), registered]
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|i| row.get(i))
+                        .find_map(|id| countries.get(id))?;
+                    Some((start, end, country.clone()))
+                })
+                .collect();
+            self.countries.extend(entries);
+        }
+        info!(
+            "geoip_loaded",
+            { networks: self.countries.entries.len() },
+            "geoip: loaded {} country networks",
+            self.countries.entries.len()
+        );
+        Ok(self)
+    }
+
+    /// ASN-Blocks (IPv4・IPv6のそれぞれ) を読み込む
+    pub fn load_asns<P: AsRef<Path>>(mut self, blocks: &[P]) -> io::Result<Self> {
+        for path in blocks {
+            let blocks = fs::read_to_string(path)?;
+            let mut rows = csv(&blocks);
+            let header = rows.next().unwrap_or_default();
+            let network = column(&header, "network")?;
+            let asn = column(&header, "autonomous_system_number")?;
+            let organization = column(&header, "autonomous_system_organization")?;
+            let entries = rows
+                .filter_map(|row| {
+                    let (start, end) = row.get(network)?.parse::<Cidr>().ok()?.range();
+                    let asn = row.get(asn)?.parse().ok()?;
+                    let organization = row.get(organization).map_or("", String::as_str);
+                    Some((start, end, (asn, Arc::from(organization))))
+                })
+                .collect();
+            self.asns.extend(entries);
+        }
+        info!(
+            "geoip_loaded",
+            { networks: self.asns.entries.len() },
+            "geoip: loaded {} ASN networks",
+            self.asns.entries.len()
+        );
+        Ok(self)
+    }
+
+    /// mmdb形式のデータベースを読み込む。国 (Country・City) とASNのどちらのデータベースでもよい
+    #[cfg(feature = "mmdb")]
+    pub fn load_mmdb<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let database = Reader::from_source(fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        info!(
+            "geoip_loaded",
+            { database: database.metadata.database_type.clone(), nodes: database.metadata.node_count as u64 },
+            "geoip: loaded {} ({} nodes)",
+            database.metadata.database_type,
+            database.metadata.node_count
+        );
+        self.databases.push(database);
+        Ok(self)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Location {
+        let asn = self.asns.get(ip);
+        #[cfg_attr(not(feature = "mmdb"), allow(unused_mut))]
+        let mut location = Location {
+            country: self.countries.get(ip).map(|code| code.to_string()),
+            asn: asn.map(|(asn, _)| *asn),
+            organization: asn
+                .map(|(_, organization)| organization.to_string())
+                .filter(|organization| !organization.is_empty()),
+        };
+        // CSVになかった項目を、mmdbのデータベースから読み込んだ順に探す
+        #[cfg(feature = "mmdb")]
+        for database in &self.databases {
+            if location.country.is_none() {
+                if let Ok(country) = database.lookup::<geoip2::Country>(ip) {
+                    // 国が分からないネットワークは登録された国を使う
+                    location.country = [country.country, country.registered_country]
+                        .into_iter()
+                        .flatten()
+                        .find_map(|country| country.iso_code)
+                        .map(str::to_string);
+                }
+            }
+            if location.asn.is_none() {
+                if let Ok(asn) = database.lookup::<geoip2::Asn>(ip) {
+                    location.asn = asn.autonomous_system_number;
+                    location.organization = asn
+                        .autonomous_system_organization
+                        .filter(|organization| !organization.is_empty())
+                        .map(str::to_string);
+                }
+            }
+        }
+        location
+    }
+
+    /// handshakeのmiddlewareにする
+    pub fn layer(self) -> GeoLayer {
+        GeoLayer {
+            geoip: self,
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+}
+
+/// 接続元の `Location` を `Handshake::extensions` に入れるmiddleware。
+/// 国を制限する場合、許可されなければ 403 で拒否する
+pub struct GeoLayer {
+    geoip: GeoIp,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl GeoLayer {
+    /// 許可する国を追加する。1つでも追加すると、それ以外 (国が分からない接続元を含む) は拒否される
+    pub fn allow_country(mut self, code: &str) -> Self {
+        self.allow.push(code.to_ascii_uppercase());
+        self
+    }
+
+    /// 拒否する国を追加する。allowより優先される
+    pub fn deny_country(mut self, code: &str) -> Self {
+        self.deny.push(code.to_ascii_uppercase());
+        self
+    }
+
+    fn permits(&self, location: &Location) -> bool {
+        let country = location.country.as_deref();
+        if country.is_some_and(|country| self.deny.iter().any(|c| c == country)) {
+            return false;
+        }
+        self.allow.is_empty()
+            || country.is_some_and(|country| self.allow.iter().any(|c| c == country))
+    }
+}
+
+impl Layer for GeoLayer {
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome {
+        let location = self.geoip.lookup(handshake.peer_addr.ip());
+        if !self.permits(&location) {
+            return Err(Rejection::new(403, "Forbidden"));
+        }
+        handshake.extensions.insert(location);
+        next.run(handshake)
+    }
+}
+
+fn column(header: &[String], name: &str) -> io::Result<usize> {
+    header.iter().position(|h| h == name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("missing column: {}", name),
+        )
+    })
+}
+
+/// CSVの行を列に分ける。`"` で囲んだ列はカンマを含められる (`""` は `"` になる)
+fn csv(content: &str) -> impl Iterator<Item = Vec<String>> + '_ {
+    content.lines().filter(|line| !line.is_empty()).map(|line| {
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                c => field.push(c),
+            }
+        }
+        fields.push(field);
+        fields
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("websocket-rs-geoip-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 1.2.3.0/24 と 2001:db8::/32 が JP、5.6.0.0/16 は登録された国だけ (GB)、1.2.3.0/24 のASNは 64500
+    fn load_csv(dir: &Path) -> GeoIp {
+        let files = [
+            (
+                "v4.csv",
+                "network,geoname_id,registered_country_geoname_id\n\
+                 1.2.3.0/24,1861060,1861060\n\
+                 5.6.0.0/16,,2635167\n",
+            ),
+            (
+                "v6.csv",
+                "network,geoname_id,registered_country_geoname_id\n2001:db8::/32,1861060,\n",
+            ),
+            (
+                "locations.csv",
+                "geoname_id,locale_code,country_iso_code\n1861060,en,JP\n2635167,en,GB\n",
+            ),
+            (
+                "asn.csv",
+                "network,autonomous_system_number,autonomous_system_organization\n\
+                 1.2.3.0/24,64500,\"Example, Inc.\"\n",
+            ),
+        ];
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        GeoIp::new()
+            .load_countries(
+                &[dir.join("v4.csv"), dir.join("v6.csv")],
+                dir.join("locations.csv"),
+            )
+            .unwrap()
+            .load_asns(&[dir.join("asn.csv")])
+            .unwrap()
+    }
+
+    #[test]
+    fn looks_up_csv_databases() {
+        let geoip = load_csv(&temp_dir("csv"));
+        assert_eq!(
+            geoip.lookup("1.2.3.4".parse().unwrap()),
+            Location {
+                country: Some("JP".to_string()),
+                asn: Some(64500),
+                organization: Some("Example, Inc.".to_string()),
+            }
+        );
+        assert_eq!(
+            geoip.lookup("5.6.7.8".parse().unwrap()).country.as_deref(),
+            Some("GB")
+        );
+        assert_eq!(
+            geoip
+                .lookup("2001:db8::1".parse().unwrap())
+                .country
+                .as_deref(),
+            Some("JP")
+        );
+        assert_eq!(
+            geoip.lookup("1.2.4.1".parse().unwrap()),
+            Location::default()
+        );
+    }
+
+    /// MaxMind DBのデータ部の文字列
+    #[cfg(feature = "mmdb")]
+    fn string(bytes: &mut Vec<u8>, s: &str) {
+        match s.len() {
+            len @ 0..=28 => bytes.push(0x40 | len as u8),
+            len => bytes.extend([0x40 | 29, (len - 29) as u8]),
+        }
+        bytes.extend(s.as_bytes());
+    }
+
+    /// 1.2.3.0/24 に `data` (データ部の値) を持つIPv4のmmdb (record_sizeは24bit)
+    #[cfg(feature = "mmdb")]
+    fn mmdb(database_type: &str, data: &[u8]) -> Vec<u8> {
+        const NODES: u32 = 24;
+        let prefix = u32::from(std::net::Ipv4Addr::new(1, 2, 3, 0));
+        let mut bytes = vec![];
+        for i in 0..NODES {
+            // 一致しない側は NODES (データなし)、最後のbitの先はデータ部の先頭 (NODES + 16)
+            let next = if i + 1 < NODES { i + 1 } else { NODES + 16 };
+            let (left, right) = match prefix >> (31 - i) & 1 {
+                0 => (next, NODES),
+                _ => (NODES, next),
+            };
+            bytes.extend(&left.to_be_bytes()[1..]);
+            bytes.extend(&right.to_be_bytes()[1..]);
+        }
+        bytes.extend([0; 16]);
+        bytes.extend(data);
+
+        bytes.extend(b"\xab\xcd\xefMaxMind.com");
+        bytes.push(0xe0 | 9);
+        string(&mut bytes, "binary_format_major_version");
+        bytes.extend([0xa1, 2]);
+        string(&mut bytes, "binary_format_minor_version");
+        bytes.push(0xa0);
+        string(&mut bytes, "build_epoch");
+        bytes.extend([0x01, 0x02, 1]);
+        string(&mut bytes, "database_type");
+        string(&mut bytes, database_type);
+        string(&mut bytes, "description");
+        bytes.push(0xe0);
+        string(&mut bytes, "ip_version");
+        bytes.extend([0xa1, 4]);
+        string(&mut bytes, "languages");
+        bytes.extend([0x00, 0x04]);
+        string(&mut bytes, "node_count");
+        bytes.extend([0xc1, NODES as u8]);
+        string(&mut bytes, "record_size");
+        bytes.extend([0xa1, 24]);
+        bytes
+    }
+
+    #[cfg(feature = "mmdb")]
+    #[test]
+    fn looks_up_mmdb_databases() {
+        let dir = temp_dir("mmdb");
+        // {"country": {"iso_code": "US"}}
+        let mut country = vec![0xe1];
+        string(&mut country, "country");
+        country.push(0xe1);
+        string(&mut country, "iso_code");
+        string(&mut country, "US");
+        fs::write(dir.join("country.mmdb"), mmdb("GeoLite2-Country", &country)).unwrap();
+        // {"autonomous_system_number": 64501, "autonomous_system_organization": "Example"}
+        let mut asn = vec![0xe2];
+        string(&mut asn, "autonomous_system_number");
+        asn.extend([0xc2, 0xfb, 0xf5]);
+        string(&mut asn, "autonomous_system_organization");
+        string(&mut asn, "Example");
+        fs::write(dir.join("asn.mmdb"), mmdb("GeoLite2-ASN", &asn)).unwrap();
+
+        let geoip = GeoIp::new()
+            .load_mmdb(dir.join("country.mmdb"))
+            .unwrap()
+            .load_mmdb(dir.join("asn.mmdb"))
+            .unwrap();
+        assert_eq!(
+            geoip.lookup("1.2.3.4".parse().unwrap()),
+            Location {
+                country: Some("US".to_string()),
+                asn: Some(64501),
+                organization: Some("Example".to_string()),
+            }
+        );
+        assert_eq!(
+            geoip.lookup("1.2.4.1".parse().unwrap()),
+            Location::default()
+        );
+        // IPv4だけのデータベースでIPv6のアドレスを調べても見つからないだけ
+        assert_eq!(
+            geoip.lookup("2001:db8::1".parse().unwrap()),
+            Location::default()
+        );
+
+        // CSVと両方読み込んだ場合はCSVを優先し、CSVにない項目だけmmdbから探す
+        let geoip = load_csv(&dir).load_mmdb(dir.join("country.mmdb")).unwrap();
+        assert_eq!(
+            geoip.lookup("1.2.3.4".parse().unwrap()).country.as_deref(),
+            Some("JP")
+        );
+
+        fs::write(dir.join("broken.mmdb"), b"not a database").unwrap();
+        for (path, kind) in [
+            ("broken.mmdb", io::ErrorKind::InvalidData),
+            ("missing.mmdb", io::ErrorKind::NotFound),
+        ] {
+            match GeoIp::new().load_mmdb(dir.join(path)) {
+                Err(e) => assert_eq!(e.kind(), kind),
+                Ok(_) => panic!("expected {} to fail", path),
+            }
+        }
+    }
+}
@@ -0,0 +1,322 @@
+// GraphQL over WebSocket (graphql-transport-ws)
+//
+// サブプロトコル `graphql-transport-ws` で接続してきたクライアントとの間で、
+// 以下のようなJSONのTextメッセージをやりとりする:
+// -> {"type":"connection_init","payload":{...}}
+// <- {"type":"connection_ack"}
+// -> {"type":"subscribe","id":"1","payload":{"query":"subscription { ... }","variables":{...}}}
+// <- {"type":"next","id":"1","payload":{"data":{...}}}   (0回以上)
+// <- {"type":"complete","id":"1"}  または {"type":"error","id":"1","payload":[{"message":"..."}]}
+// -> {"type":"complete","id":"1"}  (クライアントからの購読の中止)
+// ping/pong はどちらからでも送れる
+//
+// クエリの実行は `Resolver` に任せる。結果は `Sink` に送る
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    connection::{Connection, ConnectionHandle, ConnectionId},
+    error::{Error, Result},
+    handler::Handler,
+    json,
+    log::warning,
+    message::Message,
+};
+
+const PROTOCOL: &str = "graphql-transport-ws";
+/// connection_init を待つ時間。過ぎたら 4408 で切断する
+const CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// クライアントから要求された操作
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub id: String,
+    pub query: String,
+    pub operation_name: Option<String>,
+    /// JSONのまま
+    pub variables: Option<String>,
+}
+
+/// GraphQLの操作を実行するcallback
+pub trait Resolver: Send + Sync + 'static {
+    /// connection_init を受信した。payloadはJSONのまま。false を返すと 4403 で切断する
+    fn on_init(&self, _payload: Option<&str>) -> bool {
+        true
+    }
+
+    /// 操作を開始する。結果は `sink` に送る (別のスレッドから送ってもよい)。
+    /// queryやmutationなら `next` を1回送ってから `complete` する
+    fn subscribe(&self, operation: Operation, sink: Sink);
+}
+
+/// 操作の結果をクライアントに送るためのハンドル
+#[derive(Clone)]
+pub struct Sink {
+    id: String,
+    handle: ConnectionHandle,
+    /// クライアントが中止したか、complete/errorを送った
+    done: Arc<AtomicBool>,
+    sessions: Arc<Sessions>,
+}
+
+type Sessions = Mutex<HashMap<ConnectionId, Session>>;
+
+#[derive(Default)]
+struct Session {
+    initialized: bool,
+    /// 実行中の操作のID -> 終了したかのフラグ
+    operations: HashMap<String, Arc<AtomicBool>>,
+}
+
+impl Sink {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// クライアントが購読を中止したか
+    pub fn is_cancelled(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    /// 実行結果 (`{"data":...}` のようなJSON) を送る
+    pub fn next(&self, payload: &str) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Protocol(format!("operation {} is done", self.id)));
+        }
+        self.send("next", Some(payload))
+    }
+
+    /// エラー (`[{"message":"..."}]` のようなJSON) を送って操作を終える
+    pub fn error(&self, errors: &str) -> Result<()> {
+        self.finish("error", Some(errors))
+    }
+
+    /// 操作を終える
+    pub fn complete(&self) -> Result<()> {
+        self.finish("complete", None)
+    }
+
+    fn finish(&self, kind: &str, payload: Option<&str>) -> Result<()> {
+        if self.done.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&self.handle.id()) {
+            session.operations.remove(&self.id);
+        }
+        self.send(kind, payload)
+    }
+
+    fn send(&self, kind: &str, payload: Option<&str>) -> Result<()> {
+        self.handle
+            .send(Message::Text(encode(kind, Some(&self.id), payload)))
+    }
+}
+
+fn encode(kind: &str, id: Option<&str>, payload: Option<&str>) -> String {
+    let mut text = format!("{{\"type\":{}", json::string(kind));
+    if let Some(id) = id {
+        text.push_str(&format!(",\"id\":{}", json::string(id)));
+    }
+    if let Some(payload) = payload {
+        text.push_str(&format!(",\"payload\":{}", payload));
+    }
+    text.push('}');
+    text
+}
+
+/// サブプロトコル `graphql-transport-ws` の接続を処理するHandler。
+/// それ以外の接続は `fallback` に渡す
+pub struct GraphQlWs<R, H = ()> {
+    resolver: R,
+    fallback: H,
+    sessions: Arc<Sessions>,
+}
+
+impl<R: Resolver> GraphQlWs<R> {
+    pub fn new(resolver: R) -> Self {
+        Self::with_fallback(resolver, ())
+    }
+}
+
+impl<R: Resolver, H: Handler> GraphQlWs<R, H> {
+    pub fn with_fallback(resolver: R, fallback: H) -> Self {
+        Self {
+            resolver,
+            fallback,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn is_graphql(&self, id: ConnectionId) -> bool {
+        self.sessions.lock().unwrap().contains_key(&id)
+    }
+
+    /// 処理できないメッセージなら、切断に使うcloseのcodeとreasonを返す
+    fn receive(&self, conn: &mut Connection, text: &str) -> std::result::Result<(), (u16, String)> {
+        let invalid = || (4400, "Invalid message received".to_string());
+        let message = json::split_object(text).ok_or_else(invalid)?;
+        let kind = json::field(&message, "type")
+            .and_then(json::parse_string)
+            .ok_or_else(invalid)?;
+        let id = json::field(&message, "id").and_then(json::parse_string);
+        let payload = json::field(&message, "payload");
+
+        match kind.as_str() {
+            "connection_init" => {
+                let initialized = match self.sessions.lock().unwrap().get_mut(&conn.id()) {
+                    Some(session) => std::mem::replace(&mut session.initialized, true),
+                    None => return Ok(()),
+                };
+                if initialized {
+                    return Err((4429, "Too many initialisation requests".to_string()));
+                }
+                if !self.resolver.on_init(payload) {
+                    return Err((4403, "Forbidden".to_string()));
+                }
+                let _ = conn.send(Message::Text(encode("connection_ack", None, None)));
+            }
+            "ping" => {
+                let _ = conn.send(Message::Text(encode("pong", None, None)));
+            }
+            "pong" => {}
+            "subscribe" => {
+                let id = id.ok_or_else(invalid)?;
+                let payload =
+                    json::split_object(payload.ok_or_else(invalid)?).ok_or_else(invalid)?;
+                let operation = Operation {
+                    query: json::field(&payload, "query")
+                        .and_then(json::parse_string)
+                        .ok_or_else(invalid)?,
+                    operation_name: json::field(&payload, "operationName")
+                        .and_then(json::parse_string),
+                    variables: json::field(&payload, "variables").map(str::to_string),
+                    id: id.clone(),
+                };
+
+                let done = Arc::new(AtomicBool::new(false));
+                {
+                    let mut sessions = self.sessions.lock().unwrap();
+                    let Some(session) = sessions.get_mut(&conn.id()) else {
+                        return Ok(());
+                    };
+                    if !session.initialized {
+                        return Err((4401, "Unauthorized".to_string()));
+                    }
+                    if session.operations.contains_key(&id) {
+                        return Err((4409, format!("Subscriber for {} already exists", id)));
+                    }
+                    session.operations.insert(id.clone(), done.clone());
+                }
+                let sink = Sink {
+                    id,
+                    handle: conn.handle(),
+                    done,
+                    sessions: self.sessions.clone(),
+                };
+                self.resolver.subscribe(operation, sink);
+            }
+            "complete" => {
+                let id = id.ok_or_else(invalid)?;
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&conn.id()) {
+                    if let Some(done) = session.operations.remove(&id) {
+                        done.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+}
+
+impl<R: Resolver, H: Handler> Handler for GraphQlWs<R, H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        if offered.contains(&PROTOCOL) {
+            return Some(PROTOCOL.to_string());
+        }
+        self.fallback.select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        if conn.protocol() != Some(PROTOCOL) {
+            return self.fallback.on_open(conn);
+        }
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(conn.id(), Session::default());
+
+        // connection_init が来なければ切断する
+        let handle = conn.handle();
+        let sessions = Arc::downgrade(&self.sessions);
+        thread::spawn(move || {
+            thread::sleep(CONNECTION_INIT_TIMEOUT);
+            let Some(sessions) = sessions.upgrade() else {
+                return;
+            };
+            let initialized = sessions
+                .lock()
+                .unwrap()
+                .get(&handle.id())
+                .is_none_or(|session| session.initialized);
+            if !initialized {
+                let _ = handle.close(4408, "Connection initialisation timeout");
+            }
+        });
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        if !self.is_graphql(conn.id()) {
+            return self.fallback.on_message(conn, message);
+        }
+        let result = match &message {
+            Message::Text(text) => self.receive(conn, text),
+            Message::Binary(_) => Err((4400, "Invalid message received".to_string())),
+        };
+        if let Err((code, reason)) = result {
+            warning!(
+                "graphql_error",
+                { connection_id: conn.id(), code: code, reason: reason.as_str() },
+                "graphql-ws: {} {}",
+                code,
+                reason
+            );
+            let _ = conn.close(code, &reason);
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        if !self.is_graphql(conn.id()) {
+            self.fallback.on_peer_close(conn, code, reason);
+        }
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        if !self.is_graphql(conn.id()) {
+            self.fallback.on_pong(conn, payload, latency);
+        }
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        let Some(session) = self.sessions.lock().unwrap().remove(&id) else {
+            return self.fallback.on_close(id);
+        };
+        for done in session.operations.values() {
+            done.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        if !self.is_graphql(id) {
+            self.fallback.on_error(id, error, terminated);
+        }
+    }
+}
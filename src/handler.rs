@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use crate::{
+    connection::{Connection, ConnectionId},
+    error::Error,
+    message::Message,
+};
+
+/// 接続のライフサイクルごとに呼ばれるcallback。
+/// 必要なものだけ実装すればよい
+pub trait Handler: Send + Sync + 'static {
+    /// クライアントが Sec-WebSocket-Protocol で提示したサブプロトコルから1つ選ぶ。
+    /// None ならサブプロトコルなしで接続する
+    fn select_protocol(&self, _path: &str, _offered: &[&str]) -> Option<String> {
+        None
+    }
+
+    /// handshakeが完了した
+    fn on_open(&self, _conn: &mut Connection) {}
+
+    /// Text/Binaryメッセージを受信した (fragmentは結合済み)
+    fn on_message(&self, _conn: &mut Connection, _message: Message) {}
+
+    /// peerからCloseを受信した。status codeがなければ 1005 (RFC 6455 7.1.5)。
+    /// この後に応答のCloseを送り、on_close が呼ばれる
+    fn on_peer_close(&self, _conn: &mut Connection, _code: u16, _reason: &str) {}
+
+    /// Pongを受信した。`latency` は送信したPing (`ConnectionHandle::ping`) に対応するPongなら、
+    /// Pingを送ってからの時間。peerが自発的に送ったPongなら None
+    fn on_pong(&self, _conn: &mut Connection, _payload: &[u8], _latency: Option<Duration>) {}
+
+    /// 接続が閉じた (正常終了・異常終了どちらでも呼ばれる)
+    fn on_close(&self, _id: ConnectionId) {}
+
+    /// プロトコル違反・I/Oエラー・handshakeの拒否が起きた。
+    /// `terminated` が true の場合、このエラーによって接続は閉じられている
+    fn on_error(&self, _id: ConnectionId, _error: &Error, _terminated: bool) {}
+}
+
+/// 何もしないHandler
+impl Handler for () {}
+
+/// 実行時に選んだHandlerを `Box<dyn Handler>` として渡せるようにする
+impl<H: Handler + ?Sized> Handler for Box<H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        (**self).select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        (**self).on_open(conn)
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        (**self).on_message(conn, message)
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        (**self).on_peer_close(conn, code, reason)
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        (**self).on_pong(conn, payload, latency)
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        (**self).on_close(id)
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        (**self).on_error(id, error, terminated)
+    }
+}
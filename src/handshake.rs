@@ -0,0 +1,374 @@
+// HTTPの処理
+//
+// 以下のようなリクエストが来る:
+// GET ws://127.0.0.1:7778/ HTTP/1.1
+// Host: 127.0.0.1:7778
+// Connection: Upgrade
+// Upgrade: websocket
+// Sec-WebSocket-Version: 13
+// Sec-WebSocket-Key: 9Kl3Zz3tA0ibMWQwyn/9kQ==
+// Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits
+//
+// 以下のようなレスポンスを返す:
+// HTTP/1.1 101 OK
+// Upgrade: websocket
+// Connection: upgrade
+// Sec-WebSocket-Accept: EK2cqLXRG/oxQwrUdEVXGrPDBuA=
+
+use base64::{engine::general_purpose, Engine as _};
+use sha1::{Digest, Sha1};
+#[cfg(feature = "server")]
+use std::time::Instant;
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    time::Duration,
+};
+
+use crate::error::{Error, Result};
+#[cfg(feature = "server")]
+use crate::stream::Stream;
+
+const RFC_DEFINED_UUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// handshakeのリクエストを読み込む際の制限。
+/// 1byteずつ送ってくるようなクライアント (slowloris) が接続とスレッドを占有し続けないようにする
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// 接続を受け付けてからリクエストのヘッダーを読み終えるまでの時間
+    pub timeout: Duration,
+    /// 1回のreadで何も届かないまま待つ時間
+    pub read_timeout: Duration,
+    /// リクエスト行とヘッダーの合計のbyte数
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(5),
+            max_header_bytes: 16 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// `HTTP/1.1` など
+    pub version: String,
+    /// header名は小文字に正規化済み
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// `\r\n\r\n` までのHTTPリクエストをパースする
+    pub fn parse(buffer: &[u8]) -> Result<Self> {
+        let request_text = String::from_utf8_lossy(buffer);
+        let mut lines = request_text.lines();
+
+        let request_line = lines
+            .next()
+            .ok_or_else(|| Error::Handshake("empty request".to_string()))?;
+        let values = request_line
+            .split(' ')
+            .map(|s| s.trim())
+            .collect::<Vec<&str>>();
+        if values.len() != 3 {
+            return Err(Error::Handshake(format!(
+                "malformed request line: {}",
+                request_line
+            )));
+        }
+
+        let mut headers = Vec::new();
+        for line in lines {
+            if line.is_empty() {
+                break;
+            }
+
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| Error::Handshake(format!("malformed header: {}", line)))?;
+            headers.push((key.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+
+        Ok(Self {
+            method: values[0].to_string(),
+            path: values[1].to_string(),
+            version: values[2].to_string(),
+            headers,
+        })
+    }
+
+    /// `\r\n\r\n` が来るまでreaderから読み込んでパースする
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        Self::read_head(reader).map(|(request, _)| request)
+    }
+
+    /// `read_from` と同じだが、`\r\n\r\n` より後に読み込んでしまったbodyの先頭も返す
+    pub(crate) fn read_head<R: Read>(reader: &mut R) -> Result<(Self, Vec<u8>)> {
+        Self::read_head_with(reader, &Limits::default(), |_| Ok(()))
+    }
+
+    /// `read_head` と同じだが、`limits` の時間を過ぎたら読み込みをやめる。
+    /// 読み終えたらstreamのread timeoutは元に戻す
+    #[cfg(feature = "server")]
+    pub(crate) fn read_head_within(
+        stream: &mut dyn Stream,
+        limits: &Limits,
+    ) -> Result<(Self, Vec<u8>)> {
+        let deadline = Instant::now() + limits.timeout;
+        let result = Self::read_head_with(stream, limits, |stream| {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "handshake timed out",
+                ));
+            }
+            stream.set_read_timeout(Some(remaining.min(limits.read_timeout)))
+        });
+        stream.set_read_timeout(None)?;
+        // read timeoutはプラットフォームによって WouldBlock になる
+        result.map_err(|e| match e {
+            Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock => Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake timed out",
+            )),
+            e => e,
+        })
+    }
+
+    /// 読み込むたびに `before_read` を呼ぶ
+    fn read_head_with<R: Read + ?Sized>(
+        reader: &mut R,
+        limits: &Limits,
+        mut before_read: impl FnMut(&mut R) -> io::Result<()>,
+    ) -> Result<(Self, Vec<u8>)> {
+        let mut head = Head::default();
+        let mut chunk = [0; 1024];
+        loop {
+            before_read(reader)?;
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                return Err(Error::Handshake("connection closed".to_string()));
+            }
+            if let Some(head) = head.push(&chunk[..n], limits)? {
+                return Ok(head);
+            }
+        }
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Cookieヘッダーの name=value の一覧 (RFC 6265 5.4)。同じ名前があれば最初のものを使う
+    pub fn cookies(&self) -> HashMap<&str, &str> {
+        let mut cookies = HashMap::new();
+        let pairs = self
+            .headers
+            .iter()
+            .filter(|(key, _)| key == "cookie")
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='));
+        for (name, value) in pairs {
+            // 値はダブルクォートで囲まれていてもよい
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            cookies.entry(name.trim()).or_insert(value);
+        }
+        cookies
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies().get(name).copied()
+    }
+
+    /// RFC 6455 4.2.1 の要件を満たしているか
+    pub fn validate(&self) -> Result<()> {
+        if self.method != "GET" {
+            return Err(Error::Handshake(format!(
+                "unexpected method: {}",
+                self.method
+            )));
+        }
+
+        let has_token = |key: &str, token: &str| {
+            self.header(key).is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case(token))
+            })
+        };
+
+        if !has_token("upgrade", "websocket") {
+            return Err(Error::Handshake("missing Upgrade: websocket".to_string()));
+        }
+        if !has_token("connection", "upgrade") {
+            return Err(Error::Handshake("missing Connection: Upgrade".to_string()));
+        }
+        if self.header("sec-websocket-version") != Some("13") {
+            return Err(Error::Handshake(
+                "unsupported Sec-WebSocket-Version".to_string(),
+            ));
+        }
+        if self.header("sec-websocket-key").is_none() {
+            return Err(Error::Handshake("missing Sec-WebSocket-Key".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// 届いた順にbyte列を溜め、リクエストのヘッダーの終わり (`\r\n\r\n`) を探す。
+/// 読み込みを待たないサーバーのevent loopも、ヘッダーが揃うまで接続ごとにこれに溜める
+#[derive(Default)]
+pub(crate) struct Head {
+    buffer: Vec<u8>,
+    /// 前回までに探し終えた位置。`\r\n\r\n` が読み込みの境目をまたぐ分だけ戻って探す
+    searched: usize,
+}
+
+impl Head {
+    /// `bytes` を追加する。ヘッダーが揃ったら、リクエストとその後に届いていたbyte列を返す
+    pub(crate) fn push(
+        &mut self,
+        bytes: &[u8],
+        limits: &Limits,
+    ) -> Result<Option<(Request, Vec<u8>)>> {
+        self.buffer.extend_from_slice(bytes);
+        let Some(i) = self.buffer[self.searched..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+        else {
+            self.searched = self.buffer.len().saturating_sub(3);
+            if self.buffer.len() > limits.max_header_bytes {
+                return Err(Error::Handshake("request header too large".to_string()));
+            }
+            return Ok(None);
+        };
+        let end = self.searched + i + 4;
+        if end > limits.max_header_bytes {
+            return Err(Error::Handshake("request header too large".to_string()));
+        }
+        let rest = self.buffer.split_off(end);
+        let request = Request::parse(&self.buffer)?;
+        if request.headers.len() > limits.max_headers {
+            return Err(Error::Handshake("too many headers".to_string()));
+        }
+        Ok(Some((request, rest)))
+    }
+}
+
+/// Sec-WebSocket-Key から Sec-WebSocket-Accept を計算する
+pub fn accept_key(sec_websocket_key: &str) -> String {
+    let plain_text = format!("{}{}", sec_websocket_key, RFC_DEFINED_UUID);
+
+    let mut hasher = Sha1::new();
+    hasher.update(plain_text);
+    general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+pub fn response(sec_websocket_key: &str) -> String {
+    response_with_headers(sec_websocket_key, &[])
+}
+
+/// 追加のヘッダーを付けた101レスポンス
+pub fn response_with_headers(sec_websocket_key: &str, headers: &[(String, String)]) -> String {
+    let headers = headers
+        .iter()
+        .map(|(key, value)| format!("{}: {}\r\n", key, value))
+        .collect::<String>();
+    format!(
+        "HTTP/1.1 101 OK\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n\
+        {}\
+        \r\n",
+        accept_key(sec_websocket_key),
+        headers
+    )
+}
+
+pub fn bad_request() -> &'static str {
+    "HTTP/1.1 400 Bad Request\r\n\
+    Content-Length: 0\r\n\
+    Connection: close\r\n\
+    \r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1回の読み込みで `size` バイトずつしか返さないreader
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        size: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.size.min(buf.len()).min(self.bytes.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    const REQUEST: &[u8] =
+        b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\r\nbody";
+
+    #[test]
+    fn finds_the_end_of_the_header_across_reads() {
+        for size in 1..=REQUEST.len() {
+            let mut reader = Trickle {
+                bytes: REQUEST,
+                size,
+            };
+            let (request, rest) = Request::read_head(&mut reader).unwrap();
+            assert_eq!(request.path, "/chat");
+            assert_eq!(request.header("upgrade"), Some("websocket"));
+            // 一度に読んだ分だけ、bodyの先頭が残る
+            assert!(b"body".starts_with(&rest), "{} {:?}", size, rest);
+        }
+    }
+
+    #[test]
+    fn rejects_incomplete_and_oversized_headers() {
+        let mut reader = Trickle {
+            bytes: b"GET / HTTP/1.1\r\nHost: a\r\n",
+            size: 3,
+        };
+        assert!(matches!(
+            Request::read_head(&mut reader),
+            Err(Error::Handshake(_))
+        ));
+
+        let limits = Limits {
+            max_header_bytes: 64,
+            ..Limits::default()
+        };
+        let header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(100));
+        let mut reader = Trickle {
+            bytes: header.as_bytes(),
+            size: 7,
+        };
+        assert!(matches!(
+            Request::read_head_with(&mut reader, &limits, |_| Ok(())),
+            Err(Error::Handshake(_))
+        ));
+    }
+}
@@ -0,0 +1,213 @@
+// Happy Eyeballs (RFC 8305) によるTCP接続
+//
+// 名前解決で複数のアドレスが返ったら、IPv6とIPv4を交互に並べ (6.)、
+// `ATTEMPT_DELAY` ごとにずらして接続を始める (5.)。試行が失敗したら待たずに次を始め、
+// 最初に成功した接続を使う。残りの試行は成功しても捨てる。各試行は `CONNECT_TIMEOUT` で諦める。
+// 片方のアドレスファミリーの経路が壊れていても、もう片方ですぐに接続できる
+//
+// 名前解決は `ToSocketAddrs` (getaddrinfo) でAとAAAAをまとめて引くため、
+// AAAAの応答を待つ Resolution Delay (3.) はない
+
+use std::{
+    io,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+/// 次の試行を始めるまでの時間 (Connection Attempt Delay。RFC 8305 の推奨値)
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// 1つのアドレスへの接続を待つ時間。応答のないアドレスで試行のスレッドが残り続けないようにする
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let addrs = interleave((host, port).to_socket_addrs()?.collect());
+    race(addrs, ATTEMPT_DELAY, |addr| {
+        TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+    })
+}
+
+/// `addrs` の順に `delay` ずつずらして `attempt` を始め、最初に成功した結果を返す
+fn race<T, F>(addrs: Vec<SocketAddr>, delay: Duration, attempt: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: Fn(SocketAddr) -> io::Result<T> + Clone + Send + 'static,
+{
+    // アドレスが1つなら競わせる必要はない
+    if let [addr] = addrs[..] {
+        return attempt(addr);
+    }
+
+    let (sender, results) = mpsc::channel();
+    let mut pending = addrs.into_iter();
+    let mut running = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            let sender = sender.clone();
+            let attempt = attempt.clone();
+            thread::spawn(move || {
+                // 既に別の試行が成功していれば、受け取られずに捨てられる
+                let _ = sender.send(attempt(addr));
+            });
+            running += 1;
+        } else if running == 0 {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no addresses to connect")
+            }));
+        }
+
+        // 次の試行を始めるまで結果を待つ。始める試行が残っていなければ、結果が出るまで待つ
+        let result = match pending.len() {
+            0 => results.recv().ok(),
+            _ => results.recv_timeout(delay).ok(),
+        };
+        match result {
+            Some(Ok(stream)) => return Ok(stream),
+            // 失敗したら待たずに次を始める
+            Some(Err(e)) => {
+                running -= 1;
+                last_error = Some(e);
+            }
+            None => {}
+        }
+    }
+}
+
+/// IPv6から始めて、IPv6とIPv4を交互に並べる。同じファミリーの中では名前解決の順を保つ
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut ordered = vec![];
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// 試行を始めた順に、アドレスと始めた時刻を記録する
+    type Started = Arc<Mutex<Vec<(SocketAddr, Duration)>>>;
+
+    /// `behavior(addr)` が (待つ時間, 成功するか) を返す試行
+    fn attempts(
+        behavior: fn(SocketAddr) -> (Duration, bool),
+    ) -> (
+        Started,
+        impl Fn(SocketAddr) -> io::Result<SocketAddr> + Clone + Send + 'static,
+    ) {
+        let started = Started::default();
+        let origin = Instant::now();
+        let record = started.clone();
+        let attempt = move |addr| {
+            record.lock().unwrap().push((addr, origin.elapsed()));
+            let (wait, ok) = behavior(addr);
+            thread::sleep(wait);
+            match ok {
+                true => Ok(addr),
+                false => Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    addr.to_string(),
+                )),
+            }
+        };
+        (started, attempt)
+    }
+
+    #[test]
+    fn interleaves_address_families() {
+        let addrs = [
+            "[::1]:80",
+            "[::2]:80",
+            "[::3]:80",
+            "127.0.0.1:80",
+            "127.0.0.2:80",
+        ]
+        .map(addr)
+        .to_vec();
+        assert_eq!(
+            interleave(addrs),
+            [
+                "[::1]:80",
+                "127.0.0.1:80",
+                "[::2]:80",
+                "127.0.0.2:80",
+                "[::3]:80"
+            ]
+            .map(addr)
+        );
+        assert_eq!(
+            interleave(vec![addr("127.0.0.1:80"), addr("127.0.0.2:80")]),
+            [addr("127.0.0.1:80"), addr("127.0.0.2:80")]
+        );
+    }
+
+    #[test]
+    fn starts_the_next_attempt_after_the_delay() {
+        // IPv6の経路が応答しない
+        let (started, attempt) = attempts(|addr| match addr.is_ipv6() {
+            true => (Duration::from_secs(2), true),
+            false => (Duration::ZERO, true),
+        });
+        let addrs = vec![addr("[2001:db8::1]:80"), addr("192.0.2.1:80")];
+        let result = race(addrs, Duration::from_millis(100), attempt).unwrap();
+        assert_eq!(result, addr("192.0.2.1:80"));
+        let started = started.lock().unwrap();
+        assert_eq!(started.len(), 2);
+        assert!(started[1].1 >= Duration::from_millis(100), "{:?}", started);
+        assert!(started[1].1 < Duration::from_secs(1), "{:?}", started);
+    }
+
+    #[test]
+    fn starts_the_next_attempt_at_once_after_a_failure() {
+        let (started, attempt) = attempts(|addr| (Duration::ZERO, addr.is_ipv4()));
+        let addrs = vec![addr("[2001:db8::1]:80"), addr("192.0.2.1:80")];
+        let result = race(addrs, Duration::from_secs(5), attempt).unwrap();
+        assert_eq!(result, addr("192.0.2.1:80"));
+        assert!(started.lock().unwrap()[1].1 < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn returns_the_last_error_when_every_attempt_fails() {
+        let (started, attempt) = attempts(|_| (Duration::ZERO, false));
+        let addrs = vec![
+            addr("[2001:db8::1]:80"),
+            addr("192.0.2.1:80"),
+            addr("[2001:db8::2]:80"),
+        ];
+        let error = race(addrs, Duration::from_secs(5), attempt).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(started.lock().unwrap().len(), 3);
+        assert_eq!(
+            race(vec![], Duration::from_secs(5), Ok::<SocketAddr, io::Error>)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn connects_to_localhost() {
+        // localhostが ::1 にも解決されるなら、そちらは拒否されて 127.0.0.1 に接続する
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect("localhost", port).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,180 @@
+// WebSocket以外の簡単なHTTPのレスポンスを組み立てる。
+// JWKSやACMEの取得に使う、`Connection: close` で1回だけリクエストを送る最小限のクライアントもここに置く
+
+#[cfg(any(feature = "jwt", feature = "acme"))]
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+#[cfg(all(feature = "tls", any(feature = "jwt", feature = "acme")))]
+use crate::tls::{self, ClientTlsConfig};
+
+pub(crate) fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// `/path?a=1&b=2` を path とクエリパラメータに分ける
+pub(crate) fn split_query(target: &str) -> (&str, Vec<(&str, &str)>) {
+    match target.split_once('?') {
+        Some((path, query)) => (
+            path,
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+                .collect(),
+        ),
+        None => (target, vec![]),
+    }
+}
+
+/// クエリパラメータの値の `%XX` と `+` (スペース) を戻す。不正なUTF-8は置き換える
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match value
+                    .get(i + 1..i + 3)
+                    .map(|hex| u8::from_str_radix(hex, 16))
+                {
+                    Some(Ok(byte)) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// クライアントとして受信したHTTPのレスポンス
+#[cfg(any(feature = "jwt", feature = "acme"))]
+#[derive(Debug)]
+pub(crate) struct ClientResponse {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+#[cfg(any(feature = "jwt", feature = "acme"))]
+impl ClientResponse {
+    pub(crate) fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// `host:port` に接続して `request` を送り、サーバーが切断するまでに受信したバイト列 (`max_bytes` まで) を返す。
+/// `tls` があればTLSで接続する。`timeout` は接続・TLSのhandshake・読み書きのそれぞれに使う
+#[cfg(any(feature = "jwt", feature = "acme"))]
+pub(crate) fn send(
+    host: &str,
+    port: u16,
+    #[cfg(feature = "tls")] tls: Option<&ClientTlsConfig>,
+    request: &[u8],
+    timeout: Duration,
+    max_bytes: u64,
+) -> io::Result<Vec<u8>> {
+    let socket = (host, port)
+        .to_socket_addrs()?
+        .find_map(|addr| TcpStream::connect_timeout(&addr, timeout).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "failed to connect"))?;
+    // TLSのhandshakeにもタイムアウトが効くように、先に設定する
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    #[cfg(feature = "tls")]
+    let mut stream: Box<dyn crate::stream::Stream> = match tls {
+        Some(config) => Box::new(tls::connect(socket, host, config)?),
+        None => Box::new(socket),
+    };
+    #[cfg(not(feature = "tls"))]
+    let mut stream = socket;
+    stream.write_all(request)?;
+    let mut response = Vec::new();
+    match stream.take(max_bytes).read_to_end(&mut response) {
+        // close_notifyを送らずに切断するHTTPSのサーバーもある。bodyが揃っているかは `parse_response` で確かめる
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => {}
+        result => {
+            result?;
+        }
+    }
+    Ok(response)
+}
+
+/// `send` で受信したレスポンスをパースする。chunkedのbodyはデコードする
+#[cfg(any(feature = "jwt", feature = "acme"))]
+pub(crate) fn parse_response(response: &[u8]) -> io::Result<ClientResponse> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete response"))?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| invalid("invalid header"))?;
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid(&format!("invalid status line: {}", status_line)))?;
+    let mut parsed = ClientResponse {
+        status,
+        headers: lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect(),
+        body: vec![],
+    };
+    let chunked = parsed
+        .header("transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    let mut body = &response[end + 4..];
+    parsed.body = if chunked {
+        let mut decoded = Vec::new();
+        loop {
+            let line_end = body
+                .windows(2)
+                .position(|w| w == b"\r\n")
+                .ok_or_else(|| invalid("truncated chunk"))?;
+            let size = std::str::from_utf8(&body[..line_end])
+                .ok()
+                .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+                .ok_or_else(|| invalid("invalid chunk size"))?;
+            body = &body[line_end + 2..];
+            if size == 0 {
+                break decoded;
+            }
+            if body.len() < size + 2 {
+                return Err(invalid("truncated chunk"));
+            }
+            decoded.extend_from_slice(&body[..size]);
+            body = &body[size + 2..];
+        }
+    } else {
+        body.to_vec()
+    };
+    Ok(parsed)
+}
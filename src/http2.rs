@@ -0,0 +1,394 @@
+// HTTP/2の上のWebSocket (RFC 8441)
+//
+// HTTP/2の接続では、SETTINGS_ENABLE_CONNECT_PROTOCOL を送ったうえで、
+// 各streamのExtended CONNECTでWebSocketを始める:
+//
+//   :method = CONNECT
+//   :protocol = websocket
+//   :scheme = https
+//   :path = /chat
+//   :authority = server.example.com
+//   sec-websocket-version = 13
+//
+// 受け付けたら 200 を返し、以降はstreamのDATAでフレームを送り合う。
+// 同じ接続の他のstream (通常のリクエスト) は `with_fallback` の関数で応答する。
+//
+// handshakeの検証・middleware・Handlerは `Upgrader` に任せる。
+// HTTP/1.1のupgradeと同じ処理を通すため、`Upgrade`・`Connection`・`Sec-WebSocket-Key` を補ったリクエストを渡し、
+// 101の応答を 200 に置き換える。streamと接続のスレッドは `Bridge` のパイプでつなぐ
+
+use std::{
+    io::{self, Write},
+    net::{Shutdown, SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use h2::{ext::Protocol, server::SendResponse, RecvStream};
+use http::{Method, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    bridge::Bridge, handler::Handler, handshake::Request, log::debug, server::Upgrader,
+    stream::Stream, testing::Pipe,
+};
+
+type Fallback = dyn Fn(http::Request<()>) -> Response<Vec<u8>> + Send + Sync;
+
+/// HTTP/2の接続を受け付け、Extended CONNECTのstreamを `Upgrader` に渡す
+pub struct Http2<H: Handler> {
+    upgrader: Upgrader<H>,
+    /// WebSocket以外のリクエストへの応答。なければ 404
+    fallback: Option<Arc<Fallback>>,
+}
+
+impl<H: Handler> Clone for Http2<H> {
+    fn clone(&self) -> Self {
+        Self {
+            upgrader: self.upgrader.clone(),
+            fallback: self.fallback.clone(),
+        }
+    }
+}
+
+impl<H: Handler> Http2<H> {
+    pub fn new(upgrader: Upgrader<H>) -> Self {
+        Self {
+            upgrader,
+            fallback: None,
+        }
+    }
+
+    /// WebSocket以外のリクエストに `fallback` で応答する。リクエストのbodyは読まない
+    pub fn with_fallback<F>(mut self, fallback: F) -> Self
+    where
+        F: Fn(http::Request<()>) -> Response<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.fallback = Some(Arc::new(fallback));
+        self
+    }
+
+    /// `listener` で受け付けた接続を、TLSなしのHTTP/2 (prior knowledge) として処理する。戻らない
+    pub fn run(self, listener: TcpListener) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                loop {
+                    let (stream, peer_addr) = listener.accept().await?;
+                    let http2 = self.clone();
+                    tokio::spawn(async move { http2.serve_connection(stream, peer_addr).await });
+                }
+            })
+    }
+
+    /// 1つのHTTP/2の接続を処理する。TLSの上で使う場合は、ALPNで `h2` を選んだ接続を渡す。
+    /// tokioのランタイムの中から呼ぶこと
+    pub async fn serve_connection<T>(&self, io: T, peer_addr: SocketAddr)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut builder = h2::server::Builder::new();
+        builder.enable_connect_protocol();
+        let mut connection = match builder.handshake::<_, Bytes>(io).await {
+            Ok(connection) => connection,
+            Err(e) => return connection_failed(peer_addr, &e),
+        };
+        while let Some(accepted) = connection.accept().await {
+            let (request, respond) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => return connection_failed(peer_addr, &e),
+            };
+            let is_websocket = request.method() == Method::CONNECT
+                && request
+                    .extensions()
+                    .get::<Protocol>()
+                    .is_some_and(|protocol| protocol.as_str() == "websocket");
+            if is_websocket {
+                tokio::spawn(websocket(
+                    self.upgrader.clone(),
+                    request,
+                    respond,
+                    peer_addr,
+                ));
+            } else {
+                let response = match &self.fallback {
+                    Some(fallback) => fallback(request.map(|_| ())),
+                    None => status(StatusCode::NOT_FOUND),
+                };
+                respond_with(respond, response);
+            }
+        }
+    }
+}
+
+fn connection_failed(peer_addr: SocketAddr, error: &h2::Error) {
+    debug!(
+        "http2_connection_failed",
+        { peer: peer_addr.to_string(), error: error.to_string() },
+        "http/2 connection from {} failed: {}",
+        peer_addr,
+        error
+    );
+}
+
+/// Extended CONNECTのstreamを、HTTP/1.1のhandshakeに直して `upgrader` に渡す
+async fn websocket<H: Handler>(
+    upgrader: Upgrader<H>,
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer_addr: SocketAddr,
+) {
+    let (parts, data) = request.into_parts();
+    let mut headers = parts
+        .headers
+        .iter()
+        .map(|(key, value)| {
+            (
+                key.as_str().to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect::<Vec<_>>();
+    if let Some(authority) = parts.uri.authority() {
+        headers.push(("host".to_string(), authority.to_string()));
+    }
+    // Sec-WebSocket-Accept は 200 の応答に載せないので、鍵は何でもよい
+    let key = general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+    headers.extend([
+        ("upgrade".to_string(), "websocket".to_string()),
+        ("connection".to_string(), "upgrade".to_string()),
+        ("sec-websocket-key".to_string(), key),
+    ]);
+    let request = Request {
+        method: "GET".to_string(),
+        path: parts
+            .uri
+            .path_and_query()
+            .map_or_else(|| "/".to_string(), |path| path.to_string()),
+        version: "HTTP/2".to_string(),
+        headers,
+    };
+
+    let Ok(mut bridge) = Bridge::upgrade(&upgrader, request, peer_addr) else {
+        return respond_with(respond, status(StatusCode::INTERNAL_SERVER_ERROR));
+    };
+    let Some((head, mut body)) = bridge.response().await else {
+        return respond_with(respond, status(StatusCode::INTERNAL_SERVER_ERROR));
+    };
+    let accepted = head.status == 101;
+    let mut response = Response::builder().status(if accepted { 200 } else { head.status });
+    for (key, value) in head.end_to_end_headers() {
+        if !key.eq_ignore_ascii_case("sec-websocket-accept") {
+            response = response.header(key.as_str(), value.as_str());
+        }
+    }
+    if !accepted {
+        bridge.read_to_end(&mut body).await;
+        let response = response
+            .body(body)
+            .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR));
+        return respond_with(respond, response);
+    }
+
+    let Ok(response) = response.body(()) else {
+        return respond_with(respond, status(StatusCode::INTERNAL_SERVER_ERROR));
+    };
+    let Ok(mut send) = respond.send_response(response, false) else {
+        return;
+    };
+    let Bridge { pipe, mut chunks } = bridge;
+    tokio::spawn(forward_data(data, pipe));
+    if !body.is_empty() && send.send_data(Bytes::from(body), false).is_err() {
+        return;
+    }
+    while let Some(chunk) = chunks.recv().await {
+        if send.send_data(Bytes::from(chunk), false).is_err() {
+            return;
+        }
+    }
+    // 接続のスレッドが閉じたら、streamも閉じる
+    let _ = send.send_data(Bytes::new(), true);
+}
+
+/// streamのDATA (フレーム) を接続のスレッドに渡す
+async fn forward_data(mut data: RecvStream, mut pipe: Pipe) {
+    while let Some(Ok(chunk)) = data.data().await {
+        let _ = data.flow_control().release_capacity(chunk.len());
+        if pipe.write_all(&chunk).is_err() {
+            return;
+        }
+    }
+    // クライアントがstreamを閉じたら、接続のスレッドの読み込みをEOFにする
+    let _ = Stream::shutdown(&pipe, Shutdown::Write);
+}
+
+fn status(status: StatusCode) -> Response<Vec<u8>> {
+    let mut response = Response::new(vec![]);
+    *response.status_mut() = status;
+    response
+}
+
+fn respond_with(mut respond: SendResponse<Bytes>, response: Response<Vec<u8>>) {
+    let (parts, body) = response.into_parts();
+    let end_of_stream = body.is_empty();
+    let Ok(mut send) = respond.send_response(Response::from_parts(parts, ()), end_of_stream) else {
+        return;
+    };
+    if !end_of_stream {
+        let _ = send.send_data(Bytes::from(body), true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::{
+        connection::Connection,
+        frame::{Frame, Opcode},
+        message::Message,
+        middleware::BasicAuth,
+        server::Server,
+    };
+
+    /// 受信したメッセージをそのまま返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    fn spawn_http2(server: Server<Echo>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let http2 = Http2::new(server.upgrader()).with_fallback(|request| {
+            Response::new(format!("fallback {}", request.uri().path()).into_bytes())
+        });
+        thread::spawn(move || http2.run(listener));
+        addr
+    }
+
+    fn connect_request(path: &str) -> http::Request<()> {
+        let mut request = http::Request::builder()
+            .method(Method::CONNECT)
+            .uri(format!("http://localhost{}", path))
+            .header("sec-websocket-version", "13")
+            .body(())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+        request
+    }
+
+    /// 受信したDATAからフレームを1つ取り出す
+    async fn read_frame(data: &mut RecvStream, buffer: &mut Vec<u8>) -> Frame {
+        loop {
+            if let Some((frame, len)) = Frame::parse(buffer).unwrap() {
+                buffer.drain(..len);
+                return frame;
+            }
+            let chunk = data.data().await.unwrap().unwrap();
+            let _ = data.flow_control().release_capacity(chunk.len());
+            buffer.extend_from_slice(&chunk);
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    async fn handshake(addr: SocketAddr) -> h2::client::SendRequest<Bytes> {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (send_request, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        let send_request = send_request.ready().await.unwrap();
+        // サーバーのSETTINGSを受信するまで待つ
+        for _ in 0..100 {
+            if send_request.is_extended_connect_protocol_enabled() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(send_request.is_extended_connect_protocol_enabled());
+        send_request
+    }
+
+    #[test]
+    fn carries_websocket_and_other_requests_on_one_connection() {
+        let addr = spawn_http2(Server::bind("127.0.0.1:0", Echo).unwrap());
+        block_on(async {
+            let mut send_request = handshake(addr).await;
+
+            let (response, mut send) = send_request
+                .send_request(connect_request("/chat"), false)
+                .unwrap();
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().get("sec-websocket-accept").is_none());
+            let mut data = response.into_body();
+
+            // 同じ接続で通常のリクエストも送れる
+            let mut send_request = send_request.ready().await.unwrap();
+            let request = http::Request::get("http://localhost/index.html")
+                .body(())
+                .unwrap();
+            let (other, _) = send_request.send_request(request, true).unwrap();
+            let mut other = other.await.unwrap().into_body();
+            assert_eq!(
+                other.data().await.unwrap().unwrap(),
+                Bytes::from_static(b"fallback /index.html")
+            );
+
+            let mut buffer = vec![];
+            for text in ["hello", "world"] {
+                let frame = Frame::from(Message::Text(text.to_string())).masked([1, 2, 3, 4]);
+                send.send_data(Bytes::from(frame.to_bytes()), false)
+                    .unwrap();
+                let frame = read_frame(&mut data, &mut buffer).await;
+                assert_eq!(frame.opcode, Opcode::Text);
+                assert_eq!(frame.payload, text.as_bytes());
+            }
+
+            send.send_data(
+                Bytes::from(Frame::close(1000, "bye").masked([1, 2, 3, 4]).to_bytes()),
+                false,
+            )
+            .unwrap();
+            assert_eq!(
+                read_frame(&mut data, &mut buffer).await.opcode,
+                Opcode::Close
+            );
+        });
+    }
+
+    #[test]
+    fn returns_rejections_from_middleware() {
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_layer(BasicAuth::new("chat", "user", "secret"));
+        let addr = spawn_http2(server);
+        block_on(async {
+            let mut send_request = handshake(addr).await;
+            let (response, _) = send_request
+                .send_request(connect_request("/chat"), true)
+                .unwrap();
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert!(response.headers().contains_key("www-authenticate"));
+        });
+    }
+}
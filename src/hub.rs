@@ -0,0 +1,948 @@
+// 接続をroom単位でまとめ、roomに参加している接続へメッセージをbroadcastする
+//
+// Backend (Redis, NATSなど) を設定すると、publishしたメッセージを他のサーバーにも流し、
+// 他のサーバーでpublishされたメッセージもローカルの接続へ配送する
+//
+// 履歴を有効にすると、roomごとに直近のメッセージを保持し、新しく参加した接続に送り直せる
+//
+// Journalを設定すると、配送したメッセージをディスクにも記録し、再起動後も履歴を復元できる
+//
+// 再開可能なセッションの接続が切れた場合は、参加していたroomを覚えておき (park)、
+// その間にroomへ配送されたメッセージを溜めておく。猶予時間内に再接続すれば (resume) roomに戻して送る
+//
+// 接続を持たない購読者 (SSEなど) は `watch` でroomのメッセージをチャネルから受け取れる。
+// asyncのtaskでは `subscribe` (tokio feature) でtokioのbroadcastから受け取る。`watch_with` なら他のチャネルに流せる
+//
+// roomの名前には `orders.*` や `metrics.#` のようなパターンを使える (`topic` を参照)。
+// パターンのroomに参加・`watch` すると、一致する名前のroomのメッセージも届く
+//
+// Authorizerを設定すると、クライアントの操作によるroomへの参加とpublish (`Connection::join`・`Connection::publish`、
+// STOMPのSUBSCRIBE/SEND、SSEの購読など) を許可するか、Hubで一か所にまとめて判断する。
+// アプリケーションが `Hub::join`・`Hub::publish` を直接呼ぶ場合は確かめない
+//
+// presenceの通知を有効にすると、roomへの参加・退出を他の参加者に以下のTextメッセージで知らせる:
+// {"event":"join","room":"lobby","id":1,"user":"alice"}  (userは無ければnull)
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    backend::Backend,
+    connection::{Connection, ConnectionHandle, ConnectionId, PreparedMessage, Priority},
+    error::{Error, Result},
+    journal::{self, Journal},
+    json,
+    log::{debug, warning},
+    message::Message,
+    stats::{Breakdown, LabeledStats},
+    topic::{self, Patterns},
+};
+
+pub struct Hub {
+    rooms: Mutex<HashMap<String, HashMap<ConnectionId, Member>>>,
+    /// 参加者か `watch` の購読者がいるパターンのroom
+    patterns: Mutex<Patterns>,
+    backend: Option<Box<dyn Backend>>,
+    presence_events: AtomicBool,
+    /// roomごとの直近のメッセージ (古い順)
+    history: Mutex<HashMap<String, VecDeque<Message>>>,
+    /// roomごとに保持するメッセージ数。0なら保持しない
+    history_len: AtomicUsize,
+    replay_history: AtomicBool,
+    journal: Mutex<Option<Journal>>,
+    /// 切断中のセッション (セッションのトークン -> 状態)
+    parked: Mutex<HashMap<String, Parked>>,
+    /// 接続を持たない購読者 (room名 -> 送信先)
+    watchers: Mutex<HashMap<String, Vec<Watcher>>>,
+    /// 何人に送るごとに他のbroadcastへ順番を譲るか。0なら譲らない
+    fanout_batch: AtomicUsize,
+    /// 配送を始める参加者の位置をずらすためのカウンタ
+    fanout_offset: AtomicUsize,
+    /// 配送中のbroadcastが区切りごとに順番を待つ列
+    fanout_turns: Turns,
+    /// roomごとのpublishされた・配送したメッセージ数とバイト数
+    room_stats: Breakdown,
+    authorizer: Mutex<Option<Arc<dyn Authorizer>>>,
+}
+
+/// クライアントの操作によるroomへの参加とpublishを許可するか。`Hub::set_authorizer` で設定する。
+/// 実装しなかった操作は全て許可する
+pub trait Authorizer: Send + Sync + 'static {
+    /// roomに参加する (パターンのroomなら、一致するroomのメッセージを受け取る) のを許可するか
+    fn can_join(&self, _conn: &Connection, _room: &str) -> bool {
+        true
+    }
+
+    /// roomにメッセージをpublishするのを許可するか
+    fn can_publish(&self, _conn: &Connection, _room: &str, _message: &Message) -> bool {
+        true
+    }
+}
+
+/// `watch_with` の購読者。false を返したら購読をやめる
+type Watcher = Box<dyn FnMut(&Message) -> bool + Send>;
+
+/// broadcastで他のbroadcastへ譲るまでに送る参加者数のデフォルト
+const DEFAULT_FANOUT_BATCH: usize = 64;
+
+/// 順番を待つ時間の上限。順番の来たbroadcastが遅い接続への書き込みで止まっていても、他の配送は止めない
+const FANOUT_TURN_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// 切断中のセッションに溜めておくメッセージの上限。超えたら古いものから捨てる
+const MAX_PARKED_MESSAGES: usize = 1024;
+
+/// `subscribe` のbroadcastのチャネルに溜めるメッセージ数
+#[cfg(feature = "tokio")]
+const SUBSCRIBE_CAPACITY: usize = 1024;
+
+struct Parked {
+    /// 参加していたroomとユーザー名
+    rooms: Vec<(String, Presence)>,
+    queue: VecDeque<Message>,
+    expires_at: Instant,
+}
+
+#[derive(Clone)]
+struct Member {
+    handle: ConnectionHandle,
+    user: Option<String>,
+}
+
+/// roomに参加している接続
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presence {
+    pub id: ConnectionId,
+    /// 参加時に指定したユーザー名など
+    pub user: Option<String>,
+}
+
+impl Hub {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::build(None))
+    }
+
+    fn build(backend: Option<Box<dyn Backend>>) -> Self {
+        Self {
+            rooms: Mutex::new(HashMap::new()),
+            patterns: Mutex::default(),
+            backend,
+            presence_events: AtomicBool::new(false),
+            history: Mutex::new(HashMap::new()),
+            history_len: AtomicUsize::new(0),
+            replay_history: AtomicBool::new(false),
+            journal: Mutex::new(None),
+            parked: Mutex::new(HashMap::new()),
+            watchers: Mutex::new(HashMap::new()),
+            fanout_batch: AtomicUsize::new(DEFAULT_FANOUT_BATCH),
+            fanout_offset: AtomicUsize::new(0),
+            fanout_turns: Turns::default(),
+            room_stats: Breakdown::default(),
+            authorizer: Mutex::new(None),
+        }
+    }
+
+    /// Backendを経由して他のサーバーとpublishを共有するHub
+    pub fn with_backend<B: Backend>(backend: B) -> Result<Arc<Self>> {
+        let hub = Arc::new(Self::build(Some(Box::new(backend))));
+
+        let weak = Arc::downgrade(&hub);
+        hub.backend
+            .as_ref()
+            .unwrap()
+            .subscribe(Box::new(move |room, message| {
+                if let Some(hub) = weak.upgrade() {
+                    hub.deliver(room, &message);
+                }
+            }))?;
+
+        Ok(hub)
+    }
+
+    /// roomへの参加・退出を他の参加者に通知するか (デフォルトは通知しない)
+    pub fn set_presence_events(&self, enabled: bool) {
+        self.presence_events.store(enabled, Ordering::Relaxed);
+    }
+
+    /// roomごとに直近のlen件のメッセージを保持する。0なら保持しない (デフォルト)
+    pub fn set_history_len(&self, len: usize) {
+        self.history_len.store(len, Ordering::Relaxed);
+        let mut history = self.history.lock().unwrap();
+        history.retain(|_, messages| {
+            let excess = messages.len().saturating_sub(len);
+            messages.drain(..excess);
+            !messages.is_empty()
+        });
+    }
+
+    /// broadcastで `batch` 人に送るごとに、同時に配送中の他のbroadcastへ順番を譲る。0なら譲らない (デフォルトは64)
+    pub fn set_fanout_batch(&self, batch: usize) {
+        self.fanout_batch.store(batch, Ordering::Relaxed);
+    }
+
+    /// roomに新しく参加した接続に、保持している履歴を送るか (デフォルトは送らない)
+    pub fn set_replay_history(&self, enabled: bool) {
+        self.replay_history.store(enabled, Ordering::Relaxed);
+    }
+
+    /// 配送したメッセージをJournalに記録する。
+    /// 既に記録されているメッセージは履歴として読み込むので、`set_history_len` の後に呼ぶこと
+    pub fn set_journal(&self, journal: Journal) -> Result<()> {
+        for entry in journal::read(journal.dir())? {
+            self.record_history(&entry.room, &entry.message);
+        }
+        *self.journal.lock().unwrap() = Some(journal);
+        Ok(())
+    }
+
+    /// クライアントの操作によるroomへの参加とpublishをAuthorizerで確かめる (デフォルトは全て許可する)
+    pub fn set_authorizer<A: Authorizer>(&self, authorizer: A) {
+        *self.authorizer.lock().unwrap() = Some(Arc::new(authorizer));
+    }
+
+    /// 接続がroomに参加してよいか確かめる。許可されなければ `Error::PolicyViolation`
+    pub fn authorize_join(&self, conn: &Connection, room: &str) -> Result<()> {
+        match self.authorizer() {
+            Some(authorizer) if !authorizer.can_join(conn, room) => {
+                debug!(
+                    "join_denied",
+                    { connection_id: conn.id(), room: room },
+                    "connection {}: not allowed to join {}",
+                    conn.id(),
+                    room
+                );
+                Err(Error::PolicyViolation(format!(
+                    "not allowed to join {}",
+                    room
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 接続がroomにpublishしてよいか確かめる。許可されなければ `Error::PolicyViolation`
+    pub fn authorize_publish(
+        &self,
+        conn: &Connection,
+        room: &str,
+        message: &Message,
+    ) -> Result<()> {
+        match self.authorizer() {
+            Some(authorizer) if !authorizer.can_publish(conn, room, message) => {
+                debug!(
+                    "publish_denied",
+                    { connection_id: conn.id(), room: room },
+                    "connection {}: not allowed to publish to {}",
+                    conn.id(),
+                    room
+                );
+                Err(Error::PolicyViolation(format!(
+                    "not allowed to publish to {}",
+                    room
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 判断している間にロックを持たないように、Authorizerを取り出しておく
+    fn authorizer(&self) -> Option<Arc<dyn Authorizer>> {
+        self.authorizer.lock().unwrap().clone()
+    }
+
+    /// roomの直近のメッセージ (古い順)
+    pub fn history(&self, room: &str) -> Vec<Message> {
+        match self.history.lock().unwrap().get(room) {
+            Some(messages) => messages.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn join(&self, room: &str, handle: ConnectionHandle) {
+        self.join_member(room, Member { handle, user: None });
+    }
+
+    /// ユーザー名などを付けてroomに参加する。presenceの問い合わせや通知に含まれる
+    pub fn join_as(&self, room: &str, handle: ConnectionHandle, user: &str) {
+        let user = Some(user.to_string());
+        self.join_member(room, Member { handle, user });
+    }
+
+    fn join_member(&self, room: &str, member: Member) {
+        let presence = Presence {
+            id: member.handle.id(),
+            user: member.user.clone(),
+        };
+        let handle = member.handle.clone();
+        let previous = {
+            let mut rooms = self.rooms.lock().unwrap();
+            if !rooms.contains_key(room) {
+                self.add_pattern(room);
+            }
+            rooms
+                .entry(room.to_string())
+                .or_default()
+                .insert(presence.id, member)
+        };
+        if previous.is_some() {
+            return;
+        }
+
+        if self.replay_history.load(Ordering::Relaxed) {
+            for message in self.history(room) {
+                if handle.send(message).is_err() {
+                    break;
+                }
+            }
+        }
+        self.notify("join", room, &presence);
+    }
+
+    pub fn leave(&self, room: &str, id: ConnectionId) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(members) = rooms.get_mut(room) else {
+            return;
+        };
+        let member = members.remove(&id);
+        if members.is_empty() {
+            rooms.remove(room);
+            self.remove_pattern(room);
+        }
+        drop(rooms);
+
+        if let Some(member) = member {
+            self.notify("leave", room, &member.presence());
+        }
+    }
+
+    /// 全てのroomから抜ける (接続が閉じたとき)
+    pub fn leave_all(&self, id: ConnectionId) {
+        let mut left = vec![];
+        self.rooms.lock().unwrap().retain(|room, members| {
+            if let Some(member) = members.remove(&id) {
+                left.push((room.clone(), member.presence()));
+            }
+            self.retain_room(room, members)
+        });
+
+        for (room, presence) in left {
+            self.notify("leave", &room, &presence);
+        }
+    }
+
+    /// 接続を全てのroomから外し、grace の間は配送されたメッセージを溜めておく。
+    /// presenceの通知は、猶予時間が過ぎて戻らなかったときに行う
+    pub(crate) fn park(&self, id: ConnectionId, token: &str, grace: Duration) {
+        self.expire();
+
+        let mut rooms = vec![];
+        self.rooms.lock().unwrap().retain(|room, members| {
+            if let Some(member) = members.remove(&id) {
+                rooms.push((room.clone(), member.presence()));
+            }
+            self.retain_room(room, members)
+        });
+
+        self.parked.lock().unwrap().insert(
+            token.to_string(),
+            Parked {
+                rooms,
+                queue: VecDeque::new(),
+                expires_at: Instant::now() + grace,
+            },
+        );
+    }
+
+    /// 切断中のセッションを新しい接続で再開する。参加していたroomに戻し、溜めていたメッセージを送る。
+    /// 該当するセッションがない (猶予時間が過ぎた) 場合は false
+    pub(crate) fn resume(&self, token: &str, handle: ConnectionHandle) -> bool {
+        self.expire();
+
+        let Some(parked) = self.parked.lock().unwrap().remove(token) else {
+            return false;
+        };
+        {
+            let mut rooms = self.rooms.lock().unwrap();
+            for (room, presence) in parked.rooms {
+                let member = Member {
+                    handle: handle.clone(),
+                    user: presence.user,
+                };
+                if !rooms.contains_key(&room) {
+                    self.add_pattern(&room);
+                }
+                rooms.entry(room).or_default().insert(handle.id(), member);
+            }
+        }
+        for message in parked.queue {
+            if handle.send(message).is_err() {
+                break;
+            }
+        }
+        true
+    }
+
+    /// 再開できるセッションか
+    pub(crate) fn is_parked(&self, token: &str) -> bool {
+        self.expire();
+        self.parked.lock().unwrap().contains_key(token)
+    }
+
+    /// 猶予時間が過ぎたセッションを捨てて、roomから抜けたことを通知する
+    fn expire(&self) {
+        let now = Instant::now();
+        let mut expired = vec![];
+        self.parked.lock().unwrap().retain(|_, parked| {
+            if parked.expires_at > now {
+                return true;
+            }
+            expired.append(&mut parked.rooms);
+            false
+        });
+
+        for (room, presence) in expired {
+            self.notify("leave", &room, &presence);
+        }
+    }
+
+    /// roomに参加している接続の一覧 (ID順)
+    pub fn presence(&self, room: &str) -> Vec<Presence> {
+        let mut presence = match self.rooms.lock().unwrap().get(room) {
+            Some(members) => members.values().map(Member::presence).collect::<Vec<_>>(),
+            None => return vec![],
+        };
+        presence.sort_by_key(|presence| presence.id);
+        presence
+    }
+
+    /// 参加・退出した本人以外の参加者に通知する
+    fn notify(&self, event: &str, room: &str, presence: &Presence) {
+        if !self.presence_events.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let json = format!(
+            "{{\"event\":{},\"room\":{},\"id\":{},\"user\":{}}}",
+            json::string(event),
+            json::string(room),
+            presence.id,
+            presence
+                .user
+                .as_deref()
+                .map_or("null".to_string(), json::string)
+        );
+        for member in self.members(room) {
+            if member.handle.id() != presence.id {
+                // 通知は溜まっているbroadcastより先に届ける
+                let _ = member
+                    .handle
+                    .send_with_priority(Message::Text(json.clone()), Priority::High);
+            }
+        }
+    }
+
+    fn members(&self, room: &str) -> Vec<Member> {
+        match self.rooms.lock().unwrap().get(room) {
+            Some(members) => members.values().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    /// roomにpublishされたメッセージを受け取る接続。一致するパターンのroomの参加者も含める
+    fn subscribers(&self, room: &str) -> Vec<Member> {
+        let rooms = self.rooms.lock().unwrap();
+        let patterns = self.patterns.lock().unwrap().matches(room);
+        if patterns.is_empty() {
+            return rooms
+                .get(room)
+                .map_or(vec![], |members| members.values().cloned().collect());
+        }
+        // 複数のパターンに一致しても1回だけ送る
+        let mut subscribers = HashMap::new();
+        for name in std::iter::once(room).chain(patterns.iter().map(String::as_str)) {
+            for (id, member) in rooms.get(name).into_iter().flatten() {
+                subscribers.entry(*id).or_insert_with(|| member.clone());
+            }
+        }
+        subscribers.into_values().collect()
+    }
+
+    /// パターンのroomなら、参加者か購読者がいる間trieに入れておく
+    fn add_pattern(&self, room: &str) {
+        if topic::is_pattern(room) {
+            self.patterns.lock().unwrap().insert(room);
+        }
+    }
+
+    fn remove_pattern(&self, room: &str) {
+        if topic::is_pattern(room) {
+            self.patterns.lock().unwrap().remove(room);
+        }
+    }
+
+    /// `retain` で参加者のいなくなったroomを消すときに、trieからも取り除く
+    fn retain_room(&self, room: &str, members: &HashMap<ConnectionId, Member>) -> bool {
+        if members.is_empty() {
+            self.remove_pattern(room);
+        }
+        !members.is_empty()
+    }
+
+    /// roomの参加者全員にメッセージを送る。戻り値はローカルで配送できた接続数
+    pub fn publish(&self, room: &str, message: Message) -> usize {
+        if let Some(backend) = &self.backend {
+            backend.publish(room, &message);
+        }
+        self.deliver(room, &message)
+    }
+
+    /// 複数のメッセージをまとめて送る。接続ごとにフレームを溜めて1回で書き込むので、
+    /// メッセージごとに `publish` するよりシステムコールが少ない。戻り値は全て配送できた接続数
+    pub fn publish_batch(&self, room: &str, messages: &[Message]) -> usize {
+        if let Some(backend) = &self.backend {
+            for message in messages {
+                backend.publish(room, message);
+            }
+        }
+        self.deliver_batch(room, messages)
+    }
+
+    /// ローカルの接続にだけ配送する
+    pub(crate) fn deliver(&self, room: &str, message: &Message) -> usize {
+        self.deliver_batch(room, std::slice::from_ref(message))
+    }
+
+    fn deliver_batch(&self, room: &str, messages: &[Message]) -> usize {
+        let stats = self.room_stats.get(room);
+        for message in messages {
+            stats.record_in(message.len(), true);
+            self.enqueue(room, message);
+        }
+        // フレームは一度だけエンコードし、全ての接続で同じバイト列を使う
+        let prepared = messages
+            .iter()
+            .cloned()
+            .map(PreparedMessage::new)
+            .collect::<Vec<_>>();
+        let members = self.subscribers(room);
+        if members.is_empty() {
+            return 0;
+        }
+        // 毎回同じ参加者が最後にならないよう、送り始める位置をずらす
+        let start = self.fanout_offset.fetch_add(1, Ordering::Relaxed) % members.len();
+        let batch = self.fanout_batch.load(Ordering::Relaxed);
+        let turn = (batch != 0 && members.len() > batch).then(|| self.fanout_turns.enter());
+        members
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(members.len())
+            .enumerate()
+            .filter(|(i, member)| {
+                // 大きなroomへの配送が他のroomへの配送を待たせないよう、区切りごとに列の最後に並び直す
+                if let Some(turn) = &turn {
+                    if *i != 0 && i % batch == 0 {
+                        turn.yield_turn();
+                    }
+                }
+                let sent = member.handle.send_all(&prepared).is_ok();
+                if sent {
+                    for message in messages {
+                        stats.record_out(message.len(), true);
+                    }
+                }
+                sent
+            })
+            .count()
+    }
+
+    /// 履歴・切断中のセッション・`watch` の購読者にメッセージを渡す
+    fn enqueue(&self, room: &str, message: &Message) {
+        self.record(room, message);
+        for parked in self.parked.lock().unwrap().values_mut() {
+            if parked
+                .rooms
+                .iter()
+                .any(|(name, _)| topic::matches(name, room))
+            {
+                if parked.queue.len() >= MAX_PARKED_MESSAGES {
+                    parked.queue.pop_front();
+                }
+                parked.queue.push_back(message.clone());
+            }
+        }
+        let mut watchers = self.watchers.lock().unwrap();
+        let patterns = self.patterns.lock().unwrap().matches(room);
+        for name in std::iter::once(room).chain(patterns.iter().map(String::as_str)) {
+            if let Some(senders) = watchers.get_mut(name) {
+                // 受信側が捨てられたものはここで取り除く
+                senders.retain_mut(|watcher| watcher(message));
+                if senders.is_empty() {
+                    watchers.remove(name);
+                    self.remove_pattern(name);
+                }
+            }
+        }
+    }
+
+    /// roomに配送されるメッセージをチャネルで受け取る。Receiverを捨てれば購読をやめる。
+    /// presenceの通知は届かず、参加者の数にも含まれない
+    pub fn watch(&self, room: &str) -> Receiver<Message> {
+        let (sender, receiver) = mpsc::channel();
+        self.watch_with(room, move |message| sender.send(message.clone()).is_ok());
+        receiver
+    }
+
+    /// roomに配送されるメッセージを、asyncのtaskでtokioのbroadcastから受け取る。
+    /// 他のtaskへは `Receiver::resubscribe` で配る。全てのReceiverを捨てれば購読をやめる。
+    /// 受信が1024件より遅れたReceiverは `RecvError::Lagged` を受け取り、古いメッセージは届かない
+    #[cfg(feature = "tokio")]
+    pub fn subscribe(&self, room: &str) -> tokio::sync::broadcast::Receiver<Message> {
+        let (sender, receiver) = tokio::sync::broadcast::channel(SUBSCRIBE_CAPACITY);
+        // sendは受信者がいなければ失敗する。配送中に呼ばれるがブロックしない
+        self.watch_with(room, move |message| sender.send(message.clone()).is_ok());
+        receiver
+    }
+
+    /// roomに配送されるメッセージごとに `watcher` を呼ぶ。false を返したら購読をやめる。
+    /// std・tokio以外のチャネルにroomのメッセージを流す場合に使う。
+    /// 配送中に呼ばれるので、ブロックしないこと
+    pub fn watch_with<F>(&self, room: &str, watcher: F)
+    where
+        F: FnMut(&Message) -> bool + Send + 'static,
+    {
+        let mut watchers = self.watchers.lock().unwrap();
+        if !watchers.contains_key(room) {
+            self.add_pattern(room);
+        }
+        watchers
+            .entry(room.to_string())
+            .or_default()
+            .push(Box::new(watcher));
+    }
+
+    fn record(&self, room: &str, message: &Message) {
+        if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+            if let Err(e) = journal.append(room, message) {
+                warning!("journal_error", { error: e.to_string() }, "journal: {}", e);
+            }
+        }
+        self.record_history(room, message);
+    }
+
+    fn record_history(&self, room: &str, message: &Message) {
+        let len = self.history_len.load(Ordering::Relaxed);
+        if len == 0 {
+            return;
+        }
+
+        let mut history = self.history.lock().unwrap();
+        let messages = history.entry(room.to_string()).or_default();
+        if messages.len() >= len {
+            messages.pop_front();
+        }
+        messages.push_back(message.clone());
+    }
+
+    /// room名と参加している接続数の一覧 (接続数の多い順)
+    pub fn rooms(&self) -> Vec<(String, usize)> {
+        let mut rooms = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect::<Vec<_>>();
+        rooms.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        rooms
+    }
+
+    /// 接続ごとの参加しているroom (名前の順)
+    pub fn memberships(&self) -> HashMap<ConnectionId, Vec<String>> {
+        let mut memberships: HashMap<ConnectionId, Vec<String>> = HashMap::new();
+        for (room, members) in self.rooms.lock().unwrap().iter() {
+            for id in members.keys() {
+                memberships.entry(*id).or_default().push(room.clone());
+            }
+        }
+        for rooms in memberships.values_mut() {
+            rooms.sort();
+        }
+        memberships
+    }
+
+    /// roomごとの参加者の数と、publishされた (in)・参加者に配送した (out) メッセージ数とバイト数の累計。
+    /// 誰も参加していないroomも、一度でもpublishされていれば含む
+    pub fn room_stats(&self) -> Vec<LabeledStats> {
+        let rooms = self.rooms.lock().unwrap();
+        self.room_stats
+            .snapshots()
+            .into_iter()
+            .map(|(room, totals)| LabeledStats {
+                connections: rooms.get(&room).map_or(0, HashMap::len),
+                label: room,
+                totals,
+            })
+            .collect()
+    }
+}
+
+impl Member {
+    fn presence(&self) -> Presence {
+        Presence {
+            id: self.handle.id(),
+            user: self.user.clone(),
+        }
+    }
+}
+
+/// 配送中のbroadcastが順番に参加者へ送るための列。先頭のbroadcastだけが次の区切りまで送る
+#[derive(Default)]
+struct Turns {
+    queue: Mutex<VecDeque<u64>>,
+    changed: Condvar,
+    next: AtomicU64,
+}
+
+impl Turns {
+    /// 列の最後に並び、順番が来るまで待つ
+    fn enter(&self) -> Turn<'_> {
+        let turn = Turn {
+            turns: self,
+            ticket: self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        self.queue.lock().unwrap().push_back(turn.ticket);
+        turn.wait();
+        turn
+    }
+}
+
+/// `Turns` に並んでいるbroadcast。dropすると列から抜ける
+struct Turn<'a> {
+    turns: &'a Turns,
+    ticket: u64,
+}
+
+impl Turn<'_> {
+    /// 列の最後に並び直し、順番が来るまで待つ
+    fn yield_turn(&self) {
+        let mut queue = self.turns.queue.lock().unwrap();
+        queue.retain(|ticket| *ticket != self.ticket);
+        queue.push_back(self.ticket);
+        self.turns.changed.notify_all();
+        drop(queue);
+        self.wait();
+    }
+
+    /// 先頭になるまで待つ。`FANOUT_TURN_TIMEOUT` を過ぎたら順番を待たずに進める
+    fn wait(&self) {
+        let queue = self.turns.queue.lock().unwrap();
+        let _ = self
+            .turns
+            .changed
+            .wait_timeout_while(queue, FANOUT_TURN_TIMEOUT, |queue| {
+                queue.front() != Some(&self.ticket)
+            })
+            .unwrap();
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut queue = self.turns.queue.lock().unwrap();
+        queue.retain(|ticket| *ticket != self.ticket);
+        self.turns.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        handler::Handler,
+        server::Server,
+        testing::{self, TestServer},
+    };
+
+    /// pathのroomに参加し、受信したメッセージをそのroomにpublishする
+    struct Rooms;
+
+    impl Handler for Rooms {
+        fn on_open(&self, conn: &mut Connection) {
+            let room = conn.path()[1..].to_string();
+            if let Err(e) = conn.join(&room) {
+                let _ = conn.close(1008, &e.to_string());
+            }
+        }
+
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let room = conn.path()[1..].to_string();
+            if let Err(e) = conn.publish(&room, message) {
+                let _ = conn.send(Message::Text(e.to_string()));
+            }
+        }
+    }
+
+    struct Policy;
+
+    impl Authorizer for Policy {
+        fn can_join(&self, _conn: &Connection, room: &str) -> bool {
+            !room.starts_with("private.")
+        }
+
+        fn can_publish(&self, _conn: &Connection, room: &str, _message: &Message) -> bool {
+            !room.starts_with("news.")
+        }
+    }
+
+    fn spawn() -> TestServer {
+        testing::spawn_server(Server::bind("127.0.0.1:0", Rooms).unwrap()).unwrap()
+    }
+
+    fn text(s: &str) -> Message {
+        Message::Text(s.to_string())
+    }
+
+    #[test]
+    fn authorizer_denies_join() {
+        let server = spawn();
+        server.hub().set_authorizer(Policy);
+
+        let mut client = server.connect("/private.a").unwrap();
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close().unwrap().0, 1008);
+        assert!(server.hub().rooms().is_empty());
+    }
+
+    #[test]
+    fn authorizer_denies_publish() {
+        let server = spawn();
+        server.hub().set_authorizer(Policy);
+
+        let mut publisher = server.connect("/news.a").unwrap();
+        let mut subscriber = server.connect("/news.a").unwrap();
+        while server.hub().rooms() != vec![("news.a".to_string(), 2)] {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        publisher.send(text("hello")).unwrap();
+        assert_eq!(
+            publisher.recv().unwrap(),
+            Some(text("policy violation: not allowed to publish to news.a"))
+        );
+        // サーバーが直接publishしたメッセージは許可を確かめない。拒否されたメッセージより先に届いていないこと
+        assert_eq!(server.hub().publish("news.a", text("direct")), 2);
+        assert_eq!(subscriber.recv().unwrap(), Some(text("direct")));
+    }
+
+    #[test]
+    fn allows_everything_without_authorizer() {
+        let server = spawn();
+
+        let mut publisher = server.connect("/private.a").unwrap();
+        let mut subscriber = server.connect("/private.a").unwrap();
+        while server.hub().rooms() != vec![("private.a".to_string(), 2)] {
+            thread::sleep(Duration::from_millis(10));
+        }
+        publisher.send(text("hello")).unwrap();
+        assert_eq!(subscriber.recv().unwrap(), Some(text("hello")));
+    }
+
+    fn queue(turns: &Turns) -> Vec<u64> {
+        turns.queue.lock().unwrap().iter().copied().collect()
+    }
+
+    #[test]
+    fn broadcasts_take_turns() {
+        let turns = Turns::default();
+        let first = turns.enter();
+        // 先頭が区切りで譲らないので、待ちきれずに進む
+        let second = turns.enter();
+        assert_eq!(queue(&turns), vec![first.ticket, second.ticket]);
+        first.yield_turn();
+        assert_eq!(queue(&turns), vec![second.ticket, first.ticket]);
+        drop(second);
+        assert_eq!(queue(&turns), vec![first.ticket]);
+        drop(first);
+        assert!(queue(&turns).is_empty());
+    }
+
+    #[test]
+    fn waiting_broadcast_runs_when_the_turn_is_yielded() {
+        let turns = Arc::new(Turns::default());
+        let first = turns.enter();
+        let started = Instant::now();
+        let waiter = thread::spawn({
+            let turns = turns.clone();
+            move || {
+                let turn = turns.enter();
+                turn.ticket
+            }
+        });
+        while queue(&turns).len() < 2 {
+            thread::yield_now();
+        }
+        drop(first);
+        let ticket = waiter.join().unwrap();
+        assert_eq!(ticket, 1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(queue(&turns).is_empty());
+    }
+
+    #[test]
+    fn publishes_to_large_rooms_in_batches() {
+        let server = spawn();
+        server.hub().set_fanout_batch(2);
+        let mut clients = (0..5)
+            .map(|_| server.connect("/big").unwrap())
+            .collect::<Vec<_>>();
+        while server.hub().rooms() != vec![("big".to_string(), 5)] {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.hub().publish("big", text("hello")), 5);
+        for client in &mut clients {
+            assert_eq!(client.recv().unwrap(), Some(text("hello")));
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn subscribes_from_async_tasks() {
+        use tokio::sync::broadcast::error::RecvError;
+
+        let hub = Hub::new();
+        let mut lobby = hub.subscribe("lobby");
+        let mut news = hub.subscribe("news.*");
+        let mut other = lobby.resubscribe();
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                hub.publish("lobby", Message::Text("hello".to_string()));
+                hub.publish("news.sports", Message::Text("goal".to_string()));
+                assert_eq!(lobby.recv().await, Ok(Message::Text("hello".to_string())));
+                assert_eq!(other.recv().await, Ok(Message::Text("hello".to_string())));
+                assert_eq!(news.recv().await, Ok(Message::Text("goal".to_string())));
+
+                // 遅れた受信者は飛ばした件数を受け取る
+                for i in 0..SUBSCRIBE_CAPACITY + 1 {
+                    hub.publish("lobby", Message::Text(i.to_string()));
+                }
+                assert_eq!(lobby.recv().await, Err(RecvError::Lagged(1)));
+            });
+
+        // 全てのReceiverを捨てたら、次の配送で購読をやめる
+        drop((lobby, other));
+        hub.publish("lobby", Message::Text("bye".to_string()));
+        assert!(!hub.watchers.lock().unwrap().contains_key("lobby"));
+        assert!(hub.watchers.lock().unwrap().contains_key("news.*"));
+    }
+}
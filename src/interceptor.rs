@@ -0,0 +1,83 @@
+// メッセージのmiddleware
+//
+// `Server::with_interceptor(prefix, interceptor)` で登録したInterceptorは、pathが `prefix` で始まる接続の
+// 受信したメッセージ (Handlerの on_message の前) と送信するメッセージ (Connection/ConnectionHandle の send) に、
+// 登録した順に適用される。各Interceptorはメッセージを見るだけでも、書き換えても、None を返して捨ててもよい。
+// Pingなどの制御フレームと `send_frame` で直接送るフレームは対象外
+
+use std::sync::Arc;
+
+use crate::{connection::ConnectionId, message::Message, record::Direction};
+
+pub trait Interceptor: Send + Sync + 'static {
+    /// None を返すとメッセージを捨てる (以降のInterceptorにも渡らない)
+    fn intercept(
+        &self,
+        id: ConnectionId,
+        direction: Direction,
+        message: Message,
+    ) -> Option<Message>;
+}
+
+/// `Fn(ConnectionId, Direction, Message) -> Option<Message>` のclosureもInterceptorとして使える
+impl<F> Interceptor for F
+where
+    F: Fn(ConnectionId, Direction, Message) -> Option<Message> + Send + Sync + 'static,
+{
+    fn intercept(
+        &self,
+        id: ConnectionId,
+        direction: Direction,
+        message: Message,
+    ) -> Option<Message> {
+        self(id, direction, message)
+    }
+}
+
+/// 1つの接続に適用するInterceptorの列
+pub(crate) type Pipeline = Arc<[Arc<dyn Interceptor>]>;
+
+pub(crate) fn apply(
+    pipeline: &Pipeline,
+    id: ConnectionId,
+    direction: Direction,
+    message: Message,
+) -> Option<Message> {
+    pipeline.iter().try_fold(message, |message, interceptor| {
+        interceptor.intercept(id, direction, message)
+    })
+}
+
+/// Textメッセージに含まれる単語を `*` に置き換える
+pub struct Censor {
+    words: Vec<String>,
+}
+
+impl Censor {
+    pub fn new<S: Into<String>>(words: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            words: words
+                .into_iter()
+                .map(Into::into)
+                .filter(|word: &String| !word.is_empty())
+                .collect(),
+        }
+    }
+}
+
+impl Interceptor for Censor {
+    fn intercept(
+        &self,
+        _id: ConnectionId,
+        _direction: Direction,
+        message: Message,
+    ) -> Option<Message> {
+        let Message::Text(mut text) = message else {
+            return Some(message);
+        };
+        for word in &self.words {
+            text = text.replace(word.as_str(), &"*".repeat(word.chars().count()));
+        }
+        Some(Message::Text(text))
+    }
+}
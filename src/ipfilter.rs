@@ -0,0 +1,277 @@
+// IPアドレスによる接続の制限
+//
+// `Config::ip_filter` はTCP接続を受け付けた直後 (handshakeのリクエストを読む前) に評価し、
+// 許可されないアドレスからの接続は何も返さずに切断する。
+// 特定のpathだけを制限する場合は `IpFilter::for_path` でhandshakeのmiddlewareにする (403で拒否する)
+//
+// 判定の順序:
+// 1. denyのどれかに一致すれば拒否
+// 2. allowが空でなく、どれにも一致しなければ拒否
+// 3. それ以外は許可
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::middleware::{Handshake, Layer, Next, Outcome, Rejection};
+
+/// `192.168.0.0/16` や `::1/128` のようなアドレスの範囲。`/` を省略すると1つのアドレス
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(u128::from(net), u128::from(ip), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+
+    /// 範囲の最初と最後のアドレス。IPv4はIPv4射影アドレス (::ffff:0:0/96) の中の値にする
+    pub(crate) fn range(&self) -> (u128, u128) {
+        let prefix_len = match self.addr {
+            IpAddr::V4(_) => self.prefix_len + 96,
+            IpAddr::V6(_) => self.prefix_len,
+        };
+        let start = to_u128(self.addr);
+        let host_bits = 128 - prefix_len;
+        let host_mask = if host_bits == 128 {
+            u128::MAX
+        } else {
+            (1u128 << host_bits) - 1
+        };
+        (start & !host_mask, start | host_mask)
+    }
+}
+
+/// IPv4はIPv4射影アドレスとして128bitの値にする
+pub(crate) fn to_u128(ip: IpAddr) -> u128 {
+    match canonical(ip) {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+/// 上位 `prefix_len` bitが一致するか
+fn mask(net: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    let shift = bits - prefix_len;
+    shift == bits || net >> shift == ip >> shift
+}
+
+/// IPv4射影アドレス (::ffff:192.0.2.1) はIPv4として扱う
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseCidrError(String);
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid CIDR: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseCidrError {}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseCidrError(s.to_string());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| error())?)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| error())?;
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = prefix_len.unwrap_or(bits);
+        if prefix_len > bits {
+            return Err(error());
+        }
+        // ::ffff:10.0.0.0/104 は 10.0.0.0/8 と同じ。
+        // /96 より短いとIPv4射影アドレス以外も含むので、IPv4の範囲にできない
+        let (addr, prefix_len) = match canonical(addr) {
+            IpAddr::V4(_) if addr.is_ipv6() && prefix_len < 96 => return Err(error()),
+            IpAddr::V4(v4) if addr.is_ipv6() => (IpAddr::V4(v4), prefix_len - 96),
+            addr => (addr, prefix_len),
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 許可する範囲を追加する。1つでも追加すると、それ以外は拒否される
+    pub fn allow(mut self, cidr: Cidr) -> Self {
+        self.allow.push(cidr);
+        self
+    }
+
+    /// 拒否する範囲を追加する。allowより優先される
+    pub fn deny(mut self, cidr: Cidr) -> Self {
+        self.deny.push(cidr);
+        self
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// pathが `prefix` で始まるhandshakeだけを制限するmiddlewareにする
+    pub fn for_path(self, prefix: &str) -> PathIpFilter {
+        PathIpFilter {
+            prefix: prefix.to_string(),
+            filter: self,
+        }
+    }
+}
+
+/// 特定のpathへのhandshakeをIPアドレスで制限するmiddleware。許可されなければ 403 で拒否する
+pub struct PathIpFilter {
+    prefix: String,
+    filter: IpFilter,
+}
+
+impl Layer for PathIpFilter {
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome {
+        if handshake.request.path.starts_with(self.prefix.as_str())
+            && !self.filter.permits(handshake.peer_addr.ip())
+        {
+            return Err(Rejection::new(403, "Forbidden"));
+        }
+        next.run(handshake)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        for (input, display) in [
+            ("192.168.0.0/16", "192.168.0.0/16"),
+            ("10.1.2.3", "10.1.2.3/32"),
+            ("0.0.0.0/0", "0.0.0.0/0"),
+            ("2001:db8::/32", "2001:db8::/32"),
+            ("::1", "::1/128"),
+            ("::/0", "::/0"),
+            // IPv4射影アドレスはIPv4の範囲にする
+            ("::ffff:10.0.0.0/104", "10.0.0.0/8"),
+            ("::ffff:192.0.2.1", "192.0.2.1/32"),
+            ("::ffff:0.0.0.0/96", "0.0.0.0/0"),
+        ] {
+            assert_eq!(cidr(input).to_string(), display, "{}", input);
+        }
+        for input in [
+            "192.168.0.0/33",
+            "2001:db8::/129",
+            "192.168.0.0/",
+            "192.168.0.0/-1",
+            "example.com/8",
+            "",
+            // /96 より短い範囲はIPv4射影アドレス以外も含むので、/0 などにせずに拒否する
+            "::ffff:10.0.0.0/95",
+            "::ffff:10.0.0.0/8",
+            "::ffff:0.0.0.0/0",
+        ] {
+            assert!(input.parse::<Cidr>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn matches_addresses() {
+        let private = cidr("192.168.0.0/16");
+        assert!(private.contains(ip("192.168.0.1")));
+        assert!(private.contains(ip("192.168.255.255")));
+        assert!(!private.contains(ip("192.169.0.1")));
+        // IPv4射影アドレスで届いた接続もIPv4として比べる
+        assert!(private.contains(ip("::ffff:192.168.1.1")));
+        assert!(!private.contains(ip("2001:db8::1")));
+
+        let doc = cidr("2001:db8::/32");
+        assert!(doc.contains(ip("2001:db8:ffff::1")));
+        assert!(!doc.contains(ip("2001:db9::1")));
+        assert!(!doc.contains(ip("192.168.0.1")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("::1")));
+        assert!(cidr("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3").contains(ip("10.1.2.4")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.9.8.7")));
+    }
+
+    #[test]
+    fn computes_ranges() {
+        assert_eq!(
+            cidr("10.0.0.0/8").range(),
+            (to_u128(ip("10.0.0.0")), to_u128(ip("10.255.255.255")))
+        );
+        assert_eq!(cidr("::/0").range(), (0, u128::MAX));
+        assert_eq!(
+            cidr("2001:db8::1").range(),
+            (to_u128(ip("2001:db8::1")), to_u128(ip("2001:db8::1")))
+        );
+    }
+
+    #[test]
+    fn denies_before_allowing() {
+        let filter = IpFilter::new()
+            .allow(cidr("10.0.0.0/8"))
+            .allow(cidr("2001:db8::/32"))
+            .deny(cidr("10.0.0.0/24"));
+        assert!(filter.permits(ip("10.1.0.1")));
+        assert!(filter.permits(ip("2001:db8::1")));
+        assert!(!filter.permits(ip("10.0.0.1")));
+        assert!(!filter.permits(ip("::ffff:10.0.0.1")));
+        assert!(!filter.permits(ip("192.168.0.1")));
+
+        let filter = IpFilter::new().deny(cidr("192.168.0.0/16"));
+        assert!(filter.permits(ip("10.0.0.1")));
+        assert!(!filter.permits(ip("192.168.0.1")));
+        assert!(IpFilter::new().permits(ip("::1")));
+    }
+}
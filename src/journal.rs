@@ -0,0 +1,213 @@
+// roomに配送したメッセージをディスクに追記していくログ
+//
+// ディレクトリの中に `<連番>.wsj` のセグメントを作り、一定のサイズを超えたら次のセグメントに切り替える。
+// セグメントの数が上限を超えたら古いものから削除する
+//
+// セグメントの形式:
+// "WSJNL1\n"
+// 以降、メッセージごとに:
+// 時刻 (UNIX時間のマイクロ秒, u64 BE) | room名の長さ (u16 BE) | room名 | 種類 (1: Text / 2: Binary) |
+// payloadの長さ (u32 BE) | payload
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+const MAGIC: &[u8] = b"WSJNL1\n";
+const EXTENSION: &str = "wsj";
+
+#[derive(Clone, Debug)]
+pub struct JournalConfig {
+    pub dir: PathBuf,
+    /// 1セグメントの最大バイト数
+    pub segment_size: u64,
+    /// 保持するセグメントの数。超えたら古いものから削除する
+    pub max_segments: usize,
+}
+
+impl JournalConfig {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            segment_size: 16 * 1024 * 1024,
+            max_segments: 8,
+        }
+    }
+}
+
+/// ログに記録されたメッセージ
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub room: String,
+    pub message: Message,
+}
+
+pub struct Journal {
+    config: JournalConfig,
+    writer: BufWriter<File>,
+    /// 書き込み中のセグメントのバイト数
+    written: u64,
+    /// 既存のセグメント (古い順)。最後が書き込み中のもの
+    segments: VecDeque<(u64, PathBuf)>,
+}
+
+impl Journal {
+    /// ディレクトリを開き、新しいセグメントに書き込み始める。既存のセグメントは残す
+    pub fn open(config: JournalConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let segments = segments(&config.dir)?;
+        let next = segments.back().map_or(0, |(number, _)| number + 1);
+        let (writer, path) = create(&config.dir, next)?;
+
+        let mut journal = Self {
+            config,
+            writer,
+            written: MAGIC.len() as u64,
+            segments,
+        };
+        journal.segments.push_back((next, path));
+        journal.remove_old()?;
+        Ok(journal)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.config.dir
+    }
+
+    pub fn append(&mut self, room: &str, message: &Message) -> Result<()> {
+        if self.written >= self.config.segment_size {
+            self.rotate()?;
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let kind: u8 = match message {
+            Message::Text(_) => 1,
+            Message::Binary(_) => 2,
+        };
+        let payload = message.as_bytes();
+
+        let mut bytes = time.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(room.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(room.as_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+
+        self.writer.write_all(&bytes)?;
+        self.writer.flush()?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let next = self.segments.back().map_or(0, |(number, _)| number + 1);
+        let (writer, path) = create(&self.config.dir, next)?;
+        self.writer = writer;
+        self.written = MAGIC.len() as u64;
+        self.segments.push_back((next, path));
+        self.remove_old()
+    }
+
+    fn remove_old(&mut self) -> Result<()> {
+        while self.segments.len() > self.config.max_segments.max(1) {
+            if let Some((_, path)) = self.segments.pop_front() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn create(dir: &Path, number: u64) -> Result<(BufWriter<File>, PathBuf)> {
+    let path = dir.join(format!("{:010}.{}", number, EXTENSION));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    writer.write_all(MAGIC)?;
+    writer.flush()?;
+    Ok((writer, path))
+}
+
+/// ディレクトリ内のセグメントを番号順に並べる
+fn segments(dir: &Path) -> Result<VecDeque<(u64, PathBuf)>> {
+    let mut segments = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != EXTENSION {
+                return None;
+            }
+            let number = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((number, path))
+        })
+        .collect::<Vec<_>>();
+    segments.sort();
+    Ok(segments.into())
+}
+
+/// ディレクトリ内の全てのセグメントを古い順に読み込む。
+/// 書き込み途中で終わっているメッセージは読み飛ばす
+pub fn read<P: AsRef<Path>>(dir: P) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (_, path) in segments(dir.as_ref())? {
+        read_segment(&path, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+fn read_segment(path: &Path, entries: &mut Vec<Entry>) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::Protocol(format!(
+            "not a journal segment: {}",
+            path.display()
+        )));
+    }
+
+    loop {
+        match read_entry(&mut reader) {
+            Ok(entry) => entries.push(entry),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<Entry> {
+    let mut time = [0; 8];
+    reader.read_exact(&mut time)?;
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+    let mut room = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut room)?;
+    let mut kind = [0; 1];
+    reader.read_exact(&mut kind)?;
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut payload)?;
+
+    let message = match kind[0] {
+        1 => Message::Text(String::from_utf8(payload).map_err(|_| Error::InvalidUtf8)?),
+        2 => Message::Binary(payload),
+        kind => return Err(Error::Protocol(format!("unknown message kind: {}", kind))),
+    };
+    Ok(Entry {
+        time: UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(time)),
+        room: String::from_utf8_lossy(&room).into_owned(),
+        message,
+    })
+}
@@ -0,0 +1,499 @@
+// 外部crateに頼らずにJSONを組み立てるための最小限のヘルパー
+
+/// ダブルクォートで囲んだJSON文字列にする
+pub(crate) fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `[a, b, ...]` を要素ごとのJSONの文字列に分ける (要素の中身はパースしない)
+pub(crate) fn split_array(s: &str) -> Option<Vec<&str>> {
+    split(s.trim().strip_prefix('[')?.strip_suffix(']')?)
+}
+
+/// `{"key": value, ...}` をキーと値のJSONの文字列に分ける (値の中身はパースしない)
+pub(crate) fn split_object(s: &str) -> Option<Vec<(String, &str)>> {
+    split(s.trim().strip_prefix('{')?.strip_suffix('}')?)?
+        .into_iter()
+        .map(|member| {
+            let end = string_end(member)?;
+            let value = member[end..].trim_start().strip_prefix(':')?.trim();
+            Some((parse_string(&member[..end])?, value))
+        })
+        .collect()
+}
+
+/// objectのキーの値 (JSONの文字列のまま)。`null` は None として扱う
+pub(crate) fn field<'a>(object: &[(String, &'a str)], key: &str) -> Option<&'a str> {
+    object
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| *value)
+        .filter(|value| *value != "null")
+}
+
+/// 先頭のJSON文字列の直後の位置
+fn string_end(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// 括弧の内側をトップレベルの `,` で分ける
+fn split(inner: &str) -> Option<Vec<&str>> {
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in inner.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(inner[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if in_string || depth != 0 {
+        return None;
+    }
+
+    let last = inner[start..].trim();
+    if !last.is_empty() {
+        items.push(last);
+    } else if !items.is_empty() {
+        return None;
+    }
+    Some(items)
+}
+
+/// JSON文字列 (`"..."`) をデコードする
+pub(crate) fn parse_string(s: &str) -> Option<String> {
+    let inner = s.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '"' => out.push('"'),
+            '\\' => out.push('\\'),
+            '/' => out.push('/'),
+            'b' => out.push('\u{8}'),
+            'f' => out.push('\u{c}'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            'u' => {
+                let hex = chars.by_ref().take(4).collect::<String>();
+                let mut code = u32::from_str_radix(&hex, 16).ok()?;
+                // サロゲートペア
+                if (0xd800..0xdc00).contains(&code) {
+                    let rest = chars.by_ref().take(6).collect::<String>();
+                    let low = u32::from_str_radix(rest.strip_prefix("\\u")?, 16).ok()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return None;
+                    }
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                }
+                out.push(char::from_u32(code)?);
+            }
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// パースしたJSONの値
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// キーは書かれた順
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// JSONの型の名前 (JSON Schemaの `type` と同じ。整数も "number")
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        }
+    }
+}
+
+/// `parse` が受け付ける配列・objectの入れ子の深さ。
+/// 受信したメッセージをパースするので、深く入れ子にしたJSONでスタックを使い切らないようにする
+pub(crate) const MAX_DEPTH: usize = 128;
+
+/// JSONをパースする。JSONとして正しくない、または `MAX_DEPTH` より深く入れ子になっていれば None。
+/// 先頭から1回読むだけなので、入力の長さに比例した時間で終わる
+pub(crate) fn parse(s: &str) -> Option<Value> {
+    let mut parser = Parser {
+        bytes: s.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    (parser.pos == parser.bytes.len()).then_some(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// `depth` はこの値を囲んでいる配列・objectの数
+    fn value(&mut self, depth: usize) -> Option<Value> {
+        self.skip_whitespace();
+        match self.peek()? {
+            b'{' => self.object(depth + 1),
+            b'[' => self.array(depth + 1),
+            b'"' => self.string().map(Value::String),
+            b't' => self.literal("true", Value::Bool(true)),
+            b'f' => self.literal("false", Value::Bool(false)),
+            b'n' => self.literal("null", Value::Null),
+            b'-' | b'0'..=b'9' => self.number().map(Value::Number),
+            _ => None,
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Option<Value> {
+        let end = self.pos + word.len();
+        (self.bytes.get(self.pos..end)? == word.as_bytes()).then(|| {
+            self.pos = end;
+            value
+        })
+    }
+
+    fn array(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.pos += 1;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek()? == b']' {
+            self.pos += 1;
+            return Some(Value::Array(items));
+        }
+        loop {
+            items.push(self.value(depth)?);
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b']' => return Some(Value::Array(items)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.pos += 1;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.peek()? == b'}' {
+            self.pos += 1;
+            return Some(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek()? != b'"' {
+                return None;
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if self.next()? != b':' {
+                return None;
+            }
+            members.push((key, self.value(depth)?));
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b'}' => return Some(Value::Object(members)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return None,
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                // 制御文字はエスケープしなければならない
+                0..=0x1f => return None,
+                byte => out.push(byte),
+            }
+        }
+    }
+
+    /// `\u` の後の4桁 (サロゲートペアなら続く `\uXXXX` も読む)
+    fn unicode_escape(&mut self) -> Option<char> {
+        let high = self.hex4()?;
+        if !(0xd800..0xe000).contains(&high) {
+            return char::from_u32(high);
+        }
+        if high >= 0xdc00 || self.next()? != b'\\' || self.next()? != b'u' {
+            return None;
+        }
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.bytes.get(self.pos..self.pos + 4)?;
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        self.pos += 4;
+        u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+    }
+
+    /// `-? (0 | [1-9][0-9]*) (. [0-9]+)? ([eE] [+-]? [0-9]+)?`
+    fn number(&mut self) -> Option<f64> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.next()? {
+            b'0' => {}
+            b'1'..=b'9' => self.digits(),
+            _ => return None,
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            self.first_digit()?;
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            self.first_digit()?;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// 1桁以上の数字
+    fn first_digit(&mut self) -> Option<()> {
+        self.next().filter(u8::is_ascii_digit)?;
+        self.digits();
+        Some(())
+    }
+
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        let value = parse(r#" {"a": [1, -2.5e3, true, null], "b": {"c": "d"}, "e": ""} "#).unwrap();
+        assert_eq!(
+            value,
+            Value::Object(vec![
+                (
+                    "a".to_string(),
+                    Value::Array(vec![
+                        Value::Number(1.0),
+                        Value::Number(-2500.0),
+                        Value::Bool(true),
+                        Value::Null,
+                    ])
+                ),
+                (
+                    "b".to_string(),
+                    Value::Object(vec![("c".to_string(), Value::String("d".to_string()))])
+                ),
+                ("e".to_string(), Value::String(String::new())),
+            ])
+        );
+        assert_eq!(parse("[]"), Some(Value::Array(vec![])));
+        assert_eq!(parse("{ }"), Some(Value::Object(vec![])));
+        assert_eq!(parse("0"), Some(Value::Number(0.0)));
+        assert_eq!(parse("-0.5E-1"), Some(Value::Number(-0.05)));
+    }
+
+    #[test]
+    fn decodes_string_escapes() {
+        assert_eq!(
+            parse(r#""a\"\\\/\b\f\n\r\t\u00e9\ud83d\ude00あ""#),
+            Some(Value::String("a\"\\/\u{8}\u{c}\n\r\té😀あ".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for input in [
+            "",
+            " ",
+            "[1,]",
+            "[,1]",
+            "[1 2]",
+            "[1]]",
+            "[[1]",
+            "{\"a\":}",
+            "{\"a\" 1}",
+            "{\"a\":1,}",
+            "{a:1}",
+            "{1:2}",
+            "01",
+            "1.",
+            ".5",
+            "+1",
+            "1e",
+            "-",
+            "inf",
+            "NaN",
+            "tru",
+            "nul",
+            "true false",
+            "\"abc",
+            "\"\\x\"",
+            "\"\\u12\"",
+            "\"\\u+123\"",
+            "\"\\ud800\"",
+            "\"\\udc00\\ud800\"",
+            "\"\\ud800\\u0041\"",
+            "\"tab\there\"",
+            "\"a\"b\"",
+        ] {
+            assert_eq!(parse(input), None, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(parse(&nested(MAX_DEPTH + 1)), None);
+        // スタックを使い切らずに失敗する
+        assert_eq!(parse(&nested(100_000)), None);
+        assert_eq!(parse(&"{\"a\":".repeat(100_000)), None);
+    }
+
+    #[test]
+    fn parses_long_input_in_one_pass() {
+        let items = vec!["[1,2,{\"k\":\"v\"}]"; 100_000].join(",");
+        let Some(Value::Array(items)) = parse(&format!("[{}]", items)) else {
+            panic!("failed to parse");
+        };
+        assert_eq!(items.len(), 100_000);
+    }
+
+    #[test]
+    fn splits_top_level_members() {
+        let members = split_object(r#"{"a": [1, ","], "b": {"c": 2}}"#).unwrap();
+        assert_eq!(
+            members,
+            vec![
+                ("a".to_string(), r#"[1, ","]"#),
+                ("b".to_string(), r#"{"c": 2}"#)
+            ]
+        );
+        assert_eq!(
+            split_array("[1, [2, 3], \"x,y\"]").unwrap(),
+            vec!["1", "[2, 3]", "\"x,y\""]
+        );
+        assert_eq!(split_array("[1,]"), None);
+        assert_eq!(field(&members, "b"), Some(r#"{"c": 2}"#));
+    }
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+}
@@ -0,0 +1,511 @@
+// JSON-RPC 2.0 over WebSocket
+//
+// リクエスト・レスポンス・通知をそれぞれ1つのTextメッセージとして送る:
+// -> {"jsonrpc":"2.0","method":"add","params":[1,2],"id":1}
+// <- {"jsonrpc":"2.0","result":3,"id":1}
+// -> {"jsonrpc":"2.0","method":"log","params":["hello"]}        (idのない通知には応答しない)
+// <- {"jsonrpc":"2.0","error":{"code":-32601,"message":"Method not found"},"id":2}
+// 複数のリクエストを配列にまとめて送ると (batch)、応答も配列で返す
+//
+// サーバー側は `Methods` にメソッドを登録し、`dispatch` でリクエストを処理する。
+// `Methods` 自体もHandlerとして使える。
+// クライアント側は `RpcClient::call` でIDを付けて送り、同じIDの応答が届くまで待つ
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    client::{Client, ClientWriter},
+    connection::Connection,
+    error::{Error, Result},
+    handler::Handler,
+    json,
+    message::Message,
+};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// JSON-RPCのエラーオブジェクト
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    /// JSONのまま
+    pub data: Option<String>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn invalid_params(message: &str) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"code\":{},\"message\":{}",
+            self.code,
+            json::string(&self.message)
+        );
+        if let Some(data) = &self.data {
+            json.push_str(&format!(",\"data\":{}", data));
+        }
+        json.push('}');
+        json
+    }
+
+    fn from_json(s: &str) -> Option<Self> {
+        let object = json::split_object(s)?;
+        Some(Self {
+            code: json::field(&object, "code")?.parse().ok()?,
+            message: json::field(&object, "message").and_then(json::parse_string)?,
+            data: json::field(&object, "data").map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// 結果とエラーはどちらもJSONのまま扱う
+pub type RpcResult = std::result::Result<String, RpcError>;
+
+type Method = Box<dyn Fn(Option<&str>) -> RpcResult + Send + Sync>;
+
+/// サーバー側のメソッドの一覧
+#[derive(Default)]
+pub struct Methods {
+    methods: HashMap<String, Method>,
+}
+
+impl Methods {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// メソッドを登録する。paramsはJSONのまま (省略されたら None) 渡され、結果もJSONで返す
+    pub fn method<F>(mut self, name: &str, method: F) -> Self
+    where
+        F: Fn(Option<&str>) -> RpcResult + Send + Sync + 'static,
+    {
+        self.methods.insert(name.to_string(), Box::new(method));
+        self
+    }
+
+    /// 受信したメッセージを処理し、返すべき応答を返す。通知だけなら None
+    pub fn dispatch(&self, text: &str) -> Option<String> {
+        // 配列やobjectの中身を取り出す前に、全体がJSONとして正しいか確かめる
+        if json::parse(text).is_none() {
+            return Some(response(
+                "null",
+                Err(RpcError::new(PARSE_ERROR, "Parse error")),
+            ));
+        }
+        if text.trim_start().starts_with('[') {
+            let requests = json::split_array(text).unwrap_or_default();
+            if requests.is_empty() {
+                return Some(invalid_request("null"));
+            }
+            let responses = requests
+                .into_iter()
+                .filter_map(|request| self.call(request))
+                .collect::<Vec<_>>();
+            return (!responses.is_empty()).then(|| format!("[{}]", responses.join(",")));
+        }
+        self.call(text)
+    }
+
+    /// JSONとして正しい1つのリクエストを処理する
+    fn call(&self, request: &str) -> Option<String> {
+        let Some(object) = json::split_object(request) else {
+            return Some(invalid_request("null"));
+        };
+        let id = object
+            .iter()
+            .find(|(key, _)| key == "id")
+            .map(|(_, id)| *id);
+        // idは文字列・数値・nullのどれか。それ以外なら分からないものとしてnullで返す
+        if id.is_some_and(|id| !is_id(id)) {
+            return Some(invalid_request("null"));
+        }
+        let method = json::field(&object, "method").and_then(json::parse_string);
+        let params = json::field(&object, "params");
+        let valid = json::field(&object, "jsonrpc") == Some("\"2.0\"")
+            // paramsは省略するか、配列かobject
+            && params.is_none_or(|params| params.starts_with(['[', '{']));
+        let (Some(method), true) = (method, valid) else {
+            return Some(invalid_request(id.unwrap_or("null")));
+        };
+
+        let result = match self.methods.get(&method) {
+            Some(method) => method(params),
+            None => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+        };
+        // idのないものは通知なので応答しない
+        id.map(|id| response(id, result))
+    }
+}
+
+/// JSONとして正しい値が、リクエストのidに使える型か
+fn is_id(value: &str) -> bool {
+    value == "null" || value.starts_with(|c: char| c == '"' || c == '-' || c.is_ascii_digit())
+}
+
+fn invalid_request(id: &str) -> String {
+    response(id, Err(RpcError::new(INVALID_REQUEST, "Invalid Request")))
+}
+
+fn response(id: &str, result: RpcResult) -> String {
+    match result {
+        Ok(result) => format!(
+            "{{\"jsonrpc\":\"2.0\",\"result\":{},\"id\":{}}}",
+            result, id
+        ),
+        Err(error) => format!(
+            "{{\"jsonrpc\":\"2.0\",\"error\":{},\"id\":{}}}",
+            error.to_json(),
+            id
+        ),
+    }
+}
+
+fn request(method: &str, params: Option<&str>, id: Option<u64>) -> String {
+    let mut json = format!("{{\"jsonrpc\":\"2.0\",\"method\":{}", json::string(method));
+    if let Some(params) = params {
+        json.push_str(&format!(",\"params\":{}", params));
+    }
+    if let Some(id) = id {
+        json.push_str(&format!(",\"id\":{}", id));
+    }
+    json.push('}');
+    json
+}
+
+/// サーバーからクライアントへ送る通知
+pub fn notification(method: &str, params: Option<&str>) -> Message {
+    Message::Text(request(method, params, None))
+}
+
+/// Textメッセージを全てJSON-RPCとして処理するHandler
+impl Handler for Methods {
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        let response = match &message {
+            Message::Text(text) => self.dispatch(text),
+            Message::Binary(_) => Some(invalid_request("null")),
+        };
+        if let Some(response) = response {
+            let _ = conn.send(Message::Text(response));
+        }
+    }
+}
+
+type Notify = Box<dyn Fn(&str, Option<&str>) + Send>;
+
+/// 応答を待っているリクエスト (ID -> 応答の送り先)
+type Pending = Mutex<HashMap<u64, Sender<RpcResult>>>;
+
+/// JSON-RPCのクライアント
+pub struct RpcClient {
+    writer: ClientWriter,
+    next_id: AtomicU64,
+    pending: Arc<Pending>,
+    on_notification: Arc<Mutex<Option<Notify>>>,
+    timeout: Duration,
+}
+
+impl RpcClient {
+    /// サーバーに接続し、応答を受信するスレッドを起動する
+    pub fn connect(url: &str) -> Result<Self> {
+        Ok(Self::new(Client::connect(url)?))
+    }
+
+    pub fn new(mut client: Client) -> Self {
+        let pending = Arc::new(Pending::default());
+        let on_notification: Arc<Mutex<Option<Notify>>> = Arc::default();
+        let rpc = Self {
+            writer: client.writer(),
+            next_id: AtomicU64::new(1),
+            pending: pending.clone(),
+            on_notification: on_notification.clone(),
+            timeout: Duration::from_secs(30),
+        };
+
+        thread::spawn(move || {
+            while let Ok(Some(Message::Text(text))) = client.recv() {
+                let messages = match json::split_array(&text) {
+                    Some(messages) if text.trim_start().starts_with('[') => messages,
+                    _ => vec![text.as_str()],
+                };
+                for message in messages {
+                    receive(message, &pending, &on_notification);
+                }
+            }
+            // 切断されたので、待っているcallは全て失敗させる
+            pending.lock().unwrap().clear();
+        });
+        rpc
+    }
+
+    /// `call` が応答を待つ時間 (デフォルトは30秒)
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// サーバーからの通知を受け取るcallback
+    pub fn on_notification<F>(&self, callback: F)
+    where
+        F: Fn(&str, Option<&str>) + Send + 'static,
+    {
+        *self.on_notification.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// メソッドを呼び出して応答を待つ。paramsと結果はJSONのまま。
+    /// 送信の失敗・切断・タイムアウトは外側の Err になる
+    pub fn call(&self, method: &str, params: Option<&str>) -> Result<RpcResult> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+
+        if let Err(e) = self
+            .writer
+            .send(Message::Text(request(method, params, Some(id))))
+        {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        let result = receiver.recv_timeout(self.timeout);
+        self.pending.lock().unwrap().remove(&id);
+        match result {
+            Ok(result) => Ok(result),
+            Err(RecvTimeoutError::Timeout) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no response to {} within {:?}", method, self.timeout),
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection closed",
+            ))),
+        }
+    }
+
+    /// 応答を求めない通知を送る
+    pub fn notify(&self, method: &str, params: Option<&str>) -> Result<()> {
+        self.writer.send(notification(method, params))
+    }
+}
+
+fn receive(message: &str, pending: &Pending, on_notification: &Mutex<Option<Notify>>) {
+    let Some(object) = json::split_object(message) else {
+        return;
+    };
+
+    if let Some(method) = json::field(&object, "method").and_then(json::parse_string) {
+        if let Some(callback) = on_notification.lock().unwrap().as_ref() {
+            callback(&method, json::field(&object, "params"));
+        }
+        return;
+    }
+
+    let Some(id) = json::field(&object, "id").and_then(|id| id.parse::<u64>().ok()) else {
+        return;
+    };
+    let result = match json::field(&object, "error").and_then(RpcError::from_json) {
+        Some(error) => Err(error),
+        None => Ok(json::field(&object, "result").unwrap_or("null").to_string()),
+    };
+    if let Some(sender) = pending.lock().unwrap().remove(&id) {
+        let _ = sender.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::Server, testing};
+
+    fn methods() -> Methods {
+        Methods::new()
+            .method("add", |params| {
+                let numbers = params
+                    .and_then(json::split_array)
+                    .ok_or_else(|| RpcError::invalid_params("expected an array"))?;
+                numbers
+                    .iter()
+                    .map(|n| n.parse::<i64>())
+                    .sum::<std::result::Result<i64, _>>()
+                    .map(|sum| sum.to_string())
+                    .map_err(|_| RpcError::invalid_params("expected numbers"))
+            })
+            .method("log", |_| Ok("null".to_string()))
+    }
+
+    fn error(code: i64, message: &str, id: &str) -> String {
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"error\":{{\"code\":{},\"message\":\"{}\"}},\"id\":{}}}",
+            code, message, id
+        )
+    }
+
+    #[test]
+    fn calls_methods() {
+        let methods = methods();
+        assert_eq!(
+            methods.dispatch(r#"{"jsonrpc":"2.0","method":"add","params":[1,2],"id":1}"#),
+            Some(r#"{"jsonrpc":"2.0","result":3,"id":1}"#.to_string())
+        );
+        assert_eq!(
+            methods.dispatch(r#"{"jsonrpc":"2.0","method":"add","params":[],"id":"a"}"#),
+            Some(r#"{"jsonrpc":"2.0","result":0,"id":"a"}"#.to_string())
+        );
+        // 通知には応答しない
+        assert_eq!(
+            methods.dispatch(r#"{"jsonrpc":"2.0","method":"log"}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let methods = methods();
+        for text in [
+            r#"{"jsonrpc":"2.0","method":"add","params":[1,2],"id":1"#,
+            r#"{"jsonrpc":"2.0","method":"add","params":[1,2,],"id":1}"#,
+            r#"[{"jsonrpc":"2.0","method":"add","params":[1],"id":1},{"jsonrpc"]"#,
+            "",
+            "foo",
+        ] {
+            assert_eq!(
+                methods.dispatch(text),
+                Some(error(PARSE_ERROR, "Parse error", "null")),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn reports_invalid_requests() {
+        let methods = methods();
+        let cases = [
+            (r#"{"jsonrpc":"2.0","method":1,"id":1}"#, "1"),
+            (r#"{"jsonrpc":"1.0","method":"add","id":1}"#, "1"),
+            (r#"{"method":"add","id":1}"#, "1"),
+            (
+                r#"{"jsonrpc":"2.0","method":"add","params":"1,2","id":1}"#,
+                "1",
+            ),
+            (
+                r#"{"jsonrpc":"2.0","method":"add","params":3,"id":"x"}"#,
+                r#""x""#,
+            ),
+            // idが文字列・数値・nullでなければ返さない
+            (
+                r#"{"jsonrpc":"2.0","method":"add","params":[1],"id":{"a":1}}"#,
+                "null",
+            ),
+            (
+                r#"{"jsonrpc":"2.0","method":"add","params":[1],"id":[1]}"#,
+                "null",
+            ),
+            (
+                r#"{"jsonrpc":"2.0","method":"add","params":[1],"id":true}"#,
+                "null",
+            ),
+            (r#"{"foo":"bar"}"#, "null"),
+            ("1", "null"),
+            ("[]", "null"),
+        ];
+        for (text, id) in cases {
+            assert_eq!(
+                methods.dispatch(text),
+                Some(error(INVALID_REQUEST, "Invalid Request", id)),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn reports_unknown_methods_and_invalid_params() {
+        let methods = methods();
+        assert_eq!(
+            methods.dispatch(r#"{"jsonrpc":"2.0","method":"sub","id":1}"#),
+            Some(error(METHOD_NOT_FOUND, "Method not found", "1"))
+        );
+        assert_eq!(
+            methods.dispatch(r#"{"jsonrpc":"2.0","method":"add","params":{"a":1},"id":2}"#),
+            Some(error(INVALID_PARAMS, "expected an array", "2"))
+        );
+        assert_eq!(
+            methods.dispatch(r#"{"jsonrpc":"2.0","method":"add","params":["a"],"id":null}"#),
+            Some(error(INVALID_PARAMS, "expected numbers", "null"))
+        );
+    }
+
+    #[test]
+    fn answers_batches() {
+        let methods = methods();
+        let batch = r#"[
+            {"jsonrpc":"2.0","method":"add","params":[1,2],"id":"1"},
+            {"jsonrpc":"2.0","method":"log","params":["x"]},
+            {"foo":"boo"},
+            {"jsonrpc":"2.0","method":"sub","id":"5"},
+            1
+        ]"#;
+        assert_eq!(
+            methods.dispatch(batch),
+            Some(format!(
+                "[{},{},{},{}]",
+                r#"{"jsonrpc":"2.0","result":3,"id":"1"}"#,
+                error(INVALID_REQUEST, "Invalid Request", "null"),
+                error(METHOD_NOT_FOUND, "Method not found", r#""5""#),
+                error(INVALID_REQUEST, "Invalid Request", "null"),
+            ))
+        );
+        // 通知だけのbatchには応答しない
+        assert_eq!(
+            methods
+                .dispatch(r#"[{"jsonrpc":"2.0","method":"log"},{"jsonrpc":"2.0","method":"log"}]"#),
+            None
+        );
+    }
+
+    #[test]
+    fn client_calls_server_methods() {
+        let server =
+            testing::spawn_server(Server::bind("127.0.0.1:0", methods()).unwrap()).unwrap();
+        let client = RpcClient::new(server.connect("/").unwrap());
+        assert_eq!(
+            client.call("add", Some("[2,3]")).unwrap(),
+            Ok("5".to_string())
+        );
+        assert_eq!(
+            client.call("nope", None).unwrap(),
+            Err(RpcError::new(METHOD_NOT_FOUND, "Method not found"))
+        );
+    }
+}
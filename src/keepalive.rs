@@ -0,0 +1,151 @@
+// 応答のない接続 (half-open) の検出
+//
+// 相手のネットワークがFINを送らずに消えると、TCPの接続は開いたまま受信を待ち続ける。
+// `interval` の間なにも受信しなかった接続にPingを送り、さらに `timeout` の間なにも受信しなければ切断する。
+// 切断するときはCloseを送らずにTCP接続を切るので、書き込みで止まっているスレッドも戻る。
+// 検出までの時間は最大で `interval + timeout` (に確かめる間隔を足した分)
+//
+// `tcp-keepalive` featureを有効にすると、受け付けた接続にOSのTCP keepalive (`TcpKeepalive`) も設定できる。
+// Pingは相手のWebSocketの実装が応答するかまで確かめるが、OSのkeepaliveはアプリケーションが
+// 止まっていても応答するので、ネットワークが消えたことだけを、Pingを送るスレッドなしで検出する
+
+#[cfg(feature = "tcp-keepalive")]
+use std::{io, net::TcpStream};
+use std::{sync::Weak, thread, time::Duration};
+
+use crate::{
+    connection::ConnectionHandle,
+    log::{debug, warning},
+    registry::Registry,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keepalive {
+    /// 受信がないままこの時間が過ぎたらPingを送る
+    pub interval: Duration,
+    /// Pingを送ってからこの時間の間に何も受信しなければ切断する
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// OSのTCP keepalive。`idle` の間なにも届かなければ `interval` ごとにprobeを送り、
+/// `retries` 回応答がなければOSが接続を切断する (受信しているスレッドにはエラーが返る)
+#[cfg(feature = "tcp-keepalive")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TcpKeepalive {
+    pub idle: Duration,
+    /// probeの間隔。設定できないOS (OpenBSDなど) ではOSの設定を使う
+    pub interval: Duration,
+    /// 設定できないOS (OpenBSDなど) ではOSの設定を使う
+    pub retries: u32,
+}
+
+#[cfg(feature = "tcp-keepalive")]
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 3,
+        }
+    }
+}
+
+/// 接続にOSのTCP keepaliveを設定する
+#[cfg(feature = "tcp-keepalive")]
+pub(crate) fn set_tcp_keepalive(stream: &TcpStream, keepalive: &TcpKeepalive) -> io::Result<()> {
+    let params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_vendor = "apple",
+        windows
+    ))]
+    let params = params
+        .with_interval(keepalive.interval)
+        .with_retries(keepalive.retries);
+    socket2::SockRef::from(stream).set_tcp_keepalive(&params)
+}
+
+/// 確かめる間隔の下限
+const MIN_TICK: Duration = Duration::from_millis(10);
+
+/// 開いている接続を定期的に確かめるスレッドを起動する。サーバーがなくなったら終わる
+pub(crate) fn start(keepalive: Keepalive, registry: Weak<Registry>) {
+    let tick = (keepalive.interval.min(keepalive.timeout) / 2).max(MIN_TICK);
+    thread::Builder::new()
+        .name("keepalive".to_string())
+        .spawn(move || {
+            while let Some(registry) = registry.upgrade() {
+                for entry in registry.entries() {
+                    probe(&keepalive, &entry.handle);
+                }
+                drop(registry);
+                thread::sleep(tick);
+            }
+        })
+        .ok();
+}
+
+fn probe(keepalive: &Keepalive, handle: &ConnectionHandle) {
+    let id = handle.id();
+    let idle = handle.idle();
+    if idle >= keepalive.interval + keepalive.timeout {
+        warning!(
+            "keepalive_timeout",
+            { conn: id, idle_ms: idle.as_millis() as u64 },
+            "[{}] nothing received for {:?}, disconnecting",
+            id,
+            idle
+        );
+        handle.abort();
+    } else if idle >= keepalive.interval && !handle.is_probing() {
+        debug!(
+            "keepalive_ping",
+            { conn: id },
+            "[{}] idle for {:?}, sending ping",
+            id,
+            idle
+        );
+        let _ = handle.ping();
+    }
+}
+
+#[cfg(all(test, feature = "tcp-keepalive"))]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn sets_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let keepalive = TcpKeepalive {
+            idle: Duration::from_secs(20),
+            interval: Duration::from_secs(5),
+            retries: 4,
+        };
+        set_tcp_keepalive(&stream, &keepalive).unwrap();
+
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.tcp_keepalive_time().unwrap(), keepalive.idle);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.tcp_keepalive_interval().unwrap(), keepalive.interval);
+            assert_eq!(socket.tcp_keepalive_retries().unwrap(), keepalive.retries);
+        }
+    }
+}
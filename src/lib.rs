@@ -0,0 +1,234 @@
+// WebSocket serverの実装
+//
+// 以下の記事の写経:
+// https://zenn.dev/ohke/articles/8d6b690c144a0e
+//
+// 詳細はこちらを参照:
+// https://www.rfc-editor.org/rfc/rfc6455
+//
+// Protocol Overview:
+//
+//    The protocol has two parts: a handshake and the data transfer.
+
+//    The handshake from the client looks as follows:
+
+//         GET /chat HTTP/1.1
+//         Host: server.example.com
+//         Upgrade: websocket
+//         Connection: Upgrade
+//         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+//         Origin: http://example.com
+//         Sec-WebSocket-Protocol: chat, superchat
+//         Sec-WebSocket-Version: 13
+
+//    The handshake from the server looks as follows:
+
+//         HTTP/1.1 101 Switching Protocols
+//         Upgrade: websocket
+//         Connection: Upgrade
+//         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=
+//         Sec-WebSocket-Protocol: chat
+
+//    The leading line from the client follows the Request-Line format.
+//    The leading line from the server follows the Status-Line format.  The
+//    Request-Line and Status-Line productions are defined in [RFC2616].
+
+//    An unordered set of header fields comes after the leading line in
+//    both cases.  The meaning of these header fields is specified in
+//    Section 4 of this document.  Additional header fields may also be
+//    present, such as cookies [RFC6265].  The format and parsing of
+//    headers is as defined in [RFC2616].
+
+//    Once the client and server have both sent their handshakes, and if
+//    the handshake was successful, then the data transfer part starts.
+//    This is a two-way communication channel where each side can,
+//    independently from the other, send data at will.
+
+//    After a successful handshake, clients and servers transfer data back
+//    and forth in conceptual units referred to in this specification as
+//    "messages".  On the wire, a message is composed of one or more
+//    frames.  The WebSocket message does not necessarily correspond to a
+//    particular network layer framing, as a fragmented message may be
+//    coalesced or split by an intermediary.
+//
+//    A frame has an associated type.  Each frame belonging to the same
+//    message contains the same type of data.  Broadly speaking, there are
+//    types for textual data (which is interpreted as UTF-8 [RFC3629]
+//    text), binary data (whose interpretation is left up to the
+//    application), and control frames (which are not intended to carry
+//    data for the application but instead for protocol-level signaling,
+//    such as to signal that the connection should be closed).  This
+//    version of the protocol defines six frame types and leaves ten
+//    reserved for future use.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "server")]
+pub mod ack;
+#[cfg(feature = "acme")]
+pub mod acme;
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "server")]
+pub mod actor;
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "server")]
+pub mod backend;
+#[cfg(feature = "server")]
+pub mod ban;
+#[cfg(any(feature = "actix", feature = "http2", feature = "webtransport"))]
+mod bridge;
+#[cfg(feature = "server")]
+pub mod chaos;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "server")]
+pub mod cluster;
+#[cfg(feature = "server")]
+pub mod connection;
+#[cfg(all(unix, feature = "server"))]
+pub mod control;
+#[cfg(feature = "server")]
+mod dashboard;
+#[cfg(feature = "deflate")]
+pub mod deflate;
+pub mod error;
+#[cfg(all(unix, feature = "reactor", feature = "server"))]
+mod event_loop;
+#[cfg(feature = "std")]
+pub mod extension;
+pub mod frame;
+#[cfg(feature = "server")]
+pub mod geoip;
+#[cfg(feature = "server")]
+pub mod graphql;
+#[cfg(feature = "server")]
+pub mod handler;
+#[cfg(feature = "std")]
+pub mod handshake;
+#[cfg(feature = "client")]
+mod happy_eyeballs;
+#[cfg(feature = "server")]
+mod http;
+#[cfg(feature = "http2")]
+pub mod http2;
+#[cfg(feature = "server")]
+pub mod hub;
+#[cfg(feature = "server")]
+pub mod interceptor;
+#[cfg(feature = "server")]
+pub mod ipfilter;
+#[cfg(feature = "server")]
+pub mod journal;
+#[cfg(feature = "server")]
+mod json;
+#[cfg(feature = "server")]
+pub mod jsonrpc;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+#[cfg(feature = "server")]
+pub mod keepalive;
+#[cfg(feature = "server")]
+pub mod log;
+pub mod message;
+#[cfg(feature = "server")]
+pub mod middleware;
+#[cfg(feature = "server")]
+pub mod mqtt;
+#[cfg(feature = "server")]
+pub mod mux;
+#[cfg(feature = "server")]
+mod outbox;
+#[cfg(feature = "server")]
+mod polling;
+#[cfg(feature = "client")]
+pub mod pool;
+#[cfg(feature = "server")]
+pub mod proxy;
+#[cfg(all(unix, feature = "reactor"))]
+pub mod reactor;
+#[cfg(feature = "server")]
+pub mod record;
+#[cfg(feature = "std")]
+pub mod records;
+#[cfg(feature = "server")]
+mod registry;
+#[cfg(feature = "std")]
+pub mod request;
+#[cfg(all(unix, feature = "server"))]
+pub mod restart;
+#[cfg(feature = "async")]
+pub mod runtime;
+#[cfg(feature = "server")]
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod script;
+#[cfg(all(unix, feature = "reactor", feature = "client"))]
+pub mod selector;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod session;
+#[cfg(feature = "server")]
+pub mod socketio;
+#[cfg(feature = "server")]
+mod sse;
+#[cfg(feature = "server")]
+pub mod stats;
+#[cfg(feature = "server")]
+pub mod statsd;
+#[cfg(feature = "server")]
+pub mod stomp;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "server")]
+pub mod telemetry;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "server")]
+pub mod topic;
+#[cfg(feature = "server")]
+pub mod trace;
+#[cfg(feature = "server")]
+pub mod tunnel;
+#[cfg(all(target_os = "linux", feature = "io-uring", feature = "server"))]
+mod uring;
+#[cfg(feature = "web")]
+pub mod web;
+#[cfg(feature = "webtransport")]
+pub mod webtransport;
+
+#[cfg(feature = "server")]
+pub use admin::{Admin, ConnectionInfo};
+#[cfg(feature = "async")]
+pub use async_client::{AsyncClient, AsyncClientWriter};
+#[cfg(feature = "server")]
+pub use chaos::Chaos;
+#[cfg(feature = "client")]
+pub use client::Client;
+#[cfg(feature = "server")]
+pub use cluster::{Cluster, ClusterConfig};
+#[cfg(feature = "server")]
+pub use connection::{
+    Connection, ConnectionHandle, ConnectionId, Extensions, PreparedMessage, Priority,
+};
+pub use error::{Error, Result, ViolationKind};
+pub use frame::{Frame, Opcode};
+#[cfg(feature = "server")]
+pub use handler::Handler;
+#[cfg(feature = "server")]
+pub use hub::{Authorizer, Hub};
+pub use message::Message;
+#[cfg(feature = "server")]
+pub use server::{CloseAction, ClosePolicy, Config, Server, Upgrader};
+#[cfg(feature = "server")]
+pub use stats::{ConnectionStats, LabeledStats};
+#[cfg(feature = "std")]
+pub use stream::Stream;
@@ -0,0 +1,293 @@
+// ライブラリ内部のログ出力。レベルは実行中に変更できる
+//
+// 各ログは種類 (event) と構造化されたフィールドを持つ。
+// Text形式では人が読むためのメッセージを、Json形式では1行1イベントのJSONを出力する:
+// {"ts":"2023-11-01T12:34:56.789Z","level":"info","event":"connection_opened","connection_id":1,"peer":"127.0.0.1:50000","path":"/","message":"..."}
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::json;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// ログに付与する構造化されたフィールドの値
+#[derive(Clone, Debug, PartialEq)]
+pub enum Field {
+    Int(u64),
+    Bool(bool),
+    Str(String),
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicU8 = AtomicU8::new(0);
+
+/// Traceレベルのログを出力するtarget。空なら全て出力する
+static TARGETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+pub fn set_format(format: Format) {
+    JSON.store((format == Format::Json) as u8, Ordering::Relaxed);
+}
+
+pub fn format() -> Format {
+    if JSON.load(Ordering::Relaxed) == 1 {
+        Format::Json
+    } else {
+        Format::Text
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+/// Traceレベルのログを出力するtargetを絞り込む (例: `websocket_rs::frame`)
+pub fn set_targets<S: Into<String>>(targets: impl IntoIterator<Item = S>) {
+    *TARGETS.write().unwrap() = targets.into_iter().map(Into::into).collect();
+}
+
+pub fn target_enabled(level: Level, target: &str) -> bool {
+    if !enabled(level) {
+        return false;
+    }
+    if level < Level::Trace {
+        return true;
+    }
+    let targets = TARGETS.read().unwrap();
+    targets.is_empty() || targets.iter().any(|t| target.starts_with(t.as_str()))
+}
+
+#[doc(hidden)]
+pub fn write(
+    level: Level,
+    target: Option<&str>,
+    event: &str,
+    fields: &[(&str, Field)],
+    message: fmt::Arguments,
+) {
+    let enabled = match target {
+        Some(target) => target_enabled(level, target),
+        None => enabled(level),
+    };
+    if !enabled {
+        return;
+    }
+
+    match (format(), target) {
+        (Format::Text, Some(target)) => eprintln!("[{} {}] {}", level, target, message),
+        (Format::Text, None) => eprintln!("[{}] {}", level, message),
+        (Format::Json, _) => {
+            let mut line = format!(
+                "{{\"ts\":{},\"level\":\"{}\",\"event\":{}",
+                json::string(&timestamp(SystemTime::now())),
+                level,
+                json::string(event)
+            );
+            if let Some(target) = target {
+                line.push_str(&format!(",\"target\":{}", json::string(target)));
+            }
+            for (key, value) in fields {
+                let value = match value {
+                    Field::Int(i) => i.to_string(),
+                    Field::Bool(b) => b.to_string(),
+                    Field::Str(s) => json::string(s),
+                };
+                line.push_str(&format!(",{}:{}", json::string(key), value));
+            }
+            line.push_str(&format!(
+                ",\"message\":{}}}",
+                json::string(&message.to_string())
+            ));
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// RFC 3339形式 (UTC, ミリ秒まで)
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+impl From<u64> for Field {
+    fn from(value: u64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<usize> for Field {
+    fn from(value: usize) -> Self {
+        Self::Int(value as u64)
+    }
+}
+
+impl From<u16> for Field {
+    fn from(value: u16) -> Self {
+        Self::Int(value as u64)
+    }
+}
+
+impl From<bool> for Field {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<String> for Field {
+    fn from(value: String) -> Self {
+        Self::Str(value)
+    }
+}
+
+impl From<&str> for Field {
+    fn from(value: &str) -> Self {
+        Self::Str(value.to_string())
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => Err(format!("unknown log level: {}", s)),
+        }
+    }
+}
+
+// info!("event_name", { key: value, ... }, "message {}", arg)
+macro_rules! warning {
+    ($event:literal, { $($key:ident: $value:expr),* $(,)? }, $($arg:tt)*) => {
+        $crate::log::write(
+            $crate::log::Level::Warn,
+            None,
+            $event,
+            &[$((stringify!($key), $crate::log::Field::from($value))),*],
+            format_args!($($arg)*),
+        )
+    };
+}
+
+macro_rules! info {
+    ($event:literal, { $($key:ident: $value:expr),* $(,)? }, $($arg:tt)*) => {
+        $crate::log::write(
+            $crate::log::Level::Info,
+            None,
+            $event,
+            &[$((stringify!($key), $crate::log::Field::from($value))),*],
+            format_args!($($arg)*),
+        )
+    };
+}
+
+macro_rules! debug {
+    ($event:literal, { $($key:ident: $value:expr),* $(,)? }, $($arg:tt)*) => {
+        $crate::log::write(
+            $crate::log::Level::Debug,
+            None,
+            $event,
+            &[$((stringify!($key), $crate::log::Field::from($value))),*],
+            format_args!($($arg)*),
+        )
+    };
+}
+
+macro_rules! trace {
+    (target: $target:expr, $event:literal, { $($key:ident: $value:expr),* $(,)? }, $($arg:tt)*) => {
+        $crate::log::write(
+            $crate::log::Level::Trace,
+            Some($target),
+            $event,
+            &[$((stringify!($key), $crate::log::Field::from($value))),*],
+            format_args!($($arg)*),
+        )
+    };
+}
+
+pub(crate) use {debug, info, trace, warning};
@@ -1,373 +1,1010 @@
-// WebSocket serverの実装
+// WebSocketクライアントから送信されたテキストをechoするサーバー
 //
 // 以下の記事の写経:
 // https://zenn.dev/ohke/articles/8d6b690c144a0e
-//
-// 詳細はこちらを参照:
-// https://www.rfc-editor.org/rfc/rfc6455
-//
-// Protocol Overview:
-//
-//    The protocol has two parts: a handshake and the data transfer.
-
-//    The handshake from the client looks as follows:
-
-//         GET /chat HTTP/1.1
-//         Host: server.example.com
-//         Upgrade: websocket
-//         Connection: Upgrade
-//         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
-//         Origin: http://example.com
-//         Sec-WebSocket-Protocol: chat, superchat
-//         Sec-WebSocket-Version: 13
-
-//    The handshake from the server looks as follows:
-
-//         HTTP/1.1 101 Switching Protocols
-//         Upgrade: websocket
-//         Connection: Upgrade
-//         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=
-//         Sec-WebSocket-Protocol: chat
-
-//    The leading line from the client follows the Request-Line format.
-//    The leading line from the server follows the Status-Line format.  The
-//    Request-Line and Status-Line productions are defined in [RFC2616].
-
-//    An unordered set of header fields comes after the leading line in
-//    both cases.  The meaning of these header fields is specified in
-//    Section 4 of this document.  Additional header fields may also be
-//    present, such as cookies [RFC6265].  The format and parsing of
-//    headers is as defined in [RFC2616].
-
-//    Once the client and server have both sent their handshakes, and if
-//    the handshake was successful, then the data transfer part starts.
-//    This is a two-way communication channel where each side can,
-//    independently from the other, send data at will.
-
-//    After a successful handshake, clients and servers transfer data back
-//    and forth in conceptual units referred to in this specification as
-//    "messages".  On the wire, a message is composed of one or more
-//    frames.  The WebSocket message does not necessarily correspond to a
-//    particular network layer framing, as a fragmented message may be
-//    coalesced or split by an intermediary.
-//
-//    A frame has an associated type.  Each frame belonging to the same
-//    message contains the same type of data.  Broadly speaking, there are
-//    types for textual data (which is interpreted as UTF-8 [RFC3629]
-//    text), binary data (whose interpretation is left up to the
-//    application), and control frames (which are not intended to carry
-//    data for the application but instead for protocol-level signaling,
-//    such as to signal that the connection should be closed).  This
-//    version of the protocol defines six frame types and leaves ten
-//    reserved for future use.
-
-use base64::{engine::general_purpose, Engine as _};
-use sha1::{Digest, Sha1};
+
 use std::{
-    io::{Read, Write},
-    net::TcpListener,
-    thread::sleep,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
     time::Duration,
 };
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum Opcode {
-    Continuation, // = 0x0,
-    Text,         // = 0x1,
-    Binary,       // = 0x2,
-    Close,        // = 0x8,
-    Ping,         // = 0x9,
-    Pong,         // = 0xA,
+use websocket_rs::{
+    ack,
+    backend::{NatsBackend, RedisBackend},
+    ban::BanList,
+    cluster::Discovery,
+    geoip::GeoIp,
+    graphql::{GraphQlWs, Operation, Resolver, Sink},
+    interceptor::Censor,
+    journal::{Journal, JournalConfig},
+    jsonrpc::{Methods, RpcError},
+    keepalive::Keepalive,
+    log,
+    middleware::{self, BasicAuth, RateLimit},
+    mqtt::MqttBridge,
+    mux::{Mux, Side},
+    proxy::Proxy,
+    records, request,
+    schema::{OnInvalid, Schema},
+    script::Script,
+    server::{SendQueue, SlowConsumerPolicy},
+    socketio::{Ack, Socket, SocketIo, SocketIoHandler},
+    statsd::StatsdExporter,
+    stomp::Stomp,
+    telemetry::OtlpExporter,
+    topic, trace,
+    tunnel::TcpTunnel,
+    Authorizer, Chaos, Cluster, ClusterConfig, Config, Connection, ConnectionId, Handler, Hub,
+    Message, Server,
+};
+
+pub fn echo(payload: &[u8]) -> Vec<u8> {
+    // payloadにechoしたことを示す文字列を付与して返す
+    let mut payload = payload.to_vec();
+    payload.extend_from_slice(b" (echoed)");
+    payload
 }
 
-#[derive(Clone, Debug)]
-pub struct Frame {
-    pub fin: bool,
-    pub rsv1: bool,
-    pub rsv2: bool,
-    pub rsv3: bool,
-    pub opcode: Opcode,
-    pub mask: bool,
-    /// included extendted payload length
-    pub payload_len: usize,
-    pub masking_key: Option<[u8; 4]>,
-    /// decoded with masking_key
-    pub payload: Vec<u8>,
+/// `/` などroom以外のpathに送られたTextメッセージへの応答のしかた
+#[derive(Clone, Copy, Debug)]
+enum EchoMode {
+    /// ` (echoed)` を付けて返す
+    Suffix,
+    /// そのまま返す
+    Plain,
+    Uppercase,
+    /// 文字の順番を逆にして返す
+    Reverse,
+    /// 指定した時間待ってから返す
+    Delayed(Duration),
+    /// 同じモードで接続している全員に送る
+    FanOut,
 }
 
-impl From<u8> for Opcode {
-    fn from(byte: u8) -> Self {
-        match byte & 0x0F {
-            0x0 => Self::Continuation,
-            0x1 => Self::Text,
-            0x2 => Self::Binary,
-            0x8 => Self::Close,
-            0x9 => Self::Ping,
-            0xA => Self::Pong,
-            _ => panic!("Invalid opcode: {}", byte),
+/// fan-outモードの接続が参加するroom
+const FAN_OUT_ROOM: &str = "echo";
+
+impl std::str::FromStr for EchoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("delayed", ms)) => ms
+                .parse()
+                .map(|ms| Self::Delayed(Duration::from_millis(ms)))
+                .map_err(|_| format!("invalid delay: {}", ms)),
+            _ => match s {
+                "suffix" => Ok(Self::Suffix),
+                "plain" => Ok(Self::Plain),
+                "uppercase" => Ok(Self::Uppercase),
+                "reverse" => Ok(Self::Reverse),
+                "fanout" => Ok(Self::FanOut),
+                _ => Err(format!("unknown echo mode: {}", s)),
+            },
         }
     }
 }
 
-impl From<Opcode> for u8 {
-    fn from(opcode: Opcode) -> Self {
-        match opcode {
-            Opcode::Continuation => 0x0,
-            Opcode::Text => 0x1,
-            Opcode::Binary => 0x2,
-            Opcode::Close => 0x8,
-            Opcode::Ping => 0x9,
-            Opcode::Pong => 0xA,
-        }
-    }
+struct Echo {
+    mode: EchoMode,
+    /// `/rpc` で受け付けるJSON-RPCのメソッド
+    rpc: Methods,
+    /// `/mux` の接続の多重化
+    muxes: Mutex<HashMap<ConnectionId, Mux>>,
+}
+
+/// `/rooms/<name>` に接続するとroomに参加し、送ったメッセージは参加者全員に届く。
+/// `/rooms/<name>?user=<user>` ならユーザー名付きで参加する
+fn room(conn: &Connection) -> Option<(&str, Option<&str>)> {
+    let (path, query) = match conn.path().split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (conn.path(), None),
+    };
+    let user = query.and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("user=")));
+    Some((path.strip_prefix("/rooms/")?, user))
 }
 
-impl Frame {
-    pub fn new(opcode: Opcode, payload: Option<Vec<u8>>) -> Self {
-        let (payload_len, payload) = match payload {
-            Some(payload) => (payload.len(), payload),
-            None => (0, vec![]),
+impl Handler for Echo {
+    fn on_open(&self, conn: &mut Connection) {
+        let joined = match room(conn) {
+            Some((room, Some(user))) => conn.join_as(room, user),
+            Some((room, None)) => conn.join(room),
+            None => Ok(()),
         };
+        if let Err(e) = joined {
+            let _ = conn.close(1008, &e.to_string());
+            return;
+        }
 
-        Self {
-            fin: true, // Fragmentation is not supported, so always true
-            rsv1: false,
-            rsv2: false,
-            rsv3: false,
-            opcode,
-            mask: false,
-            payload_len,
-            masking_key: None,
-            payload,
+        if let (EchoMode::FanOut, "/") = (self.mode, conn.path()) {
+            let _ = conn.join(FAN_OUT_ROOM);
+        }
+
+        // `/mux` では相手が開いたチャネルごとにechoする
+        if conn.path() == "/mux" {
+            let handle = conn.handle();
+            let mux = Mux::new(Side::Server, move |message| handle.send(message));
+            self.muxes.lock().unwrap().insert(conn.id(), mux.clone());
+            thread::spawn(move || {
+                while let Some(channel) = mux.accept() {
+                    thread::spawn(move || {
+                        while let Some(message) = channel.recv() {
+                            if channel.send(message).is_err() {
+                                return;
+                            }
+                        }
+                        let _ = channel.close();
+                    });
+                }
+            });
         }
     }
 
-    pub fn to_bytes(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        // `/ack` では到達確認付きのメッセージにAckを返してからechoする
+        if conn.path() == "/ack" {
+            if let Some(ack::Packet::Data { id, message }) = ack::decode(&message) {
+                let _ = conn.send(ack::ack(id));
+                let _ = conn.send(message);
+            }
+            return;
+        }
 
-        buffer.push(
-            (self.fin as u8) << 7
-                | (self.rsv1 as u8) << 6
-                | (self.rsv2 as u8) << 5
-                | (self.rsv3 as u8) << 4
-                | u8::from(self.opcode),
-        );
+        // `/records` ではまとめて送られたレコードを、それぞれ大文字にしてまとめて返す
+        if conn.path() == "/records" {
+            let reply = match records::unpack(&message) {
+                Ok(records) => records::pack(records.map(|record| record.to_ascii_uppercase())),
+                Err(e) => Message::Text(e.to_string()),
+            };
+            let _ = conn.send(reply);
+            return;
+        }
 
-        if self.payload_len < 126 {
-            buffer.push((self.mask as u8) << 7 | self.payload_len as u8);
-        } else if self.payload_len < 65536 {
-            buffer.push((self.mask as u8) << 7 | 126);
-            buffer.extend_from_slice(&(self.payload_len as u16).to_be_bytes());
-        } else {
-            buffer.push((self.mask as u8) << 7 | 127);
-            buffer.extend_from_slice(&(self.payload_len as u64).to_be_bytes());
+        // `/request` ではリクエストと同じ内容を、同じIDの応答として返す
+        if conn.path() == "/request" {
+            if let Some(request::Packet::Request { id, message }) = request::decode(&message) {
+                let _ = conn.send(request::reply(id, &message));
+            }
+            return;
         }
 
-        if self.mask {
-            buffer.extend(self.masking_key.unwrap().clone());
+        if let Some((room, _)) = room(conn) {
+            // 読み取り専用のroomなら送ってきたメッセージは捨てる
+            let _ = conn.publish(room, message);
+            return;
         }
 
-        for (i, b) in self.payload.iter().enumerate() {
-            buffer.push(if self.mask {
-                b ^ self.masking_key.unwrap()[i % 4]
-            } else {
-                *b
-            });
+        if let Some(mux) = self.muxes.lock().unwrap().get(&conn.id()) {
+            mux.receive(&message);
+            return;
         }
 
-        return buffer;
+        if conn.path() == "/rpc" {
+            return self.rpc.on_message(conn, message);
+        }
+
+        let Message::Text(text) = message else {
+            return;
+        };
+        println!("Text");
+
+        let reply = match self.mode {
+            EchoMode::Suffix => String::from_utf8(echo(text.as_bytes())).unwrap(),
+            EchoMode::Plain => text,
+            EchoMode::Uppercase => text.to_uppercase(),
+            EchoMode::Reverse => text.chars().rev().collect(),
+            EchoMode::Delayed(delay) => {
+                // 受信を止めないように別スレッドで待つ
+                let handle = conn.handle();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let _ = handle.send(Message::Text(text));
+                });
+                return;
+            }
+            EchoMode::FanOut if conn.path() == "/" => {
+                let _ = conn.publish(FAN_OUT_ROOM, Message::Text(text));
+                return;
+            }
+            EchoMode::FanOut => text,
+        };
+        let _ = conn.send(Message::Text(reply));
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        if let Some(mux) = self.muxes.lock().unwrap().remove(&id) {
+            mux.shutdown();
+        }
+        println!("Close");
     }
 }
 
-impl From<&[u8]> for Frame {
-    fn from(buffer: &[u8]) -> Self {
-        let fin = buffer[0] & 0b1000_0000 != 0; // 0x80
-        let rsv1 = buffer[0] & 0b0100_0000 != 0; // 0x40
-        let rsv2 = buffer[0] & 0b0010_0000 != 0; // 0x20
-        let rsv3 = buffer[0] & 0b0001_0000 != 0; // 0x10
-        let opcode = Opcode::from(buffer[0]);
+/// `/rpc` のJSON-RPCのメソッド。`echo` はparamsをそのまま返し、`add` は数値の配列の合計を返す
+fn rpc_methods() -> Methods {
+    Methods::new()
+        .method("echo", |params| Ok(params.unwrap_or("null").to_string()))
+        .method("add", |params| {
+            let numbers = params
+                .and_then(|params| {
+                    params
+                        .strip_prefix('[')?
+                        .strip_suffix(']')
+                        .map(str::to_string)
+                })
+                .ok_or_else(|| RpcError::invalid_params("params must be an array of numbers"))?;
+            numbers
+                .split(',')
+                .filter(|n| !n.trim().is_empty())
+                .map(|n| n.trim().parse::<f64>())
+                .sum::<Result<f64, _>>()
+                .map(|sum| sum.to_string())
+                .map_err(|_| RpcError::invalid_params("params must be an array of numbers"))
+        })
+}
 
-        let mask = buffer[1] & 0b1000_0000 != 0;
+/// `/socket.io/` に接続したSocket.IOのクライアントには、受け取ったイベントをそのまま送り返す
+struct SocketIoEcho;
 
-        let (payload_len, mut i) = match buffer[1] & 0b0111_1111 {
-            126 => (u16::from_be_bytes([buffer[2], buffer[3]]) as usize, 4),
-            127 => {
-                let mut payload_len = [0; 8];
-                payload_len.copy_from_slice(&buffer[2..10]);
-                (usize::from_be_bytes(payload_len), 10)
+impl SocketIoHandler for SocketIoEcho {
+    fn on_event(&self, socket: &Socket, event: &str, args: Vec<String>, ack: Option<Ack>) {
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        match ack {
+            Some(ack) => {
+                let _ = ack.send(&args);
             }
-            n => (n as usize, 2),
-        };
+            None => {
+                let _ = socket.emit(event, &args);
+            }
+        }
+    }
+}
 
-        let masking_key = if mask {
-            let mut masking_key = [0; 4];
-            masking_key.copy_from_slice(&buffer[i..i + 4]);
-            i += 4;
-            Some(masking_key)
-        } else {
-            None
-        };
+/// 受け取ったvariablesをそのまま結果として返す
+struct GraphQlEcho;
 
-        let payload = if mask {
-            buffer[i..i + payload_len]
-                .iter()
-                .enumerate()
-                .map(|(i, byte)| byte ^ masking_key.unwrap()[i % 4])
-                .collect::<Vec<u8>>()
-        } else {
-            buffer[i..i + payload_len].to_vec()
-        };
+impl Resolver for GraphQlEcho {
+    fn subscribe(&self, operation: Operation, sink: Sink) {
+        let variables = operation.variables.as_deref().unwrap_or("null");
+        let _ = sink.next(&format!("{{\"data\":{{\"echo\":{}}}}}", variables));
+        let _ = sink.complete();
+    }
+}
+
+/// パターンに一致するroomには参加できるが、クライアントからはpublishできない
+struct ReadOnly(Vec<String>);
 
-        Self {
-            fin,
-            rsv1,
-            rsv2,
-            rsv3,
-            opcode,
-            mask,
-            payload_len,
-            masking_key,
-            payload,
+impl Authorizer for ReadOnly {
+    fn can_publish(&self, _conn: &Connection, room: &str, _message: &Message) -> bool {
+        !self.0.iter().any(|pattern| topic::matches(pattern, room))
+    }
+}
+
+fn chaos(config: &mut Config) -> &mut Chaos {
+    config.chaos.get_or_insert_with(Chaos::default)
+}
+
+/// `--daemon` で起動した子プロセスに付ける環境変数
+#[cfg(unix)]
+const DAEMON_ENV: &str = "WEBSOCKET_RS_DAEMON";
+
+/// 同じ引数で自分自身をバックグラウンドで起動し直し、pidファイルを書いて終了する。
+/// 子プロセスは新しいプロセスグループで動き、標準入力は /dev/null、標準出力・標準エラーは `log_file` に追記する。
+/// stdだけではforkできないため、起動し直す。起動し直した子プロセスでは何もしない。
+/// pidファイルのプロセスがまだ動いていれば起動せずにエラーを返し、動いていなければ上書きする
+#[cfg(unix)]
+fn daemonize(pidfile: &str, log_file: &str) -> std::io::Result<()> {
+    use std::{
+        fs::{self, OpenOptions},
+        io,
+        os::unix::process::CommandExt,
+        process::{Command, Stdio},
+    };
+
+    if std::env::var_os(DAEMON_ENV).is_some() {
+        return Ok(());
+    }
+    match fs::read_to_string(pidfile) {
+        Ok(content) => {
+            // 数字でなければ壊れたpidファイルとして扱い、上書きする
+            if let Ok(pid) = content.trim().parse::<u32>() {
+                // `kill -0` はシグナルを送らず、プロセスが存在するかだけを確かめる
+                let alive = Command::new("kill")
+                    .args(["-0", &pid.to_string()])
+                    .stderr(Stdio::null())
+                    .status()
+                    .is_ok_and(|status| status.success());
+                if alive {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("already running (pid {} in {})", pid, pidfile),
+                    ));
+                }
+            }
         }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
     }
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    let child = Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .env(DAEMON_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0)
+        .spawn()?;
+    fs::write(pidfile, format!("{}\n", child.id()))?;
+    println!("started in the background (pid {})", child.id());
+    std::process::exit(0);
 }
 
-pub fn echo(payload: &[u8]) -> Vec<u8> {
-    // payloadにechoしたことを示す文字列を付与して返す
-    let mut payload = payload.to_vec();
-    payload.extend_from_slice(b" (echoed)");
-    payload
+#[cfg(not(unix))]
+fn daemonize(_pidfile: &str, _log_file: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--daemon is only supported on unix",
+    ))
 }
 
 fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:7778").unwrap();
-
-    let mut buffer = [0; 4096];
-
-    // TCPの待ち受け
-    for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        let mut is_websocket = false;
-
-        loop {
-            // print!("buffer: {:?}", buffer);
-
-            if let Err(_) = stream.read(&mut buffer) {
-                break;
-            }
-
-            if is_websocket {
-                // WebSocketの処理
-                let frame = Frame::from(&buffer[..]);
-                // println!("frame: {:?}", frame);
-                if frame.opcode == Opcode::Text {
-                    println!("Text");
-                    let payload = echo(frame.payload.as_slice());
-                    let response = Frame::new(Opcode::Text, Some(payload));
-
-                    stream.write(&response.clone().to_bytes()).unwrap();
-                    stream.flush().unwrap();
-
-                    sleep(Duration::from_secs(3));
-
-                    stream.write(&response.clone().to_bytes()).unwrap();
-                    stream.flush().unwrap();
-                } else if frame.opcode == Opcode::Close {
-                    println!("Close");
-                    let response = Frame::new(Opcode::Close, None);
-                    stream.write(&response.to_bytes()).unwrap();
-                    stream.flush().unwrap();
-                    break;
-                } else {
-                    todo!("impl of opcode: {:?}", frame.opcode)
-                }
-            } else {
-                // HTTPの処理
-                //
-                // 以下のようなリクエストが来る:
-                // GET ws://127.0.0.1:7778/ HTTP/1.1
-                // Host: 127.0.0.1:7778
-                // Connection: Upgrade
-                // Upgrade: websocket
-                // Sec-WebSocket-Version: 13
-                // Sec-WebSocket-Key: 9Kl3Zz3tA0ibMWQwyn/9kQ==
-                // Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits
-                //
-                // 以下のようなレスポンスを返す:
-                // HTTP/1.1 101 OK
-                // Upgrade: websocket
-                // Connection: upgrade
-                // Sec-WebSocket-Accept: EK2cqLXRG/oxQwrUdEVXGrPDBuA=
-
-                let mut method = None;
-                let mut upgrade = None;
-                let mut connection = None;
-                let mut sec_websocket_version = None;
-                let mut sec_websocket_key = None;
-
-                // HTTPのヘッダーをパース
-                let request_text = String::from_utf8_lossy(&buffer[..]);
-                for (i, line) in request_text.lines().enumerate() {
-                    if i == 0 {
-                        let values = line.split(" ").map(|s| s.trim()).collect::<Vec<&str>>();
-                        method = Some(values[0]);
-                        continue;
-                    }
-
-                    if line == "" {
-                        break;
-                    }
-
-                    let values = line.split(":").map(|s| s.trim()).collect::<Vec<&str>>();
-                    let key = values[0].to_ascii_lowercase();
-                    let value = values[1];
-
-                    if key == "upgrade" {
-                        upgrade = Some(value);
-                    }
-
-                    if key == "connection" {
-                        connection = Some(value);
-                    }
-
-                    if key == "sec-websocket-version" {
-                        sec_websocket_version = Some(value);
-                    }
-
-                    if key == "sec-websocket-key" {
-                        sec_websocket_key = Some(value);
-                    }
-                }
+    let mut config = Config::default();
+    let mut admin = None;
+    let mut admin_token = None;
+    let mut control = None;
+    let mut otlp = None;
+    let mut redis = None;
+    let mut nats = None;
+    let mut mqtt = None;
+    let mut routes = vec![];
+    let mut tunnels = vec![];
+    let mut cluster = None;
+    let mut history = 0;
+    let mut fanout_batch = None;
+    let mut read_only = vec![];
+    let mut journal = None;
+    let mut rate_limit = None;
+    let mut censor = None;
+    let mut schemas = vec![];
+    let mut echo_mode = EchoMode::Suffix;
+    let mut script = None;
+    #[cfg(feature = "jwt")]
+    let mut jwt: Option<websocket_rs::jwt::Jwt> = None;
+    let mut basic_auth = None;
+    let mut ban_after = None;
+    let mut ban_cooldown = Duration::from_secs(600);
+    let mut statsd = None;
+    let mut statsd_tags = vec![];
+    let mut geoip = None;
+    let mut geoip_allow: Vec<String> = vec![];
+    #[cfg(feature = "deflate")]
+    let mut deflate = None;
+    #[cfg(feature = "tls")]
+    let (mut tls_cert, mut tls_key, mut tls_client_ca) = (None, None, None);
+    #[cfg(feature = "tls")]
+    let mut tls_sni = vec![];
+    #[cfg(feature = "tls")]
+    let mut tls_alpn = None;
+    #[cfg(feature = "tls")]
+    let mut tls_watch = None;
+    #[cfg(feature = "acme")]
+    let (mut acme_domains, mut acme_email) = (None, None);
+    #[cfg(feature = "acme")]
+    let mut acme_cache = "acme".to_string();
+    #[cfg(feature = "acme")]
+    let mut acme_directory = websocket_rs::acme::Acme::LETS_ENCRYPT.to_string();
+    let mut daemon = false;
+    let mut pidfile = "websocket-rs.pid".to_string();
+    let mut log_file = "websocket-rs.log".to_string();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // バックグラウンドで動かす。pidファイルとログの出力先も指定できる
+            // (例: --daemon --pidfile /var/run/websocket-rs.pid --log-file /var/log/websocket-rs.log)
+            "--daemon" => daemon = true,
+            "--pidfile" => pidfile = args.next().expect("--pidfile requires a path"),
+            "--log-file" => log_file = args.next().expect("--log-file requires a path"),
+            // 管理APIのHTTPエンドポイント (例: --admin 127.0.0.1:7779)。
+            // --admin-token を付けなければloopbackからのリクエストだけを受け付ける
+            "--admin" => admin = Some(args.next().expect("--admin requires an address")),
+            "--admin-token" => {
+                admin_token = Some(args.next().expect("--admin-token requires a token"))
+            }
+            // wsctl から操作するための制御ソケット (例: --control /tmp/websocket-rs.sock)
+            "--control" => control = Some(args.next().expect("--control requires a path")),
+            // http://127.0.0.1:7778/dashboard で統計情報を表示する
+            "--dashboard" => config.dashboard = true,
+            // WebSocketを使えないクライアント向けのlong-polling (例: --long-polling)
+            "--long-polling" => config.long_polling = true,
+            // roomのメッセージをServer-Sent Eventsで流す (例: curl http://127.0.0.1:7778/events/lobby)
+            "--sse" => config.sse = true,
+            // 接続ごとの送受信フレームを記録する (例: --record /tmp/wsrec)
+            "--record" => {
+                let dir = args.next().expect("--record requires a directory");
+                config.record_dir = Some(dir.into());
+            }
+            // ログ (例: --log-level trace --log-format json --log-target websocket_rs::frame --hexdump-len 32)
+            "--log-level" => {
+                let level = args.next().expect("--log-level requires a level");
+                log::set_level(level.parse().unwrap());
+            }
+            "--log-format" => {
+                let format = args.next().expect("--log-format requires text or json");
+                log::set_format(format.parse().unwrap());
+            }
+            "--log-target" => {
+                let target = args.next().expect("--log-target requires a target");
+                log::set_targets([target]);
+            }
+            "--hexdump-len" => {
+                let len = args.next().expect("--hexdump-len requires a length");
+                trace::set_hexdump_len(len.parse().unwrap());
+            }
+            // OTLP/HTTPでspanを送信する (例: --otlp http://127.0.0.1:4318/v1/traces)
+            "--otlp" => otlp = Some(args.next().expect("--otlp requires an endpoint")),
+            // pathのprefixごとにupstreamのWebSocketサーバーへ中継する (例: --proxy /chat=ws://127.0.0.1:9000/rooms)
+            "--proxy" => {
+                let route = args.next().expect("--proxy requires prefix=url");
+                let (prefix, upstream) =
+                    route.split_once('=').expect("--proxy requires prefix=url");
+                routes.push((prefix.to_string(), upstream.to_string()));
+            }
+            // pathへの接続をTCPのアドレスにトンネルする (例: --tunnel /ssh=127.0.0.1:22)
+            "--tunnel" => {
+                let route = args.next().expect("--tunnel requires path=address");
+                let (path, target) = route
+                    .split_once('=')
+                    .expect("--tunnel requires path=address");
+                tunnels.push((path.to_string(), target.to_string()));
+            }
+            // サブプロトコル mqtt の接続をMQTTブローカーに中継する (例: --mqtt 127.0.0.1:1883)
+            "--mqtt" => mqtt = Some(args.next().expect("--mqtt requires an address")),
+            // roomへのpublishをRedis経由で他のサーバーと共有する (例: --redis 127.0.0.1:6379)
+            "--redis" => redis = Some(args.next().expect("--redis requires an address")),
+            // Redisの代わりにNATSを使う (例: --nats 127.0.0.1:4222)
+            "--nats" => nats = Some(args.next().expect("--nats requires an address")),
+            // roomごとに直近のメッセージを保持し、新しく参加した接続に送る (例: --history 20)
+            "--history" => {
+                let len = args.next().expect("--history requires a length");
+                history = len.parse().unwrap();
+            }
+            // broadcastで指定した人数に送るごとに他の配送へ譲る。0なら譲らない (例: --fanout-batch 32)
+            "--fanout-batch" => {
+                let batch = args.next().expect("--fanout-batch requires a count");
+                fanout_batch = Some(batch.parse().unwrap());
+            }
+            // パターンに一致するroomへのクライアントからのpublishを拒否する。複数指定できる (例: --read-only 'news.#')
+            "--read-only" => {
+                read_only.push(args.next().expect("--read-only requires a room pattern"));
+            }
+            // 配送したメッセージをディスクに記録し、再起動時に履歴として読み込む (例: --journal /tmp/wsjournal)
+            "--journal" => journal = Some(args.next().expect("--journal requires a directory")),
+            // 切断から指定した秒数以内の再接続ならセッションを再開する (例: --session-grace 30)
+            "--session-grace" => {
+                let secs = args.next().expect("--session-grace requires seconds");
+                config.session_grace = Some(Duration::from_secs(secs.parse().unwrap()));
+            }
+            // クラスタモード (例: --cluster node1@127.0.0.1:7878 --cluster-secret <secret> --cluster-peers 127.0.0.1:7879,127.0.0.1:7880)
+            "--cluster" => {
+                let node = args.next().expect("--cluster requires name@address");
+                let (name, listen) = node
+                    .split_once('@')
+                    .expect("--cluster requires name@address");
+                cluster = Some(ClusterConfig {
+                    name: name.to_string(),
+                    listen: listen.parse().expect("invalid --cluster address"),
+                    discovery: Discovery::Static(vec![]),
+                    heartbeat: Duration::from_secs(1),
+                    secret: vec![],
+                });
+            }
+            // ノード間の認証に使う共有鍵。全てのノードで同じにする
+            "--cluster-secret" => {
+                let secret = args.next().expect("--cluster-secret requires a secret");
+                let config = cluster
+                    .as_mut()
+                    .expect("--cluster-secret requires --cluster");
+                config.secret = secret.into_bytes();
+            }
+            "--cluster-peers" => {
+                let peers = args.next().expect("--cluster-peers requires addresses");
+                let config = cluster
+                    .as_mut()
+                    .expect("--cluster-peers requires --cluster");
+                config.discovery = Discovery::Static(peers.split(',').map(String::from).collect());
+            }
+            // DNSの名前からノードを見つける (例: --cluster-dns websocket-rs.internal:7878)
+            "--cluster-dns" => {
+                let name = args.next().expect("--cluster-dns requires name:port");
+                let config = cluster.as_mut().expect("--cluster-dns requires --cluster");
+                config.discovery = Discovery::Dns(name);
+            }
+            // handshakeのリクエストを読み終えるまでの秒数 (例: --handshake-timeout 5)
+            "--handshake-timeout" => {
+                let secs = args.next().expect("--handshake-timeout requires seconds");
+                config.handshake_limits.timeout = Duration::from_secs(secs.parse().unwrap());
+            }
+            // 送信するメッセージを指定したbyte数ごとのフレームに分ける (例: --fragment-size 16384)
+            "--fragment-size" => {
+                let size = args.next().expect("--fragment-size requires bytes");
+                config.fragment_size = Some(size.parse().unwrap());
+            }
+            // 接続ごとの送信キューの長さと、一杯になったときの動作 (例: --send-queue 256,drop-oldest)
+            // 動作は close (1008で切断), try-again (1013で切断), drop-oldest, drop-newest のどれか
+            "--send-queue" => {
+                let value = args.next().expect("--send-queue requires a capacity");
+                let (capacity, policy) = value.split_once(',').unwrap_or((&value, "close"));
+                let policy = match policy {
+                    "close" => SlowConsumerPolicy::Close(1008),
+                    "try-again" => SlowConsumerPolicy::Close(1013),
+                    "drop-oldest" => SlowConsumerPolicy::DropOldest,
+                    "drop-newest" => SlowConsumerPolicy::DropNewest,
+                    _ => panic!("unknown --send-queue policy: {}", policy),
+                };
+                config.send_queue = Some(SendQueue {
+                    capacity: capacity.parse().unwrap(),
+                    policy,
+                });
+            }
+            // 受信するメッセージの最大のbyte数 (例: --max-message-size 1048576)
+            "--max-message-size" => {
+                let max = args.next().expect("--max-message-size requires bytes");
+                config.max_message_size = Some(max.parse().unwrap());
+            }
+            // 1つの接続から1秒間に受信するPing・Pong・Closeの数 (例: --max-control-frames 20)
+            "--max-control-frames" => {
+                let max = args.next().expect("--max-control-frames requires a count");
+                config.control_frame_limit.max = max.parse().unwrap();
+            }
+            // 指定した秒数なにも受信しなかった接続にPingを送り、さらに応答がなければ切断する
+            // (例: --keepalive 30 --keepalive-timeout 10)
+            "--keepalive" => {
+                let secs = args.next().expect("--keepalive requires seconds");
+                config
+                    .keepalive
+                    .get_or_insert_with(Keepalive::default)
+                    .interval = Duration::from_secs(secs.parse().unwrap());
+            }
+            "--keepalive-timeout" => {
+                let secs = args.next().expect("--keepalive-timeout requires seconds");
+                config
+                    .keepalive
+                    .get_or_insert_with(Keepalive::default)
+                    .timeout = Duration::from_secs(secs.parse().unwrap());
+            }
+            // permessage-deflate を合意する。指定した大きさより小さいメッセージは圧縮しない
+            // (例: --deflate --deflate-min-size 256)
+            #[cfg(feature = "deflate")]
+            "--deflate" => deflate = Some(deflate.unwrap_or_default()),
+            #[cfg(feature = "deflate")]
+            "--deflate-min-size" => {
+                let bytes = args.next().expect("--deflate-min-size requires bytes");
+                deflate = Some(bytes.parse().unwrap());
+            }
+            // 受け付けた接続にOSのTCP keepaliveを設定する。指定した秒数なにも届かなければprobeを送る
+            // (例: --tcp-keepalive 60)
+            #[cfg(feature = "tcp-keepalive")]
+            "--tcp-keepalive" => {
+                let secs = args.next().expect("--tcp-keepalive requires seconds");
+                config.tcp_keepalive = Some(websocket_rs::keepalive::TcpKeepalive {
+                    idle: Duration::from_secs(secs.parse().unwrap()),
+                    ..Default::default()
+                });
+            }
+            // TLSで待ち受ける。--tls-client-ca を指定するとクライアント証明書を必須にする
+            // (例: --tls-cert cert.pem --tls-key key.pem --tls-client-ca ca.pem)
+            #[cfg(feature = "tls")]
+            "--tls-cert" => tls_cert = Some(args.next().expect("--tls-cert requires a path")),
+            #[cfg(feature = "tls")]
+            "--tls-key" => tls_key = Some(args.next().expect("--tls-key requires a path")),
+            #[cfg(feature = "tls")]
+            "--tls-client-ca" => {
+                tls_client_ca = Some(args.next().expect("--tls-client-ca requires a path"));
+            }
+            // 証明書と秘密鍵のファイルが更新されたら読み直す。指定した秒数ごとに確かめる
+            // (例: --tls-watch 60)
+            #[cfg(feature = "tls")]
+            "--tls-watch" => {
+                let secs = args.next().expect("--tls-watch requires seconds");
+                tls_watch = Some(Duration::from_secs(secs.parse().unwrap()));
+            }
+            // ALPNで提示するプロトコル (例: --tls-alpn http/1.1)
+            #[cfg(feature = "tls")]
+            "--tls-alpn" => tls_alpn = Some(args.next().expect("--tls-alpn requires protocols")),
+            // --tls-cert の代わりにACMEで証明書を取得する。443番ポートで待ち受けること
+            // (例: --acme-domain chat.example.org --acme-email admin@example.org)
+            #[cfg(feature = "acme")]
+            "--acme-domain" => {
+                acme_domains = Some(args.next().expect("--acme-domain requires domains"));
+            }
+            #[cfg(feature = "acme")]
+            "--acme-email" => {
+                acme_email = Some(args.next().expect("--acme-email requires an email"))
+            }
+            // 鍵と証明書を保存するディレクトリ (例: --acme-cache /var/lib/websocket-rs/acme)
+            #[cfg(feature = "acme")]
+            "--acme-cache" => acme_cache = args.next().expect("--acme-cache requires a path"),
+            // ACMEのdirectoryのURL。デフォルトはLet's Encrypt (例: --acme-directory https://acme-staging-v02.api.letsencrypt.org/directory)
+            #[cfg(feature = "acme")]
+            "--acme-directory" => {
+                acme_directory = args.next().expect("--acme-directory requires a url");
+            }
+            // SNIのホスト名ごとの証明書と秘密鍵 (例: --tls-sni example.org=example.pem,example.key)
+            #[cfg(feature = "tls")]
+            "--tls-sni" => {
+                let sni = args.next().expect("--tls-sni requires host=cert,key");
+                let (host, files) = sni
+                    .split_once('=')
+                    .expect("--tls-sni requires host=cert,key");
+                let (cert, key) = files
+                    .split_once(',')
+                    .expect("--tls-sni requires host=cert,key");
+                tls_sni.push((host.to_string(), cert.to_string(), key.to_string()));
+            }
+            // 違反の種類ごとに接続を閉じるstatus codeを変える。`:reason` を付けるとエラーの内容も送る
+            // (例: --close-code invalid-utf8=1002 --close-code too-big=1008:reason)
+            // 種類は invalid-utf8, too-big, unmasked, reserved-opcode, protocol, policy
+            "--close-code" => {
+                let rule = args.next().expect("--close-code requires kind=code");
+                let (kind, action) = rule
+                    .split_once('=')
+                    .expect("--close-code requires kind=code");
+                let (code, reason) = match action.strip_suffix(":reason") {
+                    Some(code) => (code, true),
+                    None => (action, false),
+                };
+                config.close_policy =
+                    config
+                        .close_policy
+                        .set(kind.parse().unwrap(), code.parse().unwrap(), reason);
+            }
+            // 1分間に指定した回数の違反 (不正なフレーム・認証の失敗など) をしたアドレスを拒否する
+            // (例: --ban-after 5 --ban-cooldown 600)
+            "--ban-after" => {
+                let max = args.next().expect("--ban-after requires a count");
+                ban_after = Some(max.parse().unwrap());
+            }
+            "--ban-cooldown" => {
+                let secs = args.next().expect("--ban-cooldown requires seconds");
+                ban_cooldown = Duration::from_secs(secs.parse().unwrap());
+            }
+            // statsd・Datadog Agentにメトリクスを送る (例: --statsd 127.0.0.1:8125 --statsd-tags env:prod,region:tokyo)
+            "--statsd" => statsd = Some(args.next().expect("--statsd requires an address")),
+            "--statsd-tags" => {
+                let tags = args.next().expect("--statsd-tags requires key:value pairs");
+                statsd_tags.extend(tags.split(',').filter_map(|tag| {
+                    let (key, value) = tag.split_once(':')?;
+                    Some((key.to_string(), value.to_string()))
+                }));
+            }
+            // GeoLite2のCSV (mmdb featureならmmdbも) を置いたディレクトリ。接続元の国で制限する場合は --geoip-allow を付ける
+            // (例: --geoip /var/lib/geoip --geoip-allow JP,US)
+            "--geoip" => geoip = Some(args.next().expect("--geoip requires a directory")),
+            "--geoip-allow" => {
+                let countries = args.next().expect("--geoip-allow requires country codes");
+                geoip_allow.extend(countries.split(',').map(str::to_string));
+            }
+            // 接続を受け付けるアドレスの範囲 (例: --allow 10.0.0.0/8 --deny 10.0.9.0/24)
+            "--allow" => {
+                let cidr = args.next().expect("--allow requires a CIDR");
+                let filter = config.ip_filter.take().unwrap_or_default();
+                config.ip_filter = Some(filter.allow(cidr.parse().unwrap()));
+            }
+            "--deny" => {
+                let cidr = args.next().expect("--deny requires a CIDR");
+                let filter = config.ip_filter.take().unwrap_or_default();
+                config.ip_filter = Some(filter.deny(cidr.parse().unwrap()));
+            }
+            // IPアドレスごとに1分間に受け付けるhandshakeの回数 (例: --rate-limit 30)
+            "--rate-limit" => {
+                let max = args.next().expect("--rate-limit requires a count");
+                rate_limit = Some(max.parse().unwrap());
+            }
+            // room以外へのTextメッセージの返し方 (例: --echo-mode uppercase)
+            // suffix (デフォルト), plain, uppercase, reverse, delayed:<ミリ秒>, fanout
+            "--echo-mode" => {
+                let mode = args.next().expect("--echo-mode requires a mode");
+                echo_mode = mode.parse().unwrap();
+            }
+            // メッセージの処理を定義したスクリプトを読み込む (例: --script rules.wsscript)。
+            // rhai featureでは、拡張子が .rhai ならRhaiのスクリプトとして読み込む
+            "--script" => script = Some(args.next().expect("--script requires a path")),
+            // handshakeでBasic認証を行う (例: --basic-auth alice:secret)
+            "--basic-auth" => {
+                let credentials = args.next().expect("--basic-auth requires user:password");
+                let (user, password) = credentials
+                    .split_once(':')
+                    .expect("--basic-auth requires user:password");
+                basic_auth = Some(BasicAuth::new("websocket-rs", user, password));
+            }
+            // handshakeでJWTを検証する (jwt feature。例: --jwt-secret secret, --jwt-pem public.pem, --jwt-jwks jwks.json,
+            // --jwt-jwks-url https://auth.example.org/.well-known/jwks.json。http:// は --jwt-jwks-url-insecure)
+            #[cfg(feature = "jwt")]
+            "--jwt-secret" => {
+                let secret = args.next().expect("--jwt-secret requires a secret");
+                jwt = Some(jwt.unwrap_or_default().hs256(secret.as_bytes()));
+            }
+            #[cfg(feature = "jwt")]
+            "--jwt-pem" => {
+                let path = args.next().expect("--jwt-pem requires a path");
+                let pem = std::fs::read_to_string(path).expect("failed to read --jwt-pem");
+                jwt = Some(jwt.unwrap_or_default().rs256_pem(&pem).unwrap());
+            }
+            #[cfg(feature = "jwt")]
+            "--jwt-jwks" => {
+                let path = args.next().expect("--jwt-jwks requires a path");
+                let jwks = std::fs::read_to_string(path).expect("failed to read --jwt-jwks");
+                jwt = Some(jwt.unwrap_or_default().jwks(&jwks).unwrap());
+            }
+            #[cfg(feature = "jwt")]
+            "--jwt-jwks-url" => {
+                let url = args.next().expect("--jwt-jwks-url requires a url");
+                jwt = Some(jwt.unwrap_or_default().jwks_url(&url).unwrap());
+            }
+            #[cfg(feature = "jwt")]
+            "--jwt-jwks-url-insecure" => {
+                let url = args.next().expect("--jwt-jwks-url-insecure requires a url");
+                jwt = Some(jwt.unwrap_or_default().jwks_url_insecure(&url).unwrap());
+            }
+            // pathが指定したprefixで始まる接続で受信するメッセージをJSON Schemaで検証する。
+            // --schemaはエラーを返し、--schema-closeは1008で閉じる (例: --schema /rooms/=message.schema.json)
+            flag @ ("--schema" | "--schema-close") => {
+                let rule = args.next().expect("--schema requires prefix=path");
+                let (prefix, path) = rule.split_once('=').expect("--schema requires prefix=path");
+                let schema = Schema::load(path).expect("failed to load --schema");
+                let on_invalid = match flag {
+                    "--schema" => OnInvalid::Reply,
+                    _ => OnInvalid::Close,
+                };
+                schemas.push((prefix.to_string(), schema, on_invalid));
+            }
+            // roomで送受信するメッセージの単語を伏せ字にする (例: --censor foo,bar)
+            "--censor" => {
+                let words = args.next().expect("--censor requires words");
+                censor = Some(Censor::new(words.split(',').map(String::from)));
+            }
+            // 障害注入 (例: --chaos-latency 3000 --chaos-duplicate 1.0)
+            "--chaos-latency" => {
+                let ms = args.next().expect("--chaos-latency requires milliseconds");
+                chaos(&mut config).latency = Duration::from_millis(ms.parse().unwrap());
+            }
+            "--chaos-duplicate" => {
+                let p = args
+                    .next()
+                    .expect("--chaos-duplicate requires a probability");
+                chaos(&mut config).duplicate = p.parse().unwrap();
+            }
+            "--chaos-drop-pong" => {
+                let p = args
+                    .next()
+                    .expect("--chaos-drop-pong requires a probability");
+                chaos(&mut config).drop_pong = p.parse().unwrap();
+            }
+            "--chaos-reset" => {
+                let p = args.next().expect("--chaos-reset requires a probability");
+                chaos(&mut config).reset = p.parse().unwrap();
+            }
+            _ => panic!("unknown option: {}", arg),
+        }
+    }
+
+    if daemon {
+        daemonize(&pidfile, &log_file)?;
+    }
 
-                // TODO: validation of request
-                println!("method: {:?}", method);
-                println!("upgrade: {:?}", upgrade);
-                println!("connection: {:?}", connection);
-                println!("sec_websocket_version: {:?}", sec_websocket_version);
-                println!("sec_websocket_key: {:?}", sec_websocket_key);
-
-                let rfc_defined_uuid = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-                let plain_text = format!("{}{}", sec_websocket_key.unwrap(), rfc_defined_uuid);
-
-                let mut hasher = Sha1::new();
-                hasher.update(plain_text);
-                let sec_websocket_accept = general_purpose::STANDARD.encode(hasher.finalize());
-
-                let response = format!(
-                    "HTTP/1.1 101 OK\r\n\
-                    Upgrade: websocket\r\n\
-                    Connection: Upgrade\r\n\
-                    Sec-WebSocket-Accept: {}\r\n\
-                    \r\n",
-                    sec_websocket_accept
-                );
-
-                stream.write(response.as_bytes()).unwrap();
-                stream.flush().unwrap();
-                is_websocket = true;
+    // STOMPのクライアントはサブプロトコル v12.stomp で、GraphQLのクライアントは graphql-transport-ws で接続する
+    let echo = Echo {
+        mode: echo_mode,
+        rpc: rpc_methods(),
+        muxes: Mutex::default(),
+    };
+    // スクリプトのルールに一致しなかったメッセージはEchoが処理する
+    let echo: Box<dyn Handler> = match script {
+        #[cfg(feature = "rhai")]
+        Some(path) if path.ends_with(".rhai") => Box::new(
+            websocket_rs::script::RhaiScript::load_with_fallback(&path, echo)
+                .expect("failed to load --script"),
+        ),
+        Some(path) => {
+            Box::new(Script::load_with_fallback(&path, echo).expect("failed to load --script"))
+        }
+        None => Box::new(echo),
+    };
+    let handler = Stomp::with_fallback(GraphQlWs::with_fallback(
+        GraphQlEcho,
+        SocketIo::with_fallback(SocketIoEcho, echo),
+    ));
+    let mut handler: Box<dyn Handler> = match mqtt {
+        Some(broker) => Box::new(MqttBridge::with_fallback(&broker, handler)),
+        None => Box::new(handler),
+    };
+    if !tunnels.is_empty() {
+        handler = Box::new(tunnels.iter().fold(
+            TcpTunnel::with_fallback(handler),
+            |tunnel, (path, target)| tunnel.route(path, target),
+        ));
+    }
+    if !routes.is_empty() {
+        handler = Box::new(routes.iter().fold(
+            Proxy::with_fallback(handler),
+            |proxy, (prefix, upstream)| proxy.route(prefix, upstream),
+        ));
+    }
+    // 前のプロセスから待ち受けソケットを引き継いでいればそれを使う (`restart` を参照)
+    #[cfg(unix)]
+    let server = match websocket_rs::restart::inherited()? {
+        Some(listener) => Server::from_listener(listener, handler)?,
+        None => Server::bind("127.0.0.1:7778", handler)?,
+    };
+    #[cfg(not(unix))]
+    let server = Server::bind("127.0.0.1:7778", handler)?;
+    let mut server = server.with_config(config).with_layer(middleware::Log);
+    if let Some(dir) = geoip {
+        let dir = std::path::Path::new(&dir);
+        // mmdbは `mmdb` featureのときだけ読み込む。CSVとmmdbがどちらもあれば両方読み込む
+        let mmdb = [
+            "GeoLite2-Country.mmdb",
+            "GeoLite2-City.mmdb",
+            "GeoLite2-ASN.mmdb",
+        ]
+        .map(|name| dir.join(name))
+        .into_iter()
+        .filter(|path| cfg!(feature = "mmdb") && path.exists())
+        .collect::<Vec<_>>();
+        #[cfg_attr(not(feature = "mmdb"), allow(unused_mut))]
+        let mut geoip = GeoIp::new();
+        #[cfg(feature = "mmdb")]
+        for path in &mmdb {
+            geoip = geoip.load_mmdb(path).expect("failed to load --geoip");
+        }
+        let locations = dir.join("GeoLite2-Country-Locations-en.csv");
+        let geoip = if mmdb.is_empty() || locations.exists() {
+            geoip
+                .load_countries(
+                    &[
+                        dir.join("GeoLite2-Country-Blocks-IPv4.csv"),
+                        dir.join("GeoLite2-Country-Blocks-IPv6.csv"),
+                    ],
+                    locations,
+                )
+                .expect("failed to load --geoip")
+        } else {
+            geoip
+        };
+        // ASNのファイルはなくてもよい
+        let asn = [
+            dir.join("GeoLite2-ASN-Blocks-IPv4.csv"),
+            dir.join("GeoLite2-ASN-Blocks-IPv6.csv"),
+        ];
+        let geoip = if asn.iter().all(|path| path.exists()) {
+            geoip.load_asns(&asn).expect("failed to load --geoip")
+        } else {
+            geoip
+        };
+        let layer = geoip_allow
+            .iter()
+            .fold(geoip.layer(), |layer, country| layer.allow_country(country));
+        server = server.with_layer(layer);
+    }
+    #[cfg(feature = "deflate")]
+    if let Some(min_size) = deflate {
+        server = server.with_extension(websocket_rs::deflate::Deflate::new().min_size(min_size));
+    }
+    #[cfg(feature = "tls")]
+    let tls = match tls_cert {
+        Some(cert) => {
+            let key = tls_key.expect("--tls-cert requires --tls-key");
+            Some(websocket_rs::tls::TlsConfig::from_pem_files(cert, key)?)
+        }
+        None => None,
+    };
+    #[cfg(feature = "acme")]
+    let tls = match acme_domains {
+        Some(domains) if tls.is_none() => {
+            let domains = domains.split(',').map(str::trim).collect::<Vec<_>>();
+            let mut acme = websocket_rs::acme::Acme::new(&acme_directory, &domains, acme_cache);
+            if let Some(email) = acme_email {
+                acme = acme.contact(&email);
             }
+            Some(websocket_rs::tls::TlsConfig::from_acme(acme)?)
+        }
+        Some(_) => panic!("--acme-domain cannot be used with --tls-cert"),
+        None => tls,
+    };
+    #[cfg(feature = "tls")]
+    if let Some(mut tls) = tls {
+        for (host, cert, key) in tls_sni {
+            tls = tls.certificate_files_for(&host, cert, key)?;
+        }
+        if let Some(protocols) = tls_alpn {
+            let protocols = protocols.split(',').map(str::trim).collect::<Vec<_>>();
+            tls = tls.alpn_protocols(&protocols);
+        }
+        if let Some(interval) = tls_watch {
+            tls.reloader().watch(interval);
         }
+        if let Some(ca) = tls_client_ca {
+            tls = tls.require_client_cert(&std::fs::read(ca)?)?;
+        }
+        server = server.with_tls(tls)?;
+    }
+    if let Some(max) = ban_after {
+        let bans = BanList::new(max, Duration::from_secs(60), ban_cooldown);
+        server = server.with_ban_list(Arc::new(bans));
+    }
+    if let Some(basic_auth) = basic_auth {
+        server = server.with_layer(basic_auth);
+    }
+    #[cfg(feature = "jwt")]
+    if let Some(jwt) = jwt {
+        server = server.with_layer(jwt);
+    }
+    if let Some(censor) = censor {
+        server = server.with_interceptor("/rooms/", censor);
+    }
+    for (prefix, schema, on_invalid) in schemas {
+        server = server.with_validator(&prefix, schema, on_invalid);
+    }
+    if let Some(max) = rate_limit {
+        server = server.with_layer(RateLimit::new(max, Duration::from_secs(60)));
+    }
+    if let Some(endpoint) = otlp {
+        let exporter =
+            OtlpExporter::new(&endpoint, "websocket-rs").expect("invalid --otlp endpoint");
+        server = server.with_span_exporter(exporter);
+    }
+    if let Some(addr) = redis {
+        let backend =
+            RedisBackend::connect(&addr, "websocket-rs:").expect("failed to connect to redis");
+        server = server.with_hub(Hub::with_backend(backend).expect("failed to subscribe to redis"));
+    }
+    if let Some(addr) = nats {
+        let backend =
+            NatsBackend::connect(&addr, "websocket-rs").expect("failed to connect to nats");
+        server = server.with_hub(Hub::with_backend(backend).expect("failed to subscribe to nats"));
+    }
+    server.hub().set_presence_events(true);
+    server.hub().set_history_len(history);
+    server.hub().set_replay_history(history > 0);
+    if let Some(batch) = fanout_batch {
+        server.hub().set_fanout_batch(batch);
+    }
+    if !read_only.is_empty() {
+        server.hub().set_authorizer(ReadOnly(read_only));
+    }
+    if let Some(dir) = journal {
+        let journal = Journal::open(JournalConfig::new(dir)).expect("failed to open --journal");
+        server
+            .hub()
+            .set_journal(journal)
+            .expect("failed to read --journal");
+    }
+    if let Some(config) = cluster {
+        Cluster::start(config, server.admin(), server.hub())?;
+    }
+    if let Some(addr) = admin {
+        match &admin_token {
+            Some(token) => server.admin().serve_with_token(addr, token)?,
+            None => server.admin().serve(addr)?,
+        };
+    }
+    if let Some(addr) = statsd {
+        let exporter = statsd_tags
+            .iter()
+            .fold(StatsdExporter::new(&addr), |exporter, (key, value)| {
+                exporter.tag(key, value)
+            });
+        exporter.start(server.admin())?;
+    }
+    #[cfg(unix)]
+    if let Some(path) = control {
+        server.admin().serve_control(path)?;
     }
 
-    Ok(())
+    server.run()
 }
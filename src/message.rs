@@ -0,0 +1,36 @@
+use alloc::{string::String, vec::Vec};
+
+use crate::frame::{Frame, Opcode};
+
+/// アプリケーションがやり取りするデータの単位 (1つ以上のフレームから成る)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Message {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Text(text) => text.as_bytes(),
+            Self::Binary(data) => data,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<Message> for Frame {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Text(text) => Frame::new(Opcode::Text, Some(text.into_bytes())),
+            Message::Binary(data) => Frame::new(Opcode::Binary, Some(data)),
+        }
+    }
+}
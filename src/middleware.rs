@@ -0,0 +1,271 @@
+// handshakeのmiddleware
+//
+// `Server::with_layer` で登録したLayerは、WebSocketのhandshakeを受け付けるかどうか決める前に、
+// 登録した順に外側から呼ばれる (towerのLayerと同じ):
+//   Log -> 認証 -> RateLimit -> (受け付ける)
+// 各Layerは `next.run(handshake)` で内側に処理を渡す。呼ばずに Err(Rejection) を返せばその場で拒否する。
+// nextの前後に処理を書けるので、リクエストのヘッダーを書き換えたり、内側の結果をログに残したりできる
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose, Engine as _};
+
+#[cfg(feature = "jwt")]
+use crate::jwt::Claims;
+#[cfg(feature = "tls")]
+use crate::tls::TlsInfo;
+use crate::{
+    connection::Extensions,
+    handshake::Request,
+    log::{debug, info},
+    session::Session,
+};
+
+/// 処理中のhandshake
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    /// Layerが書き換えたリクエストはそのまま後続の処理 (Handlerなど) に渡る
+    pub request: Request,
+    pub peer_addr: SocketAddr,
+    /// 101の応答に追加するヘッダー
+    pub response_headers: Vec<(String, String)>,
+    /// `Jwt` が検証したトークンのpayload。`Connection::claims` で取得できる
+    #[cfg(feature = "jwt")]
+    pub claims: Option<Claims>,
+    /// `SessionLayer` が読み込んだセッション。`Connection::session` で取得できる
+    pub session: Option<Session>,
+    /// 接続に紐づける値。`Connection::extensions` で取得できる
+    pub extensions: Extensions,
+    /// TLSで受け付けた接続の情報。クライアント証明書のsubject・SANで認可できる
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsInfo>,
+}
+
+/// handshakeを拒否する際に返すHTTPの応答
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejection {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Rejection {
+    /// 例: `Rejection::new(401, "Unauthorized")`
+    pub fn new(status: u16, reason: &str) -> Self {
+        Self {
+            status,
+            reason: reason.to_string(),
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: &str) -> Self {
+        self.body = body.to_string();
+        self
+    }
+
+    pub(crate) fn to_response(&self) -> String {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (key, value) in &self.headers {
+            response.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        response.push_str(&format!(
+            "Content-Type: text/plain\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\
+            \r\n\
+            {}",
+            self.body.len(),
+            self.body
+        ));
+        response
+    }
+}
+
+pub type Outcome = std::result::Result<(), Rejection>;
+
+pub trait Layer: Send + Sync + 'static {
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome;
+}
+
+/// `Fn(&mut Handshake, Next) -> Outcome` のclosureもLayerとして使える
+impl<F> Layer for F
+where
+    F: Fn(&mut Handshake, Next<'_>) -> Outcome + Send + Sync + 'static,
+{
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome {
+        self(handshake, next)
+    }
+}
+
+/// 内側のLayer
+pub struct Next<'a> {
+    layers: &'a [Box<dyn Layer>],
+}
+
+impl Next<'_> {
+    pub fn run(self, handshake: &mut Handshake) -> Outcome {
+        match self.layers.split_first() {
+            Some((layer, rest)) => layer.call(handshake, Next { layers: rest }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// 登録された全てのLayerを通す
+pub(crate) fn run(layers: &[Box<dyn Layer>], handshake: &mut Handshake) -> Outcome {
+    Next { layers }.run(handshake)
+}
+
+/// handshakeの結果と処理時間をログに残す
+pub struct Log;
+
+impl Layer for Log {
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome {
+        let started = Instant::now();
+        let peer = handshake.peer_addr.to_string();
+        let path = handshake.request.path.clone();
+        let outcome = next.run(handshake);
+        let elapsed = started.elapsed().as_micros() as u64;
+        match &outcome {
+            Ok(()) => debug!(
+                "handshake_accepted",
+                { peer: peer.as_str(), path: path.as_str(), elapsed_us: elapsed },
+                "handshake from {} to {} accepted ({}us)",
+                peer,
+                path,
+                elapsed
+            ),
+            Err(rejection) => info!(
+                "handshake_rejected",
+                {
+                    peer: peer.as_str(),
+                    path: path.as_str(),
+                    status: rejection.status,
+                    elapsed_us: elapsed,
+                },
+                "handshake from {} to {} rejected with {} ({}us)",
+                peer,
+                path,
+                rejection.status,
+                elapsed
+            ),
+        }
+        outcome
+    }
+}
+
+/// IPアドレスごとに、`window` の間に受け付けるhandshakeを `max` 回までに制限する。
+/// 超えたら 429 Too Many Requests で拒否する
+pub struct RateLimit {
+    max: u32,
+    window: Duration,
+    /// IPアドレス -> (windowの開始時刻, 回数)
+    hits: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Layer for RateLimit {
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome {
+        {
+            let now = Instant::now();
+            let mut hits = self.hits.lock().unwrap();
+            // 古いwindowは捨てる
+            hits.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+            let (start, count) = hits.entry(handshake.peer_addr.ip()).or_insert((now, 0));
+            if *count >= self.max {
+                let retry_after = self.window.saturating_sub(now.duration_since(*start));
+                return Err(Rejection::new(429, "Too Many Requests")
+                    .header("Retry-After", &retry_after.as_secs().max(1).to_string()));
+            }
+            *count += 1;
+        }
+        next.run(handshake)
+    }
+}
+
+type Verify = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// `Authorization: Basic` のユーザー名とパスワードを確認する。
+/// ないか間違っていれば `WWW-Authenticate` を付けて 401 で拒否する
+pub struct BasicAuth {
+    realm: String,
+    verify: Verify,
+}
+
+impl BasicAuth {
+    /// 1組のユーザー名とパスワードだけを受け付ける
+    pub fn new(realm: &str, user: &str, password: &str) -> Self {
+        let (user, password) = (user.to_string(), password.to_string());
+        Self::with_verifier(realm, move |u, p| {
+            // どちらが違っても同じ時間で比べる
+            constant_time_eq(u.as_bytes(), user.as_bytes())
+                & constant_time_eq(p.as_bytes(), password.as_bytes())
+        })
+    }
+
+    /// ユーザー名とパスワードを `verify` で確認する
+    pub fn with_verifier<F>(realm: &str, verify: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            realm: realm.to_string(),
+            verify: Box::new(verify),
+        }
+    }
+
+    fn credentials(request: &Request) -> Option<(String, String)> {
+        let encoded = request.header("authorization")?.strip_prefix("Basic ")?;
+        let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let (user, password) = String::from_utf8(decoded)
+            .ok()?
+            .split_once(':')
+            .map(|(user, password)| (user.to_string(), password.to_string()))?;
+        Some((user, password))
+    }
+}
+
+impl Layer for BasicAuth {
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome {
+        match Self::credentials(&handshake.request) {
+            Some((user, password)) if (self.verify)(&user, &password) => next.run(handshake),
+            _ => Err(Rejection::new(401, "Unauthorized").header(
+                "WWW-Authenticate",
+                &format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+            )),
+        }
+    }
+}
+
+/// 比較にかかる時間が内容に依存しない。長さが違っても長い方の最後まで比べる
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let len = a.len().max(b.len());
+    let diff = (0..len).fold((a.len() != b.len()) as u8, |acc, i| {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        acc | (x ^ y)
+    });
+    std::hint::black_box(diff) == 0
+}
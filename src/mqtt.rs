@@ -0,0 +1,277 @@
+// MQTT over WebSocket のブリッジ
+//
+// サブプロトコル `mqtt` で接続してきたクライアントごとにMQTTブローカーへTCPで接続し、
+// クライアントからのBinaryメッセージの中身をそのままブローカーへ、
+// ブローカーから届いたバイト列をBinaryメッセージとしてクライアントへ中継する。
+// MQTTのパケットの境界とWebSocketのメッセージの境界は一致しなくてよい (MQTT 3.1.1 6章)
+//
+// ブラウザのMQTTクライアント (MQTT.js など) から ws://host:port/mqtt のように接続できる
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    connection::{Connection, ConnectionId},
+    error::Error,
+    handler::Handler,
+    log::{debug, warning},
+    message::Message,
+};
+
+const PROTOCOL: &str = "mqtt";
+
+/// サブプロトコル `mqtt` の接続をMQTTブローカーに中継するHandler。
+/// それ以外の接続は `fallback` に渡す
+pub struct MqttBridge<H = ()> {
+    broker: String,
+    fallback: H,
+    /// 接続ごとのブローカーへのTCP接続
+    streams: Mutex<HashMap<ConnectionId, TcpStream>>,
+}
+
+impl MqttBridge {
+    /// `broker` はMQTTブローカーのアドレス (例: 127.0.0.1:1883)
+    pub fn new(broker: &str) -> Self {
+        Self::with_fallback(broker, ())
+    }
+}
+
+impl<H: Handler> MqttBridge<H> {
+    pub fn with_fallback(broker: &str, fallback: H) -> Self {
+        Self {
+            broker: broker.to_string(),
+            fallback,
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_mqtt(&self, id: ConnectionId) -> bool {
+        self.streams.lock().unwrap().contains_key(&id)
+    }
+
+    fn open(&self, conn: &mut Connection) -> std::io::Result<()> {
+        let stream = TcpStream::connect(&self.broker)?;
+        let mut reader = stream.try_clone()?;
+        self.streams.lock().unwrap().insert(conn.id(), stream);
+        debug!(
+            "mqtt_opened",
+            { connection_id: conn.id(), broker: self.broker.as_str() },
+            "connection {} bridged to mqtt broker {}",
+            conn.id(),
+            self.broker
+        );
+
+        // ブローカーからの受信は別スレッドで行う
+        let handle = conn.handle();
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if handle.send(Message::Binary(buffer[..n].to_vec())).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            // ブローカーから切断された
+            let _ = handle.close(1000, "");
+        });
+        Ok(())
+    }
+}
+
+impl<H: Handler> Handler for MqttBridge<H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        if offered.contains(&PROTOCOL) {
+            return Some(PROTOCOL.to_string());
+        }
+        self.fallback.select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        if conn.protocol() != Some(PROTOCOL) {
+            return self.fallback.on_open(conn);
+        }
+        if let Err(e) = self.open(conn) {
+            warning!(
+                "mqtt_error",
+                { connection_id: conn.id(), error: e.to_string() },
+                "mqtt: {}",
+                e
+            );
+            let _ = conn.close(1011, "mqtt broker unavailable");
+        }
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        if conn.protocol() != Some(PROTOCOL) {
+            return self.fallback.on_message(conn, message);
+        }
+        // MQTTのパケットはBinaryメッセージで送ることになっている
+        let Message::Binary(bytes) = message else {
+            let _ = conn.close(1003, "mqtt requires binary messages");
+            return;
+        };
+        let written = match self.streams.lock().unwrap().get_mut(&conn.id()) {
+            Some(stream) => stream.write_all(&bytes),
+            None => return,
+        };
+        if let Err(e) = written {
+            warning!(
+                "mqtt_error",
+                { connection_id: conn.id(), error: e.to_string() },
+                "mqtt: {}",
+                e
+            );
+            let _ = conn.close(1011, "mqtt broker unavailable");
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        if !self.is_mqtt(conn.id()) {
+            self.fallback.on_peer_close(conn, code, reason);
+        }
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        if !self.is_mqtt(conn.id()) {
+            self.fallback.on_pong(conn, payload, latency);
+        }
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        match self.streams.lock().unwrap().remove(&id) {
+            Some(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            None => self.fallback.on_close(id),
+        }
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        if !self.is_mqtt(id) {
+            self.fallback.on_error(id, error, terminated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        client::Client,
+        server::Server,
+        testing::{self, TestServer},
+    };
+
+    /// 受け取ったバイト列をそのまま返すブローカーの代わり
+    fn echo_broker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buffer = [0; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buffer) {
+                        if stream.write_all(&buffer[..n]).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// mqtt以外の接続に届いたメッセージを返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    fn spawn(broker: &str) -> TestServer {
+        let bridge = MqttBridge::with_fallback(broker, Echo);
+        testing::spawn_server(Server::bind("127.0.0.1:0", bridge).unwrap()).unwrap()
+    }
+
+    fn connect_mqtt(server: &TestServer) -> Client {
+        let client = Client::connect_with_protocols(&server.url("/mqtt"), &["mqtt"]).unwrap();
+        assert_eq!(client.protocol(), Some(PROTOCOL));
+        client
+    }
+
+    /// `len` bytes届くまで受信する。パケットの境界とメッセージの境界は一致しなくてよい
+    fn recv_bytes(client: &mut Client, len: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        while bytes.len() < len {
+            match client.recv().unwrap() {
+                Some(Message::Binary(chunk)) => bytes.extend(chunk),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn relays_bytes_to_and_from_the_broker() {
+        let server = spawn(&echo_broker());
+        let mut client = connect_mqtt(&server);
+        // CONNECT (MQTT 3.1.1, client id "a")
+        let connect = [
+            0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x01,
+            b'a',
+        ];
+        client.send(Message::Binary(connect.to_vec())).unwrap();
+        assert_eq!(recv_bytes(&mut client, connect.len()), connect);
+        // PINGREQ を2つのメッセージに分けて送っても、そのまま中継する
+        client.send(Message::Binary(vec![0xc0])).unwrap();
+        client.send(Message::Binary(vec![0x00])).unwrap();
+        assert_eq!(recv_bytes(&mut client, 2), [0xc0, 0x00]);
+    }
+
+    #[test]
+    fn closes_on_text_messages() {
+        let server = spawn(&echo_broker());
+        let mut client = connect_mqtt(&server);
+        client.send(Message::Text("CONNECT".to_string())).unwrap();
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close().unwrap().0, 1003);
+    }
+
+    #[test]
+    fn closes_when_the_broker_is_unavailable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let server = spawn(&broker);
+        let mut client = connect_mqtt(&server);
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close().unwrap().0, 1011);
+    }
+
+    #[test]
+    fn passes_other_connections_to_the_fallback() {
+        let server = spawn(&echo_broker());
+        let mut client = server.connect("/").unwrap();
+        assert_eq!(client.protocol(), None);
+        client.send(Message::Text("hello".to_string())).unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("hello".to_string()))
+        );
+    }
+}
@@ -0,0 +1,314 @@
+// 1つのWebSocketの接続の上に、独立した複数の論理チャネルを多重化する
+//
+// 全てのパケットはBinaryメッセージとして送る:
+// チャネルID (u32 BE) | 種類 (u8) | payload
+//
+// 種類:
+// 1: OPEN    チャネルを開く (payloadなし)
+// 2: TEXT    Textメッセージ
+// 3: BINARY  Binaryメッセージ
+// 4: CREDIT  送信してよいバイト数を増やす (u32 BE)
+// 5: CLOSE   チャネルを閉じる (payloadなし)
+//
+// チャネルごとにフロー制御を行う。送信側は相手から受け取ったCREDITの分だけ送信でき、
+// 使い切ったら `Channel::send` はCREDITが届くまで待つ。受信側はアプリケーションが `Channel::recv` で
+// 取り出した分だけCREDITを返すので、読まれないチャネルが他のチャネルを詰まらせることはない。
+// IDはクライアント側が奇数、サーバー側が偶数を使うので、双方から同時に開いても衝突しない
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+const OPEN: u8 = 1;
+const TEXT: u8 = 2;
+const BINARY: u8 = 3;
+const CREDIT: u8 = 4;
+const CLOSE: u8 = 5;
+
+/// チャネルを開いた直後に送信してよいバイト数
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+type SendFn = Box<dyn Fn(Message) -> Result<()> + std::marker::Send + Sync>;
+
+/// 多重化された接続。受信したメッセージは `receive` に渡す
+#[derive(Clone)]
+pub struct Mux {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    send: SendFn,
+    next_id: AtomicU32,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    channels: HashMap<u32, ChannelState>,
+    /// 相手が開いて、まだ `accept` されていないチャネル
+    incoming: VecDeque<u32>,
+    /// 下の接続が閉じた
+    shutdown: bool,
+}
+
+struct ChannelState {
+    inbox: VecDeque<Message>,
+    /// 送信してよい残りのバイト数
+    window: i64,
+    closed_by_peer: bool,
+    closed: bool,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            inbox: VecDeque::new(),
+            window: INITIAL_WINDOW as i64,
+            closed_by_peer: false,
+            closed: false,
+        }
+    }
+}
+
+/// 論理チャネル
+pub struct Channel {
+    id: u32,
+    inner: Arc<Inner>,
+}
+
+fn packet(id: u32, kind: u8, payload: &[u8]) -> Message {
+    let mut bytes = id.to_be_bytes().to_vec();
+    bytes.push(kind);
+    bytes.extend_from_slice(payload);
+    Message::Binary(bytes)
+}
+
+fn closed() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "channel closed",
+    ))
+}
+
+impl Mux {
+    /// `send` は下のWebSocketの接続にメッセージを送る関数
+    pub fn new<F>(side: Side, send: F) -> Self
+    where
+        F: Fn(Message) -> Result<()> + std::marker::Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(Inner {
+                send: Box::new(send),
+                next_id: AtomicU32::new(match side {
+                    Side::Client => 1,
+                    Side::Server => 2,
+                }),
+                state: Mutex::new(State::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// 新しいチャネルを開く
+    pub fn open(&self) -> Result<Channel> {
+        let id = self.inner.next_id.fetch_add(2, Ordering::Relaxed);
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.shutdown {
+                return Err(closed());
+            }
+            state.channels.insert(id, ChannelState::new());
+        }
+        (self.inner.send)(packet(id, OPEN, &[]))?;
+        Ok(self.channel(id))
+    }
+
+    /// 相手が開いたチャネルを待つ。接続が閉じたら None
+    pub fn accept(&self) -> Option<Channel> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(id) = state.incoming.pop_front() {
+                return Some(self.channel(id));
+            }
+            if state.shutdown {
+                return None;
+            }
+            state = self.inner.changed.wait(state).unwrap();
+        }
+    }
+
+    /// 受信したメッセージを処理する。多重化のパケットでなければ false
+    pub fn receive(&self, message: &Message) -> bool {
+        let Message::Binary(bytes) = message else {
+            return false;
+        };
+        if bytes.len() < 5 {
+            return false;
+        }
+        let id = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        let payload = &bytes[5..];
+
+        let mut state = self.inner.state.lock().unwrap();
+        match bytes[4] {
+            OPEN => {
+                state.channels.insert(id, ChannelState::new());
+                state.incoming.push_back(id);
+            }
+            kind @ (TEXT | BINARY) => {
+                let message = if kind == TEXT {
+                    match String::from_utf8(payload.to_vec()) {
+                        Ok(text) => Message::Text(text),
+                        Err(_) => return true,
+                    }
+                } else {
+                    Message::Binary(payload.to_vec())
+                };
+                if let Some(channel) = state.channels.get_mut(&id) {
+                    channel.inbox.push_back(message);
+                }
+            }
+            CREDIT if payload.len() == 4 => {
+                let credit = u32::from_be_bytes(payload.try_into().unwrap());
+                if let Some(channel) = state.channels.get_mut(&id) {
+                    channel.window += credit as i64;
+                }
+            }
+            CLOSE => {
+                if let Some(channel) = state.channels.get_mut(&id) {
+                    channel.closed_by_peer = true;
+                    if channel.closed {
+                        state.channels.remove(&id);
+                    }
+                }
+            }
+            _ => return false,
+        }
+        self.inner.changed.notify_all();
+        true
+    }
+
+    /// 下の接続が閉じたときに呼ぶ。待っている送受信は全て終了する
+    pub fn shutdown(&self) {
+        self.inner.state.lock().unwrap().shutdown = true;
+        self.inner.changed.notify_all();
+    }
+
+    fn channel(&self, id: u32) -> Channel {
+        Channel {
+            id,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Channel {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// メッセージを送る。相手からのCREDITを使い切っていれば届くまで待つ
+    pub fn send(&self, message: Message) -> Result<()> {
+        let kind = match message {
+            Message::Text(_) => TEXT,
+            Message::Binary(_) => BINARY,
+        };
+        {
+            let mut state = self.wait(|channel| channel.window > 0)?;
+            let channel = state.channels.get_mut(&self.id).ok_or_else(closed)?;
+            channel.window -= message.len() as i64;
+        }
+        (self.inner.send)(packet(self.id, kind, message.as_bytes()))
+    }
+
+    /// メッセージを受信する。相手がチャネルを閉じたら None
+    pub fn recv(&self) -> Option<Message> {
+        self.recv_timeout(None)
+    }
+
+    /// `recv` と同じだが、timeoutまでに届かなければ None
+    pub fn recv_timeout(&self, timeout: Option<Duration>) -> Option<Message> {
+        let message = {
+            let mut state = self.inner.state.lock().unwrap();
+            loop {
+                let channel = state.channels.get_mut(&self.id)?;
+                if let Some(message) = channel.inbox.pop_front() {
+                    break message;
+                }
+                if channel.closed_by_peer || state.shutdown {
+                    return None;
+                }
+                state = match timeout {
+                    Some(timeout) => {
+                        let (state, result) =
+                            self.inner.changed.wait_timeout(state, timeout).unwrap();
+                        if result.timed_out() {
+                            return None;
+                        }
+                        state
+                    }
+                    None => self.inner.changed.wait(state).unwrap(),
+                };
+            }
+        };
+        // 読んだ分だけ相手に送信を許可する
+        let credit = (message.len() as u32).to_be_bytes();
+        let _ = (self.inner.send)(packet(self.id, CREDIT, &credit));
+        Some(message)
+    }
+
+    /// チャネルを閉じる。相手の `recv` は残りのメッセージを返した後に None を返す
+    pub fn close(&self) -> Result<()> {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            let Some(channel) = state.channels.get_mut(&self.id) else {
+                return Ok(());
+            };
+            if channel.closed {
+                return Ok(());
+            }
+            channel.closed = true;
+            if channel.closed_by_peer {
+                state.channels.remove(&self.id);
+            }
+        }
+        self.inner.changed.notify_all();
+        (self.inner.send)(packet(self.id, CLOSE, &[]))
+    }
+
+    /// 条件を満たすまで待つ。チャネルか接続が閉じたらエラー
+    fn wait<F>(&self, ready: F) -> Result<MutexGuard<'_, State>>
+    where
+        F: Fn(&ChannelState) -> bool,
+    {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                return Err(closed());
+            }
+            match state.channels.get(&self.id) {
+                Some(channel) if channel.closed || channel.closed_by_peer => return Err(closed()),
+                Some(channel) if ready(channel) => return Ok(state),
+                Some(_) => state = self.inner.changed.wait(state).unwrap(),
+                None => return Err(closed()),
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+// 接続ごとの送信キュー
+//
+// `Config::send_queue` を設定すると、`ConnectionHandle::send` はメッセージをキューに入れてすぐに戻り、
+// 接続ごとの送信スレッドがキューに溜まったメッセージをまとめてTCP接続に書き込む。
+// 読み込みの遅いpeerがいても、送る側 (broadcastなど) が書き込みで止まらない。
+// キューが一杯になったら `SlowConsumerPolicy` に従う
+//
+// メッセージは `Priority` ごとの列に入れ、優先度の高い列から書き込む。
+// 捨てる場合は優先度の低いメッセージから捨てる
+//
+// Closeを送る前に `finish` でキューを閉じ、溜まっているメッセージを書き終えるまで待つ。
+// Closeの後にメッセージが書き込まれることはない (RFC 6455 5.5.1)
+
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+};
+
+use crate::{
+    connection::{PreparedMessage, Priority},
+    server::{SendQueue, SlowConsumerPolicy},
+};
+
+pub(crate) struct Outbox {
+    state: Mutex<State>,
+    ready: Condvar,
+    /// 送信スレッドがキューを空にして書き込みを終えたとき
+    drained: Condvar,
+    config: SendQueue,
+}
+
+#[derive(Default)]
+struct State {
+    /// `Priority` ごとの列 (優先度の低い順)
+    lanes: [VecDeque<PreparedMessage>; 3],
+    closed: bool,
+    /// `finish` が呼ばれた。新しいメッセージは受け付けない
+    finishing: bool,
+    /// 送信スレッドが取り出したメッセージを書き込んでいる
+    writing: bool,
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    /// 空でない列のうち、一番優先度の低いもの
+    fn lowest(&mut self) -> Option<(Priority, &mut VecDeque<PreparedMessage>)> {
+        Priority::ALL
+            .into_iter()
+            .zip(self.lanes.iter_mut())
+            .find(|(_, lane)| !lane.is_empty())
+    }
+
+    fn clear(&mut self) {
+        self.lanes.iter_mut().for_each(VecDeque::clear);
+    }
+}
+
+pub(crate) enum Push {
+    Queued,
+    /// キューが一杯だったため、古いメッセージか新しいメッセージを捨てた
+    Dropped,
+    /// キューが一杯だったため閉じた。指定されたcodeで接続を閉じる
+    Overflowed(u16),
+    Closed,
+}
+
+impl Outbox {
+    pub fn new(config: SendQueue) -> Self {
+        Self {
+            state: Mutex::default(),
+            ready: Condvar::new(),
+            drained: Condvar::new(),
+            config,
+        }
+    }
+
+    pub fn push(&self, message: PreparedMessage, priority: Priority) -> Push {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.finishing {
+            return Push::Closed;
+        }
+        if state.len() < self.config.capacity {
+            state.lanes[priority as usize].push_back(message);
+            self.ready.notify_one();
+            return Push::Queued;
+        }
+        match self.config.policy {
+            // 新しいメッセージより優先度の低いものから捨てる
+            SlowConsumerPolicy::DropOldest => match state.lowest() {
+                Some((lowest, lane)) if lowest <= priority => {
+                    lane.pop_front();
+                    state.lanes[priority as usize].push_back(message);
+                    Push::Dropped
+                }
+                _ => Push::Dropped,
+            },
+            SlowConsumerPolicy::DropNewest => match state.lowest() {
+                Some((lowest, lane)) if lowest < priority => {
+                    lane.pop_back();
+                    state.lanes[priority as usize].push_back(message);
+                    Push::Dropped
+                }
+                _ => Push::Dropped,
+            },
+            SlowConsumerPolicy::Close(code) => {
+                state.closed = true;
+                state.clear();
+                self.ready.notify_one();
+                self.drained.notify_all();
+                Push::Overflowed(code)
+            }
+        }
+    }
+
+    /// キューに溜まったメッセージを優先度の高い順に全て取り出す。空なら届くまで待ち、閉じていれば None。
+    /// 呼ばれた時点で、前回取り出したメッセージは書き終えたものとする
+    pub fn pop_all(&self) -> Option<Vec<PreparedMessage>> {
+        let mut state = self.state.lock().unwrap();
+        state.writing = false;
+        self.drained.notify_all();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if state.len() > 0 {
+                state.writing = true;
+                return Some(
+                    state
+                        .lanes
+                        .iter_mut()
+                        .rev()
+                        .flat_map(|lane| lane.drain(..))
+                        .collect(),
+                );
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// 新しいメッセージを受け付けないようにし、溜まっているメッセージを送信スレッドが書き終えるまで待つ
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.finishing = true;
+        let _state = self
+            .drained
+            .wait_while(state, |state| {
+                !state.closed && (state.len() > 0 || state.writing)
+            })
+            .unwrap();
+    }
+
+    /// 送信スレッドを止める。残っているメッセージは捨てる
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.clear();
+        self.ready.notify_one();
+        self.drained.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        connection::Connection,
+        handler::Handler,
+        message::Message,
+        server::{Config, Server},
+        testing,
+    };
+
+    fn outbox(capacity: usize, policy: SlowConsumerPolicy) -> Outbox {
+        Outbox::new(SendQueue { capacity, policy })
+    }
+
+    fn text(s: &str) -> PreparedMessage {
+        PreparedMessage::new(Message::Text(s.to_string()))
+    }
+
+    fn texts(messages: Vec<PreparedMessage>) -> Vec<Message> {
+        messages.iter().map(|m| m.message().clone()).collect()
+    }
+
+    #[test]
+    fn pops_higher_priorities_first() {
+        let outbox = outbox(10, SlowConsumerPolicy::DropNewest);
+        outbox.push(text("low"), Priority::Low);
+        outbox.push(text("normal"), Priority::Normal);
+        outbox.push(text("high"), Priority::High);
+        outbox.push(text("normal 2"), Priority::Normal);
+        assert_eq!(
+            texts(outbox.pop_all().unwrap()),
+            ["high", "normal", "normal 2", "low"]
+                .map(|s| Message::Text(s.to_string()))
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn drops_lower_priorities_when_full() {
+        let outbox = outbox(2, SlowConsumerPolicy::DropOldest);
+        outbox.push(text("low"), Priority::Low);
+        outbox.push(text("normal"), Priority::Normal);
+        assert!(matches!(
+            outbox.push(text("high"), Priority::High),
+            Push::Dropped
+        ));
+        assert_eq!(
+            texts(outbox.pop_all().unwrap()),
+            vec![
+                Message::Text("high".to_string()),
+                Message::Text("normal".to_string())
+            ]
+        );
+
+        let outbox = self::outbox(1, SlowConsumerPolicy::DropNewest);
+        outbox.push(text("first"), Priority::Normal);
+        assert!(matches!(
+            outbox.push(text("second"), Priority::Normal),
+            Push::Dropped
+        ));
+        assert_eq!(
+            texts(outbox.pop_all().unwrap()),
+            vec![Message::Text("first".to_string())]
+        );
+    }
+
+    #[test]
+    fn closes_when_full() {
+        let outbox = outbox(1, SlowConsumerPolicy::Close(1008));
+        outbox.push(text("first"), Priority::Normal);
+        assert!(matches!(
+            outbox.push(text("second"), Priority::Normal),
+            Push::Overflowed(1008)
+        ));
+        assert!(outbox.pop_all().is_none());
+        assert!(matches!(
+            outbox.push(text("third"), Priority::Normal),
+            Push::Closed
+        ));
+    }
+
+    #[test]
+    fn finish_waits_for_queued_messages_to_be_written() {
+        let outbox = Arc::new(outbox(10, SlowConsumerPolicy::DropNewest));
+        let written = Arc::new(AtomicBool::new(false));
+        outbox.push(text("a"), Priority::Normal);
+        outbox.push(text("b"), Priority::Normal);
+        let writer = thread::spawn({
+            let outbox = outbox.clone();
+            let written = written.clone();
+            move || {
+                let messages = outbox.pop_all().unwrap();
+                thread::sleep(Duration::from_millis(50));
+                written.store(true, Ordering::SeqCst);
+                assert!(outbox.pop_all().is_none());
+                messages.len()
+            }
+        });
+        outbox.finish();
+        assert!(written.load(Ordering::SeqCst));
+        assert!(matches!(
+            outbox.push(text("c"), Priority::Normal),
+            Push::Closed
+        ));
+        outbox.close();
+        assert_eq!(writer.join().unwrap(), 2);
+    }
+
+    /// 接続したらメッセージを送ってすぐに閉じる
+    struct SendThenClose;
+
+    impl Handler for SendThenClose {
+        fn on_open(&self, conn: &mut Connection) {
+            let handle = conn.handle();
+            for i in 0..200 {
+                handle.send(Message::Text(i.to_string())).unwrap();
+            }
+            conn.close(1000, "done").unwrap();
+            assert!(handle.send(Message::Text("late".to_string())).is_err());
+        }
+    }
+
+    #[test]
+    fn queued_messages_are_sent_before_close() {
+        let server = Server::bind("127.0.0.1:0", SendThenClose)
+            .unwrap()
+            .with_config(Config {
+                send_queue: Some(SendQueue {
+                    capacity: 1000,
+                    policy: SlowConsumerPolicy::DropNewest,
+                }),
+                ..Config::default()
+            });
+        let server = testing::spawn_server(server).unwrap();
+        let mut client = server.connect("/").unwrap();
+        for i in 0..200 {
+            assert_eq!(client.recv().unwrap(), Some(Message::Text(i.to_string())));
+        }
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close(), Some(&(1000, "done".to_string())));
+    }
+}
@@ -0,0 +1,256 @@
+// Upgradeヘッダーを落とすproxyの内側など、WebSocketが使えないクライアント向けのlong-polling
+//
+// サーバーの中から自分自身にWebSocketで接続し、HTTPリクエストとの間でメッセージを中継する。
+// Handlerからは普通の接続と同じに見える (peer_addrはループバックになる)
+//
+// POST /poll/open?path=/rooms/lobby  -> {"session":"<id>"} (pathを省略したら "/")
+// GET /poll/<id>                     -> 届いたメッセージのJSON配列。なければ届くまで最大25秒待つ
+//                                       Textは文字列、Binaryは {"binary":"<base64>"}
+// POST /poll/<id>                    -> bodyを1つのメッセージとして送る。
+//                                       Content-Type が application/octet-stream ならBinary、それ以外はText
+// DELETE /poll/<id>                  -> 切断する
+//
+// 切断されたセッションには 404 を返す。60秒間リクエストのないセッションは切断する
+
+use base64::{engine::general_purpose, Engine as _};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client::{Client, ClientWriter},
+    connection::Connection,
+    error::Result,
+    frame::Frame,
+    handshake::Request,
+    http, json,
+    log::debug,
+    message::Message,
+};
+
+const PREFIX: &str = "/poll/";
+/// GETでメッセージを待つ最大の時間
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// この時間リクエストのないセッションは切断する
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// POSTで受け付けるbodyの最大サイズ
+const MAX_BODY: usize = 1024 * 1024;
+
+/// long-pollingのセッションの一覧
+#[derive(Default)]
+pub(crate) struct Sessions {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    sweeping: AtomicBool,
+}
+
+struct Session {
+    writer: ClientWriter,
+    inbox: Mutex<Inbox>,
+    arrived: Condvar,
+    last_seen: Mutex<Instant>,
+}
+
+#[derive(Default)]
+struct Inbox {
+    messages: VecDeque<Message>,
+    closed: bool,
+}
+
+/// long-pollingが処理すべきリクエストか
+pub(crate) fn handles(request: &Request) -> bool {
+    request.path.starts_with(PREFIX)
+}
+
+/// `addr` はサーバー自身のアドレス。`body` はリクエストの読み込みで既に読んでいたbodyの先頭
+pub(crate) fn serve(
+    mut conn: Connection,
+    request: &Request,
+    body: Vec<u8>,
+    sessions: &Arc<Sessions>,
+    addr: SocketAddr,
+) {
+    sessions.start_sweeping();
+    let (path, query) = http::split_query(&request.path);
+    let target = &path[PREFIX.len()..];
+
+    let response = match (request.method.as_str(), target) {
+        ("POST", "open") => {
+            let path = query
+                .iter()
+                .find(|(key, _)| *key == "path")
+                .map_or("/", |(_, value)| value);
+            match sessions.open(addr, path) {
+                Ok(id) => ok(&format!("{{\"session\":{}}}", json::string(&id))),
+                Err(e) => http::response("502 Bad Gateway", "text/plain", &e.to_string()),
+            }
+        }
+        (method, id) => match sessions.get(id) {
+            None => http::response("404 Not Found", "text/plain", ""),
+            Some(session) => match method {
+                "GET" => {
+                    let (messages, closed) = session.poll();
+                    if messages.is_empty() && closed {
+                        sessions.remove(id);
+                        http::response("404 Not Found", "text/plain", "")
+                    } else {
+                        ok(&to_json(&messages))
+                    }
+                }
+                "POST" => match read_body(&mut conn, request, body) {
+                    Some(body) => {
+                        let message =
+                            if request.header("content-type") == Some("application/octet-stream") {
+                                Some(Message::Binary(body))
+                            } else {
+                                String::from_utf8(body).ok().map(Message::Text)
+                            };
+                        match message.map(|message| session.writer.send(message)) {
+                            Some(Ok(())) => http::response("204 No Content", "text/plain", ""),
+                            Some(Err(_)) => http::response("404 Not Found", "text/plain", ""),
+                            None => http::response("400 Bad Request", "text/plain", ""),
+                        }
+                    }
+                    None => http::response("413 Payload Too Large", "text/plain", ""),
+                },
+                "DELETE" => {
+                    sessions.remove(id);
+                    http::response("204 No Content", "text/plain", "")
+                }
+                _ => http::response("405 Method Not Allowed", "text/plain", ""),
+            },
+        },
+    };
+    let _ = conn.stream().write_all(response.as_bytes());
+}
+
+fn ok(body: &str) -> String {
+    http::response("200 OK", "application/json", body)
+}
+
+/// Content-Length の分だけbodyを読む。大きすぎる場合は None
+fn read_body(conn: &mut Connection, request: &Request, mut body: Vec<u8>) -> Option<Vec<u8>> {
+    let len = request
+        .header("content-length")
+        .and_then(|len| len.parse::<usize>().ok())
+        .unwrap_or(0);
+    if len > MAX_BODY {
+        return None;
+    }
+    if body.len() < len {
+        let mut rest = vec![0; len - body.len()];
+        conn.stream().read_exact(&mut rest).ok()?;
+        body.extend_from_slice(&rest);
+    }
+    body.truncate(len);
+    Some(body)
+}
+
+fn to_json(messages: &[Message]) -> String {
+    let items = messages
+        .iter()
+        .map(|message| match message {
+            Message::Text(text) => json::string(text),
+            Message::Binary(bytes) => format!(
+                "{{\"binary\":\"{}\"}}",
+                general_purpose::STANDARD.encode(bytes)
+            ),
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", items.join(","))
+}
+
+impl Sessions {
+    /// サーバー自身に接続し、受信したメッセージをためておくスレッドを起動する
+    fn open(&self, addr: SocketAddr, path: &str) -> Result<String> {
+        let mut client = Client::connect_addr(addr, path)?;
+        let id = format!("{:032x}", rand::random::<u128>());
+        let session = Arc::new(Session {
+            writer: client.writer(),
+            inbox: Mutex::new(Inbox::default()),
+            arrived: Condvar::new(),
+            last_seen: Mutex::new(Instant::now()),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id.clone(), session.clone());
+        debug!(
+            "polling_opened",
+            { session: id.as_str(), path: path },
+            "long-polling session {} opened: {}",
+            id,
+            path
+        );
+
+        thread::spawn(move || {
+            while let Ok(Some(message)) = client.recv() {
+                session.inbox.lock().unwrap().messages.push_back(message);
+                session.arrived.notify_all();
+            }
+            session.inbox.lock().unwrap().closed = true;
+            session.arrived.notify_all();
+        });
+        Ok(id)
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Session>> {
+        let session = self.sessions.lock().unwrap().get(id).cloned()?;
+        *session.last_seen.lock().unwrap() = Instant::now();
+        Some(session)
+    }
+
+    fn remove(&self, id: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().remove(id) {
+            session.close();
+        }
+    }
+
+    /// 放置されたセッションを切断するスレッドを1度だけ起動する
+    fn start_sweeping(self: &Arc<Self>) {
+        if self.sweeping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let sessions = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            let Some(sessions) = sessions.upgrade() else {
+                return;
+            };
+            sessions.sessions.lock().unwrap().retain(|_, session| {
+                // GETで待っている間もlast_seenは古いままなので、待ち時間の分は猶予する
+                let alive =
+                    session.last_seen.lock().unwrap().elapsed() < IDLE_TIMEOUT + POLL_TIMEOUT;
+                if !alive {
+                    session.close();
+                }
+                alive
+            });
+        });
+    }
+}
+
+impl Session {
+    /// メッセージが届くか切断されるまで待ち、届いていたものを全て返す
+    fn poll(&self) -> (Vec<Message>, bool) {
+        let inbox = self.inbox.lock().unwrap();
+        let (mut inbox, _) = self
+            .arrived
+            .wait_timeout_while(inbox, POLL_TIMEOUT, |inbox| {
+                inbox.messages.is_empty() && !inbox.closed
+            })
+            .unwrap();
+        *self.last_seen.lock().unwrap() = Instant::now();
+        (inbox.messages.drain(..).collect(), inbox.closed)
+    }
+
+    fn close(&self) {
+        let _ = self.writer.send_frame(Frame::close(1000, ""));
+    }
+}
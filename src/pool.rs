@@ -0,0 +1,392 @@
+// 同じサーバーへの接続のプール
+//
+// 接続を `size` 個開いたままにしておき、`get` で1つずつ貸し出す。
+// 貸し出した `Pooled` を捨てるとプールに戻り、次の `get` で再利用される。
+// `health_interval` ごとに貸し出していない接続にPingを送り、`health_timeout` の間にPongが返らない接続や
+// サーバーから閉じられた接続は捨てる。足りなくなった分はすぐに接続し直す
+//
+//   let pool = Pool::connect("ws://127.0.0.1:7778/rpc", PoolConfig::default())?;
+//   let mut client = pool.get()?;
+//   client.send(Message::Text(request))?;
+//   match client.recv() {
+//       Ok(Some(response)) => { ... }
+//       // 送受信に失敗した接続はプールに戻さない
+//       _ => client.discard(),
+//   }
+//
+// 貸し出していない間に届いたメッセージは捨てる (リクエストと応答を1対1でやりとりする用途向け)
+
+use std::{
+    collections::VecDeque,
+    io,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client::Client,
+    error::{Error, Result},
+};
+
+/// 接続し直すのに失敗したときに、次に試すまで待つ時間
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// 開いておく接続の数
+    pub size: usize,
+    /// 接続するときに提示するサブプロトコル
+    pub protocols: Vec<String>,
+    /// 貸し出していない接続を確かめる間隔
+    pub health_interval: Duration,
+    /// Pingを送ってからPongを待つ時間
+    pub health_timeout: Duration,
+    /// `get` で空いている接続を待つ時間
+    pub checkout_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            protocols: vec![],
+            health_interval: Duration::from_secs(10),
+            health_timeout: Duration::from_secs(5),
+            checkout_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    url: String,
+    config: PoolConfig,
+    state: Mutex<State>,
+    /// 接続がプールに戻った
+    available: Condvar,
+    /// 接続が減った
+    lost: Condvar,
+}
+
+struct State {
+    idle: VecDeque<Client>,
+    /// 開いている接続の数 (貸し出し中を含む)
+    open: usize,
+}
+
+/// プールから借りた接続。捨てるとプールに戻る
+pub struct Pooled {
+    client: Option<Client>,
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    /// `config.size` 個の接続を開く。1つでも失敗したらエラーを返す
+    pub fn connect(url: &str, config: PoolConfig) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            url: url.to_string(),
+            config,
+            state: Mutex::new(State {
+                idle: VecDeque::new(),
+                open: 0,
+            }),
+            available: Condvar::new(),
+            lost: Condvar::new(),
+        });
+        for _ in 0..shared.config.size {
+            let client = shared.open()?;
+            shared.put(client);
+            shared.state.lock().unwrap().open += 1;
+        }
+
+        let weak = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name("pool".to_string())
+            .spawn(move || maintain(weak))?;
+        Ok(Self { shared })
+    }
+
+    /// 空いている接続を借りる。`checkout_timeout` の間に空かなければエラー
+    pub fn get(&self) -> Result<Pooled> {
+        let deadline = Instant::now() + self.shared.config.checkout_timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop_front() {
+                return Ok(Pooled {
+                    client: Some(client),
+                    shared: self.shared.clone(),
+                });
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no connection available in the pool",
+                )));
+            }
+            state = self
+                .shared
+                .available
+                .wait_timeout(state, remaining)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// 貸し出していない接続の数
+    pub fn idle(&self) -> usize {
+        self.shared.state.lock().unwrap().idle.len()
+    }
+
+    /// 開いている接続の数 (貸し出し中を含む)
+    pub fn open(&self) -> usize {
+        self.shared.state.lock().unwrap().open
+    }
+}
+
+impl Shared {
+    fn open(&self) -> Result<Client> {
+        let protocols = self
+            .config
+            .protocols
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        Client::connect_with_protocols(&self.url, &protocols)
+    }
+
+    fn put(&self, client: Client) {
+        self.state.lock().unwrap().idle.push_back(client);
+        self.available.notify_one();
+    }
+
+    fn remove(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.lost.notify_one();
+    }
+}
+
+impl Pooled {
+    /// 壊れた接続 (送受信に失敗した、応答の途中で止まったなど) をプールに戻さずに捨てる。
+    /// 代わりの接続はプールが開き直す
+    pub fn discard(mut self) {
+        self.client = None;
+        self.shared.remove();
+    }
+}
+
+impl Deref for Pooled {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Pooled {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        match self.client.take() {
+            Some(client) if !client.is_closed() => self.shared.put(client),
+            Some(_) => self.shared.remove(),
+            None => {}
+        }
+    }
+}
+
+/// 減った接続を開き直し、`health_interval` ごとに貸し出していない接続を確かめる。
+/// プールがなくなったら終わる
+fn maintain(shared: Weak<Shared>) {
+    let mut checked_at = Instant::now();
+    while let Some(shared) = shared.upgrade() {
+        let interval = shared.config.health_interval;
+        if checked_at.elapsed() >= interval {
+            check(&shared);
+            checked_at = Instant::now();
+        }
+
+        let missing = {
+            let state = shared.state.lock().unwrap();
+            shared.config.size.saturating_sub(state.open)
+        };
+        let mut failed = false;
+        for _ in 0..missing {
+            let Ok(client) = shared.open() else {
+                failed = true;
+                break;
+            };
+            shared.state.lock().unwrap().open += 1;
+            shared.put(client);
+        }
+
+        let state = shared.state.lock().unwrap();
+        // 開き直している間に減っていれば、待たずに開き直す
+        if state.open < shared.config.size && !failed {
+            continue;
+        }
+        let mut wait = interval.saturating_sub(checked_at.elapsed());
+        if failed {
+            wait = wait.min(RETRY_DELAY);
+        }
+        drop(shared.lost.wait_timeout(state, wait).unwrap());
+    }
+}
+
+/// 貸し出していない接続を1つずつ取り出してPingを送る
+fn check(shared: &Shared) {
+    let count = shared.state.lock().unwrap().idle.len();
+    for _ in 0..count {
+        let Some(mut client) = shared.state.lock().unwrap().idle.pop_front() else {
+            return;
+        };
+        match client.probe(shared.config.health_timeout) {
+            Ok(true) if !client.is_closed() => shared.put(client),
+            _ => shared.remove(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{Connection, ConnectionId},
+        handler::Handler,
+        message::Message,
+        server::Server,
+        testing::{self, TestServer},
+    };
+
+    /// 受信したメッセージをそのまま返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    fn spawn_pool(config: PoolConfig) -> (TestServer, Pool) {
+        let server = testing::spawn_server(Server::bind("127.0.0.1:0", Echo).unwrap()).unwrap();
+        let pool = Pool::connect(&server.url("/"), config).unwrap();
+        (server, pool)
+    }
+
+    /// サーバー側の接続のID
+    fn server_connections(server: &TestServer) -> Vec<ConnectionId> {
+        let mut ids = server
+            .admin()
+            .connections()
+            .iter()
+            .map(|info| info.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn reuses_returned_connections() {
+        let config = PoolConfig {
+            size: 2,
+            ..PoolConfig::default()
+        };
+        let (server, pool) = spawn_pool(config);
+        assert_eq!((pool.open(), pool.idle()), (2, 2));
+        wait_for(|| server_connections(&server).len() == 2);
+        let before = server_connections(&server);
+
+        for i in 0..10 {
+            let mut client = pool.get().unwrap();
+            assert_eq!(pool.idle(), 1);
+            let text = format!("request {}", i);
+            client.send(Message::Text(text.clone())).unwrap();
+            assert_eq!(client.recv().unwrap(), Some(Message::Text(text)));
+        }
+        // 返した接続を使い回すので、新しい接続は開かない
+        assert_eq!((pool.open(), pool.idle()), (2, 2));
+        assert_eq!(server_connections(&server), before);
+    }
+
+    #[test]
+    fn lends_at_most_size_connections() {
+        let config = PoolConfig {
+            size: 2,
+            checkout_timeout: Duration::from_millis(100),
+            ..PoolConfig::default()
+        };
+        let (_server, pool) = spawn_pool(config);
+        let first = pool.get().unwrap();
+        let _second = pool.get().unwrap();
+        assert_eq!(pool.idle(), 0);
+        match pool.get() {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            _ => panic!("expected the checkout to time out"),
+        }
+        assert_eq!(pool.open(), 2);
+
+        // 待っている間に返されれば、その接続を借りられる
+        let waiting = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get().is_ok())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(first);
+        assert!(waiting.join().unwrap());
+    }
+
+    #[test]
+    fn replaces_discarded_and_closed_connections() {
+        let config = PoolConfig {
+            size: 2,
+            health_interval: Duration::from_millis(50),
+            health_timeout: Duration::from_millis(500),
+            ..PoolConfig::default()
+        };
+        let (server, pool) = spawn_pool(config);
+        wait_for(|| server_connections(&server).len() == 2);
+
+        // 捨てた接続の代わりを開き直す
+        pool.get().unwrap().discard();
+        assert_eq!(pool.open(), 1);
+        wait_for(|| pool.open() == 2 && pool.idle() == 2);
+
+        // サーバーから閉じられた接続は、health checkで捨てて開き直す
+        wait_for(|| server_connections(&server).len() == 2);
+        let kicked = server_connections(&server);
+        for &id in &kicked {
+            server.admin().kick(id, 1001, "going away").unwrap();
+        }
+        wait_for(|| {
+            let now = server_connections(&server);
+            now.len() == 2 && now.iter().all(|id| !kicked.contains(id))
+        });
+        wait_for(|| pool.open() == 2 && pool.idle() == 2);
+        let mut client = pool.get().unwrap();
+        client
+            .send(Message::Text("still works".to_string()))
+            .unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("still works".to_string()))
+        );
+    }
+}
@@ -0,0 +1,201 @@
+// WebSocketのリバースプロキシ
+//
+// pathのprefixごとにupstreamのWebSocketサーバーを設定し、クライアントの接続ごとにupstreamへ接続して
+// メッセージを双方向に中継する。prefixを取り除いた残りのpath (クエリを含む) はupstreamのURLの後ろに付ける:
+// route("/chat", "ws://127.0.0.1:9000/rooms") なら /chat/lobby?user=a -> ws://127.0.0.1:9000/rooms/lobby?user=a
+//
+// サブプロトコルはクライアントが最初に提示したものを選び、同じものをupstreamに要求する。
+// upstreamが受け付けなければ 1014 (Bad Gateway) で切断する。
+// Closeのstatus codeとreasonは双方向にそのまま伝える
+
+use std::{collections::HashMap, sync::Mutex, thread, time::Duration};
+
+use crate::{
+    client::{Client, ClientWriter},
+    connection::{Connection, ConnectionId},
+    error::{Error, Result},
+    frame::{Frame, Opcode},
+    handler::Handler,
+    log::{debug, warning},
+    message::Message,
+};
+
+/// upstreamに接続できない・upstreamが異常終了した場合のclose code (RFC 6455 7.4.1 のIANA登録)
+const BAD_GATEWAY: u16 = 1014;
+
+/// routeに一致したpathの接続をupstreamに中継するHandler。
+/// どのrouteにも一致しない接続は `fallback` に渡す
+pub struct Proxy<H = ()> {
+    /// (pathのprefix, upstreamのURL)
+    routes: Vec<(String, String)>,
+    fallback: H,
+    /// 中継中の接続のupstream。Closeを転送した後は None
+    upstreams: Mutex<HashMap<ConnectionId, Option<ClientWriter>>>,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Proxy {
+    pub fn new() -> Self {
+        Self::with_fallback(())
+    }
+}
+
+impl<H: Handler> Proxy<H> {
+    pub fn with_fallback(fallback: H) -> Self {
+        Self {
+            routes: vec![],
+            fallback,
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `prefix` で始まるpathの接続を `upstream` (ws://host:port/path) に中継する。
+    /// 複数のrouteに一致する場合は、prefixが最も長いものを使う
+    pub fn route(mut self, prefix: &str, upstream: &str) -> Self {
+        self.routes.push((prefix.to_string(), upstream.to_string()));
+        self
+    }
+
+    /// pathに対応するupstreamのURL
+    fn upstream(&self, path: &str) -> Option<String> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, upstream)| {
+                format!(
+                    "{}{}",
+                    upstream.trim_end_matches('/'),
+                    &path[prefix.len()..]
+                )
+            })
+    }
+
+    fn is_proxied(&self, id: ConnectionId) -> bool {
+        self.upstreams.lock().unwrap().contains_key(&id)
+    }
+
+    fn open(&self, conn: &mut Connection, url: &str) -> Result<()> {
+        let protocols = conn.protocol().into_iter().collect::<Vec<_>>();
+        let mut upstream = Client::connect_with_protocols(url, &protocols)?;
+        if upstream.protocol() != conn.protocol() {
+            return Err(Error::Handshake(format!(
+                "upstream selected a different subprotocol: {:?}",
+                upstream.protocol()
+            )));
+        }
+        self.upstreams
+            .lock()
+            .unwrap()
+            .insert(conn.id(), Some(upstream.writer()));
+        debug!(
+            "proxy_opened",
+            { connection_id: conn.id(), upstream: url },
+            "connection {} proxied to {}",
+            conn.id(),
+            url
+        );
+
+        // upstreamからの受信は別スレッドで行う
+        let handle = conn.handle();
+        thread::spawn(move || {
+            let result = loop {
+                match upstream.recv() {
+                    Ok(Some(message)) => {
+                        if handle.send(message).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            let _ = match (result, upstream.peer_close()) {
+                (Ok(()), Some((code, reason))) => handle.close(*code, reason),
+                (Ok(()), None) => handle.close(1000, ""),
+                (Err(_), _) => handle.close(BAD_GATEWAY, ""),
+            };
+        });
+        Ok(())
+    }
+}
+
+impl<H: Handler> Handler for Proxy<H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        if self.upstream(path).is_some() {
+            return offered.first().map(|protocol| protocol.to_string());
+        }
+        self.fallback.select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        let Some(url) = self.upstream(conn.path()) else {
+            return self.fallback.on_open(conn);
+        };
+        if let Err(e) = self.open(conn, &url) {
+            warning!(
+                "proxy_error",
+                { connection_id: conn.id(), upstream: url.as_str(), error: e.to_string() },
+                "proxy to {}: {}",
+                url,
+                e
+            );
+            let _ = conn.close(BAD_GATEWAY, "");
+        }
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        let sent = match self.upstreams.lock().unwrap().get(&conn.id()) {
+            Some(Some(upstream)) => upstream.send(message),
+            Some(None) => return,
+            None => return self.fallback.on_message(conn, message),
+        };
+        if sent.is_err() {
+            let _ = conn.close(BAD_GATEWAY, "");
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        let upstream = match self.upstreams.lock().unwrap().get_mut(&conn.id()) {
+            Some(upstream) => upstream.take(),
+            None => return self.fallback.on_peer_close(conn, code, reason),
+        };
+        let Some(upstream) = upstream else {
+            return;
+        };
+        // 1005 (status codeなし) はCloseフレームに載せられない
+        let frame = match code {
+            1005 => Frame::new(Opcode::Close, None),
+            code => Frame::close(code, reason),
+        };
+        let _ = upstream.send_frame(frame);
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        if !self.upstreams.lock().unwrap().contains_key(&conn.id()) {
+            self.fallback.on_pong(conn, payload, latency);
+        }
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        match self.upstreams.lock().unwrap().remove(&id) {
+            // Closeを交換せずに切れた
+            Some(Some(upstream)) => {
+                let _ = upstream.send_frame(Frame::close(1001, ""));
+            }
+            Some(None) => {}
+            None => self.fallback.on_close(id),
+        }
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        if !self.is_proxied(id) {
+            self.fallback.on_error(id, error, terminated);
+        }
+    }
+}
@@ -0,0 +1,467 @@
+// ファイルディスクリプタのreadinessを待つreactor
+//
+// `reactor` featureで有効になる (Unixのみ)。Linuxではepoll、macOS・BSDではkqueueを使い、
+// どちらも `Reactor` traitとして同じように扱う:
+//
+//   let reactor = reactor::new()?;
+//   reactor.register(stream.as_raw_fd(), 1, Interest::Readable)?;
+//   let mut events = vec![];
+//   reactor.poll(&mut events, Some(Duration::from_secs(1)))?;
+//   for event in &events { ... } // event.token == 1
+//
+// イベントはlevel-triggered: 読み込めるデータが残っている間は、次の `poll` でも同じイベントを返す
+//
+// サーバーの `Server::run_event_loop` と、クライアントの `selector::Selector` はこのreactorを使う
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+use std::{collections::HashMap, sync::Mutex};
+use std::{io, os::fd::RawFd, time::Duration};
+
+/// 待つイベントの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interest {
+    Readable,
+    Writable,
+    Both,
+}
+
+impl Interest {
+    fn readable(self) -> bool {
+        self != Self::Writable
+    }
+
+    fn writable(self) -> bool {
+        self != Self::Readable
+    }
+}
+
+/// `poll` が返すイベント。相手が切断した場合やエラーも readable として返す (読み込むとEOFかエラーになる)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub token: usize,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// readinessを待つ仕組み。1つのreactorを複数のスレッドから使える
+pub trait Reactor: Send + Sync {
+    /// `fd` を `interest` で登録する。イベントは `token` で返す
+    fn register(&self, fd: RawFd, token: usize, interest: Interest) -> io::Result<()>;
+
+    /// 登録済みの `fd` のtokenと待つイベントを変える
+    fn reregister(&self, fd: RawFd, token: usize, interest: Interest) -> io::Result<()>;
+
+    /// `fd` を閉じる前に呼ぶ
+    fn deregister(&self, fd: RawFd) -> io::Result<()>;
+
+    /// イベントを待ち、起きたイベントを `events` に追加する。
+    /// `timeout` が過ぎるかシグナルで中断されたら、追加せずに戻る。None なら無期限に待つ
+    fn poll(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// 一度に受け取るイベントの数
+const MAX_EVENTS: usize = 256;
+
+/// このプラットフォームのreactor (Linuxではepoll、macOS・BSDではkqueue)
+pub fn new() -> io::Result<Box<dyn Reactor>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return Ok(Box::new(Epoll::new()?));
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    return Ok(Box::new(Kqueue::new()?));
+    #[allow(unreachable_code)]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "no reactor on this platform",
+    ))
+}
+
+/// `timeout` をミリ秒に切り上げる。None なら -1 (無期限)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn timeout_millis(timeout: Option<Duration>) -> libc::c_int {
+    let Some(timeout) = timeout else {
+        return -1;
+    };
+    // 1ms未満を0に切り捨てると、待たずに戻るのを繰り返してしまう
+    let millis = timeout.as_nanos().div_ceil(1_000_000);
+    millis.min(libc::c_int::MAX as u128) as libc::c_int
+}
+
+/// 待たずに `fd` から読み込めるか (EOF・エラーを含む)。ブロッキングのモードは変えない
+pub fn is_readable(fd: RawFd) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: pollfdは1つだけで、呼び出しの間有効
+    match unsafe { libc::poll(&mut pollfd, 1, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Linuxのepoll
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub struct Epoll {
+    fd: RawFd,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Epoll {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: 引数はフラグだけ
+        let fd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Self { fd })
+    }
+
+    fn control(
+        &self,
+        op: libc::c_int,
+        fd: RawFd,
+        token: usize,
+        interest: Interest,
+    ) -> io::Result<()> {
+        let mut flags = 0;
+        if interest.readable() {
+            flags |= libc::EPOLLIN | libc::EPOLLRDHUP;
+        }
+        if interest.writable() {
+            flags |= libc::EPOLLOUT;
+        }
+        let mut event = libc::epoll_event {
+            events: flags as u32,
+            u64: token as u64,
+        };
+        // SAFETY: eventは呼び出しの間有効
+        check(unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) })?;
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Reactor for Epoll {
+    fn register(&self, fd: RawFd, token: usize, interest: Interest) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    fn reregister(&self, fd: RawFd, token: usize, interest: Interest) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    fn deregister(&self, fd: RawFd) -> io::Result<()> {
+        // SAFETY: EPOLL_CTL_DELではeventを参照しない
+        check(unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) })?;
+        Ok(())
+    }
+
+    fn poll(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        let mut received = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        // SAFETY: receivedはMAX_EVENTS個の要素を持ち、呼び出しの間有効
+        let count = unsafe {
+            libc::epoll_wait(
+                self.fd,
+                received.as_mut_ptr(),
+                MAX_EVENTS as libc::c_int,
+                timeout_millis(timeout),
+            )
+        };
+        let count = match check(count) {
+            Ok(count) => count as usize,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let closed = (libc::EPOLLHUP | libc::EPOLLRDHUP | libc::EPOLLERR) as u32;
+        events.extend(received[..count].iter().map(|event| {
+            let flags = event.events;
+            Event {
+                token: event.u64 as usize,
+                readable: flags & (libc::EPOLLIN as u32 | closed) != 0,
+                writable: flags & (libc::EPOLLOUT as u32 | libc::EPOLLERR as u32) != 0,
+            }
+        }));
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        // SAFETY: epoll_create1で作ったfdで、他からは閉じない
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// macOS・BSDのkqueue
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub struct Kqueue {
+    fd: RawFd,
+    /// keventの `udata` の型がプラットフォームごとに違うので、tokenはfdから引く
+    tokens: Mutex<HashMap<RawFd, usize>>,
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+impl Kqueue {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: 引数はない
+        let fd = check(unsafe { libc::kqueue() })?;
+        // SAFETY: kqueueで作ったfdにフラグを設定するだけ
+        if let Err(e) = check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) }) {
+            // SAFETY: 上で作ったfdで、他からは使っていない
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(Self {
+            fd,
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// `filter` ごとに追加・削除する。削除するものがなければ (ENOENT) 無視する
+    fn apply(&self, fd: RawFd, interest: Option<Interest>) -> io::Result<()> {
+        for (filter, wanted) in [
+            (libc::EVFILT_READ, interest.is_some_and(Interest::readable)),
+            (libc::EVFILT_WRITE, interest.is_some_and(Interest::writable)),
+        ] {
+            // SAFETY: keventはC言語の構造体なので、0で埋めた値は有効
+            let mut change: libc::kevent = unsafe { std::mem::zeroed() };
+            change.ident = fd as _;
+            change.filter = filter;
+            change.flags = if wanted {
+                libc::EV_ADD | libc::EV_ENABLE
+            } else {
+                libc::EV_DELETE
+            };
+            // SAFETY: changeは1つだけで、受け取るイベントはない
+            let result = unsafe {
+                libc::kevent(
+                    self.fd,
+                    &change,
+                    1,
+                    std::ptr::null_mut(),
+                    0,
+                    std::ptr::null(),
+                )
+            };
+            match check(result) {
+                Ok(_) => {}
+                Err(e) if !wanted && e.raw_os_error() == Some(libc::ENOENT) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+impl Reactor for Kqueue {
+    fn register(&self, fd: RawFd, token: usize, interest: Interest) -> io::Result<()> {
+        self.tokens.lock().unwrap().insert(fd, token);
+        self.apply(fd, Some(interest))
+    }
+
+    fn reregister(&self, fd: RawFd, token: usize, interest: Interest) -> io::Result<()> {
+        self.register(fd, token, interest)
+    }
+
+    fn deregister(&self, fd: RawFd) -> io::Result<()> {
+        self.tokens.lock().unwrap().remove(&fd);
+        self.apply(fd, None)
+    }
+
+    fn poll(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.map(|timeout| libc::timespec {
+            tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as _,
+        });
+        // SAFETY: keventはC言語の構造体なので、0で埋めた値は有効
+        let mut received: [libc::kevent; MAX_EVENTS] = unsafe { std::mem::zeroed() };
+        // SAFETY: receivedはMAX_EVENTS個の要素を持ち、timeoutとともに呼び出しの間有効
+        let count = unsafe {
+            libc::kevent(
+                self.fd,
+                std::ptr::null(),
+                0,
+                received.as_mut_ptr(),
+                MAX_EVENTS as libc::c_int,
+                timeout
+                    .as_ref()
+                    .map_or(std::ptr::null(), |timeout| timeout as *const _),
+            )
+        };
+        let count = match check(count) {
+            Ok(count) => count as usize,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let tokens = self.tokens.lock().unwrap();
+        for event in &received[..count] {
+            // 同じpollの中で先にderegisterされたもの
+            let Some(&token) = tokens.get(&(event.ident as RawFd)) else {
+                continue;
+            };
+            let closed = event.flags & (libc::EV_EOF | libc::EV_ERROR) != 0;
+            events.push(Event {
+                token,
+                readable: event.filter == libc::EVFILT_READ || closed,
+                writable: event.filter == libc::EVFILT_WRITE,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+impl Drop for Kqueue {
+    fn drop(&mut self) {
+        // SAFETY: kqueueで作ったfdで、他からは閉じない
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::{fd::AsRawFd, unix::net::UnixStream},
+        time::Instant,
+    };
+
+    use super::*;
+
+    #[test]
+    fn reports_readable_streams_by_token() {
+        let reactor = new().unwrap();
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let (mut c, d) = UnixStream::pair().unwrap();
+        reactor
+            .register(b.as_raw_fd(), 1, Interest::Readable)
+            .unwrap();
+        reactor
+            .register(d.as_raw_fd(), 2, Interest::Readable)
+            .unwrap();
+
+        let mut events = vec![];
+        let started = Instant::now();
+        reactor
+            .poll(&mut events, Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(events.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        a.write_all(b"x").unwrap();
+        reactor.poll(&mut events, None).unwrap();
+        assert_eq!(
+            events,
+            vec![Event {
+                token: 1,
+                readable: true,
+                writable: false
+            }]
+        );
+        // 読み込むまでは同じイベントを返す
+        events.clear();
+        reactor.poll(&mut events, Some(Duration::ZERO)).unwrap();
+        assert_eq!(events.len(), 1);
+        (&b).read_exact(&mut [0]).unwrap();
+        events.clear();
+        reactor.poll(&mut events, Some(Duration::ZERO)).unwrap();
+        assert!(events.is_empty());
+
+        // 切断も readable になる
+        drop(a);
+        c.write_all(b"y").unwrap();
+        reactor.poll(&mut events, None).unwrap();
+        events.sort_by_key(|event| event.token);
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| (event.token, event.readable))
+                .collect::<Vec<_>>(),
+            vec![(1, true), (2, true)]
+        );
+
+        reactor.deregister(b.as_raw_fd()).unwrap();
+        reactor
+            .reregister(d.as_raw_fd(), 3, Interest::Both)
+            .unwrap();
+        events.clear();
+        reactor.poll(&mut events, Some(Duration::ZERO)).unwrap();
+        assert_eq!(
+            events,
+            vec![Event {
+                token: 3,
+                readable: true,
+                writable: true
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn rounds_timeouts_up_to_milliseconds() {
+        assert_eq!(timeout_millis(None), -1);
+        assert_eq!(timeout_millis(Some(Duration::ZERO)), 0);
+        assert_eq!(timeout_millis(Some(Duration::from_micros(1))), 1);
+        assert_eq!(timeout_millis(Some(Duration::from_millis(1500))), 1500);
+        assert_eq!(timeout_millis(Some(Duration::MAX)), libc::c_int::MAX);
+    }
+
+    #[test]
+    fn checks_readability_without_blocking() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        assert!(!is_readable(b.as_raw_fd()).unwrap());
+        a.write_all(b"x").unwrap();
+        assert!(is_readable(b.as_raw_fd()).unwrap());
+    }
+}
@@ -0,0 +1,125 @@
+// 接続ごとに送受信したフレームをファイルに記録する
+//
+// ファイルの形式:
+// "WSREC1\n" | 記録開始時刻 (UNIX時間のマイクロ秒, u64 BE)
+// 以降、フレームごとに:
+// 方向 (b'<' 受信 / b'>' 送信) | 記録開始からの経過マイクロ秒 (u64 BE) | 長さ (u32 BE) | フレームのバイト列 (マスク済み)
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    frame::Frame,
+};
+
+const MAGIC: &[u8] = b"WSREC1\n";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// peerから受信した
+    Inbound,
+    /// peerへ送信した
+    Outbound,
+}
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub direction: Direction,
+    /// 記録開始からの経過時間
+    pub elapsed: Duration,
+    /// ワイヤー上のバイト列
+    pub bytes: Vec<u8>,
+}
+
+impl Record {
+    pub fn frame(&self) -> Result<Frame> {
+        Frame::read_from(&mut self.bytes.as_slice())
+    }
+}
+
+pub(crate) struct Recorder {
+    writer: BufWriter<File>,
+    started_at: Instant,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(now.as_micros() as u64).to_be_bytes())?;
+
+        Ok(Self {
+            writer,
+            started_at: Instant::now(),
+        })
+    }
+
+    pub fn write(&mut self, direction: Direction, bytes: &[u8]) {
+        let elapsed = self.started_at.elapsed().as_micros() as u64;
+        let direction = match direction {
+            Direction::Inbound => b'<',
+            Direction::Outbound => b'>',
+        };
+
+        // 記録に失敗しても通信は続ける
+        let _ = self
+            .writer
+            .write_all(&[direction])
+            .and_then(|_| self.writer.write_all(&elapsed.to_be_bytes()))
+            .and_then(|_| self.writer.write_all(&(bytes.len() as u32).to_be_bytes()))
+            .and_then(|_| self.writer.write_all(bytes))
+            .and_then(|_| self.writer.flush());
+    }
+}
+
+/// 記録されたファイルを読み込む。戻り値は記録開始時刻とフレームの一覧
+pub fn read<P: AsRef<Path>>(path: P) -> Result<(SystemTime, Vec<Record>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::Protocol("not a recording file".to_string()));
+    }
+    let mut started_at = [0; 8];
+    reader.read_exact(&mut started_at)?;
+    let started_at = UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(started_at));
+
+    let mut records = Vec::new();
+    loop {
+        let mut direction = [0; 1];
+        match reader.read_exact(&mut direction) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let direction = match direction[0] {
+            b'<' => Direction::Inbound,
+            b'>' => Direction::Outbound,
+            b => return Err(Error::Protocol(format!("unknown direction: {:#x}", b))),
+        };
+
+        let mut elapsed = [0; 8];
+        reader.read_exact(&mut elapsed)?;
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut bytes)?;
+
+        records.push(Record {
+            direction,
+            elapsed: Duration::from_micros(u64::from_be_bytes(elapsed)),
+            bytes,
+        });
+    }
+
+    Ok((started_at, records))
+}
@@ -0,0 +1,225 @@
+// 小さなレコードを1つのBinaryメッセージにまとめる
+//
+// 頻繁に送る小さなレコード (座標、ティック、ログの1行など) をレコードごとにメッセージにすると、
+// フレームのヘッダーと書き込みのたびのシステムコールが中身より重くなる。
+// `Packer` で溜めてから1つのBinaryメッセージとして送り、受信側は `unpack` で1件ずつ取り出す:
+//
+//   let mut packer = Packer::new();
+//   for tick in ticks {
+//       packer.push(&tick.to_bytes());
+//       if packer.byte_len() >= 16 * 1024 {
+//           conn.send(packer.take().unwrap())?;
+//       }
+//   }
+//   if let Some(message) = packer.take() {
+//       conn.send(message)?;
+//   }
+//
+//   for record in records::unpack(&message)? { ... }
+//
+// 形式: (レコードの長さ (LEB128の可変長整数) | レコード)*
+// 127バイトまでのレコードなら1件あたりのオーバーヘッドは1バイト
+
+use std::{io, iter::FusedIterator};
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+/// レコードを溜めて1つのメッセージにする
+#[derive(Clone, Debug, Default)]
+pub struct Packer {
+    bytes: Vec<u8>,
+    count: usize,
+}
+
+impl Packer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 溜めるバイト数の見込みが分かっていれば、あらかじめ確保しておく
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            bytes: Vec::with_capacity(capacity),
+            count: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: &[u8]) {
+        let mut len = record.len();
+        while len >= 0x80 {
+            self.bytes.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        self.bytes.push(len as u8);
+        self.bytes.extend_from_slice(record);
+        self.count += 1;
+    }
+
+    /// 溜めているレコードの数
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// 長さを含めた、メッセージにしたときのpayloadのバイト数
+    pub fn byte_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// 溜めたレコードをBinaryメッセージにして空に戻す。1件もなければ None
+    pub fn take(&mut self) -> Option<Message> {
+        if self.is_empty() {
+            return None;
+        }
+        self.count = 0;
+        Some(Message::Binary(std::mem::take(&mut self.bytes)))
+    }
+}
+
+/// レコードをまとめて1つのメッセージにする
+pub fn pack<I, R>(records: I) -> Message
+where
+    I: IntoIterator<Item = R>,
+    R: AsRef<[u8]>,
+{
+    let mut packer = Packer::new();
+    for record in records {
+        packer.push(record.as_ref());
+    }
+    Message::Binary(packer.bytes)
+}
+
+/// 受信したメッセージのレコードを順に取り出す
+#[derive(Clone, Debug)]
+pub struct Records<'a> {
+    bytes: &'a [u8],
+}
+
+/// `pack`・`Packer` で作られたメッセージのレコード。
+/// 途中で切れているなど形式が正しくなければ、1件も取り出さずにエラーを返す
+pub fn unpack(message: &Message) -> Result<Records<'_>> {
+    let Message::Binary(bytes) = message else {
+        return Err(invalid("records must be sent as a binary message"));
+    };
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        let (len, header) = read_len(rest)?;
+        if rest.len() - header < len {
+            return Err(invalid("record is truncated"));
+        }
+        rest = &rest[header + len..];
+    }
+    Ok(Records { bytes })
+}
+
+/// 先頭のレコードの長さと、長さに使ったバイト数
+fn read_len(bytes: &[u8]) -> Result<(usize, usize)> {
+    let mut len = 0usize;
+    for (i, byte) in bytes.iter().enumerate() {
+        let bits = (*byte & 0x7f) as usize;
+        let shift = 7 * i as u32;
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            return Err(invalid("record length is too large"));
+        }
+        len |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok((len, i + 1));
+        }
+    }
+    Err(invalid("record length is truncated"))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        // `unpack` で形式を確かめてあるので失敗しない
+        let (len, header) = read_len(self.bytes).ok()?;
+        let (record, rest) = self.bytes[header..].split_at(len);
+        self.bytes = rest;
+        Some(record)
+    }
+}
+
+impl FusedIterator for Records<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_records() {
+        let long = vec![7u8; 300];
+        let records: Vec<&[u8]> = vec![b"abc", b"", &long, b"x"];
+        let message = pack(&records);
+        assert_eq!(unpack(&message).unwrap().collect::<Vec<_>>(), records);
+
+        let mut packer = Packer::new();
+        assert_eq!(packer.take(), None);
+        for record in &records {
+            packer.push(record);
+        }
+        assert_eq!(packer.len(), 4);
+        // 127バイトまでは長さに1バイト、300バイトなら2バイト使う
+        assert_eq!(packer.byte_len(), (1 + 3) + 1 + (2 + 300) + (1 + 1));
+        assert_eq!(packer.take(), Some(message));
+        assert!(packer.is_empty());
+        assert_eq!(packer.byte_len(), 0);
+    }
+
+    #[test]
+    fn encodes_lengths_as_leb128() {
+        let mut packer = Packer::new();
+        packer.push(&[0; 127]);
+        packer.push(&[0; 128]);
+        let Some(Message::Binary(bytes)) = packer.take() else {
+            panic!("no message");
+        };
+        assert_eq!(bytes[0], 0x7f);
+        assert_eq!(&bytes[128..130], &[0x80, 0x01]);
+    }
+
+    #[test]
+    fn empty_message_has_no_records() {
+        assert_eq!(unpack(&Message::Binary(vec![])).unwrap().count(), 0);
+        assert_eq!(pack(Vec::<&[u8]>::new()), Message::Binary(vec![]));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        for bytes in [
+            // 長さより短いレコード
+            vec![0x05, b'a', b'b'],
+            // 2件目が切れている
+            vec![0x01, b'a', 0x02, b'b'],
+            // 長さの途中で終わる
+            vec![0x80],
+            vec![0x01, b'a', 0xff, 0xff],
+            // usizeに収まらない長さ
+            vec![
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            ],
+            vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+        ] {
+            assert!(
+                unpack(&Message::Binary(bytes.clone())).is_err(),
+                "{:?}",
+                bytes
+            );
+        }
+        assert!(unpack(&Message::Text("abc".to_string())).is_err());
+    }
+}
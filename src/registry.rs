@@ -0,0 +1,51 @@
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
+
+use crate::connection::{ConnectionHandle, ConnectionId};
+
+/// 現在開いている接続の一覧
+#[derive(Default)]
+pub(crate) struct Registry {
+    entries: Mutex<HashMap<ConnectionId, Entry>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Entry {
+    pub handle: ConnectionHandle,
+    pub path: String,
+    pub connected_at: SystemTime,
+}
+
+impl Registry {
+    pub fn insert(&self, handle: ConnectionHandle, path: String) {
+        let entry = Entry {
+            handle,
+            path,
+            connected_at: SystemTime::now(),
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.handle.id(), entry);
+    }
+
+    pub fn remove(&self, id: ConnectionId) {
+        self.entries.lock().unwrap().remove(&id);
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<Entry> {
+        self.entries.lock().unwrap().get(&id).cloned()
+    }
+
+    /// ID順
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.handle.id());
+        entries
+    }
+}
@@ -0,0 +1,210 @@
+// メッセージのリクエストと応答の対応付け
+//
+// 送信側は `Requester::request` でメッセージにIDを付けて送り、返された `Pending` で応答を待つ。
+// 受信したメッセージを `Requester::receive` に渡すと、同じIDの応答を待っている `Pending` に届く。
+// 受信側は `decode` でリクエストを取り出し、`reply(id, message)` を送り返す。
+// JSON-RPCと違い、メッセージの中身の形式は決めない
+//
+// どちらもBinaryメッセージとして送る:
+// 種類 (1: Textのリクエスト / 2: Binaryのリクエスト / 3: Textの応答 / 4: Binaryの応答) | ID (u64 BE) | payload
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+const TEXT_REQUEST: u8 = 1;
+const BINARY_REQUEST: u8 = 2;
+const TEXT_REPLY: u8 = 3;
+const BINARY_REPLY: u8 = 4;
+
+/// `request` が応答を待つ時間のデフォルト
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    /// 応答を求めるメッセージ。`reply(id, ...)` を送り返す
+    Request { id: u64, message: Message },
+    /// IDのリクエストへの応答
+    Reply { id: u64, message: Message },
+}
+
+/// 受信したメッセージを取り出す。この形式でなければ None
+pub fn decode(message: &Message) -> Option<Packet> {
+    let Message::Binary(bytes) = message else {
+        return None;
+    };
+    if bytes.len() < 9 {
+        return None;
+    }
+    let id = u64::from_be_bytes(bytes[1..9].try_into().unwrap());
+    let payload = bytes[9..].to_vec();
+
+    let message = match bytes[0] {
+        TEXT_REQUEST | TEXT_REPLY => Message::Text(String::from_utf8(payload).ok()?),
+        BINARY_REQUEST | BINARY_REPLY => Message::Binary(payload),
+        _ => return None,
+    };
+    match bytes[0] {
+        TEXT_REQUEST | BINARY_REQUEST => Some(Packet::Request { id, message }),
+        _ => Some(Packet::Reply { id, message }),
+    }
+}
+
+/// IDのリクエストへの応答
+pub fn reply(id: u64, message: &Message) -> Message {
+    let kind = match message {
+        Message::Text(_) => TEXT_REPLY,
+        Message::Binary(_) => BINARY_REPLY,
+    };
+    encode(kind, id, message)
+}
+
+fn encode(kind: u8, id: u64, message: &Message) -> Message {
+    let mut bytes = vec![kind];
+    bytes.extend_from_slice(&id.to_be_bytes());
+    bytes.extend_from_slice(message.as_bytes());
+    Message::Binary(bytes)
+}
+
+/// 応答を待っているリクエスト (ID -> 応答の送り先)
+type Waiters = Mutex<HashMap<u64, Sender<Message>>>;
+
+/// リクエストにIDを付けて送り、応答を待っているリクエストに届ける
+pub struct Requester {
+    next_id: AtomicU64,
+    waiters: Arc<Waiters>,
+    timeout: Duration,
+}
+
+/// 応答を待っているリクエスト。捨てると、後から届いた応答は無視される
+pub struct Pending {
+    id: u64,
+    receiver: Receiver<Message>,
+    deadline: Instant,
+    waiters: Arc<Waiters>,
+}
+
+impl Default for Requester {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Requester {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            waiters: Arc::default(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// `request` が応答を待つ時間 (デフォルトは30秒)
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// IDを付けてsendで送る。応答は返された `Pending` で待つ
+    pub fn request<F>(&self, message: &Message, send: F) -> Result<Pending>
+    where
+        F: FnOnce(Message) -> Result<()>,
+    {
+        self.request_timeout(message, self.timeout, send)
+    }
+
+    /// `request` と同じだが、このリクエストだけ応答を待つ時間を変える
+    pub fn request_timeout<F>(
+        &self,
+        message: &Message,
+        timeout: Duration,
+        send: F,
+    ) -> Result<Pending>
+    where
+        F: FnOnce(Message) -> Result<()>,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let kind = match message {
+            Message::Text(_) => TEXT_REQUEST,
+            Message::Binary(_) => BINARY_REQUEST,
+        };
+        let (sender, receiver) = mpsc::channel();
+        self.waiters.lock().unwrap().insert(id, sender);
+        let pending = Pending {
+            id,
+            receiver,
+            deadline: Instant::now() + timeout,
+            waiters: self.waiters.clone(),
+        };
+        // 失敗した場合はpendingを捨てて待つのをやめる
+        send(encode(kind, id, message))?;
+        Ok(pending)
+    }
+
+    /// 受信したメッセージが応答なら、待っているリクエストに届けてtrueを返す。
+    /// 待っていない (時間切れになった) リクエストへの応答もtrueを返して捨てる
+    pub fn receive(&self, message: &Message) -> bool {
+        let Some(Packet::Reply { id, message }) = decode(message) else {
+            return false;
+        };
+        if let Some(sender) = self.waiters.lock().unwrap().remove(&id) {
+            let _ = sender.send(message);
+        }
+        true
+    }
+
+    /// 待っている全てのリクエストを失敗させる (切断されたときなど)
+    pub fn cancel_all(&self) {
+        self.waiters.lock().unwrap().clear();
+    }
+
+    /// 応答を待っているリクエストの数
+    pub fn pending(&self) -> usize {
+        self.waiters.lock().unwrap().len()
+    }
+}
+
+impl Pending {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// 応答が届くまで待つ。時間切れと `cancel_all` はエラーになる
+    pub fn wait(self) -> Result<Message> {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        match self.receiver.recv_timeout(timeout) {
+            Ok(message) => Ok(message),
+            Err(RecvTimeoutError::Timeout) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no reply to request {}", self.id),
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("request {} was cancelled", self.id),
+            ))),
+        }
+    }
+
+    /// 応答が届いていれば返す。待たない
+    pub fn try_wait(&self) -> Option<Message> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.waiters.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,166 @@
+// 待ち受けソケットを新しいプロセスに引き継ぎ、接続を受け付けられない時間を作らずにバイナリを入れ替える
+//
+// 古いプロセスは `Restarter::restart` で新しいバイナリを起動し、受け付けを止めて既存の接続をdrainで閉じる:
+//
+//   let restarter = server.restarter()?;
+//   // SIGHUPや管理APIなどをきっかけに
+//   restarter.restart(Command::new(env::current_exe()?), Some(notice), Some(grace), timeout)?;
+//   process::exit(0);
+//
+// 新しいプロセスは `inherited` で待ち受けソケットを受け取る:
+//
+//   let server = match restart::inherited()? {
+//       Some(listener) => Server::from_listener(listener, handler)?,
+//       None => Server::bind(addr, handler)?,
+//   };
+//
+// 待ち受けソケットは子プロセスの標準入力として渡す。標準入力はexecしても閉じられないので、
+// close-on-execを外したりfdの番号を受け渡したりしなくてよい。子プロセスには環境変数
+// `WEBSOCKET_RS_LISTENER=stdin` を設定して、標準入力が待ち受けソケットであることを知らせる。
+//
+// 引き継いだ後は両方のプロセスが同じ待ち受けソケットでacceptする。古いプロセスは受け付けを止めるため、
+// 自分自身に1回接続してacceptで止まっているスレッドを起こす (この接続はどちらかのプロセスで
+// handshakeのエラーとして記録される)。それまでに古いプロセスが受け付けた接続も通常どおり処理し、drainで閉じる
+
+use std::{
+    env, io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsFd, OwnedFd},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{admin::Admin, message::Message};
+
+/// 標準入力が引き継いだ待ち受けソケットであることを子プロセスに知らせる環境変数
+pub const LISTENER_ENV: &str = "WEBSOCKET_RS_LISTENER";
+
+/// 前のプロセスから引き継いだ待ち受けソケット。引き継いでいなければ None
+pub fn inherited() -> io::Result<Option<TcpListener>> {
+    if env::var_os(LISTENER_ENV).is_none_or(|value| value != "stdin") {
+        return Ok(None);
+    }
+    let listener = TcpListener::from(io::stdin().as_fd().try_clone_to_owned()?);
+    // 標準入力が待ち受けソケットでなければここで失敗する
+    listener.local_addr()?;
+    Ok(Some(listener))
+}
+
+/// `Server::restarter` で取得する
+pub struct Restarter {
+    listener: TcpListener,
+    /// acceptで止まっているスレッドを起こすための接続先
+    addr: SocketAddr,
+    handed_off: Arc<AtomicBool>,
+    admin: Admin,
+}
+
+impl Restarter {
+    pub(crate) fn new(
+        listener: TcpListener,
+        addr: SocketAddr,
+        handed_off: Arc<AtomicBool>,
+        admin: Admin,
+    ) -> Self {
+        Self {
+            listener,
+            addr,
+            handed_off,
+            admin,
+        }
+    }
+
+    /// 待ち受けソケットを標準入力にして `command` を起動し、このプロセスでの受け付けを止める
+    pub fn spawn(&self, mut command: Command) -> io::Result<Child> {
+        let listener = OwnedFd::from(self.listener.try_clone()?);
+        let child = command
+            .env(LISTENER_ENV, "stdin")
+            .stdin(Stdio::from(listener))
+            .spawn()?;
+        self.handed_off.store(true, Ordering::SeqCst);
+        // acceptで止まっているスレッドを起こす
+        let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+        Ok(child)
+    }
+
+    /// `spawn` の後、`Admin::drain_with` で既存の接続に `notice` を送って `grace` 後に閉じ、
+    /// 全て閉じるか `timeout` が過ぎるまで待つ
+    pub fn restart(
+        &self,
+        command: Command,
+        notice: Option<Message>,
+        grace: Option<Duration>,
+        timeout: Duration,
+    ) -> io::Result<Child> {
+        let child = self.spawn(command)?;
+        self.admin.drain_with(notice, grace);
+        self.admin.wait_drained(timeout);
+        Ok(child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process, thread};
+
+    use super::*;
+    use crate::{connection::Connection, handler::Handler, server::Server, testing};
+
+    /// 接続したクライアントに自分のプロセスIDを送る
+    struct Pid;
+
+    impl Handler for Pid {
+        fn on_open(&self, conn: &mut Connection) {
+            let _ = conn.send(Message::Text(process::id().to_string()));
+        }
+    }
+
+    /// `restart_hands_listener_to_new_process` が子プロセスとして実行する。
+    /// 引き継いでいなければ何もしない
+    #[test]
+    fn inherited_listener_server() {
+        let Some(listener) = inherited().unwrap() else {
+            return;
+        };
+        Server::from_listener(listener, Pid).unwrap().run().unwrap();
+    }
+
+    #[test]
+    fn restart_hands_listener_to_new_process() {
+        let server = Server::bind("127.0.0.1:0", Pid).unwrap();
+        let restarter = server.restarter().unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let mut old = server.connect("/").unwrap();
+        assert_eq!(
+            old.recv().unwrap(),
+            Some(Message::Text(process::id().to_string()))
+        );
+
+        let mut command = Command::new(env::current_exe().unwrap());
+        command.args(["--exact", "restart::tests::inherited_listener_server"]);
+        // 古い接続が閉じるまで戻らないので、こちらで古い接続のCloseに応答する
+        let restarting = thread::spawn(move || {
+            restarter.restart(
+                command,
+                Some(Message::Text("restarting".to_string())),
+                Some(Duration::from_millis(100)),
+                Duration::from_secs(5),
+            )
+        });
+        assert_eq!(
+            old.recv().unwrap(),
+            Some(Message::Text("restarting".to_string()))
+        );
+        assert_eq!(old.recv().unwrap(), None);
+        let mut child = restarting.join().unwrap().unwrap();
+        let mut new = server.connect("/").unwrap();
+        let pid = new.recv().unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(pid, Some(Message::Text(child.id().to_string())));
+    }
+}
@@ -0,0 +1,182 @@
+// asyncのランタイムの抽象化
+//
+// `AsyncClient` が使うのは、taskの起動・一定時間待つこと・TCPの接続と読み書きだけなので、
+// それを `Runtime` にまとめる。featureで有効にしたランタイムごとに実装がある:
+//
+//   tokio     -> `Tokio`
+//   async-std -> `AsyncStd`
+//   smol      -> `Smol`
+//
+// 受信しているtaskとは別のtaskから送信できるように、ソケットは `&self` で読み書きする。
+// 1つのフレームを書き込んでいる途中に別の書き込みが割り込まないようにするのは呼ぶ側 (`AsyncClientWriter`) の役目
+
+use std::{
+    future::{poll_fn, Future},
+    io,
+    pin::pin,
+    task::Poll,
+    time::Duration,
+};
+
+/// asyncのランタイム。`AsyncClient<R>` の `R` に指定する
+pub trait Runtime: Send + Sync + 'static {
+    type TcpStream: AsyncSocket;
+
+    /// `host` を名前解決してTCPで接続する
+    fn connect(host: &str, port: u16) -> impl Future<Output = io::Result<Self::TcpStream>> + Send;
+
+    /// taskを起動する。終わるのは待たない
+    fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static;
+
+    fn sleep(duration: Duration) -> impl Future<Output = ()> + Send;
+}
+
+/// ランタイムのTCPの接続。複数のtaskから共有して読み書きする
+pub trait AsyncSocket: Send + Sync + 'static {
+    /// 読み込んだバイト数を返す。0ならEOF
+    fn read(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn write_all(&self, buf: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// `future` が `duration` の間に終わらなければ None
+pub(crate) async fn timeout<R: Runtime, T>(
+    duration: Duration,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    let mut future = pin!(future);
+    let mut sleep = pin!(R::sleep(duration));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        sleep.as_mut().poll(cx).map(|()| None)
+    })
+    .await
+}
+
+#[cfg(feature = "tokio")]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Runtime for Tokio {
+    type TcpStream = tokio::net::TcpStream;
+
+    fn connect(host: &str, port: u16) -> impl Future<Output = io::Result<Self::TcpStream>> + Send {
+        let host = host.to_string();
+        async move { tokio::net::TcpStream::connect((host.as_str(), port)).await }
+    }
+
+    /// tokioのランタイムの中から呼ぶこと
+    fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(future);
+    }
+
+    fn sleep(duration: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// tokioの `TcpStream` は `&self` で読み書きするとき、readinessを待ってから `try_read`・`try_write` する
+#[cfg(feature = "tokio")]
+impl AsyncSocket for tokio::net::TcpStream {
+    async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            self.readable().await?;
+            match self.try_read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            self.writable().await?;
+            match self.try_write(buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => buf = &buf[n..],
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async-std")]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStd {
+    type TcpStream = async_std::net::TcpStream;
+
+    fn connect(host: &str, port: u16) -> impl Future<Output = io::Result<Self::TcpStream>> + Send {
+        let host = host.to_string();
+        async move { async_std::net::TcpStream::connect((host.as_str(), port)).await }
+    }
+
+    fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        async_std::task::spawn(future);
+    }
+
+    fn sleep(duration: Duration) -> impl Future<Output = ()> + Send {
+        async_std::task::sleep(duration)
+    }
+}
+
+#[cfg(feature = "async-std")]
+impl AsyncSocket for async_std::net::TcpStream {
+    async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        async_std::io::ReadExt::read(&mut &*self, buf).await
+    }
+
+    async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        async_std::io::WriteExt::write_all(&mut &*self, buf).await
+    }
+}
+
+#[cfg(feature = "smol")]
+pub struct Smol;
+
+#[cfg(feature = "smol")]
+impl Runtime for Smol {
+    type TcpStream = smol::net::TcpStream;
+
+    fn connect(host: &str, port: u16) -> impl Future<Output = io::Result<Self::TcpStream>> + Send {
+        let host = host.to_string();
+        async move { smol::net::TcpStream::connect((host.as_str(), port)).await }
+    }
+
+    /// smolのグローバルなexecutorで動かす
+    fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        smol::spawn(future).detach();
+    }
+
+    async fn sleep(duration: Duration) {
+        smol::Timer::after(duration).await;
+    }
+}
+
+/// smolの `TcpStream` は `&self` では読み書きできないが、cloneしても同じ接続を指す
+#[cfg(feature = "smol")]
+impl AsyncSocket for smol::net::TcpStream {
+    async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        smol::io::AsyncReadExt::read(&mut self.clone(), buf).await
+    }
+
+    async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        smol::io::AsyncWriteExt::write_all(&mut self.clone(), buf).await
+    }
+}
@@ -0,0 +1,691 @@
+// 受信したメッセージの検証
+//
+// `Server::with_validator(prefix, validator, on_invalid)` で登録したValidatorは、pathが `prefix` で始まる接続の
+// 受信したメッセージを、Interceptorを適用した後、Handlerの on_message の前に検証する。
+// 失敗したメッセージはHandlerに渡らず、`OnInvalid::Reply` なら以下のTextメッセージを送り返し、
+// `OnInvalid::Close` なら1008で接続を閉じる:
+// {"error":"invalid_message","path":"/user/age","message":"expected integer, got string"}
+//
+// `Schema` はJSON Schemaの以下のキーワードで検証する:
+// type, enum, const, properties, required, additionalProperties, items, minItems, maxItems,
+// minLength, maxLength, minimum, maximum, exclusiveMinimum, exclusiveMaximum, allOf, anyOf, oneOf, not
+// 検証に影響しない注釈 (`ANNOTATIONS`) 以外のキーワード ($ref, pattern, formatなど) を含むスキーマは読み込めない
+
+use std::{fmt, fs, io, path::Path};
+
+use crate::{
+    error::{Error, Result},
+    json::{self, Value},
+    message::Message,
+};
+
+/// 検証に失敗した理由
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invalid {
+    /// 違反した値の位置 (JSON Pointer。メッセージ全体なら空)
+    pub path: String,
+    pub message: String,
+}
+
+impl Invalid {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            path: String::new(),
+            message: message.into(),
+        }
+    }
+
+    fn at<S: Into<String>>(path: &str, message: S) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// 送り返すエラーのメッセージ
+    pub(crate) fn reply(&self) -> Message {
+        Message::Text(format!(
+            "{{\"error\":\"invalid_message\",\"path\":{},\"message\":{}}}",
+            json::string(&self.path),
+            json::string(&self.message)
+        ))
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.as_str() {
+            "" => write!(f, "invalid message: {}", self.message),
+            path => write!(f, "invalid message at {}: {}", path, self.message),
+        }
+    }
+}
+
+pub trait Validator: Send + Sync + 'static {
+    fn validate(&self, message: &Message) -> std::result::Result<(), Invalid>;
+}
+
+/// `Fn(&Message) -> Result<(), Invalid>` のclosureもValidatorとして使える
+impl<F> Validator for F
+where
+    F: Fn(&Message) -> std::result::Result<(), Invalid> + Send + Sync + 'static,
+{
+    fn validate(&self, message: &Message) -> std::result::Result<(), Invalid> {
+        self(message)
+    }
+}
+
+/// 検証に失敗したメッセージをどう扱うか
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnInvalid {
+    /// エラーを送り返し、接続は続ける
+    #[default]
+    Reply,
+    /// 1008 (Policy Violation) で接続を閉じる
+    Close,
+}
+
+/// 検証には使わず、スキーマに書いてあっても無視するキーワード
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+];
+
+/// JSON Schemaで受信したTextメッセージを検証する。Binaryメッセージは失敗する
+#[derive(Clone, Debug)]
+pub struct Schema {
+    root: Node,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    /// `false` のスキーマ。どの値も一致しない
+    reject: bool,
+    types: Vec<String>,
+    enumeration: Option<Vec<Value>>,
+    constant: Option<Value>,
+    properties: Vec<(String, Node)>,
+    required: Vec<String>,
+    additional_properties: Option<Box<Node>>,
+    items: Option<Box<Node>>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    all_of: Vec<Node>,
+    any_of: Vec<Node>,
+    one_of: Vec<Node>,
+    not: Option<Box<Node>>,
+}
+
+impl Schema {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self> {
+        let invalid = |message: String| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("schema: {}", message),
+            ))
+        };
+        let value = json::parse(source).ok_or_else(|| invalid("not valid JSON".to_string()))?;
+        let root = compile(&value, "").map_err(invalid)?;
+        Ok(Self { root })
+    }
+}
+
+impl Validator for Schema {
+    fn validate(&self, message: &Message) -> std::result::Result<(), Invalid> {
+        let Message::Text(text) = message else {
+            return Err(Invalid::new("expected a JSON text message"));
+        };
+        let value = json::parse(text).ok_or_else(|| Invalid::new("not valid JSON"))?;
+        self.root.check(&value, "")
+    }
+}
+
+/// スキーマのJSONを読み込む。`path` はエラーに含めるスキーマ内の位置
+fn compile(schema: &Value, path: &str) -> std::result::Result<Node, String> {
+    let members = match schema {
+        Value::Bool(accept) => {
+            return Ok(Node {
+                reject: !accept,
+                ..Node::default()
+            })
+        }
+        Value::Object(members) => members,
+        _ => {
+            return Err(format!(
+                "{}: a schema must be an object or a boolean",
+                at(path)
+            ))
+        }
+    };
+
+    let mut node = Node::default();
+    for (key, value) in members {
+        let path = format!("{}/{}", path, escape(key));
+        let error = |expected: &str| format!("{}: must be {}", at(&path), expected);
+        match key.as_str() {
+            "type" => {
+                node.types = match value {
+                    Value::String(name) => vec![name.clone()],
+                    Value::Array(names) => names
+                        .iter()
+                        .map(|name| match name {
+                            Value::String(name) => Some(name.clone()),
+                            _ => None,
+                        })
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| error("a string or an array of strings"))?,
+                    _ => return Err(error("a string or an array of strings")),
+                };
+                if let Some(name) = node.types.iter().find(|name| !is_type(name)) {
+                    return Err(format!("{}: unknown type {}", at(&path), name));
+                }
+            }
+            "enum" => match value {
+                Value::Array(values) => node.enumeration = Some(values.clone()),
+                _ => return Err(error("an array")),
+            },
+            "const" => node.constant = Some(value.clone()),
+            "properties" => match value {
+                Value::Object(properties) => {
+                    for (name, schema) in properties {
+                        let path = format!("{}/{}", path, escape(name));
+                        node.properties
+                            .push((name.clone(), compile(schema, &path)?));
+                    }
+                }
+                _ => return Err(error("an object")),
+            },
+            "required" => {
+                node.required = match value {
+                    Value::Array(names) => names
+                        .iter()
+                        .map(|name| match name {
+                            Value::String(name) => Some(name.clone()),
+                            _ => None,
+                        })
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| error("an array of strings"))?,
+                    _ => return Err(error("an array of strings")),
+                }
+            }
+            "additionalProperties" => {
+                node.additional_properties = Some(Box::new(compile(value, &path)?))
+            }
+            "items" => node.items = Some(Box::new(compile(value, &path)?)),
+            "minItems" => node.min_items = Some(count(value).ok_or_else(|| error("a count"))?),
+            "maxItems" => node.max_items = Some(count(value).ok_or_else(|| error("a count"))?),
+            "minLength" => node.min_length = Some(count(value).ok_or_else(|| error("a count"))?),
+            "maxLength" => node.max_length = Some(count(value).ok_or_else(|| error("a count"))?),
+            "minimum" => node.minimum = Some(number(value).ok_or_else(|| error("a number"))?),
+            "maximum" => node.maximum = Some(number(value).ok_or_else(|| error("a number"))?),
+            "exclusiveMinimum" => {
+                node.exclusive_minimum = Some(number(value).ok_or_else(|| error("a number"))?)
+            }
+            "exclusiveMaximum" => {
+                node.exclusive_maximum = Some(number(value).ok_or_else(|| error("a number"))?)
+            }
+            "allOf" | "anyOf" | "oneOf" => {
+                let Value::Array(schemas) = value else {
+                    return Err(error("an array of schemas"));
+                };
+                let nodes = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, schema)| compile(schema, &format!("{}/{}", path, i)))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                match key.as_str() {
+                    "allOf" => node.all_of = nodes,
+                    "anyOf" => node.any_of = nodes,
+                    _ => node.one_of = nodes,
+                }
+            }
+            "not" => node.not = Some(Box::new(compile(value, &path)?)),
+            key if ANNOTATIONS.contains(&key) => {}
+            // 知らないキーワードを無視すると、書いた人が拒否するつもりのメッセージを受け付けてしまう
+            key => return Err(format!("{}: unsupported keyword {}", at(&path), key)),
+        }
+    }
+    Ok(node)
+}
+
+impl Node {
+    /// `path` は検証している値のメッセージ内の位置
+    fn check(&self, value: &Value, path: &str) -> std::result::Result<(), Invalid> {
+        if self.reject {
+            return Err(Invalid::at(path, "no value is allowed here"));
+        }
+        if !self.types.is_empty() && !self.types.iter().any(|name| has_type(value, name)) {
+            return Err(Invalid::at(
+                path,
+                format!(
+                    "expected {}, got {}",
+                    self.types.join(" or "),
+                    value.type_name()
+                ),
+            ));
+        }
+        if let Some(values) = &self.enumeration {
+            if !values.iter().any(|allowed| same(allowed, value)) {
+                return Err(Invalid::at(path, "must be one of the allowed values"));
+            }
+        }
+        if let Some(constant) = &self.constant {
+            if !same(constant, value) {
+                return Err(Invalid::at(path, "must be the constant value"));
+            }
+        }
+
+        match value {
+            Value::Object(members) => self.check_object(members, path)?,
+            Value::Array(items) => self.check_array(items, path)?,
+            Value::String(s) => {
+                let len = s.chars().count();
+                if let Some(min) = self.min_length.filter(|min| len < *min) {
+                    return Err(Invalid::at(
+                        path,
+                        format!("must be at least {} characters", min),
+                    ));
+                }
+                if let Some(max) = self.max_length.filter(|max| len > *max) {
+                    return Err(Invalid::at(
+                        path,
+                        format!("must be at most {} characters", max),
+                    ));
+                }
+            }
+            Value::Number(n) => self.check_number(*n, path)?,
+            Value::Null | Value::Bool(_) => {}
+        }
+
+        for node in &self.all_of {
+            node.check(value, path)?;
+        }
+        if !self.any_of.is_empty()
+            && !self
+                .any_of
+                .iter()
+                .any(|node| node.check(value, path).is_ok())
+        {
+            return Err(Invalid::at(path, "must match a schema in anyOf"));
+        }
+        if !self.one_of.is_empty() {
+            let matched = self
+                .one_of
+                .iter()
+                .filter(|node| node.check(value, path).is_ok())
+                .count();
+            if matched != 1 {
+                return Err(Invalid::at(
+                    path,
+                    format!(
+                        "must match exactly one schema in oneOf (matched {})",
+                        matched
+                    ),
+                ));
+            }
+        }
+        if let Some(node) = &self.not {
+            if node.check(value, path).is_ok() {
+                return Err(Invalid::at(path, "must not match the schema in not"));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_object(
+        &self,
+        members: &[(String, Value)],
+        path: &str,
+    ) -> std::result::Result<(), Invalid> {
+        if let Some(name) = self
+            .required
+            .iter()
+            .find(|name| !members.iter().any(|(key, _)| key == *name))
+        {
+            return Err(Invalid::at(
+                path,
+                format!("missing property {}", json::string(name)),
+            ));
+        }
+        for (key, value) in members {
+            let path = format!("{}/{}", path, escape(key));
+            match self.properties.iter().find(|(name, _)| name == key) {
+                Some((_, node)) => node.check(value, &path)?,
+                None => match &self.additional_properties {
+                    Some(node) if node.reject => {
+                        return Err(Invalid::at(&path, "unexpected property"));
+                    }
+                    Some(node) => node.check(value, &path)?,
+                    None => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn check_array(&self, items: &[Value], path: &str) -> std::result::Result<(), Invalid> {
+        if let Some(min) = self.min_items.filter(|min| items.len() < *min) {
+            return Err(Invalid::at(
+                path,
+                format!("must have at least {} items", min),
+            ));
+        }
+        if let Some(max) = self.max_items.filter(|max| items.len() > *max) {
+            return Err(Invalid::at(
+                path,
+                format!("must have at most {} items", max),
+            ));
+        }
+        if let Some(node) = &self.items {
+            for (i, item) in items.iter().enumerate() {
+                node.check(item, &format!("{}/{}", path, i))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_number(&self, n: f64, path: &str) -> std::result::Result<(), Invalid> {
+        let violated = [
+            (self.minimum.filter(|min| n < *min), ">="),
+            (self.maximum.filter(|max| n > *max), "<="),
+            (self.exclusive_minimum.filter(|min| n <= *min), ">"),
+            (self.exclusive_maximum.filter(|max| n >= *max), "<"),
+        ];
+        match violated
+            .iter()
+            .find_map(|(limit, op)| limit.map(|limit| (op, limit)))
+        {
+            Some((op, limit)) => Err(Invalid::at(path, format!("must be {} {}", op, limit))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_type(name: &str) -> bool {
+    matches!(
+        name,
+        "null" | "boolean" | "object" | "array" | "number" | "integer" | "string"
+    )
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match (value, name) {
+        (Value::Number(n), "integer") => n.fract() == 0.0,
+        (value, name) => value.type_name() == name,
+    }
+}
+
+/// 値が等しいか。objectのキーの順は問わない
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| b.iter().any(|(k, b)| k == key && same(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn count(value: &Value) -> Option<usize> {
+    match value {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+        _ => None,
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// JSON Pointerの1つの階層
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// エラーに含めるスキーマ内の位置
+fn at(path: &str) -> &str {
+    match path {
+        "" => "/",
+        path => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connection::Connection, handler::Handler, server::Server, testing};
+
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    const ORDER: &str = r#"{
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "order",
+        "type": "object",
+        "required": ["item", "qty"],
+        "additionalProperties": false,
+        "properties": {
+            "item": {"type": "string", "minLength": 1, "maxLength": 8},
+            "qty": {"type": "integer", "minimum": 1, "exclusiveMaximum": 100},
+            "tags": {"type": "array", "items": {"enum": ["gift", "express"]}, "maxItems": 2},
+            "note": {"type": ["string", "null"]},
+            "kind": {"const": "order"}
+        }
+    }"#;
+
+    fn check(schema: &Schema, text: &str) -> std::result::Result<(), Invalid> {
+        schema.validate(&Message::Text(text.to_string()))
+    }
+
+    fn error(schema: &Schema, text: &str) -> (String, String) {
+        let invalid = check(schema, text).unwrap_err();
+        (invalid.path, invalid.message)
+    }
+
+    #[test]
+    fn accepts_valid_messages() {
+        let schema = Schema::parse(ORDER).unwrap();
+        assert!(check(&schema, r#"{"item":"pen","qty":2}"#).is_ok());
+        assert!(check(
+            &schema,
+            r#"{"item":"pen","qty":99,"tags":["gift"],"note":null,"kind":"order"}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn reports_where_the_message_is_invalid() {
+        let schema = Schema::parse(ORDER).unwrap();
+        let case = |text, path: &str, message: &str| {
+            assert_eq!(
+                error(&schema, text),
+                (path.to_string(), message.to_string()),
+                "{}",
+                text
+            )
+        };
+        case(r#"{"item":"pen"}"#, "", "missing property \"qty\"");
+        case(
+            r#"{"item":"pen","qty":1.5}"#,
+            "/qty",
+            "expected integer, got number",
+        );
+        case(r#"{"item":"pen","qty":0}"#, "/qty", "must be >= 1");
+        case(r#"{"item":"pen","qty":100}"#, "/qty", "must be < 100");
+        case(
+            r#"{"item":"","qty":1}"#,
+            "/item",
+            "must be at least 1 characters",
+        );
+        case(
+            r#"{"item":"あいうえおかきくけ","qty":1}"#,
+            "/item",
+            "must be at most 8 characters",
+        );
+        case(
+            r#"{"item":"pen","qty":1,"x":1}"#,
+            "/x",
+            "unexpected property",
+        );
+        case(
+            r#"{"item":"pen","qty":1,"tags":["bad"]}"#,
+            "/tags/0",
+            "must be one of the allowed values",
+        );
+        case(
+            r#"{"item":"pen","qty":1,"tags":["gift","gift","gift"]}"#,
+            "/tags",
+            "must have at most 2 items",
+        );
+        case(
+            r#"{"item":"pen","qty":1,"note":1}"#,
+            "/note",
+            "expected string or null, got number",
+        );
+        case(
+            r#"{"item":"pen","qty":1,"kind":"x"}"#,
+            "/kind",
+            "must be the constant value",
+        );
+        case(r#"[1]"#, "", "expected object, got array");
+        case("not json", "", "not valid JSON");
+        assert!(schema.validate(&Message::Binary(b"{}".to_vec())).is_err());
+    }
+
+    #[test]
+    fn combines_schemas() {
+        let schema = Schema::parse(
+            r#"{
+                "anyOf": [{"type": "string"}, {"type": "number"}],
+                "oneOf": [{"minimum": 0}, {"maximum": 10}],
+                "not": {"const": 5}
+            }"#,
+        )
+        .unwrap();
+        assert!(check(&schema, "\"a\"").is_err());
+        assert!(check(&schema, "20").is_ok());
+        assert!(check(&schema, "-1").is_ok());
+        assert_eq!(
+            error(&schema, "3").1,
+            "must match exactly one schema in oneOf (matched 2)"
+        );
+        assert!(check(&schema, "true").is_err());
+
+        let schema = Schema::parse(
+            r#"{"allOf": [{"minimum": 0}, {"maximum": 10}], "enum": [{"a": 1, "b": [2]}, 3]}"#,
+        )
+        .unwrap();
+        assert!(check(&schema, "3").is_ok());
+        assert!(check(&schema, r#"{"b": [2], "a": 1}"#).is_ok());
+        assert!(check(&schema, "4").is_err());
+    }
+
+    #[test]
+    fn additional_properties_can_be_a_schema() {
+        let schema = Schema::parse(
+            r#"{"properties": {"id": {"type": "integer"}}, "additionalProperties": {"type": "string"}}"#,
+        )
+        .unwrap();
+        assert!(check(&schema, r#"{"id": 1, "name": "a"}"#).is_ok());
+        assert_eq!(error(&schema, r#"{"id": 1, "n/a": 2}"#).0, "/n~1a");
+        assert!(check(&schema, "false").is_ok());
+        assert!(Schema::parse("false").is_ok_and(|schema| check(&schema, "1").is_err()));
+    }
+
+    #[test]
+    fn rejects_unsupported_keywords() {
+        for schema in [
+            r##"{"$ref": "#/$defs/order"}"##,
+            r#"{"type": "string", "pattern": "^a"}"#,
+            r#"{"type": "string", "format": "email"}"#,
+            r#"{"properties": {"a": {"uniqueItems": true}}}"#,
+            r#"{"items": {"patternProperties": {}}}"#,
+        ] {
+            let error = Schema::parse(schema).unwrap_err().to_string();
+            assert!(
+                error.contains("unsupported keyword"),
+                "{}: {}",
+                schema,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_schemas() {
+        for schema in [
+            "",
+            "1",
+            r#"{"type": "text"}"#,
+            r#"{"type": 1}"#,
+            r#"{"required": "a"}"#,
+            r#"{"minLength": -1}"#,
+            r#"{"minimum": "1"}"#,
+            r#"{"anyOf": {}}"#,
+        ] {
+            assert!(Schema::parse(schema).is_err(), "{}", schema);
+        }
+    }
+
+    #[test]
+    fn server_replies_to_or_closes_on_invalid_messages() {
+        let schema = || Schema::parse(r#"{"type": "object", "required": ["id"]}"#).unwrap();
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_validator("/reply", schema(), OnInvalid::Reply)
+            .with_validator("/close", schema(), OnInvalid::Close);
+        let server = testing::spawn_server(server).unwrap();
+        let text = |s: &str| Message::Text(s.to_string());
+
+        let mut client = server.connect("/reply").unwrap();
+        client.send(text("{}")).unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(text(
+                r#"{"error":"invalid_message","path":"","message":"missing property \"id\""}"#
+            ))
+        );
+        client.send(text(r#"{"id":1}"#)).unwrap();
+        assert_eq!(client.recv().unwrap(), Some(text(r#"{"id":1}"#)));
+
+        let mut client = server.connect("/close").unwrap();
+        client.send(text("[]")).unwrap();
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close().unwrap().0, 1008);
+
+        // 他のpathは検証しない
+        let mut client = server.connect("/other").unwrap();
+        client.send(text("[]")).unwrap();
+        assert_eq!(client.recv().unwrap(), Some(text("[]")));
+    }
+}
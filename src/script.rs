@@ -0,0 +1,305 @@
+// 起動時に読み込むスクリプトでメッセージの処理を定義する
+//
+// 1行に1つのルールを書く。受信したメッセージごとに上から順に条件を調べ、最初に一致したルールの動作を実行する。
+// どのルールにも一致しなかったメッセージは `fallback` に渡す:
+//
+//   # `!ping` に pong と返す
+//   on equals "!ping" => reply "pong"
+//   # `/shout` では大文字にして返す
+//   on path "/shout" text => reply "{upper}"
+//   # roomの発言から単語を伏せ字にしてからHandlerに渡す
+//   on path "/rooms/" contains "spam" => replace "spam" "****"; forward
+//   on prefix "!say " => publish "lobby" "{rest}"; reply "sent"
+//   on equals "!bye" => close 1000 "bye"
+//   on binary => drop
+//
+// 条件 (全て満たすと一致):
+//   path "<prefix>"   接続のpathが <prefix> で始まる
+//   text / binary     メッセージの種類
+//   equals "<s>" / prefix "<s>" / contains "<s>"   Textメッセージの内容
+// 動作 (`;` で区切って順に実行する):
+//   reply "<template>"            送信元に返す
+//   publish "<room>" "<template>" roomに配送する
+//   replace "<from>" "<to>"       以降の動作で使うメッセージを書き換える
+//   forward                       (書き換えた) メッセージを `fallback` に渡す
+//   close <code> "<reason>"       接続を閉じる
+//   drop                          何もしない
+// templateの中の {message} {upper} {lower} {rest} {id} {path} は置き換えられる
+// ({rest} は prefix で一致した部分より後ろ)
+//
+// 1行のルールで書けない処理は、rhai featureの `RhaiScript` でRhaiの関数として書ける
+
+#[cfg(feature = "rhai")]
+mod rhai;
+
+#[cfg(feature = "rhai")]
+pub use self::rhai::RhaiScript;
+
+use std::{fs, io, path::Path, time::Duration};
+
+use crate::{
+    connection::{Connection, ConnectionId},
+    error::{Error, Result},
+    handler::Handler,
+    message::Message,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Condition {
+    Path(String),
+    Text,
+    Binary,
+    Equals(String),
+    Prefix(String),
+    Contains(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Publish(String, String),
+    Replace(String, String),
+    Forward,
+    Close(u16, String),
+    Drop,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+}
+
+/// スクリプトのルールでメッセージを処理するHandler。
+/// どのルールにも一致しないメッセージと、メッセージ以外のイベントは `fallback` に渡す
+pub struct Script<H = ()> {
+    rules: Vec<Rule>,
+    fallback: H,
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with_fallback(path, ())
+    }
+
+    pub fn parse(source: &str) -> Result<Self> {
+        Self::parse_with_fallback(source, ())
+    }
+}
+
+impl<H: Handler> Script<H> {
+    pub fn load_with_fallback<P: AsRef<Path>>(path: P, fallback: H) -> Result<Self> {
+        Self::parse_with_fallback(&fs::read_to_string(path)?, fallback)
+    }
+
+    pub fn parse_with_fallback(source: &str, fallback: H) -> Result<Self> {
+        let rules = source
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+            .map(|(i, line)| {
+                parse_rule(line).map_err(|e| {
+                    Error::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("script line {}: {}", i + 1, e),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { rules, fallback })
+    }
+
+    /// 最初に一致したルールと、{rest} に入る文字列
+    fn matches<'a>(&self, path: &str, message: &'a Message) -> Option<(&Rule, &'a str)> {
+        self.rules.iter().find_map(|rule| {
+            let mut rest = "";
+            let matched = rule.conditions.iter().all(|condition| {
+                let text = match message {
+                    Message::Text(text) => Some(text.as_str()),
+                    Message::Binary(_) => None,
+                };
+                match condition {
+                    Condition::Path(prefix) => path.starts_with(prefix.as_str()),
+                    Condition::Text => text.is_some(),
+                    Condition::Binary => text.is_none(),
+                    Condition::Equals(s) => text == Some(s.as_str()),
+                    Condition::Prefix(s) => match text.and_then(|t| t.strip_prefix(s.as_str())) {
+                        Some(r) => {
+                            rest = r;
+                            true
+                        }
+                        None => false,
+                    },
+                    Condition::Contains(s) => text.is_some_and(|t| t.contains(s.as_str())),
+                }
+            });
+            matched.then_some((rule, rest))
+        })
+    }
+}
+
+impl<H: Handler> Handler for Script<H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        self.fallback.select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        self.fallback.on_open(conn);
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        let Some((rule, rest)) = self.matches(conn.path(), &message) else {
+            return self.fallback.on_message(conn, message);
+        };
+        let rest = rest.to_string();
+        let mut message = message;
+        for action in &rule.actions {
+            let render = |template: &str, message: &Message| {
+                let text = match message {
+                    Message::Text(text) => text.clone(),
+                    Message::Binary(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                };
+                template
+                    .replace("{message}", &text)
+                    .replace("{upper}", &text.to_uppercase())
+                    .replace("{lower}", &text.to_lowercase())
+                    .replace("{rest}", &rest)
+                    .replace("{id}", &conn.id().to_string())
+                    .replace("{path}", conn.path())
+            };
+            match action {
+                Action::Reply(template) => {
+                    let _ = conn.send(Message::Text(render(template, &message)));
+                }
+                Action::Publish(room, template) => {
+                    let text = render(template, &message);
+                    let _ = conn.publish(room, Message::Text(text));
+                }
+                Action::Replace(from, to) => {
+                    if let Message::Text(text) = &message {
+                        message = Message::Text(text.replace(from.as_str(), to));
+                    }
+                }
+                Action::Forward => self.fallback.on_message(conn, message.clone()),
+                Action::Close(code, reason) => {
+                    let _ = conn.close(*code, reason);
+                }
+                Action::Drop => {}
+            }
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        self.fallback.on_peer_close(conn, code, reason);
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        self.fallback.on_pong(conn, payload, latency);
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        self.fallback.on_close(id);
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        self.fallback.on_error(id, error, terminated);
+    }
+}
+
+/// 空白で区切る。`"` で囲んだ部分は1つの単語で、`\"` `\\` `\n` をエスケープできる。`;` は単独の単語になる
+fn tokenize(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ';' {
+            chars.next();
+            tokens.push(";".to_string());
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => token.push('\n'),
+                        Some(c) => token.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    Some(c) => token.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' || c == '"' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_rule(line: &str) -> std::result::Result<Rule, String> {
+    let tokens = tokenize(line)?;
+    let mut tokens = tokens.iter().map(String::as_str);
+    if tokens.next() != Some("on") {
+        return Err("rule must start with `on`".to_string());
+    }
+    let mut next = |what: &str| {
+        tokens
+            .next()
+            .map(str::to_string)
+            .ok_or_else(|| format!("missing {}", what))
+    };
+
+    let mut conditions = vec![];
+    loop {
+        let condition = match next("`=>`")?.as_str() {
+            "=>" => break,
+            "path" => Condition::Path(next("path")?),
+            "text" => Condition::Text,
+            "binary" => Condition::Binary,
+            "equals" => Condition::Equals(next("string")?),
+            "prefix" => Condition::Prefix(next("string")?),
+            "contains" => Condition::Contains(next("string")?),
+            other => return Err(format!("unknown condition: {}", other)),
+        };
+        conditions.push(condition);
+    }
+
+    let mut actions = vec![];
+    while let Ok(keyword) = next("action") {
+        let action = match keyword.as_str() {
+            ";" => continue,
+            "reply" => Action::Reply(next("template")?),
+            "publish" => Action::Publish(next("room")?, next("template")?),
+            "replace" => Action::Replace(next("string")?, next("string")?),
+            "forward" => Action::Forward,
+            "close" => {
+                let code = next("close code")?;
+                let code = code
+                    .parse()
+                    .map_err(|_| format!("invalid close code: {}", code))?;
+                Action::Close(code, next("reason")?)
+            }
+            "drop" => Action::Drop,
+            other => return Err(format!("unknown action: {}", other)),
+        };
+        actions.push(action);
+    }
+    if actions.is_empty() {
+        return Err("rule has no actions".to_string());
+    }
+    Ok(Rule {
+        conditions,
+        actions,
+    })
+}
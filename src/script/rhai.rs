@@ -0,0 +1,316 @@
+// Rhai (https://rhai.rs) のスクリプトでメッセージを処理するHandler
+//
+// 起動時にスクリプトを読み込んでコンパイルし、以下の関数があれば呼ぶ:
+//
+//   // 接続したとき
+//   fn on_open(conn) {
+//       conn.reply(`welcome ${conn.id}`);
+//   }
+//
+//   // メッセージを受信したとき。Textは文字列、BinaryはBlobで渡す
+//   fn on_message(conn, message) {
+//       if message == "!ping" { conn.reply("pong"); return; }
+//       if message.starts_with("!say ") { conn.publish("lobby", message.sub_string(5)); return; }
+//       // 書き換えてからfallbackに渡す (replaceは文字列そのものを書き換える)
+//       if conn.path.starts_with("/rooms/") { message.replace("spam", "****"); }
+//       message
+//   }
+//
+// `on_message` の戻り値が文字列・Blobなら、そのメッセージを `fallback` に渡す。() なら何もしない。
+// `conn` の関数 (reply・publish・close) は関数が戻った後に順に実行する。
+// 1回の呼び出しで実行できる操作の数には上限があるので、無限ループで接続のスレッドが止まることはない
+
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rhai::{Blob, Dynamic, Engine, Scope, AST};
+
+use crate::{
+    connection::{Connection, ConnectionId},
+    error::{Error, Result},
+    handler::Handler,
+    log::warning,
+    message::Message,
+};
+
+/// 1回の関数の呼び出しで実行できる操作の数
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Rhaiのスクリプトの関数でメッセージを処理するHandler。
+/// `on_message` がないスクリプトと、メッセージ以外のイベントは `fallback` に渡す
+pub struct RhaiScript<H = ()> {
+    engine: Engine,
+    ast: AST,
+    on_open: bool,
+    on_message: bool,
+    fallback: H,
+}
+
+/// スクリプトに渡す接続。`conn.reply` などは `actions` に積み、関数が戻った後に実行する
+#[derive(Clone)]
+struct Conn {
+    id: ConnectionId,
+    path: String,
+    actions: Arc<Mutex<Vec<Action>>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Action {
+    Reply(Message),
+    Publish(String, Message),
+    Close(u16, String),
+}
+
+impl RhaiScript {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with_fallback(path, ())
+    }
+
+    pub fn parse(source: &str) -> Result<Self> {
+        Self::parse_with_fallback(source, ())
+    }
+}
+
+impl<H: Handler> RhaiScript<H> {
+    pub fn load_with_fallback<P: AsRef<Path>>(path: P, fallback: H) -> Result<Self> {
+        Self::parse_with_fallback(&fs::read_to_string(path)?, fallback)
+    }
+
+    pub fn parse_with_fallback(source: &str, fallback: H) -> Result<Self> {
+        let engine = engine();
+        let ast = engine.compile(source).map_err(|e| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("script: {}", e),
+            ))
+        })?;
+        let defines = |name: &str, params: usize| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == params)
+        };
+        Ok(Self {
+            on_open: defines("on_open", 1),
+            on_message: defines("on_message", 2),
+            engine,
+            ast,
+            fallback,
+        })
+    }
+
+    /// スクリプトの関数を呼び、`conn` に積まれた動作を実行する。エラーはログに出して () を返す
+    fn call(&self, conn: &mut Connection, name: &str, args: Vec<Dynamic>) -> Dynamic {
+        let actions = Arc::new(Mutex::new(vec![]));
+        let mut call_args = vec![Dynamic::from(Conn {
+            id: conn.id(),
+            path: conn.path().to_string(),
+            actions: actions.clone(),
+        })];
+        call_args.extend(args);
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, call_args)
+            .unwrap_or_else(|e| {
+                warning!(
+                    "script_failed",
+                    { id: conn.id(), function: name, error: e.to_string() },
+                    "script {} failed on connection {}: {}",
+                    name,
+                    conn.id(),
+                    e
+                );
+                Dynamic::UNIT
+            });
+
+        let actions = std::mem::take(&mut *actions.lock().unwrap());
+        for action in actions {
+            match action {
+                Action::Reply(message) => {
+                    let _ = conn.send(message);
+                }
+                Action::Publish(room, message) => {
+                    let _ = conn.publish(&room, message);
+                }
+                Action::Close(code, reason) => {
+                    let _ = conn.close(code, &reason);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine
+        .register_type_with_name::<Conn>("Conn")
+        .register_get("id", |conn: &mut Conn| conn.id as i64)
+        .register_get("path", |conn: &mut Conn| conn.path.clone())
+        .register_fn("reply", |conn: &mut Conn, text: &str| {
+            conn.push(Action::Reply(Message::Text(text.to_string())))
+        })
+        .register_fn("reply", |conn: &mut Conn, data: Blob| {
+            conn.push(Action::Reply(Message::Binary(data)))
+        })
+        .register_fn("publish", |conn: &mut Conn, room: &str, text: &str| {
+            conn.push(Action::Publish(
+                room.to_string(),
+                Message::Text(text.to_string()),
+            ))
+        })
+        .register_fn("publish", |conn: &mut Conn, room: &str, data: Blob| {
+            conn.push(Action::Publish(room.to_string(), Message::Binary(data)))
+        })
+        .register_fn("close", |conn: &mut Conn, code: i64, reason: &str| {
+            conn.push(Action::Close(code as u16, reason.to_string()))
+        });
+    engine
+}
+
+impl Conn {
+    fn push(&mut self, action: Action) {
+        self.actions.lock().unwrap().push(action);
+    }
+}
+
+impl<H: Handler> Handler for RhaiScript<H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        self.fallback.select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        if self.on_open {
+            let _ = self.call(conn, "on_open", vec![]);
+        }
+        self.fallback.on_open(conn);
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        if !self.on_message {
+            return self.fallback.on_message(conn, message);
+        }
+        let arg = match message {
+            Message::Text(text) => Dynamic::from(text),
+            Message::Binary(data) => Dynamic::from_blob(data),
+        };
+        let result = self.call(conn, "on_message", vec![arg]);
+        let forward = if result.is_string() {
+            result.into_string().ok().map(Message::Text)
+        } else if result.is_blob() {
+            result.into_blob().ok().map(Message::Binary)
+        } else {
+            None
+        };
+        if let Some(message) = forward {
+            self.fallback.on_message(conn, message);
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        self.fallback.on_peer_close(conn, code, reason);
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        self.fallback.on_pong(conn, payload, latency);
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        self.fallback.on_close(id);
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        self.fallback.on_error(id, error, terminated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// 受信したメッセージを `echo: ` を付けて返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            if let Message::Text(text) = message {
+                let _ = conn.send(Message::Text(format!("echo: {}", text)));
+            }
+        }
+    }
+
+    const SCRIPT: &str = r#"
+        fn on_open(conn) {
+            conn.reply(`welcome ${conn.path}`);
+        }
+
+        fn on_message(conn, message) {
+            if type_of(message) == "blob" {
+                conn.reply(message + blob(1, 0));
+                return;
+            }
+            if message == "!ping" {
+                conn.reply("pong");
+                return;
+            }
+            if message == "!bye" {
+                conn.close(4000, "bye");
+                return;
+            }
+            if message == "!loop" {
+                loop {}
+            }
+            message.replace("spam", "****");
+            message
+        }
+    "#;
+
+    fn text(s: &str) -> Option<Message> {
+        Some(Message::Text(s.to_string()))
+    }
+
+    #[test]
+    fn replies_transforms_and_forwards() {
+        let script = RhaiScript::parse_with_fallback(SCRIPT, Echo).unwrap();
+        let (server, _) = testing::spawn_test_server(script).unwrap();
+        let mut client = server.connect("/chat").unwrap();
+        assert_eq!(client.recv().unwrap(), text("welcome /chat"));
+
+        client.send(Message::Text("!ping".to_string())).unwrap();
+        assert_eq!(client.recv().unwrap(), text("pong"));
+        client.send(Message::Text("no spam".to_string())).unwrap();
+        assert_eq!(client.recv().unwrap(), text("echo: no ****"));
+        client.send(Message::Binary(vec![1, 2])).unwrap();
+        assert_eq!(client.recv().unwrap(), Some(Message::Binary(vec![1, 2, 0])));
+
+        // 無限ループは操作の上限で止まり、接続は使い続けられる
+        client.send(Message::Text("!loop".to_string())).unwrap();
+        client.send(Message::Text("!ping".to_string())).unwrap();
+        assert_eq!(client.recv().unwrap(), text("pong"));
+
+        client.send(Message::Text("!bye".to_string())).unwrap();
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close(), Some(&(4000, "bye".to_string())));
+    }
+
+    #[test]
+    fn forwards_everything_without_on_message() {
+        let script = RhaiScript::parse_with_fallback("let unused = 1;", Echo).unwrap();
+        let (server, _) = testing::spawn_test_server(script).unwrap();
+        let mut client = server.connect("/").unwrap();
+        client.send(Message::Text("hello".to_string())).unwrap();
+        assert_eq!(client.recv().unwrap(), text("echo: hello"));
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        match RhaiScript::parse("fn on_message(conn, message) {") {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("expected a syntax error"),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// 1つのスレッドで複数の接続の受信を待つ
+//
+// `Selector` に登録した `Client` のうち、読み込めるデータのあるもののキーを `select` で返す。
+// 返されたクライアントから `try_recv` でメッセージを取り出す。接続ごとにスレッドを立てずに済む
+//
+//   let mut selector = Selector::new()?;
+//   selector.register("a", Client::connect("ws://127.0.0.1:7778/")?)?;
+//   loop {
+//       for key in selector.select(None)? {
+//           let client = selector.get_mut(&key).unwrap();
+//           while let TryRecv::Message(message) = client.try_recv()? { ... }
+//       }
+//   }
+//
+// `reactor` (epoll・kqueue) で待つので、受信するまでCPUを使わない。
+// `Client` のbufferに受信済みのデータが残っていれば、待たずにそのキーを返す
+
+use std::{
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client::Client,
+    error::Result,
+    reactor::{self, Event, Interest, Reactor},
+};
+
+struct Entry<K> {
+    key: K,
+    token: usize,
+    client: Client,
+}
+
+pub struct Selector<K> {
+    reactor: Box<dyn Reactor>,
+    entries: Vec<Entry<K>>,
+    next_token: usize,
+}
+
+impl<K: Clone + PartialEq> Selector<K> {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            reactor: reactor::new()?,
+            entries: vec![],
+            next_token: 0,
+        })
+    }
+
+    /// 同じキーで登録済みなら置き換え、前のクライアントを返す
+    pub fn register(&mut self, key: K, client: Client) -> Result<Option<Client>> {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.key == key) {
+            self.reactor.deregister(entry.client.as_raw_fd())?;
+            self.reactor
+                .register(client.as_raw_fd(), entry.token, Interest::Readable)?;
+            return Ok(Some(std::mem::replace(&mut entry.client, client)));
+        }
+        let token = self.next_token;
+        self.reactor
+            .register(client.as_raw_fd(), token, Interest::Readable)?;
+        self.next_token += 1;
+        self.entries.push(Entry { key, token, client });
+        Ok(None)
+    }
+
+    pub fn deregister(&mut self, key: &K) -> Option<Client> {
+        let index = self.entries.iter().position(|entry| entry.key == *key)?;
+        let entry = self.entries.remove(index);
+        let _ = self.reactor.deregister(entry.client.as_raw_fd());
+        Some(entry.client)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut Client> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.key == *key)
+            .map(|entry| &mut entry.client)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|entry| &entry.key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 読み込めるデータ (Closeや切断を含む) のある接続のキーを、1つ以上になるまで待って返す。
+    /// `timeout` が過ぎたら空を返す。None なら無期限に待つ。
+    /// 切断された接続は、`deregister` するまで毎回返す
+    pub fn select(&mut self, timeout: Option<Duration>) -> Result<Vec<K>> {
+        let buffered = self
+            .entries
+            .iter()
+            .filter(|entry| entry.client.has_buffered())
+            .map(|entry| entry.key.clone())
+            .collect::<Vec<_>>();
+        if !buffered.is_empty() {
+            return Ok(buffered);
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut events = vec![];
+        loop {
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            events.clear();
+            self.reactor.poll(&mut events, timeout)?;
+            let ready = self.keys_of(&events);
+            if !ready.is_empty() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(ready);
+            }
+        }
+    }
+
+    fn keys_of(&self, events: &[Event]) -> Vec<K> {
+        self.entries
+            .iter()
+            .filter(|entry| events.iter().any(|event| event.token == entry.token))
+            .map(|entry| entry.key.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        client::TryRecv, connection::Connection, handler::Handler, message::Message,
+        server::Server, testing,
+    };
+
+    /// 接続したクライアントに "hello" を送り、受信したメッセージをそのまま返す
+    struct Greeter;
+
+    impl Handler for Greeter {
+        fn on_open(&self, conn: &mut Connection) {
+            let _ = conn.send(Message::Text("hello".to_string()));
+        }
+
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    #[test]
+    fn returns_keys_of_clients_with_data() {
+        let server = testing::spawn_server(Server::bind("127.0.0.1:0", Greeter).unwrap()).unwrap();
+        let mut selector = Selector::new().unwrap();
+        for key in ["a", "b"] {
+            let mut client = server.connect("/").unwrap();
+            assert_eq!(
+                client.recv().unwrap(),
+                Some(Message::Text("hello".to_string()))
+            );
+            assert!(selector.register(key, client).unwrap().is_none());
+        }
+        assert!(selector
+            .select(Some(Duration::from_millis(50)))
+            .unwrap()
+            .is_empty());
+
+        selector
+            .get_mut(&"b")
+            .unwrap()
+            .send(Message::Text("ping".to_string()))
+            .unwrap();
+        assert_eq!(
+            selector.select(Some(Duration::from_secs(5))).unwrap(),
+            ["b"]
+        );
+        let client = selector.get_mut(&"b").unwrap();
+        assert_eq!(
+            client.try_recv().unwrap(),
+            TryRecv::Message(Message::Text("ping".to_string()))
+        );
+        assert_eq!(client.try_recv().unwrap(), TryRecv::Empty);
+
+        assert!(selector.deregister(&"a").is_some());
+        assert_eq!(selector.keys().collect::<Vec<_>>(), [&"b"]);
+    }
+
+    #[test]
+    fn returns_buffered_clients_without_waiting() {
+        let server = testing::spawn_server(Server::bind("127.0.0.1:0", Greeter).unwrap()).unwrap();
+        let mut client = server.connect("/").unwrap();
+        client.send(Message::Text("ping".to_string())).unwrap();
+        // "hello" と "ping" の両方が届いてから1つ目を取り出すと、2つ目はClientのbufferに残る
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            client.try_recv().unwrap(),
+            TryRecv::Message(Message::Text("hello".to_string()))
+        );
+        let mut selector = Selector::new().unwrap();
+        selector.register(0, client).unwrap();
+        assert_eq!(selector.select(Some(Duration::ZERO)).unwrap(), [0]);
+        assert_eq!(
+            selector.get_mut(&0).unwrap().try_recv().unwrap(),
+            TryRecv::Message(Message::Text("ping".to_string()))
+        );
+    }
+}
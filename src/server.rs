@@ -0,0 +1,1257 @@
+use std::{
+    io::{self, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+#[cfg(all(unix, feature = "reactor"))]
+use crate::event_loop;
+#[cfg(feature = "tcp-keepalive")]
+use crate::keepalive::TcpKeepalive;
+#[cfg(unix)]
+use crate::restart::Restarter;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsConfig};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
+use crate::{
+    admin::Admin,
+    ban::BanList,
+    chaos::Chaos,
+    connection::{Connection, ConnectionId, Extensions},
+    dashboard,
+    error::{Error, Result, ViolationKind},
+    extension::{self, Extension},
+    frame::{Frame, Opcode, DEFAULT_MAX_PAYLOAD_LEN},
+    handler::Handler,
+    handshake::{self, Request},
+    http,
+    hub::Hub,
+    interceptor::Interceptor,
+    ipfilter::IpFilter,
+    keepalive::{self, Keepalive},
+    log::{debug, info, warning},
+    message::Message,
+    middleware::{self, Handshake, Layer},
+    polling,
+    record::Recorder,
+    registry::Registry,
+    schema::{OnInvalid, Validator},
+    sse,
+    stats::{Breakdown, Stats},
+    stream::Stream,
+    telemetry::{Span, SpanExporter, Value},
+};
+
+/// サーバーの設定
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// `/dashboard` で統計情報を表示するページを配信する
+    pub dashboard: bool,
+    /// 障害注入モード。None なら無効
+    pub chaos: Option<Chaos>,
+    /// 指定したディレクトリに接続ごとの送受信フレームを `<接続ID>.wsrec` として記録する
+    pub record_dir: Option<PathBuf>,
+    /// 再開可能なセッション。handshakeの応答の `Session-Token` ヘッダーでトークンを発行し、
+    /// 切断からこの時間内に同じトークンで再接続すれば、参加していたroomと切断中のメッセージを引き継ぐ。
+    /// None なら無効
+    pub session_grace: Option<Duration>,
+    /// WebSocketを使えないクライアント向けに `/poll/` でlong-pollingを受け付ける
+    pub long_polling: bool,
+    /// `/events/<room>` でroomに配送されるメッセージをServer-Sent Eventsとして流す
+    pub sse: bool,
+    /// 受信するメッセージの最大のbyte数 (fragmentを結合した後の大きさ)。超えた接続は 1009 で閉じる。
+    /// デフォルトは `frame::DEFAULT_MAX_PAYLOAD_LEN` (64MiB)。None なら制限しない
+    pub max_message_size: Option<usize>,
+    /// 送信するメッセージをこの大きさ (bytes) ごとのフレームに分ける。
+    /// 大きなメッセージの送信中にもPingなどを送れるようになる。None なら分けない
+    pub fragment_size: Option<usize>,
+    /// `feed` で溜めたメッセージを、最初に溜めてからこの時間が過ぎたら自動で書き込む。
+    /// None なら `flush` を呼ぶまで書き込まない
+    pub flush_deadline: Option<Duration>,
+    /// 設定すると、送信するメッセージを接続ごとのキューに入れて別のスレッドで書き込む
+    pub send_queue: Option<SendQueue>,
+    /// 1つの接続から受信するPing・Pong・Closeの数の制限。超えた接続は 1008 で閉じる
+    pub control_frame_limit: ControlFrameLimit,
+    /// handshakeのリクエストを読み込む時間とサイズの制限
+    pub handshake_limits: handshake::Limits,
+    /// 接続を受け付けた直後に送信元のアドレスを調べ、許可されなければhandshakeの前に切断する
+    pub ip_filter: Option<IpFilter>,
+    /// 違反を見つけて接続を閉じるときに送るCloseフレーム
+    pub close_policy: ClosePolicy,
+    /// 受信のない接続にPingを送り、応答がなければ切断する。None なら何もしない
+    pub keepalive: Option<Keepalive>,
+    /// 受け付けた接続にOSのTCP keepaliveを設定する。None ならOSの設定のまま
+    #[cfg(feature = "tcp-keepalive")]
+    pub tcp_keepalive: Option<TcpKeepalive>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dashboard: false,
+            chaos: None,
+            record_dir: None,
+            session_grace: None,
+            long_polling: false,
+            sse: false,
+            max_message_size: Some(DEFAULT_MAX_PAYLOAD_LEN),
+            fragment_size: None,
+            flush_deadline: None,
+            send_queue: None,
+            control_frame_limit: ControlFrameLimit::default(),
+            handshake_limits: handshake::Limits::default(),
+            ip_filter: None,
+            close_policy: ClosePolicy::default(),
+            keepalive: None,
+            #[cfg(feature = "tcp-keepalive")]
+            tcp_keepalive: None,
+        }
+    }
+}
+
+/// `window` の間に `max` 個までControlフレームを受け付ける。
+/// Pingのたびに Pong を返すので、大量に送られるとCPUを使い続けることになる
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ControlFrameLimit {
+    pub max: u32,
+    pub window: Duration,
+}
+
+impl Default for ControlFrameLimit {
+    fn default() -> Self {
+        Self {
+            max: 100,
+            window: Duration::from_secs(1),
+        }
+    }
+}
+
+/// 接続ごとの送信キュー。`capacity` 個のメッセージが溜まったら `policy` に従う
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SendQueue {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+/// 送信キューが一杯になったときの動作
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// 指定したstatus code (1008 や 1013) で接続を閉じる
+    Close(u16),
+    /// 一番古いメッセージを捨てて、新しいメッセージを入れる
+    DropOldest,
+    /// 新しいメッセージを捨てる
+    DropNewest,
+}
+
+/// 違反の種類ごとの、接続を閉じるCloseフレーム。
+/// デフォルトはRFC 6455のstatus codeで、reasonは送らない
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClosePolicy {
+    actions: [CloseAction; ViolationKind::ALL.len()],
+}
+
+/// `reason` が true ならエラーの内容をreasonとして送る (123bytesまで)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CloseAction {
+    pub code: u16,
+    pub reason: bool,
+}
+
+impl Default for ClosePolicy {
+    fn default() -> Self {
+        Self {
+            actions: ViolationKind::ALL.map(|kind| CloseAction {
+                code: kind.default_close_code(),
+                reason: false,
+            }),
+        }
+    }
+}
+
+impl ClosePolicy {
+    pub fn get(&self, kind: ViolationKind) -> CloseAction {
+        self.actions[kind as usize]
+    }
+
+    pub fn set(mut self, kind: ViolationKind, code: u16, reason: bool) -> Self {
+        self.actions[kind as usize] = CloseAction { code, reason };
+        self
+    }
+
+    /// `error` で接続を閉じるときのCloseフレーム。違反でなければ None
+    fn frame(&self, error: &Error) -> Option<Frame> {
+        let action = self.get(error.violation_kind()?);
+        let mut reason = match action.reason {
+            true => error.to_string(),
+            false => String::new(),
+        };
+        // Controlフレームのpayloadは125bytesまで (status codeの2bytesを除く)
+        let mut len = reason.len().min(123);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        reason.truncate(len);
+        Some(Frame::close(action.code, &reason))
+    }
+}
+
+pub struct Server<H: Handler> {
+    listener: TcpListener,
+    shared: Arc<Shared<H>>,
+}
+
+/// 接続ごとのスレッドから参照する状態
+pub(crate) struct Shared<H> {
+    pub handler: H,
+    pub config: Config,
+    pub registry: Arc<Registry>,
+    pub stats: Arc<Stats>,
+    /// route (クエリを除いたpath) ごとの統計
+    pub routes: Arc<Breakdown>,
+    /// true の間は新しい接続を受け付けない
+    pub draining: Arc<AtomicBool>,
+    /// 待ち受けソケットを新しいプロセスに引き継いだ。受け付けを止める (`restart` を参照)
+    pub handed_off: Arc<AtomicBool>,
+    pub exporter: Option<Arc<dyn SpanExporter>>,
+    pub hub: Arc<Hub>,
+    /// long-pollingのセッションが自分自身に接続するためのアドレス
+    pub addr: SocketAddr,
+    pub polling: Arc<polling::Sessions>,
+    /// handshakeのmiddleware (外側から順に)
+    pub layers: Vec<Box<dyn Layer>>,
+    /// (pathのprefix, メッセージのInterceptor)
+    pub interceptors: Vec<(String, Arc<dyn Interceptor>)>,
+    /// (pathのprefix, メッセージのValidator, 失敗したときの扱い)
+    pub validators: Vec<(String, Arc<dyn Validator>, OnInvalid)>,
+    /// 次の接続のID
+    pub next_id: AtomicU64,
+    /// 違反を繰り返したアドレスを一時的に拒否する
+    pub bans: Option<Arc<BanList>>,
+    /// Sec-WebSocket-Extensionsで合意できる拡張 (優先する順)
+    pub extensions: Vec<Box<dyn Extension>>,
+    /// TLSで受け付ける場合の設定 (`run` のみ)
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl<H: Handler> Server<H> {
+    pub fn bind<A: ToSocketAddrs>(addr: A, handler: H) -> io::Result<Self> {
+        Self::from_listener(TcpListener::bind(addr)?, handler)
+    }
+
+    /// 待ち受け済みのソケットから作る。前のプロセスから引き継いだソケット (`restart::inherited`) などに使う
+    pub fn from_listener(listener: TcpListener, handler: H) -> io::Result<Self> {
+        let mut addr = listener.local_addr()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        Ok(Self {
+            listener,
+            shared: Arc::new(Shared {
+                handler,
+                config: Config::default(),
+                registry: Arc::new(Registry::default()),
+                stats: Arc::new(Stats::default()),
+                routes: Arc::default(),
+                draining: Arc::new(AtomicBool::new(false)),
+                handed_off: Arc::new(AtomicBool::new(false)),
+                exporter: None,
+                hub: Hub::new(),
+                addr,
+                polling: Arc::default(),
+                layers: vec![],
+                interceptors: vec![],
+                validators: vec![],
+                next_id: AtomicU64::new(1),
+                bans: None,
+                extensions: vec![],
+                #[cfg(feature = "tls")]
+                tls: None,
+            }),
+        })
+    }
+
+    /// 設定を差し替える。`run` の前に呼ぶこと
+    pub fn with_config(mut self, config: Config) -> Self {
+        if let Some(keepalive) = config.keepalive {
+            keepalive::start(keepalive, Arc::downgrade(&self.shared.registry));
+        }
+        Arc::get_mut(&mut self.shared)
+            .expect("with_config must be called before run")
+            .config = config;
+        self
+    }
+
+    /// handshake・メッセージ・closeをspanとして記録し、exporterに渡す。`run` の前に呼ぶこと
+    pub fn with_span_exporter<E: SpanExporter>(mut self, exporter: E) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_span_exporter must be called before run")
+            .exporter = Some(Arc::new(exporter));
+        self
+    }
+
+    /// Hubを差し替える (Redisで他のサーバーとpublishを共有する場合など)。`run` の前に呼ぶこと
+    pub fn with_hub(mut self, hub: Arc<Hub>) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_hub must be called before run")
+            .hub = hub;
+        self
+    }
+
+    /// handshakeのmiddlewareを追加する。先に追加したものほど外側になる。`run` の前に呼ぶこと
+    pub fn with_layer<L: Layer>(mut self, layer: L) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_layer must be called before run")
+            .layers
+            .push(Box::new(layer));
+        self
+    }
+
+    /// プロトコル違反・認証の失敗・レート制限の超過を繰り返したアドレスを一時的に拒否する。
+    /// 同じBanListを管理APIなどから操作できるように `Arc` で渡す。`run` の前に呼ぶこと
+    pub fn with_ban_list(mut self, bans: Arc<BanList>) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_ban_list must be called before run")
+            .bans = Some(bans);
+        self
+    }
+
+    /// Sec-WebSocket-Extensionsで合意できる拡張を追加する。先に追加したものから順に合意し、
+    /// 送信するフレームにもその順に適用する。`run` の前に呼ぶこと
+    pub fn with_extension<E: Extension>(mut self, extension: E) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_extension must be called before run")
+            .extensions
+            .push(Box::new(extension));
+        self
+    }
+
+    /// 受け付けた接続をTLSで終端する (`wss://`)。`run` の前に呼ぶこと。
+    /// TLSのhandshakeは接続ごとのスレッドで行うので、`run_event_loop`・`run_io_uring` では使えない
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> io::Result<Self> {
+        let config = tls.build()?;
+        Arc::get_mut(&mut self.shared)
+            .expect("with_tls must be called before run")
+            .tls = Some(config);
+        #[cfg(feature = "acme")]
+        tls.start_acme();
+        Ok(self)
+    }
+
+    /// pathが `prefix` で始まる接続で受信するメッセージをValidatorで検証する。
+    /// 失敗したメッセージはHandlerに渡さず、`on_invalid` に従って扱う。`run` の前に呼ぶこと
+    pub fn with_validator<V: Validator>(
+        mut self,
+        prefix: &str,
+        validator: V,
+        on_invalid: OnInvalid,
+    ) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_validator must be called before run")
+            .validators
+            .push((prefix.to_string(), Arc::new(validator), on_invalid));
+        self
+    }
+
+    /// pathが `prefix` で始まる接続で送受信するメッセージにInterceptorを適用する。
+    /// 先に追加したものから順に適用される。`run` の前に呼ぶこと
+    pub fn with_interceptor<I: Interceptor>(mut self, prefix: &str, interceptor: I) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_interceptor must be called before run")
+            .interceptors
+            .push((prefix.to_string(), Arc::new(interceptor)));
+        self
+    }
+
+    /// roomへのbroadcastに使うHub
+    pub fn hub(&self) -> Arc<Hub> {
+        self.shared.hub.clone()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// 接続の一覧・切断を行うための管理APIを取得する
+    pub fn admin(&self) -> Admin {
+        Admin::new(
+            self.shared.registry.clone(),
+            self.shared.stats.clone(),
+            self.shared.routes.clone(),
+            self.shared.hub.clone(),
+            self.shared.draining.clone(),
+        )
+    }
+
+    /// 待ち受けソケットを新しいプロセスに引き継いで再起動するためのRestarterを取得する
+    #[cfg(unix)]
+    pub fn restarter(&self) -> io::Result<Restarter> {
+        Ok(Restarter::new(
+            self.listener.try_clone()?,
+            self.shared.addr,
+            self.shared.handed_off.clone(),
+            self.admin(),
+        ))
+    }
+
+    /// 他のHTTPサーバーが受け付けたhandshakeを引き取るためのUpgraderを取得する。
+    /// `with_*` で設定した後に呼ぶこと。Upgraderだけを使う場合は `run` を呼ばなくてよい
+    pub fn upgrader(&self) -> Upgrader<H> {
+        Upgrader {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// TCPの待ち受け
+    pub fn run(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            if !self.shared.admits(&mut stream) {
+                continue;
+            }
+
+            let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+
+            // 1接続につき1スレッド
+            let shared = self.shared.clone();
+            thread::spawn(move || handle(id, stream, &shared));
+
+            // 引き継いだ後に受け付けた接続は処理するが、次の接続は新しいプロセスに任せる
+            if self.shared.handed_off.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `run` と同じだが、接続ごとにスレッドを使わず、`threads` 個のスレッドのevent loopで全ての接続を扱う。
+    /// Handlerはevent loopのスレッドで呼ばれるので、Handlerの中で長く待たないこと (`event_loop` を参照)
+    #[cfg(all(unix, feature = "reactor"))]
+    pub fn run_event_loop(self, threads: usize) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        self.shared.rejects_tls()?;
+        event_loop::run(self.listener, self.shared, threads)
+    }
+
+    /// `run_event_loop` と同じだが、io_uringで全ての接続の読み書きをまとめて登録する (Linuxのみ)。
+    /// 書き込みは溜めておき、event loopが送るので待たずに戻る (`uring` を参照)
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn run_io_uring(self, threads: usize) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        self.shared.rejects_tls()?;
+        uring::run(self.listener, self.shared, threads)
+    }
+}
+
+/// 他のHTTPサーバー (hyperなど) がリクエストを読み込んだ接続を引き取り、WebSocketの接続として処理する。
+/// handshakeの検証・middleware・サブプロトコルの選択と101の応答の書き込みから先はServerと同じ
+pub struct Upgrader<H: Handler> {
+    shared: Arc<Shared<H>>,
+}
+
+impl<H: Handler> Clone for Upgrader<H> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<H: Handler> Upgrader<H> {
+    /// `request` はstreamから読み込み済みのhandshakeのリクエスト。応答はまだ書き込んでいないこと。
+    /// streamは `TcpStream` に限らず、`Stream` を実装していればよい。
+    /// 接続は別スレッドで処理し、割り当てた接続IDを返す
+    pub fn upgrade<S: Stream>(&self, mut stream: S, request: Request) -> io::Result<ConnectionId> {
+        if !self.shared.permits(&stream) {
+            let response = http::response("403 Forbidden", "text/plain", "");
+            stream.write_all(response.as_bytes())?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "address is not allowed",
+            ));
+        }
+
+        if self.shared.draining.load(Ordering::SeqCst) {
+            let response = http::response("503 Service Unavailable", "text/plain", "");
+            stream.write_all(response.as_bytes())?;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "server is draining",
+            ));
+        }
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            if let Some((conn, span, handshake_span)) = open(id, Box::new(stream), &shared) {
+                upgrade(conn, request, span, handshake_span, &shared);
+            }
+        });
+        Ok(id)
+    }
+}
+
+impl<H> Shared<H> {
+    /// `Config::ip_filter` とBanListで送信元のアドレスが許可されているか
+    pub(crate) fn permits(&self, stream: &dyn Stream) -> bool {
+        if self.config.ip_filter.is_none() && self.bans.is_none() {
+            return true;
+        }
+        let allowed = |ip| {
+            self.config
+                .ip_filter
+                .as_ref()
+                .is_none_or(|filter| filter.permits(ip))
+                && self.bans.as_ref().is_none_or(|bans| !bans.is_banned(ip))
+        };
+        match stream.peer_addr() {
+            Ok(addr) if allowed(addr.ip()) => true,
+            Ok(addr) => {
+                debug!(
+                    "ip_denied",
+                    { peer: addr.to_string() },
+                    "denied connection from {}",
+                    addr
+                );
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// 受け付けた接続を処理するか。許可されない接続元は切断し、drain中なら503を返す。
+    /// 新しいプロセスに引き継いだ後に受け付けた接続は、drain中でも処理する。
+    /// 処理する接続には `Config::tcp_keepalive` を設定する
+    pub(crate) fn admits(&self, stream: &mut TcpStream) -> bool {
+        if !self.permits(stream) {
+            let _ = stream.shutdown(Shutdown::Both);
+            return false;
+        }
+        if self.draining.load(Ordering::SeqCst) && !self.handed_off.load(Ordering::SeqCst) {
+            // TLSのhandshake前なので、503を返さずに切断する
+            #[cfg(feature = "tls")]
+            if self.tls.is_some() {
+                let _ = stream.shutdown(Shutdown::Both);
+                return false;
+            }
+            let response = http::response("503 Service Unavailable", "text/plain", "");
+            let _ = stream.write_all(response.as_bytes());
+            return false;
+        }
+        #[cfg(feature = "tcp-keepalive")]
+        if let Some(tcp_keepalive) = &self.config.tcp_keepalive {
+            if let Err(e) = keepalive::set_tcp_keepalive(stream, tcp_keepalive) {
+                warning!(
+                    "tcp_keepalive_failed",
+                    { error: e.to_string() },
+                    "failed to set tcp keepalive: {}",
+                    e
+                );
+            }
+        }
+        true
+    }
+
+    /// event loopはTLSを扱わないので、`with_tls` していればエラーにする
+    #[cfg(all(unix, feature = "reactor", feature = "tls"))]
+    fn rejects_tls(&self) -> io::Result<()> {
+        if self.tls.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "tls is only supported by Server::run",
+            ));
+        }
+        Ok(())
+    }
+
+    /// 違反を記録する。I/Oのエラーは違反としない
+    pub(crate) fn strike(&self, conn: &Connection, e: &Error) {
+        if let (Some(bans), false) = (&self.bans, matches!(e, Error::Io(_))) {
+            bans.strike(conn.peer_addr().ip(), &e.to_string());
+        }
+    }
+}
+
+/// 接続を作り、接続全体とhandshakeのspanを始める
+pub(crate) fn open<H: Handler>(
+    id: ConnectionId,
+    stream: Box<dyn Stream>,
+    shared: &Shared<H>,
+) -> Option<(Connection, Option<Span>, Option<Span>)> {
+    let mut conn = match Connection::new(id, stream, shared.stats.clone(), shared.hub.clone()) {
+        Ok(conn) => conn,
+        Err(e) => {
+            shared.handler.on_error(id, &Error::Io(e), true);
+            return None;
+        }
+    };
+    conn.set_chaos(shared.config.chaos.clone().map(Arc::new));
+    conn.set_control_frame_limit(shared.config.control_frame_limit);
+    conn.set_max_message_size(shared.config.max_message_size);
+    conn.set_fragment_size(shared.config.fragment_size);
+    conn.set_flush_deadline(shared.config.flush_deadline);
+
+    let span = shared.exporter.as_ref().map(|_| {
+        let mut span = Span::root("websocket.connection");
+        span.set_attribute("websocket.connection_id", Value::Int(id as i64));
+        span.set_attribute("net.peer", Value::String(conn.peer_addr().to_string()));
+        span
+    });
+    let handshake_span = span.as_ref().map(|span| span.child("websocket.handshake"));
+    Some((conn, span, handshake_span))
+}
+
+fn handle<H: Handler>(id: ConnectionId, stream: TcpStream, shared: &Shared<H>) {
+    #[cfg(feature = "tls")]
+    let stream: Box<dyn Stream> = match &shared.tls {
+        Some(config) => match tls::accept(stream, config, &shared.config.handshake_limits) {
+            Ok(stream) => Box::new(stream),
+            Err(e) => {
+                debug!(
+                    "tls_handshake_failed",
+                    { connection_id: id, error: e.to_string() },
+                    "connection {}: tls handshake failed: {}",
+                    id,
+                    e
+                );
+                return;
+            }
+        },
+        None => Box::new(stream),
+    };
+    #[cfg(not(feature = "tls"))]
+    let stream = Box::new(stream);
+    let Some((mut conn, span, handshake_span)) = open(id, stream, shared) else {
+        return;
+    };
+    let handler = &shared.handler;
+    let exporter = shared.exporter.as_deref();
+
+    let (request, body) =
+        match Request::read_head_within(conn.stream(), &shared.config.handshake_limits) {
+            Ok(head) => head,
+            Err(e) => {
+                shared.strike(&conn, &e);
+                end_with_error(handshake_span, span, exporter, &e);
+                reject(&mut conn, handler, e);
+                return;
+            }
+        };
+
+    if handles_http(&request, shared) {
+        serve_http(conn, &request, body, shared);
+        return;
+    }
+
+    upgrade(conn, request, span, handshake_span, shared);
+}
+
+/// WebSocket以外で応答するリクエスト (dashboard・SSE・long-polling)
+pub(crate) fn handles_http<H: Handler>(request: &Request, shared: &Shared<H>) -> bool {
+    (shared.config.dashboard && dashboard::handles(request))
+        || (shared.config.sse && sse::handles(request))
+        || (shared.config.long_polling && polling::handles(request))
+}
+
+/// `handles_http` のリクエストに応答する。SSEなどは接続が閉じるまで戻らない
+pub(crate) fn serve_http<H: Handler>(
+    conn: Connection,
+    request: &Request,
+    body: Vec<u8>,
+    shared: &Shared<H>,
+) {
+    if shared.config.dashboard && dashboard::handles(request) {
+        dashboard::serve(conn, request, shared);
+    } else if shared.config.sse && sse::handles(request) {
+        sse::serve(conn, request, &shared.hub);
+    } else if shared.config.long_polling && polling::handles(request) {
+        polling::serve(conn, request, body, &shared.polling, shared.addr);
+    }
+}
+
+/// WebSocketのhandshakeのリクエストを処理し、受け付けたら接続が閉じるまで処理する
+fn upgrade<H: Handler>(
+    conn: Connection,
+    request: Request,
+    span: Option<Span>,
+    handshake_span: Option<Span>,
+    shared: &Shared<H>,
+) {
+    let Some((mut conn, span)) = establish(conn, request, span, handshake_span, shared) else {
+        return;
+    };
+    let trace = span.as_ref().zip(shared.exporter.as_deref());
+    let result = serve(&mut conn, &shared.handler, trace);
+    finish(conn, span, result, shared);
+}
+
+/// handshakeに応答して `Handler::on_open` まで進める。拒否した場合は None
+pub(crate) fn establish<H: Handler>(
+    mut conn: Connection,
+    request: Request,
+    span: Option<Span>,
+    mut handshake_span: Option<Span>,
+    shared: &Shared<H>,
+) -> Option<(Connection, Option<Span>)> {
+    let id = conn.id();
+    let handler = &shared.handler;
+    let exporter = shared.exporter.as_deref();
+
+    if let Some(span) = handshake_span.as_mut() {
+        span.set_attribute("http.target", Value::String(request.path.clone()));
+    }
+    let mut handshake = Handshake {
+        request,
+        peer_addr: conn.peer_addr(),
+        response_headers: vec![],
+        #[cfg(feature = "jwt")]
+        claims: None,
+        session: None,
+        extensions: Extensions::new(),
+        #[cfg(feature = "tls")]
+        tls: conn.tls().cloned(),
+    };
+    if let Err(rejection) = middleware::run(&shared.layers, &mut handshake) {
+        let _ = conn.stream().write_all(rejection.to_response().as_bytes());
+        let e = Error::Handshake(format!("{} {}", rejection.status, rejection.reason));
+        if matches!(rejection.status, 401 | 403 | 429) {
+            shared.strike(&conn, &e);
+        }
+        end_with_error(handshake_span, span, exporter, &e);
+        report_error(handler, &conn, &e, true);
+        return None;
+    }
+    let Handshake {
+        request,
+        response_headers: mut headers,
+        #[cfg(feature = "jwt")]
+        claims,
+        session,
+        extensions,
+        ..
+    } = handshake;
+    #[cfg(feature = "jwt")]
+    conn.set_claims(claims);
+    conn.set_user_session(session);
+    conn.extensions_mut().extend(extensions);
+    let mut resume = false;
+    if shared.config.session_grace.is_some() {
+        let (token, resumable) = match session_token(&request) {
+            Some(token) if shared.hub.is_parked(&token) => (token, true),
+            _ => (new_session_token(), false),
+        };
+        headers.push(("Session-Token".to_string(), token.clone()));
+        conn.set_session_token(token);
+        resume = resumable;
+    }
+    let offered = request
+        .header("sec-websocket-protocol")
+        .map(|value| value.split(',').map(str::trim).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if !offered.is_empty() {
+        if let Some(protocol) = handler.select_protocol(&request.path, &offered) {
+            headers.push(("Sec-WebSocket-Protocol".to_string(), protocol.clone()));
+            conn.set_protocol(protocol);
+        }
+    }
+    // ヘッダーは複数あってもよい
+    let offered_extensions = request
+        .headers
+        .iter()
+        .filter(|(key, _)| key == "sec-websocket-extensions")
+        .map(|(_, value)| value.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (extensions, codecs) = extension::negotiate(&shared.extensions, &offered_extensions);
+    if !extensions.is_empty() {
+        headers.push((
+            "Sec-WebSocket-Extensions".to_string(),
+            extension::to_header(&extensions),
+        ));
+    }
+    if let Err(e) = accept(&mut conn, request, &headers) {
+        shared.strike(&conn, &e);
+        end_with_error(handshake_span, span, exporter, &e);
+        reject(&mut conn, handler, e);
+        return None;
+    }
+    conn.set_codecs(codecs);
+    conn.set_negotiated_extensions(extensions);
+    let (route, _) = http::split_query(conn.path());
+    conn.set_route_stats(shared.routes.get(route));
+    if let (Some(handshake_span), Some(exporter)) = (handshake_span, exporter) {
+        handshake_span.end(exporter);
+    }
+
+    if let Some(dir) = &shared.config.record_dir {
+        match Recorder::create(dir.join(format!("{}.wsrec", id))) {
+            Ok(recorder) => conn.set_recorder(recorder),
+            Err(e) => report_error(handler, &conn, &Error::Io(e), false),
+        }
+    }
+
+    let interceptors = shared
+        .interceptors
+        .iter()
+        .filter(|(prefix, _)| conn.path().starts_with(prefix.as_str()))
+        .map(|(_, interceptor)| interceptor.clone())
+        .collect::<Vec<_>>();
+    if !interceptors.is_empty() {
+        conn.set_interceptors(interceptors.into());
+    }
+    let validators = shared
+        .validators
+        .iter()
+        .filter(|(prefix, _, _)| conn.path().starts_with(prefix.as_str()))
+        .map(|(_, validator, on_invalid)| (validator.clone(), *on_invalid))
+        .collect::<Vec<_>>();
+    conn.set_validators(validators);
+    // 送信スレッドは上で設定したInterceptorなどを引き継ぐので、最後に始める
+    if let Some(queue) = shared.config.send_queue {
+        conn.set_send_queue(queue);
+    }
+
+    if resume {
+        let token = conn.session_token().unwrap().to_string();
+        conn.set_resumed(shared.hub.resume(&token, conn.handle()));
+    }
+
+    shared
+        .registry
+        .insert(conn.handle(), conn.path().to_string());
+    info!(
+        "connection_opened",
+        {
+            connection_id: id,
+            peer: conn.peer_addr().to_string(),
+            path: conn.path(),
+        },
+        "connection {} opened: {} {}",
+        id,
+        conn.peer_addr(),
+        conn.path()
+    );
+    handler.on_open(&mut conn);
+    Some((conn, span))
+}
+
+/// `serve` の結果を記録し、接続の後始末をして `Handler::on_close` を呼ぶ
+pub(crate) fn finish<H: Handler>(
+    mut conn: Connection,
+    mut span: Option<Span>,
+    result: Result<()>,
+    shared: &Shared<H>,
+) {
+    let id = conn.id();
+    let handler = &shared.handler;
+    let exporter = shared.exporter.as_deref();
+    if let Err(e) = result {
+        if let Some(frame) = shared.config.close_policy.frame(&e) {
+            let _ = conn.send_frame(frame);
+            shared.strike(&conn, &e);
+        }
+        if let Some(span) = span.as_mut() {
+            span.add_event(
+                "error",
+                vec![("message".to_string(), Value::String(e.to_string()))],
+            );
+        }
+        report_error(handler, &conn, &e, true);
+    }
+
+    shared.registry.remove(id);
+    // Closeを交換せずに切れた場合は、再接続を待つ
+    match (conn.session_token(), shared.config.session_grace) {
+        (Some(token), Some(grace)) if conn.peer_close().is_none() => {
+            shared.hub.park(id, token, grace)
+        }
+        _ => shared.hub.leave_all(id),
+    }
+    let _ = conn.stream().shutdown(Shutdown::Both);
+    info!(
+        "connection_closed",
+        {
+            connection_id: id,
+            peer: conn.peer_addr().to_string(),
+            close_code: conn.peer_close().map_or(0, |(code, _)| *code),
+        },
+        "connection {} closed",
+        id
+    );
+
+    if let (Some(mut span), Some(exporter)) = (span, exporter) {
+        let mut attributes = vec![];
+        if let Some((code, reason)) = conn.peer_close() {
+            attributes.push(("websocket.close_code".to_string(), Value::Int(*code as i64)));
+            attributes.push((
+                "websocket.close_reason".to_string(),
+                Value::String(reason.clone()),
+            ));
+        }
+        span.add_event("close", attributes);
+        span.end(exporter);
+    }
+    handler.on_close(id);
+}
+
+/// handshakeに失敗した場合にspanを閉じる
+pub(crate) fn end_with_error(
+    handshake_span: Option<Span>,
+    span: Option<Span>,
+    exporter: Option<&dyn SpanExporter>,
+    e: &Error,
+) {
+    let Some(exporter) = exporter else {
+        return;
+    };
+    for mut span in handshake_span.into_iter().chain(span) {
+        span.add_event(
+            "error",
+            vec![("message".to_string(), Value::String(e.to_string()))],
+        );
+        span.end(exporter);
+    }
+}
+
+pub(crate) fn reject<H: Handler>(conn: &mut Connection, handler: &H, e: Error) {
+    if let Error::Handshake(_) = e {
+        let _ = conn.stream().write_all(handshake::bad_request().as_bytes());
+    }
+    report_error(handler, conn, &e, true);
+}
+
+/// ログに残してから on_error に渡す
+fn report_error<H: Handler>(handler: &H, conn: &Connection, e: &Error, terminated: bool) {
+    warning!(
+        "connection_error",
+        {
+            connection_id: conn.id(),
+            peer: conn.peer_addr().to_string(),
+            error: e.to_string(),
+            terminated: terminated,
+        },
+        "connection {}: {} (terminated: {})",
+        conn.id(),
+        e,
+        terminated
+    );
+    handler.on_error(conn.id(), e, terminated);
+}
+
+pub(crate) fn accept(
+    conn: &mut Connection,
+    request: Request,
+    headers: &[(String, String)],
+) -> Result<()> {
+    request.validate()?;
+
+    let response =
+        handshake::response_with_headers(request.header("sec-websocket-key").unwrap(), headers);
+    conn.stream().write_all(response.as_bytes())?;
+    conn.stream().flush()?;
+    conn.set_request(request);
+    Ok(())
+}
+
+/// 再接続時に指定されたセッションのトークン (`Session-Token` ヘッダーか `?session=` クエリ)
+fn session_token(request: &Request) -> Option<String> {
+    if let Some(token) = request.header("session-token") {
+        return Some(token.to_string());
+    }
+    let (_, query) = http::split_query(&request.path);
+    query
+        .into_iter()
+        .find(|(key, _)| *key == "session")
+        .map(|(_, token)| token.to_string())
+}
+
+fn new_session_token() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// WebSocketの処理。peerとCloseを交換し終えたら Ok を返す。
+/// `trace` があればメッセージごとに子spanを記録する
+pub(crate) fn serve<H: Handler>(
+    conn: &mut Connection,
+    handler: &H,
+    trace: Option<(&Span, &dyn SpanExporter)>,
+) -> Result<()> {
+    // fragmentされたメッセージの先頭フレームのopcodeと、結合中のpayload
+    let mut fragments: Option<(Opcode, Vec<u8>)> = None;
+
+    loop {
+        let frame = match conn.read_frame() {
+            Ok(frame) => frame,
+            // こちらから閉じた (kickなど) 後の切断はエラーとしない
+            Err(Error::Io(_)) if conn.is_closing() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if dispatch(conn, handler, trace, &mut fragments, frame)? {
+            return Ok(());
+        }
+    }
+}
+
+/// 受信したフレームを1つ処理する。fragmentされたメッセージは最後のフレームまで `fragments` に溜める。
+/// peerとCloseを交換し終えたら true を返す
+pub(crate) fn dispatch<H: Handler>(
+    conn: &mut Connection,
+    handler: &H,
+    trace: Option<(&Span, &dyn SpanExporter)>,
+    fragments: &mut Option<(Opcode, Vec<u8>)>,
+    frame: Frame,
+) -> Result<bool> {
+    debug!(
+        "frame_received",
+        {
+            connection_id: conn.id(),
+            peer: conn.peer_addr().to_string(),
+            opcode: format!("{:?}", frame.opcode),
+            fin: frame.fin,
+            size: frame.payload_len,
+        },
+        "connection {}: {:?} frame (fin: {}, {} bytes)",
+        conn.id(),
+        frame.opcode,
+        frame.fin,
+        frame.payload_len
+    );
+
+    if frame.opcode.is_control() {
+        conn.count_control_frame()?;
+    }
+
+    let (opcode, payload) = match frame.opcode {
+        Opcode::Close => {
+            let peer_close = frame.close_code_and_reason();
+            let (code, reason) = peer_close.clone().unwrap_or((1005, String::new()));
+            handler.on_peer_close(conn, code, &reason);
+            conn.set_peer_close(peer_close);
+            conn.reply_close()?;
+            return Ok(true);
+        }
+        Opcode::Ping => {
+            conn.pong(frame.payload)?;
+            return Ok(false);
+        }
+        Opcode::Pong => {
+            let latency = conn.pong_received(&frame.payload);
+            handler.on_pong(conn, &frame.payload, latency);
+            return Ok(false);
+        }
+        Opcode::Text | Opcode::Binary => {
+            if fragments.is_some() {
+                return Err(Error::Protocol(
+                    "new message before previous one finished".to_string(),
+                ));
+            }
+            if !frame.fin {
+                *fragments = Some((frame.opcode, frame.payload));
+                return Ok(false);
+            }
+            (frame.opcode, frame.payload)
+        }
+        Opcode::Continuation => {
+            let (opcode, mut payload) = fragments.take().ok_or_else(|| {
+                Error::Protocol("continuation frame without a message".to_string())
+            })?;
+            let size = payload.len() + frame.payload.len();
+            if size > conn.max_message_size() {
+                return Err(Error::MessageTooBig(size));
+            }
+            payload.extend_from_slice(&frame.payload);
+            if !frame.fin {
+                *fragments = Some((opcode, payload));
+                return Ok(false);
+            }
+            (opcode, payload)
+        }
+    };
+
+    // Close送信後に届いたデータは捨てる
+    if conn.is_closing() {
+        return Ok(false);
+    }
+
+    let message = if opcode == Opcode::Text {
+        Message::Text(String::from_utf8(payload).map_err(|_| Error::InvalidUtf8)?)
+    } else {
+        Message::Binary(payload)
+    };
+    conn.record_message_in();
+    let Some(message) = conn.intercept_inbound(message) else {
+        return Ok(false);
+    };
+    if let Some((invalid, on_invalid)) = conn.validate(&message) {
+        match on_invalid {
+            OnInvalid::Reply => {
+                debug!(
+                    "invalid_message",
+                    { connection_id: conn.id(), error: invalid.to_string() },
+                    "connection {}: {}",
+                    conn.id(),
+                    invalid
+                );
+                conn.send(invalid.reply())?;
+                return Ok(false);
+            }
+            OnInvalid::Close => return Err(Error::PolicyViolation(invalid.to_string())),
+        }
+    }
+
+    let mut span = trace.map(|(parent, _)| parent.child("websocket.message"));
+    if let Some(span) = span.as_mut() {
+        span.set_attribute("websocket.opcode", Value::String(format!("{:?}", opcode)));
+        span.set_attribute("websocket.message_size", Value::Int(message.len() as i64));
+    }
+    handler.on_message(conn, message);
+    if let (Some(span), Some((_, exporter))) = (span, trace) {
+        span.end(exporter);
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        sync::mpsc::{self, Sender},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::testing::{self, Pipe};
+
+    struct Echo {
+        closed: Mutex<Sender<ConnectionId>>,
+    }
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+
+        fn on_close(&self, id: ConnectionId) {
+            let _ = self.closed.lock().unwrap().send(id);
+        }
+    }
+
+    fn read_response(client: &mut Pipe) -> String {
+        let mut response = vec![];
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn upgrades_streams_other_than_tcp() {
+        let (closed, on_close) = mpsc::channel();
+        let server = Server::bind(
+            "127.0.0.1:0",
+            Echo {
+                closed: Mutex::new(closed),
+            },
+        )
+        .unwrap();
+        let (mut client, mut stream) = testing::pipe();
+        client
+            .write_all(
+                b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        // 他のHTTPサーバーがリクエストを読み込んだ後に引き取る
+        let request = Request::read_from(&mut stream).unwrap();
+        let id = server.upgrader().upgrade(stream, request).unwrap();
+
+        let response = read_response(&mut client);
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert!(
+            response.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            "{}",
+            response
+        );
+
+        let hello = Frame::from(Message::Text("hello".to_string())).masked([1, 2, 3, 4]);
+        client.write_all(&hello.to_bytes()).unwrap();
+        let echoed = Frame::read_from(&mut client).unwrap();
+        assert_eq!(
+            (echoed.opcode, echoed.payload.as_slice()),
+            (Opcode::Text, &b"hello"[..])
+        );
+        let connections = server.admin().connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(
+            (connections[0].id, connections[0].path.as_str()),
+            (id, "/chat")
+        );
+
+        client
+            .write_all(&Frame::close(1000, "bye").masked([5, 6, 7, 8]).to_bytes())
+            .unwrap();
+        let close = Frame::read_from(&mut client).unwrap();
+        assert_eq!(close.opcode, Opcode::Close);
+        assert_eq!(on_close.recv_timeout(Duration::from_secs(5)).unwrap(), id);
+        // サーバーが切断したのでEOFになる
+        assert_eq!(client.read(&mut [0]).unwrap(), 0);
+    }
+
+    #[test]
+    fn limits_message_size_by_default() {
+        assert_eq!(
+            Config::default().max_message_size,
+            Some(DEFAULT_MAX_PAYLOAD_LEN)
+        );
+        let (closed, _on_close) = mpsc::channel();
+        let server = Server::bind(
+            "127.0.0.1:0",
+            Echo {
+                closed: Mutex::new(closed),
+            },
+        )
+        .unwrap();
+        let (mut client, mut stream) = testing::pipe();
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let request = Request::read_from(&mut stream).unwrap();
+        server.upgrader().upgrade(stream, request).unwrap();
+        read_response(&mut client);
+
+        // payloadの長さだけを送り、payloadを確保して読み込む前に 1009 で閉じられる
+        let mut header = vec![0x82, 0x80 | 127];
+        header.extend(u64::MAX.to_be_bytes());
+        header.extend([1, 2, 3, 4]);
+        client.write_all(&header).unwrap();
+        let close = Frame::read_from(&mut client).unwrap();
+        assert_eq!(close.opcode, Opcode::Close);
+        assert_eq!(close.close_code_and_reason().unwrap().0, 1009);
+    }
+}
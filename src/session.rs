@@ -0,0 +1,140 @@
+// cookieで識別するセッション
+//
+// `SessionLayer` をhandshakeのmiddlewareとして登録すると、cookie (デフォルトは `sid`) のIDで
+// `SessionStore` からセッションを読み込み、`Connection::session` で参照できるようにする。
+// セッションの作成 (ログインなど) はHTTPのアプリケーション側で行い、同じstoreに保存しておく
+//
+// 再開可能なセッション (`Config::session_grace`) とは別のもの
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::middleware::{Handshake, Layer, Next, Outcome, Rejection};
+
+/// セッションの内容
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    id: String,
+    values: HashMap<String, String>,
+}
+
+impl Session {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+}
+
+/// セッションの保存先
+pub trait SessionStore: Send + Sync + 'static {
+    fn load(&self, id: &str) -> Option<Session>;
+    fn save(&self, session: Session);
+    fn remove(&self, id: &str);
+}
+
+/// メモリ上のSessionStore。プロセスが終了すると消える
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    fn save(&self, session: Session) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.id.clone(), session);
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+impl<S: SessionStore> SessionStore for Arc<S> {
+    fn load(&self, id: &str) -> Option<Session> {
+        (**self).load(id)
+    }
+
+    fn save(&self, session: Session) {
+        (**self).save(session)
+    }
+
+    fn remove(&self, id: &str) {
+        (**self).remove(id)
+    }
+}
+
+/// cookieのIDでセッションを読み込むhandshakeのmiddleware
+pub struct SessionLayer<S> {
+    store: S,
+    cookie: String,
+    required: bool,
+}
+
+impl<S: SessionStore> SessionLayer<S> {
+    /// handlerからもstoreを使う場合は `Arc<MemoryStore>` などを渡す
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            cookie: "sid".to_string(),
+            required: false,
+        }
+    }
+
+    /// セッションIDのcookieの名前 (デフォルトは sid)
+    pub fn cookie(mut self, name: &str) -> Self {
+        self.cookie = name.to_string();
+        self
+    }
+
+    /// true ならセッションのない接続を 401 で拒否する
+    pub fn required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+}
+
+impl<S: SessionStore> Layer for SessionLayer<S> {
+    fn call(&self, handshake: &mut Handshake, next: Next<'_>) -> Outcome {
+        let session = handshake
+            .request
+            .cookie(&self.cookie)
+            .and_then(|id| self.store.load(id));
+        if session.is_none() && self.required {
+            return Err(Rejection::new(401, "Unauthorized"));
+        }
+        handshake.session = session;
+        next.run(handshake)
+    }
+}
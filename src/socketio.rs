@@ -0,0 +1,617 @@
+// Socket.IO (Engine.IO v4 / Socket.IO v5) 互換のプロトコル層
+//
+// WebSocketのtransportだけに対応する (`/socket.io/?EIO=4&transport=websocket`)。
+// 接続するとサーバーからEngine.IOのOPENパケットを送り、以降は pingInterval ごとにPINGを送る
+//
+// Engine.IOのパケット (Textメッセージ): <種類><データ>
+//   0 OPEN / 1 CLOSE / 2 PING / 3 PONG / 4 MESSAGE
+// MESSAGEのデータはSocket.IOのパケット: <種類>[<namespace>,][<ack id>][JSONの配列]
+//   0 CONNECT / 1 DISCONNECT / 2 EVENT / 3 ACK / 4 CONNECT_ERROR
+//
+// https://socket.io/docs/v4/engine-io-protocol/
+// https://socket.io/docs/v4/socket-io-protocol/
+//
+// バイナリの添付 (BINARY_EVENT / BINARY_ACK) には対応していない
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    connection::{Connection, ConnectionHandle, ConnectionId},
+    error::{Error, Result},
+    handler::Handler,
+    json,
+    log::{debug, warning},
+    message::Message,
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(25);
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Socket.IOのイベントを処理するcallback。
+/// 引数はJSONの文字列のまま渡すので、必要に応じてパースする
+pub trait SocketIoHandler: Send + Sync + 'static {
+    /// namespaceへの接続要求。false を返すと拒否する
+    fn on_connect(&self, _socket: &Socket) -> bool {
+        true
+    }
+
+    /// クライアントがemitしたイベント。クライアントがackを要求していれば `ack` がある
+    fn on_event(&self, _socket: &Socket, _event: &str, _args: Vec<String>, _ack: Option<Ack>) {}
+
+    /// namespaceから切断した (接続が閉じた場合も呼ばれる)
+    fn on_disconnect(&self, _socket: &Socket) {}
+}
+
+/// namespaceごとのクライアントとの接続
+#[derive(Clone)]
+pub struct Socket {
+    handle: ConnectionHandle,
+    namespace: String,
+    acks: Arc<Acks>,
+}
+
+/// クライアントのackに応答するためのハンドル
+pub struct Ack {
+    socket: Socket,
+    id: u64,
+}
+
+type AckCallback = Box<dyn FnOnce(Vec<String>) + Send>;
+
+/// サーバーからのemitに対するackの待ち合わせ
+#[derive(Default)]
+struct Acks {
+    next_id: Mutex<u64>,
+    callbacks: Mutex<HashMap<(ConnectionId, String, u64), AckCallback>>,
+}
+
+impl Socket {
+    pub fn id(&self) -> ConnectionId {
+        self.handle.id()
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// イベントを送る。argsはJSONの値の文字列 (例: `"\"hello\""`, `"{\"a\":1}"`)
+    pub fn emit(&self, event: &str, args: &[&str]) -> Result<()> {
+        self.send(packet(
+            EVENT,
+            &self.namespace,
+            None,
+            &event_args(event, args),
+        ))
+    }
+
+    /// イベントを送り、クライアントのackを受け取ったらcallbackを呼ぶ
+    pub fn emit_with_ack<F>(&self, event: &str, args: &[&str], callback: F) -> Result<()>
+    where
+        F: FnOnce(Vec<String>) + Send + 'static,
+    {
+        let id = {
+            let mut next_id = self.acks.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        self.acks
+            .callbacks
+            .lock()
+            .unwrap()
+            .insert((self.id(), self.namespace.clone(), id), Box::new(callback));
+        self.send(packet(
+            EVENT,
+            &self.namespace,
+            Some(id),
+            &event_args(event, args),
+        ))
+    }
+
+    /// namespaceから切断する
+    pub fn disconnect(&self) -> Result<()> {
+        self.send(packet(DISCONNECT, &self.namespace, None, ""))
+    }
+
+    fn send(&self, packet: String) -> Result<()> {
+        self.handle
+            .send(Message::Text(format!("{}{}", MESSAGE, packet)))
+    }
+}
+
+impl Ack {
+    /// ackとして値を返す。argsはJSONの値の文字列
+    pub fn send(self, args: &[&str]) -> Result<()> {
+        let data = format!("[{}]", args.join(","));
+        self.socket
+            .send(packet(ACK, &self.socket.namespace, Some(self.id), &data))
+    }
+}
+
+// Engine.IOのパケットの種類
+const OPEN: char = '0';
+const CLOSE: char = '1';
+const PING: char = '2';
+const PONG: char = '3';
+const MESSAGE: char = '4';
+
+// Socket.IOのパケットの種類
+const CONNECT: char = '0';
+const DISCONNECT: char = '1';
+const EVENT: char = '2';
+const ACK: char = '3';
+const CONNECT_ERROR: char = '4';
+
+/// Socket.IOのパケットを組み立てる。デフォルトのnamespace (`/`) は省略する
+fn packet(kind: char, namespace: &str, id: Option<u64>, data: &str) -> String {
+    let mut packet = kind.to_string();
+    if namespace != "/" {
+        packet.push_str(namespace);
+        packet.push(',');
+    }
+    if let Some(id) = id {
+        packet.push_str(&id.to_string());
+    }
+    packet.push_str(data);
+    packet
+}
+
+fn event_args(event: &str, args: &[&str]) -> String {
+    let mut items = vec![json::string(event)];
+    items.extend(args.iter().map(|arg| arg.to_string()));
+    format!("[{}]", items.join(","))
+}
+
+/// 受信したSocket.IOのパケット
+struct Packet<'a> {
+    kind: char,
+    namespace: &'a str,
+    id: Option<u64>,
+    data: &'a str,
+}
+
+fn parse(packet: &str) -> Option<Packet<'_>> {
+    let kind = packet.chars().next()?;
+    let mut rest = &packet[kind.len_utf8()..];
+
+    let mut namespace = "/";
+    if rest.starts_with('/') {
+        match rest.find(',') {
+            Some(i) => {
+                namespace = &rest[..i];
+                rest = &rest[i + 1..];
+            }
+            None => {
+                namespace = rest;
+                rest = "";
+            }
+        }
+    }
+
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let id = match digits {
+        0 => None,
+        _ => Some(rest[..digits].parse().ok()?),
+    };
+
+    Some(Packet {
+        kind,
+        namespace,
+        id,
+        data: &rest[digits..],
+    })
+}
+
+/// Socket.IOのクライアントを受け付けるHandler。
+/// `/socket.io/` 以外のpathへの接続は `fallback` に渡す
+pub struct SocketIo<S, H = ()> {
+    handler: S,
+    fallback: H,
+    /// 接続ごとのハンドルと接続済みのnamespace
+    namespaces: Mutex<HashMap<ConnectionId, (ConnectionHandle, HashSet<String>)>>,
+    acks: Arc<Acks>,
+}
+
+impl<S: SocketIoHandler> SocketIo<S> {
+    pub fn new(handler: S) -> Self {
+        Self::with_fallback(handler, ())
+    }
+}
+
+impl<S: SocketIoHandler, H: Handler> SocketIo<S, H> {
+    pub fn with_fallback(handler: S, fallback: H) -> Self {
+        Self {
+            handler,
+            fallback,
+            namespaces: Mutex::new(HashMap::new()),
+            acks: Arc::new(Acks::default()),
+        }
+    }
+
+    fn socket(&self, handle: ConnectionHandle, namespace: &str) -> Socket {
+        Socket {
+            handle,
+            namespace: namespace.to_string(),
+            acks: self.acks.clone(),
+        }
+    }
+
+    fn is_socketio(&self, id: ConnectionId) -> bool {
+        self.namespaces.lock().unwrap().contains_key(&id)
+    }
+
+    fn open(&self, conn: &mut Connection) -> Result<()> {
+        let query = conn.path().split_once('?').map_or("", |(_, query)| query);
+        let params = query.split('&').collect::<Vec<_>>();
+        if !params.contains(&"EIO=4") || !params.contains(&"transport=websocket") {
+            return Err(Error::Protocol(format!(
+                "unsupported socket.io request: {}",
+                conn.path()
+            )));
+        }
+
+        let sid = conn
+            .session_token()
+            .map_or_else(|| format!("{:016x}", rand::random::<u64>()), str::to_string);
+        conn.send(Message::Text(format!(
+            "{}{{\"sid\":{},\"upgrades\":[],\"pingInterval\":{},\"pingTimeout\":{},\"maxPayload\":1000000}}",
+            OPEN,
+            json::string(&sid),
+            PING_INTERVAL.as_millis(),
+            PING_TIMEOUT.as_millis()
+        )))?;
+        self.namespaces
+            .lock()
+            .unwrap()
+            .insert(conn.id(), (conn.handle(), HashSet::new()));
+
+        // Engine.IOのheartbeat
+        let handle = conn.handle();
+        thread::spawn(move || loop {
+            thread::sleep(PING_INTERVAL);
+            if handle.is_closing() || handle.send(Message::Text(PING.to_string())).is_err() {
+                return;
+            }
+        });
+        Ok(())
+    }
+
+    fn receive(&self, conn: &mut Connection, text: &str) -> Result<()> {
+        let Some(kind) = text.chars().next() else {
+            return Ok(());
+        };
+        match kind {
+            PONG => Ok(()),
+            PING => conn.send(Message::Text(format!("{}{}", PONG, &text[1..]))),
+            CLOSE => conn.close(1000, ""),
+            MESSAGE => self.dispatch(conn, &text[1..]),
+            _ => Err(Error::Protocol(format!(
+                "unknown engine.io packet: {}",
+                text
+            ))),
+        }
+    }
+
+    fn dispatch(&self, conn: &mut Connection, text: &str) -> Result<()> {
+        let packet = parse(text)
+            .ok_or_else(|| Error::Protocol(format!("invalid socket.io packet: {}", text)))?;
+        let socket = self.socket(conn.handle(), packet.namespace);
+        let connected = self
+            .namespaces
+            .lock()
+            .unwrap()
+            .get(&conn.id())
+            .is_some_and(|(_, namespaces)| namespaces.contains(packet.namespace));
+
+        match packet.kind {
+            CONNECT if !connected => {
+                if !self.handler.on_connect(&socket) {
+                    return socket.send(packet_error(packet.namespace, "Invalid namespace"));
+                }
+                if let Some((_, namespaces)) = self.namespaces.lock().unwrap().get_mut(&conn.id()) {
+                    namespaces.insert(packet.namespace.to_string());
+                }
+                let data = format!("{{\"sid\":{}}}", json::string(&conn.id().to_string()));
+                socket.send(self::packet(CONNECT, packet.namespace, None, &data))
+            }
+            DISCONNECT if connected => {
+                if let Some((_, namespaces)) = self.namespaces.lock().unwrap().get_mut(&conn.id()) {
+                    namespaces.remove(packet.namespace);
+                }
+                self.handler.on_disconnect(&socket);
+                Ok(())
+            }
+            EVENT if connected => {
+                let mut args = json::split_array(packet.data)
+                    .ok_or_else(|| Error::Protocol(format!("invalid socket.io event: {}", text)))?
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                if args.is_empty() {
+                    return Err(Error::Protocol(format!(
+                        "socket.io event without a name: {}",
+                        text
+                    )));
+                }
+                let event = json::parse_string(&args.remove(0)).ok_or_else(|| {
+                    Error::Protocol(format!("invalid socket.io event name: {}", text))
+                })?;
+                let ack = packet.id.map(|id| Ack {
+                    socket: socket.clone(),
+                    id,
+                });
+                self.handler.on_event(&socket, &event, args, ack);
+                Ok(())
+            }
+            ACK if connected => {
+                let key = (
+                    conn.id(),
+                    packet.namespace.to_string(),
+                    packet.id.unwrap_or(0),
+                );
+                let Some(callback) = self.acks.callbacks.lock().unwrap().remove(&key) else {
+                    return Ok(());
+                };
+                let args = json::split_array(packet.data)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect();
+                callback(args);
+                Ok(())
+            }
+            CONNECT | DISCONNECT | EVENT | ACK => {
+                debug!(
+                    "socketio_ignored",
+                    { connection_id: conn.id(), namespace: packet.namespace },
+                    "socket.io: packet for a namespace in the wrong state: {}",
+                    text
+                );
+                Ok(())
+            }
+            _ => Err(Error::Protocol(format!(
+                "unsupported socket.io packet: {}",
+                text
+            ))),
+        }
+    }
+}
+
+fn packet_error(namespace: &str, message: &str) -> String {
+    let data = format!("{{\"message\":{}}}", json::string(message));
+    packet(CONNECT_ERROR, namespace, None, &data)
+}
+
+impl<S: SocketIoHandler, H: Handler> Handler for SocketIo<S, H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        self.fallback.select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        if !conn.path().starts_with("/socket.io/") {
+            return self.fallback.on_open(conn);
+        }
+        if let Err(e) = self.open(conn) {
+            warning!(
+                "socketio_error",
+                { connection_id: conn.id(), error: e.to_string() },
+                "socket.io: {}",
+                e
+            );
+            let _ = conn.close(1002, "");
+        }
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        if !self.is_socketio(conn.id()) {
+            return self.fallback.on_message(conn, message);
+        }
+        let Message::Text(text) = message else {
+            return;
+        };
+        if let Err(e) = self.receive(conn, &text) {
+            warning!(
+                "socketio_error",
+                { connection_id: conn.id(), error: e.to_string() },
+                "socket.io: {}",
+                e
+            );
+            let _ = conn.close(1002, "");
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        if !self.is_socketio(conn.id()) {
+            self.fallback.on_peer_close(conn, code, reason);
+        }
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        if !self.is_socketio(conn.id()) {
+            self.fallback.on_pong(conn, payload, latency);
+        }
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        let Some((handle, namespaces)) = self.namespaces.lock().unwrap().remove(&id) else {
+            return self.fallback.on_close(id);
+        };
+        self.acks
+            .callbacks
+            .lock()
+            .unwrap()
+            .retain(|(conn, _, _), _| *conn != id);
+        // 切断済みなので、socketへの送信は失敗する
+        for namespace in namespaces {
+            self.handler
+                .on_disconnect(&self.socket(handle.clone(), &namespace));
+        }
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        if !self.is_socketio(id) {
+            self.fallback.on_error(id, error, terminated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{client::Client, server::Server, testing};
+
+    #[test]
+    fn builds_and_parses_packets() {
+        assert_eq!(packet(EVENT, "/", None, "[\"a\"]"), "2[\"a\"]");
+        assert_eq!(packet(ACK, "/chat", Some(12), "[1]"), "3/chat,12[1]");
+        assert_eq!(
+            event_args("hi", &["1", "{\"a\":2}"]),
+            "[\"hi\",1,{\"a\":2}]"
+        );
+
+        let packet = parse("2/chat,12[\"hi\",1]").unwrap();
+        assert_eq!(packet.kind, EVENT);
+        assert_eq!(packet.namespace, "/chat");
+        assert_eq!(packet.id, Some(12));
+        assert_eq!(packet.data, "[\"hi\",1]");
+
+        let packet = parse("0").unwrap();
+        assert_eq!(
+            (packet.kind, packet.namespace, packet.id, packet.data),
+            (CONNECT, "/", None, "")
+        );
+        let packet = parse("0/admin").unwrap();
+        assert_eq!((packet.namespace, packet.data), ("/admin", ""));
+        let packet = parse("3{\"x\":1}").unwrap();
+        assert_eq!((packet.id, packet.data), (None, "{\"x\":1}"));
+
+        assert!(parse("").is_none());
+        // u64に収まらないack id
+        assert!(parse("2123456789012345678901234567890[\"a\"]").is_none());
+    }
+
+    struct Echo {
+        acks: Mutex<mpsc::Sender<Vec<String>>>,
+    }
+
+    impl SocketIoHandler for Echo {
+        fn on_connect(&self, socket: &Socket) -> bool {
+            socket.namespace() != "/private"
+        }
+
+        fn on_event(&self, socket: &Socket, event: &str, args: Vec<String>, ack: Option<Ack>) {
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            match (event, ack) {
+                (_, Some(ack)) => ack.send(&args).unwrap(),
+                ("ask", None) => {
+                    let acks = self.acks.lock().unwrap().clone();
+                    socket
+                        .emit_with_ack("question", &args, move |args| {
+                            let _ = acks.send(args);
+                        })
+                        .unwrap();
+                }
+                (event, None) => socket.emit(event, &args).unwrap(),
+            }
+        }
+    }
+
+    fn recv_text(client: &mut Client) -> String {
+        match client.recv().unwrap() {
+            Some(Message::Text(text)) => text,
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    fn send_text(client: &mut Client, text: &str) {
+        client.send(Message::Text(text.to_string())).unwrap();
+    }
+
+    #[test]
+    fn speaks_socketio_over_websocket() {
+        let (acks, acked) = mpsc::channel();
+        let handler = SocketIo::new(Echo {
+            acks: Mutex::new(acks),
+        });
+        let server = testing::spawn_server(Server::bind("127.0.0.1:0", handler).unwrap()).unwrap();
+        let mut client = server
+            .connect("/socket.io/?EIO=4&transport=websocket")
+            .unwrap();
+
+        let open = recv_text(&mut client);
+        assert!(open.starts_with("0{\"sid\":"), "{}", open);
+        assert!(open.contains("\"pingInterval\":25000"), "{}", open);
+
+        // Engine.IOのPINGにはPONGを返す
+        send_text(&mut client, "2probe");
+        assert_eq!(recv_text(&mut client), "3probe");
+
+        // 接続前のnamespaceへのイベントは無視される
+        send_text(&mut client, "42[\"early\"]");
+        send_text(&mut client, "40");
+        let connected = recv_text(&mut client);
+        assert!(connected.starts_with("40{\"sid\":"), "{}", connected);
+
+        send_text(&mut client, "42[\"echo\",\"hi\",{\"a\":1}]");
+        assert_eq!(recv_text(&mut client), "42[\"echo\",\"hi\",{\"a\":1}]");
+        send_text(&mut client, "427[\"echo\",1,2]");
+        assert_eq!(recv_text(&mut client), "437[1,2]");
+
+        // サーバーからのemitにackを返す
+        send_text(&mut client, "42[\"ask\",\"why\"]");
+        let question = recv_text(&mut client);
+        let packet = parse(&question[1..]).unwrap();
+        assert_eq!(packet.data, "[\"question\",\"why\"]");
+        send_text(
+            &mut client,
+            &format!("43{}[\"because\"]", packet.id.unwrap()),
+        );
+        assert_eq!(
+            acked.recv_timeout(Duration::from_secs(5)).unwrap(),
+            vec!["\"because\""]
+        );
+
+        // namespaceごとに接続する
+        send_text(&mut client, "40/private,");
+        assert_eq!(
+            recv_text(&mut client),
+            "44/private,{\"message\":\"Invalid namespace\"}"
+        );
+        send_text(&mut client, "40/chat,");
+        assert!(recv_text(&mut client).starts_with("40/chat,{\"sid\":"));
+        send_text(&mut client, "42/chat,[\"echo\",\"room\"]");
+        assert_eq!(recv_text(&mut client), "42/chat,[\"echo\",\"room\"]");
+
+        send_text(&mut client, "1");
+        assert_eq!(client.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_other_transports_and_malformed_packets() {
+        let handler = SocketIo::new(Echo {
+            acks: Mutex::new(mpsc::channel().0),
+        });
+        let server = testing::spawn_server(Server::bind("127.0.0.1:0", handler).unwrap()).unwrap();
+
+        let mut client = server
+            .connect("/socket.io/?EIO=3&transport=websocket")
+            .unwrap();
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close().map(|(code, _)| *code), Some(1002));
+
+        let mut client =
+            Client::connect(&server.url("/socket.io/?EIO=4&transport=websocket")).unwrap();
+        recv_text(&mut client);
+        send_text(&mut client, "40");
+        recv_text(&mut client);
+        send_text(&mut client, "42[\"unterminated\"");
+        assert_eq!(client.recv().unwrap(), None);
+        assert_eq!(client.peer_close().map(|(code, _)| *code), Some(1002));
+    }
+}
@@ -0,0 +1,81 @@
+// roomに配送されるメッセージをServer-Sent Events (text/event-stream) で流す
+//
+// GET /events/<room> に対して、以下のようなイベントを送り続ける:
+// data: hello              (Textメッセージ。改行を含む場合は行ごとに data: を付ける)
+//
+// event: binary            (Binaryメッセージはbase64にする)
+// data: AAEC
+//
+// 何も配送されない間も、切断を検出するために15秒ごとにコメント (": keepalive") を送る
+
+use base64::{engine::general_purpose, Engine as _};
+use std::{
+    net::Shutdown,
+    sync::{mpsc::RecvTimeoutError, Arc},
+    time::Duration,
+};
+
+use crate::{connection::Connection, handshake::Request, http, hub::Hub, message::Message};
+
+const PREFIX: &str = "/events/";
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// SSEが処理すべきリクエストか
+pub(crate) fn handles(request: &Request) -> bool {
+    request.method == "GET" && request.path.starts_with(PREFIX)
+}
+
+pub(crate) fn serve(mut conn: Connection, request: &Request, hub: &Arc<Hub>) {
+    let (path, _) = http::split_query(&request.path);
+    let room = &path[PREFIX.len()..];
+    if room.is_empty() {
+        let response = http::response("404 Not Found", "text/plain", "");
+        let _ = conn.stream().write_all(response.as_bytes());
+        return;
+    }
+
+    if hub.authorize_join(&conn, room).is_err() {
+        let response = http::response("403 Forbidden", "text/plain", "");
+        let _ = conn.stream().write_all(response.as_bytes());
+        return;
+    }
+
+    let receiver = hub.watch(room);
+    let head = "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\
+        \r\n";
+    if conn.stream().write_all(head.as_bytes()).is_err() {
+        return;
+    }
+
+    loop {
+        let event = match receiver.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(message) => event(&message),
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if conn.stream().write_all(event.as_bytes()).is_err() {
+            break;
+        }
+    }
+    let _ = conn.stream().shutdown(Shutdown::Both);
+}
+
+fn event(message: &Message) -> String {
+    match message {
+        Message::Text(text) => {
+            let mut event = text
+                .split('\n')
+                .map(|line| format!("data: {}\n", line.trim_end_matches('\r')))
+                .collect::<String>();
+            event.push('\n');
+            event
+        }
+        Message::Binary(bytes) => format!(
+            "event: binary\ndata: {}\n\n",
+            general_purpose::STANDARD.encode(bytes)
+        ),
+    }
+}
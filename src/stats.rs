@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// 送受信したメッセージ数・バイト数
+#[derive(Default)]
+pub(crate) struct Stats {
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// ある時点での累計値
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Snapshot {
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Stats {
+    pub fn record_in(&self, bytes: usize, message: bool) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        if message {
+            self.messages_in.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_out(&self, bytes: usize, message: bool) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        if message {
+            self.messages_out.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// ラベル (routeのpath・room名) ごとの統計
+#[derive(Default)]
+pub(crate) struct Breakdown {
+    stats: Mutex<HashMap<String, Arc<Stats>>>,
+}
+
+/// ラベルごとの統計のある時点での値
+#[derive(Clone, Debug)]
+pub struct LabeledStats {
+    /// routeのpath (クエリを除く) かroom名
+    pub label: String,
+    /// routeなら開いている接続の数、roomなら参加者の数
+    pub connections: usize,
+    /// routeなら接続が送受信した累計、roomならpublishされた (in) ・参加者に配送した (out) 累計
+    pub totals: Snapshot,
+}
+
+impl Breakdown {
+    pub fn get(&self, label: &str) -> Arc<Stats> {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(label.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// ラベルの順に並べた累計
+    pub fn snapshots(&self) -> Vec<(String, Snapshot)> {
+        let mut snapshots = self
+            .stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(label, stats)| (label.clone(), stats.snapshot()))
+            .collect::<Vec<_>>();
+        snapshots.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshots
+    }
+}
+
+/// 接続ごとの統計
+#[derive(Default)]
+pub(crate) struct ConnectionCounters {
+    pub stats: Stats,
+    /// 書き込み中・書き込み待ちのフレーム数
+    pending_writes: AtomicUsize,
+    /// 最後にフレームを送受信した時刻 (UNIX時間のマイクロ秒)
+    last_activity: AtomicU64,
+    /// 最後にフレームを受信した時刻
+    last_received: Mutex<Option<Instant>>,
+    /// 応答待ちのPingのpayloadと送信時刻
+    outstanding_ping: Mutex<Option<(Vec<u8>, Instant)>>,
+    ping_rtt: Mutex<Option<Duration>>,
+}
+
+/// 接続ごとの統計のある時点での値
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// 書き込み中・書き込み待ちのフレーム数
+    pub queue_depth: usize,
+    pub last_activity: SystemTime,
+    /// 直近のPingに対するPongまでの時間
+    pub ping_rtt: Option<Duration>,
+}
+
+impl ConnectionCounters {
+    pub fn touch(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_activity
+            .store(now.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) {
+        *self.last_received.lock().unwrap() = Some(Instant::now());
+        self.touch();
+    }
+
+    pub fn since_received(&self) -> Duration {
+        self.last_received
+            .lock()
+            .unwrap()
+            .map_or(Duration::ZERO, |at| at.elapsed())
+    }
+
+    /// 最後の受信より後に送ったPingの応答を待っている
+    pub fn ping_pending(&self) -> bool {
+        let last_received = *self.last_received.lock().unwrap();
+        match (&*self.outstanding_ping.lock().unwrap(), last_received) {
+            (Some((_, sent_at)), Some(received_at)) => *sent_at >= received_at,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    pub fn begin_write(&self) {
+        self.pending_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn end_write(&self) {
+        self.pending_writes.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn ping_sent(&self, payload: Vec<u8>) {
+        *self.outstanding_ping.lock().unwrap() = Some((payload, Instant::now()));
+    }
+
+    /// 応答待ちのPingと一致すればRTTを記録して返す
+    pub fn pong_received(&self, payload: &[u8]) -> Option<Duration> {
+        let mut outstanding = self.outstanding_ping.lock().unwrap();
+        match outstanding.as_ref() {
+            Some((expected, sent_at)) if expected == payload => {
+                let rtt = sent_at.elapsed();
+                *outstanding = None;
+                *self.ping_rtt.lock().unwrap() = Some(rtt);
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn snapshot(&self) -> ConnectionStats {
+        let stats = self.stats.snapshot();
+        ConnectionStats {
+            messages_in: stats.messages_in,
+            messages_out: stats.messages_out,
+            bytes_in: stats.bytes_in,
+            bytes_out: stats.bytes_out,
+            queue_depth: self.pending_writes.load(Ordering::Relaxed),
+            last_activity: UNIX_EPOCH
+                + Duration::from_micros(self.last_activity.load(Ordering::Relaxed)),
+            ping_rtt: *self.ping_rtt.lock().unwrap(),
+        }
+    }
+}
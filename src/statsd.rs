@@ -0,0 +1,197 @@
+// statsd (DogStatsD) へのメトリクスの送信
+//
+// `interval` ごとに以下をUDPで送る:
+// websocket.connections:3|g|#env:prod
+// websocket.draining:0|g|#env:prod
+// websocket.messages_in:42|c|#env:prod       (前回からの増分)
+// websocket.messages_out:40|c|#env:prod
+// websocket.bytes_in:1234|c|#env:prod
+// websocket.bytes_out:1200|c|#env:prod
+// websocket.route.connections:2|g|#env:prod,route:/chat   (routeごと。room.* はroomごとで、タグは room:<名前>)
+// websocket.route.messages_in:30|c|#env:prod,route:/chat
+//
+// 1つのdatagramに収まる分ずつまとめて送る。
+// タグを指定しなければ `|#...` は付けない (素のstatsdでも受け取れる)
+
+use std::{
+    collections::HashMap,
+    io,
+    net::UdpSocket,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{admin::Admin, log::warning, stats::Snapshot};
+
+const DEFAULT_PREFIX: &str = "websocket";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// 1つのdatagramの大きさの上限 (IPv4のMTU 1500 からヘッダーを除き、余裕を持たせた値)
+const MAX_PACKET_SIZE: usize = 1432;
+
+pub struct StatsdExporter {
+    addr: String,
+    prefix: String,
+    tags: Vec<(String, String)>,
+    interval: Duration,
+}
+
+impl StatsdExporter {
+    /// `addr` は statsd・Datadog Agentのアドレス (例: "127.0.0.1:8125")
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            tags: vec![],
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// メトリクス名の先頭 (デフォルトは "websocket")
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// 全てのメトリクスに付けるDogStatsDのタグ
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// 送信する間隔 (デフォルトは10秒)
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// `admin` のサーバーの統計を送り続けるスレッドを起動する
+    pub fn start(self, admin: Admin) -> io::Result<JoinHandle<()>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(&self.addr)?;
+
+        Ok(thread::spawn(move || {
+            let mut previous = Previous::new(&admin);
+            loop {
+                thread::sleep(self.interval);
+                let lines = self.lines(&admin, &mut previous);
+                for packet in packets(&lines) {
+                    if let Err(e) = socket.send(packet.as_bytes()) {
+                        warning!(
+                            "statsd_error",
+                            { addr: self.addr.as_str(), error: e.to_string() },
+                            "statsd: failed to send to {}: {}",
+                            self.addr,
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+        }))
+    }
+
+    /// 前回からの増分を計算し、`previous` を今回の値にする
+    fn lines(&self, admin: &Admin, previous: &mut Previous) -> Vec<String> {
+        let tags = self.tags(None);
+        let current = admin.stats();
+        let mut lines = vec![
+            self.line("connections", admin.connections().len() as u64, "g", &tags),
+            self.line("draining", admin.is_draining() as u64, "g", &tags),
+        ];
+        lines.extend(self.counters("", &previous.total, &current, &tags));
+        previous.total = current;
+
+        for (kind, stats, previous) in [
+            ("route", admin.route_stats(), &mut previous.routes),
+            ("room", admin.room_stats(), &mut previous.rooms),
+        ] {
+            for stats in stats {
+                let tags = self.tags(Some((kind, &stats.label)));
+                let before = previous.get(&stats.label).copied().unwrap_or_default();
+                let gauge = format!("{}.connections", kind);
+                lines.push(self.line(&gauge, stats.connections as u64, "g", &tags));
+                lines.extend(self.counters(&format!("{}.", kind), &before, &stats.totals, &tags));
+                previous.insert(stats.label, stats.totals);
+            }
+        }
+        lines
+    }
+
+    fn counters(
+        &self,
+        kind: &str,
+        previous: &Snapshot,
+        current: &Snapshot,
+        tags: &str,
+    ) -> Vec<String> {
+        [
+            ("messages_in", current.messages_in - previous.messages_in),
+            ("messages_out", current.messages_out - previous.messages_out),
+            ("bytes_in", current.bytes_in - previous.bytes_in),
+            ("bytes_out", current.bytes_out - previous.bytes_out),
+        ]
+        .iter()
+        .map(|(name, value)| self.line(&format!("{}{}", kind, name), *value, "c", tags))
+        .collect()
+    }
+
+    fn line(&self, name: &str, value: u64, kind: &str, tags: &str) -> String {
+        format!("{}.{}:{}|{}{}", self.prefix, name, value, kind, tags)
+    }
+
+    /// `|#env:prod,route:/chat`。タグがなければ空
+    fn tags(&self, label: Option<(&str, &str)>) -> String {
+        let tags = self
+            .tags
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(label)
+            .map(|(key, value)| format!("{}:{}", key, value.replace([',', '|', '#'], "_")))
+            .collect::<Vec<_>>();
+        if tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", tags.join(","))
+        }
+    }
+}
+
+/// 前回送ったときの累計
+struct Previous {
+    total: Snapshot,
+    routes: HashMap<String, Snapshot>,
+    rooms: HashMap<String, Snapshot>,
+}
+
+impl Previous {
+    fn new(admin: &Admin) -> Self {
+        Self {
+            total: admin.stats(),
+            routes: admin
+                .route_stats()
+                .into_iter()
+                .map(|stats| (stats.label, stats.totals))
+                .collect(),
+            rooms: admin
+                .room_stats()
+                .into_iter()
+                .map(|stats| (stats.label, stats.totals))
+                .collect(),
+        }
+    }
+}
+
+/// 行をdatagramの大きさを超えないようにまとめる
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = vec![];
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_SIZE => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
@@ -0,0 +1,654 @@
+// STOMP 1.2 over WebSocket
+//
+// サブプロトコル `v12.stomp` (`v11.stomp`, `v10.stomp`) で接続してきたクライアントの
+// destinationをHubのroomとして扱う。SENDはroomへのpublishに、SUBSCRIBEはroomの購読になる。
+// roomに普通のWebSocketの接続からpublishされたメッセージもMESSAGEとして届く
+//
+// フレームの形式 (1メッセージに1つ以上のフレーム):
+// COMMAND
+// header1:value1
+// header2:value2
+//
+// body^@   (^@ はNUL。content-length があればその長さをbodyとする)
+//
+// ACK/NACK は受け付けるが、NACKされたメッセージの再配送はしない
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    connection::{Connection, ConnectionId},
+    error::{Error, Result},
+    handler::Handler,
+    log::warning,
+    message::Message,
+};
+
+const PROTOCOLS: [&str; 3] = ["v12.stomp", "v11.stomp", "v10.stomp"];
+
+/// STOMPのフレーム
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub command: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// 最初に現れたヘッダーの値 (STOMP 1.2 では同じヘッダーが複数あれば最初のものを使う)
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.command.as_bytes().to_vec();
+        bytes.push(b'\n');
+        for (key, value) in &self.headers {
+            bytes.extend_from_slice(escape(key).as_bytes());
+            bytes.push(b':');
+            bytes.extend_from_slice(escape(value).as_bytes());
+            bytes.push(b'\n');
+        }
+        if !self.body.is_empty() && self.get("content-length").is_none() {
+            bytes.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        bytes.push(b'\n');
+        bytes.extend_from_slice(&self.body);
+        bytes.push(0);
+        bytes
+    }
+
+    /// メッセージに含まれるフレームを全てパースする。ハートビートの改行は読み飛ばす
+    pub fn parse_all(bytes: &[u8]) -> Result<Vec<Self>> {
+        let mut frames = vec![];
+        let mut rest = bytes;
+        loop {
+            while let [b'\r' | b'\n', tail @ ..] = rest {
+                rest = tail;
+            }
+            if rest.is_empty() {
+                return Ok(frames);
+            }
+            let (frame, tail) = Self::parse(rest)?;
+            frames.push(frame);
+            rest = tail;
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        let malformed = || Error::Protocol("malformed stomp frame".to_string());
+        let end = header_end(bytes).ok_or_else(malformed)?;
+        let head = std::str::from_utf8(&bytes[..end.0]).map_err(|_| Error::InvalidUtf8)?;
+        let mut lines = head.lines();
+        let command = lines.next().ok_or_else(malformed)?.to_string();
+        // CONNECTとCONNECTEDのヘッダーはエスケープしない (STOMP 1.2)
+        let escaped = command != "CONNECT" && command != "CONNECTED";
+        let mut headers = vec![];
+        for line in lines {
+            let (key, value) = line.split_once(':').ok_or_else(malformed)?;
+            if escaped {
+                headers.push((unescape(key)?, unescape(value)?));
+            } else {
+                headers.push((key.to_string(), value.to_string()));
+            }
+        }
+
+        let rest = &bytes[end.1..];
+        let len = headers
+            .iter()
+            .find(|(key, _)| key == "content-length")
+            .map(|(_, len)| len.parse::<usize>().map_err(|_| malformed()))
+            .transpose()?;
+        let len = match len {
+            Some(len) if rest.len() > len && rest[len] == 0 => len,
+            Some(_) => return Err(malformed()),
+            None => rest.iter().position(|b| *b == 0).ok_or_else(malformed)?,
+        };
+        let frame = Self {
+            command,
+            headers,
+            body: rest[..len].to_vec(),
+        };
+        Ok((frame, &rest[len + 1..]))
+    }
+}
+
+/// ヘッダーの後の空行の位置: (ヘッダーの終わり, bodyの始まり)。
+/// 行の終わりは `\n` でも `\r\n` でもよい。フレームごとに先頭から一度だけ調べる
+fn header_end(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut start = 0;
+    loop {
+        let i = start + bytes[start..].iter().position(|b| *b == b'\n')?;
+        let end = if i > 0 && bytes[i - 1] == b'\r' {
+            i - 1
+        } else {
+            i
+        };
+        match &bytes[i + 1..] {
+            [b'\n', ..] => return Some((end, i + 2)),
+            [b'\r', b'\n', ..] => return Some((end, i + 3)),
+            _ => start = i + 1,
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+        .replace(':', "\\c")
+}
+
+fn unescape(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some('c') => out.push(':'),
+            Some('\\') => out.push('\\'),
+            _ => {
+                return Err(Error::Protocol(format!(
+                    "invalid escape in stomp header: {}",
+                    s
+                )))
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// サブプロトコル `v12.stomp` などの接続をSTOMPとして処理するHandler。
+/// それ以外の接続は `fallback` に渡す
+pub struct Stomp<H = ()> {
+    fallback: H,
+    sessions: Mutex<HashMap<ConnectionId, Session>>,
+    next_message_id: Arc<AtomicU64>,
+}
+
+struct Session {
+    connected: bool,
+    /// 購読のID -> 購読
+    subscriptions: HashMap<String, Subscription>,
+}
+
+struct Subscription {
+    /// true にすると配送するスレッドが終了する
+    stop: Arc<AtomicBool>,
+    /// ackが client / client-individual なら、未確認のmessage-id
+    pending: Arc<Mutex<Vec<String>>>,
+    ack: Ack,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Ack {
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+impl Default for Stomp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stomp {
+    pub fn new() -> Self {
+        Self::with_fallback(())
+    }
+}
+
+impl<H: Handler> Stomp<H> {
+    pub fn with_fallback(fallback: H) -> Self {
+        Self {
+            fallback,
+            sessions: Mutex::new(HashMap::new()),
+            next_message_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    fn is_stomp(&self, id: ConnectionId) -> bool {
+        self.sessions.lock().unwrap().contains_key(&id)
+    }
+
+    fn receive(&self, conn: &mut Connection, frame: Frame) -> Result<()> {
+        let connected = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&conn.id())
+            .is_some_and(|session| session.connected);
+
+        match frame.command.as_str() {
+            "CONNECT" | "STOMP" => {
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&conn.id()) {
+                    session.connected = true;
+                }
+                send(
+                    conn,
+                    Frame::new("CONNECTED")
+                        .header("version", "1.2")
+                        .header("heart-beat", "0,0")
+                        .header("server", "websocket-rs"),
+                )?;
+                return Ok(());
+            }
+            _ if !connected => {
+                return Err(Error::Protocol("CONNECT is required first".to_string()));
+            }
+            "SEND" => {
+                let destination = required(&frame, "destination")?;
+                let message = match String::from_utf8(frame.body.clone()) {
+                    Ok(text) => Message::Text(text),
+                    Err(_) => Message::Binary(frame.body.clone()),
+                };
+                conn.publish(destination, message)?;
+            }
+            "SUBSCRIBE" => {
+                let destination = required(&frame, "destination")?;
+                let id = required(&frame, "id")?;
+                let ack = match frame.get("ack").unwrap_or("auto") {
+                    "auto" => Ack::Auto,
+                    "client" => Ack::Client,
+                    "client-individual" => Ack::ClientIndividual,
+                    ack => return Err(Error::Protocol(format!("unknown ack mode: {}", ack))),
+                };
+                conn.hub().authorize_join(conn, destination)?;
+                let subscription = self.subscribe(conn, destination, id, ack);
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&conn.id()) {
+                    if let Some(old) = session.subscriptions.insert(id.to_string(), subscription) {
+                        old.stop.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+            "UNSUBSCRIBE" => {
+                let id = required(&frame, "id")?;
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&conn.id()) {
+                    if let Some(subscription) = session.subscriptions.remove(id) {
+                        subscription.stop.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+            "ACK" | "NACK" => {
+                let id = required(&frame, "id")?;
+                if let Some(session) = self.sessions.lock().unwrap().get(&conn.id()) {
+                    for subscription in session.subscriptions.values() {
+                        subscription.acknowledge(id);
+                    }
+                }
+            }
+            // トランザクションは受け付けるだけで、SENDやACKはすぐに処理する
+            "BEGIN" | "COMMIT" | "ABORT" => {
+                required(&frame, "transaction")?;
+            }
+            "DISCONNECT" => {
+                if let Some(receipt) = frame.get("receipt") {
+                    send(conn, Frame::new("RECEIPT").header("receipt-id", receipt))?;
+                }
+                conn.close(1000, "")?;
+                return Ok(());
+            }
+            command => {
+                return Err(Error::Protocol(format!(
+                    "unknown stomp command: {}",
+                    command
+                )))
+            }
+        }
+
+        if let Some(receipt) = frame.get("receipt") {
+            send(conn, Frame::new("RECEIPT").header("receipt-id", receipt))?;
+        }
+        Ok(())
+    }
+
+    /// roomに配送されるメッセージをMESSAGEとして送るスレッドを起動する
+    fn subscribe(&self, conn: &Connection, destination: &str, id: &str, ack: Ack) -> Subscription {
+        let subscription = Subscription {
+            stop: Arc::new(AtomicBool::new(false)),
+            pending: Arc::new(Mutex::new(vec![])),
+            ack,
+        };
+
+        let receiver = conn.hub().watch(destination);
+        let handle = conn.handle();
+        let stop = subscription.stop.clone();
+        let pending = subscription.pending.clone();
+        let next_message_id = self.next_message_id.clone();
+        let (destination, id) = (destination.to_string(), id.to_string());
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                let message = match receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                let message_id = next_message_id.fetch_add(1, Ordering::Relaxed).to_string();
+                let content_type = match message {
+                    Message::Text(_) => "text/plain;charset=utf-8",
+                    Message::Binary(_) => "application/octet-stream",
+                };
+                let mut frame = Frame::new("MESSAGE")
+                    .header("subscription", &id)
+                    .header("message-id", &message_id)
+                    .header("destination", &destination)
+                    .header("content-type", content_type);
+                if ack != Ack::Auto {
+                    frame = frame.header("ack", &message_id);
+                    pending.lock().unwrap().push(message_id);
+                }
+                let frame = frame.body(message.as_bytes().to_vec());
+                if handle.send(encode(&frame)).is_err() {
+                    break;
+                }
+            }
+        });
+        subscription
+    }
+}
+
+impl Subscription {
+    fn acknowledge(&self, id: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(i) = pending.iter().position(|pending| pending == id) else {
+            return;
+        };
+        match self.ack {
+            // clientモードではそれまでのメッセージもまとめて確認される
+            Ack::Client => drop(pending.drain(..=i)),
+            _ => drop(pending.remove(i)),
+        }
+    }
+}
+
+fn required<'a>(frame: &'a Frame, key: &str) -> Result<&'a str> {
+    frame
+        .get(key)
+        .ok_or_else(|| Error::Protocol(format!("{} requires a {} header", frame.command, key)))
+}
+
+/// STOMPのフレームはTextメッセージで送る。bodyがUTF-8でなければBinaryメッセージにする
+fn encode(frame: &Frame) -> Message {
+    match String::from_utf8(frame.to_bytes()) {
+        Ok(text) => Message::Text(text),
+        Err(e) => Message::Binary(e.into_bytes()),
+    }
+}
+
+fn send(conn: &mut Connection, frame: Frame) -> Result<()> {
+    conn.send(encode(&frame))
+}
+
+impl<H: Handler> Handler for Stomp<H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        PROTOCOLS
+            .iter()
+            .find(|protocol| offered.contains(protocol))
+            .map(|protocol| protocol.to_string())
+            .or_else(|| self.fallback.select_protocol(path, offered))
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        if !conn.protocol().is_some_and(|p| PROTOCOLS.contains(&p)) {
+            return self.fallback.on_open(conn);
+        }
+        self.sessions.lock().unwrap().insert(
+            conn.id(),
+            Session {
+                connected: false,
+                subscriptions: HashMap::new(),
+            },
+        );
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        if !self.is_stomp(conn.id()) {
+            return self.fallback.on_message(conn, message);
+        }
+        let result = Frame::parse_all(message.as_bytes()).and_then(|frames| {
+            frames
+                .into_iter()
+                .try_for_each(|frame| self.receive(conn, frame))
+        });
+        if let Err(e) = result {
+            warning!(
+                "stomp_error",
+                { connection_id: conn.id(), error: e.to_string() },
+                "stomp: {}",
+                e
+            );
+            let _ = send(conn, Frame::new("ERROR").header("message", &e.to_string()));
+            let _ = conn.close(1002, "");
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        if !self.is_stomp(conn.id()) {
+            self.fallback.on_peer_close(conn, code, reason);
+        }
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        if !self.is_stomp(conn.id()) {
+            self.fallback.on_pong(conn, payload, latency);
+        }
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        let Some(session) = self.sessions.lock().unwrap().remove(&id) else {
+            return self.fallback.on_close(id);
+        };
+        for subscription in session.subscriptions.values() {
+            subscription.stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        if !self.is_stomp(id) {
+            self.fallback.on_error(id, error, terminated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Client,
+        server::Server,
+        testing::{self, TestServer},
+    };
+
+    fn parse(bytes: &[u8]) -> Result<Vec<Frame>> {
+        Frame::parse_all(bytes)
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let frame = Frame::new("SEND")
+            .header("destination", "/queue/a")
+            .header("note", "a:b\nc\\d\re")
+            .body(b"hello\0world".to_vec());
+        let bytes = frame.to_bytes();
+        assert!(bytes.starts_with(b"SEND\ndestination:/queue/a\nnote:a\\cb\\nc\\\\d\\re\n"));
+        // NULを含むbodyにはcontent-lengthが付く
+        let parsed = parse(&bytes).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].get("note"), Some("a:b\nc\\d\re"));
+        assert_eq!(parsed[0].get("content-length"), Some("11"));
+        assert_eq!(parsed[0].body, b"hello\0world");
+    }
+
+    #[test]
+    fn parses_several_frames_and_heartbeats() {
+        let bytes = b"\n\nSEND\ndestination:a\n\nfirst\0\r\n\
+                      SEND\r\ndestination:b\r\ndestination:c\r\n\r\nsecond\0\n";
+        let frames = parse(bytes).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("destination"), Some("a"));
+        assert_eq!(frames[0].body, b"first");
+        // 同じヘッダーが複数あれば最初のもの
+        assert_eq!(frames[1].get("destination"), Some("b"));
+        assert_eq!(frames[1].body, b"second");
+        assert!(parse(b"\n\n\r\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn does_not_unescape_connect_headers() {
+        let frames = parse(b"CONNECT\nlogin:a\\cb\nhost:x\n\n\0").unwrap();
+        assert_eq!(frames[0].get("login"), Some("a\\cb"));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        for bytes in [
+            &b"SEND\ndestination:a\nbody\0"[..],
+            b"SEND\ndestination:a\n\nbody",
+            b"SEND\nno colon\n\n\0",
+            b"SEND\ncontent-length:10\n\nshort\0",
+            b"SEND\ncontent-length:2\n\nlong\0",
+            b"SEND\ncontent-length:x\n\n\0",
+            b"SEND\nkey:a\\tb\n\n\0",
+            b"SEND\nkey:a\\\n\n\0",
+            b"SEND\nkey:\xff\n\n\0",
+        ] {
+            assert!(
+                parse(bytes).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_end_of_headers_in_one_pass() {
+        let mut bytes = vec![];
+        for _ in 0..20_000 {
+            bytes.extend_from_slice(b"SEND\ndestination:a\n\nx\0");
+        }
+        assert_eq!(parse(&bytes).unwrap().len(), 20_000);
+    }
+
+    fn spawn() -> TestServer {
+        testing::spawn_server(Server::bind("127.0.0.1:0", Stomp::new()).unwrap()).unwrap()
+    }
+
+    fn connect(server: &TestServer) -> Client {
+        let client = Client::connect_with_protocols(&server.url("/"), &["v12.stomp"]).unwrap();
+        assert_eq!(client.protocol(), Some("v12.stomp"));
+        client
+    }
+
+    fn send(client: &mut Client, frame: Frame) {
+        client.send(encode(&frame)).unwrap();
+    }
+
+    fn recv(client: &mut Client) -> Frame {
+        let message = client.recv().unwrap().expect("closed");
+        let mut frames = parse(message.as_bytes()).unwrap();
+        assert_eq!(frames.len(), 1);
+        frames.remove(0)
+    }
+
+    #[test]
+    fn subscribes_and_receives_messages() {
+        let server = spawn();
+        let mut client = connect(&server);
+        send(
+            &mut client,
+            Frame::new("CONNECT").header("accept-version", "1.2"),
+        );
+        assert_eq!(recv(&mut client).command, "CONNECTED");
+
+        send(
+            &mut client,
+            Frame::new("SUBSCRIBE")
+                .header("destination", "news")
+                .header("id", "0")
+                .header("receipt", "r1"),
+        );
+        let receipt = recv(&mut client);
+        assert_eq!(receipt.command, "RECEIPT");
+        assert_eq!(receipt.get("receipt-id"), Some("r1"));
+
+        send(
+            &mut client,
+            Frame::new("SEND")
+                .header("destination", "news")
+                .body(b"hello".to_vec()),
+        );
+        let message = recv(&mut client);
+        assert_eq!(message.command, "MESSAGE");
+        assert_eq!(message.get("subscription"), Some("0"));
+        assert_eq!(message.get("destination"), Some("news"));
+        assert_eq!(message.body, b"hello");
+
+        // roomへの普通のpublishも届く
+        server
+            .hub()
+            .publish("news", Message::Text("from hub".to_string()));
+        assert_eq!(recv(&mut client).body, b"from hub");
+
+        send(
+            &mut client,
+            Frame::new("DISCONNECT").header("receipt", "bye"),
+        );
+        assert_eq!(recv(&mut client).get("receipt-id"), Some("bye"));
+        assert_eq!(client.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn sends_error_and_closes_on_protocol_errors() {
+        let server = spawn();
+        for frame in [
+            // CONNECTの前のSEND
+            Frame::new("SEND").header("destination", "news"),
+            Frame::new("BOGUS"),
+        ] {
+            let mut client = connect(&server);
+            if frame.command == "BOGUS" {
+                send(&mut client, Frame::new("CONNECT"));
+                recv(&mut client);
+            }
+            send(&mut client, frame);
+            assert_eq!(recv(&mut client).command, "ERROR");
+            assert_eq!(client.recv().unwrap(), None);
+            assert_eq!(client.peer_close().unwrap().0, 1002);
+        }
+    }
+}
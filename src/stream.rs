@@ -0,0 +1,87 @@
+// 接続に使うバイト列のstream
+//
+// サーバーは `std::net::TcpStream` 以外の接続も扱える。他のHTTPサーバーがupgradeした接続や、
+// Unixドメインソケット、テスト用のパイプ (`testing::Pipe`) などを `Upgrader::upgrade` に渡せる:
+//
+//   upgrader.upgrade(stream, request)?; // stream: impl Stream
+//
+// 接続ごとに、フレームを読み込むスレッドと書き込むスレッド (`ConnectionHandle::send` など) が
+// 同じ接続を同時に使うため、`try_clone` で同じ接続を指す別のstreamを作れる必要がある。
+// また、書き込みで止まっているスレッドがあっても `shutdown` で切断できること
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsInfo;
+
+/// サーバーが接続に使うstream。`Client` も `ws://`・`wss://` の接続に使う
+pub trait Stream: Read + Write + Send + Sync + 'static {
+    /// 同じ接続を指す別のstream。一方で読み込みながら、もう一方で書き込めること
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+
+    /// 接続を閉じる。他のスレッドの読み込み・書き込みも戻ること
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// 接続元のアドレス。IPアドレスによる制限やログに使う
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// handshakeを読み込む間の時間制限に使う。None なら待ち続ける
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// TLSの接続なら、SNIや相手の証明書などの情報
+    #[cfg(feature = "tls")]
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+
+    /// ソケットから読み込み済みで、まだ `read` で返していないデータがあるか (TLSで復号を待つデータなど)。
+    /// `Client::is_readable` がソケットと合わせて確かめる
+    #[cfg(feature = "tls")]
+    fn has_buffered(&self) -> bool {
+        false
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Unixドメインソケットには接続元のIPアドレスがないので、`peer_addr` は `127.0.0.1:0` を返す
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
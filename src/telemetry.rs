@@ -0,0 +1,382 @@
+// OpenTelemetry形式のspanの記録とOTLPでのexport
+//
+// 1接続につき1つの `websocket.connection` spanを作り、
+// その子として `websocket.handshake` と メッセージごとの `websocket.message` を記録する。
+// Closeはconnection spanのeventとして記録する
+//
+// OTLP/HTTP (JSON) で送信する:
+// https://opentelemetry.io/docs/specs/otlp/#otlphttp
+//
+// 送信待ちのspanは最大 `QUEUE_CAPACITY` 個までで、collectorが遅くて溢れたspanは捨てる
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    json,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Int(i64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub name: String,
+    pub time: SystemTime,
+    pub attributes: Vec<(String, Value)>,
+}
+
+/// 終了したspan
+#[derive(Clone, Debug)]
+pub struct SpanData {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, Value)>,
+    pub events: Vec<Event>,
+}
+
+/// 終了したspanの送信先
+pub trait SpanExporter: Send + Sync + 'static {
+    fn export(&self, span: SpanData);
+}
+
+/// 記録中のspan
+pub(crate) struct Span {
+    data: SpanData,
+}
+
+impl Span {
+    pub fn root(name: &str) -> Self {
+        Self::new(name, rand::random(), None)
+    }
+
+    pub fn child(&self, name: &str) -> Self {
+        Self::new(name, self.data.trace_id, Some(self.data.span_id))
+    }
+
+    fn new(name: &str, trace_id: [u8; 16], parent_span_id: Option<[u8; 8]>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            data: SpanData {
+                trace_id,
+                span_id: rand::random(),
+                parent_span_id,
+                name: name.to_string(),
+                start: now,
+                end: now,
+                attributes: vec![],
+                events: vec![],
+            },
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: Value) {
+        self.data.attributes.push((key.to_string(), value));
+    }
+
+    pub fn add_event(&mut self, name: &str, attributes: Vec<(String, Value)>) {
+        self.data.events.push(Event {
+            name: name.to_string(),
+            time: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    pub fn end(mut self, exporter: &dyn SpanExporter) {
+        self.data.end = SystemTime::now();
+        exporter.export(self.data);
+    }
+}
+
+/// OTLP/HTTP (JSON) でcollectorに送信するexporter。
+/// spanはバックグラウンドのスレッドでまとめて送信する
+pub struct OtlpExporter {
+    sender: SyncSender<SpanData>,
+    dropped: AtomicU64,
+}
+
+const BATCH_SIZE: usize = 512;
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// 送信待ちのspanの最大数
+const QUEUE_CAPACITY: usize = 4 * BATCH_SIZE;
+/// collectorへの接続と、1回の読み書きを待つ時間
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl OtlpExporter {
+    /// endpointは `http://127.0.0.1:4318/v1/traces` の形式
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Self> {
+        let invalid = || {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported endpoint: {}", endpoint),
+            ))
+        };
+        let rest = endpoint.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (rest[..i].to_string(), rest[i..].to_string()),
+            None => (rest.to_string(), "/v1/traces".to_string()),
+        };
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let service_name = service_name.to_string();
+        thread::spawn(move || run(receiver, &authority, &path, &service_name));
+
+        Ok(Self {
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// 送信待ちが一杯で捨てたspanの数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    /// 送信待ちが一杯なら待たずに捨てる
+    fn export(&self, span: SpanData) {
+        if self.sender.try_send(span).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn run(receiver: Receiver<SpanData>, authority: &str, path: &str, service_name: &str) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + BATCH_INTERVAL;
+
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let disconnected = match receiver.recv_timeout(timeout) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if batch.len() >= BATCH_SIZE || Instant::now() >= deadline || disconnected {
+            if !batch.is_empty() {
+                let body = to_json(service_name, &batch);
+                // collectorに届かなくてもサーバーの動作には影響させない
+                let _ = post(authority, path, &body);
+                batch.clear();
+            }
+            deadline = Instant::now() + BATCH_INTERVAL;
+        }
+
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// collectorが応答しなくても、exporterのスレッドが止まり続けないようにtimeoutを付ける
+fn post(authority: &str, path: &str, body: &str) -> io::Result<()> {
+    let mut stream = connect(authority)?;
+    stream.set_read_timeout(Some(POST_TIMEOUT))?;
+    stream.set_write_timeout(Some(POST_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}",
+        path,
+        authority,
+        body.len(),
+        body
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(())
+}
+
+/// 名前解決したアドレスに順に接続する
+fn connect(authority: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("no address for {}", authority),
+    );
+    for addr in authority.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, POST_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn nanos(time: SystemTime) -> String {
+    format!(
+        "\"{}\"",
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    )
+}
+
+fn attributes_json(attributes: &[(String, Value)]) -> String {
+    let items = attributes
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(s) => format!("{{\"stringValue\":{}}}", json::string(s)),
+                Value::Int(i) => format!("{{\"intValue\":\"{}\"}}", i),
+            };
+            format!("{{\"key\":{},\"value\":{}}}", json::string(key), value)
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", items.join(","))
+}
+
+fn to_json(service_name: &str, spans: &[SpanData]) -> String {
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let events = span
+                .events
+                .iter()
+                .map(|event| {
+                    format!(
+                        "{{\"timeUnixNano\":{},\"name\":{},\"attributes\":{}}}",
+                        nanos(event.time),
+                        json::string(&event.name),
+                        attributes_json(&event.attributes)
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!(
+                "{{\"traceId\":\"{}\",\"spanId\":\"{}\",\"parentSpanId\":\"{}\",\"name\":{},\"kind\":2,\
+                \"startTimeUnixNano\":{},\"endTimeUnixNano\":{},\"attributes\":{},\"events\":[{}]}}",
+                hex(&span.trace_id),
+                hex(&span.span_id),
+                span.parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+                json::string(&span.name),
+                nanos(span.start),
+                nanos(span.end),
+                attributes_json(&span.attributes),
+                events.join(",")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":{}}},\
+        \"scopeSpans\":[{{\"scope\":{{\"name\":\"websocket-rs\"}},\"spans\":[{}]}}]}}]}}",
+        attributes_json(&[(
+            "service.name".to_string(),
+            Value::String(service_name.to_string())
+        )]),
+        spans.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn span(name: &str) -> SpanData {
+        Span::root(name).data
+    }
+
+    #[test]
+    fn rejects_unsupported_endpoints() {
+        for endpoint in [
+            "https://127.0.0.1:4318/v1/traces",
+            "127.0.0.1:4318",
+            "http:///v1/traces",
+        ] {
+            match OtlpExporter::new(endpoint, "test") {
+                Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+                _ => panic!("expected {} to be rejected", endpoint),
+            }
+        }
+    }
+
+    #[test]
+    fn posts_batches_to_the_collector() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", collector.local_addr().unwrap());
+        let exporter = OtlpExporter::new(&endpoint, "test").unwrap();
+        exporter.export(span("websocket.connection"));
+
+        let (mut stream, _) = collector.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1\r\n");
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(len) = line.strip_prefix("Content-Length: ") {
+                content_length = len.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(
+            body.contains("\"name\":\"websocket.connection\""),
+            "{}",
+            body
+        );
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn drops_spans_while_the_collector_is_slow() {
+        // 接続を受け付けるが応答しないcollector
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", collector.local_addr().unwrap());
+        let exporter = OtlpExporter::new(&endpoint, "test").unwrap();
+        for _ in 0..BATCH_SIZE {
+            exporter.export(span("first"));
+        }
+        // 1回目の送信を待っている間は、送信待ちの上限を超えた分を捨てる
+        let _stream = collector.accept().unwrap();
+        for _ in 0..QUEUE_CAPACITY + 10 {
+            exporter.export(span("second"));
+        }
+        assert_eq!(exporter.dropped(), 10);
+    }
+}
@@ -0,0 +1,407 @@
+// テスト用のメモリ上の双方向のパイプ
+//
+// `pipe()` が返す2つの端は、片方に書き込んだバイト列をもう片方から読み込める。
+// `Read`・`Write` を実装しているので、ソケットを使わずにhandshake (`Request::read_from` など) や
+// フレーム (`Frame::read_from`・`Frame::to_bytes`) の処理を決まった順序で試せる:
+//
+//     let (mut client, mut server) = testing::pipe();
+//     client.write_all(&Frame::from(Message::Text("hi".into())).masked([1, 2, 3, 4]).to_bytes())?;
+//     let frame = Frame::read_from(&mut server)?;
+//
+// 片方を捨てる (または `shutdown` する) と、もう片方の読み込みはEOF (0 bytes) になり、書き込みは BrokenPipe になる
+// (`try_clone` した端があれば、最後の1つを捨てたときに閉じる)
+//
+// `Stream` を実装しているので、サーバーの端を `Upgrader::upgrade` に渡してTCPを使わずに接続を試せる
+//
+// `spawn_test_server` は空いているポートでサーバーを起動し、接続済みのクライアントを返す。
+// 他のクレートからの結合テストに使う:
+//
+//     let (server, mut client) = testing::spawn_test_server(MyHandler)?;
+//     client.send(Message::Text("hi".into()))?;
+//     assert_eq!(client.recv()?, Some(Message::Text("hi".into())));
+
+#[cfg(feature = "server")]
+use std::thread;
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::stream::Stream;
+#[cfg(feature = "server")]
+use crate::{
+    admin::Admin, client::Client, error::Result, handler::Handler, hub::Hub, server::Server,
+};
+
+/// パイプの片方の端
+pub struct Pipe {
+    /// 相手が書き込み、こちらが読み込む
+    incoming: Arc<Buffer>,
+    /// こちらが書き込み、相手が読み込む
+    outgoing: Arc<Buffer>,
+    /// `try_clone` した端と共有する
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+    /// 同じ端を指すPipeの数を数える。最後の1つを捨てたときに閉じる
+    clones: Arc<()>,
+    /// `peer_addr` が返すアドレス
+    peer_addr: SocketAddr,
+}
+
+#[derive(Default)]
+struct Buffer {
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Buffer {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// つながった2つの端を返す
+pub fn pipe() -> (Pipe, Pipe) {
+    let a = Arc::new(Buffer::default());
+    let b = Arc::new(Buffer::default());
+    (
+        Pipe {
+            incoming: a.clone(),
+            outgoing: b.clone(),
+            read_timeout: Arc::default(),
+            clones: Arc::new(()),
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        },
+        Pipe {
+            incoming: b,
+            outgoing: a,
+            read_timeout: Arc::default(),
+            clones: Arc::new(()),
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        },
+    )
+}
+
+impl Pipe {
+    /// `peer_addr` が返すアドレスを変える (デフォルトは `127.0.0.1:0`)。
+    /// `Config::ip_filter` などを通す接続を、パイプで試すのに使う
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.peer_addr = addr;
+        self
+    }
+
+    /// 読み込むデータがないまま `timeout` が過ぎたら `TimedOut` を返す。None なら待ち続ける
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.read_timeout.lock().unwrap() = timeout;
+    }
+
+    /// 読み込まれていないバイト数
+    pub fn available(&self) -> usize {
+        self.incoming.state.lock().unwrap().bytes.len()
+    }
+
+    /// 両方向を閉じる。相手の読み込みは残りを読んだ後にEOFになる
+    pub fn shutdown(&self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self
+            .read_timeout
+            .lock()
+            .unwrap()
+            .map(|timeout| Instant::now() + timeout);
+        let mut state = self.incoming.state.lock().unwrap();
+        while state.bytes.is_empty() {
+            if state.closed {
+                return Ok(0);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "pipe read timed out",
+                        ));
+                    }
+                    self.incoming
+                        .ready
+                        .wait_timeout(state, remaining)
+                        .unwrap()
+                        .0
+                }
+                None => self.incoming.ready.wait(state).unwrap(),
+            };
+        }
+        let len = buf.len().min(state.bytes.len());
+        for (dst, src) in buf.iter_mut().zip(state.bytes.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"));
+        }
+        state.bytes.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.clones) == 1 {
+            self.shutdown();
+        }
+    }
+}
+
+/// パイプにはアドレスがないので、`peer_addr` は `with_peer_addr` で指定したアドレスを返す
+impl Stream for Pipe {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(Pipe {
+            incoming: self.incoming.clone(),
+            outgoing: self.outgoing.clone(),
+            read_timeout: self.read_timeout.clone(),
+            clones: self.clones.clone(),
+            peer_addr: self.peer_addr,
+        }))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            self.incoming.close();
+        }
+        if how != Shutdown::Read {
+            self.outgoing.close();
+        }
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Pipe::set_read_timeout(self, timeout);
+        Ok(())
+    }
+}
+
+/// テスト用に別のスレッドで動かしているサーバー。捨てると新しい接続を断り、既存の接続を 1001 で閉じる
+#[cfg(feature = "server")]
+pub struct TestServer {
+    addr: SocketAddr,
+    hub: Arc<Hub>,
+    admin: Admin,
+}
+
+/// `127.0.0.1` の空いているポートで `handler` のサーバーを起動し、`/` に接続したクライアントを返す
+#[cfg(feature = "server")]
+pub fn spawn_test_server<H: Handler>(handler: H) -> Result<(TestServer, Client)> {
+    let server = spawn_server(Server::bind("127.0.0.1:0", handler)?)?;
+    let client = server.connect("/")?;
+    Ok((server, client))
+}
+
+/// 設定済みの `server` を別のスレッドで動かす (`Server::bind("127.0.0.1:0", handler)` で作る)
+#[cfg(feature = "server")]
+pub fn spawn_server<H: Handler>(server: Server<H>) -> Result<TestServer> {
+    spawn_with(server, Server::run)
+}
+
+/// `spawn_server` と同じだが、`Server::run_event_loop(threads)` で動かす
+#[cfg(all(unix, feature = "server", feature = "reactor"))]
+pub fn spawn_event_loop_server<H: Handler>(
+    server: Server<H>,
+    threads: usize,
+) -> Result<TestServer> {
+    spawn_with(server, move |server| server.run_event_loop(threads))
+}
+
+/// `spawn_server` と同じだが、`Server::run_io_uring(threads)` で動かす
+#[cfg(all(target_os = "linux", feature = "server", feature = "io-uring"))]
+pub fn spawn_io_uring_server<H: Handler>(server: Server<H>, threads: usize) -> Result<TestServer> {
+    spawn_with(server, move |server| server.run_io_uring(threads))
+}
+
+#[cfg(feature = "server")]
+fn spawn_with<H: Handler>(
+    server: Server<H>,
+    run: impl FnOnce(Server<H>) -> io::Result<()> + Send + 'static,
+) -> Result<TestServer> {
+    let test_server = TestServer {
+        addr: server.local_addr()?,
+        hub: server.hub(),
+        admin: server.admin(),
+    };
+    thread::spawn(move || run(server));
+    Ok(test_server)
+}
+
+#[cfg(feature = "server")]
+impl TestServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `ws://127.0.0.1:<port><path>`
+    pub fn url(&self, path: &str) -> String {
+        format!("ws://{}{}", self.addr, path)
+    }
+
+    /// 新しいクライアントで `path` に接続する
+    pub fn connect(&self, path: &str) -> Result<Client> {
+        Client::connect(&self.url(path))
+    }
+
+    pub fn hub(&self) -> Arc<Hub> {
+        self.hub.clone()
+    }
+
+    pub fn admin(&self) -> &Admin {
+        &self.admin
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.admin.drain();
+        for connection in self.admin.connections() {
+            let _ = self.admin.kick(connection.id, 1001, "test server dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn reads_what_the_other_end_wrote() {
+        let (mut a, mut b) = pipe();
+        a.write_all(b"hello").unwrap();
+        assert_eq!(b.available(), 5);
+        let mut buf = [0; 3];
+        assert_eq!(b.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        b.write_all(b"world").unwrap();
+        let mut buf = [0; 5];
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(b.available(), 2);
+    }
+
+    #[test]
+    fn reads_eof_after_the_other_end_is_dropped() {
+        let (mut a, mut b) = pipe();
+        a.write_all(b"bye").unwrap();
+        drop(a);
+        // 残りを読んだ後にEOFになる
+        let mut rest = vec![];
+        b.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"bye");
+        assert_eq!(b.read(&mut [0; 8]).unwrap(), 0);
+
+        // 待っている読み込みもEOFで戻る
+        let (a, mut b) = pipe();
+        let reader = thread::spawn(move || b.read(&mut [0; 8]).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        drop(a);
+        assert_eq!(reader.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn closes_when_the_last_clone_is_dropped() {
+        let (a, mut b) = pipe();
+        let mut clone = Stream::try_clone(&a).unwrap();
+        drop(a);
+        clone.write_all(b"still open").unwrap();
+        let mut buf = [0; 10];
+        b.read_exact(&mut buf).unwrap();
+        drop(clone);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn writes_fail_with_broken_pipe() {
+        let (mut a, b) = pipe();
+        drop(b);
+        assert_eq!(
+            a.write(b"lost").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        // shutdownした方向だけ閉じる
+        let (mut a, mut b) = pipe();
+        Stream::shutdown(&a, Shutdown::Write).unwrap();
+        assert_eq!(
+            a.write(b"lost").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(b.read(&mut [0; 4]).unwrap(), 0);
+        b.write_all(b"ok").unwrap();
+        let mut buf = [0; 2];
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+
+    #[test]
+    fn read_times_out_without_data() {
+        let (mut a, mut b) = pipe();
+        b.set_read_timeout(Some(Duration::from_millis(50)));
+        let started = Instant::now();
+        assert_eq!(
+            b.read(&mut [0; 4]).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // timeoutの前に届けば読み込める。`try_clone` した端とtimeoutを共有する
+        let clone = Stream::try_clone(&b).unwrap();
+        clone
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            a.write_all(b"late").unwrap();
+            a
+        });
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"late");
+        let a = writer.join().unwrap();
+
+        b.set_read_timeout(None);
+        drop(a);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,1472 @@
+// rustlsでTLSを終端する (`wss://`)
+//
+// `TlsConfig` で証明書と秘密鍵を読み込み、`Server::with_tls` に渡す:
+//
+//   let tls = TlsConfig::from_pem_files("cert.pem", "key.pem")?
+//       .require_client_cert(&fs::read("ca.pem")?)?;
+//   Server::bind("0.0.0.0:443", handler)?.with_tls(tls)?.run()?;
+//
+// 複数のドメインを1つのサーバーで扱う場合は、`certificate_for` でSNIのホスト名ごとの証明書を追加する。
+// どれにも当てはまらないかSNIがなければ `from_pem` の証明書を使う。
+// ALPNは `http/1.1` だけを提示する (`alpn_protocols` で変えられる)。
+//
+// ファイルから読み込んだ証明書は `TlsReloader` で読み直せる。読み直した証明書は次のTLSのhandshakeから使い、
+// 接続済みの接続はそのまま続く。`watch` はファイルの更新時刻を定期的に確かめ、変わっていれば読み直す:
+//
+//   let tls = TlsConfig::from_pem_files("/etc/letsencrypt/live/example.org/fullchain.pem", "privkey.pem")?;
+//   tls.reloader().watch(Duration::from_secs(60));
+//
+// TLSのhandshakeは接続ごとのスレッドで、HTTPのhandshakeと同じ時間制限 (`Config::handshake_limits`) の中で行う。
+// クライアント証明書を検証した場合は、そのsubjectとSANを `Handshake::tls` (middleware) と
+// `Connection::tls` で参照できるので、証明書の識別名で接続を認可できる。
+//
+// 接続ごとに読み込むスレッドと書き込むスレッドが同じTLSのセッションを使うので、rustlsの状態をMutexで共有する。
+// ソケットの読み書きはロックの外で行い、読み込みを待っている間も書き込める。
+// `run` だけが対応し、`run_event_loop`・`run_io_uring` はTLSを扱わない
+//
+// `TlsConfig::from_acme` (`acme` feature) はACMEで証明書を取得・更新する。詳細は `acme` を参照。
+// CAの検証 (TLS-ALPN-01) の接続はALPNの `acme-tls/1` で見分け、検証用の証明書で応じてからhandshakeの後に切断する
+//
+// `Client` は `wss://` に接続するときに `ClientTlsConfig` を使う。デフォルトはwebpki-roots (Mozillaが信頼するCA) で検証する。
+// `pin_public_key`・`pin_certificate` でSHA-256のハッシュをピン留めすると、CAの検証に加えて、
+// 提示された証明書チェーンのどれかがピンに一致することを確かめる。`pins_only` にするとCAを信頼せず、
+// サーバーの証明書がピンに一致するかだけを確かめる (自己署名の証明書など)
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        ResolvesClientCert, WebPkiServerVerifier,
+    },
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    ServerConfig, ServerConnection, SignatureScheme,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+#[cfg(feature = "acme")]
+use crate::acme::{self, Acme};
+use crate::{
+    handshake::Limits,
+    log::{info, warning},
+    stream::Stream,
+};
+
+/// TLSで受け付けた接続の情報
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// クライアントがSNIで指定したホスト名
+    pub server_name: Option<String>,
+    /// ALPNで合意したプロトコル。クライアントがALPNを使わなければ None
+    pub alpn_protocol: Option<String>,
+    /// 検証したクライアント証明書。要求していないか、提示されなければ None
+    pub peer_certificate: Option<PeerCertificate>,
+}
+
+/// 相手の証明書の識別情報。サーバーではクライアント証明書 (mTLS)、`Client` ではサーバーの証明書
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCertificate {
+    /// 例: `CN=alice, O=example`
+    pub subject: String,
+    /// SubjectAltNameのDNS名
+    pub dns_names: Vec<String>,
+    /// SubjectAltNameのメールアドレス
+    pub emails: Vec<String>,
+    /// SubjectAltNameのURI (SPIFFE IDなど)
+    pub uris: Vec<String>,
+    /// SubjectAltNameのIPアドレス
+    pub ip_addresses: Vec<IpAddr>,
+    /// 証明書のDER
+    pub der: Vec<u8>,
+}
+
+impl PeerCertificate {
+    fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let mut peer = Self {
+            subject: cert.subject().to_string(),
+            dns_names: vec![],
+            emails: vec![],
+            uris: vec![],
+            ip_addresses: vec![],
+            der: der.to_vec(),
+        };
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) => peer.dns_names.push(name.to_string()),
+                    GeneralName::RFC822Name(email) => peer.emails.push(email.to_string()),
+                    GeneralName::URI(uri) => peer.uris.push(uri.to_string()),
+                    GeneralName::IPAddress(ip) => {
+                        if let Ok(v4) = <[u8; 4]>::try_from(*ip) {
+                            peer.ip_addresses.push(IpAddr::from(v4));
+                        } else if let Ok(v6) = <[u8; 16]>::try_from(*ip) {
+                            peer.ip_addresses.push(IpAddr::from(v6));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Some(peer)
+    }
+}
+
+/// TLSの設定。`Server::with_tls` に渡す
+pub struct TlsConfig {
+    /// 実行中のサーバーと共有し、`TlsReloader` が差し替える
+    certificates: Arc<RwLock<Certificates>>,
+    /// クライアント証明書を検証するCAと、証明書を必須にするか
+    client_auth: Option<(RootCertStore, bool)>,
+    /// ALPNで提示するプロトコル (優先する順)
+    alpn_protocols: Vec<String>,
+    /// `from_acme` の設定。`Server::with_tls` で取得・更新を始める
+    #[cfg(feature = "acme")]
+    acme: Option<Acme>,
+}
+
+impl TlsConfig {
+    /// PEMの証明書チェーン (サーバーの証明書から順に) と秘密鍵から作る。
+    /// SNIで選ぶ証明書がなければこの証明書を使う
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> io::Result<Self> {
+        Ok(Self::with_default(certified_key(cert_chain, key)?))
+    }
+
+    /// ACMEで取得した証明書を使う。`cache_dir` に取得済みの証明書があればそれを使い、
+    /// なければ取得するまで自己署名の証明書で待ち受ける。取得と更新は `Server::with_tls` の後に別のスレッドで行う
+    #[cfg(feature = "acme")]
+    pub fn from_acme(acme: Acme) -> io::Result<Self> {
+        let certified = acme.initial_certificate()?;
+        let mut config = Self::with_default(certified.clone());
+        config
+            .certificates
+            .write()
+            .unwrap()
+            .set_acme(acme.domains(), certified);
+        config.acme = Some(acme);
+        Ok(config)
+    }
+
+    fn with_default(default: Arc<CertifiedKey>) -> Self {
+        Self {
+            certificates: Arc::new(RwLock::new(Certificates {
+                default,
+                names: HashMap::new(),
+                files: vec![],
+                #[cfg(feature = "acme")]
+                challenges: HashMap::new(),
+            })),
+            client_auth: None,
+            alpn_protocols: vec!["http/1.1".to_string()],
+            #[cfg(feature = "acme")]
+            acme: None,
+        }
+    }
+
+    pub fn from_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        cert_chain: P,
+        key: Q,
+    ) -> io::Result<Self> {
+        let config = Self::from_pem(&fs::read(&cert_chain)?, &fs::read(&key)?)?;
+        config.certificates.write().unwrap().files.push((
+            None,
+            cert_chain.as_ref().to_path_buf(),
+            key.as_ref().to_path_buf(),
+        ));
+        Ok(config)
+    }
+
+    /// SNIで `server_name` を指定したクライアントに使う証明書を追加する。
+    /// `*.example.org` は `example.org` の1段下のホスト名 (`a.example.org` など) に当てはまる
+    pub fn certificate_for(
+        self,
+        server_name: &str,
+        cert_chain: &[u8],
+        key: &[u8],
+    ) -> io::Result<Self> {
+        self.certificates.write().unwrap().names.insert(
+            server_name.to_ascii_lowercase(),
+            certified_key(cert_chain, key)?,
+        );
+        Ok(self)
+    }
+
+    pub fn certificate_files_for<P: AsRef<Path>, Q: AsRef<Path>>(
+        self,
+        server_name: &str,
+        cert_chain: P,
+        key: Q,
+    ) -> io::Result<Self> {
+        let config =
+            self.certificate_for(server_name, &fs::read(&cert_chain)?, &fs::read(&key)?)?;
+        config.certificates.write().unwrap().files.push((
+            Some(server_name.to_ascii_lowercase()),
+            cert_chain.as_ref().to_path_buf(),
+            key.as_ref().to_path_buf(),
+        ));
+        Ok(config)
+    }
+
+    /// ファイルから読み込んだ証明書を読み直すためのTlsReloader。
+    /// `Server::with_tls` に渡した後も使える
+    pub fn reloader(&self) -> TlsReloader {
+        TlsReloader {
+            certificates: self.certificates.clone(),
+        }
+    }
+
+    /// クライアント証明書を必須にし、PEMの `ca` のいずれかで検証する (mTLS)。
+    /// 提示しないか検証に失敗したクライアントはTLSのhandshakeで切断する
+    pub fn require_client_cert(mut self, ca: &[u8]) -> io::Result<Self> {
+        self.client_auth = Some((roots(ca)?, true));
+        Ok(self)
+    }
+
+    /// `require_client_cert` と同じだが、証明書を提示しないクライアントも受け付ける。
+    /// 提示しなかった接続は `TlsInfo::peer_certificate` が None になる
+    pub fn request_client_cert(mut self, ca: &[u8]) -> io::Result<Self> {
+        self.client_auth = Some((roots(ca)?, false));
+        Ok(self)
+    }
+
+    /// ALPNで提示するプロトコル (優先する順)。デフォルトは `http/1.1` だけ。
+    /// クライアントがALPNを使い、共通のプロトコルがなければTLSのhandshakeで切断する。
+    /// 空にするとALPNの拡張に応答しない
+    pub fn alpn_protocols(mut self, protocols: &[&str]) -> Self {
+        self.alpn_protocols = protocols
+            .iter()
+            .map(|protocol| protocol.to_string())
+            .collect();
+        self
+    }
+
+    pub(crate) fn build(&self) -> io::Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?;
+        let builder = match &self.client_auth {
+            Some((roots, required)) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(roots.clone()),
+                    provider(),
+                );
+                let verifier = if *required {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(invalid)?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(Arc::new(Resolver(self.certificates.clone())));
+        config.alpn_protocols = self
+            .alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        // CAの検証の接続だけが提示する
+        #[cfg(feature = "acme")]
+        if self.acme.is_some() {
+            config.alpn_protocols.push(acme::ALPN.to_vec());
+        }
+        Ok(Arc::new(config))
+    }
+
+    /// `from_acme` なら、証明書の取得・更新を別のスレッドで始める
+    #[cfg(feature = "acme")]
+    pub(crate) fn start_acme(&self) {
+        if let Some(acme) = &self.acme {
+            acme::spawn(acme.clone(), Arc::downgrade(&self.certificates));
+        }
+    }
+}
+
+/// SNIのホスト名 (小文字) ごとの証明書
+#[derive(Clone, Debug)]
+pub(crate) struct Certificates {
+    default: Arc<CertifiedKey>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+    /// ファイルから読み込んだ証明書の (SNIのホスト名, 証明書チェーン, 秘密鍵)。
+    /// ホスト名が None なら `default`
+    files: Vec<(Option<String>, PathBuf, PathBuf)>,
+    /// TLS-ALPN-01 の検証で、ホスト名ごとに提示する証明書
+    #[cfg(feature = "acme")]
+    challenges: HashMap<String, Arc<CertifiedKey>>,
+}
+
+#[cfg(feature = "acme")]
+impl Certificates {
+    /// ACMEで取得した証明書を、`domains` と既定の証明書にする
+    pub(crate) fn set_acme(&mut self, domains: &[String], certified: Arc<CertifiedKey>) {
+        for domain in domains {
+            self.names
+                .insert(domain.to_ascii_lowercase(), certified.clone());
+        }
+        self.default = certified;
+    }
+
+    /// TLS-ALPN-01 の検証で `domain` に提示する証明書。None なら取り除く
+    pub(crate) fn set_challenge(&mut self, domain: &str, certified: Option<Arc<CertifiedKey>>) {
+        let domain = domain.to_ascii_lowercase();
+        match certified {
+            Some(certified) => self.challenges.insert(domain, certified),
+            None => self.challenges.remove(&domain),
+        };
+    }
+}
+
+impl Certificates {
+    fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return self.default.clone();
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        self.names
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.names.get(&wildcard)))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+#[derive(Debug)]
+struct Resolver(Arc<RwLock<Certificates>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.0.read().unwrap();
+        // 検証中でなければ証明書を返さず、handshakeを失敗させる
+        #[cfg(feature = "acme")]
+        if let Some(mut protocols) = client_hello.alpn() {
+            if protocols.any(|protocol| protocol == acme::ALPN) {
+                let server_name = client_hello.server_name()?.to_ascii_lowercase();
+                return certificates.challenges.get(&server_name).cloned();
+            }
+        }
+        Some(certificates.select(client_hello.server_name()))
+    }
+}
+
+/// `TlsConfig::reloader` で取得する
+#[derive(Clone)]
+pub struct TlsReloader {
+    certificates: Arc<RwLock<Certificates>>,
+}
+
+impl TlsReloader {
+    /// `from_pem_files`・`certificate_files_for` のファイルを全て読み直し、まとめて差し替える。
+    /// 1つでも読めないか秘密鍵が証明書と対にならなければ、どれも差し替えずにエラーを返す
+    pub fn reload(&self) -> io::Result<()> {
+        let mut certificates = self.certificates.read().unwrap().clone();
+        for (server_name, cert_chain, key) in &certificates.files.clone() {
+            let certified = certified_key(&fs::read(cert_chain)?, &fs::read(key)?)?;
+            match server_name {
+                Some(server_name) => {
+                    certificates.names.insert(server_name.clone(), certified);
+                }
+                None => certificates.default = certified,
+            }
+        }
+        *self.certificates.write().unwrap() = certificates;
+        Ok(())
+    }
+
+    /// 別のスレッドで `interval` ごとにファイルの更新時刻を確かめ、変わっていれば `reload` する。
+    /// 証明書と秘密鍵を書き換えている途中などで失敗した場合は、次の確認でもう一度読み直す。
+    /// サーバーとTlsReloaderを全て捨てると止まる
+    pub fn watch(self, interval: Duration) {
+        let certificates = Arc::downgrade(&self.certificates);
+        drop(self);
+        thread::Builder::new()
+            .name("tls-reload".to_string())
+            .spawn(move || watch(certificates, interval))
+            .ok();
+    }
+
+    /// ファイルの更新時刻。読めないファイルは None
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let certificates = self.certificates.read().unwrap();
+        certificates
+            .files
+            .iter()
+            .flat_map(|(_, cert_chain, key)| [cert_chain, key])
+            .map(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+fn watch(certificates: Weak<RwLock<Certificates>>, interval: Duration) {
+    let mut last = None;
+    while let Some(certificates) = certificates.upgrade() {
+        let reloader = TlsReloader { certificates };
+        let modified = reloader.modified();
+        match &last {
+            None => last = Some(modified),
+            Some(last_modified) if *last_modified == modified => {}
+            Some(_) => match reloader.reload() {
+                Ok(()) => {
+                    info!("tls_reloaded", {}, "reloaded tls certificates");
+                    last = Some(modified);
+                }
+                Err(e) => warning!(
+                    "tls_reload_failed",
+                    { error: e.to_string() },
+                    "failed to reload tls certificates: {}",
+                    e
+                ),
+            },
+        }
+        drop(reloader);
+        thread::sleep(interval);
+    }
+}
+
+/// 他のクレートが別のCryptoProviderを有効にしていても、ringを使う
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+pub(crate) fn certificates(pem: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no certificate in PEM",
+        ));
+    }
+    Ok(certs)
+}
+
+/// 秘密鍵が証明書と対になっていなければ失敗する
+pub(crate) fn certified_key(cert_chain: &[u8], key: &[u8]) -> io::Result<Arc<CertifiedKey>> {
+    let key = PrivateKeyDer::from_pem_slice(key).map_err(invalid)?;
+    let certified =
+        CertifiedKey::from_der(certificates(cert_chain)?, key, &provider()).map_err(invalid)?;
+    Ok(Arc::new(certified))
+}
+
+fn roots(pem: &[u8]) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certificates(pem)? {
+        roots.add(cert).map_err(invalid)?;
+    }
+    Ok(roots)
+}
+
+/// 証明書・公開鍵のピンに使うSHA-256。`ring` はrustlsの `crypto::ring` と同じ名前なので `::ring` と書く
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(::ring::digest::digest(&::ring::digest::SHA256, data).as_ref());
+    hash
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// 受け付けた接続でTLSのhandshakeを行う。`limits.timeout` の間に終わらなければ失敗する
+pub(crate) fn accept(
+    mut socket: TcpStream,
+    config: &Arc<ServerConfig>,
+    limits: &Limits,
+) -> io::Result<TlsStream> {
+    let mut conn = ServerConnection::new(config.clone()).map_err(invalid)?;
+    let deadline = Instant::now() + limits.timeout;
+    while conn.is_handshaking() {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "tls handshake timed out"))?;
+        socket.set_read_timeout(Some(remaining.min(limits.read_timeout)))?;
+        conn.complete_io(&mut socket)?;
+    }
+    socket.set_read_timeout(None)?;
+    // TLS-ALPN-01 の検証はhandshakeだけで終わる (RFC 8737)
+    #[cfg(feature = "acme")]
+    if conn.alpn_protocol() == Some(acme::ALPN) {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "tls-alpn-01 validation",
+        ));
+    }
+    let info = TlsInfo {
+        server_name: conn.server_name().map(str::to_string),
+        alpn_protocol: conn
+            .alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        peer_certificate: conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| PeerCertificate::parse(cert)),
+    };
+    Ok(TlsStream::new(conn.into(), socket, info))
+}
+
+/// `wss://` に接続するクライアントのTLSの設定。`Client::connect_with_tls` に渡す
+#[derive(Clone, Debug)]
+pub struct ClientTlsConfig {
+    roots: RootCertStore,
+    /// 証明書 (DER) またはSubjectPublicKeyInfo (DER) のSHA-256
+    pins: Vec<Pin>,
+    pins_only: bool,
+    /// クライアント証明書と秘密鍵 (mTLS)
+    identity: Option<Arc<CertifiedKey>>,
+    alpn_protocols: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Pin {
+    Certificate([u8; 32]),
+    PublicKey([u8; 32]),
+}
+
+impl Default for ClientTlsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientTlsConfig {
+    /// webpki-roots のCAで検証する
+    pub fn new() -> Self {
+        Self {
+            roots: RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+            pins: vec![],
+            pins_only: false,
+            identity: None,
+            alpn_protocols: vec!["http/1.1".to_string()],
+        }
+    }
+
+    /// webpki-roots の代わりに、PEMの `ca` のCAだけで検証する
+    pub fn with_root_certificates(ca: &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            roots: roots(ca)?,
+            ..Self::new()
+        })
+    }
+
+    /// サーバーの証明書チェーンのどれかのSubjectPublicKeyInfo (DER) のSHA-256が `sha256` であることを求める。
+    /// 複数指定するとどれか1つに一致すればよい (鍵の更新に備えて予備の鍵も指定しておく)
+    pub fn pin_public_key(mut self, sha256: [u8; 32]) -> Self {
+        self.pins.push(Pin::PublicKey(sha256));
+        self
+    }
+
+    /// `pin_public_key` と同じだが、証明書 (DER) 全体のSHA-256と比べる。証明書を更新すると一致しなくなる
+    pub fn pin_certificate(mut self, sha256: [u8; 32]) -> Self {
+        self.pins.push(Pin::Certificate(sha256));
+        self
+    }
+
+    /// CAによる検証とホスト名の確認を行わず、サーバーの証明書 (チェーンの先頭) がピンに一致するかだけを確かめる。
+    /// CAを信頼しない場合や自己署名の証明書に使う。ピンがなければ全ての接続を拒否する
+    pub fn pins_only(mut self) -> Self {
+        self.pins_only = true;
+        self
+    }
+
+    /// サーバーに求められたら提示するクライアント証明書と秘密鍵 (PEM)
+    pub fn client_cert(mut self, cert_chain: &[u8], key: &[u8]) -> io::Result<Self> {
+        self.identity = Some(certified_key(cert_chain, key)?);
+        Ok(self)
+    }
+
+    /// ALPNで提示するプロトコル (優先する順)。デフォルトは `http/1.1` だけ
+    pub fn alpn_protocols(mut self, protocols: &[&str]) -> Self {
+        self.alpn_protocols = protocols
+            .iter()
+            .map(|protocol| protocol.to_string())
+            .collect();
+        self
+    }
+
+    fn build(&self) -> io::Result<Arc<ClientConfig>> {
+        let provider = provider();
+        let webpki = if self.pins_only {
+            None
+        } else {
+            let verifier = WebPkiServerVerifier::builder_with_provider(
+                Arc::new(self.roots.clone()),
+                provider.clone(),
+            )
+            .build()
+            .map_err(invalid)?;
+            Some(verifier)
+        };
+        let verifier = PinningVerifier {
+            webpki,
+            pins: self.pins.clone(),
+            provider: provider.clone(),
+        };
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        let mut config = match &self.identity {
+            Some(identity) => {
+                builder.with_client_cert_resolver(Arc::new(Identity(identity.clone())))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self
+            .alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        Ok(Arc::new(config))
+    }
+}
+
+/// CAによる検証 (`webpki`) とピンの確認を行う
+#[derive(Debug)]
+struct PinningVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<Pin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinningVerifier {
+    fn matches(&self, cert: &CertificateDer<'_>) -> bool {
+        let public_key = X509Certificate::from_der(cert)
+            .ok()
+            .map(|(_, parsed)| sha256(parsed.public_key().raw));
+        self.pins.iter().any(|pin| match pin {
+            Pin::Certificate(hash) => *hash == sha256(cert),
+            Pin::PublicKey(hash) => Some(*hash) == public_key,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let matched = match &self.webpki {
+            Some(webpki) => {
+                webpki.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                )?;
+                // チェーンを検証済みなので、中間CAの鍵をピン留めしてもよい
+                self.pins.is_empty()
+                    || self.matches(end_entity)
+                    || intermediates.iter().any(|cert| self.matches(cert))
+            }
+            None => self.matches(end_entity),
+        };
+        if !matched {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[derive(Debug)]
+struct Identity(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for Identity {
+    fn resolve(&self, _: &[&[u8]], _: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// 接続したソケットでTLSのhandshakeを行う。`host` はSNIで送り、証明書の検証に使う
+pub(crate) fn connect(
+    mut socket: TcpStream,
+    host: &str,
+    config: &ClientTlsConfig,
+) -> io::Result<TlsStream> {
+    // IPv6のアドレスは `[::1]` のように括弧で囲まれている
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string()).map_err(invalid)?;
+    let mut conn = ClientConnection::new(config.build()?, server_name).map_err(invalid)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut socket)?;
+    }
+    let info = TlsInfo {
+        server_name: Some(host.to_string()),
+        alpn_protocol: conn
+            .alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        peer_certificate: conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| PeerCertificate::parse(cert)),
+    };
+    Ok(TlsStream::new(conn.into(), socket, info))
+}
+
+/// TLSの接続。`try_clone` したstreamは同じTLSのセッションを使う
+pub(crate) struct TlsStream {
+    session: Arc<Session>,
+    socket: TcpStream,
+}
+
+struct Session {
+    state: Mutex<State>,
+    /// 暗号化したレコードを書き込む順番を守るため、書き込みは1つずつ
+    writing: Mutex<()>,
+    info: TlsInfo,
+}
+
+struct State {
+    conn: rustls::Connection,
+    /// ソケットから読み込み、まだrustlsに渡していないバイト列
+    incoming: Vec<u8>,
+}
+
+impl State {
+    /// 送信するレコード (暗号化したデータ・alertなど) を取り出す
+    fn outgoing(&mut self) -> io::Result<Vec<u8>> {
+        let mut outgoing = vec![];
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut outgoing)?;
+        }
+        Ok(outgoing)
+    }
+
+    /// 読み込んだバイト列をrustlsに渡して復号する
+    fn process(&mut self) -> io::Result<()> {
+        let n = self.conn.read_tls(&mut &self.incoming[..])?;
+        self.incoming.drain(..n);
+        self.conn.process_new_packets().map_err(invalid_data)?;
+        Ok(())
+    }
+}
+
+impl TlsStream {
+    fn new(conn: rustls::Connection, socket: TcpStream, info: TlsInfo) -> Self {
+        Self {
+            session: Arc::new(Session {
+                state: Mutex::new(State {
+                    conn,
+                    incoming: vec![],
+                }),
+                writing: Mutex::new(()),
+                info,
+            }),
+            socket,
+        }
+    }
+
+    /// 受信で生じた送信 (KeyUpdateへの応答など) を書き込む。
+    /// 書き込み中のスレッドがあれば、そのスレッドか次の書き込みが送るので待たない
+    fn flush_pending(&self) -> io::Result<()> {
+        let Ok(_writing) = self.session.writing.try_lock() else {
+            return Ok(());
+        };
+        let outgoing = self.session.state.lock().unwrap().outgoing()?;
+        (&self.socket).write_all(&outgoing)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut chunk = [0; 16 * 1024];
+        loop {
+            {
+                let mut state = self.session.state.lock().unwrap();
+                match state.conn.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // close_notifyを受信していれば 0、なければUnexpectedEof
+                    result => return result,
+                }
+                if !state.incoming.is_empty() {
+                    state.process()?;
+                    continue;
+                }
+            }
+            self.flush_pending()?;
+            // ロックの外で待つ
+            let n = self.socket.read(&mut chunk)?;
+            let mut state = self.session.state.lock().unwrap();
+            if n == 0 {
+                // EOFをrustlsに知らせる
+                state.conn.read_tls(&mut io::empty())?;
+                state.conn.process_new_packets().map_err(invalid_data)?;
+            } else {
+                state.incoming.extend_from_slice(&chunk[..n]);
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _writing = self.session.writing.lock().unwrap();
+        let (n, outgoing) = {
+            let mut state = self.session.state.lock().unwrap();
+            let n = state.conn.writer().write(buf)?;
+            (n, state.outgoing()?)
+        };
+        self.socket.write_all(&outgoing)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/// `shutdown` はclose_notifyを送らずにTCPの接続を閉じる。
+/// WebSocketのCloseのやり取りで終わるので、切り詰められたかどうかはCloseで分かる
+impl Stream for TlsStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(Self {
+            session: self.session.clone(),
+            socket: self.socket.try_clone()?,
+        }))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        Some(self.session.info.clone())
+    }
+
+    fn has_buffered(&self) -> bool {
+        let mut state = self.session.state.lock().unwrap();
+        !state.incoming.is_empty()
+            || state
+                .conn
+                .process_new_packets()
+                .map_or(true, |io| io.plaintext_bytes_to_read() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair, PublicKeyData,
+        SanType,
+    };
+    use rustls::{ClientConfig, ClientConnection, StreamOwned};
+
+    use super::*;
+    use crate::{
+        client::Client,
+        connection::Connection,
+        frame::{Frame, Opcode},
+        handler::Handler,
+        message::Message,
+        middleware::{Handshake, Next, Outcome, Rejection},
+        server::Server,
+        testing,
+    };
+
+    /// テスト用のCAと、CAが署名した証明書
+    struct Pki {
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "test ca");
+            Self {
+                ca: CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap(),
+            }
+        }
+
+        fn ca_pem(&self) -> String {
+            self.ca.pem()
+        }
+
+        /// (証明書のPEM, 秘密鍵のPEM)
+        fn issue(&self, common_name: &str, names: Vec<SanType>) -> (String, String) {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+            params.subject_alt_names = names;
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.ca).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn server_config(&self) -> TlsConfig {
+            let (cert, key) = self.issue("localhost", vec![dns("localhost")]);
+            TlsConfig::from_pem(cert.as_bytes(), key.as_bytes()).unwrap()
+        }
+
+        /// このCAを信頼するクライアントの設定。`identity` はクライアント証明書と秘密鍵
+        fn client_config(&self, identity: Option<(String, String)>) -> Arc<ClientConfig> {
+            self.client_config_with_alpn(identity, &[])
+        }
+
+        /// `client_config` と同じだが、ALPNで `protocols` を提示する
+        fn client_config_with_alpn(
+            &self,
+            identity: Option<(String, String)>,
+            protocols: &[&str],
+        ) -> Arc<ClientConfig> {
+            let builder = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots(self.ca_pem().as_bytes()).unwrap());
+            let mut config = match identity {
+                Some((cert, key)) => builder
+                    .with_client_auth_cert(
+                        certificates(cert.as_bytes()).unwrap(),
+                        PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+            Arc::new(config)
+        }
+    }
+
+    fn dns(name: &str) -> SanType {
+        SanType::DnsName(name.try_into().unwrap())
+    }
+
+    fn uri(uri: &str) -> SanType {
+        SanType::URI(uri.try_into().unwrap())
+    }
+
+    /// TLSで接続してhandshakeのリクエストを送り、応答のヘッダーを返す
+    fn connect(
+        server: &testing::TestServer,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<(StreamOwned<ClientConnection, TcpStream>, String)> {
+        connect_to(server, "localhost", config)
+    }
+
+    /// `connect` と同じだが、SNIで `server_name` を指定し、その名前で証明書を検証する
+    fn connect_to(
+        server: &testing::TestServer,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<(StreamOwned<ClientConnection, TcpStream>, String)> {
+        let server_name = server_name.to_string().try_into().unwrap();
+        let conn = ClientConnection::new(config, server_name).unwrap();
+        let socket = TcpStream::connect(server.addr())?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut stream = StreamOwned::new(conn, socket);
+        stream.write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )?;
+        let mut response = vec![];
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+        Ok((stream, String::from_utf8(response).unwrap()))
+    }
+
+    fn read_text(stream: &mut StreamOwned<ClientConnection, TcpStream>) -> String {
+        let frame = Frame::read_from(stream).unwrap();
+        assert_eq!(frame.opcode, Opcode::Text);
+        String::from_utf8(frame.payload).unwrap()
+    }
+
+    /// 接続したらSNIのホスト名とクライアント証明書のsubjectを送り、受信したメッセージを返す
+    struct Identity;
+
+    impl Handler for Identity {
+        fn on_open(&self, conn: &mut Connection) {
+            let tls = conn.tls().unwrap();
+            let subject = tls
+                .peer_certificate
+                .as_ref()
+                .map_or("none".to_string(), |peer| peer.subject.clone());
+            let _ = conn.send(Message::Text(format!(
+                "{} {}",
+                tls.server_name.as_deref().unwrap_or("none"),
+                subject
+            )));
+        }
+
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    #[test]
+    fn serves_websocket_over_tls() {
+        let pki = Pki::new();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(pki.server_config())
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+
+        let (mut stream, response) = connect(&server, pki.client_config(None)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert_eq!(read_text(&mut stream), "localhost none");
+        let frame = Frame::new(Opcode::Text, Some(b"hi".to_vec())).masked([1, 2, 3, 4]);
+        stream.write_all(&frame.to_bytes()).unwrap();
+        assert_eq!(read_text(&mut stream), "hi");
+
+        // 平文のhandshakeはTLSのhandshakeで失敗する
+        assert!(testing::TestServer::connect(&server, "/").is_err());
+    }
+
+    #[test]
+    fn authorizes_by_client_certificate() {
+        let pki = Pki::new();
+        let tls = pki
+            .server_config()
+            .require_client_cert(pki.ca_pem().as_bytes())
+            .unwrap();
+        // SANのURIで認可する
+        let layer = |handshake: &mut Handshake, next: Next<'_>| -> Outcome {
+            let peer = handshake
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.peer_certificate.as_ref())
+                .ok_or_else(|| Rejection::new(401, "Unauthorized"))?;
+            if !peer
+                .uris
+                .iter()
+                .any(|uri| uri == "spiffe://example.org/alice")
+            {
+                return Err(Rejection::new(403, "Forbidden"));
+            }
+            next.run(handshake)
+        };
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_layer(layer)
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+
+        let alice = pki.issue("alice", vec![uri("spiffe://example.org/alice")]);
+        let (mut stream, response) = connect(&server, pki.client_config(Some(alice))).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert_eq!(read_text(&mut stream), "localhost CN=alice");
+
+        let bob = pki.issue("bob", vec![uri("spiffe://example.org/bob")]);
+        let (_, response) = connect(&server, pki.client_config(Some(bob))).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+        // 証明書を提示しないクライアントはTLSのhandshakeで切断する
+        assert!(connect(&server, pki.client_config(None)).is_err());
+
+        // 別のCAが署名した証明書も受け付けない
+        let other = Pki::new().issue("alice", vec![uri("spiffe://example.org/alice")]);
+        assert!(connect(&server, pki.client_config(Some(other))).is_err());
+    }
+
+    #[test]
+    fn client_certificate_can_be_optional() {
+        let pki = Pki::new();
+        let tls = pki
+            .server_config()
+            .request_client_cert(pki.ca_pem().as_bytes())
+            .unwrap();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+
+        let (mut stream, _) = connect(&server, pki.client_config(None)).unwrap();
+        assert_eq!(read_text(&mut stream), "localhost none");
+
+        let carol = pki.issue("carol", vec![dns("carol.example.org")]);
+        let (mut stream, _) = connect(&server, pki.client_config(Some(carol))).unwrap();
+        assert_eq!(read_text(&mut stream), "localhost CN=carol");
+    }
+
+    #[test]
+    fn selects_certificate_by_server_name() {
+        let pki = Pki::new();
+        let (a_cert, a_key) = pki.issue("a.example.org", vec![dns("a.example.org")]);
+        let (any_cert, any_key) = pki.issue("*.example.net", vec![dns("*.example.net")]);
+        let tls = pki
+            .server_config()
+            .certificate_for("A.example.org", a_cert.as_bytes(), a_key.as_bytes())
+            .unwrap()
+            .certificate_for("*.example.net", any_cert.as_bytes(), any_key.as_bytes())
+            .unwrap();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let served = |stream: &StreamOwned<ClientConnection, TcpStream>| {
+            stream.conn.peer_certificates().unwrap()[0].to_vec()
+        };
+
+        let (mut stream, _) =
+            connect_to(&server, "a.example.org", pki.client_config(None)).unwrap();
+        assert_eq!(
+            served(&stream),
+            certificates(a_cert.as_bytes()).unwrap()[0].to_vec()
+        );
+        assert_eq!(read_text(&mut stream), "a.example.org none");
+
+        let (mut stream, _) =
+            connect_to(&server, "b.example.net", pki.client_config(None)).unwrap();
+        assert_eq!(
+            served(&stream),
+            certificates(any_cert.as_bytes()).unwrap()[0].to_vec()
+        );
+        assert_eq!(read_text(&mut stream), "b.example.net none");
+
+        // 当てはまらなければ既定の証明書 (localhost) なので、クライアントの検証で失敗する
+        assert!(connect_to(&server, "b.example.org", pki.client_config(None)).is_err());
+        assert!(connect_to(&server, "x.b.example.net", pki.client_config(None)).is_err());
+    }
+
+    /// 接続したらALPNで合意したプロトコルを送る
+    struct Alpn;
+
+    impl Handler for Alpn {
+        fn on_open(&self, conn: &mut Connection) {
+            let protocol = conn.tls().unwrap().alpn_protocol.clone();
+            let _ = conn.send(Message::Text(protocol.unwrap_or("none".to_string())));
+        }
+    }
+
+    #[test]
+    fn negotiates_alpn() {
+        let pki = Pki::new();
+        let server = Server::bind("127.0.0.1:0", Alpn)
+            .unwrap()
+            .with_tls(pki.server_config())
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+
+        let config = pki.client_config_with_alpn(None, &["h2", "http/1.1"]);
+        let (mut stream, _) = connect(&server, config).unwrap();
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+        assert_eq!(read_text(&mut stream), "http/1.1");
+
+        // ALPNを使わないクライアントも受け付ける
+        let (mut stream, _) = connect(&server, pki.client_config(None)).unwrap();
+        assert_eq!(read_text(&mut stream), "none");
+
+        // 共通のプロトコルがなければhandshakeで切断する
+        let config = pki.client_config_with_alpn(None, &["h2"]);
+        assert!(connect(&server, config).is_err());
+
+        // 提示するプロトコルを変える
+        let tls = pki.server_config().alpn_protocols(&["x-test", "http/1.1"]);
+        let server = Server::bind("127.0.0.1:0", Alpn)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let config = pki.client_config_with_alpn(None, &["http/1.1", "x-test"]);
+        let (mut stream, _) = connect(&server, config).unwrap();
+        assert_eq!(read_text(&mut stream), "x-test");
+    }
+
+    #[test]
+    fn reloads_certificate_files() {
+        let dir = env::temp_dir().join(format!("websocket-rs-tls-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let write = |(cert, key): &(String, String)| {
+            fs::write(&cert_path, cert).unwrap();
+            fs::write(&key_path, key).unwrap();
+        };
+        let der = |(cert, _): &(String, String)| certificates(cert.as_bytes()).unwrap()[0].to_vec();
+        let pki = Pki::new();
+        let served = |server: &testing::TestServer| {
+            let (stream, _) = connect(server, pki.client_config(None)).unwrap();
+            stream.conn.peer_certificates().unwrap()[0].to_vec()
+        };
+        let first = pki.issue("first", vec![dns("localhost")]);
+        let second = pki.issue("second", vec![dns("localhost")]);
+        let third = pki.issue("third", vec![dns("localhost")]);
+
+        write(&first);
+        let tls = TlsConfig::from_pem_files(&cert_path, &key_path).unwrap();
+        let reloader = tls.reloader();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let (mut before, _) = connect(&server, pki.client_config(None)).unwrap();
+        assert_eq!(served(&server), der(&first));
+
+        write(&second);
+        reloader.reload().unwrap();
+        assert_eq!(served(&server), der(&second));
+        // 接続済みの接続はそのまま続く
+        assert_eq!(read_text(&mut before), "localhost none");
+
+        // 秘密鍵が対にならなければ差し替えない
+        fs::write(&cert_path, &third.0).unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(served(&server), der(&second));
+
+        // 更新されたファイルを読み直す
+        reloader.clone().watch(Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(100));
+        write(&third);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while served(&server) != der(&third) {
+            assert!(Instant::now() < deadline, "certificate was not reloaded");
+            thread::sleep(Duration::from_millis(20));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_key_not_matching_certificate() {
+        let pki = Pki::new();
+        let (cert, _) = pki.issue("localhost", vec![dns("localhost")]);
+        let (_, key) = pki.issue("localhost", vec![dns("localhost")]);
+        assert!(TlsConfig::from_pem(cert.as_bytes(), key.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parses_subject_alternative_names() {
+        let pki = Pki::new();
+        let (cert, _) = pki.issue(
+            "alice",
+            vec![
+                dns("alice.example.org"),
+                uri("spiffe://example.org/alice"),
+                SanType::Rfc822Name("alice@example.org".try_into().unwrap()),
+                SanType::IpAddress(IpAddr::from([192, 0, 2, 1])),
+            ],
+        );
+        let der = certificates(cert.as_bytes()).unwrap().remove(0);
+        let peer = PeerCertificate::parse(&der).unwrap();
+        assert_eq!(peer.subject, "CN=alice");
+        assert_eq!(peer.dns_names, ["alice.example.org"]);
+        assert_eq!(peer.uris, ["spiffe://example.org/alice"]);
+        assert_eq!(peer.emails, ["alice@example.org"]);
+        assert_eq!(peer.ip_addresses, [IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(peer.der, der.to_vec());
+    }
+
+    fn wss(server: &testing::TestServer) -> String {
+        format!("wss://localhost:{}/", server.addr().port())
+    }
+
+    fn recv_text(client: &mut Client) -> String {
+        match client.recv().unwrap() {
+            Some(Message::Text(text)) => text,
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn client_connects_over_tls() {
+        let pki = Pki::new();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(pki.server_config())
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+
+        let tls = ClientTlsConfig::with_root_certificates(pki.ca_pem().as_bytes()).unwrap();
+        let mut client = Client::connect_with_tls(&wss(&server), &[], &tls).unwrap();
+        assert_eq!(recv_text(&mut client), "localhost none");
+        client.send(Message::Text("hi".to_string())).unwrap();
+        assert_eq!(recv_text(&mut client), "hi");
+        let info = client.tls().unwrap();
+        assert_eq!(info.alpn_protocol.as_deref(), Some("http/1.1"));
+        assert_eq!(info.peer_certificate.unwrap().subject, "CN=localhost");
+        client.close(1000, "").unwrap();
+
+        // webpki-roots のCAはテスト用のCAを信頼しない
+        assert!(Client::connect(&wss(&server)).is_err());
+        assert!(Client::connect_with_tls(
+            &format!("ws://127.0.0.1:{}/", server.addr().port()),
+            &[],
+            &tls
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn client_pins_server_certificate_or_public_key() {
+        let pki = Pki::new();
+        let (cert, key) = pki.issue("localhost", vec![dns("localhost")]);
+        let tls = TlsConfig::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let certificate = sha256(&certificates(cert.as_bytes()).unwrap()[0]);
+        let public_key = sha256(&KeyPair::from_pem(&key).unwrap().subject_public_key_info());
+        let trusted = || ClientTlsConfig::with_root_certificates(pki.ca_pem().as_bytes()).unwrap();
+        let connect = |tls: ClientTlsConfig| Client::connect_with_tls(&wss(&server), &[], &tls);
+
+        let mut client = connect(trusted().pin_public_key(public_key)).unwrap();
+        assert_eq!(recv_text(&mut client), "localhost none");
+        assert!(connect(trusted().pin_certificate(certificate)).is_ok());
+        // どれか1つに一致すればよい
+        assert!(connect(
+            trusted()
+                .pin_public_key([0; 32])
+                .pin_certificate(certificate)
+        )
+        .is_ok());
+
+        assert!(connect(trusted().pin_public_key([0; 32])).is_err());
+        assert!(connect(trusted().pin_certificate(public_key)).is_err());
+        // ピンが一致しても、CAの検証には通らなければならない
+        assert!(connect(ClientTlsConfig::new().pin_public_key(public_key)).is_err());
+    }
+
+    #[test]
+    fn client_pins_self_signed_certificate() {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["self.example".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let tls =
+            TlsConfig::from_pem(cert.pem().as_bytes(), key.serialize_pem().as_bytes()).unwrap();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let public_key = sha256(&key.subject_public_key_info());
+
+        // ホスト名も確認しない
+        let pinned = ClientTlsConfig::new()
+            .pin_public_key(public_key)
+            .pins_only();
+        let mut client = Client::connect_with_tls(&wss(&server), &[], &pinned).unwrap();
+        assert_eq!(recv_text(&mut client), "localhost none");
+
+        let other = sha256(&KeyPair::generate().unwrap().subject_public_key_info());
+        let pinned = ClientTlsConfig::new().pin_public_key(other).pins_only();
+        assert!(Client::connect_with_tls(&wss(&server), &[], &pinned).is_err());
+        assert!(
+            Client::connect_with_tls(&wss(&server), &[], &ClientTlsConfig::new().pins_only())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn client_presents_certificate() {
+        let pki = Pki::new();
+        let tls = pki
+            .server_config()
+            .require_client_cert(pki.ca_pem().as_bytes())
+            .unwrap();
+        let server = Server::bind("127.0.0.1:0", Identity)
+            .unwrap()
+            .with_tls(tls)
+            .unwrap();
+        let server = testing::spawn_server(server).unwrap();
+        let trusted = || ClientTlsConfig::with_root_certificates(pki.ca_pem().as_bytes()).unwrap();
+
+        let (cert, key) = pki.issue("alice", vec![uri("spiffe://example.org/alice")]);
+        let tls = trusted()
+            .client_cert(cert.as_bytes(), key.as_bytes())
+            .unwrap();
+        let mut client = Client::connect_with_tls(&wss(&server), &[], &tls).unwrap();
+        assert_eq!(recv_text(&mut client), "localhost CN=alice");
+
+        // TLS 1.3ではクライアント証明書の拒否はhandshakeの後に届く
+        let rejected = Client::connect_with_tls(&wss(&server), &[], &trusted())
+            .and_then(|mut client| client.recv());
+        assert!(rejected.is_err());
+    }
+}
@@ -0,0 +1,248 @@
+// roomの名前のワイルドカード
+//
+// roomの名前を `.` で区切った階層として扱い、以下を含む名前はパターンになる:
+// `*`  ちょうど1つの階層 (`orders.*` は `orders.new` に一致し、`orders` や `orders.new.jp` には一致しない)
+// `#`  0個以上の階層 (`metrics.#` は `metrics`・`metrics.cpu`・`metrics.cpu.core0` に一致する)
+//
+// パターンのroomに参加した接続には、一致する名前のroomにpublishされたメッセージが届く。
+// 参加中のパターンは階層ごとのtrieに入れておき、publishのたびに全てのパターンを調べずに済むようにする
+//
+// 照合では、パターンの階層ごとに「名前のどの階層まで一致し得るか」の集合を進める。
+// `#` をいくつ含んでいても、時間はパターンと名前の階層数の積に比例する
+
+use std::collections::HashMap;
+
+const SEPARATOR: char = '.';
+const ONE: &str = "*";
+const ANY: &str = "#";
+
+/// `*` か `#` の階層を含むか
+pub fn is_pattern(name: &str) -> bool {
+    name.split(SEPARATOR)
+        .any(|segment| segment == ONE || segment == ANY)
+}
+
+/// `topic` が `pattern` に一致するか。パターンでなければ名前が同じときだけ一致する
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let topic = topic.split(SEPARATOR).collect::<Vec<_>>();
+    let mut reached = start(&topic);
+    for segment in pattern.split(SEPARATOR) {
+        reached = advance(&reached, segment, &topic);
+        if !reached.contains(&true) {
+            return false;
+        }
+    }
+    reached[topic.len()]
+}
+
+/// 何も一致させていない状態: 名前の先頭 (0階層目) にだけいる
+fn start(topic: &[&str]) -> Vec<bool> {
+    let mut reached = vec![false; topic.len() + 1];
+    reached[0] = true;
+    reached
+}
+
+/// `reached[i]` は、ここまでのパターンの階層が名前の先頭 i 階層に一致し得るか。
+/// パターンの次の階層 `segment` まで一致させたときの集合を返す
+fn advance(reached: &[bool], segment: &str, topic: &[&str]) -> Vec<bool> {
+    let mut next = vec![false; reached.len()];
+    if segment == ANY {
+        // 0個以上の階層を飲み込む: 一度届いた位置より後ろには全て届く
+        let mut any = false;
+        for (next, reached) in next.iter_mut().zip(reached) {
+            any |= reached;
+            *next = any;
+        }
+    } else {
+        for (i, name) in topic.iter().enumerate() {
+            next[i + 1] = reached[i] && (segment == ONE || segment == *name);
+        }
+    }
+    next
+}
+
+/// 登録されているパターンのtrie
+#[derive(Default)]
+pub(crate) struct Patterns {
+    root: Node,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// このノードで終わるパターンと、登録された回数
+    pattern: Option<(String, usize)>,
+}
+
+impl Patterns {
+    /// 同じパターンを複数回登録したら、同じ回数 `remove` するまで残る
+    pub fn insert(&mut self, pattern: &str) {
+        let mut node = &mut self.root;
+        for segment in pattern.split(SEPARATOR) {
+            node = node.children.entry(segment.to_string()).or_default();
+        }
+        node.pattern
+            .get_or_insert_with(|| (pattern.to_string(), 0))
+            .1 += 1;
+    }
+
+    pub fn remove(&mut self, pattern: &str) {
+        let segments = pattern.split(SEPARATOR).collect::<Vec<_>>();
+        remove(&mut self.root, &segments);
+    }
+
+    /// `topic` に一致する登録済みのパターン (名前順)
+    pub fn matches(&self, topic: &str) -> Vec<String> {
+        if self.root.children.is_empty() {
+            return vec![];
+        }
+        let segments = topic.split(SEPARATOR).collect::<Vec<_>>();
+        let mut patterns = vec![];
+        collect(&self.root, &start(&segments), &segments, &mut patterns);
+        patterns.sort();
+        patterns
+    }
+}
+
+/// パターンを取り除き、空になったノードを消す。ノードが空になったら true
+fn remove(node: &mut Node, segments: &[&str]) -> bool {
+    match segments.split_first() {
+        None => {
+            if let Some((_, count)) = &mut node.pattern {
+                *count -= 1;
+                if *count == 0 {
+                    node.pattern = None;
+                }
+            }
+        }
+        Some((segment, rest)) => {
+            if let Some(child) = node.children.get_mut(*segment) {
+                if remove(child, rest) {
+                    node.children.remove(*segment);
+                }
+            }
+        }
+    }
+    node.pattern.is_none() && node.children.is_empty()
+}
+
+/// `node` までのパターンが `reached` の位置まで一致し得るとして、一致するパターンを集める。
+/// trieの各ノードは1度しか訪れないので、同じパターンを重複して集めることはない
+fn collect(node: &Node, reached: &[bool], topic: &[&str], patterns: &mut Vec<String>) {
+    if reached[topic.len()] {
+        if let Some((pattern, _)) = &node.pattern {
+            patterns.push(pattern.clone());
+        }
+    }
+    // 一致し得る位置の次の階層と同じ名前の子と、`*`・`#` の子だけを調べる
+    let mut segments = topic
+        .iter()
+        .zip(reached)
+        .filter(|(_, reached)| **reached)
+        .map(|(segment, _)| *segment)
+        .chain([ONE, ANY])
+        .collect::<Vec<_>>();
+    segments.sort_unstable();
+    segments.dedup();
+    for segment in segments {
+        if let Some(child) = node.children.get(segment) {
+            let next = advance(reached, segment, topic);
+            if next.contains(&true) {
+                collect(child, &next, topic, patterns);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn detects_patterns() {
+        assert!(is_pattern("orders.*"));
+        assert!(is_pattern("#"));
+        assert!(is_pattern("metrics.#.max"));
+        // 階層の一部だけの `*`・`#` は普通の文字
+        assert!(!is_pattern("orders.new*"));
+        assert!(!is_pattern("a#b"));
+        assert!(!is_pattern("orders"));
+        assert!(!is_pattern(""));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        for (pattern, topic, expected) in [
+            ("orders.*", "orders.new", true),
+            ("orders.*", "orders", false),
+            ("orders.*", "orders.new.jp", false),
+            ("*.new", "orders.new", true),
+            ("*", "orders", true),
+            ("*", "", true),
+            ("orders.*", "orders.", true),
+            ("metrics.#", "metrics", true),
+            ("metrics.#", "metrics.cpu", true),
+            ("metrics.#", "metrics.cpu.core0", true),
+            ("metrics.#", "metricsx", false),
+            ("#", "anything.at.all", true),
+            ("#.max", "max", true),
+            ("#.max", "cpu.core0.max", true),
+            ("#.max", "cpu.core0.min", false),
+            ("a.#.b.*", "a.b.c", true),
+            ("a.#.b.*", "a.x.y.b.c", true),
+            ("a.#.b.*", "a.x.y.b", false),
+            ("orders", "orders", true),
+            ("orders", "orders.new", false),
+            ("orders.new*", "orders.new1", false),
+        ] {
+            assert_eq!(matches(pattern, topic), expected, "{} {}", pattern, topic);
+        }
+    }
+
+    #[test]
+    fn finds_registered_patterns() {
+        let mut patterns = Patterns::default();
+        assert!(patterns.matches("a.b").is_empty());
+        for pattern in ["a.*", "a.#", "#", "*.b", "a.b", "x.*"] {
+            patterns.insert(pattern);
+        }
+        assert_eq!(patterns.matches("a.b"), ["#", "*.b", "a.#", "a.*", "a.b"]);
+        assert_eq!(patterns.matches("a"), ["#", "a.#"]);
+        assert_eq!(patterns.matches("y.z.w"), ["#"]);
+        // 同じパターンに何通りにも一致しても1回だけ返す
+        patterns.insert("#.#");
+        assert_eq!(patterns.matches("a.b.c"), ["#", "#.#", "a.#"]);
+    }
+
+    #[test]
+    fn keeps_patterns_until_removed_as_often_as_inserted() {
+        let mut patterns = Patterns::default();
+        patterns.insert("a.*");
+        patterns.insert("a.*");
+        patterns.insert("a.b.#");
+        patterns.remove("a.*");
+        assert_eq!(patterns.matches("a.b"), ["a.*", "a.b.#"]);
+        patterns.remove("a.*");
+        assert_eq!(patterns.matches("a.b"), ["a.b.#"]);
+        // 登録していないパターンを取り除いても何も起きない
+        patterns.remove("a.c");
+        patterns.remove("a.b");
+        patterns.remove("a.b.#");
+        assert!(patterns.matches("a.b").is_empty());
+        assert!(patterns.root.children.is_empty());
+    }
+
+    #[test]
+    fn many_multi_level_wildcards_do_not_backtrack_exponentially() {
+        let pattern = vec![ANY; 24].join(".") + ".end";
+        let topic = vec!["x"; 40].join(".");
+        let started = Instant::now();
+        assert!(!matches(&pattern, &topic));
+        let mut patterns = Patterns::default();
+        patterns.insert(&pattern);
+        assert!(patterns.matches(&topic).is_empty());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,100 @@
+// フレーム単位のトレース
+//
+// ログレベルを Trace にし、target `websocket_rs::frame` を有効にすると、
+// 送受信したフレームのヘッダーとpayloadの先頭Nバイトのhexdumpを出力する。
+// 他のWebSocket実装との相互接続で問題が起きたときの調査用
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    connection::ConnectionId,
+    frame::Frame,
+    log::{self, trace, Level},
+    record::Direction,
+};
+
+pub const TARGET: &str = "websocket_rs::frame";
+
+static HEXDUMP_LEN: AtomicUsize = AtomicUsize::new(64);
+
+/// hexdumpするpayloadのバイト数
+pub fn set_hexdump_len(len: usize) {
+    HEXDUMP_LEN.store(len, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled() -> bool {
+    log::target_enabled(Level::Trace, TARGET)
+}
+
+pub(crate) fn frame(id: ConnectionId, direction: Direction, frame: &Frame) {
+    if !enabled() {
+        return;
+    }
+
+    let direction = match direction {
+        Direction::Inbound => "<",
+        Direction::Outbound => ">",
+    };
+    let len = frame.payload.len().min(HEXDUMP_LEN.load(Ordering::Relaxed));
+    let dump = if len > 0 {
+        format!("\n{}", hexdump(&frame.payload[..len]))
+    } else {
+        String::new()
+    };
+
+    trace!(
+        target: TARGET,
+        "frame",
+        {
+            connection_id: id,
+            direction: direction,
+            fin: frame.fin,
+            rsv1: frame.rsv1,
+            rsv2: frame.rsv2,
+            rsv3: frame.rsv3,
+            opcode: format!("{:?}", frame.opcode),
+            mask: frame.mask,
+            size: frame.payload_len,
+            hexdump: hexdump(&frame.payload[..len]),
+        },
+        "connection {} {} fin={} rsv1={} rsv2={} rsv3={} opcode={:?} mask={} masking_key={:02x?} payload_len={}{}",
+        id,
+        direction,
+        frame.fin,
+        frame.rsv1,
+        frame.rsv2,
+        frame.rsv3,
+        frame.opcode,
+        frame.mask,
+        frame.masking_key,
+        frame.payload_len,
+        dump
+    );
+}
+
+/// `0000  48 65 6c 6c 6f                                   |Hello|` の形式
+fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex = chunk
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            format!("{:04x}  {:<47}  |{}|", i * 16, hex, ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
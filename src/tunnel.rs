@@ -0,0 +1,217 @@
+// WebSocketの上でTCPの接続をトンネルする
+//
+// [TCPクライアント] -> forward (ws-tunnel) ==WebSocket==> TcpTunnel (サーバー) -> [TCPのサービス]
+//
+// TCPのバイト列はそのままBinaryメッセージのpayloadにする (境界に意味はない)。
+// どちらかのTCP接続が閉じたらWebSocketの接続を閉じ、WebSocketの接続が閉じたらTCP接続も閉じる。
+// HTTPしか通さない環境の向こうにあるTCPのサービス (SSHやデータベースなど) に接続するために使う
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    client::{Client, ClientWriter},
+    connection::{Connection, ConnectionId},
+    error::{Error, Result},
+    frame::Frame,
+    handler::Handler,
+    log::{debug, warning},
+    message::Message,
+};
+
+/// 1回のreadで読み込む最大のバイト数 (= Binaryメッセージの最大サイズ)
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// routeに一致したpathの接続を、対応するTCPのアドレスにトンネルするHandler (サーバー側)。
+/// どのrouteにも一致しない接続は `fallback` に渡す
+pub struct TcpTunnel<H = ()> {
+    /// (path, 接続先のアドレス)
+    routes: Vec<(String, String)>,
+    fallback: H,
+    streams: Mutex<HashMap<ConnectionId, TcpStream>>,
+}
+
+impl Default for TcpTunnel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpTunnel {
+    pub fn new() -> Self {
+        Self::with_fallback(())
+    }
+}
+
+impl<H: Handler> TcpTunnel<H> {
+    pub fn with_fallback(fallback: H) -> Self {
+        Self {
+            routes: vec![],
+            fallback,
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `path` への接続を `target` (host:port) にトンネルする
+    pub fn route(mut self, path: &str, target: &str) -> Self {
+        self.routes.push((path.to_string(), target.to_string()));
+        self
+    }
+
+    fn target(&self, path: &str) -> Option<&str> {
+        let (path, _) = path.split_once('?').unwrap_or((path, ""));
+        self.routes
+            .iter()
+            .find(|(route, _)| route == path)
+            .map(|(_, target)| target.as_str())
+    }
+
+    fn is_tunnel(&self, id: ConnectionId) -> bool {
+        self.streams.lock().unwrap().contains_key(&id)
+    }
+
+    fn open(&self, conn: &mut Connection, target: &str) -> io::Result<()> {
+        let stream = TcpStream::connect(target)?;
+        let mut reader = stream.try_clone()?;
+        self.streams.lock().unwrap().insert(conn.id(), stream);
+        debug!(
+            "tunnel_opened",
+            { connection_id: conn.id(), target: target },
+            "connection {} tunneled to {}",
+            conn.id(),
+            target
+        );
+
+        let handle = conn.handle();
+        thread::spawn(move || {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            while let Ok(n @ 1..) = reader.read(&mut buffer) {
+                if handle.send(Message::Binary(buffer[..n].to_vec())).is_err() {
+                    return;
+                }
+            }
+            let _ = handle.close(1000, "");
+        });
+        Ok(())
+    }
+}
+
+impl<H: Handler> Handler for TcpTunnel<H> {
+    fn select_protocol(&self, path: &str, offered: &[&str]) -> Option<String> {
+        self.fallback.select_protocol(path, offered)
+    }
+
+    fn on_open(&self, conn: &mut Connection) {
+        let Some(target) = self.target(conn.path()).map(str::to_string) else {
+            return self.fallback.on_open(conn);
+        };
+        if let Err(e) = self.open(conn, &target) {
+            warning!(
+                "tunnel_error",
+                { connection_id: conn.id(), target: target.as_str(), error: e.to_string() },
+                "tunnel to {}: {}",
+                target,
+                e
+            );
+            let _ = conn.close(1014, "");
+        }
+    }
+
+    fn on_message(&self, conn: &mut Connection, message: Message) {
+        let written = match self.streams.lock().unwrap().get_mut(&conn.id()) {
+            Some(stream) => stream.write_all(message.as_bytes()),
+            None => return self.fallback.on_message(conn, message),
+        };
+        if written.is_err() {
+            let _ = conn.close(1014, "");
+        }
+    }
+
+    fn on_peer_close(&self, conn: &mut Connection, code: u16, reason: &str) {
+        if !self.is_tunnel(conn.id()) {
+            self.fallback.on_peer_close(conn, code, reason);
+        }
+    }
+
+    fn on_pong(&self, conn: &mut Connection, payload: &[u8], latency: Option<Duration>) {
+        if !self.is_tunnel(conn.id()) {
+            self.fallback.on_pong(conn, payload, latency);
+        }
+    }
+
+    fn on_close(&self, id: ConnectionId) {
+        match self.streams.lock().unwrap().remove(&id) {
+            Some(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            None => self.fallback.on_close(id),
+        }
+    }
+
+    fn on_error(&self, id: ConnectionId, error: &Error, terminated: bool) {
+        if !self.is_tunnel(id) {
+            self.fallback.on_error(id, error, terminated);
+        }
+    }
+}
+
+/// listenerで受け付けたTCP接続ごとに `url` へWebSocketで接続してトンネルする (クライアント側)
+pub fn forward(listener: TcpListener, url: &str) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let url = url.to_string();
+        thread::spawn(move || {
+            if let Err(e) = tunnel(stream, &url) {
+                warning!(
+                    "tunnel_error",
+                    { url: url.as_str(), error: e.to_string() },
+                    "tunnel to {}: {}",
+                    url,
+                    e
+                );
+            }
+        });
+    }
+    Ok(())
+}
+
+/// 1つのTCP接続を `url` へトンネルする。どちらかが閉じるまで戻らない
+pub fn tunnel(stream: TcpStream, url: &str) -> Result<()> {
+    let mut client = Client::connect(url)?;
+    let writer = client.writer();
+
+    // WebSocket -> TCP
+    let mut output = stream.try_clone()?;
+    let receiver = thread::spawn(move || {
+        while let Ok(Some(message)) = client.recv() {
+            if output.write_all(message.as_bytes()).is_err() {
+                break;
+            }
+        }
+        let _ = output.shutdown(Shutdown::Both);
+    });
+
+    // TCP -> WebSocket
+    let result = copy(stream, &writer);
+    let _ = writer.send_frame(Frame::close(1000, ""));
+    let _ = receiver.join();
+    result
+}
+
+fn copy(mut stream: TcpStream, writer: &ClientWriter) -> Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        match stream.read(&mut buffer)? {
+            0 => return Ok(()),
+            n => writer.send(Message::Binary(buffer[..n].to_vec()))?,
+        }
+    }
+}
@@ -0,0 +1,812 @@
+// io_uringのevent loop
+//
+// `Server::run_io_uring(threads)` は `event_loop` と同じく `threads` 個のスレッドで全ての接続を扱う。
+// readinessを待ってから1つずつ読み書きする代わりに、全ての接続のrecvとsend、待ち受けソケットのacceptを
+// io_uringに登録しておき、1回の `io_uring_enter` でまとめて登録し、完了したものをまとめて受け取る。
+// 接続が多いほど、1メッセージあたりのsyscallが減る。届いたbyte列は `event_loop::State` で処理する
+//
+// 送信: Handlerや `ConnectionHandle::send` が書き込んだbyte列は接続ごとのbufferに溜める。
+// event loopは受け取った完了を処理し終えてから、溜まっている全ての接続のsendをまとめて登録する。
+// 他のスレッドから書き込んだ場合は eventfd でevent loopを起こす。
+// 書き込みは待たずに戻るので、相手が読み込まなければbufferが溜まり、`MAX_PENDING` を超えると書き込みがエラーになる
+//
+// 接続を閉じるとき (`Stream::shutdown`) は、溜まっている分を送り終えてからソケットを閉じる。
+// `CLOSE_LINGER` までに送り終わらなければ、送らずに閉じる
+//
+// recv・sendに渡したbufferは完了を受け取るまでカーネルが使うので、完了するまで解放しない
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::{
+    event_loop::{self, State, Step},
+    handler::Handler,
+    server::{self, Shared},
+    stream::Stream,
+};
+
+/// 接続ごとのrecvのbufferの大きさ
+const RECV_BUFFER: usize = 4 * 1024;
+
+/// 1つの接続に溜めておける、送信待ちのbyte数
+const MAX_PENDING: usize = 16 * 1024 * 1024;
+
+/// 接続を終えてから、溜まっている分を送り終えるのを待つ時間
+const CLOSE_LINGER: Duration = Duration::from_secs(5);
+
+/// submission queueの大きさ。一杯になったらその時点で登録する
+const RING_ENTRIES: u32 = 1024;
+
+/// `user_data` の下位2bitで操作を区別する。上位bitは接続のtoken (acceptとwakeは0)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Accept = 0,
+    Wake = 1,
+    Recv = 2,
+    Send = 3,
+}
+
+fn user_data(token: usize, op: Op) -> u64 {
+    ((token as u64) << 2) | op as u64
+}
+
+fn parse_user_data(data: u64) -> (usize, Op) {
+    let op = match data & 3 {
+        0 => Op::Accept,
+        1 => Op::Wake,
+        2 => Op::Recv,
+        _ => Op::Send,
+    };
+    ((data >> 2) as usize, op)
+}
+
+/// `threads` 個のスレッドでio_uringのevent loopを動かす
+pub(crate) fn run<H: Handler>(
+    listener: TcpListener,
+    shared: Arc<Shared<H>>,
+    threads: usize,
+) -> io::Result<()> {
+    event_loop::run_threads(listener, shared, threads, |listener, shared| {
+        UringLoop::new(listener, shared)?.run()
+    })
+}
+
+/// 他のスレッドから、送信するbyte列が溜まったことをevent loopに知らせる
+struct Waker {
+    eventfd: OwnedFd,
+    /// event loopのスレッド
+    thread: ThreadId,
+    /// 送信するbyte列が溜まった接続のtoken
+    dirty: Mutex<Vec<usize>>,
+}
+
+impl Waker {
+    fn new() -> io::Result<Self> {
+        // SAFETY: 引数は定数で、返り値は下で確かめる
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            // SAFETY: eventfdが返した、他に持ち主のいないfd
+            eventfd: unsafe { OwnedFd::from_raw_fd(fd) },
+            thread: thread::current().id(),
+            dirty: Mutex::new(vec![]),
+        })
+    }
+
+    fn on_loop_thread(&self) -> bool {
+        thread::current().id() == self.thread
+    }
+
+    fn queue(&self, token: usize) {
+        self.dirty.lock().unwrap().push(token);
+        // event loopのスレッドなら、完了の処理を終えたところで登録するので起こさなくてよい
+        if !self.on_loop_thread() {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        let one = 1u64.to_ne_bytes();
+        // SAFETY: 8byteのbufferを渡している。失敗してもカウンタが飽和しているだけなので無視する
+        unsafe {
+            libc::write(
+                self.eventfd.as_raw_fd(),
+                one.as_ptr() as *const libc::c_void,
+                one.len(),
+            );
+        }
+    }
+
+    fn take(&self) -> Vec<usize> {
+        std::mem::take(&mut *self.dirty.lock().unwrap())
+    }
+}
+
+/// 接続ごとの送信待ちのbyte列
+#[derive(Default)]
+struct Pending {
+    bytes: Vec<u8>,
+    /// `Waker::dirty` に入っている
+    queued: bool,
+    /// 溜まっている分を送り終えたら `shutdown` する
+    shutdown: Option<Shutdown>,
+    /// 専用のスレッドに渡したので、ソケットに直接書き込む
+    detached: bool,
+}
+
+struct Outgoing {
+    token: usize,
+    waker: Arc<Waker>,
+    pending: Mutex<Pending>,
+}
+
+impl Outgoing {
+    /// event loopに送信を登録させる
+    fn queue(&self, pending: &mut Pending) {
+        if !pending.queued {
+            pending.queued = true;
+            self.waker.queue(self.token);
+        }
+    }
+}
+
+/// io_uringのevent loopが扱う接続。書き込みは溜めておき、event loopがまとめて送る
+#[derive(Clone)]
+struct UringStream {
+    socket: Arc<TcpStream>,
+    outgoing: Arc<Outgoing>,
+}
+
+impl Read for UringStream {
+    // event loopはrecvで読み込むので、これを使うのは専用のスレッドに渡した後だけ
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.socket).read(buf)
+    }
+}
+
+impl Write for UringStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pending = self.outgoing.pending.lock().unwrap();
+        if pending.detached {
+            drop(pending);
+            return (&*self.socket).write(buf);
+        }
+        if pending.bytes.len() + buf.len() > MAX_PENDING {
+            return Err(io::Error::other("send buffer is full"));
+        }
+        pending.bytes.extend_from_slice(buf);
+        self.outgoing.queue(&mut pending);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for UringStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    /// event loopのスレッドからなら、溜まっている分を送り終えてから閉じる。
+    /// 他のスレッドからなら、書き込みを待たずにすぐ閉じる (応答のない接続を切るときなど)
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut pending = self.outgoing.pending.lock().unwrap();
+        if pending.detached || !self.outgoing.waker.on_loop_thread() {
+            drop(pending);
+            return self.socket.shutdown(how);
+        }
+        pending.shutdown = Some(how);
+        self.outgoing.queue(&mut pending);
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+}
+
+/// event loopが持つ接続ごとの状態
+struct Slot {
+    socket: Arc<TcpStream>,
+    outgoing: Arc<Outgoing>,
+    /// None なら接続を終えた
+    state: Option<State>,
+    recv_buffer: Vec<u8>,
+    receiving: bool,
+    /// 送信中のbyte列と、送信済みのbyte数
+    sending: Option<(Vec<u8>, usize)>,
+    /// 接続を終えてから、溜まっている分を送り終えるのを待つ期限
+    linger: Option<Instant>,
+    /// ソケットを閉じたか、専用のスレッドに渡した
+    released: bool,
+}
+
+impl Slot {
+    /// handshakeの期限か、送り終えるのを待つ期限
+    fn deadline(&self) -> Option<Instant> {
+        if self.released {
+            return None;
+        }
+        match &self.state {
+            Some(state) => state.deadline(),
+            None => self.linger,
+        }
+    }
+}
+
+/// 1つのスレッドのio_uringのevent loop
+struct UringLoop<H> {
+    ring: IoUring,
+    listener: TcpListener,
+    shared: Arc<Shared<H>>,
+    waker: Arc<Waker>,
+    /// eventfdを読み込むbuffer
+    wake_buffer: Box<[u8; 8]>,
+    slots: HashMap<usize, Slot>,
+    next_token: usize,
+    /// 完了を受け取っていない、bufferを渡したrecv・send・eventfdのread
+    busy: usize,
+}
+
+impl<H: Handler> UringLoop<H> {
+    fn new(listener: TcpListener, shared: Arc<Shared<H>>) -> io::Result<Self> {
+        Ok(Self {
+            ring: IoUring::new(RING_ENTRIES)?,
+            listener,
+            shared,
+            waker: Arc::new(Waker::new()?),
+            wake_buffer: Box::new([0; 8]),
+            slots: HashMap::new(),
+            next_token: 1,
+            busy: 0,
+        })
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        self.push_accept()?;
+        self.push_wake()?;
+        loop {
+            for token in self.waker.take() {
+                self.flush(token)?;
+            }
+
+            let now = Instant::now();
+            let timeout = self
+                .slots
+                .values()
+                .filter_map(Slot::deadline)
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now));
+            self.submit_and_wait(timeout)?;
+
+            let completions = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect::<Vec<_>>();
+            for (data, result) in completions {
+                let (token, op) = parse_user_data(data);
+                match op {
+                    Op::Accept => {
+                        self.accepted(result)?;
+                        // 新しいプロセスに引き継いだ後はacceptし直さない
+                        if !self.shared.handed_off.load(Ordering::SeqCst) {
+                            self.push_accept()?;
+                        }
+                    }
+                    Op::Wake => {
+                        self.busy -= 1;
+                        self.push_wake()?;
+                    }
+                    Op::Recv => {
+                        self.busy -= 1;
+                        self.received(token, result)?;
+                    }
+                    Op::Send => {
+                        self.busy -= 1;
+                        self.sent(token, result)?;
+                    }
+                }
+                self.release(token);
+            }
+            self.expire(Instant::now());
+        }
+    }
+
+    /// 登録し、完了が1つ以上届くか `timeout` が過ぎるまで待つ
+    fn submit_and_wait(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let submitter = self.ring.submitter();
+        let result = match timeout {
+            Some(timeout) => {
+                let timespec = types::Timespec::from(timeout);
+                let args = types::SubmitArgs::new().timespec(&timespec);
+                submitter.submit_with_args(1, &args)
+            }
+            None => submitter.submit_and_wait(1),
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::ETIME | libc::EINTR | libc::EBUSY)
+                ) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// submission queueに入れる。一杯ならそれまでの分を先に登録する
+    ///
+    /// SAFETY: `entry` が指すbufferは、完了を受け取るまで有効でなければならない
+    unsafe fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        loop {
+            if self.ring.submission().push(&entry).is_ok() {
+                return Ok(());
+            }
+            self.ring.submit()?;
+        }
+    }
+
+    fn push_accept(&mut self) -> io::Result<()> {
+        let entry = opcode::Accept::new(
+            types::Fd(self.listener.as_raw_fd()),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+        .flags(libc::SOCK_CLOEXEC)
+        .build()
+        .user_data(user_data(0, Op::Accept));
+        // SAFETY: bufferを渡していない
+        unsafe { self.push(entry) }
+    }
+
+    fn push_wake(&mut self) -> io::Result<()> {
+        let entry = opcode::Read::new(
+            types::Fd(self.waker.eventfd.as_raw_fd()),
+            self.wake_buffer.as_mut_ptr(),
+            8,
+        )
+        .build()
+        .user_data(user_data(0, Op::Wake));
+        self.busy += 1;
+        // SAFETY: `wake_buffer` はevent loopと同じだけ生きる。Dropで完了を待つ
+        unsafe { self.push(entry) }
+    }
+
+    fn push_recv(&mut self, token: usize) -> io::Result<()> {
+        let Some(slot) = self.slots.get_mut(&token) else {
+            return Ok(());
+        };
+        let entry = opcode::Recv::new(
+            types::Fd(slot.socket.as_raw_fd()),
+            slot.recv_buffer.as_mut_ptr(),
+            slot.recv_buffer.len() as u32,
+        )
+        .build()
+        .user_data(user_data(token, Op::Recv));
+        slot.receiving = true;
+        self.busy += 1;
+        // SAFETY: `recv_buffer` は `receiving` の間、Slotを外さないので解放されない
+        unsafe { self.push(entry) }
+    }
+
+    fn push_send(&mut self, token: usize) -> io::Result<()> {
+        let Some(slot) = self.slots.get_mut(&token) else {
+            return Ok(());
+        };
+        let Some((bytes, sent)) = &slot.sending else {
+            return Ok(());
+        };
+        let rest = &bytes[*sent..];
+        let entry = opcode::Send::new(
+            types::Fd(slot.socket.as_raw_fd()),
+            rest.as_ptr(),
+            rest.len().min(u32::MAX as usize) as u32,
+        )
+        .flags(libc::MSG_NOSIGNAL)
+        .build()
+        .user_data(user_data(token, Op::Send));
+        self.busy += 1;
+        // SAFETY: `sending` は完了を受け取るまで変えず、Slotも外さない
+        unsafe { self.push(entry) }
+    }
+
+    fn accepted(&mut self, result: i32) -> io::Result<()> {
+        if result < 0 {
+            return match -result {
+                // 待ち受けソケットが使えなくなった
+                libc::EBADF | libc::EINVAL | libc::ENOTSOCK => {
+                    Err(io::Error::from_raw_os_error(-result))
+                }
+                // 接続ごとのエラーや、fdが足りない場合は受け付け直す
+                _ => Ok(()),
+            };
+        }
+        // SAFETY: acceptが返した、他に持ち主のいないfd
+        let mut stream = TcpStream::from(unsafe { OwnedFd::from_raw_fd(result as RawFd) });
+        if !self.shared.admits(&mut stream) {
+            return Ok(());
+        }
+
+        let token = self.next_token;
+        self.next_token += 1;
+        let socket = Arc::new(stream);
+        let outgoing = Arc::new(Outgoing {
+            token,
+            waker: self.waker.clone(),
+            pending: Mutex::new(Pending::default()),
+        });
+        let stream = UringStream {
+            socket: socket.clone(),
+            outgoing: outgoing.clone(),
+        };
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let Some((conn, span, handshake_span)) = server::open(id, Box::new(stream), &self.shared)
+        else {
+            return Ok(());
+        };
+        let state = State::accepted(conn, span, handshake_span, &self.shared);
+        self.slots.insert(
+            token,
+            Slot {
+                socket,
+                outgoing,
+                state: Some(state),
+                recv_buffer: vec![0; RECV_BUFFER],
+                receiving: false,
+                sending: None,
+                linger: None,
+                released: false,
+            },
+        );
+        self.push_recv(token)
+    }
+
+    fn received(&mut self, token: usize, result: i32) -> io::Result<()> {
+        let Some(slot) = self.slots.get_mut(&token) else {
+            return Ok(());
+        };
+        slot.receiving = false;
+        // 接続を終えた後に届いた分は捨てる
+        let Some(state) = slot.state.take() else {
+            return Ok(());
+        };
+        let received = if result < 0 {
+            Err(io::Error::from_raw_os_error(-result))
+        } else {
+            Ok(&slot.recv_buffer[..result as usize])
+        };
+        match state.received(received, &self.shared) {
+            Step::Wait(state) => {
+                slot.state = Some(state);
+                self.push_recv(token)?;
+            }
+            Step::End(ending) => {
+                ending.end(&self.shared);
+                self.close(token);
+            }
+            Step::Http(conn, request, body) => {
+                // 応答を送り続けるので、専用のスレッドに渡す。以降はソケットに直接読み書きする
+                slot.outgoing.pending.lock().unwrap().detached = true;
+                slot.released = true;
+                let shared = self.shared.clone();
+                thread::spawn(move || server::serve_http(conn, &request, body, &shared));
+            }
+        }
+        Ok(())
+    }
+
+    fn sent(&mut self, token: usize, result: i32) -> io::Result<()> {
+        let Some(slot) = self.slots.get_mut(&token) else {
+            return Ok(());
+        };
+        if result < 0 {
+            // 送れなくなった接続は閉じる。recvがEOFで完了し、接続を終える
+            slot.sending = None;
+            let _ = slot.socket.shutdown(Shutdown::Both);
+            if slot.state.is_none() {
+                slot.released = true;
+            }
+            return Ok(());
+        }
+        let Some((bytes, sent)) = &mut slot.sending else {
+            return Ok(());
+        };
+        *sent += result as usize;
+        if *sent < bytes.len() {
+            return self.push_send(token);
+        }
+        slot.sending = None;
+        self.flush(token)
+    }
+
+    /// 溜まっているbyte列を送る。送るものがなく、閉じるよう頼まれていれば閉じる
+    fn flush(&mut self, token: usize) -> io::Result<()> {
+        let Some(slot) = self.slots.get_mut(&token) else {
+            return Ok(());
+        };
+        if slot.sending.is_some() || slot.released {
+            // 送り終えたところでもう一度呼ぶ
+            return Ok(());
+        }
+        let mut pending = slot.outgoing.pending.lock().unwrap();
+        pending.queued = false;
+        if !pending.bytes.is_empty() {
+            slot.sending = Some((std::mem::take(&mut pending.bytes), 0));
+            drop(pending);
+            return self.push_send(token);
+        }
+        if let Some(how) = pending.shutdown.take() {
+            drop(pending);
+            let _ = slot.socket.shutdown(how);
+            if how == Shutdown::Both || slot.state.is_none() {
+                slot.released = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// 接続を終えた。溜まっている分を送り終えたらソケットを閉じる
+    fn close(&mut self, token: usize) {
+        let Some(slot) = self.slots.get_mut(&token) else {
+            return;
+        };
+        slot.state = None;
+        slot.linger = Some(Instant::now() + CLOSE_LINGER);
+        let mut pending = slot.outgoing.pending.lock().unwrap();
+        pending.shutdown = Some(Shutdown::Both);
+        slot.outgoing.queue(&mut pending);
+    }
+
+    /// 完了を待つものがなくなった接続を外す
+    fn release(&mut self, token: usize) {
+        let idle = self.slots.get(&token).is_some_and(|slot| {
+            slot.state.is_none() && slot.released && !slot.receiving && slot.sending.is_none()
+        });
+        if idle {
+            self.slots.remove(&token);
+        }
+    }
+
+    /// handshakeの期限や、閉じる前に送り終えるのを待つ期限が過ぎた接続を閉じる
+    fn expire(&mut self, now: Instant) {
+        let tokens = self.slots.keys().copied().collect::<Vec<_>>();
+        for token in tokens {
+            let Some(slot) = self.slots.get_mut(&token) else {
+                continue;
+            };
+            if slot.deadline().is_none_or(|deadline| deadline > now) {
+                continue;
+            }
+            match slot.state.take() {
+                Some(state) => {
+                    state.expire().end(&self.shared);
+                    self.close(token);
+                }
+                None => {
+                    // 送信中のsendもエラーで完了する
+                    let _ = slot.socket.shutdown(Shutdown::Both);
+                    slot.released = true;
+                    self.release(token);
+                }
+            }
+        }
+    }
+}
+
+impl<H> Drop for UringLoop<H> {
+    // カーネルがbufferを使い終わるまで待ってから解放する
+    fn drop(&mut self) {
+        for slot in self.slots.values() {
+            let _ = slot.socket.shutdown(Shutdown::Both);
+        }
+        self.waker.wake();
+        while self.busy > 0 {
+            if self.ring.submit_and_wait(1).is_err() {
+                // 待てなければ、bufferを解放せずに残す
+                std::mem::forget(std::mem::take(&mut self.slots));
+                std::mem::forget(std::mem::replace(&mut self.wake_buffer, Box::new([0; 8])));
+                return;
+            }
+            for cqe in self.ring.completion() {
+                if parse_user_data(cqe.user_data()).1 != Op::Accept {
+                    self.busy -= 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        connection::Connection,
+        frame::{Frame, Opcode},
+        message::Message,
+        server::{Config, Server},
+        testing::{self, TestServer},
+    };
+
+    /// 受信したメッセージを返す。"/lobby" に接続したらroomに入る
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_open(&self, conn: &mut Connection) {
+            if conn.path() == "/lobby" {
+                let _ = conn.join("lobby");
+            }
+        }
+
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    fn spawn(config: Config, threads: usize) -> TestServer {
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_config(config);
+        testing::spawn_io_uring_server(server, threads).unwrap()
+    }
+
+    const REQUEST: &str = "GET / HTTP/1.1\r\n\
+        Host: 127.0.0.1\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        \r\n";
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut response = vec![];
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn encodes_operations_in_user_data() {
+        for op in [Op::Accept, Op::Wake, Op::Recv, Op::Send] {
+            assert_eq!(parse_user_data(user_data(12345, op)), (12345, op));
+        }
+    }
+
+    #[test]
+    fn echoes_messages_of_many_clients_on_one_thread() {
+        let server = spawn(Config::default(), 1);
+        let mut clients = (0..3)
+            .map(|_| server.connect("/").unwrap())
+            .collect::<Vec<_>>();
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.send(Message::Text(format!("hello {}", i))).unwrap();
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            assert_eq!(
+                client.recv().unwrap(),
+                Some(Message::Text(format!("hello {}", i)))
+            );
+        }
+        for mut client in clients {
+            client.close(1000, "bye").unwrap();
+        }
+    }
+
+    #[test]
+    fn sends_messages_larger_than_the_socket_buffer() {
+        let server = spawn(Config::default(), 2);
+        let mut client = server.connect("/").unwrap();
+        let message = Message::Binary((0..4 * 1024 * 1024).map(|i| i as u8).collect());
+        client.send(message.clone()).unwrap();
+        assert_eq!(client.recv().unwrap(), Some(message));
+    }
+
+    #[test]
+    fn wakes_the_loop_for_messages_from_other_threads() {
+        let server = spawn(Config::default(), 1);
+        let mut client = server.connect("/lobby").unwrap();
+        // on_openでroomに入ったことを、Echoの応答で確かめる
+        client.send(Message::Text("joined?".to_string())).unwrap();
+        client.recv().unwrap();
+
+        let hub = server.hub();
+        thread::spawn(move || hub.publish("lobby", Message::Text("news".to_string())))
+            .join()
+            .unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("news".to_string()))
+        );
+    }
+
+    #[test]
+    fn reads_handshakes_and_frames_sent_in_pieces() {
+        let server = spawn(Config::default(), 1);
+        let mut slow = TcpStream::connect(server.addr()).unwrap();
+        slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (first, rest) = REQUEST.split_at(20);
+        slow.write_all(first.as_bytes()).unwrap();
+
+        let mut client = server.connect("/").unwrap();
+        client.send(Message::Text("fast".to_string())).unwrap();
+        assert_eq!(
+            client.recv().unwrap(),
+            Some(Message::Text("fast".to_string()))
+        );
+
+        let frame = Frame::new(Opcode::Text, Some(b"slow".to_vec()))
+            .masked([1, 2, 3, 4])
+            .to_bytes();
+        slow.write_all(rest.as_bytes()).unwrap();
+        assert!(read_response(&mut slow).starts_with("HTTP/1.1 101"));
+        slow.write_all(&frame[..3]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        slow.write_all(&frame[3..]).unwrap();
+        assert_eq!(Frame::read_from(&mut slow).unwrap().payload, b"slow");
+    }
+
+    #[test]
+    fn closes_handshakes_that_do_not_finish_in_time() {
+        let mut config = Config::default();
+        config.handshake_limits.timeout = Duration::from_millis(200);
+        let server = spawn(config, 1);
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(&REQUEST.as_bytes()[..20]).unwrap();
+
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn sends_the_close_frame_before_closing_the_socket() {
+        let server = spawn(Config::default(), 1);
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(REQUEST.as_bytes()).unwrap();
+        read_response(&mut stream);
+
+        let close = Frame::close(1000, "bye").masked([1, 2, 3, 4]).to_bytes();
+        stream.write_all(&close).unwrap();
+        assert_eq!(Frame::read_from(&mut stream).unwrap().opcode, Opcode::Close);
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.admin().connections().is_empty() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
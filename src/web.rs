@@ -0,0 +1,295 @@
+// ブラウザ (wasm32-unknown-unknown) のWebSocketクライアント
+//
+// ブラウザではTCPを直接使えないので、handshake・フレーム・Ping/Pongはブラウザの `WebSocket` に任せ、
+// そのイベントをメッセージのキューにして `AsyncClient` と同じメソッドで受け取る:
+//
+//   #[cfg(target_arch = "wasm32")]
+//   type Ws = websocket_rs::web::WebClient;
+//   #[cfg(not(target_arch = "wasm32"))]
+//   type Ws = websocket_rs::AsyncClient<websocket_rs::runtime::Tokio>;
+//
+//   let mut client = Ws::connect("ws://127.0.0.1:7778/").await?;
+//   client.send(Message::Text("hello".to_string())).await?;
+//   let reply = client.recv().await?;
+//
+// ブラウザはhandshakeの応答のヘッダーを見せないので `header` はない。
+// wasm32以外のターゲットではビルドできるが、`connect` はエラーを返す
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+};
+
+use js_sys::{Array, ArrayBuffer, JsString, Uint8Array};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+use crate::{
+    error::{Error, Result},
+    message::Message,
+};
+
+/// ブラウザの `WebSocket` を使うクライアント。`AsyncClient` と同じく1つのtaskから使う
+pub struct WebClient {
+    socket: WebSocket,
+    inbox: Rc<RefCell<Inbox>>,
+    /// `WebSocket` のイベントハンドラー。dropすると呼べなくなるので持っておく
+    _handlers: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+/// `WebSocket` のイベントで届いたもの
+#[derive(Default)]
+struct Inbox {
+    open: bool,
+    messages: VecDeque<Message>,
+    /// closeイベントのstatus codeとreason。サーバーがstatus codeを送らなければ 1005、切断されたら 1006
+    peer_close: Option<(u16, String)>,
+    waker: Option<Waker>,
+}
+
+impl Inbox {
+    fn push(&mut self, message: Message) {
+        self.messages.push_back(message);
+        self.wake();
+    }
+
+    fn opened(&mut self) {
+        self.open = true;
+        self.wake();
+    }
+
+    fn closed(&mut self, code: u16, reason: String) {
+        self.peer_close = Some((code, reason));
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// 接続できたら true、閉じられたら false
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if self.open {
+            return Poll::Ready(true);
+        }
+        if self.peer_close.is_some() {
+            return Poll::Ready(false);
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// 届いた順にメッセージを返す。残っていなければ、閉じられたら None
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        if let Some(message) = self.messages.pop_front() {
+            return Poll::Ready(Some(message));
+        }
+        if self.peer_close.is_some() {
+            return Poll::Ready(None);
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// 閉じられるまで待つ。それまでに届いたメッセージは捨てる
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<(u16, String)> {
+        self.messages.clear();
+        match &self.peer_close {
+            Some(close) => Poll::Ready(close.clone()),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl WebClient {
+    /// `url` (`ws://` または `wss://`) に接続し、openイベントまで待つ
+    pub async fn connect(url: &str) -> Result<Self> {
+        Self::connect_with_protocols(url, &[]).await
+    }
+
+    /// `Sec-WebSocket-Protocol` で `protocols` を提案して接続する
+    pub async fn connect_with_protocols(url: &str, protocols: &[&str]) -> Result<Self> {
+        if !cfg!(target_arch = "wasm32") {
+            return Err(Error::Handshake(
+                "WebClient only runs on wasm32 in a browser".to_string(),
+            ));
+        }
+        let protocols = protocols
+            .iter()
+            .map(|protocol| JsValue::from_str(protocol))
+            .collect::<Array>();
+        let socket = WebSocket::new_with_str_sequence(url, &protocols).map_err(js_error)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let inbox = Rc::new(RefCell::new(Inbox::default()));
+        let on_open = {
+            let inbox = inbox.clone();
+            Closure::<dyn FnMut(JsValue)>::new(move |_| inbox.borrow_mut().opened())
+        };
+        let on_message = {
+            let inbox = inbox.clone();
+            Closure::<dyn FnMut(JsValue)>::new(move |event: JsValue| {
+                let data = event.unchecked_into::<MessageEvent>().data();
+                let message = match data.dyn_into::<JsString>() {
+                    Ok(text) => Message::Text(String::from(text)),
+                    Err(data) => match data.dyn_into::<ArrayBuffer>() {
+                        Ok(buffer) => Message::Binary(Uint8Array::new(&buffer).to_vec()),
+                        // binaryTypeはarraybufferにしているので、Blobは届かない
+                        Err(_) => return,
+                    },
+                };
+                inbox.borrow_mut().push(message);
+            })
+        };
+        let on_close = {
+            let inbox = inbox.clone();
+            Closure::<dyn FnMut(JsValue)>::new(move |event: JsValue| {
+                let event = event.unchecked_into::<CloseEvent>();
+                inbox.borrow_mut().closed(event.code(), event.reason());
+            })
+        };
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        let client = Self {
+            socket,
+            inbox,
+            _handlers: vec![on_open, on_message, on_close],
+        };
+        // ブラウザは接続できなかった理由を見せないので、closeイベントのstatus codeだけを返す
+        if !poll_fn(|cx| client.inbox.borrow_mut().poll_open(cx)).await {
+            let (code, _) = client.inbox.borrow().peer_close.clone().unwrap_or_default();
+            return Err(Error::Handshake(format!("connection failed ({})", code)));
+        }
+        Ok(client)
+    }
+
+    /// サーバーが選んだサブプロトコル
+    pub fn protocol(&self) -> Option<String> {
+        Some(self.socket.protocol()).filter(|protocol| !protocol.is_empty())
+    }
+
+    /// サーバーからCloseを受信した (または切断された)
+    pub fn is_closed(&self) -> bool {
+        self.inbox.borrow().peer_close.is_some()
+    }
+
+    /// closeイベントのstatus codeとreason (`recv` が None を返した後に使う)
+    pub fn peer_close(&self) -> Option<(u16, String)> {
+        self.inbox.borrow().peer_close.clone()
+    }
+
+    /// ブラウザの送信バッファに入れる。送り終わるのは待たない
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        if self.socket.ready_state() != WebSocket::OPEN {
+            return Err(Error::Io(io::ErrorKind::NotConnected.into()));
+        }
+        match message {
+            Message::Text(text) => self.socket.send_with_str(&text),
+            Message::Binary(data) => self.socket.send_with_u8_array(&data),
+        }
+        .map_err(js_error)
+    }
+
+    /// メッセージを1つ受信する。Pingへの応答はブラウザが行う。
+    /// サーバーから閉じられた場合は None
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        Ok(poll_fn(|cx| self.inbox.borrow_mut().poll_recv(cx)).await)
+    }
+
+    /// Closeを送り、closeイベントまで待って、そのstatus codeとreasonを返す。
+    /// ブラウザが送れるstatus codeは 1000 と 3000〜4999 だけ
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(u16, String)> {
+        if !self.is_closed() {
+            self.socket
+                .close_with_code_and_reason(code, reason)
+                .map_err(js_error)?;
+        }
+        Ok(poll_fn(|cx| self.inbox.borrow_mut().poll_close(cx)).await)
+    }
+}
+
+impl Drop for WebClient {
+    fn drop(&mut self) {
+        self.socket.set_onopen(None);
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        if !self.is_closed() {
+            let _ = self.socket.close();
+        }
+    }
+}
+
+/// ブラウザが投げた例外 (DOMException など) を `Error` にする
+fn js_error(error: JsValue) -> Error {
+    let message = js_sys::Error::from(error)
+        .message()
+        .as_string()
+        .unwrap_or_default();
+    match message.is_empty() {
+        true => Error::Io(io::Error::other("WebSocket error")),
+        false => Error::Io(io::Error::other(message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_messages_until_the_close_event() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut inbox = Inbox::default();
+        assert_eq!(inbox.poll_open(&mut cx), Poll::Pending);
+        inbox.opened();
+        assert_eq!(inbox.poll_open(&mut cx), Poll::Ready(true));
+
+        assert_eq!(inbox.poll_recv(&mut cx), Poll::Pending);
+        inbox.push(Message::Text("hello".to_string()));
+        inbox.push(Message::Binary(vec![1, 2]));
+        inbox.closed(1000, "bye".to_string());
+        // closeイベントの前に届いたメッセージは受け取れる
+        assert_eq!(
+            inbox.poll_recv(&mut cx),
+            Poll::Ready(Some(Message::Text("hello".to_string())))
+        );
+        assert_eq!(
+            inbox.poll_recv(&mut cx),
+            Poll::Ready(Some(Message::Binary(vec![1, 2])))
+        );
+        assert_eq!(inbox.poll_recv(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn close_discards_pending_messages() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut inbox = Inbox::default();
+        inbox.opened();
+        inbox.push(Message::Text("late".to_string()));
+        assert_eq!(inbox.poll_close(&mut cx), Poll::Pending);
+        inbox.closed(1005, String::new());
+        assert_eq!(
+            inbox.poll_close(&mut cx),
+            Poll::Ready((1005, String::new()))
+        );
+        assert_eq!(inbox.poll_recv(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn fails_to_open_when_closed_first() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut inbox = Inbox::default();
+        inbox.closed(1006, String::new());
+        assert_eq!(inbox.poll_open(&mut cx), Poll::Ready(false));
+    }
+}
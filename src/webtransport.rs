@@ -0,0 +1,304 @@
+// (実験的) WebTransport (HTTP/3) の上のWebSocket
+//
+// WebTransportのsessionはQUICの接続の上のExtended CONNECT (:protocol = webtransport) で始まり、
+// その中で双方向streamをいくつでも開ける。このクレートでは、双方向streamの1つを
+// WebSocketの1つの接続として扱い、streamの上ではRFC 6455のフレーム (クライアントはマスクする) を送り合う:
+//
+//   const transport = new WebTransport("https://server.example.com:4433/chat");
+//   const { readable, writable } = await transport.createBidirectionalStream();
+//
+// handshakeの検証・middleware・Handlerは `Upgrader` に任せる。sessionのpathとヘッダーに
+// `Upgrade`・`Connection`・`Sec-WebSocket-Key` を補ったリクエストを、streamごとに `Bridge` で渡す。
+// 拒否された場合は、そのstatus codeをエラーコードにしてstreamをリセットする。
+//
+// QUIC・HTTP/3は wtransport に任せる。ブラウザのAPIもこのクレートも仕様が固まっていないので、
+// 今後のバージョンで互換性なく変えることがある
+
+use std::{
+    io::{self, Write},
+    net::{Shutdown, SocketAddr},
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use wtransport::{
+    endpoint::IncomingSession, Endpoint, RecvStream, SendStream, ServerConfig, VarInt,
+};
+
+use crate::{
+    bridge::Bridge, handler::Handler, handshake::Request, log::debug, server::Upgrader,
+    stream::Stream, testing::Pipe,
+};
+
+/// WebTransportのsessionを受け付け、双方向streamを `Upgrader` に渡す
+pub struct WebTransport<H: Handler> {
+    upgrader: Upgrader<H>,
+}
+
+impl<H: Handler> Clone for WebTransport<H> {
+    fn clone(&self) -> Self {
+        Self {
+            upgrader: self.upgrader.clone(),
+        }
+    }
+}
+
+impl<H: Handler> WebTransport<H> {
+    pub fn new(upgrader: Upgrader<H>) -> Self {
+        Self { upgrader }
+    }
+
+    /// `config` のアドレスと証明書でsessionを受け付ける。戻らない
+    pub fn run(self, config: ServerConfig) -> io::Result<()> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(async move {
+                let endpoint = Endpoint::server(config)?;
+                loop {
+                    let incoming = endpoint.accept().await;
+                    let transport = self.clone();
+                    tokio::spawn(async move { transport.serve_session(incoming).await });
+                }
+            })
+    }
+
+    /// 1つのsessionを処理する。sessionのpathは受け付けたstreamのリクエストのpathになる。
+    /// tokioのランタイムの中から呼ぶこと
+    pub async fn serve_session(&self, incoming: IncomingSession) {
+        let session = match incoming.await {
+            Ok(session) => session,
+            Err(e) => return session_failed(None, &e),
+        };
+        let peer_addr = session.remote_address();
+        let path = session.path().to_string();
+        let mut headers = session
+            .headers()
+            .iter()
+            .filter(|(key, _)| !key.starts_with(':'))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value.clone()))
+            .collect::<Vec<_>>();
+        headers.push(("host".to_string(), session.authority().to_string()));
+        let connection = match session.accept().await {
+            Ok(connection) => connection,
+            Err(e) => return session_failed(Some(peer_addr), &e),
+        };
+        loop {
+            let (send, recv) = match connection.accept_bi().await {
+                Ok(stream) => stream,
+                // クライアントがsessionを閉じた
+                Err(_) => return,
+            };
+            let request = Request {
+                method: "GET".to_string(),
+                path: path.clone(),
+                version: "HTTP/3".to_string(),
+                headers: headers.clone(),
+            };
+            tokio::spawn(websocket(
+                self.upgrader.clone(),
+                request,
+                send,
+                recv,
+                peer_addr,
+            ));
+        }
+    }
+}
+
+fn session_failed(peer_addr: Option<SocketAddr>, error: &impl std::fmt::Display) {
+    let peer = peer_addr.map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+    debug!(
+        "webtransport_session_failed",
+        { peer: peer.clone(), error: error.to_string() },
+        "webtransport session from {} failed: {}",
+        peer,
+        error
+    );
+}
+
+/// 双方向streamを、HTTP/1.1のhandshakeに直して `upgrader` に渡す
+async fn websocket<H: Handler>(
+    upgrader: Upgrader<H>,
+    mut request: Request,
+    mut send: SendStream,
+    recv: RecvStream,
+    peer_addr: SocketAddr,
+) {
+    // 101の応答はクライアントに送らないので、鍵は何でもよい
+    let key = general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+    request.headers.extend([
+        ("upgrade".to_string(), "websocket".to_string()),
+        ("connection".to_string(), "upgrade".to_string()),
+        ("sec-websocket-key".to_string(), key),
+        ("sec-websocket-version".to_string(), "13".to_string()),
+    ]);
+
+    let Ok(mut bridge) = Bridge::upgrade(&upgrader, request, peer_addr) else {
+        return reject(send, recv, 500);
+    };
+    let Some((head, body)) = bridge.response().await else {
+        return reject(send, recv, 500);
+    };
+    if head.status != 101 {
+        return reject(send, recv, head.status);
+    }
+
+    let Bridge { pipe, mut chunks } = bridge;
+    tokio::spawn(forward_recv(recv, pipe));
+    if !body.is_empty() && send.write_all(&body).await.is_err() {
+        return;
+    }
+    while let Some(chunk) = chunks.recv().await {
+        if send.write_all(&chunk).await.is_err() {
+            return;
+        }
+    }
+    // 接続のスレッドが閉じたら、streamも閉じる
+    let _ = send.finish().await;
+}
+
+/// 拒否したstreamを、応答のstatus codeをエラーコードにしてリセットする
+fn reject(mut send: SendStream, recv: RecvStream, status: u16) {
+    let code = VarInt::from_u32(status.into());
+    let _ = send.reset(code);
+    recv.stop(code);
+}
+
+/// streamで受信したバイト列 (フレーム) を接続のスレッドに渡す
+async fn forward_recv(mut recv: RecvStream, mut pipe: Pipe) {
+    let mut buf = [0; 16 * 1024];
+    while let Ok(Some(n)) = recv.read(&mut buf).await {
+        if pipe.write_all(&buf[..n]).is_err() {
+            return;
+        }
+    }
+    // クライアントがstreamを閉じたら、接続のスレッドの読み込みをEOFにする
+    let _ = Stream::shutdown(&pipe, Shutdown::Write);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, thread};
+
+    use wtransport::{ClientConfig, Identity};
+
+    use super::*;
+    use crate::{
+        connection::Connection,
+        frame::{Frame, Opcode},
+        message::Message,
+        middleware::BasicAuth,
+        server::Server,
+    };
+
+    /// 受信したメッセージをそのまま返す
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&self, conn: &mut Connection, message: Message) {
+            let _ = conn.send(message);
+        }
+    }
+
+    /// 自己署名の証明書で `server` を起動し、そのアドレスと証明書を検証するクライアントの設定を返す
+    fn spawn_webtransport(server: Server<Echo>) -> (SocketAddr, ClientConfig) {
+        // certificate hashesで検証する証明書は、ECDSA P-256で有効期間が2週間以内であること
+        let identity = Identity::self_signed_builder()
+            .subject_alt_names(["localhost"])
+            .from_now_utc()
+            .validity_days(7)
+            .build()
+            .unwrap();
+        let hash = identity.certificate_chain().as_slice()[0].hash();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let config = ServerConfig::builder()
+            .with_bind_socket(socket)
+            .with_identity(identity)
+            .build();
+        let transport = WebTransport::new(server.upgrader());
+        thread::spawn(move || transport.run(config));
+
+        let client = ClientConfig::builder()
+            .with_bind_default()
+            .with_server_certificate_hashes([hash])
+            .build();
+        (addr, client)
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    async fn open_session(addr: SocketAddr, client: ClientConfig) -> wtransport::Connection {
+        Endpoint::client(client)
+            .unwrap()
+            .connect(format!("https://localhost:{}/chat", addr.port()))
+            .await
+            .unwrap()
+    }
+
+    /// streamからフレームを1つ取り出す
+    async fn read_frame(recv: &mut RecvStream, buffer: &mut Vec<u8>) -> Frame {
+        let mut buf = [0; 4096];
+        loop {
+            if let Some((frame, len)) = Frame::parse(buffer).unwrap() {
+                buffer.drain(..len);
+                return frame;
+            }
+            let n = recv.read(&mut buf).await.unwrap().unwrap();
+            buffer.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn carries_websocket_on_bidirectional_streams() {
+        let (addr, client) = spawn_webtransport(Server::bind("127.0.0.1:0", Echo).unwrap());
+        block_on(async {
+            let connection = open_session(addr, client).await;
+            // 同じsessionで、streamごとに別のWebSocketの接続になる
+            for text in ["hello", "world"] {
+                let (mut send, mut recv) = connection.open_bi().await.unwrap().await.unwrap();
+                let frame = Frame::from(Message::Text(text.to_string())).masked([1, 2, 3, 4]);
+                send.write_all(&frame.to_bytes()).await.unwrap();
+                let mut buffer = vec![];
+                let frame = read_frame(&mut recv, &mut buffer).await;
+                assert_eq!(frame.opcode, Opcode::Text);
+                assert_eq!(frame.payload, text.as_bytes());
+
+                let close = Frame::close(1000, "bye").masked([1, 2, 3, 4]);
+                send.write_all(&close.to_bytes()).await.unwrap();
+                assert_eq!(
+                    read_frame(&mut recv, &mut buffer).await.opcode,
+                    Opcode::Close
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn resets_streams_rejected_by_middleware() {
+        let server = Server::bind("127.0.0.1:0", Echo)
+            .unwrap()
+            .with_layer(BasicAuth::new("chat", "user", "secret"));
+        let (addr, client) = spawn_webtransport(server);
+        block_on(async {
+            let connection = open_session(addr, client).await;
+            let (mut send, mut recv) = connection.open_bi().await.unwrap().await.unwrap();
+            // streamはクライアントが書き込むまでサーバーに届かない
+            let frame = Frame::from(Message::Text("hello".to_string())).masked([1, 2, 3, 4]);
+            send.write_all(&frame.to_bytes()).await.unwrap();
+            let mut buf = [0; 64];
+            match recv.read(&mut buf).await {
+                Err(wtransport::error::StreamReadError::Reset(code)) => {
+                    assert_eq!(code, VarInt::from_u32(401))
+                }
+                other => panic!("expected the stream to be reset: {:?}", other),
+            }
+        });
+    }
+}